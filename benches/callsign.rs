@@ -0,0 +1,74 @@
+//! Cost of finding a client by callsign and of copying its callsign: a scan
+//! over every client and fresh `String`s against the registry's callsign
+//! index and shared [`Callsign`]s
+//!
+//! Run with `cargo bench --bench callsign`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use openfsd::callsign::Callsign;
+use openfsd::client::{Client, ClientState};
+use openfsd::server::registry::ClientRegistry;
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::net::SocketAddr;
+
+fn registry(clients: u16) -> ClientRegistry {
+    (0..clients)
+        .map(|n| {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10_000 + n));
+            let mut client = Client::new(addr);
+            client.state = ClientState::Active;
+            client.callsign = Some(format!("CCA{}", n).into());
+            (addr, client)
+        })
+        .collect()
+}
+
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("callsign/lookup");
+    for clients in [10, 100, 1000] {
+        let registry = registry(clients);
+        let wanted = format!("CCA{}", clients / 2);
+        group.bench_with_input(BenchmarkId::new("scan", clients), &wanted, |b, wanted| {
+            b.iter(|| {
+                registry
+                    .values()
+                    .find(|client| client.callsign() == Some(black_box(wanted.as_str())))
+                    .map(|client| client.addr)
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("indexed", clients), &wanted, |b, wanted| {
+            b.iter(|| registry.named(black_box(wanted)).map(|(addr, _)| *addr))
+        });
+    }
+    group.finish();
+}
+
+/// Keep a callsign in three places, the way a login does, then look it up
+/// the way a packet does
+fn login(c: &mut Criterion) {
+    let mut group = c.benchmark_group("callsign/login");
+    group.bench_function("string", |b| {
+        let mut map: HashMap<String, u16> = HashMap::new();
+        b.iter(|| {
+            let source = black_box("CCA1501");
+            let kept = (source.to_string(), source.to_string());
+            map.insert(source.to_uppercase(), 1);
+            black_box((kept, map.get(&source.to_uppercase()).copied()))
+        })
+    });
+    group.bench_function("shared", |b| {
+        let mut map: HashMap<Callsign, u16> = HashMap::new();
+        b.iter(|| {
+            let source = black_box("CCA1501");
+            let name = Callsign::new(source);
+            let kept = (name.clone(), name.clone());
+            map.insert(name, 1);
+            black_box((kept, map.get(Callsign::key(source).as_ref()).copied()))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, lookup, login);
+criterion_main!(benches);
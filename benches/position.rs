@@ -0,0 +1,56 @@
+//! Cost of handling one position update: the full parse the server used to
+//! do against the sliced fast path
+//!
+//! Run with `cargo bench --bench position`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use openfsd::packet::{Packet, PositionUpdate};
+use std::hint::black_box;
+
+const PILOT: &str = "@N:CCA1501:1200:1:40.08:116.58:5000:250:4194304:0\r\n";
+const ATC: &str = "%ZBPE_CTR:25300:6:300:5:40.08:116.58:0\r\n";
+
+/// Read the fields the handler reads, then write the update once per
+/// recipient in range
+fn full_parse(line: &str, recipients: usize) {
+    let packet = Packet::parse(line).unwrap();
+    let latitude: Option<f64> = packet.data.get(2).and_then(|s| s.parse().ok());
+    let longitude: Option<f64> = packet.data.get(3).and_then(|s| s.parse().ok());
+    black_box((packet.data.first(), latitude, longitude));
+    for _ in 0..recipients {
+        black_box(packet.format());
+    }
+}
+
+fn sliced(line: &str, recipients: usize) {
+    let update = PositionUpdate::parse(line).unwrap();
+    let [first, _, latitude, longitude] = update.leading_fields();
+    let latitude: Option<f64> = latitude.and_then(|s| s.parse().ok());
+    let longitude: Option<f64> = longitude.and_then(|s| s.parse().ok());
+    black_box((first, latitude, longitude));
+    for _ in 0..recipients {
+        black_box(update.line().as_bytes());
+    }
+}
+
+fn position_update(c: &mut Criterion) {
+    for (name, line) in [("pilot", PILOT), ("atc", ATC)] {
+        let mut group = c.benchmark_group(format!("position_update/{}", name));
+        for recipients in [1, 10, 50] {
+            group.bench_with_input(
+                BenchmarkId::new("full_parse", recipients),
+                &recipients,
+                |b, &recipients| b.iter(|| full_parse(black_box(line), recipients)),
+            );
+            group.bench_with_input(
+                BenchmarkId::new("sliced", recipients),
+                &recipients,
+                |b, &recipients| b.iter(|| sliced(black_box(line), recipients)),
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, position_update);
+criterion_main!(benches);
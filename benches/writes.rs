@@ -0,0 +1,82 @@
+//! Cost of sending a backlog of position updates to a client: a socket
+//! write per packet, as the server used to do, against writes coalesced
+//! into batches of up to 64 KiB
+//!
+//! Run with `cargo bench --bench writes`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use openfsd::packet::PositionUpdate;
+use std::hint::black_box;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+const PILOT: &str = "@N:CCA1501:1200:1:40.08:116.58:5000:250:4194304:0\r\n";
+
+/// The server's batch size
+const WRITE_BATCH_BYTES: usize = 64 * 1024;
+
+/// A connection to a local client that reads and discards what it gets
+async fn connect() -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        tokio::io::copy(&mut stream, &mut tokio::io::sink())
+            .await
+            .unwrap()
+    });
+    TcpStream::connect(addr).await.unwrap()
+}
+
+async fn one_by_one(stream: &mut TcpStream, update: &PositionUpdate, backlog: usize) {
+    for _ in 0..backlog {
+        stream.write_all(update.line().as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+}
+
+async fn coalesced(stream: &mut TcpStream, update: &PositionUpdate, backlog: usize) {
+    let mut buffer = Vec::with_capacity(WRITE_BATCH_BYTES + update.line().len());
+    for _ in 0..backlog {
+        buffer.extend_from_slice(update.line().as_bytes());
+        if buffer.len() >= WRITE_BATCH_BYTES {
+            stream.write_all(&buffer).await.unwrap();
+            stream.flush().await.unwrap();
+            buffer.clear();
+        }
+    }
+    if !buffer.is_empty() {
+        stream.write_all(&buffer).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+}
+
+fn backlog(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut stream = runtime.block_on(connect());
+    let update = PositionUpdate::parse(PILOT).unwrap();
+
+    let mut group = c.benchmark_group("writes/backlog");
+    for backlog in [100, 1_000, 20_000] {
+        group.throughput(Throughput::Elements(backlog as u64));
+        group.bench_with_input(
+            BenchmarkId::new("one_by_one", backlog),
+            &backlog,
+            |b, &backlog| {
+                b.iter(|| runtime.block_on(one_by_one(&mut stream, &update, black_box(backlog))))
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("coalesced", backlog),
+            &backlog,
+            |b, &backlog| {
+                b.iter(|| runtime.block_on(coalesced(&mut stream, &update, black_box(backlog))))
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, backlog);
+criterion_main!(benches);
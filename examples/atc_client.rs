@@ -0,0 +1,464 @@
+/// Example ATC client
+///
+/// Logs in as a controller and walks through the ATC side of the protocol:
+/// the #AA login, periodic % position updates, a traffic table built from
+/// pilot positions and flight plans, and interactive handoffs, amendments,
+/// squawk assignments and ATIS updates. Every packet goes through `Packet`
+/// so the format comments here double as protocol documentation.
+///
+/// The account needs a controller rating of at least --rating.
+///
+/// Usage: cargo run --example atc_client -- --callsign ZSPD_APP --cid 1234567 --password secret
+use clap::{Parser, ValueEnum};
+use openfsd::flight_plan::FlightPlan;
+use openfsd::packet::{Packet, PacketType};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+
+const POSITION_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Log in as a controller and exercise the ATC protocol
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Server to connect to
+    #[arg(long, default_value = "127.0.0.1:6809")]
+    server: String,
+
+    #[arg(long, default_value = "ZSPD_APP")]
+    callsign: String,
+
+    #[arg(long, default_value = "1234567")]
+    cid: String,
+
+    #[arg(long)]
+    password: String,
+
+    #[arg(long, default_value = "Example Controller")]
+    real_name: String,
+
+    /// Controller rating to log in with, e.g. 5 for C1
+    #[arg(long, default_value_t = 5)]
+    rating: u8,
+
+    #[arg(long, value_enum, default_value_t = Facility::App)]
+    facility: Facility,
+
+    /// Primary frequency in MHz
+    #[arg(long, default_value_t = 120.3)]
+    frequency: f64,
+
+    /// Visibility range in nautical miles
+    #[arg(long, default_value_t = 150)]
+    range: u32,
+
+    #[arg(long, default_value_t = 31.1434, allow_negative_numbers = true)]
+    latitude: f64,
+
+    #[arg(long, default_value_t = 121.8052, allow_negative_numbers = true)]
+    longitude: f64,
+
+    /// Client ID sent in $ID; EuroScope's by default
+    #[arg(long, default_value = "69d7")]
+    client_id: String,
+
+    /// Seconds between traffic tables, 0 to only print on `traffic`
+    #[arg(long, default_value_t = 30)]
+    table_interval: u64,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Facility {
+    Fss,
+    Del,
+    Gnd,
+    Twr,
+    App,
+    Ctr,
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Facility::Fss => 1,
+            Facility::Del => 2,
+            Facility::Gnd => 3,
+            Facility::Twr => 4,
+            Facility::App => 5,
+            Facility::Ctr => 6,
+        }
+    }
+}
+
+/// What is known about a pilot from its positions and flight plan
+#[derive(Default)]
+struct Traffic {
+    squawk: String,
+    latitude: String,
+    longitude: String,
+    altitude: String,
+    ground_speed: String,
+    flight_plan: Option<FlightPlan>,
+}
+
+struct Controller {
+    callsign: String,
+    cid: String,
+    writer: OwnedWriteHalf,
+    traffic: BTreeMap<String, Traffic>,
+    /// Frequency as FSD writes it: 120.300 MHz is 20300
+    frequency: String,
+}
+
+impl Controller {
+    async fn send(&mut self, packet: Packet) -> std::io::Result<()> {
+        println!("📤 {}", packet);
+        self.writer.write_all(packet.format().as_bytes()).await
+    }
+
+    fn packet(&self, packet_type: PacketType, command: &str, to: &str, data: &[&str]) -> Packet {
+        Packet {
+            packet_type,
+            command: command.to_string(),
+            source: self.callsign.clone(),
+            destination: to.to_string(),
+            data: data.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    println!("🔌 Connecting to {}...", cli.server);
+    let stream = TcpStream::connect(&cli.server).await?;
+    let (reader, writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let mut atc = Controller {
+        callsign: cli.callsign.clone(),
+        cid: cli.cid.clone(),
+        writer,
+        traffic: BTreeMap::new(),
+        frequency: format!("{:.0}", (cli.frequency - 100.0) * 1000.0),
+    };
+
+    // $ID(callsign):SERVER:(client id):(client name):(major):(minor):(CID):(uid)
+    let identify = atc.packet(
+        PacketType::Request,
+        "ID",
+        "SERVER",
+        &[
+            &cli.client_id,
+            "OpenFSD ATC example",
+            "3",
+            "2",
+            &cli.cid,
+            "1234567890",
+        ],
+    );
+    atc.send(identify).await?;
+    // #AA(callsign):SERVER:(real name):(CID):(password):(rating):(protocol)
+    let rating = cli.rating.to_string();
+    let login = atc.packet(
+        PacketType::Client,
+        "AA",
+        "SERVER",
+        &[&cli.real_name, &cli.cid, &cli.password, &rating, "100"],
+    );
+    atc.send(login).await?;
+    print_help();
+
+    let mut position = tokio::time::interval(POSITION_INTERVAL);
+    let table_interval = Duration::from_secs(cli.table_interval.max(1));
+    let mut table =
+        tokio::time::interval_at(tokio::time::Instant::now() + table_interval, table_interval);
+    let mut input = BufReader::new(tokio::io::stdin()).lines();
+
+    loop {
+        tokio::select! {
+            _ = position.tick() => {
+                // %(callsign):(frequency):(facility):(range):(rating):(lat):(lon):(elevation)
+                let update = Packet {
+                    packet_type: PacketType::AtcUpdate,
+                    command: "%".to_string(),
+                    source: String::new(),
+                    destination: atc.callsign.clone(),
+                    data: vec![
+                        atc.frequency.clone(),
+                        cli.facility.code().to_string(),
+                        cli.range.to_string(),
+                        rating.clone(),
+                        format!("{:.5}", cli.latitude),
+                        format!("{:.5}", cli.longitude),
+                        "0".to_string(),
+                    ],
+                };
+                atc.writer.write_all(update.format().as_bytes()).await?;
+            }
+            _ = table.tick(), if cli.table_interval > 0 => print_traffic(&atc.traffic),
+            line = lines.next_line() => match line? {
+                Some(line) => match Packet::parse(&line) {
+                    Ok(packet) => receive(&mut atc, packet).await?,
+                    Err(_) => println!("📥 {}", line),
+                },
+                None => {
+                    println!("⚠️  Server closed connection");
+                    return Ok(());
+                }
+            },
+            command = input.next_line() => {
+                let Some(command) = command? else { break };
+                if !run_command(&mut atc, command.trim()).await? {
+                    break;
+                }
+            }
+        }
+    }
+
+    // #DA(callsign):(CID)
+    let logoff = atc.packet(PacketType::Client, "DA", &atc.cid, &[]);
+    atc.send(logoff).await?;
+    println!("👋 Logged off");
+    Ok(())
+}
+
+/// Track traffic and show what is addressed to this controller
+async fn receive(atc: &mut Controller, packet: Packet) -> std::io::Result<()> {
+    let to_me = packet.destination == atc.callsign;
+    match (&packet.packet_type, packet.command.as_str()) {
+        // @N:(callsign):(squawk):(rating):(lat):(lon):(alt):(groundspeed):(pbh):(flags)
+        (PacketType::PilotUpdate, _) => {
+            let traffic = atc.traffic.entry(packet.destination.clone()).or_default();
+            let field = |i: usize| packet.data.get(i).cloned().unwrap_or_default();
+            traffic.squawk = field(0);
+            traffic.latitude = field(2);
+            traffic.longitude = field(3);
+            traffic.altitude = field(4);
+            traffic.ground_speed = field(5);
+        }
+        // $FP(callsign):*A:(rules):(aircraft):...:(route)
+        (PacketType::Request, "FP") => {
+            if let Some(plan) = FlightPlan::from_packet(&packet) {
+                println!(
+                    "📋 {} filed {} → {} {} {}",
+                    packet.source, plan.departure, plan.destination, plan.aircraft, plan.altitude
+                );
+                atc.traffic
+                    .entry(packet.source.clone())
+                    .or_default()
+                    .flight_plan = Some(plan);
+            }
+        }
+        // $AM(controller):SERVER:(callsign):(plan fields)
+        (PacketType::Request, "AM") => {
+            if let Some((callsign, fields)) = packet.data.split_first() {
+                if let Some(plan) = FlightPlan::from_fields(fields) {
+                    println!("✏️  {} amended {}", packet.source, callsign);
+                    atc.traffic.entry(callsign.clone()).or_default().flight_plan = Some(plan);
+                }
+            }
+        }
+        // #DP(callsign):(CID) when a pilot logs off
+        (PacketType::Client, "DP") => {
+            atc.traffic.remove(&packet.source);
+        }
+        // $HO(from):(to):(callsign) offers an aircraft to us
+        (PacketType::Request, "HO") if to_me => {
+            let callsign = packet.data.first().cloned().unwrap_or_default();
+            println!(
+                "🤝 {} offers {}; `accept {} {}` to take it",
+                packet.source, callsign, callsign, packet.source
+            );
+        }
+        // $HA(from):(to):(callsign) accepts one of our handoffs
+        (PacketType::Request, "HA") if to_me => {
+            let callsign = packet.data.first().cloned().unwrap_or_default();
+            println!("✅ {} accepted {}", packet.source, callsign);
+        }
+        // $CQSERVER:(callsign):CAPS asks which features we support
+        (PacketType::Request, "CQ")
+            if to_me && packet.data.first().map(String::as_str) == Some("CAPS") =>
+        {
+            let caps = atc.packet(
+                PacketType::Request,
+                "CR",
+                &packet.source,
+                &["CAPS", "ATCINFO=1", "SECPOS=1"],
+            );
+            atc.send(caps).await?;
+        }
+        // #TM(from):(to or @frequency):(text)
+        (PacketType::Client, "TM")
+            if to_me || packet.destination == format!("@{}", atc.frequency) =>
+        {
+            println!("💬 {}: {}", packet.source, packet.data.join(":"));
+        }
+        _ if to_me => println!("📥 {}", packet),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Run one interactive command; false to log off
+async fn run_command(atc: &mut Controller, command: &str) -> std::io::Result<bool> {
+    let (verb, rest) = command.split_once(' ').unwrap_or((command, ""));
+    let args: Vec<&str> = rest.split_whitespace().collect();
+    match (verb, args.as_slice()) {
+        ("", _) => {}
+        ("traffic", _) => print_traffic(&atc.traffic),
+        ("handoff", [callsign, to]) => {
+            let packet = atc.packet(PacketType::Request, "HO", to, &[callsign]);
+            atc.send(packet).await?;
+        }
+        ("accept", [callsign, from]) => {
+            let packet = atc.packet(PacketType::Request, "HA", from, &[callsign]);
+            atc.send(packet).await?;
+        }
+        ("squawk", [callsign, code]) => {
+            // #PC(controller):*:CCP:BC:(callsign):(code)
+            let packet = atc.packet(
+                PacketType::Client,
+                "PC",
+                "*",
+                &["CCP", "BC", callsign, code],
+            );
+            atc.send(packet).await?;
+        }
+        ("amend", [callsign, ..]) => {
+            let Some(mut plan) = atc
+                .traffic
+                .get(*callsign)
+                .and_then(|t| t.flight_plan.clone())
+            else {
+                println!("❌ No flight plan for {}", callsign);
+                return Ok(true);
+            };
+            let changes = rest
+                .trim_start()
+                .strip_prefix(*callsign)
+                .unwrap_or_default();
+            if let Err(e) = amend(&mut plan, changes) {
+                println!("❌ {}", e);
+                return Ok(true);
+            }
+            let mut data = vec![callsign.to_string()];
+            data.extend(plan.fields());
+            let packet = Packet {
+                packet_type: PacketType::Request,
+                command: "AM".to_string(),
+                source: atc.callsign.clone(),
+                destination: "SERVER".to_string(),
+                data,
+            };
+            atc.send(packet).await?;
+            atc.traffic
+                .entry(callsign.to_string())
+                .or_default()
+                .flight_plan = Some(plan);
+        }
+        ("atis", _) if !rest.is_empty() => {
+            // $CR(callsign):SERVER:ATIS:T:(line) for each line, then
+            // $CR(callsign):SERVER:ATIS:E:(count); the server answers
+            // pilots' $CQ ATIS requests with it
+            let lines: Vec<&str> = rest.split('|').map(str::trim).collect();
+            for line in &lines {
+                let packet = atc.packet(PacketType::Request, "CR", "SERVER", &["ATIS", "T", line]);
+                atc.send(packet).await?;
+            }
+            let count = lines.len().to_string();
+            let packet = atc.packet(PacketType::Request, "CR", "SERVER", &["ATIS", "E", &count]);
+            atc.send(packet).await?;
+        }
+        ("msg", [to, ..]) => {
+            let text = rest
+                .trim_start()
+                .strip_prefix(*to)
+                .unwrap_or_default()
+                .trim();
+            let packet = atc.packet(PacketType::Client, "TM", to, &[text]);
+            atc.send(packet).await?;
+        }
+        ("freq", _) if !rest.is_empty() => {
+            let to = format!("@{}", atc.frequency);
+            let packet = atc.packet(PacketType::Client, "TM", &to, &[rest]);
+            atc.send(packet).await?;
+        }
+        ("quit", _) => return Ok(false),
+        ("help", _) => print_help(),
+        _ => println!("❌ Unknown command, type `help`"),
+    }
+    Ok(true)
+}
+
+/// Apply `field=value` changes; route= and remarks= take the rest of the line
+fn amend(plan: &mut FlightPlan, changes: &str) -> Result<(), String> {
+    let mut rest = changes.trim();
+    while !rest.is_empty() {
+        let (field, after) = rest.split_once('=').ok_or("expected field=value")?;
+        let (value, next) = match field {
+            "route" | "remarks" => (after, ""),
+            _ => after.split_once(' ').unwrap_or((after, "")),
+        };
+        let target = match field {
+            "rules" => &mut plan.flight_rules,
+            "aircraft" => &mut plan.aircraft,
+            "speed" => &mut plan.cruise_speed,
+            "dep" => &mut plan.departure,
+            "alt" => &mut plan.altitude,
+            "dest" => &mut plan.destination,
+            "alternate" => &mut plan.alternate,
+            "remarks" => &mut plan.remarks,
+            "route" => &mut plan.route,
+            other => return Err(format!("unknown field {}", other)),
+        };
+        *target = value.trim().to_string();
+        rest = next.trim();
+    }
+    Ok(())
+}
+
+fn print_traffic(traffic: &BTreeMap<String, Traffic>) {
+    println!(
+        "\n{:<8} {:<5} {:>9} {:>10} {:>6} {:>4}  {:<6} {:<4} {:<4} {:<6} Route",
+        "Callsign", "Sqwk", "Lat", "Lon", "Alt", "GS", "Type", "Dep", "Dest", "FL"
+    );
+    for (callsign, t) in traffic {
+        let plan = t.flight_plan.clone().unwrap_or_default();
+        let route: String = plan.route.chars().take(30).collect();
+        println!(
+            "{:<8} {:<5} {:>9} {:>10} {:>6} {:>4}  {:<6} {:<4} {:<4} {:<6} {}",
+            callsign,
+            t.squawk,
+            t.latitude,
+            t.longitude,
+            t.altitude,
+            t.ground_speed,
+            plan.aircraft,
+            plan.departure,
+            plan.destination,
+            plan.altitude,
+            route
+        );
+    }
+    if traffic.is_empty() {
+        println!("(no traffic in range)");
+    }
+    println!();
+}
+
+fn print_help() {
+    println!("\nCommands:");
+    println!("  traffic                          Show pilots in range");
+    println!("  handoff <callsign> <controller>  Offer an aircraft to another controller");
+    println!("  accept <callsign> <controller>   Accept a handoff");
+    println!("  squawk <callsign> <code>         Assign a transponder code");
+    println!("  amend <callsign> field=value...  Amend a flight plan (rules, aircraft, speed,");
+    println!("                                   dep, alt, dest, alternate, remarks=, route=)");
+    println!("  atis <line> | <line> ...         Update the text ATIS");
+    println!("  msg <callsign> <text>            Send a private message");
+    println!("  freq <text>                      Send a message on the primary frequency");
+    println!("  quit                             Log off\n");
+}
@@ -1,17 +1,45 @@
 /// Simple FSD client example
 ///
-/// This example demonstrates how to connect to an FSD server and send basic packets.
+/// This example demonstrates how to connect to an FSD server and send basic
+/// packets, built with the crate's `Packet` type rather than by hand. For
+/// interactive poking at a server, or scripted sessions that answer the
+/// server's requests, see the `test_client` example.
 ///
 /// Usage: cargo run --example simple_client
+use openfsd::packet::{Packet, PacketType};
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::TcpStream;
 
 // Example FSD protocol values
-const EXAMPLE_CALLSIGN: &str = "TEST123";
-const EXAMPLE_CLIENT_ID: &str = "69d7"; // EuroScope client ID
-const EXAMPLE_CID: &str = "1234567"; // Example VATSIM CID
-const EXAMPLE_PASSWORD: &str = "password"; // Placeholder - not a real password
-const EXAMPLE_UID: &str = "987654321"; // Example unique identifier
+const CALLSIGN: &str = "TEST123";
+const CLIENT_ID: &str = "69d7"; // EuroScope client ID
+const CID: &str = "1234567"; // Example VATSIM CID
+const PASSWORD: &str = "password"; // Placeholder - not a real password
+const UID: &str = "987654321"; // Example unique identifier
+
+/// Build and send a packet, then give the server a moment to answer
+async fn send(
+    writer: &mut OwnedWriteHalf,
+    packet_type: PacketType,
+    command: &str,
+    source: &str,
+    to: &str,
+    data: &[&str],
+) -> std::io::Result<()> {
+    let packet = Packet {
+        packet_type,
+        command: command.to_string(),
+        source: source.to_string(),
+        destination: to.to_string(),
+        data: data.iter().map(|s| s.to_string()).collect(),
+    };
+    println!("> {}", packet);
+    writer.write_all(packet.format().as_bytes()).await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -21,93 +49,61 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Connect to the FSD server
     let server_addr = "127.0.0.1:6809";
     println!("Connecting to {}...", server_addr);
-
     let stream = TcpStream::connect(server_addr).await?;
     println!("Connected!\n");
 
     let (reader, mut writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
 
-    // Spawn a task to read responses from server
+    // Print what the server sends, parsed into its parts
     let read_handle = tokio::spawn(async move {
-        let mut line = String::new();
-        loop {
-            line.clear();
-            match reader.read_line(&mut line).await {
-                Ok(0) => {
-                    println!("Server closed connection");
-                    break;
-                }
-                Ok(_) => {
-                    print!("< {}", line);
-                }
-                Err(e) => {
-                    eprintln!("Error reading from server: {}", e);
-                    break;
-                }
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match Packet::parse(&line) {
+                Ok(p) => println!(
+                    "< {} {} -> {}: {}",
+                    p.command,
+                    p.source,
+                    p.destination,
+                    p.data.join(" | ")
+                ),
+                Err(_) => println!("< {}", line),
             }
         }
+        println!("Server closed connection");
     });
 
-    // Send client identification
-    let id_packet = format!(
-        "$ID{}:SERVER:{}:Example Client:3:2:{}:{}\r\n",
-        EXAMPLE_CALLSIGN, EXAMPLE_CLIENT_ID, EXAMPLE_CID, EXAMPLE_UID
-    );
-    println!("> {}", id_packet.trim_end());
-    writer.write_all(id_packet.as_bytes()).await?;
-    writer.flush().await?;
-
-    // Wait a bit for server response
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    use PacketType::{Client, PilotUpdate, Request};
 
-    // Send pilot login
-    let login_packet = format!(
-        "#AP{}:SERVER:{}:{}:1:1:2:John Doe KJFK\r\n",
-        EXAMPLE_CALLSIGN, EXAMPLE_CID, EXAMPLE_PASSWORD
-    );
-    println!("> {}", login_packet.trim_end());
-    writer.write_all(login_packet.as_bytes()).await?;
-    writer.flush().await?;
+    // $ID(callsign):SERVER:(client id):(client name):(major):(minor):(cid):(uid)
+    let data = [CLIENT_ID, "Example Client", "3", "2", CID, UID];
+    send(&mut writer, Request, "ID", CALLSIGN, "SERVER", &data).await?;
 
-    // Wait a bit
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    // #AP(callsign):SERVER:(cid):(password):(rating):(protocol):(sim):(real name)
+    let data = [CID, PASSWORD, "1", "100", "2", "John Doe KJFK"];
+    send(&mut writer, Client, "AP", CALLSIGN, "SERVER", &data).await?;
 
-    // Send a position update
-    let pos_packet = format!(
-        "@N{}:1200:1:40.6413:-73.7781:5000:250:414141414:30\r\n",
-        EXAMPLE_CALLSIGN
-    );
-    println!("> {}", pos_packet.trim_end());
-    writer.write_all(pos_packet.as_bytes()).await?;
-    writer.flush().await?;
+    // @N:(callsign):(squawk):(rating):(lat):(lon):(alt):(speed):(pbh):(alt diff)
+    let data = [
+        "1200",
+        "1",
+        "40.6413",
+        "-73.7781",
+        "5000",
+        "250",
+        "414141414",
+        "30",
+    ];
+    send(&mut writer, PilotUpdate, "N", "", CALLSIGN, &data).await?;
 
-    // Wait a bit
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    let data = ["Hello from the example client!"];
+    send(&mut writer, Client, "TM", CALLSIGN, "*", &data).await?;
 
-    // Send a text message
-    let msg_packet = format!(
-        "#TM{}:*:Hello from the example client!\r\n",
-        EXAMPLE_CALLSIGN
-    );
-    println!("> {}", msg_packet.trim_end());
-    writer.write_all(msg_packet.as_bytes()).await?;
-    writer.flush().await?;
-
-    // Wait a bit
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-
-    // Send logoff
-    let logoff_packet = format!("#DP{}:{}\r\n", EXAMPLE_CALLSIGN, EXAMPLE_CID);
-    println!("> {}", logoff_packet.trim_end());
-    writer.write_all(logoff_packet.as_bytes()).await?;
-    writer.flush().await?;
+    // #DP(callsign):(cid)
+    send(&mut writer, Client, "DP", CALLSIGN, CID, &[]).await?;
 
     println!("\nClosing connection...");
     drop(writer);
-
-    // Wait for reader to finish
-    let _ = tokio::time::timeout(tokio::time::Duration::from_secs(2), read_handle).await;
+    let _ = tokio::time::timeout(Duration::from_secs(2), read_handle).await;
 
     println!("Disconnected.");
     Ok(())
@@ -3,34 +3,81 @@
 /// This is a more feature-rich test client that allows interactive testing
 /// of the FSD server with various commands and scenarios.
 ///
+/// With --script the commands are read from a file instead, one per line,
+/// plus `expect <text> <timeout>` to wait for a received line containing the
+/// text and `sleep <ms>` to pause. The client exits non-zero as soon as an
+/// expectation fails, so a script can run in CI. In both modes the server's
+/// CAPS requests, pings and auth challenges are answered automatically.
+///
 /// Usage: cargo run --example test_client
+///        cargo run --example test_client -- --script examples/scripts/pilot_session.txt
+use clap::Parser;
+use openfsd::auth::challenge::compute_response;
+use openfsd::packet::Packet;
+use std::collections::VecDeque;
 use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use tokio::time::Instant;
 
 const DEFAULT_CALLSIGN: &str = "TEST123";
-const DEFAULT_CID: &str = "1234567";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Debug, Parser)]
+#[command(about = "Interactive and scriptable FSD test client")]
+struct Cli {
+    /// FSD server address
+    #[arg(long, default_value = "127.0.0.1:6809")]
+    server: String,
+    /// Run the commands in this file instead of reading stdin
+    #[arg(long, value_name = "FILE")]
+    script: Option<PathBuf>,
+    /// Network ID to log in with
+    #[arg(long, default_value = "1234567")]
+    cid: String,
+    #[arg(long, default_value = "password")]
+    password: String,
+    /// Client ID sent in $ID
+    #[arg(long, default_value = "69d7")]
+    client_id: String,
+    /// Key for answering the server's auth challenge
+    #[arg(long)]
+    client_key: Option<String>,
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<ExitCode> {
+    let cli = Cli::parse();
+
     println!("╔════════════════════════════════════════╗");
     println!("║   OpenFSD Interactive Test Client     ║");
     println!("╚════════════════════════════════════════╝\n");
 
+    // Read the script up front so a typo in the path fails before connecting
+    let script = match &cli.script {
+        Some(path) => Some(std::fs::read_to_string(path)?),
+        None => None,
+    };
+
     // Connect to the FSD server
-    let server_addr = "127.0.0.1:6809";
-    println!("🔌 Connecting to {}...", server_addr);
+    println!("🔌 Connecting to {}...", cli.server);
 
-    let stream = TcpStream::connect(server_addr).await?;
+    let stream = TcpStream::connect(&cli.server).await?;
     println!("✅ Connected!\n");
 
-    let (reader, mut writer) = stream.into_split();
+    let (reader, writer) = stream.into_split();
     let mut reader = BufReader::new(reader);
 
-    let (_tx, mut rx) = mpsc::channel::<String>(100);
+    let (tx, rx) = mpsc::channel::<String>(100);
 
-    // Spawn a task to read responses from server
+    // Spawn a task to read lines from the server; the session prints them,
+    // answers the ones that need an answer and keeps them for `expect`
     tokio::spawn(async move {
         let mut line = String::new();
         loop {
@@ -41,8 +88,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     break;
                 }
                 Ok(_) => {
-                    print!("📥 {}", line);
-                    io::stdout().flush().unwrap();
+                    if tx.send(line.trim_end().to_string()).await.is_err() {
+                        break;
+                    }
                 }
                 Err(e) => {
                     eprintln!("\n❌ Error reading from server: {}", e);
@@ -52,119 +100,89 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
-    // Main command loop
-    let mut callsign = DEFAULT_CALLSIGN.to_string();
-    let mut logged_in = false;
+    let mut session = Session {
+        writer,
+        incoming: rx,
+        received: VecDeque::new(),
+        connected: true,
+        callsign: DEFAULT_CALLSIGN.to_string(),
+        cid: cli.cid,
+        password: cli.password,
+        client_id: cli.client_id,
+        client_key: cli.client_key,
+        logged_in: false,
+        strict: script.is_some(),
+    };
+
+    let code = match script {
+        Some(script) => run_script(&mut session, &script).await?,
+        None => {
+            run_interactive(&mut session).await?;
+            ExitCode::SUCCESS
+        }
+    };
+
+    drop(session);
+    println!("✅ Disconnected.");
+    Ok(code)
+}
+
+/// Read commands from stdin until quit or end of input
+async fn run_interactive(session: &mut Session) -> Result<()> {
+    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
 
     print_help();
+    prompt()?;
 
     loop {
-        print!("\n> ");
-        io::stdout().flush()?;
-
         tokio::select! {
-            // Handle incoming messages from server
-            Some(_) = rx.recv() => {
-                // Messages are printed by the reader task
+            // Handle incoming lines from server
+            Some(line) = session.incoming.recv() => {
+                session.receive(line).await?;
             }
 
             // Handle user input
-            input = tokio::task::spawn_blocking(|| {
-                let mut buffer = String::new();
-                io::stdin().read_line(&mut buffer).ok().map(|_| buffer)
-            }) => {
-                if let Ok(Some(input)) = input {
-                    let input = input.trim();
-
-                    if input.is_empty() {
-                        continue;
-                    }
-
-                    match input.split_whitespace().next().unwrap_or("") {
-                        "help" | "h" => {
-                            print_help();
-                        }
-                        "quit" | "q" | "exit" => {
-                            println!("👋 Disconnecting...");
-                            if logged_in {
-                                let logoff = format!("#DP{}:{}\r\n", callsign, DEFAULT_CID);
-                                let _ = writer.write_all(logoff.as_bytes()).await;
-                                let _ = writer.flush().await;
-                            }
-                            break;
-                        }
-                        "id" => {
-                            let parts: Vec<&str> = input.split_whitespace().collect();
-                            if parts.len() > 1 {
-                                callsign = parts[1].to_string();
-                            }
-                            send_identification(&mut writer, &callsign).await?;
-                        }
-                        "login" => {
-                            let parts: Vec<&str> = input.split_whitespace().collect();
-                            let client_type = parts.get(1).unwrap_or(&"pilot");
-                            send_login(&mut writer, &callsign, client_type).await?;
-                            logged_in = true;
-                        }
-                        "logoff" => {
-                            send_logoff(&mut writer, &callsign).await?;
-                            logged_in = false;
-                        }
-                        "pos" => {
-                            let parts: Vec<&str> = input.split_whitespace().collect();
-                            let lat = parts.get(1).and_then(|s| s.parse().ok()).unwrap_or(40.6413);
-                            let lon = parts.get(2).and_then(|s| s.parse().ok()).unwrap_or(-73.7781);
-                            let alt = parts.get(3).and_then(|s| s.parse().ok()).unwrap_or(5000);
-                            send_position(&mut writer, &callsign, lat, lon, alt).await?;
-                        }
-                        "msg" => {
-                            let parts: Vec<&str> = input.splitn(3, ' ').collect();
-                            let to = parts.get(1).unwrap_or(&"*");
-                            let message = parts.get(2).unwrap_or(&"Test message");
-                            send_message(&mut writer, &callsign, to, message).await?;
-                        }
-                        "fp" => {
-                            send_flight_plan(&mut writer, &callsign).await?;
-                        }
-                        "metar" => {
-                            let parts: Vec<&str> = input.split_whitespace().collect();
-                            let icao = parts.get(1).unwrap_or(&"KJFK");
-                            send_metar_request(&mut writer, &callsign, icao).await?;
-                        }
-                        "caps" => {
-                            send_caps_response(&mut writer, &callsign).await?;
-                        }
-                        "rn" => {
-                            let parts: Vec<&str> = input.split_whitespace().collect();
-                            let target = parts.get(1).unwrap_or(&"*");
-                            send_realname_request(&mut writer, &callsign, target).await?;
-                        }
-                        "raw" => {
-                            let raw_packet = input.strip_prefix("raw ").unwrap_or("");
-                            if !raw_packet.is_empty() {
-                                let packet = format!("{}\r\n", raw_packet);
-                                println!("📤 {}", packet.trim_end());
-                                writer.write_all(packet.as_bytes()).await?;
-                                writer.flush().await?;
-                            }
-                        }
-                        "test" => {
-                            println!("🧪 Running automated test sequence...\n");
-                            run_test_sequence(&mut writer, &callsign).await?;
-                            logged_in = true;
-                        }
-                        _ => {
-                            println!("❓ Unknown command. Type 'help' for available commands.");
-                        }
-                    }
+            input = stdin.next_line() => {
+                let Some(input) = input? else {
+                    session.quit().await;
+                    return Ok(());
+                };
+                if !session.run_command(input.trim()).await? {
+                    return Ok(());
                 }
+                prompt()?;
             }
         }
     }
+}
 
-    drop(writer);
-    println!("✅ Disconnected.");
-    Ok(())
+/// Run every command in a script, stopping at the first failure
+async fn run_script(session: &mut Session, script: &str) -> Result<ExitCode> {
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        println!("▶️  {}", line);
+        match session.run_command(line).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(ExitCode::SUCCESS),
+            Err(e) => {
+                eprintln!("❌ Script failed at line {}: {}", number + 1, e);
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    }
+
+    session.quit().await;
+    println!("✅ Script completed!");
+    Ok(ExitCode::SUCCESS)
+}
+
+fn prompt() -> io::Result<()> {
+    print!("\n> ");
+    io::stdout().flush()
 }
 
 fn print_help() {
@@ -180,172 +198,345 @@ fn print_help() {
     println!("  caps                 - Send capabilities response");
     println!("  rn [callsign]        - Request real name");
     println!("  raw [packet]         - Send raw FSD packet");
+    println!("  expect [text] [time] - Wait for a line containing text (e.g. 5s, 500ms)");
+    println!("  sleep [ms]           - Pause, still answering the server");
     println!("  test                 - Run automated test sequence");
     println!("  quit, q, exit        - Disconnect and exit");
 }
 
-async fn send_identification(
-    writer: &mut tokio::net::tcp::OwnedWriteHalf,
-    callsign: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let packet = format!(
-        "$ID{}:SERVER:69d7:OpenFSD Test Client:3:2:{}:987654321\r\n",
-        callsign, DEFAULT_CID
-    );
-    println!("📤 {}", packet.trim_end());
-    writer.write_all(packet.as_bytes()).await?;
-    writer.flush().await?;
-    Ok(())
+/// Parse an `expect` timeout: a bare number of milliseconds, or with an
+/// `ms` or `s` suffix
+fn parse_timeout(value: &str) -> Option<Duration> {
+    if let Some(ms) = value.strip_suffix("ms") {
+        ms.parse().ok().map(Duration::from_millis)
+    } else if let Some(secs) = value.strip_suffix('s') {
+        secs.parse().ok().map(Duration::from_secs_f64)
+    } else {
+        value.parse().ok().map(Duration::from_millis)
+    }
 }
 
-async fn send_login(
-    writer: &mut tokio::net::tcp::OwnedWriteHalf,
-    callsign: &str,
-    client_type: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let packet = match client_type {
-        "atc" | "ATC" => {
-            // #AA(callsign):SERVER:(full name):(network ID):(password):(rating):(protocol version)
-            format!(
-                "#AA{}:SERVER:Test Controller:{}:password:5:100\r\n",
-                callsign, DEFAULT_CID
-            )
+/// One connection to the server and the state the commands share
+struct Session {
+    writer: OwnedWriteHalf,
+    incoming: mpsc::Receiver<String>,
+    /// Lines received since the last one an `expect` matched
+    received: VecDeque<String>,
+    connected: bool,
+    callsign: String,
+    cid: String,
+    password: String,
+    client_id: String,
+    client_key: Option<String>,
+    logged_in: bool,
+    /// Fail on unknown commands instead of printing a hint, for scripts
+    strict: bool,
+}
+
+impl Session {
+    /// Run one command; false means the session should end
+    async fn run_command(&mut self, input: &str) -> Result<bool> {
+        if input.is_empty() {
+            return Ok(true);
         }
-        _ => {
-            // #AP(callsign):SERVER:(network ID):(password):(rating):(protocol version):(num2):(full name ICAO)
-            format!(
-                "#AP{}:SERVER:{}:password:1:100:2:Test Pilot KJFK\r\n",
-                callsign, DEFAULT_CID
-            )
+
+        let parts: Vec<&str> = input.split_whitespace().collect();
+        match parts[0] {
+            "help" | "h" => {
+                print_help();
+            }
+            "quit" | "q" | "exit" => {
+                self.quit().await;
+                return Ok(false);
+            }
+            "id" => {
+                if parts.len() > 1 {
+                    self.callsign = parts[1].to_string();
+                }
+                self.send_identification().await?;
+            }
+            "login" => {
+                let client_type = parts.get(1).unwrap_or(&"pilot");
+                self.send_login(client_type).await?;
+                self.logged_in = true;
+            }
+            "logoff" => {
+                self.send_logoff().await?;
+                self.logged_in = false;
+            }
+            "pos" => {
+                let lat = parts.get(1).and_then(|s| s.parse().ok()).unwrap_or(40.6413);
+                let lon = parts
+                    .get(2)
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(-73.7781);
+                let alt = parts.get(3).and_then(|s| s.parse().ok()).unwrap_or(5000);
+                self.send_position(lat, lon, alt).await?;
+            }
+            "msg" => {
+                let parts: Vec<&str> = input.splitn(3, ' ').collect();
+                let to = parts.get(1).unwrap_or(&"*");
+                let message = parts.get(2).unwrap_or(&"Test message");
+                self.send_message(to, message).await?;
+            }
+            "fp" => {
+                self.send_flight_plan().await?;
+            }
+            "metar" => {
+                let icao = parts.get(1).unwrap_or(&"KJFK");
+                self.send_metar_request(icao).await?;
+            }
+            "caps" => {
+                self.send_caps_response().await?;
+            }
+            "rn" => {
+                let target = parts.get(1).unwrap_or(&"*");
+                self.send_realname_request(target).await?;
+            }
+            "raw" => {
+                let raw_packet = input.strip_prefix("raw ").unwrap_or("");
+                if !raw_packet.is_empty() {
+                    self.send(raw_packet).await?;
+                }
+            }
+            "expect" => {
+                // The timeout is the last word so the text may contain spaces
+                let (text, timeout) = input
+                    .strip_prefix("expect ")
+                    .and_then(|rest| rest.trim().rsplit_once(' '))
+                    .and_then(|(text, timeout)| Some((text.trim(), parse_timeout(timeout)?)))
+                    .ok_or("usage: expect <text> <timeout>")?;
+                self.expect(text, timeout).await?;
+            }
+            "sleep" => {
+                let ms = parts
+                    .get(1)
+                    .and_then(|s| s.parse().ok())
+                    .ok_or("usage: sleep <ms>")?;
+                self.sleep(Duration::from_millis(ms)).await?;
+            }
+            "test" => {
+                println!("🧪 Running automated test sequence...\n");
+                self.run_test_sequence().await?;
+                self.logged_in = true;
+            }
+            _ => {
+                if self.strict {
+                    return Err(format!("unknown command '{}'", parts[0]).into());
+                }
+                println!("❓ Unknown command. Type 'help' for available commands.");
+            }
         }
-    };
-    println!("📤 {}", packet.trim_end());
-    writer.write_all(packet.as_bytes()).await?;
-    writer.flush().await?;
-    Ok(())
-}
+        Ok(true)
+    }
 
-async fn send_logoff(
-    writer: &mut tokio::net::tcp::OwnedWriteHalf,
-    callsign: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let packet = format!("#DP{}:{}\r\n", callsign, DEFAULT_CID);
-    println!("📤 {}", packet.trim_end());
-    writer.write_all(packet.as_bytes()).await?;
-    writer.flush().await?;
-    Ok(())
-}
+    /// Print a line from the server, answer it if it needs an answer and
+    /// keep it for `expect`
+    async fn receive(&mut self, line: String) -> Result<()> {
+        println!("📥 {}", line);
 
-async fn send_position(
-    writer: &mut tokio::net::tcp::OwnedWriteHalf,
-    callsign: &str,
-    lat: f64,
-    lon: f64,
-    alt: i32,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let packet = format!(
-        "@N{}:1200:1:{}:{}:{}:250:414141414:30\r\n",
-        callsign, lat, lon, alt
-    );
-    println!("📤 {}", packet.trim_end());
-    writer.write_all(packet.as_bytes()).await?;
-    writer.flush().await?;
-    Ok(())
-}
+        if let Ok(packet) = Packet::parse(&line) {
+            if packet.destination.eq_ignore_ascii_case(&self.callsign) {
+                self.auto_respond(&packet).await?;
+            }
+        }
 
-async fn send_message(
-    writer: &mut tokio::net::tcp::OwnedWriteHalf,
-    callsign: &str,
-    to: &str,
-    message: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let packet = format!("#TM{}:{}:{}\r\n", callsign, to, message);
-    println!("📤 {}", packet.trim_end());
-    writer.write_all(packet.as_bytes()).await?;
-    writer.flush().await?;
-    Ok(())
-}
+        self.received.push_back(line);
+        Ok(())
+    }
 
-async fn send_flight_plan(
-    writer: &mut tokio::net::tcp::OwnedWriteHalf,
-    callsign: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let packet = format!(
-        "#FP{}:*:V:B738:420:KJFK:1200:1200:35000:KLAX:03:30:02:45:F:Remarks here\r\n",
-        callsign
-    );
-    println!("📤 {}", packet.trim_end());
-    writer.write_all(packet.as_bytes()).await?;
-    writer.flush().await?;
-    Ok(())
-}
+    /// Answer the requests a real client answers without asking its user
+    async fn auto_respond(&mut self, packet: &Packet) -> Result<()> {
+        match packet.command.as_str() {
+            "CQ" if packet.data.first().is_some_and(|d| d == "CAPS") => {
+                println!("🤖 Answering capabilities request");
+                self.send_caps_response().await?;
+            }
+            "PI" => {
+                println!("🤖 Answering ping from {}", packet.source);
+                let pong = format!(
+                    "$PO{}:{}:{}",
+                    self.callsign,
+                    packet.source,
+                    packet.data.join(":")
+                );
+                self.send(&pong).await?;
+            }
+            "ZC" => match (&self.client_key, packet.data.first()) {
+                (Some(key), Some(challenge)) => {
+                    println!("🤖 Answering auth challenge");
+                    let response = format!(
+                        "$ZR{}:{}:{}",
+                        self.callsign,
+                        packet.source,
+                        compute_response(key, challenge)
+                    );
+                    self.send(&response).await?;
+                }
+                _ => println!("⚠️  Auth challenge received but no --client-key given"),
+            },
+            _ => {}
+        }
+        Ok(())
+    }
 
-async fn send_metar_request(
-    writer: &mut tokio::net::tcp::OwnedWriteHalf,
-    callsign: &str,
-    icao: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let packet = format!("$AX{}:SERVER:METAR:{}\r\n", callsign, icao);
-    println!("📤 {}", packet.trim_end());
-    writer.write_all(packet.as_bytes()).await?;
-    writer.flush().await?;
-    Ok(())
-}
+    /// Wait until a received line contains `text`, looking first at the
+    /// lines that arrived since the last match
+    async fn expect(&mut self, text: &str, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(index) = self.received.iter().position(|line| line.contains(text)) {
+                // Later expectations only see what came after this line
+                self.received.drain(..=index);
+                println!("✔️  Got \"{}\"", text);
+                return Ok(());
+            }
+            if !self.connected {
+                return Err(
+                    format!("expected \"{}\" but the server closed the connection", text).into(),
+                );
+            }
 
-async fn send_caps_response(
-    writer: &mut tokio::net::tcp::OwnedWriteHalf,
-    callsign: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let packet = format!(
-        "$CR{}:SERVER:CAPS:ATCINFO=1:MODELDESC=1:ACCONFIG=1\r\n",
-        callsign
-    );
-    println!("📤 {}", packet.trim_end());
-    writer.write_all(packet.as_bytes()).await?;
-    writer.flush().await?;
-    Ok(())
-}
+            match tokio::time::timeout_at(deadline, self.incoming.recv()).await {
+                Ok(Some(line)) => self.receive(line).await?,
+                Ok(None) => self.connected = false,
+                Err(_) => {
+                    return Err(format!("expected \"{}\" within {:?}", text, timeout).into());
+                }
+            }
+        }
+    }
 
-async fn send_realname_request(
-    writer: &mut tokio::net::tcp::OwnedWriteHalf,
-    callsign: &str,
-    target: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let packet = format!("$CQ{}:{}:RN\r\n", callsign, target);
-    println!("📤 {}", packet.trim_end());
-    writer.write_all(packet.as_bytes()).await?;
-    writer.flush().await?;
-    Ok(())
-}
+    /// Pause without leaving the server's requests unanswered
+    async fn sleep(&mut self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        while self.connected {
+            match tokio::time::timeout_at(deadline, self.incoming.recv()).await {
+                Ok(Some(line)) => self.receive(line).await?,
+                Ok(None) => self.connected = false,
+                Err(_) => return Ok(()),
+            }
+        }
+        tokio::time::sleep_until(deadline).await;
+        Ok(())
+    }
 
-async fn run_test_sequence(
-    writer: &mut tokio::net::tcp::OwnedWriteHalf,
-    callsign: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    println!("1️⃣  Sending identification...");
-    send_identification(writer, callsign).await?;
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    async fn quit(&mut self) {
+        println!("👋 Disconnecting...");
+        if self.logged_in && self.connected {
+            let _ = self.send_logoff().await;
+        }
+    }
+
+    async fn send(&mut self, packet: &str) -> Result<()> {
+        println!("📤 {}", packet);
+        self.writer
+            .write_all(format!("{}\r\n", packet).as_bytes())
+            .await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    async fn send_identification(&mut self) -> Result<()> {
+        let packet = format!(
+            "$ID{}:SERVER:{}:OpenFSD Test Client:3:2:{}:987654321",
+            self.callsign, self.client_id, self.cid
+        );
+        self.send(&packet).await
+    }
+
+    async fn send_login(&mut self, client_type: &str) -> Result<()> {
+        let packet = match client_type {
+            "atc" | "ATC" => {
+                // #AA(callsign):SERVER:(full name):(network ID):(password):(rating):(protocol version)
+                format!(
+                    "#AA{}:SERVER:Test Controller:{}:{}:5:100",
+                    self.callsign, self.cid, self.password
+                )
+            }
+            _ => {
+                // #AP(callsign):SERVER:(network ID):(password):(rating):(protocol version):(num2):(full name ICAO)
+                format!(
+                    "#AP{}:SERVER:{}:{}:1:100:2:Test Pilot KJFK",
+                    self.callsign, self.cid, self.password
+                )
+            }
+        };
+        self.send(&packet).await
+    }
+
+    async fn send_logoff(&mut self) -> Result<()> {
+        let packet = format!("#DP{}:{}", self.callsign, self.cid);
+        self.send(&packet).await
+    }
+
+    async fn send_position(&mut self, lat: f64, lon: f64, alt: i32) -> Result<()> {
+        let packet = format!(
+            "@N{}:1200:1:{}:{}:{}:250:414141414:30",
+            self.callsign, lat, lon, alt
+        );
+        self.send(&packet).await
+    }
 
-    println!("\n2️⃣  Logging in as pilot...");
-    send_login(writer, callsign, "pilot").await?;
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    async fn send_message(&mut self, to: &str, message: &str) -> Result<()> {
+        let packet = format!("#TM{}:{}:{}", self.callsign, to, message);
+        self.send(&packet).await
+    }
+
+    async fn send_flight_plan(&mut self) -> Result<()> {
+        let packet = format!(
+            "#FP{}:*:V:B738:420:KJFK:1200:1200:35000:KLAX:03:30:02:45:F:Remarks here",
+            self.callsign
+        );
+        self.send(&packet).await
+    }
 
-    println!("\n3️⃣  Sending position update...");
-    send_position(writer, callsign, 40.6413, -73.7781, 5000).await?;
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    async fn send_metar_request(&mut self, icao: &str) -> Result<()> {
+        let packet = format!("$AX{}:SERVER:METAR:{}", self.callsign, icao);
+        self.send(&packet).await
+    }
 
-    println!("\n4️⃣  Sending broadcast message...");
-    send_message(writer, callsign, "*", "Hello from test client!").await?;
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    async fn send_caps_response(&mut self) -> Result<()> {
+        let packet = format!(
+            "$CR{}:SERVER:CAPS:ATCINFO=1:MODELDESC=1:ACCONFIG=1",
+            self.callsign
+        );
+        self.send(&packet).await
+    }
+
+    async fn send_realname_request(&mut self, target: &str) -> Result<()> {
+        let packet = format!("$CQ{}:{}:RN", self.callsign, target);
+        self.send(&packet).await
+    }
 
-    println!("\n5️⃣  Filing flight plan...");
-    send_flight_plan(writer, callsign).await?;
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    async fn run_test_sequence(&mut self) -> Result<()> {
+        let pause = Duration::from_millis(500);
 
-    println!("\n6️⃣  Requesting METAR...");
-    send_metar_request(writer, callsign, "KJFK").await?;
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        println!("1️⃣  Sending identification...");
+        self.send_identification().await?;
+        self.sleep(pause).await?;
 
-    println!("\n✅ Test sequence completed!");
-    Ok(())
+        println!("\n2️⃣  Logging in as pilot...");
+        self.send_login("pilot").await?;
+        self.sleep(pause).await?;
+
+        println!("\n3️⃣  Sending position update...");
+        self.send_position(40.6413, -73.7781, 5000).await?;
+        self.sleep(pause).await?;
+
+        println!("\n4️⃣  Sending broadcast message...");
+        self.send_message("*", "Hello from test client!").await?;
+        self.sleep(pause).await?;
+
+        println!("\n5️⃣  Filing flight plan...");
+        self.send_flight_plan().await?;
+        self.sleep(pause).await?;
+
+        println!("\n6️⃣  Requesting METAR...");
+        self.send_metar_request("KJFK").await?;
+        self.sleep(pause).await?;
+
+        println!("\n✅ Test sequence completed!");
+        Ok(())
+    }
 }
@@ -0,0 +1,633 @@
+/// AI traffic generator
+///
+/// Fills an empty server with bot aircraft for controller training. Each bot
+/// files a flight plan between two airports in the region, climbs, cruises
+/// and descends along a direct track, squawks the code a controller assigns
+/// and answers private messages with a canned reply. Ctrl-C logs every bot
+/// off.
+///
+/// Bots log in with consecutive network IDs from --cid-start, so the server
+/// needs accounts for that range or guest mode. More than a handful of bots
+/// from one machine also needs higher [limits] per-address caps.
+///
+/// Usage: cargo run --example traffic_bots -- --count 20 --region 18,100,45,135
+use clap::Parser;
+use openfsd::flight_plan::FlightPlan;
+use openfsd::packet::{Packet, PacketType};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+const EARTH_RADIUS_NM: f64 = 3440.065;
+const UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(15);
+/// Pause between logins, so the server's unauthenticated connection cap
+/// isn't hit
+const LOGIN_SPACING: Duration = Duration::from_millis(250);
+/// Shortest route worth flying with a climb and a descent
+const MIN_ROUTE_NM: f64 = 100.0;
+/// Longest route the bundled types would plausibly fly
+const MAX_ROUTE_NM: f64 = 2500.0;
+const BOT_REPLY: &str = "I am a bot flying background traffic and can't follow instructions.";
+
+struct Airport {
+    icao: &'static str,
+    latitude: f64,
+    longitude: f64,
+    elevation: f64,
+}
+
+const fn airport(icao: &'static str, latitude: f64, longitude: f64, elevation: f64) -> Airport {
+    Airport {
+        icao,
+        latitude,
+        longitude,
+        elevation,
+    }
+}
+
+const AIRPORTS: &[Airport] = &[
+    airport("ZBAA", 40.0801, 116.5846, 116.0),
+    airport("ZSPD", 31.1434, 121.8052, 13.0),
+    airport("ZSSS", 31.1979, 121.3363, 10.0),
+    airport("ZGGG", 23.3924, 113.2988, 50.0),
+    airport("ZGSZ", 22.6393, 113.8107, 13.0),
+    airport("ZUUU", 30.5785, 103.9471, 1625.0),
+    airport("ZLXY", 34.4471, 108.7516, 1572.0),
+    airport("ZPPP", 25.1019, 102.9292, 6903.0),
+    airport("VHHH", 22.3080, 113.9185, 28.0),
+    airport("RCTP", 25.0777, 121.2328, 106.0),
+    airport("RKSI", 37.4691, 126.4505, 23.0),
+    airport("RJTT", 35.5523, 139.7798, 35.0),
+    airport("RJAA", 35.7647, 140.3864, 141.0),
+    airport("RJBB", 34.4273, 135.2440, 26.0),
+    airport("WSSS", 1.3502, 103.9940, 22.0),
+    airport("VTBS", 13.6811, 100.7475, 5.0),
+    airport("EGLL", 51.4706, -0.4619, 83.0),
+    airport("LFPG", 49.0097, 2.5479, 392.0),
+    airport("EHAM", 52.3105, 4.7683, -11.0),
+    airport("EDDF", 50.0333, 8.5706, 364.0),
+    airport("LEMD", 40.4719, -3.5626, 1998.0),
+    airport("KJFK", 40.6413, -73.7781, 13.0),
+    airport("KBOS", 42.3656, -71.0096, 20.0),
+    airport("KORD", 41.9742, -87.9073, 672.0),
+    airport("KATL", 33.6407, -84.4277, 1026.0),
+    airport("KLAX", 33.9416, -118.4085, 128.0),
+    airport("KSFO", 37.6213, -122.3790, 13.0),
+    airport("YSSY", -33.9461, 151.1772, 21.0),
+    airport("YMML", -37.6690, 144.8410, 434.0),
+];
+
+const AIRLINES: &[&str] = &[
+    "CCA", "CES", "CSN", "CHH", "CXA", "CPA", "JAL", "ANA", "KAL", "SIA", "DLH", "BAW", "AFR",
+    "UAL", "DAL", "QFA",
+];
+
+/// Type designator and cruise speed in knots
+const AIRCRAFT: &[(&str, f64)] = &[
+    ("B738", 450.0),
+    ("A320", 447.0),
+    ("A321", 450.0),
+    ("A333", 470.0),
+    ("B789", 488.0),
+    ("A359", 488.0),
+    ("B77W", 490.0),
+];
+
+/// Spawn bot aircraft for controller training
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Server to connect to
+    #[arg(long, default_value = "127.0.0.1:6809")]
+    server: String,
+
+    /// Number of bots
+    #[arg(long, default_value_t = 10)]
+    count: usize,
+
+    /// Only use airports in MIN_LAT,MIN_LON,MAX_LAT,MAX_LON
+    #[arg(long, default_value = "-90,-180,90,180", value_parser = parse_region)]
+    region: Region,
+
+    /// Cruise speed in knots; by default each type flies its usual speed
+    #[arg(long)]
+    speed: Option<f64>,
+
+    /// Network ID of the first bot
+    #[arg(long, default_value_t = 1_800_000)]
+    cid_start: u64,
+
+    /// Password of every bot; any password works in guest mode
+    #[arg(long, default_value = "traffic")]
+    password: String,
+
+    /// Client ID the bots log in with, which an enforcing server must know
+    #[arg(long, default_value = "88e4")]
+    client_id: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    min_latitude: f64,
+    min_longitude: f64,
+    max_latitude: f64,
+    max_longitude: f64,
+}
+
+impl Region {
+    fn contains(&self, airport: &Airport) -> bool {
+        (self.min_latitude..=self.max_latitude).contains(&airport.latitude)
+            && (self.min_longitude..=self.max_longitude).contains(&airport.longitude)
+    }
+}
+
+fn parse_region(s: &str) -> Result<Region, String> {
+    let values: Vec<f64> = s
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("invalid number: {}", e))?;
+    let [min_latitude, min_longitude, max_latitude, max_longitude] = values[..] else {
+        return Err("expected MIN_LAT,MIN_LON,MAX_LAT,MAX_LON".to_string());
+    };
+    if min_latitude >= max_latitude || min_longitude >= max_longitude {
+        return Err("minimums must be below maximums".to_string());
+    }
+    Ok(Region {
+        min_latitude,
+        min_longitude,
+        max_latitude,
+        max_longitude,
+    })
+}
+
+/// One bot aircraft and where it is on its current flight
+struct Bot {
+    callsign: String,
+    cid: String,
+    aircraft: &'static str,
+    cruise_speed: f64,
+    cruise_altitude: f64,
+    origin: &'static Airport,
+    destination: &'static Airport,
+    latitude: f64,
+    longitude: f64,
+    altitude: f64,
+    ground_speed: f64,
+    heading: f64,
+    squawk: String,
+}
+
+impl Bot {
+    fn new(index: usize, cli: &Cli, airports: &[&'static Airport], rng: &mut StdRng) -> Self {
+        let (aircraft, speed) = *AIRCRAFT.choose(rng).unwrap();
+        let origin = *airports.choose(rng).unwrap();
+        let mut bot = Self {
+            callsign: format!("{}{}", AIRLINES.choose(rng).unwrap(), 1001 + index),
+            cid: (cli.cid_start + index as u64).to_string(),
+            aircraft,
+            cruise_speed: cli.speed.unwrap_or(speed),
+            cruise_altitude: 0.0,
+            origin,
+            destination: origin,
+            latitude: origin.latitude,
+            longitude: origin.longitude,
+            altitude: origin.elevation,
+            ground_speed: 0.0,
+            heading: 0.0,
+            squawk: "2000".to_string(),
+        };
+        bot.next_flight(airports, rng);
+        bot
+    }
+
+    /// Pick a destination from where the bot is and a cruise level for it
+    fn next_flight(&mut self, airports: &[&'static Airport], rng: &mut StdRng) {
+        self.origin = self.destination;
+        self.destination = routes_from(self.origin, airports).choose(rng).unwrap();
+        let levels = if self.route_nm() < 300.0 {
+            24..=30
+        } else {
+            32..=40
+        };
+        self.cruise_altitude = rng.gen_range(levels) as f64 * 1000.0;
+        self.squawk = "2000".to_string();
+    }
+
+    fn route_nm(&self) -> f64 {
+        distance_nm(
+            self.origin.latitude,
+            self.origin.longitude,
+            self.destination,
+        )
+    }
+
+    /// Fly on for `elapsed`; true once the bot reaches its destination
+    fn step(&mut self, elapsed: Duration) -> bool {
+        let remaining = distance_nm(self.latitude, self.longitude, self.destination);
+        let to_lose = (self.altitude - self.destination.elevation).max(0.0);
+        // Three miles per thousand feet to lose
+        let descending = remaining <= to_lose / 1000.0 * 3.0;
+
+        self.ground_speed = if self.altitude < 10_000.0 {
+            self.cruise_speed.min(250.0)
+        } else {
+            self.cruise_speed
+        };
+        let step = self.ground_speed * elapsed.as_secs_f64() / 3600.0;
+        if step >= remaining {
+            self.latitude = self.destination.latitude;
+            self.longitude = self.destination.longitude;
+            self.altitude = self.destination.elevation;
+            self.ground_speed = 0.0;
+            return true;
+        }
+
+        self.heading = bearing(self.latitude, self.longitude, self.destination);
+        (self.latitude, self.longitude) =
+            destination_point(self.latitude, self.longitude, self.heading, step);
+        if descending {
+            let on_path = self.destination.elevation + (remaining - step) / 3.0 * 1000.0;
+            self.altitude = self.altitude.min(on_path);
+        } else {
+            let climb = 2000.0 * elapsed.as_secs_f64() / 60.0;
+            self.altitude = (self.altitude + climb).min(self.cruise_altitude);
+        }
+        false
+    }
+
+    fn flight_plan(&self) -> FlightPlan {
+        let minutes = (self.route_nm() / self.cruise_speed * 60.0) as u32 + 20;
+        let departure_time = chrono::Utc::now().format("%H%M").to_string();
+        FlightPlan {
+            flight_rules: "I".to_string(),
+            aircraft: self.aircraft.to_string(),
+            cruise_speed: format!("{:.0}", self.cruise_speed),
+            departure: self.origin.icao.to_string(),
+            departure_time: departure_time.clone(),
+            actual_departure_time: departure_time,
+            altitude: format!("FL{:.0}", self.cruise_altitude / 100.0),
+            destination: self.destination.icao.to_string(),
+            hours_enroute: (minutes / 60).to_string(),
+            minutes_enroute: (minutes % 60).to_string(),
+            hours_fuel: (minutes / 60 + 1).to_string(),
+            minutes_fuel: (minutes % 60).to_string(),
+            alternate: String::new(),
+            remarks: "/V/ AI TRAFFIC BOT".to_string(),
+            route: "DCT".to_string(),
+            route_elements: Vec::new(),
+        }
+        .with_route_elements()
+    }
+
+    /// @N:(callsign):(squawk):(rating):(lat):(lon):(alt):(groundspeed):(pbh):(flags)
+    fn position(&self) -> Packet {
+        // Heading is packed into bits 2-11 as a fraction of 1024
+        let pbh = ((self.heading / 360.0 * 1024.0) as u32 & 0x3ff) << 2;
+        Packet {
+            packet_type: PacketType::PilotUpdate,
+            command: "N".to_string(),
+            source: String::new(),
+            destination: self.callsign.clone(),
+            data: vec![
+                self.squawk.clone(),
+                "1".to_string(),
+                format!("{:.5}", self.latitude),
+                format!("{:.5}", self.longitude),
+                format!("{:.0}", self.altitude),
+                format!("{:.0}", self.ground_speed),
+                pbh.to_string(),
+                "0".to_string(),
+            ],
+        }
+    }
+}
+
+/// Airports a flight from `from` could go to
+fn routes_from(from: &Airport, airports: &[&'static Airport]) -> Vec<&'static Airport> {
+    airports
+        .iter()
+        .copied()
+        .filter(|to| {
+            let distance = distance_nm(from.latitude, from.longitude, to);
+            (MIN_ROUTE_NM..=MAX_ROUTE_NM).contains(&distance)
+        })
+        .collect()
+}
+
+fn distance_nm(latitude: f64, longitude: f64, to: &Airport) -> f64 {
+    let (lat1, lat2) = (latitude.to_radians(), to.latitude.to_radians());
+    let dlon = (to.longitude - longitude).to_radians();
+    let a =
+        ((lat2 - lat1) / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_NM * a.sqrt().asin()
+}
+
+/// Initial great-circle bearing to an airport, in degrees true
+fn bearing(latitude: f64, longitude: f64, to: &Airport) -> f64 {
+    let (lat1, lat2) = (latitude.to_radians(), to.latitude.to_radians());
+    let dlon = (to.longitude - longitude).to_radians();
+    let y = dlon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+    (y.atan2(x).to_degrees() + 360.0) % 360.0
+}
+
+fn destination_point(latitude: f64, longitude: f64, heading: f64, distance: f64) -> (f64, f64) {
+    let angle = distance / EARTH_RADIUS_NM;
+    let (lat1, lon1, heading) = (
+        latitude.to_radians(),
+        longitude.to_radians(),
+        heading.to_radians(),
+    );
+    let lat2 = (lat1.sin() * angle.cos() + lat1.cos() * angle.sin() * heading.cos()).asin();
+    let lon2 = lon1
+        + (heading.sin() * angle.sin() * lat1.cos()).atan2(angle.cos() - lat1.sin() * lat2.sin());
+    (
+        lat2.to_degrees(),
+        (lon2.to_degrees() + 540.0) % 360.0 - 180.0,
+    )
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let airports: Vec<&'static Airport> =
+        AIRPORTS.iter().filter(|a| cli.region.contains(a)).collect();
+    // Every airport is a possible start, so drop any with no route out
+    let airports: Vec<_> = airports
+        .iter()
+        .copied()
+        .filter(|from| !routes_from(from, &airports).is_empty())
+        .collect();
+    if airports.is_empty() {
+        let known: Vec<_> = AIRPORTS.iter().map(|a| a.icao).collect();
+        return Err(format!(
+            "the region needs two airports {} to {} nm apart; known airports: {}",
+            MIN_ROUTE_NM,
+            MAX_ROUTE_NM,
+            known.join(" ")
+        )
+        .into());
+    }
+
+    println!(
+        "🤖 Spawning {} bots at {} airports, Ctrl-C to stop",
+        cli.count,
+        airports.len()
+    );
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let mut rng = StdRng::from_entropy();
+    let mut bots = JoinSet::new();
+    let mut ctrl_c = std::pin::pin!(tokio::signal::ctrl_c());
+    let mut stopping = false;
+
+    for index in 0..cli.count {
+        let bot = Bot::new(index, &cli, &airports, &mut rng);
+        let task = fly(
+            bot,
+            cli.server.clone(),
+            cli.client_id.clone(),
+            cli.password.clone(),
+            airports.clone(),
+            shutdown.clone(),
+        );
+        bots.spawn(task);
+        tokio::select! {
+            _ = tokio::time::sleep(LOGIN_SPACING) => {}
+            _ = &mut ctrl_c => {
+                stopping = true;
+                break;
+            }
+        }
+    }
+
+    while !stopping {
+        tokio::select! {
+            _ = &mut ctrl_c => stopping = true,
+            done = bots.join_next() => {
+                if done.is_none() {
+                    println!("All bots have stopped");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    println!("\n👋 Logging off {} bots...", bots.len());
+    let _ = shutdown_tx.send(true);
+    let _ = tokio::time::timeout(Duration::from_secs(5), async {
+        while bots.join_next().await.is_some() {}
+    })
+    .await;
+    Ok(())
+}
+
+/// Log in one bot and fly it until shutdown
+async fn fly(
+    mut bot: Bot,
+    server: String,
+    client_id: String,
+    password: String,
+    airports: Vec<&'static Airport>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    if let Err(e) = run_bot(
+        &mut bot,
+        &server,
+        &client_id,
+        &password,
+        &airports,
+        &mut shutdown,
+    )
+    .await
+    {
+        eprintln!("❌ {}: {}", bot.callsign, e);
+    }
+}
+
+async fn run_bot(
+    bot: &mut Bot,
+    server: &str,
+    client_id: &str,
+    password: &str,
+    airports: &[&'static Airport],
+    shutdown: &mut watch::Receiver<bool>,
+) -> Result<(), String> {
+    let stream = TcpStream::connect(server)
+        .await
+        .map_err(|e| format!("cannot connect to {}: {}", server, e))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut rng = StdRng::from_entropy();
+    let callsign = bot.callsign.clone();
+
+    let identify = Packet {
+        packet_type: PacketType::Request,
+        command: "ID".to_string(),
+        source: callsign.clone(),
+        destination: "SERVER".to_string(),
+        data: vec![
+            client_id.to_string(),
+            "OpenFSD traffic bot".to_string(),
+            "3".to_string(),
+            "2".to_string(),
+            bot.cid.clone(),
+            rng.gen_range(100_000_000..999_999_999u32).to_string(),
+        ],
+    };
+    let login = Packet {
+        packet_type: PacketType::Client,
+        command: "AP".to_string(),
+        source: callsign.clone(),
+        destination: "SERVER".to_string(),
+        data: vec![
+            bot.cid.clone(),
+            password.to_string(),
+            "1".to_string(),
+            "100".to_string(),
+            "1".to_string(),
+            "Traffic Bot".to_string(),
+        ],
+    };
+    send(&mut writer, &identify).await?;
+    send(&mut writer, &login).await?;
+
+    // The server asks for capabilities once the login is accepted
+    let accepted = tokio::time::timeout(LOGIN_TIMEOUT, async {
+        loop {
+            let line = lines.next_line().await.map_err(|e| e.to_string())?;
+            let Some(line) = line else {
+                return Err("the server closed the connection".to_string());
+            };
+            let Ok(packet) = Packet::parse(&line) else {
+                continue;
+            };
+            if packet.command == "ER" && packet.destination == callsign {
+                return Err(format!("login refused: {}", line));
+            }
+            let done = is_caps_request(&packet, &callsign);
+            respond(bot, &packet, &mut writer).await?;
+            if done {
+                return Ok(());
+            }
+        }
+    })
+    .await;
+    accepted.map_err(|_| "login timed out".to_string())??;
+
+    send(&mut writer, &bot.flight_plan().to_packet(&callsign)).await?;
+    announce(bot);
+
+    let mut ticker = tokio::time::interval(UPDATE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                if bot.step(UPDATE_INTERVAL) {
+                    println!("🛬 {} arrived at {}", callsign, bot.destination.icao);
+                    bot.next_flight(airports, &mut rng);
+                    send(&mut writer, &bot.flight_plan().to_packet(&callsign)).await?;
+                    announce(bot);
+                }
+                send(&mut writer, &bot.position()).await?;
+            }
+            line = lines.next_line() => {
+                match line.map_err(|e| e.to_string())? {
+                    Some(line) => {
+                        if let Ok(packet) = Packet::parse(&line) {
+                            respond(bot, &packet, &mut writer).await?;
+                        }
+                    }
+                    None => return Err("the server closed the connection".to_string()),
+                }
+            }
+            _ = shutdown.changed() => {
+                // #DP(callsign):(CID)
+                let logoff = Packet {
+                    packet_type: PacketType::Client,
+                    command: "DP".to_string(),
+                    source: callsign.clone(),
+                    destination: bot.cid.clone(),
+                    data: Vec::new(),
+                };
+                send(&mut writer, &logoff).await?;
+                let _ = writer.shutdown().await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn announce(bot: &Bot) {
+    println!(
+        "🛫 {} {} {} → {} FL{:.0} ({:.0} nm)",
+        bot.callsign,
+        bot.aircraft,
+        bot.origin.icao,
+        bot.destination.icao,
+        bot.cruise_altitude / 100.0,
+        bot.route_nm()
+    );
+}
+
+/// $CQSERVER:(callsign):CAPS
+fn is_caps_request(packet: &Packet, callsign: &str) -> bool {
+    packet.command == "CQ"
+        && packet.destination == callsign
+        && packet.data.first().map(String::as_str) == Some("CAPS")
+}
+
+/// Answer whatever the server or a controller expects from a pilot
+async fn respond(
+    bot: &mut Bot,
+    packet: &Packet,
+    writer: &mut OwnedWriteHalf,
+) -> Result<(), String> {
+    let callsign = bot.callsign.clone();
+    let reply = |command: &str, data: Vec<String>| Packet {
+        packet_type: packet.packet_type.clone(),
+        command: command.to_string(),
+        source: callsign.clone(),
+        destination: packet.source.clone(),
+        data,
+    };
+
+    match (packet.command.as_str(), packet.data.as_slice()) {
+        ("CQ", _) if is_caps_request(packet, &callsign) => {
+            let caps = vec!["CAPS".to_string(), "VERSION=1".to_string()];
+            send(writer, &reply("CR", caps)).await
+        }
+        // $PI(from):(to):(data) is answered with $PO carrying the same data
+        ("PI", data) if packet.destination == callsign => {
+            send(writer, &reply("PO", data.to_vec())).await
+        }
+        ("TM", [text, ..]) if packet.destination == callsign && packet.source != "server" => {
+            println!("💬 {} ← {}: {}", callsign, packet.source, text);
+            send(writer, &reply("TM", vec![BOT_REPLY.to_string()])).await
+        }
+        // #PC(controller):(to):CCP:BC:(callsign):(code); code 0 acknowledges
+        // a flight plan
+        ("PC", [ccp, bc, target, code, ..])
+            if ccp == "CCP" && bc == "BC" && *target == callsign && code != "0" =>
+        {
+            if code.len() == 4 && code.chars().all(|c| ('0'..='7').contains(&c)) {
+                println!("🔢 {} squawking {} for {}", callsign, code, packet.source);
+                bot.squawk = code.clone();
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn send(writer: &mut OwnedWriteHalf, packet: &Packet) -> Result<(), String> {
+    writer
+        .write_all(packet.format().as_bytes())
+        .await
+        .map_err(|e| format!("send failed: {}", e))
+}
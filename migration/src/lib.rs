@@ -2,6 +2,26 @@ pub use sea_orm_migration::prelude::*;
 
 mod m20250101_000001_create_users;
 mod m20250101_000002_create_client_whitelist;
+mod m20250101_000003_add_client_whitelist_min_version;
+mod m20250101_000004_add_client_whitelist_client_key;
+mod m20250101_000005_create_login_tokens;
+mod m20250101_000006_create_prefiled_flight_plans;
+mod m20250101_000007_create_position_snapshots;
+mod m20250101_000008_create_stats_daily;
+mod m20250101_000009_create_flight_tracks;
+mod m20250101_000010_add_flight_tracks_aircraft;
+mod m20250101_000011_add_users_deleted_at;
+mod m20250101_000012_add_users_last_login_at;
+mod m20250101_000013_create_sessions;
+mod m20250101_000014_create_server_messages;
+mod m20250101_000015_create_audit_log;
+mod m20250101_000016_create_user_notes;
+mod m20250101_000017_add_users_disabled_at;
+mod m20250101_000018_add_users_email;
+mod m20250101_000019_create_bans;
+mod m20250101_000020_add_sessions_summary;
+mod m20250101_000021_create_airports;
+mod m20250101_000022_create_announcements;
 
 pub struct Migrator;
 
@@ -11,6 +31,26 @@ impl MigratorTrait for Migrator {
         vec![
             Box::new(m20250101_000001_create_users::Migration),
             Box::new(m20250101_000002_create_client_whitelist::Migration),
+            Box::new(m20250101_000003_add_client_whitelist_min_version::Migration),
+            Box::new(m20250101_000004_add_client_whitelist_client_key::Migration),
+            Box::new(m20250101_000005_create_login_tokens::Migration),
+            Box::new(m20250101_000006_create_prefiled_flight_plans::Migration),
+            Box::new(m20250101_000007_create_position_snapshots::Migration),
+            Box::new(m20250101_000008_create_stats_daily::Migration),
+            Box::new(m20250101_000009_create_flight_tracks::Migration),
+            Box::new(m20250101_000010_add_flight_tracks_aircraft::Migration),
+            Box::new(m20250101_000011_add_users_deleted_at::Migration),
+            Box::new(m20250101_000012_add_users_last_login_at::Migration),
+            Box::new(m20250101_000013_create_sessions::Migration),
+            Box::new(m20250101_000014_create_server_messages::Migration),
+            Box::new(m20250101_000015_create_audit_log::Migration),
+            Box::new(m20250101_000016_create_user_notes::Migration),
+            Box::new(m20250101_000017_add_users_disabled_at::Migration),
+            Box::new(m20250101_000018_add_users_email::Migration),
+            Box::new(m20250101_000019_create_bans::Migration),
+            Box::new(m20250101_000020_add_sessions_summary::Migration),
+            Box::new(m20250101_000021_create_airports::Migration),
+            Box::new(m20250101_000022_create_announcements::Migration),
         ]
     }
 }
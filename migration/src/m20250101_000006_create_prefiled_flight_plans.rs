@@ -0,0 +1,173 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(PrefiledFlightPlans::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::NetworkId)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::Callsign)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::FlightRules)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::Aircraft)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::CruiseSpeed)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::Departure)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::DepartureTime)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::ActualDepartureTime)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::Altitude)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::Destination)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::HoursEnroute)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::MinutesEnroute)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::HoursFuel)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::MinutesFuel)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::Alternate)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::Remarks)
+                            .text()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(PrefiledFlightPlans::Route).text().not_null())
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::ExpiresAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::ConsumedAt)
+                            .timestamp_with_time_zone()
+                            .null(),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                            .default(Expr::current_timestamp()),
+                    )
+                    .col(
+                        ColumnDef::new(PrefiledFlightPlans::UpdatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                            .default(Expr::current_timestamp()),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_prefiled_flight_plans_network_id_callsign")
+                    .table(PrefiledFlightPlans::Table)
+                    .col(PrefiledFlightPlans::NetworkId)
+                    .col(PrefiledFlightPlans::Callsign)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PrefiledFlightPlans::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum PrefiledFlightPlans {
+    Table,
+    Id,
+    NetworkId,
+    Callsign,
+    FlightRules,
+    Aircraft,
+    CruiseSpeed,
+    Departure,
+    DepartureTime,
+    ActualDepartureTime,
+    Altitude,
+    Destination,
+    HoursEnroute,
+    MinutesEnroute,
+    HoursFuel,
+    MinutesFuel,
+    Alternate,
+    Remarks,
+    Route,
+    ExpiresAt,
+    ConsumedAt,
+    CreatedAt,
+    UpdatedAt,
+}
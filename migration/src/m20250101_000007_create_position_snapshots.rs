@@ -0,0 +1,102 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(PositionSnapshots::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(PositionSnapshots::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(PositionSnapshots::NetworkId)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PositionSnapshots::Callsign)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PositionSnapshots::Latitude)
+                            .double()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PositionSnapshots::Longitude)
+                            .double()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PositionSnapshots::Altitude)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(PositionSnapshots::Squawk).string().null())
+                    .col(
+                        ColumnDef::new(PositionSnapshots::AssignedSquawk)
+                            .string()
+                            .null(),
+                    )
+                    .col(
+                        ColumnDef::new(PositionSnapshots::TrackingController)
+                            .string()
+                            .null(),
+                    )
+                    .col(ColumnDef::new(PositionSnapshots::FlightPlan).text().null())
+                    .col(
+                        ColumnDef::new(PositionSnapshots::UpdatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                            .default(Expr::current_timestamp()),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_position_snapshots_network_id_callsign")
+                    .table(PositionSnapshots::Table)
+                    .col(PositionSnapshots::NetworkId)
+                    .col(PositionSnapshots::Callsign)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PositionSnapshots::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum PositionSnapshots {
+    Table,
+    Id,
+    NetworkId,
+    Callsign,
+    Latitude,
+    Longitude,
+    Altitude,
+    Squawk,
+    AssignedSquawk,
+    TrackingController,
+    FlightPlan,
+    UpdatedAt,
+}
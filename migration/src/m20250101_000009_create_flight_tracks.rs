@@ -0,0 +1,92 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(FlightTracks::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(FlightTracks::Id)
+                            .big_integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(FlightTracks::SessionId).string().not_null())
+                    .col(ColumnDef::new(FlightTracks::Callsign).string().not_null())
+                    .col(ColumnDef::new(FlightTracks::NetworkId).string().not_null())
+                    .col(
+                        ColumnDef::new(FlightTracks::RecordedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(FlightTracks::Latitude).double().not_null())
+                    .col(ColumnDef::new(FlightTracks::Longitude).double().not_null())
+                    .col(ColumnDef::new(FlightTracks::Altitude).integer().not_null())
+                    .col(
+                        ColumnDef::new(FlightTracks::Groundspeed)
+                            .integer()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_flight_tracks_session_id")
+                    .table(FlightTracks::Table)
+                    .col(FlightTracks::SessionId)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_flight_tracks_callsign_recorded_at")
+                    .table(FlightTracks::Table)
+                    .col(FlightTracks::Callsign)
+                    .col(FlightTracks::RecordedAt)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_flight_tracks_recorded_at")
+                    .table(FlightTracks::Table)
+                    .col(FlightTracks::RecordedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(FlightTracks::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum FlightTracks {
+    Table,
+    Id,
+    SessionId,
+    Callsign,
+    NetworkId,
+    RecordedAt,
+    Latitude,
+    Longitude,
+    Altitude,
+    Groundspeed,
+}
@@ -0,0 +1,71 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(UserNotes::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(UserNotes::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(UserNotes::UserId).integer().not_null())
+                    .col(ColumnDef::new(UserNotes::AuthorNetworkId).string().not_null())
+                    .col(ColumnDef::new(UserNotes::Text).text().not_null())
+                    .col(
+                        ColumnDef::new(UserNotes::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk_user_notes_user_id")
+                            .from(UserNotes::Table, UserNotes::UserId)
+                            .to(Users::Table, Users::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_user_notes_user_id")
+                    .table(UserNotes::Table)
+                    .col(UserNotes::UserId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(UserNotes::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum UserNotes {
+    Table,
+    Id,
+    UserId,
+    AuthorNetworkId,
+    Text,
+    CreatedAt,
+}
+
+#[derive(DeriveIden)]
+enum Users {
+    Table,
+    Id,
+}
@@ -0,0 +1,67 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Bans::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Bans::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Bans::NetworkId).string().null())
+                    .col(ColumnDef::new(Bans::Ip).string().null())
+                    .col(ColumnDef::new(Bans::Reason).string().not_null())
+                    .col(ColumnDef::new(Bans::CreatedBy).string().not_null())
+                    .col(
+                        ColumnDef::new(Bans::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(Bans::ExpiresAt)
+                            .timestamp_with_time_zone()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_bans_network_id")
+                    .table(Bans::Table)
+                    .col(Bans::NetworkId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Bans::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Bans {
+    Table,
+    Id,
+    NetworkId,
+    Ip,
+    Reason,
+    CreatedBy,
+    CreatedAt,
+    ExpiresAt,
+}
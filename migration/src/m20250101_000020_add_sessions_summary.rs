@@ -0,0 +1,97 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// Columns filled in when a session ends; SQLite can only add one column
+/// per statement
+fn columns() -> Vec<ColumnDef> {
+    vec![
+        ColumnDef::new(Sessions::DisconnectedAt)
+            .timestamp_with_time_zone()
+            .null()
+            .to_owned(),
+        ColumnDef::new(Sessions::DisconnectReason)
+            .string()
+            .null()
+            .to_owned(),
+        ColumnDef::new(Sessions::ClientSoftware)
+            .string()
+            .null()
+            .to_owned(),
+        ColumnDef::new(Sessions::DurationSecs)
+            .big_integer()
+            .null()
+            .to_owned(),
+        ColumnDef::new(Sessions::PacketsIn)
+            .big_integer()
+            .null()
+            .to_owned(),
+        ColumnDef::new(Sessions::PacketsOut)
+            .big_integer()
+            .null()
+            .to_owned(),
+        ColumnDef::new(Sessions::BytesIn)
+            .big_integer()
+            .null()
+            .to_owned(),
+        ColumnDef::new(Sessions::BytesOut)
+            .big_integer()
+            .null()
+            .to_owned(),
+        ColumnDef::new(Sessions::PositionUpdates)
+            .big_integer()
+            .null()
+            .to_owned(),
+        ColumnDef::new(Sessions::ParseErrors)
+            .big_integer()
+            .null()
+            .to_owned(),
+    ]
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for mut column in columns() {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Sessions::Table)
+                        .add_column(&mut column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in columns() {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Sessions::Table)
+                        .drop_column(Alias::new(column.get_column_name()))
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Sessions {
+    Table,
+    DisconnectedAt,
+    DisconnectReason,
+    ClientSoftware,
+    DurationSecs,
+    PacketsIn,
+    PacketsOut,
+    BytesIn,
+    BytesOut,
+    PositionUpdates,
+    ParseErrors,
+}
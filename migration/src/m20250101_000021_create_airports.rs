@@ -0,0 +1,44 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Airports::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Airports::Icao)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Airports::Name).string().not_null())
+                    .col(ColumnDef::new(Airports::Latitude).double().not_null())
+                    .col(ColumnDef::new(Airports::Longitude).double().not_null())
+                    .col(ColumnDef::new(Airports::ElevationFt).integer().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Airports::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Airports {
+    Table,
+    Icao,
+    Name,
+    Latitude,
+    Longitude,
+    ElevationFt,
+}
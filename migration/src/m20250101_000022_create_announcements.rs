@@ -0,0 +1,84 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Announcements::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Announcements::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Announcements::Text).string().not_null())
+                    .col(ColumnDef::new(Announcements::Target).string().not_null())
+                    .col(
+                        ColumnDef::new(Announcements::FireAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(Announcements::RepeatSecs)
+                            .big_integer()
+                            .null(),
+                    )
+                    .col(
+                        ColumnDef::new(Announcements::FiredAt)
+                            .timestamp_with_time_zone()
+                            .null(),
+                    )
+                    .col(
+                        ColumnDef::new(Announcements::Done)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .col(ColumnDef::new(Announcements::CreatedBy).string().not_null())
+                    .col(
+                        ColumnDef::new(Announcements::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_announcements_fire_at")
+                    .table(Announcements::Table)
+                    .col(Announcements::FireAt)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Announcements::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Announcements {
+    Table,
+    Id,
+    Text,
+    Target,
+    FireAt,
+    RepeatSecs,
+    FiredAt,
+    Done,
+    CreatedBy,
+    CreatedAt,
+}
@@ -0,0 +1,148 @@
+//! Airport coordinates and elevations, for features that need to know where
+//! an airport is
+//!
+//! The `airports` table is filled with `openfsd-admin airports import` from
+//! an open-data CSV such as OurAirports' `airports.csv`. The server reads it
+//! once at startup and again when the control socket asks it to, and looks
+//! airports up in memory by ICAO code. With the table empty every lookup
+//! comes back empty, and what depends on it makes do without: arrival
+//! estimates use the flight plan alone, for one.
+
+use crate::db::entities::airport;
+use sea_orm::{DatabaseConnection, DbErr, EntityTrait};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+pub use airport::Model as Airport;
+
+/// Airports by ICAO code
+#[derive(Debug, Clone, Default)]
+pub struct Airports {
+    by_icao: HashMap<String, Airport>,
+}
+
+impl Airports {
+    /// Every airport in the database
+    pub async fn load(db: &DatabaseConnection) -> Result<Self, DbErr> {
+        let airports = airport::Entity::find().all(db).await?;
+        Ok(Self::from_iter(airports))
+    }
+
+    /// The airport with `icao` as its code, in any case
+    pub fn get(&self, icao: &str) -> Option<&Airport> {
+        match self.by_icao.get(icao) {
+            Some(airport) => Some(airport),
+            None => self.by_icao.get(&icao.trim().to_ascii_uppercase()),
+        }
+    }
+
+    /// (latitude, longitude) of the airport with `icao` as its code
+    pub fn position(&self, icao: &str) -> Option<(f64, f64)> {
+        self.get(icao).map(Airport::position)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_icao.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_icao.is_empty()
+    }
+}
+
+impl FromIterator<Airport> for Airports {
+    fn from_iter<I: IntoIterator<Item = Airport>>(airports: I) -> Self {
+        Self {
+            by_icao: airports
+                .into_iter()
+                .map(|airport| (airport.icao.clone(), airport))
+                .collect(),
+        }
+    }
+}
+
+/// The airports the server looks up, replaced as a whole on reload
+#[derive(Debug, Default)]
+pub struct AirportCache {
+    current: RwLock<Arc<Airports>>,
+}
+
+impl AirportCache {
+    /// The airports loaded last; empty before the first load
+    pub fn get(&self) -> Arc<Airports> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Read the airports from the database again, returning how many there
+    /// are; on failure the ones loaded before are kept
+    pub async fn reload(&self, db: &DatabaseConnection) -> Result<usize, DbErr> {
+        let airports = Airports::load(db).await?;
+        let count = airports.len();
+        if airports.is_empty() {
+            tracing::warn!(
+                "No airports in the database; import some with `openfsd-admin airports import`"
+            );
+        } else {
+            tracing::info!("Loaded {} airports", count);
+        }
+        *self.current.write().unwrap() = Arc::new(airports);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::airport_csv;
+
+    /// OurAirports rows for 21 airports, with a few to skip or reject
+    const FIXTURE: &str = include_str!("../tests/fixtures/airports.csv");
+
+    #[tokio::test]
+    async fn test_import_and_lookup() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let report = airport_csv::import_airports(&db, FIXTURE.as_bytes(), "test", |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!((report.imported, report.skipped), (21, 3));
+        let lines: Vec<u64> = report.errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, [24, 27]);
+
+        let cache = AirportCache::default();
+        assert_eq!(cache.reload(&db).await.unwrap(), 21);
+        let airports = cache.get();
+        let pudong = airports.get("ZSPD").unwrap();
+        assert_eq!(pudong.name, "Shanghai Pudong International Airport");
+        assert_eq!(pudong.elevation_ft, Some(13));
+        assert_eq!(airports.position("zbaa"), Some((40.0801, 116.5846)));
+        assert_eq!(airports.get("EHAM").unwrap().elevation_ft, Some(-11));
+        // Skipped: no ICAO code, closed
+        assert!(airports.get("00A").is_none());
+        assert!(airports.get("EDDT").is_none());
+
+        // A second import replaces the first
+        let csv = "icao,name,latitude,longitude\nZSHC,Hangzhou Xiaoshan,30.2295,120.4344\n";
+        airport_csv::import_airports(&db, csv.as_bytes(), "test", |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(cache.reload(&db).await.unwrap(), 1);
+        assert!(cache.get().get("ZSPD").is_none());
+        assert_eq!(cache.get().get("ZSHC").unwrap().elevation_ft, None);
+    }
+
+    #[tokio::test]
+    async fn test_empty_table() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let cache = AirportCache::default();
+        assert!(cache.get().is_empty());
+        assert_eq!(cache.reload(&db).await.unwrap(), 0);
+        assert_eq!(cache.get().position("ZSPD"), None);
+
+        let missing =
+            airport_csv::import_airports(&db, "ident,name\n".as_bytes(), "test", |_, _| {}).await;
+        assert!(matches!(
+            missing,
+            Err(airport_csv::ImportError::MissingColumn("latitude"))
+        ));
+    }
+}
@@ -0,0 +1,35 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Compute the expected $ZR response for a $ZC challenge using the client key
+pub fn compute_response(client_key: &str, challenge: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(client_key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(challenge.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Check a $ZR response against the expected value
+pub fn verify_response(client_key: &str, challenge: &str, response: &str) -> bool {
+    let mut mac = Hmac::<Sha256>::new_from_slice(client_key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(challenge.as_bytes());
+    match hex::decode(response) {
+        Ok(bytes) => mac.verify_slice(&bytes).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_response() {
+        let response = compute_response("secret", "0123456789abcdef");
+        assert_eq!(response.len(), 64);
+        assert!(verify_response("secret", "0123456789abcdef", &response));
+        assert!(!verify_response("other", "0123456789abcdef", &response));
+        assert!(!verify_response("secret", "0123456789abcdef", "not hex"));
+    }
+}
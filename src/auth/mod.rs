@@ -1,4 +1,10 @@
+pub mod challenge;
 pub mod password;
+pub mod token;
 pub mod validator;
+pub mod version;
 
-pub use validator::{validate_client_id, validate_login};
+pub use validator::{
+    authenticate, validate_client_id, validate_login, AuthError, AuthenticatedUser,
+    ClientVerification,
+};
@@ -1,7 +1,19 @@
+use crate::config::SecurityConfig;
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
+use rand::distributions::{Alphanumeric, DistString};
+use thiserror::Error;
+
+/// Length of passwords made by [`generate_password`]
+const GENERATED_PASSWORD_LENGTH: usize = 20;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PolicyError {
+    #[error("Password must be at least {0} characters long")]
+    TooShort(usize),
+}
 
 /// Hash a password using Argon2
 pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
@@ -23,6 +35,21 @@ pub fn verify_password(password: &str, hash: &str) -> Result<bool, argon2::passw
     }
 }
 
+/// Check a new password against the `[security]` policy
+pub fn check_policy(password: &str, security: &SecurityConfig) -> Result<(), PolicyError> {
+    // An empty password is too short even if the policy allows zero length
+    let min_length = security.min_password_length.max(1);
+    if password.chars().count() < min_length {
+        return Err(PolicyError::TooShort(min_length));
+    }
+    Ok(())
+}
+
+/// A random alphanumeric password
+pub fn generate_password() -> String {
+    Alphanumeric.sample_string(&mut OsRng, GENERATED_PASSWORD_LENGTH)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -35,4 +62,18 @@ mod tests {
         assert!(verify_password(password, &hash).unwrap());
         assert!(!verify_password("wrong_password", &hash).unwrap());
     }
+
+    #[test]
+    fn test_policy() {
+        let security = SecurityConfig {
+            min_password_length: 8,
+            ..SecurityConfig::default()
+        };
+        assert_eq!(
+            check_policy("short", &security),
+            Err(PolicyError::TooShort(8))
+        );
+        assert!(check_policy("long enough", &security).is_ok());
+        assert!(check_policy(&generate_password(), &security).is_ok());
+    }
 }
@@ -0,0 +1,36 @@
+use crate::db::service;
+use rand::RngCore;
+use sea_orm::{DatabaseConnection, DbErr};
+use sha2::{Digest, Sha256};
+
+/// Password-field prefix marking a login token; without a colon, which
+/// would split the password field of the login packet
+pub const TOKEN_PREFIX: &str = "TOKEN-";
+
+/// Hash a login token for storage; tokens are random so a fast hash suffices
+pub fn hash_login_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Issue a single-use login token for a network ID, valid for `ttl` from
+/// `now`, returning the plaintext token
+pub async fn issue_login_token(
+    db: &DatabaseConnection,
+    network_id: &str,
+    ttl: chrono::Duration,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<String, DbErr> {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+
+    service::create_login_token(
+        db,
+        hash_login_token(&token),
+        network_id.to_string(),
+        now + ttl,
+    )
+    .await?;
+
+    Ok(token)
+}
@@ -1,5 +1,9 @@
 use crate::auth::password;
+use crate::auth::token::{self, TOKEN_PREFIX};
+use crate::auth::version::ClientVersion;
+use crate::config::{AuthConfig, TokenLoginMode, WhitelistConfig};
 use crate::db::{entities::user, service};
+use chrono::{DateTime, Utc};
 use sea_orm::DatabaseConnection;
 use thiserror::Error;
 
@@ -9,52 +13,520 @@ pub enum AuthError {
     InvalidCredentials,
     #[error("Client not whitelisted: {0}")]
     ClientNotWhitelisted(String),
+    #[error("Client version {version} is older than required {min_version}")]
+    ClientVersionTooOld {
+        version: String,
+        min_version: String,
+    },
+    #[error("Unable to determine client version from: {0}")]
+    UnparseableClientVersion(String),
     #[error("User not found")]
     UserNotFound,
+    #[error("Account disabled")]
+    AccountDisabled,
     #[error("Database error: {0}")]
     DatabaseError(#[from] sea_orm::DbErr),
     #[error("Password verification error")]
     PasswordError,
 }
 
-/// Validate client ID against whitelist
+/// Outcome of a client ID check that let the client in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientVerification {
+    /// The client software is on the whitelist
+    Verified,
+    /// Unknown client software, allowed because enforcement is off
+    Unverified,
+}
+
+/// Validate client ID against whitelist, including the entry's minimum version
+///
+/// Without enforcement every client passes, but unknown ones are reported as
+/// unverified (and logged if `policy.log_unknown` is set).
 pub async fn validate_client_id(
     db: &DatabaseConnection,
     client_id: &str,
+    client_string: Option<&str>,
+    policy: &WhitelistConfig,
+) -> Result<ClientVerification, AuthError> {
+    if !policy.enforce {
+        let known = match service::find_whitelisted_client(db, client_id).await {
+            Ok(entry) => entry.is_some(),
+            Err(e) => {
+                tracing::error!("Failed to look up client ID {}: {}", client_id, e);
+                false
+            }
+        };
+        if known {
+            return Ok(ClientVerification::Verified);
+        }
+        if policy.log_unknown {
+            tracing::warn!(
+                "Allowing unknown client ID {} ({:?}), whitelist is not enforced",
+                client_id,
+                client_string.unwrap_or_default()
+            );
+        }
+        return Ok(ClientVerification::Unverified);
+    }
+
+    let entry = match service::find_whitelisted_client(db, client_id).await? {
+        Some(entry) => entry,
+        None => {
+            tracing::warn!("Client ID not whitelisted: {}", client_id);
+            return Err(AuthError::ClientNotWhitelisted(client_id.to_string()));
+        }
+    };
+
+    check_client_version(
+        client_string,
+        entry.min_version.as_deref(),
+        policy.allow_unparseable_version,
+    )?;
+    Ok(ClientVerification::Verified)
+}
+
+/// Check a client's reported version against a whitelist entry's minimum version
+///
+/// Entries without a minimum version accept any client string.
+pub fn check_client_version(
+    client_string: Option<&str>,
+    min_version: Option<&str>,
+    allow_unparseable: bool,
 ) -> Result<(), AuthError> {
-    let is_whitelisted = service::is_client_whitelisted(db, client_id).await?;
+    let min_version = match min_version {
+        Some(min_version) => min_version,
+        None => return Ok(()),
+    };
+
+    let required = match ClientVersion::parse(min_version) {
+        Some(required) => required,
+        None => {
+            tracing::warn!("Ignoring invalid whitelist min_version: {}", min_version);
+            return Ok(());
+        }
+    };
+
+    let client_string = client_string.unwrap_or_default();
+    let version = match ClientVersion::from_client_string(client_string) {
+        Some(version) => version,
+        None if allow_unparseable => {
+            tracing::warn!("Could not parse client version from {:?}, allowing", client_string);
+            return Ok(());
+        }
+        None => {
+            return Err(AuthError::UnparseableClientVersion(client_string.to_string()));
+        }
+    };
 
-    if !is_whitelisted {
-        log::warn!("Client ID not whitelisted: {}", client_id);
-        return Err(AuthError::ClientNotWhitelisted(client_id.to_string()));
+    if version < required {
+        tracing::warn!(
+            "Client version {} is older than required {}",
+            version,
+            required
+        );
+        return Err(AuthError::ClientVersionTooOld {
+            version: version.to_string(),
+            min_version: required.to_string(),
+        });
     }
 
     Ok(())
 }
 
-/// Validate user login credentials
+/// A successfully authenticated login
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub user: user::Model,
+    /// Ephemeral guest without a database account
+    pub is_guest: bool,
+}
+
+/// Authenticate a login, falling back to an ephemeral guest when enabled
+///
+/// Guests are only created for network IDs without an account; a wrong
+/// password for an existing account is still rejected.
+pub async fn authenticate(
+    db: &DatabaseConnection,
+    network_id: &str,
+    password: &str,
+    real_name: &str,
+    auth_config: &AuthConfig,
+    now: DateTime<Utc>,
+) -> Result<AuthenticatedUser, AuthError> {
+    match validate_login(db, network_id, password, auth_config, now).await {
+        Ok(user) => Ok(AuthenticatedUser {
+            user,
+            is_guest: false,
+        }),
+        Err(AuthError::UserNotFound) if auth_config.allow_guest => {
+            tracing::warn!("Unknown network ID {} logging in as guest", network_id);
+            Ok(AuthenticatedUser {
+                user: guest_user(network_id, real_name, now),
+                is_guest: true,
+            })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Build an in-memory user for a guest login
+fn guest_user(network_id: &str, real_name: &str, now: DateTime<Utc>) -> user::Model {
+    user::Model {
+        id: 0,
+        network_id: network_id.to_string(),
+        password_hash: String::new(),
+        real_name: real_name.to_string(),
+        atc_rating: 1,
+        pilot_rating: 1,
+        created_at: now,
+        updated_at: now,
+        last_login_at: None,
+        email: None,
+        deleted_at: None,
+        disabled_at: None,
+    }
+}
+
+/// Validate user login credentials (password or single-use login token),
+/// with tokens checked for expiry as of `now`
 pub async fn validate_login(
     db: &DatabaseConnection,
     network_id: &str,
     password: &str,
+    auth_config: &AuthConfig,
+    now: DateTime<Utc>,
 ) -> Result<user::Model, AuthError> {
-    // Find user by network ID
-    let user = service::find_user_by_network_id(db, network_id)
+    let user = check_credentials(db, network_id, password, auth_config, now).await?;
+
+    // Checked after the credentials so only the account holder learns of it
+    if user.is_disabled() {
+        tracing::warn!("Login attempt for disabled user: {}", network_id);
+        return Err(AuthError::AccountDisabled);
+    }
+
+    tracing::info!("User {} successfully authenticated", network_id);
+    Ok(user)
+}
+
+async fn check_credentials(
+    db: &DatabaseConnection,
+    network_id: &str,
+    password: &str,
+    auth_config: &AuthConfig,
+    now: DateTime<Utc>,
+) -> Result<user::Model, AuthError> {
+    // Find user by network ID; deleted accounts look like a failed login
+    // rather than an unknown CID, which would also allow a guest login
+    let user = service::find_user_including_deleted(db, network_id)
         .await?
         .ok_or(AuthError::UserNotFound)?;
+    if user.is_deleted() {
+        tracing::warn!("Login attempt for deleted user: {}", network_id);
+        return Err(AuthError::InvalidCredentials);
+    }
+
+    // Try login tokens before the password
+    let prefixed_token = password.strip_prefix(TOKEN_PREFIX);
+    let token = match auth_config.token_login {
+        TokenLoginMode::Prefix => prefixed_token,
+        TokenLoginMode::TokenThenPassword => Some(prefixed_token.unwrap_or(password)),
+    };
+
+    if let Some(token) = token {
+        let token_hash = token::hash_login_token(token);
+        if service::consume_login_token(db, &token_hash, network_id, now).await? {
+            tracing::info!("User {} authenticated with login token", network_id);
+            return Ok(user);
+        }
+        if prefixed_token.is_some() {
+            tracing::warn!("Invalid or expired login token for user: {}", network_id);
+            return Err(AuthError::InvalidCredentials);
+        }
+    }
 
     // Verify password
     let password_valid = password::verify_password(password, &user.password_hash)
         .map_err(|e| {
-            log::error!("Password verification error: {}", e);
+            tracing::error!("Password verification error: {}", e);
             AuthError::PasswordError
         })?;
 
     if !password_valid {
-        log::warn!("Invalid password for user: {}", network_id);
+        tracing::warn!("Invalid password for user: {}", network_id);
         return Err(AuthError::InvalidCredentials);
     }
 
-    log::info!("User {} successfully authenticated", network_id);
     Ok(user)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_version_equal() {
+        assert!(check_client_version(Some("EuroScope 3.2.1.25"), Some("3.2.1.25"), false).is_ok());
+        assert!(check_client_version(Some("EuroScope 3.2"), Some("3.2.0"), false).is_ok());
+    }
+
+    #[test]
+    fn test_client_version_older() {
+        let result = check_client_version(Some("EuroScope 3.1.9"), Some("3.2"), false);
+        assert!(matches!(result, Err(AuthError::ClientVersionTooOld { .. })));
+    }
+
+    #[test]
+    fn test_client_version_newer() {
+        assert!(check_client_version(Some("EuroScope 3.10"), Some("3.2.1"), false).is_ok());
+        assert!(check_client_version(Some("vPilot v2.6.3"), Some("2.6"), false).is_ok());
+    }
+
+    #[test]
+    fn test_client_version_unparseable() {
+        assert!(check_client_version(Some("HomebrewClient"), Some("1.0"), true).is_ok());
+        assert!(matches!(
+            check_client_version(Some("HomebrewClient"), Some("1.0"), false),
+            Err(AuthError::UnparseableClientVersion(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_whitelist_policies() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let validate = |policy: WhitelistConfig| {
+            let db = db.clone();
+            async move { validate_client_id(&db, "f00d", Some("Homebrew 0.1"), &policy).await }
+        };
+
+        // Enforced: unknown software is rejected
+        assert!(matches!(
+            validate(WhitelistConfig::default()).await,
+            Err(AuthError::ClientNotWhitelisted(_))
+        ));
+
+        // Not enforced: allowed, with or without a warning
+        for log_unknown in [true, false] {
+            let policy = WhitelistConfig {
+                enforce: false,
+                log_unknown,
+                ..WhitelistConfig::default()
+            };
+            assert_eq!(
+                validate(policy).await.unwrap(),
+                ClientVerification::Unverified
+            );
+        }
+
+        // Known software stays verified either way
+        let policy = WhitelistConfig {
+            enforce: false,
+            ..WhitelistConfig::default()
+        };
+        assert_eq!(
+            validate_client_id(&db, "69d7", Some("EuroScope 3.2"), &policy)
+                .await
+                .unwrap(),
+            ClientVerification::Verified
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_client_is_rejected() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let policy = WhitelistConfig::default();
+        service::add_client_to_whitelist(&db, "b0b0".to_string(), "Test".to_string(), None, None)
+            .await
+            .unwrap();
+        assert!(validate_client_id(&db, "b0b0", None, &policy).await.is_ok());
+
+        assert!(service::set_client_enabled(&db, "b0b0", false)
+            .await
+            .unwrap());
+        assert!(matches!(
+            validate_client_id(&db, "b0b0", None, &policy).await,
+            Err(AuthError::ClientNotWhitelisted(_))
+        ));
+        // Already disabled
+        assert!(!service::set_client_enabled(&db, "b0b0", false)
+            .await
+            .unwrap());
+
+        assert!(service::set_client_enabled(&db, "b0b0", true)
+            .await
+            .unwrap());
+        assert!(validate_client_id(&db, "b0b0", None, &policy).await.is_ok());
+
+        assert!(service::remove_client_from_whitelist(&db, "b0b0")
+            .await
+            .unwrap());
+        assert!(!service::remove_client_from_whitelist(&db, "b0b0")
+            .await
+            .unwrap());
+    }
+
+    async fn user_with_password(db: &DatabaseConnection, network_id: &str, password: &str) {
+        let hash = password::hash_password(password).unwrap();
+        service::create_user(db, network_id.to_string(), hash, "Test User".to_string(), 1, 1)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_login_token_single_use() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let now = chrono::Utc::now();
+        user_with_password(&db, "1234567", "password").await;
+        let config = AuthConfig::default();
+
+        let token = token::issue_login_token(&db, "1234567", chrono::Duration::minutes(5), now)
+            .await
+            .unwrap();
+        let password = format!("{}{}", TOKEN_PREFIX, token);
+
+        assert!(validate_login(&db, "1234567", &password, &config, now).await.is_ok());
+        assert!(matches!(
+            validate_login(&db, "1234567", &password, &config, now).await,
+            Err(AuthError::InvalidCredentials)
+        ));
+        // The regular password still works alongside tokens
+        assert!(validate_login(&db, "1234567", "password", &config, now).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_disabled_user_cannot_log_in() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let now = chrono::Utc::now();
+        user_with_password(&db, "1234567", "password").await;
+        let config = AuthConfig::default();
+        service::disable_user(&db, "1234567").await.unwrap();
+
+        assert!(matches!(
+            validate_login(&db, "1234567", "password", &config, now).await,
+            Err(AuthError::AccountDisabled)
+        ));
+        // Without the password the account looks like any other
+        assert!(matches!(
+            validate_login(&db, "1234567", "wrong", &config, now).await,
+            Err(AuthError::InvalidCredentials)
+        ));
+
+        service::enable_user(&db, "1234567").await.unwrap();
+        assert!(validate_login(&db, "1234567", "password", &config, now)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_login_token_expired() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let now = chrono::Utc::now();
+        user_with_password(&db, "1234567", "password").await;
+
+        let token = token::issue_login_token(&db, "1234567", chrono::Duration::seconds(-1), now)
+            .await
+            .unwrap();
+        let password = format!("{}{}", TOKEN_PREFIX, token);
+
+        assert!(matches!(
+            validate_login(&db, "1234567", &password, &AuthConfig::default(), now).await,
+            Err(AuthError::InvalidCredentials)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_login_token_without_prefix() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let now = chrono::Utc::now();
+        user_with_password(&db, "1234567", "password").await;
+        let token = token::issue_login_token(&db, "1234567", chrono::Duration::minutes(5), now)
+            .await
+            .unwrap();
+
+        // Prefix mode treats a bare token as a (wrong) password
+        assert!(validate_login(&db, "1234567", &token, &AuthConfig::default(), now)
+            .await
+            .is_err());
+
+        let config = AuthConfig {
+            token_login: TokenLoginMode::TokenThenPassword,
+            ..AuthConfig::default()
+        };
+        assert!(validate_login(&db, "1234567", &token, &config, now).await.is_ok());
+        assert!(validate_login(&db, "1234567", "password", &config, now).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_guest_login() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let now = chrono::Utc::now();
+        user_with_password(&db, "1234567", "password").await;
+        let config = AuthConfig {
+            allow_guest: true,
+            ..AuthConfig::default()
+        };
+
+        let guest = authenticate(&db, "7654321", "anything", "Guest Pilot", &config, now)
+            .await
+            .unwrap();
+        assert!(guest.is_guest);
+        assert_eq!(guest.user.real_name, "Guest Pilot");
+        assert_eq!(guest.user.pilot_rating, 1);
+
+        // Existing accounts still need the right password
+        assert!(matches!(
+            authenticate(&db, "1234567", "wrong", "Guest Pilot", &config, now).await,
+            Err(AuthError::InvalidCredentials)
+        ));
+        let user = authenticate(&db, "1234567", "password", "", &config, now)
+            .await
+            .unwrap();
+        assert!(!user.is_guest);
+    }
+
+    #[tokio::test]
+    async fn test_guest_login_disabled() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let now = chrono::Utc::now();
+        assert!(matches!(
+            authenticate(
+                &db,
+                "7654321",
+                "anything",
+                "Guest Pilot",
+                &AuthConfig::default(),
+                now
+            )
+            .await,
+            Err(AuthError::UserNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_deleted_user_rejected() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let now = chrono::Utc::now();
+        user_with_password(&db, "1234567", "password").await;
+        assert!(service::soft_delete_user(&db, "1234567").await.unwrap());
+
+        // Same error as a wrong password, and no guest fallback
+        let config = AuthConfig {
+            allow_guest: true,
+            ..AuthConfig::default()
+        };
+        assert!(matches!(
+            authenticate(&db, "1234567", "password", "", &config, now).await,
+            Err(AuthError::InvalidCredentials)
+        ));
+
+        assert!(service::restore_user(&db, "1234567").await.unwrap());
+        assert!(validate_login(&db, "1234567", "password", &config, now)
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn test_client_version_no_minimum() {
+        assert!(check_client_version(Some("HomebrewClient"), None, false).is_ok());
+    }
+}
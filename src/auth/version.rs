@@ -0,0 +1,81 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Lenient dotted client version (e.g. "3.2.1.25")
+///
+/// Missing trailing components compare as zero, so "3.2" == "3.2.0".
+#[derive(Debug, Clone)]
+pub struct ClientVersion(Vec<u32>);
+
+impl ClientVersion {
+    /// Parse a bare version string such as "3.2", "v2.6.3" or "1.4.2-beta"
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim().trim_start_matches(['v', 'V']);
+        let mut parts = Vec::new();
+
+        for part in s.split('.') {
+            let digits: String = part.chars().take_while(|c| c.is_ascii_digit()).collect();
+            if digits.is_empty() {
+                break;
+            }
+            parts.push(digits.parse().ok()?);
+            // A suffix such as "-beta" ends the numeric part of the version
+            if digits.len() != part.len() {
+                break;
+            }
+        }
+
+        if parts.is_empty() {
+            None
+        } else {
+            Some(Self(parts))
+        }
+    }
+
+    /// Extract the version from a client string such as "EuroScope 3.2.1.25"
+    pub fn from_client_string(client_string: &str) -> Option<Self> {
+        client_string.split_whitespace().find_map(|token| {
+            let numeric = token.trim_start_matches(['v', 'V']);
+            if numeric.starts_with(|c: char| c.is_ascii_digit()) {
+                Self::parse(token)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn component(&self, index: usize) -> u32 {
+        self.0.get(index).copied().unwrap_or(0)
+    }
+}
+
+impl Ord for ClientVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = self.0.len().max(other.0.len());
+        (0..len)
+            .map(|i| self.component(i).cmp(&other.component(i)))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for ClientVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ClientVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ClientVersion {}
+
+impl fmt::Display for ClientVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(|p| p.to_string()).collect();
+        write!(f, "{}", parts.join("."))
+    }
+}
@@ -1,30 +1,637 @@
 /// OpenFSD Admin Tool
 ///
 /// Utility for managing OpenFSD database users and configuration
-use openfsd::{auth, db};
-use std::io::{self, Write};
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use ipnet::IpNet;
+use migration::{Migrator, MigratorTrait};
+use openfsd::config::{Config, DatabaseConfig, Overrides, SecurityConfig};
+use openfsd::db::user_csv::OnDuplicate;
+use openfsd::flight_plan::FlightPlan;
+use openfsd::server::control::{self, ClientInfo};
+use openfsd::tracks::export::TrackFormat;
+use openfsd::{auth, db, motd, tracks};
+use sea_orm::ConnectionTrait;
+use sea_orm_migration::MigrationStatus;
+use serde_json::json;
+use std::io::{self, BufRead, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+type Result<T, E = Box<dyn std::error::Error>> = std::result::Result<T, E>;
+
+/// Manage OpenFSD users, the client whitelist and recorded data
+///
+/// Without a command an interactive menu is shown.
+#[derive(Debug, Parser)]
+#[command(name = "openfsd-admin", version)]
+struct Cli {
+    /// Database URL [default: $DATABASE_URL, or sqlite://openfsd.db]
+    #[arg(long, global = true, value_name = "URL")]
+    db: Option<String>,
+
+    /// Configuration file with the password policy [default: ./config.toml if it exists]
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Manage user accounts
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage the client whitelist
+    #[command(subcommand)]
+    Whitelist(WhitelistCommand),
+    /// Ban CIDs and IP addresses
+    #[command(subcommand)]
+    Ban(BanCommand),
+    /// Schedule announcements to everyone, pilots or controllers
+    #[command(subcommand)]
+    Announce(AnnounceCommand),
+    /// See and disconnect clients on the running server
+    #[command(subcommand)]
+    Clients(ClientsCommand),
+    /// Check the database and manage its schema
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// Look up connection sessions
+    #[command(subcommand)]
+    Session(SessionCommand),
+    /// Prefile a flight plan, activated when the pilot logs in
+    Prefile(Box<PrefileArgs>),
+    /// Print a single-use login token
+    IssueToken {
+        /// Network ID the token logs in as
+        cid: String,
+        /// How long the token is valid, e.g. 900, 15m or 2h
+        ttl: String,
+    },
+    /// Print daily statistics
+    Stats {
+        #[arg(long, default_value_t = 30)]
+        days: u32,
+        /// Show packet counts and handler times of the running server instead
+        #[arg(long)]
+        handlers: bool,
+        /// Print the handler statistics as JSON
+        #[arg(long, requires = "handlers")]
+        json: bool,
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Export the track recorded for a session
+    ExportTrack {
+        #[arg(long)]
+        session: String,
+        /// geojson or kml
+        #[arg(long)]
+        format: TrackFormat,
+        #[arg(long, value_name = "FILE")]
+        out: String,
+    },
+    /// Show or change the message of the day
+    #[command(subcommand)]
+    Motd(MotdCommand),
+    /// Supervisor notes on accounts
+    #[command(subcommand)]
+    Notes(NotesCommand),
+    /// Load airport positions and elevations
+    #[command(subcommand)]
+    Airports(AirportsCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum UserCommand {
+    /// Create an account; the password is read from stdin or prompted for
+    Add(UserAddArgs),
+    /// List accounts
+    List(UserListArgs),
+    /// Delete an account; it can be restored later
+    Delete {
+        cid: String,
+        /// Don't ask for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Undo a delete
+    Restore { cid: String },
+    /// Set a new password; it is prompted for twice unless read from stdin
+    Passwd {
+        cid: String,
+        /// Read the new password from the first line of stdin
+        #[arg(long, conflicts_with = "generate")]
+        password_stdin: bool,
+        /// Set a random password and print it once
+        #[arg(long)]
+        generate: bool,
+    },
+    /// Stop an account from logging in without deleting it
+    Disable { cid: String },
+    /// Let a disabled account log in again
+    Enable { cid: String },
+    /// Create or update accounts from a CSV file
+    Import {
+        file: PathBuf,
+        /// Check the file and report what would change without writing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// For CIDs that already have an account: skip, update or fail
+        #[arg(long, default_value = "skip")]
+        on_duplicate: OnDuplicate,
+    },
+    /// Write active accounts to a CSV file
+    Export {
+        file: PathBuf,
+        /// Include password hashes, e.g. to move to another server
+        #[arg(long)]
+        include_hashes: bool,
+    },
+}
+
+#[derive(Debug, Args)]
+struct UserAddArgs {
+    /// Network ID (VATSIM CID/IVAO VID)
+    #[arg(long)]
+    cid: String,
+    /// Real name
+    #[arg(long)]
+    name: String,
+    #[arg(long, default_value_t = 1)]
+    atc_rating: i32,
+    #[arg(long, default_value_t = 1)]
+    pilot_rating: i32,
+    /// Read the password from the first line of stdin instead of prompting
+    #[arg(long)]
+    password_stdin: bool,
+    /// Reset a deleted account with the same CID instead of failing
+    #[arg(long)]
+    reactivate: bool,
+}
+
+#[derive(Debug, Args)]
+struct UserListArgs {
+    /// Include deleted accounts
+    #[arg(long, conflicts_with = "deleted_only")]
+    include_deleted: bool,
+    /// Only show deleted accounts
+    #[arg(long)]
+    deleted_only: bool,
+    /// Network ID prefix
+    #[arg(long)]
+    cid: Option<String>,
+    /// Text contained in the name
+    #[arg(long)]
+    name: Option<String>,
+    #[arg(long)]
+    atc_rating: Option<i32>,
+    #[arg(long)]
+    pilot_rating: Option<i32>,
+    #[command(flatten)]
+    output: ListOutput,
+}
+
+#[derive(Debug, Subcommand)]
+enum WhitelistCommand {
+    /// Allow client software to connect
+    Add {
+        /// Four-character client ID, e.g. 69d7
+        client_id: String,
+        /// Name, e.g. "EuroScope 3.2"
+        name: String,
+        /// Oldest version allowed, e.g. 3.2.1
+        #[arg(long)]
+        min_version: Option<String>,
+        /// Key for auth challenges
+        #[arg(long, conflicts_with = "key_stdin")]
+        key: Option<String>,
+        /// Read the key for auth challenges from the first line of stdin
+        #[arg(long)]
+        key_stdin: bool,
+    },
+    /// Refuse a client without forgetting it
+    Disable { client_id: String },
+    /// Allow a disabled client again
+    Enable { client_id: String },
+    /// Remove a client from the whitelist
+    Remove {
+        client_id: String,
+        /// Don't ask for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Change or clear the oldest version allowed
+    SetMinVersion {
+        client_id: String,
+        /// Leave out to allow every version
+        #[arg(long)]
+        min_version: Option<String>,
+    },
+    /// List whitelisted clients
+    List {
+        /// Client ID prefix
+        #[arg(long)]
+        client_id: Option<String>,
+        /// Text contained in the name
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        enabled: Option<bool>,
+        /// Print client keys in full, after confirmation
+        #[arg(long)]
+        show_keys: bool,
+        #[command(flatten)]
+        output: ListOutput,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SessionCommand {
+    /// List sessions, newest first
+    List {
+        #[arg(long)]
+        cid: Option<String>,
+        /// First day, YYYY-MM-DD
+        #[arg(long)]
+        from: Option<chrono::NaiveDate>,
+        /// Last day (inclusive), YYYY-MM-DD
+        #[arg(long)]
+        to: Option<chrono::NaiveDate>,
+        #[command(flatten)]
+        output: ListOutput,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum MotdCommand {
+    /// Print the message stored in the database
+    Show {
+        /// motd or atc_motd
+        #[arg(long, default_value = motd::MOTD_KEY)]
+        key: String,
+    },
+    /// Replace the message with the lines on stdin
+    Set {
+        /// motd or atc_motd
+        #[arg(long, default_value = motd::MOTD_KEY)]
+        key: String,
+        /// Name recorded as the author
+        #[arg(long)]
+        by: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum BanCommand {
+    /// Ban a CID, an IP address or a range; takes effect at the next login
+    Add(BanAddArgs),
+    /// List bans, newest first
+    List {
+        /// Leave out expired bans
+        #[arg(long)]
+        active: bool,
+        #[command(flatten)]
+        output: ListOutput,
+    },
+    /// Lift a ban
+    Remove { id: i32 },
+}
+
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("target").required(true).multiple(true).args(["cid", "ip", "cidr"])))]
+struct BanAddArgs {
+    #[arg(long)]
+    cid: Option<String>,
+    #[arg(long, conflicts_with = "cidr")]
+    ip: Option<IpAddr>,
+    /// Address range, e.g. 203.0.113.0/24
+    #[arg(long)]
+    cidr: Option<IpNet>,
+    #[arg(long)]
+    reason: String,
+    /// How long the ban lasts, e.g. 12h or 7d; permanent if left out
+    #[arg(long, value_parser = parse_expiry)]
+    expires: Option<chrono::Duration>,
+}
+
+#[derive(Debug, Subcommand)]
+enum AnnounceCommand {
+    /// Schedule an announcement, sent as a message from the server
+    Add(AnnounceAddArgs),
+    /// List announcements by when they are next sent
+    List {
+        /// Leave out one-shot announcements already sent or skipped
+        #[arg(long)]
+        pending: bool,
+        #[command(flatten)]
+        output: ListOutput,
+    },
+    /// Delete an announcement
+    Remove { id: i32 },
+}
+
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("when").required(true).args(["at", "after"])))]
+struct AnnounceAddArgs {
+    text: String,
+    /// When to send it, e.g. 2026-10-17T18:00:00Z
+    #[arg(long)]
+    at: Option<chrono::DateTime<chrono::Utc>>,
+    /// How soon to send it, e.g. 30m or 2h
+    #[arg(long = "in", value_name = "DURATION", value_parser = parse_expiry)]
+    after: Option<chrono::Duration>,
+    /// Send it again this often, e.g. 1h; once if left out
+    #[arg(long, value_parser = parse_expiry)]
+    repeat: Option<chrono::Duration>,
+    #[arg(long, default_value = "all", value_parser = db::entities::announcement::TARGETS)]
+    target: String,
+}
+
+#[derive(Debug, Subcommand)]
+enum ClientsCommand {
+    /// List the clients logged in right now
+    List {
+        /// Print the list as JSON
+        #[arg(long)]
+        json: bool,
+        /// Refresh every SECS seconds until interrupted
+        #[arg(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "5",
+              value_parser = clap::value_parser!(u64).range(1..))]
+        watch: Option<u64>,
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Disconnect a client, telling it why
+    Kick {
+        callsign: String,
+        #[arg(long)]
+        reason: String,
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Refuse new connections and report not ready, keeping the clients online
+    Drain {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Write the full server state to a JSON file in the server's dump directory
+    Dump {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum DbCommand {
+    /// Show which migrations are applied and which are pending
+    Status,
+    /// Apply pending migrations
+    Migrate {
+        /// Apply only this many, oldest first
+        #[arg(long)]
+        steps: Option<u32>,
+    },
+    /// Undo the most recently applied migrations
+    Rollback {
+        #[arg(long)]
+        steps: u32,
+        /// Don't ask for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Connect and print the backend and its version
+    Check,
+}
+
+/// Where the server's control socket is; defaults to the [control] config
+#[derive(Debug, Args)]
+struct ControlArgs {
+    /// Address of the control socket
+    #[arg(long = "control", value_name = "ADDR")]
+    address: Option<String>,
+    /// Shared secret of the control socket
+    #[arg(long)]
+    secret: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum AirportsCommand {
+    /// Replace the airports with those in a CSV file, e.g. OurAirports' airports.csv
+    Import { file: PathBuf },
+    /// Make the running server read the airports again after an import
+    Reload {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum NotesCommand {
+    /// Show the latest notes on an account
+    List {
+        cid: String,
+        #[arg(long, default_value_t = 20)]
+        limit: u64,
+    },
+    /// Add a note to an account
+    Add {
+        cid: String,
+        /// CID of the supervisor writing the note
+        #[arg(long)]
+        author: String,
+        #[arg(required = true)]
+        text: Vec<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+struct PrefileArgs {
+    /// Network ID of the pilot
+    #[arg(long)]
+    cid: String,
+    #[arg(long)]
+    callsign: String,
+    /// I or V
+    #[arg(long, default_value = "I")]
+    rules: String,
+    /// Aircraft type, e.g. H/B744/L
+    #[arg(long)]
+    aircraft: String,
+    /// Cruise true airspeed in knots
+    #[arg(long)]
+    speed: String,
+    #[arg(long)]
+    departure: String,
+    /// Estimated departure time, UTC HHMM
+    #[arg(long, default_value = "")]
+    departure_time: String,
+    #[arg(long)]
+    altitude: String,
+    #[arg(long)]
+    destination: String,
+    #[arg(long, default_value = "0")]
+    hours_enroute: String,
+    #[arg(long, default_value = "0")]
+    minutes_enroute: String,
+    #[arg(long, default_value = "0")]
+    hours_fuel: String,
+    #[arg(long, default_value = "0")]
+    minutes_fuel: String,
+    #[arg(long, default_value = "")]
+    alternate: String,
+    #[arg(long, default_value = "")]
+    remarks: String,
+    #[arg(long, default_value = "")]
+    route: String,
+    /// How long the plan waits for the pilot, e.g. 12h or 2d
+    #[arg(long, default_value = "24h")]
+    ttl: String,
+}
+
+/// Paging and format of list commands
+#[derive(Debug, Args)]
+struct ListOutput {
+    /// Page to show, starting at 1
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    page: u64,
+    /// Rows per page
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u64).range(1..))]
+    limit: u64,
+    /// Print the page as JSON
+    #[arg(long)]
+    json: bool,
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Some(command) => run(command, cli.db, cli.config.as_deref()).await,
+        None => interactive(cli.db, cli.config.as_deref()).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(command: Command, db_url: Option<String>, config: Option<&Path>) -> Result<()> {
+    // Talks to the running server, not the database
+    if let Command::Clients(command) = command {
+        return clients_command(command, config).await;
+    }
+    if let Command::Stats {
+        handlers: true,
+        json,
+        control,
+        ..
+    } = command
+    {
+        return handler_stats_report(control, json, config).await;
+    }
+    if let Command::Airports(AirportsCommand::Reload { control }) = command {
+        let (address, secret) = control_target(control, config)?;
+        let count = control::reload_airports(&address, &secret).await?;
+        println!("✅ 服务器已重新加载 {} 个机场", count);
+        return Ok(());
+    }
+    // Must not migrate on connect
+    if let Command::Db(command) = command {
+        return db_command(command, db_url).await;
+    }
+
+    let db_conn = connect(db_url).await?;
+    match command {
+        Command::User(UserCommand::Add(args)) => {
+            add_user_command(&db_conn, args, &password_policy(config)?).await
+        }
+        Command::User(UserCommand::Passwd {
+            cid,
+            password_stdin,
+            generate,
+        }) => {
+            let security = password_policy(config)?;
+            change_password(&db_conn, &cid, password_stdin, generate, &security).await
+        }
+        Command::User(UserCommand::List(args)) => list_users_command(&db_conn, args).await,
+        Command::User(UserCommand::Delete { cid, yes }) => delete_user(&db_conn, &cid, yes).await,
+        Command::User(UserCommand::Restore { cid }) => restore_user(&db_conn, &cid).await,
+        Command::User(UserCommand::Disable { cid }) => {
+            set_user_disabled(&db_conn, &cid, true).await
+        }
+        Command::User(UserCommand::Enable { cid }) => {
+            set_user_disabled(&db_conn, &cid, false).await
+        }
+        Command::User(UserCommand::Import {
+            file,
+            dry_run,
+            on_duplicate,
+        }) => {
+            let security = password_policy(config)?;
+            import_users(&db_conn, &file, dry_run, on_duplicate, &security).await
+        }
+        Command::User(UserCommand::Export {
+            file,
+            include_hashes,
+        }) => export_users(&db_conn, &file, include_hashes).await,
+        Command::Whitelist(command) => whitelist_command(&db_conn, command).await,
+        Command::Session(SessionCommand::List {
+            cid,
+            from,
+            to,
+            output,
+        }) => list_sessions_command(&db_conn, cid, from, to, output).await,
+        Command::Prefile(args) => prefile_command(&db_conn, *args).await,
+        Command::IssueToken { cid, ttl } => issue_token(&db_conn, &cid, &ttl).await,
+        Command::Stats { days, .. } => stats_report(&db_conn, days).await,
+        Command::ExportTrack {
+            session,
+            format,
+            out,
+        } => export_track(&db_conn, &session, format, &out).await,
+        Command::Motd(command) => motd_command(&db_conn, command).await,
+        Command::Notes(command) => notes_command(&db_conn, command).await,
+        Command::Ban(command) => ban_command(&db_conn, command).await,
+        Command::Announce(command) => announce_command(&db_conn, command).await,
+        Command::Airports(AirportsCommand::Import { file }) => {
+            import_airports(&db_conn, &file).await
+        }
+        Command::Clients(_)
+        | Command::Db(_)
+        | Command::Airports(AirportsCommand::Reload { .. }) => {
+            unreachable!("handled before connecting")
+        }
+    }
+}
+
+/// The menu shown when no command is given
+async fn interactive(db_url: Option<String>, config: Option<&Path>) -> Result<()> {
     println!("╔════════════════════════════════════════╗");
     println!("║      OpenFSD Admin Tool v0.1.0         ║");
     println!("╚════════════════════════════════════════╝\n");
 
     // Get database URL
-    print!("数据库 URL [sqlite://openfsd.db]: ");
-    io::stdout().flush()?;
-    let mut db_url = String::new();
-    io::stdin().read_line(&mut db_url)?;
-    let db_url = db_url.trim();
-    let db_url = if db_url.is_empty() {
-        "sqlite://openfsd.db"
-    } else {
-        db_url
+    let db_url = match db_url {
+        Some(url) => url,
+        None => {
+            let url = prompt("数据库 URL [sqlite://openfsd.db]: ")?;
+            if url.is_empty() {
+                "sqlite://openfsd.db".to_string()
+            } else {
+                url
+            }
+        }
     };
 
     // Connect to database
-    println!("\n🔌 连接数据库: {}", db_url);
-    let db_conn = db::init(db_url).await?;
+    println!("\n🔌 连接数据库: {}", db::sanitize_url(&db_url));
+    let db_conn = connect(Some(db_url)).await?;
     println!("✅ 数据库连接成功！\n");
 
     // Main menu
@@ -33,17 +640,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("  1. 添加新用户");
         println!("  2. 列出所有用户");
         println!("  3. 添加客户端到白名单");
+        println!("  4. 更新客户端最低版本");
+        println!("  5. 列出白名单");
+        println!("  6. 预提交飞行计划");
         println!("  0. 退出");
-        print!("\n> ");
-        io::stdout().flush()?;
 
-        let mut choice = String::new();
-        io::stdin().read_line(&mut choice)?;
-
-        match choice.trim() {
-            "1" => add_user(&db_conn).await?,
+        match prompt("\n> ")?.as_str() {
+            "1" => add_user(&db_conn, &password_policy(config)?).await?,
             "2" => list_users(&db_conn).await?,
             "3" => add_client_to_whitelist(&db_conn).await?,
+            "4" => update_client_min_version(&db_conn).await?,
+            "5" => list_whitelist(&db_conn).await?,
+            "6" => prefile_flight_plan(&db_conn).await?,
             "0" => break,
             _ => println!("❌ 无效选择"),
         }
@@ -53,111 +661,1709 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn add_user(db: &sea_orm::DatabaseConnection) -> Result<(), Box<dyn std::error::Error>> {
-    println!("\n=== 添加新用户 ===");
-
-    print!("Network ID (VATSIM CID/IVAO VID): ");
-    io::stdout().flush()?;
-    let mut network_id = String::new();
-    io::stdin().read_line(&mut network_id)?;
-    let network_id = network_id.trim().to_string();
-
-    print!("密码: ");
-    io::stdout().flush()?;
-    let mut password = String::new();
-    io::stdin().read_line(&mut password)?;
-    let password = password.trim();
+/// Connect to `url`, DATABASE_URL or the default SQLite file, in that order
+async fn connect(url: Option<String>) -> Result<sea_orm::DatabaseConnection> {
+    Ok(db::init(&database_config(url)).await?)
+}
 
-    print!("真实姓名: ");
-    io::stdout().flush()?;
-    let mut real_name = String::new();
-    io::stdin().read_line(&mut real_name)?;
-    let real_name = real_name.trim().to_string();
+fn database_config(url: Option<String>) -> DatabaseConfig {
+    let mut db_config = DatabaseConfig::default();
+    if let Some(url) = url.or_else(|| std::env::var("DATABASE_URL").ok()) {
+        db_config.url = url;
+    }
+    db_config
+}
 
-    print!("ATC 等级 (1-12) [1]: ");
-    io::stdout().flush()?;
-    let mut atc_rating_str = String::new();
-    io::stdin().read_line(&mut atc_rating_str)?;
-    let atc_rating: i32 = atc_rating_str.trim().parse().unwrap_or(1);
+/// `[security]` from the configuration, which sets the password policy
+fn password_policy(path: Option<&Path>) -> Result<SecurityConfig> {
+    let config = Config::load(path, std::env::vars(), &Overrides::default())?;
+    Ok(config.security)
+}
 
-    print!("飞行员等级 (1-11) [1]: ");
-    io::stdout().flush()?;
-    let mut pilot_rating_str = String::new();
-    io::stdin().read_line(&mut pilot_rating_str)?;
-    let pilot_rating: i32 = pilot_rating_str.trim().parse().unwrap_or(1);
+/// An account to create
+struct NewUser {
+    network_id: String,
+    password: String,
+    real_name: String,
+    atc_rating: i32,
+    pilot_rating: i32,
+}
 
-    // Hash password
-    println!("\n🔐 Hash 密码...");
-    let password_hash = auth::password::hash_password(password)
+/// Create the account, or reset the deleted account with its CID if
+/// `reactivate` agrees; `None` if it didn't
+async fn create_or_reactivate_user(
+    db: &sea_orm::DatabaseConnection,
+    new_user: NewUser,
+    security: &SecurityConfig,
+    reactivate: impl FnOnce() -> Result<bool>,
+) -> Result<Option<db::entities::user::Model>> {
+    auth::password::check_policy(&new_user.password, security)?;
+    let password_hash = auth::password::hash_password(&new_user.password)
         .map_err(|e| format!("Password hash error: {}", e))?;
 
-    // Create user
-    println!("💾 创建用户...");
+    // A soft-deleted CID keeps its row; reusing it resets that account
+    let existing = db::service::find_user_including_deleted(db, &new_user.network_id).await?;
+    if existing.as_ref().is_some_and(|user| user.is_deleted()) {
+        if !reactivate()? {
+            return Ok(None);
+        }
+        let user = db::service::reactivate_user(
+            db,
+            &new_user.network_id,
+            password_hash,
+            new_user.real_name,
+            new_user.atc_rating,
+            new_user.pilot_rating,
+        )
+        .await?
+        .ok_or("User was restored concurrently")?;
+        return Ok(Some(user));
+    }
+
     let user = db::service::create_user(
         db,
-        network_id.clone(),
+        new_user.network_id,
         password_hash,
-        real_name,
-        atc_rating,
-        pilot_rating,
+        new_user.real_name,
+        new_user.atc_rating,
+        new_user.pilot_rating,
     )
     .await?;
+    Ok(Some(user))
+}
 
+fn print_user_created(user: &db::entities::user::Model) {
     println!("\n✅ 用户创建成功！");
     println!("   Network ID: {}", user.network_id);
     println!("   真实姓名: {}", user.real_name);
     println!("   ATC 等级: {}", user.atc_rating);
     println!("   飞行员等级: {}", user.pilot_rating);
+}
+
+async fn add_user(db: &sea_orm::DatabaseConnection, security: &SecurityConfig) -> Result<()> {
+    println!("\n=== 添加新用户 ===");
+
+    let network_id = prompt("Network ID (VATSIM CID/IVAO VID): ")?;
+    let password = prompt("密码: ")?;
+    let real_name = prompt("真实姓名: ")?;
+    let atc_rating: i32 = prompt("ATC 等级 (1-12) [1]: ")?.parse().unwrap_or(1);
+    let pilot_rating: i32 = prompt("飞行员等级 (1-11) [1]: ")?.parse().unwrap_or(1);
 
+    println!("\n💾 创建用户...");
+    let new_user = NewUser {
+        network_id,
+        password,
+        real_name,
+        atc_rating,
+        pilot_rating,
+    };
+    let user = create_or_reactivate_user(db, new_user, security, || {
+        let answer =
+            prompt("⚠️  该 CID 属于已删除的账户，是否重新启用并重置密码、姓名和等级？(y/N): ")?;
+        Ok(answer.eq_ignore_ascii_case("y"))
+    })
+    .await?;
+
+    match user {
+        Some(user) => print_user_created(&user),
+        None => println!("已取消"),
+    }
     Ok(())
 }
 
-async fn list_users(db: &sea_orm::DatabaseConnection) -> Result<(), Box<dyn std::error::Error>> {
-    use sea_orm::EntityTrait;
+/// `openfsd-admin user add --cid <cid> --name <name> [--password-stdin]`
+async fn add_user_command(
+    db: &sea_orm::DatabaseConnection,
+    args: UserAddArgs,
+    security: &SecurityConfig,
+) -> Result<()> {
+    let password = read_secret(args.password_stdin, "密码: ")?;
+    let network_id = args.cid.clone();
+    let new_user = NewUser {
+        network_id: args.cid,
+        password,
+        real_name: args.name,
+        atc_rating: args.atc_rating,
+        pilot_rating: args.pilot_rating,
+    };
+    let user = create_or_reactivate_user(db, new_user, security, || match args.reactivate {
+        true => Ok(true),
+        false => Err(format!(
+            "{} belongs to a deleted account, pass --reactivate to reset it",
+            network_id
+        )
+        .into()),
+    })
+    .await?
+    .ok_or("User was not created")?;
 
-    println!("\n=== 用户列表 ===\n");
+    print_user_created(&user);
+    Ok(())
+}
 
-    let users = db::entities::user::Entity::find().all(db).await?;
+/// `openfsd-admin user passwd <cid> [--password-stdin|--generate]`
+async fn change_password(
+    db: &sea_orm::DatabaseConnection,
+    network_id: &str,
+    password_stdin: bool,
+    generate: bool,
+    security: &SecurityConfig,
+) -> Result<()> {
+    if db::service::find_user_by_network_id(db, network_id)
+        .await?
+        .is_none()
+    {
+        return Err(format!("User not found: {}", network_id).into());
+    }
 
-    if users.is_empty() {
-        println!("📭 暂无用户");
+    let password = if generate {
+        auth::password::generate_password()
+    } else if password_stdin {
+        read_secret(true, "")?
     } else {
-        for user in users {
-            println!("📋 Network ID: {}", user.network_id);
-            println!("   姓名: {}", user.real_name);
-            println!("   ATC 等级: {} | 飞行员等级: {}", user.atc_rating, user.pilot_rating);
-            println!("   创建时间: {}", user.created_at);
-            println!();
+        let password = prompt("新密码: ")?;
+        if prompt("再次输入新密码: ")? != password {
+            return Err("Passwords do not match".into());
         }
+        password
+    };
+    auth::password::check_policy(&password, security)?;
+
+    let password_hash = auth::password::hash_password(&password)
+        .map_err(|e| format!("Password hash error: {}", e))?;
+    if !db::service::update_password(db, network_id, password_hash, "openfsd-admin").await? {
+        return Err(format!("User not found: {}", network_id).into());
     }
 
+    println!("✅ 已重置 {} 的密码", network_id);
+    if generate {
+        println!("   新密码（仅显示一次）: {}", password);
+    }
     Ok(())
 }
 
-async fn add_client_to_whitelist(
-    db: &sea_orm::DatabaseConnection,
-) -> Result<(), Box<dyn std::error::Error>> {
-    println!("\n=== 添加客户端到白名单 ===");
+/// A password or key from the first line of stdin, or typed at a prompt
+///
+/// Secrets are never taken as arguments, where they would end up in the
+/// shell history and the process list.
+fn read_secret(from_stdin: bool, label: &str) -> Result<String> {
+    if !from_stdin {
+        return prompt(label);
+    }
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
 
-    print!("Client ID (4字符，如 69d7): ");
-    io::stdout().flush()?;
-    let mut client_id = String::new();
-    io::stdin().read_line(&mut client_id)?;
-    let client_id = client_id.trim().to_string();
+/// Rows per page in the interactive listings
+const INTERACTIVE_PAGE_SIZE: u64 = 20;
 
-    print!("Client 名称 (如 EuroScope 3.2): ");
-    io::stdout().flush()?;
-    let mut client_name = String::new();
-    io::stdin().read_line(&mut client_name)?;
-    let client_name = client_name.trim().to_string();
+async fn list_users(db: &sea_orm::DatabaseConnection) -> Result<()> {
+    println!("\n=== 用户列表 ===\n");
+
+    let filter = db::service::UserFilter {
+        active: Some(true),
+        ..Default::default()
+    };
+    let mut page = 0;
+    loop {
+        let users = db::service::list_users(db, &filter, page, INTERACTIVE_PAGE_SIZE).await?;
+        print_users(&users);
+        if !next_page(&users, &mut page)? {
+            return Ok(());
+        }
+    }
+}
+
+fn print_users(users: &db::service::Page<db::entities::user::Model>) {
+    if users.items.is_empty() {
+        println!("📭 暂无用户");
+        return;
+    }
+
+    for user in &users.items {
+        println!("📋 Network ID: {}", user.network_id);
+        println!("   姓名: {}", user.real_name);
+        println!(
+            "   ATC 等级: {} | 飞行员等级: {}",
+            user.atc_rating, user.pilot_rating
+        );
+        println!("   创建时间: {}", user.created_at);
+        if let Some(disabled_at) = user.disabled_at {
+            println!("   ⛔ 已停用: {}", disabled_at);
+        }
+        if let Some(deleted_at) = user.deleted_at {
+            println!("   🗑️  已删除: {}", deleted_at);
+        }
+        println!();
+    }
+    print_page_footer(users);
+}
+
+fn print_page_footer<T>(page: &db::service::Page<T>) {
+    println!(
+        "第 {}/{} 页，共 {} 条",
+        page.page + 1,
+        page.page_count().max(1),
+        page.total
+    );
+}
+
+/// A page as JSON, with the items converted by `item`
+fn page_json<T>(
+    page: &db::service::Page<T>,
+    item: impl Fn(&T) -> serde_json::Value,
+) -> serde_json::Value {
+    json!({
+        "page": page.page + 1,
+        "pages": page.page_count().max(1),
+        "total": page.total,
+        "items": page.items.iter().map(item).collect::<Vec<_>>(),
+    })
+}
+
+/// Ask whether to show the next page of an interactive listing
+fn next_page<T>(current: &db::service::Page<T>, page: &mut u64) -> Result<bool> {
+    if current.page + 1 >= current.page_count() {
+        return Ok(false);
+    }
+    if !prompt("回车显示下一页，q 返回: ")?.is_empty() {
+        return Ok(false);
+    }
+    *page += 1;
+    Ok(true)
+}
+
+async fn add_client_to_whitelist(db: &sea_orm::DatabaseConnection) -> Result<()> {
+    println!("\n=== 添加客户端到白名单 ===");
+
+    let client_id = prompt("Client ID (4字符，如 69d7): ")?;
+    let client_name = prompt("Client 名称 (如 EuroScope 3.2): ")?;
+    let min_version = check_min_version(prompt("最低版本 (如 3.2.1，留空表示不限制): ")?)?;
+    let client_key = prompt("Client 密钥 (用于认证挑战，留空表示不挑战): ")?;
 
     // Add to whitelist
     println!("\n💾 添加到白名单...");
-    let entry = db::service::add_client_to_whitelist(db, client_id.clone(), client_name).await?;
+    add_whitelist_entry(db, client_id, client_name, min_version, client_key).await
+}
+
+async fn add_whitelist_entry(
+    db: &sea_orm::DatabaseConnection,
+    client_id: String,
+    client_name: String,
+    min_version: Option<String>,
+    client_key: String,
+) -> Result<()> {
+    let client_key = (!client_key.is_empty()).then_some(client_key);
+    let entry =
+        db::service::add_client_to_whitelist(db, client_id, client_name, min_version, client_key)
+            .await?;
 
     println!("\n✅ 客户端已添加到白名单！");
     println!("   Client ID: {}", entry.client_id);
     println!("   Client 名称: {}", entry.client_name);
+    println!(
+        "   最低版本: {}",
+        entry.min_version.as_deref().unwrap_or("不限制")
+    );
+    println!("   Client 密钥: {}", mask_key(entry.client_key.as_deref()));
 
     Ok(())
 }
+
+async fn list_whitelist(db: &sea_orm::DatabaseConnection) -> Result<()> {
+    println!("\n=== 客户端白名单 ===\n");
+
+    let filter = db::service::WhitelistFilter::default();
+    let mut page = 0;
+    loop {
+        let entries = db::service::list_whitelist(db, &filter, page, INTERACTIVE_PAGE_SIZE).await?;
+        print_whitelist(&entries, false);
+        if !next_page(&entries, &mut page)? {
+            return Ok(());
+        }
+    }
+}
+
+fn print_whitelist(
+    entries: &db::service::Page<db::entities::client_whitelist::Model>,
+    show_keys: bool,
+) {
+    if entries.items.is_empty() {
+        println!("📭 白名单为空");
+        return;
+    }
+
+    // Widths are in columns; each CJK character takes two
+    println!(
+        "{:<10} {:<22} {:<4} {:<8} {:<16} Client 密钥",
+        "Client ID", "名称", "启用", "最低版本", "添加时间"
+    );
+    for entry in &entries.items {
+        let key = match (show_keys, entry.client_key.as_deref()) {
+            (true, Some(key)) => key,
+            (_, key) => mask_key(key),
+        };
+        println!(
+            "{:<10} {:<24} {:<5} {:<12} {:<20} {}",
+            entry.client_id,
+            entry.client_name,
+            if entry.enabled { "是" } else { "否" },
+            entry.min_version.as_deref().unwrap_or("-"),
+            entry.created_at.format("%Y-%m-%d %H:%M"),
+            key
+        );
+    }
+    println!();
+    print_page_footer(entries);
+}
+
+/// Never print client keys in full
+fn mask_key(key: Option<&str>) -> &'static str {
+    match key {
+        Some(_) => "********",
+        None => "未设置",
+    }
+}
+
+/// `openfsd-admin whitelist disable|enable <client_id>`
+async fn set_client_enabled(
+    db: &sea_orm::DatabaseConnection,
+    client_id: &str,
+    enabled: bool,
+) -> Result<()> {
+    if !db::service::set_client_enabled(db, client_id, enabled).await? {
+        let entry = db::service::find_whitelist_entry(db, client_id).await?;
+        return Err(match (entry, enabled) {
+            (None, _) => format!("Client not whitelisted: {}", client_id),
+            (Some(_), true) => format!("Client is already enabled: {}", client_id),
+            (Some(_), false) => format!("Client is already disabled: {}", client_id),
+        }
+        .into());
+    }
+
+    match enabled {
+        true => println!("✅ 已启用客户端 {}", client_id),
+        false => println!(
+            "✅ 已停用客户端 {}（可用 whitelist enable 恢复）",
+            client_id
+        ),
+    }
+    Ok(())
+}
+
+/// `openfsd-admin whitelist remove <client_id>`
+async fn remove_whitelist_entry(
+    db: &sea_orm::DatabaseConnection,
+    client_id: &str,
+    yes: bool,
+) -> Result<()> {
+    if !yes && !confirm(&format!("确定要从白名单移除客户端 {} 吗？", client_id))? {
+        return Err("Cancelled".into());
+    }
+    if !db::service::remove_client_from_whitelist(db, client_id).await? {
+        return Err(format!("Client not whitelisted: {}", client_id).into());
+    }
+    println!("✅ 已从白名单移除客户端 {}", client_id);
+    Ok(())
+}
+
+async fn update_client_min_version(db: &sea_orm::DatabaseConnection) -> Result<()> {
+    println!("\n=== 更新客户端最低版本 ===");
+
+    let client_id = prompt("Client ID: ")?;
+    let min_version = check_min_version(prompt("最低版本 (如 3.2.1，留空表示不限制): ")?)?;
+    set_min_version(db, &client_id, min_version).await
+}
+
+async fn set_min_version(
+    db: &sea_orm::DatabaseConnection,
+    client_id: &str,
+    min_version: Option<String>,
+) -> Result<()> {
+    let entry = db::service::update_client_min_version(db, client_id, min_version)
+        .await?
+        .ok_or_else(|| format!("Client not on the whitelist: {}", client_id))?;
+
+    println!("\n✅ 最低版本已更新！");
+    println!("   Client ID: {}", entry.client_id);
+    println!(
+        "   最低版本: {}",
+        entry.min_version.as_deref().unwrap_or("不限制")
+    );
+    Ok(())
+}
+
+/// A minimum version as entered; empty means no minimum
+fn check_min_version(min_version: String) -> Result<Option<String>> {
+    if min_version.is_empty() {
+        return Ok(None);
+    }
+    if auth::version::ClientVersion::parse(&min_version).is_none() {
+        return Err(format!("Invalid version: {}", min_version).into());
+    }
+    Ok(Some(min_version))
+}
+
+/// `openfsd-admin whitelist add|set-min-version|list`
+async fn whitelist_command(
+    db: &sea_orm::DatabaseConnection,
+    command: WhitelistCommand,
+) -> Result<()> {
+    match command {
+        WhitelistCommand::Add {
+            client_id,
+            name,
+            min_version,
+            key,
+            key_stdin,
+        } => {
+            let min_version = check_min_version(min_version.unwrap_or_default())?;
+            let client_key = match (key, key_stdin) {
+                (Some(key), _) => key,
+                (None, true) => read_secret(true, "")?,
+                (None, false) => String::new(),
+            };
+            add_whitelist_entry(db, client_id, name, min_version, client_key).await
+        }
+        WhitelistCommand::Disable { client_id } => set_client_enabled(db, &client_id, false).await,
+        WhitelistCommand::Enable { client_id } => set_client_enabled(db, &client_id, true).await,
+        WhitelistCommand::Remove { client_id, yes } => {
+            remove_whitelist_entry(db, &client_id, yes).await
+        }
+        WhitelistCommand::SetMinVersion {
+            client_id,
+            min_version,
+        } => {
+            let min_version = check_min_version(min_version.unwrap_or_default())?;
+            set_min_version(db, &client_id, min_version).await
+        }
+        WhitelistCommand::List {
+            client_id,
+            name,
+            enabled,
+            show_keys,
+            output,
+        } => {
+            if show_keys && !confirm("⚠️  将以明文显示 Client 密钥，确定继续吗？")?
+            {
+                return Err("Cancelled".into());
+            }
+            let filter = db::service::WhitelistFilter {
+                client_id_prefix: client_id,
+                name_contains: name,
+                enabled,
+            };
+            let entries =
+                db::service::list_whitelist(db, &filter, output.page - 1, output.limit).await?;
+            if !output.json {
+                print_whitelist(&entries, show_keys);
+                return Ok(());
+            }
+            let json = page_json(&entries, |entry| {
+                let mut json = json!({
+                    "client_id": entry.client_id,
+                    "client_name": entry.client_name,
+                    "enabled": entry.enabled,
+                    "min_version": entry.min_version,
+                    "has_key": entry.client_key.is_some(),
+                    "created_at": entry.created_at.to_rfc3339(),
+                });
+                if show_keys {
+                    json["client_key"] = json!(entry.client_key);
+                }
+                json
+            });
+            println!("{}", json);
+            Ok(())
+        }
+    }
+}
+
+async fn prefile_flight_plan(db: &sea_orm::DatabaseConnection) -> Result<()> {
+    println!("\n=== 预提交飞行计划 ===");
+
+    let network_id = prompt("Network ID: ")?;
+    let callsign = prompt("呼号: ")?;
+    let plan = FlightPlan {
+        flight_rules: prompt("飞行规则 (I/V): ")?,
+        aircraft: prompt("机型 (如 H/B744/L): ")?,
+        cruise_speed: prompt("巡航真空速 (kt): ")?,
+        departure: prompt("起飞机场: ")?,
+        departure_time: prompt("预计起飞时间 (UTC HHMM): ")?,
+        actual_departure_time: String::new(),
+        altitude: prompt("巡航高度: ")?,
+        destination: prompt("目的地机场: ")?,
+        hours_enroute: prompt("航程时间 (小时): ")?,
+        minutes_enroute: prompt("航程时间 (分钟): ")?,
+        hours_fuel: prompt("燃油续航 (小时): ")?,
+        minutes_fuel: prompt("燃油续航 (分钟): ")?,
+        alternate: prompt("备降机场: ")?,
+        remarks: prompt("备注: ")?,
+        route: prompt("航路: ")?,
+        route_elements: Vec::new(),
+    }
+    .with_route_elements();
+
+    let ttl = prompt("有效期 [24h]: ")?;
+    let ttl = if ttl.is_empty() { "24h" } else { &ttl };
+    save_prefile(db, &network_id, &callsign, &plan, ttl).await
+}
+
+/// `openfsd-admin prefile --cid <cid> --callsign <callsign> ...`
+async fn prefile_command(db: &sea_orm::DatabaseConnection, args: PrefileArgs) -> Result<()> {
+    let plan = FlightPlan {
+        flight_rules: args.rules,
+        aircraft: args.aircraft,
+        cruise_speed: args.speed,
+        departure: args.departure,
+        departure_time: args.departure_time,
+        actual_departure_time: String::new(),
+        altitude: args.altitude,
+        destination: args.destination,
+        hours_enroute: args.hours_enroute,
+        minutes_enroute: args.minutes_enroute,
+        hours_fuel: args.hours_fuel,
+        minutes_fuel: args.minutes_fuel,
+        alternate: args.alternate,
+        remarks: args.remarks,
+        route: args.route,
+        route_elements: Vec::new(),
+    }
+    .with_route_elements();
+    save_prefile(db, &args.cid, &args.callsign, &plan, &args.ttl).await
+}
+
+async fn save_prefile(
+    db: &sea_orm::DatabaseConnection,
+    network_id: &str,
+    callsign: &str,
+    plan: &FlightPlan,
+    ttl: &str,
+) -> Result<()> {
+    let ttl = parse_duration(ttl).ok_or_else(|| format!("Invalid TTL: {}", ttl))?;
+    plan.validate()?;
+
+    let prefile = db::service::upsert_prefiled_flight_plan(
+        db,
+        network_id,
+        callsign,
+        plan,
+        chrono::Utc::now() + ttl,
+    )
+    .await?;
+
+    println!("\n✅ 飞行计划已预提交！");
+    println!("   Network ID: {}", prefile.network_id);
+    println!("   呼号: {}", prefile.callsign);
+    println!("   {} → {}", prefile.departure, prefile.destination);
+    println!("   有效期至: {}", prefile.expires_at);
+
+    Ok(())
+}
+
+fn prompt(label: &str) -> Result<String> {
+    print!("{}", label);
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_string())
+}
+
+/// Ask a yes/no question on stderr, so stdout stays clean for `--json`
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{} (y/N): ", question);
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().eq_ignore_ascii_case("y"))
+}
+
+/// `openfsd-admin issue-token <cid> <ttl>` - print a single-use login token
+async fn issue_token(
+    db_conn: &sea_orm::DatabaseConnection,
+    network_id: &str,
+    ttl: &str,
+) -> Result<()> {
+    let ttl = parse_duration(ttl).ok_or_else(|| format!("Invalid TTL: {}", ttl))?;
+
+    if db::service::find_user_by_network_id(db_conn, network_id)
+        .await?
+        .is_none()
+    {
+        return Err(format!("User not found: {}", network_id).into());
+    }
+
+    let token =
+        auth::token::issue_login_token(db_conn, network_id, ttl, chrono::Utc::now()).await?;
+    println!("{}{}", auth::token::TOKEN_PREFIX, token);
+
+    Ok(())
+}
+
+/// `openfsd-admin stats [--days N]` - print daily statistics
+async fn stats_report(db_conn: &sea_orm::DatabaseConnection, days: u32) -> Result<()> {
+    let rows = db::service::list_daily_stats(db_conn, days).await?;
+    if rows.is_empty() {
+        println!("最近 {} 天没有统计数据", days);
+        return Ok(());
+    }
+
+    // CJK headers are two columns wide per character, hence the narrower widths
+    println!(
+        "{:<10} {:>8} {:>10} {:>10} {:>10} {:>8}",
+        "日期", "峰值", "CID", "连接", "消息", "计划"
+    );
+    for row in rows {
+        println!(
+            "{:<12} {:>10} {:>10} {:>12} {:>12} {:>10}",
+            row.date.to_string(),
+            row.peak_clients,
+            row.unique_cids,
+            row.connections,
+            row.messages,
+            row.flight_plans
+        );
+    }
+
+    Ok(())
+}
+
+/// `openfsd-admin stats --handlers` - packet counts and handler times from
+/// the control socket
+async fn handler_stats_report(
+    control: ControlArgs,
+    json: bool,
+    config: Option<&Path>,
+) -> Result<()> {
+    let (address, secret) = control_target(control, config)?;
+    let stats = control::server_stats(&address, &secret).await?;
+    if json {
+        println!("{}", serde_json::to_string(&stats)?);
+        return Ok(());
+    }
+
+    let report = &stats.handlers;
+    if report.commands.is_empty() {
+        println!("📭 服务器还没有处理过数据包");
+    } else {
+        println!(
+            "{:<8} {:>10} {:>10} {:>12} {:>10} {:>10} {:>10}",
+            "Command", "Received", "Handled", "Total ms", "Mean ms", "p99 ms", "Max ms"
+        );
+        for command in &report.commands {
+            println!(
+                "{:<8} {:>10} {:>10} {:>12.1} {:>10.3} {:>10.3} {:>10.3}",
+                command.command,
+                command.received,
+                command.handled,
+                command.total_ms,
+                command.mean_ms,
+                command.p99_ms,
+                command.max_ms
+            );
+        }
+        let errors: Vec<String> = report
+            .error_replies
+            .iter()
+            .map(|(code, count)| format!("{}×{}", code, count))
+            .collect();
+        println!(
+            "\n错误回复: {}",
+            if errors.is_empty() {
+                "-".to_string()
+            } else {
+                errors.join(", ")
+            }
+        );
+        println!("无法解析的行: {}", report.parse_errors);
+    }
+
+    let queues = &stats.queues;
+    println!(
+        "\n数据包队列: {}/{} (最高 {})",
+        queues.packets.depth, queues.packets.capacity, queues.packets.high_water
+    );
+    println!(
+        "广播队列: {}/{} (最高 {})",
+        queues.broadcast.depth, queues.broadcast.capacity, queues.broadcast.high_water
+    );
+    for client in &queues.worst_clients {
+        println!(
+            "  {:<12} {:<22} {:>6}",
+            client.callsign.as_deref().unwrap_or("-"),
+            client.addr,
+            client.depth
+        );
+    }
+    println!(
+        "积压断开: {} 次, 丢弃消息: {}",
+        queues.lagged, queues.dropped
+    );
+    Ok(())
+}
+
+/// `openfsd-admin export-track --session <id> --format geojson|kml --out <file>`
+async fn export_track(
+    db_conn: &sea_orm::DatabaseConnection,
+    session: &str,
+    format: TrackFormat,
+    out: &str,
+) -> Result<()> {
+    let mut file = io::BufWriter::new(std::fs::File::create(out)?);
+    let result = tracks::export::export_track(db_conn, session, format, &mut file).await;
+    drop(file);
+
+    match result {
+        Ok(Some(info)) => {
+            println!(
+                "✅ 已导出 {} 的 {} 个轨迹点到 {}",
+                info.callsign, info.samples, out
+            );
+            Ok(())
+        }
+        Ok(None) => {
+            std::fs::remove_file(out)?;
+            println!("会话 {} 没有轨迹数据", session);
+            Ok(())
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(out);
+            Err(e.into())
+        }
+    }
+}
+
+/// `openfsd-admin user import <file> [--dry-run] [--on-duplicate skip|update|fail]`
+async fn import_users(
+    db_conn: &sea_orm::DatabaseConnection,
+    file: &Path,
+    dry_run: bool,
+    on_duplicate: OnDuplicate,
+    security: &SecurityConfig,
+) -> Result<()> {
+    let reader = io::BufReader::new(std::fs::File::open(file)?);
+    let report = db::user_csv::import_users(
+        db_conn,
+        reader,
+        on_duplicate,
+        dry_run,
+        security,
+        "openfsd-admin",
+        |done, total| {
+            eprint!("\r⏳ 已写入 {}/{}", done, total);
+            if done == total {
+                eprintln!();
+            }
+        },
+    )
+    .await?;
+
+    for error in &report.errors {
+        println!("⚠️  第 {} 行: {}", error.line, error.message);
+    }
+    let prefix = if dry_run { "试运行：将" } else { "已" };
+    println!(
+        "{}新建 {} 个、更新 {} 个账户，跳过 {} 个，{} 行有错误",
+        prefix,
+        report.created,
+        report.updated,
+        report.skipped,
+        report.errors.len()
+    );
+    if !report.errors.is_empty() {
+        return Err(format!("{} rows were not imported", report.errors.len()).into());
+    }
+    Ok(())
+}
+
+/// `openfsd-admin airports import <file>`
+async fn import_airports(db_conn: &sea_orm::DatabaseConnection, file: &Path) -> Result<()> {
+    let reader = io::BufReader::new(std::fs::File::open(file)?);
+    let report =
+        db::airport_csv::import_airports(db_conn, reader, "openfsd-admin", |done, total| {
+            eprint!("\r⏳ 已写入 {}/{}", done, total);
+            if done == total {
+                eprintln!();
+            }
+        })
+        .await?;
+
+    for error in &report.errors {
+        println!("⚠️  第 {} 行: {}", error.line, error.message);
+    }
+    println!(
+        "已导入 {} 个机场，跳过 {} 行，{} 行有错误",
+        report.imported,
+        report.skipped,
+        report.errors.len()
+    );
+    println!("运行中的服务器需执行 `openfsd-admin airports reload` 或重启后生效");
+    Ok(())
+}
+
+/// `openfsd-admin user export <file> [--include-hashes]`
+async fn export_users(
+    db_conn: &sea_orm::DatabaseConnection,
+    file: &Path,
+    include_hashes: bool,
+) -> Result<()> {
+    let writer = io::BufWriter::new(std::fs::File::create(file)?);
+    let count = db::user_csv::export_users(db_conn, writer, include_hashes).await?;
+    println!("✅ 已导出 {} 个账户到 {}", count, file.display());
+    if include_hashes {
+        println!("⚠️  文件包含密码哈希，请妥善保管");
+    }
+    Ok(())
+}
+
+/// `openfsd-admin user list [--include-deleted|--deleted-only] [--cid <prefix>]
+/// [--name <text>] [--atc-rating N] [--pilot-rating N] [--limit N] [--page N] [--json]`
+async fn list_users_command(
+    db_conn: &sea_orm::DatabaseConnection,
+    args: UserListArgs,
+) -> Result<()> {
+    let filter = db::service::UserFilter {
+        network_id_prefix: args.cid,
+        name_contains: args.name,
+        atc_rating: args.atc_rating,
+        pilot_rating: args.pilot_rating,
+        active: if args.deleted_only {
+            Some(false)
+        } else if args.include_deleted {
+            None
+        } else {
+            Some(true)
+        },
+    };
+    let output = args.output;
+
+    let users = db::service::list_users(db_conn, &filter, output.page - 1, output.limit).await?;
+    if !output.json {
+        print_users(&users);
+        return Ok(());
+    }
+    let json = page_json(&users, |user| {
+        json!({
+            "network_id": user.network_id,
+            "real_name": user.real_name,
+            "atc_rating": user.atc_rating,
+            "pilot_rating": user.pilot_rating,
+            "email": user.email,
+            "created_at": user.created_at.to_rfc3339(),
+            "last_login_at": user.last_login_at.map(|at| at.to_rfc3339()),
+            "disabled_at": user.disabled_at.map(|at| at.to_rfc3339()),
+            "deleted_at": user.deleted_at.map(|at| at.to_rfc3339()),
+        })
+    });
+    println!("{}", json);
+    Ok(())
+}
+
+/// `openfsd-admin session list [--cid <cid>] [--from YYYY-MM-DD] [--to YYYY-MM-DD]
+/// [--limit N] [--page N] [--json]`
+async fn list_sessions_command(
+    db_conn: &sea_orm::DatabaseConnection,
+    network_id: Option<String>,
+    from: Option<chrono::NaiveDate>,
+    to: Option<chrono::NaiveDate>,
+    output: ListOutput,
+) -> Result<()> {
+    let filter = db::service::SessionFilter {
+        network_id,
+        from: from.map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc()),
+        // The end date is inclusive
+        to: to.map(|date| {
+            (date + chrono::Duration::days(1))
+                .and_time(chrono::NaiveTime::MIN)
+                .and_utc()
+        }),
+    };
+
+    let sessions =
+        db::service::list_sessions(db_conn, &filter, output.page - 1, output.limit).await?;
+    if output.json {
+        let json = page_json(&sessions, |session| {
+            json!({
+                "session_id": session.session_id,
+                "network_id": session.network_id,
+                "callsign": session.callsign,
+                "client_type": session.client_type,
+                "address": session.address,
+                "connected_at": session.connected_at.to_rfc3339(),
+                "disconnected_at": session.disconnected_at.map(|at| at.to_rfc3339()),
+                "disconnect_reason": session.disconnect_reason,
+                "client_software": session.client_software,
+                "duration_secs": session.duration_secs,
+                "packets_in": session.packets_in,
+                "packets_out": session.packets_out,
+                "bytes_in": session.bytes_in,
+                "bytes_out": session.bytes_out,
+                "position_updates": session.position_updates,
+                "parse_errors": session.parse_errors,
+            })
+        });
+        println!("{}", json);
+        return Ok(());
+    }
+    if sessions.items.is_empty() {
+        println!("📭 没有会话记录");
+        return Ok(());
+    }
+    for session in &sessions.items {
+        println!(
+            "{}  {:<8} {:<10} {:<8} {:<16} {}",
+            session.connected_at.format("%Y-%m-%d %H:%M:%S"),
+            session.network_id,
+            session.callsign,
+            session.client_type,
+            session.address,
+            session.session_id
+        );
+    }
+    print_page_footer(&sessions);
+    Ok(())
+}
+
+/// `openfsd-admin user delete <cid> [--yes]` - soft-delete an account
+async fn delete_user(
+    db_conn: &sea_orm::DatabaseConnection,
+    network_id: &str,
+    yes: bool,
+) -> Result<()> {
+    if db::service::find_user_by_network_id(db_conn, network_id)
+        .await?
+        .is_none()
+    {
+        return Err(format!("User not found: {}", network_id).into());
+    }
+    if !yes && !confirm(&format!("确定要删除用户 {} 吗？", network_id))? {
+        return Err("Cancelled".into());
+    }
+
+    if !db::service::soft_delete_user(db_conn, network_id).await? {
+        return Err(format!("User not found: {}", network_id).into());
+    }
+    println!("✅ 已删除用户 {}（可用 user restore 恢复）", network_id);
+    Ok(())
+}
+
+/// `openfsd-admin user disable|enable <cid>`
+async fn set_user_disabled(
+    db_conn: &sea_orm::DatabaseConnection,
+    network_id: &str,
+    disabled: bool,
+) -> Result<()> {
+    let changed = if disabled {
+        db::service::disable_user(db_conn, network_id).await?
+    } else {
+        db::service::enable_user(db_conn, network_id).await?
+    };
+    if !changed {
+        let user = db::service::find_user_by_network_id(db_conn, network_id).await?;
+        return Err(match (user, disabled) {
+            (None, _) => format!("User not found: {}", network_id),
+            (Some(_), true) => format!("User is already disabled: {}", network_id),
+            (Some(_), false) => format!("User is not disabled: {}", network_id),
+        }
+        .into());
+    }
+
+    match disabled {
+        true => println!("✅ 已停用用户 {}（可用 user enable 恢复）", network_id),
+        false => println!("✅ 已启用用户 {}", network_id),
+    }
+    Ok(())
+}
+
+/// `openfsd-admin user restore <cid>` - undo a soft delete
+async fn restore_user(db_conn: &sea_orm::DatabaseConnection, network_id: &str) -> Result<()> {
+    if !db::service::restore_user(db_conn, network_id).await? {
+        return Err(format!("No deleted user: {}", network_id).into());
+    }
+    println!("✅ 已恢复用户 {}", network_id);
+    Ok(())
+}
+
+/// `openfsd-admin motd show|set [--key motd|atc_motd] [--by <name>]`
+///
+/// `set` reads the new lines from standard input; running servers pick them
+/// up within a minute.
+async fn motd_command(db_conn: &sea_orm::DatabaseConnection, command: MotdCommand) -> Result<()> {
+    let key = match &command {
+        MotdCommand::Show { key } | MotdCommand::Set { key, .. } => key,
+    };
+    if key != motd::MOTD_KEY && key != motd::ATC_MOTD_KEY {
+        return Err(format!("Unknown message key: {}", key).into());
+    }
+
+    match &command {
+        MotdCommand::Show { key } => match db::service::find_server_message(db_conn, key).await? {
+            Some(message) => {
+                println!(
+                    "# {}，更新于 {}{}",
+                    key,
+                    message.updated_at,
+                    message
+                        .updated_by
+                        .as_ref()
+                        .map(|by| format!("，更新者 {}", by))
+                        .unwrap_or_default()
+                );
+                for line in message.lines() {
+                    println!("{}", line);
+                }
+            }
+            None => println!("数据库中没有 {}，使用配置文件或默认内容", key),
+        },
+        MotdCommand::Set { key, by } => {
+            let lines: Vec<String> = io::stdin().lines().collect::<Result<_, _>>()?;
+            db::service::set_server_message(db_conn, key, &lines, by.as_deref()).await?;
+            println!("✅ 已更新 {}（{} 行）", key, lines.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// `openfsd-admin notes list <cid> [--limit N]` or
+/// `openfsd-admin notes add <cid> --author <supervisor cid> <text...>`
+async fn notes_command(db_conn: &sea_orm::DatabaseConnection, command: NotesCommand) -> Result<()> {
+    match command {
+        NotesCommand::List { cid, limit } => {
+            let notes = db::service::list_user_notes(db_conn, &cid, limit).await?;
+            if notes.is_empty() {
+                println!("📭 {} 没有备注", cid);
+            }
+            for note in notes {
+                println!(
+                    "{}  {}  {}",
+                    note.created_at.format("%Y-%m-%d %H:%M"),
+                    note.author_network_id,
+                    note.text
+                );
+            }
+        }
+        NotesCommand::Add { cid, author, text } => {
+            let is_supervisor = db::service::find_user_by_network_id(db_conn, &author)
+                .await?
+                .is_some_and(|user| user.is_supervisor());
+            if !is_supervisor {
+                return Err(format!("{} is not a supervisor", author).into());
+            }
+
+            let text = text.join(" ");
+            if text.chars().count() > db::entities::user_note::MAX_NOTE_LENGTH {
+                println!(
+                    "⚠️  备注超过 {} 字，已截断",
+                    db::entities::user_note::MAX_NOTE_LENGTH
+                );
+            }
+            db::service::add_user_note(db_conn, &cid, &author, &text)
+                .await?
+                .ok_or_else(|| format!("User not found: {}", cid))?;
+            println!("✅ 已为 {} 添加备注", cid);
+        }
+    }
+
+    Ok(())
+}
+
+/// `openfsd-admin ban add|list|remove`
+async fn ban_command(db_conn: &sea_orm::DatabaseConnection, command: BanCommand) -> Result<()> {
+    match command {
+        BanCommand::Add(args) => {
+            let ban = db::service::NewBan {
+                network_id: args.cid,
+                ip: args
+                    .ip
+                    .map(|ip| ip.to_string())
+                    .or(args.cidr.map(|range| range.trunc().to_string())),
+                reason: args.reason,
+                expires_at: args.expires.map(|expires| chrono::Utc::now() + expires),
+            };
+            let ban = db::service::add_ban(db_conn, ban, "openfsd-admin").await?;
+            println!(
+                "✅ 已添加封禁 #{}，{}",
+                ban.id,
+                format_remaining(&ban, chrono::Utc::now())
+            );
+        }
+        BanCommand::List { active, output } => {
+            let bans =
+                db::service::list_bans(db_conn, active, output.page - 1, output.limit).await?;
+            let now = chrono::Utc::now();
+            if output.json {
+                let json = page_json(&bans, |ban| {
+                    json!({
+                        "id": ban.id,
+                        "network_id": ban.network_id,
+                        "ip": ban.ip,
+                        "reason": ban.reason,
+                        "created_by": ban.created_by,
+                        "created_at": ban.created_at.to_rfc3339(),
+                        "expires_at": ban.expires_at.map(|at| at.to_rfc3339()),
+                        "active": ban.is_active(now),
+                    })
+                });
+                println!("{}", json);
+                return Ok(());
+            }
+            if bans.items.is_empty() {
+                println!("📭 没有封禁");
+                return Ok(());
+            }
+            for ban in &bans.items {
+                println!(
+                    "#{} {} {}",
+                    ban.id,
+                    [ban.network_id.as_deref(), ban.ip.as_deref()]
+                        .into_iter()
+                        .flatten()
+                        .collect::<Vec<_>>()
+                        .join(" / "),
+                    format_remaining(ban, now)
+                );
+                println!("   原因: {}", ban.reason);
+                println!(
+                    "   添加: {} ({})",
+                    ban.created_at.format("%Y-%m-%d %H:%M"),
+                    ban.created_by
+                );
+            }
+            println!();
+            print_page_footer(&bans);
+        }
+        BanCommand::Remove { id } => {
+            if !db::service::remove_ban(db_conn, id, "openfsd-admin").await? {
+                return Err(format!("Ban not found: {}", id).into());
+            }
+            println!("✅ 已解除封禁 #{}", id);
+        }
+    }
+    Ok(())
+}
+
+/// `openfsd-admin announce add|list|remove`
+async fn announce_command(
+    db_conn: &sea_orm::DatabaseConnection,
+    command: AnnounceCommand,
+) -> Result<()> {
+    match command {
+        AnnounceCommand::Add(args) => {
+            let fire_at = match (args.at, args.after) {
+                (Some(at), _) => at,
+                (None, after) => chrono::Utc::now() + after.unwrap_or_default(),
+            };
+            let announcement = db::service::NewAnnouncement {
+                text: args.text,
+                target: args.target,
+                fire_at,
+                repeat: args.repeat,
+            };
+            let announcement =
+                db::service::add_announcement(db_conn, announcement, "openfsd-admin").await?;
+            println!(
+                "✅ 已安排公告 #{}，{} 发送给{}{}",
+                announcement.id,
+                announcement.fire_at.format("%Y-%m-%d %H:%M UTC"),
+                format_target(&announcement.target),
+                announcement
+                    .repeat_secs
+                    .map(|secs| format!("，每 {} 重复", format_repeat(secs)))
+                    .unwrap_or_default()
+            );
+        }
+        AnnounceCommand::List { pending, output } => {
+            let announcements =
+                db::service::list_announcements(db_conn, pending, output.page - 1, output.limit)
+                    .await?;
+            if output.json {
+                let json = page_json(&announcements, |announcement| {
+                    json!({
+                        "id": announcement.id,
+                        "text": announcement.text,
+                        "target": announcement.target,
+                        "fire_at": announcement.fire_at.to_rfc3339(),
+                        "repeat_secs": announcement.repeat_secs,
+                        "fired_at": announcement.fired_at.map(|at| at.to_rfc3339()),
+                        "done": announcement.done,
+                        "created_by": announcement.created_by,
+                        "created_at": announcement.created_at.to_rfc3339(),
+                    })
+                });
+                println!("{}", json);
+                return Ok(());
+            }
+            if announcements.items.is_empty() {
+                println!("📭 没有公告");
+                return Ok(());
+            }
+            for announcement in &announcements.items {
+                println!(
+                    "#{} [{}] {}",
+                    announcement.id,
+                    format_target(&announcement.target),
+                    format_schedule(announcement)
+                );
+                println!("   内容: {}", announcement.text);
+                println!(
+                    "   添加: {} ({})",
+                    announcement.created_at.format("%Y-%m-%d %H:%M"),
+                    announcement.created_by
+                );
+            }
+            println!();
+            print_page_footer(&announcements);
+        }
+        AnnounceCommand::Remove { id } => {
+            if !db::service::remove_announcement(db_conn, id, "openfsd-admin").await? {
+                return Err(format!("Announcement not found: {}", id).into());
+            }
+            println!("✅ 已删除公告 #{}", id);
+        }
+    }
+    Ok(())
+}
+
+/// `openfsd-admin db status|migrate|rollback|check`
+async fn db_command(command: DbCommand, db_url: Option<String>) -> Result<()> {
+    let mut db_config = database_config(db_url);
+    if let DbCommand::Check = command {
+        // Report an unreachable database right away
+        db_config.startup_retry_timeout = 0;
+    }
+    let db_conn = db::connect(&db_config).await?;
+
+    match command {
+        DbCommand::Status => {
+            let migrations = Migrator::get_migration_with_status(&db_conn).await?;
+            let mut pending = 0;
+            for migration in &migrations {
+                let status = match migration.status() {
+                    MigrationStatus::Applied => "✅ 已应用",
+                    MigrationStatus::Pending => {
+                        pending += 1;
+                        "⏳ 待应用"
+                    }
+                };
+                println!("{}  {}", status, migration.name());
+            }
+            println!(
+                "\n已应用 {} 个，待应用 {} 个",
+                migrations.len() - pending,
+                pending
+            );
+        }
+        DbCommand::Migrate { steps } => {
+            let pending = Migrator::get_pending_migrations(&db_conn).await?;
+            if pending.is_empty() {
+                println!("✅ 数据库已是最新");
+                return Ok(());
+            }
+            let count = steps.map_or(pending.len(), |steps| pending.len().min(steps as usize));
+            Migrator::up(&db_conn, steps).await?;
+            for migration in &pending[..count] {
+                println!("✅ 已应用 {}", migration.name());
+            }
+        }
+        DbCommand::Rollback { steps, yes } => {
+            let applied = Migrator::get_applied_migrations(&db_conn).await?;
+            let undone: Vec<_> = applied.iter().rev().take(steps as usize).collect();
+            if undone.is_empty() {
+                println!("📭 没有已应用的迁移");
+                return Ok(());
+            }
+            for migration in &undone {
+                println!("   {}", migration.name());
+            }
+            let question = format!(
+                "⚠️  回滚这 {} 个迁移会删除相应的表和列及其中的数据，确定继续吗？",
+                undone.len()
+            );
+            if !yes && !confirm(&question)? {
+                return Err("Cancelled".into());
+            }
+            Migrator::down(&db_conn, Some(steps)).await?;
+            println!("✅ 已回滚 {} 个迁移", undone.len());
+        }
+        DbCommand::Check => {
+            let backend = match db_conn.get_database_backend() {
+                sea_orm::DatabaseBackend::Sqlite => "SQLite",
+                sea_orm::DatabaseBackend::Postgres => "PostgreSQL",
+                sea_orm::DatabaseBackend::MySql => "MySQL",
+            };
+            let version = db::server_version(&db_conn).await?;
+            let pending = Migrator::get_pending_migrations(&db_conn).await?.len();
+            println!("✅ 已连接 {}", db::sanitize_url(&db_config.url));
+            println!("   {} {}", backend, version);
+            println!("   待应用的迁移: {}", pending);
+        }
+    }
+    Ok(())
+}
+
+/// `openfsd-admin clients list|kick|drain|dump`
+async fn clients_command(command: ClientsCommand, config: Option<&Path>) -> Result<()> {
+    match command {
+        ClientsCommand::List {
+            json,
+            watch,
+            control,
+        } => {
+            let (address, secret) = control_target(control, config)?;
+            loop {
+                let clients = control::list_clients(&address, &secret).await?;
+                if json {
+                    println!("{}", serde_json::to_string(&clients)?);
+                } else {
+                    if watch.is_some() {
+                        // Clear the screen and move to the top left
+                        print!("\x1b[2J\x1b[H");
+                    }
+                    print_clients(&clients);
+                }
+                let Some(secs) = watch else {
+                    return Ok(());
+                };
+                tokio::time::sleep(std::time::Duration::from_secs(secs)).await;
+            }
+        }
+        ClientsCommand::Kick {
+            callsign,
+            reason,
+            control,
+        } => {
+            let (address, secret) = control_target(control, config)?;
+            control::kick_client(&address, &secret, &callsign, &reason).await?;
+            println!("✅ 已断开 {}", callsign);
+            Ok(())
+        }
+        ClientsCommand::Drain { control } => {
+            let (address, secret) = control_target(control, config)?;
+            control::drain(&address, &secret).await?;
+            println!("✅ 服务器不再接受新连接，在线客户端不受影响");
+            Ok(())
+        }
+        ClientsCommand::Dump { control } => {
+            let (address, secret) = control_target(control, config)?;
+            let path = control::dump_state(&address, &secret).await?;
+            println!("✅ 服务器状态已写入 {}", path.display());
+            Ok(())
+        }
+    }
+}
+
+/// The control socket address and secret, from the flags or the config
+fn control_target(args: ControlArgs, path: Option<&Path>) -> Result<(String, String)> {
+    let config = Config::load(path, std::env::vars(), &Overrides::default())?.control;
+    let address = args.address.unwrap_or(config.address);
+    let secret = args
+        .secret
+        .or(config.secret)
+        .ok_or("No control secret: set secret in the [control] section or pass --secret")?;
+    Ok((address, secret))
+}
+
+fn print_clients(clients: &[ClientInfo]) {
+    if clients.is_empty() {
+        println!("📭 没有在线的客户端");
+        return;
+    }
+
+    println!(
+        "{:<12} {:<10} {:<9} {:<6} {:<8} {:<22} {:<14} {:<10} {:<8} IP",
+        "Callsign", "CID", "Type", "Rating", "Aircraft", "Position", "Motion", "Online", "Memory"
+    );
+    for client in clients {
+        let position = match (client.latitude, client.longitude) {
+            (Some(lat), Some(lon)) => format!("{:.4}, {:.4}", lat, lon),
+            _ => "-".to_string(),
+        };
+        println!(
+            "{:<12} {:<10} {:<9} {:<6} {:<8} {:<22} {:<14} {:<10} {:<8} {}",
+            client.callsign,
+            client.cid.as_deref().unwrap_or("-"),
+            client.client_type,
+            client
+                .rating
+                .map_or("-".to_string(), |rating| rating.to_string()),
+            client.aircraft_type.as_deref().unwrap_or("-"),
+            position,
+            format_motion(client),
+            format_connected(client.connected_secs),
+            format_bytes(client.memory_bytes),
+            client.ip
+        );
+    }
+    println!("\n共 {} 个客户端", clients.len());
+}
+
+/// Heading and groundspeed, e.g. "090° 452kt", marked GND on the ground
+fn format_motion(client: &ClientInfo) -> String {
+    let (Some(heading), Some(groundspeed)) = (client.heading, client.groundspeed) else {
+        return "-".to_string();
+    };
+    match client.on_ground {
+        Some(true) => format!("{:03}° {}kt GND", heading, groundspeed),
+        _ => format!("{:03}° {}kt", heading, groundspeed),
+    }
+}
+
+/// A size in bytes, e.g. "2.5 KiB"
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        _ => format!("{:.1} KiB", bytes as f64 / 1024.0),
+    }
+}
+
+/// How long a client has been online, e.g. "1h 05m"
+fn format_connected(secs: u64) -> String {
+    let (hours, minutes) = (secs / 3600, secs / 60 % 60);
+    match hours {
+        0 => format!("{}m", minutes),
+        _ => format!("{}h {:02}m", hours, minutes),
+    }
+}
+
+/// How long a ban still runs, e.g. "剩余 6d 23h"
+fn format_remaining(ban: &db::entities::ban::Model, now: chrono::DateTime<chrono::Utc>) -> String {
+    let Some(expires_at) = ban.expires_at else {
+        return "永久".to_string();
+    };
+    let remaining = expires_at - now;
+    if remaining <= chrono::Duration::zero() {
+        return "已过期".to_string();
+    }
+    let (days, hours, minutes) = (
+        remaining.num_days(),
+        remaining.num_hours() % 24,
+        remaining.num_minutes() % 60,
+    );
+    match (days, hours) {
+        (0, 0) => format!("剩余 {}m", minutes.max(1)),
+        (0, _) => format!("剩余 {}h {}m", hours, minutes),
+        _ => format!("剩余 {}d {}h", days, hours),
+    }
+}
+
+/// Who an announcement goes to, e.g. "飞行员"
+fn format_target(target: &str) -> &'static str {
+    match target {
+        "pilots" => "飞行员",
+        "atc" => "管制员",
+        _ => "所有人",
+    }
+}
+
+/// A repeat interval in the largest whole unit, e.g. "90m" or "1d"
+fn format_repeat(secs: i64) -> String {
+    match secs {
+        secs if secs % 86400 == 0 => format!("{}d", secs / 86400),
+        secs if secs % 3600 == 0 => format!("{}h", secs / 3600),
+        secs if secs % 60 == 0 => format!("{}m", secs / 60),
+        secs => format!("{}s", secs),
+    }
+}
+
+/// When an announcement goes out next, or what became of it
+fn format_schedule(announcement: &db::entities::announcement::Model) -> String {
+    if announcement.done {
+        return match announcement.fired_at {
+            Some(fired_at) => format!("已发送 {}", fired_at.format("%Y-%m-%d %H:%M UTC")),
+            None => "已跳过 (错过发送时间)".to_string(),
+        };
+    }
+    let next = format!("下次 {}", announcement.fire_at.format("%Y-%m-%d %H:%M UTC"));
+    match announcement.repeat_secs {
+        Some(secs) => format!("{}，每 {} 重复", next, format_repeat(secs)),
+        None => next,
+    }
+}
+
+/// A ban length for `--expires`; it must be in the future
+fn parse_expiry(s: &str) -> Result<chrono::Duration, String> {
+    match parse_duration(s) {
+        Some(duration) if duration > chrono::Duration::zero() => Ok(duration),
+        _ => Err(format!(
+            "expected a duration such as 12h or 7d, got {:?}",
+            s
+        )),
+    }
+}
+
+/// Parse a duration such as "900" (seconds), "15m", "2h" or "7d"
+fn parse_duration(s: &str) -> Option<chrono::Duration> {
+    let s = s.trim();
+    let (value, unit) = match s.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&s[..i], c),
+        _ => (s, 's'),
+    };
+    let value: i64 = value.parse().ok()?;
+
+    match unit {
+        's' => Some(chrono::Duration::seconds(value)),
+        'm' => Some(chrono::Duration::minutes(value)),
+        'h' => Some(chrono::Duration::hours(value)),
+        'd' => Some(chrono::Duration::days(value)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("900"), Some(chrono::Duration::seconds(900)));
+        assert_eq!(parse_duration("15m"), Some(chrono::Duration::minutes(15)));
+        assert_eq!(parse_duration("12h"), Some(chrono::Duration::hours(12)));
+        assert_eq!(parse_duration(" 7d "), Some(chrono::Duration::days(7)));
+        assert_eq!(parse_duration("7w"), None);
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration(""), None);
+
+        assert_eq!(parse_expiry("7d"), Ok(chrono::Duration::days(7)));
+        assert!(parse_expiry("0h").is_err());
+        assert!(parse_expiry("-1d").is_err());
+    }
+
+    #[test]
+    fn test_format_remaining() {
+        let now = chrono::Utc::now();
+        let ban = |expires_at| db::entities::ban::Model {
+            id: 1,
+            network_id: Some("1234567".to_string()),
+            ip: None,
+            reason: "Test".to_string(),
+            created_by: "test".to_string(),
+            created_at: now,
+            expires_at,
+        };
+        let after = |duration| Some(now + duration);
+        assert_eq!(format_remaining(&ban(None), now), "永久");
+        assert_eq!(
+            format_remaining(&ban(after(chrono::Duration::hours(-1))), now),
+            "已过期"
+        );
+        assert_eq!(
+            format_remaining(&ban(after(chrono::Duration::minutes(150))), now),
+            "剩余 2h 30m"
+        );
+        assert_eq!(
+            format_remaining(&ban(after(chrono::Duration::hours(30))), now),
+            "剩余 1d 6h"
+        );
+    }
+
+    #[test]
+    fn test_format_connected() {
+        assert_eq!(format_connected(59), "0m");
+        assert_eq!(format_connected(300), "5m");
+        assert_eq!(format_connected(3900), "1h 05m");
+        assert_eq!(format_connected(90000), "25h 00m");
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(900), "900 B");
+        assert_eq!(format_bytes(2560), "2.5 KiB");
+    }
+
+    #[test]
+    fn test_format_motion() {
+        let mut client = ClientInfo {
+            callsign: "DLH4AB".to_string(),
+            cid: None,
+            client_type: "pilot".to_string(),
+            rating: None,
+            latitude: None,
+            longitude: None,
+            groundspeed: None,
+            heading: None,
+            on_ground: None,
+            connected_secs: 0,
+            ip: std::net::Ipv4Addr::LOCALHOST.into(),
+            memory_bytes: 0,
+            aircraft_type: None,
+        };
+        assert_eq!(format_motion(&client), "-");
+        (client.heading, client.groundspeed) = (Some(90), Some(452));
+        assert_eq!(format_motion(&client), "090° 452kt");
+        (client.groundspeed, client.on_ground) = (Some(0), Some(true));
+        assert_eq!(format_motion(&client), "090° 0kt GND");
+    }
+
+    #[test]
+    fn test_format_schedule() {
+        let now: chrono::DateTime<chrono::Utc> = "2026-10-17T12:00:00Z".parse().unwrap();
+        let mut announcement = db::entities::announcement::Model {
+            id: 1,
+            text: "Event starts in 30 minutes".to_string(),
+            target: "pilots".to_string(),
+            fire_at: now,
+            repeat_secs: Some(5400),
+            fired_at: None,
+            done: false,
+            created_by: "test".to_string(),
+            created_at: now,
+        };
+        assert_eq!(
+            format_schedule(&announcement),
+            "下次 2026-10-17 12:00 UTC，每 90m 重复"
+        );
+        (announcement.repeat_secs, announcement.done) = (None, true);
+        assert_eq!(format_schedule(&announcement), "已跳过 (错过发送时间)");
+        announcement.fired_at = Some(now);
+        assert_eq!(
+            format_schedule(&announcement),
+            "已发送 2026-10-17 12:00 UTC"
+        );
+        assert_eq!(format_repeat(86400), "1d");
+        assert_eq!(format_repeat(7200), "2h");
+        assert_eq!(format_repeat(45), "45s");
+    }
+
+    #[test]
+    fn test_announcement_needs_a_time() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(
+                ["openfsd-admin", "announce", "add", "Event starts soon"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+        assert_eq!(
+            parse(&[]).unwrap_err().kind(),
+            clap::error::ErrorKind::MissingRequiredArgument
+        );
+        assert!(parse(&["--in", "30m"]).is_ok());
+        assert!(parse(&["--at", "2026-10-17T18:00:00Z", "--repeat", "1h"]).is_ok());
+        assert!(parse(&["--in", "30m", "--target", "atc"]).is_ok());
+        assert!(parse(&["--in", "30m", "--target", "observers"]).is_err());
+        assert!(parse(&["--at", "tomorrow"]).is_err());
+        assert!(parse(&["--in", "30m", "--repeat", "0m"]).is_err());
+    }
+
+    #[test]
+    fn test_ban_target_is_required() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(
+                ["openfsd-admin", "ban", "add", "--reason", "Test"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+        assert_eq!(
+            parse(&[]).unwrap_err().kind(),
+            clap::error::ErrorKind::MissingRequiredArgument
+        );
+        assert!(parse(&["--cid", "1234567"]).is_ok());
+        assert!(parse(&["--cid", "1234567", "--ip", "203.0.113.7"]).is_ok());
+        assert!(parse(&["--cidr", "203.0.113.0/24", "--expires", "7d"]).is_ok());
+        assert!(parse(&["--ip", "203.0.113.7", "--cidr", "203.0.113.0/24"]).is_err());
+        assert!(parse(&["--ip", "not-an-address"]).is_err());
+        assert!(parse(&["--cid", "1234567", "--expires", "soon"]).is_err());
+    }
+}
@@ -0,0 +1,696 @@
+/// OpenFSD Load Tester
+///
+/// Simulates many pilots flying against one server and reports how it held up
+use clap::Parser;
+use openfsd::flight_plan::FlightPlan;
+use openfsd::packet::{Packet, PacketType};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+type Result<T, E = Box<dyn std::error::Error>> = std::result::Result<T, E>;
+
+/// Mean radius of the earth in nautical miles
+const EARTH_RADIUS_NM: f64 = 3440.065;
+
+/// How long a connection or a login may take before it counts as failed
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Simulate many pilots against an OpenFSD server
+///
+/// Client N logs in with network ID --cid-start + N, so the server needs
+/// either guest mode or accounts for that range with --password. Raise the
+/// server's [limits] per-address connection caps before testing from one
+/// machine.
+#[derive(Debug, Parser)]
+#[command(name = "openfsd-loadtest", version)]
+struct Cli {
+    /// Server to connect to
+    #[arg(long, default_value = "127.0.0.1:6809")]
+    server: String,
+
+    /// Number of simulated pilots
+    #[arg(long, short = 'n', default_value_t = 100)]
+    clients: usize,
+
+    /// New connections per second
+    #[arg(long, default_value_t = 50.0, value_parser = parse_positive)]
+    ramp: f64,
+
+    /// Seconds to keep every pilot flying once all have connected
+    #[arg(long, default_value_t = 60)]
+    duration: u64,
+
+    /// Network ID of the first pilot; the default range stays clear of the
+    /// bootstrap supervisor
+    #[arg(long, default_value_t = 1_900_000)]
+    cid_start: u64,
+
+    /// Password of every pilot; any password works in guest mode
+    #[arg(long, default_value = "loadtest")]
+    password: String,
+
+    /// Client ID every pilot identifies with; add it to the whitelist first
+    /// when the server enforces one
+    #[arg(long, default_value = "88e4")]
+    client_id: String,
+
+    /// Callsigns are this prefix and a four-digit number
+    #[arg(long, default_value = "LT")]
+    callsign_prefix: String,
+
+    /// Seconds between position updates; a round-trip probe goes with each
+    #[arg(long, default_value_t = 5.0, value_parser = parse_positive)]
+    position_interval: f64,
+
+    /// Chance that a position update comes with a text message
+    #[arg(long, default_value_t = 0.02)]
+    text_chance: f64,
+
+    /// Chance that a pilot files a flight plan after logging in
+    #[arg(long, default_value_t = 0.5)]
+    flight_plan_chance: f64,
+
+    /// Write per-pilot results to a CSV file
+    #[arg(long, value_name = "FILE")]
+    csv: Option<PathBuf>,
+}
+
+/// What one simulated pilot saw
+#[derive(Debug, Default)]
+struct PilotReport {
+    callsign: String,
+    login: Option<Duration>,
+    round_trips: Vec<Duration>,
+    sent: u64,
+    received: u64,
+    /// $ER packets addressed to the pilot
+    errors: u64,
+    /// The server closed the connection before the test ended
+    disconnected: bool,
+    /// Why the pilot never got flying
+    failure: Option<String>,
+}
+
+/// Packets from the server that the pilot acts on
+enum Event {
+    LoggedIn,
+    Error(String),
+    RoundTrip,
+    Closed,
+}
+
+/// Live totals for the progress line
+#[derive(Default)]
+struct Progress {
+    connected: AtomicUsize,
+    logged_in: AtomicUsize,
+    finished: AtomicUsize,
+}
+
+/// A position moving along a great circle
+#[derive(Debug, Clone)]
+struct Track {
+    latitude: f64,
+    longitude: f64,
+    /// Degrees true
+    heading: f64,
+    ground_speed: f64,
+    altitude: i32,
+}
+
+impl Track {
+    fn random(rng: &mut impl Rng) -> Self {
+        Self {
+            latitude: rng.gen_range(-60.0..60.0),
+            longitude: rng.gen_range(-180.0..180.0),
+            heading: rng.gen_range(0.0..360.0),
+            ground_speed: rng.gen_range(250.0..480.0),
+            altitude: rng.gen_range(10..40) * 1000,
+        }
+    }
+
+    /// Fly on for `elapsed`, following the great circle
+    fn advance(&mut self, elapsed: Duration) {
+        let distance = self.ground_speed * elapsed.as_secs_f64() / 3600.0 / EARTH_RADIUS_NM;
+        let (lat1, lon1) = (self.latitude.to_radians(), self.longitude.to_radians());
+        let heading = self.heading.to_radians();
+
+        let lat2 =
+            (lat1.sin() * distance.cos() + lat1.cos() * distance.sin() * heading.cos()).asin();
+        let lon2 = lon1
+            + (heading.sin() * distance.sin() * lat1.cos())
+                .atan2(distance.cos() - lat1.sin() * lat2.sin());
+
+        // The heading on arrival is the reverse of the bearing back
+        let back = ((lon1 - lon2).sin() * lat1.cos())
+            .atan2(lat2.cos() * lat1.sin() - lat2.sin() * lat1.cos() * (lon1 - lon2).cos());
+        self.latitude = lat2.to_degrees();
+        self.longitude = (lon2.to_degrees() + 540.0).rem_euclid(360.0) - 180.0;
+        self.heading = (back.to_degrees() + 180.0).rem_euclid(360.0);
+    }
+
+    /// @N(callsign):(squawk):(rating):(lat):(lon):(alt):(groundspeed):(pbh):(flags)
+    fn to_packet(&self, callsign: &str) -> Packet {
+        Packet {
+            packet_type: PacketType::PilotUpdate,
+            command: "N".to_string(),
+            source: String::new(),
+            destination: callsign.to_string(),
+            data: vec![
+                "2000".to_string(),
+                "1".to_string(),
+                format!("{:.5}", self.latitude),
+                format!("{:.5}", self.longitude),
+                self.altitude.to_string(),
+                format!("{:.0}", self.ground_speed),
+                "0".to_string(),
+                "0".to_string(),
+            ],
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let cli = Arc::new(cli);
+    let progress = Arc::new(Progress::default());
+    let ramp = Duration::from_secs_f64(cli.clients as f64 / cli.ramp);
+    let end = Instant::now() + ramp + Duration::from_secs(cli.duration);
+    println!(
+        "Starting {} pilots against {} at {} per second",
+        cli.clients, cli.server, cli.ramp
+    );
+
+    let reporter = tokio::spawn(report_progress(progress.clone(), cli.clients));
+    let mut pilots = Vec::with_capacity(cli.clients);
+    let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / cli.ramp));
+    for index in 0..cli.clients {
+        ticker.tick().await;
+        let (cli, progress) = (cli.clone(), progress.clone());
+        pilots.push(tokio::spawn(async move {
+            let report = fly(index, &cli, end, &progress).await;
+            progress.finished.fetch_add(1, Ordering::Relaxed);
+            report
+        }));
+    }
+
+    let mut reports = Vec::with_capacity(pilots.len());
+    for pilot in pilots {
+        reports.push(pilot.await?);
+    }
+    reporter.abort();
+
+    print_summary(&reports, &cli);
+    if let Some(path) = &cli.csv {
+        write_csv(path, &reports)?;
+        println!("Per-pilot results written to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Print a progress line every five seconds
+async fn report_progress(progress: Arc<Progress>, total: usize) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(5));
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        eprintln!(
+            "  {} connected, {} logged in, {} finished of {}",
+            progress.connected.load(Ordering::Relaxed),
+            progress.logged_in.load(Ordering::Relaxed),
+            progress.finished.load(Ordering::Relaxed),
+            total
+        );
+    }
+}
+
+/// Connect, log in and fly one pilot until `end`
+async fn fly(index: usize, cli: &Cli, end: Instant, progress: &Progress) -> PilotReport {
+    let callsign = format!("{}{:04}", cli.callsign_prefix, index + 1);
+    let cid = (cli.cid_start + index as u64).to_string();
+    let mut report = PilotReport {
+        callsign: callsign.clone(),
+        ..PilotReport::default()
+    };
+
+    let stream = match tokio::time::timeout(LOGIN_TIMEOUT, TcpStream::connect(&cli.server)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => return report.failed(format!("connect: {}", e)),
+        Err(_) => return report.failed("connect: timed out".to_string()),
+    };
+    progress.connected.fetch_add(1, Ordering::Relaxed);
+    let (reader, mut writer) = stream.into_split();
+    let received = Arc::new(AtomicU64::new(0));
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let reader = tokio::spawn(read_packets(
+        reader,
+        callsign.clone(),
+        events_tx,
+        received.clone(),
+    ));
+
+    let mut rng = StdRng::from_entropy();
+    let login = [
+        Packet {
+            packet_type: PacketType::Request,
+            command: "ID".to_string(),
+            source: callsign.clone(),
+            destination: "SERVER".to_string(),
+            data: vec![
+                cli.client_id.clone(),
+                "OpenFSD loadtest".to_string(),
+                "3".to_string(),
+                "2".to_string(),
+                cid.clone(),
+                rng.gen_range(100_000_000..999_999_999u32).to_string(),
+            ],
+        },
+        Packet {
+            packet_type: PacketType::Client,
+            command: "AP".to_string(),
+            source: callsign.clone(),
+            destination: "SERVER".to_string(),
+            data: vec![
+                cid.clone(),
+                cli.password.clone(),
+                "1".to_string(),
+                "100".to_string(),
+                "1".to_string(),
+                format!("Load Test {}", index + 1),
+            ],
+        },
+    ];
+    let started = Instant::now();
+    for packet in &login {
+        if let Err(e) = send(&mut writer, packet, &mut report).await {
+            reader.abort();
+            return report.failed(format!("login: {}", e));
+        }
+    }
+
+    // The server asks for capabilities once the login is accepted
+    let outcome = tokio::time::timeout(LOGIN_TIMEOUT, async {
+        loop {
+            match events.recv().await {
+                Some(Event::LoggedIn) => return Ok(()),
+                Some(Event::Error(line)) => return Err(format!("login refused: {}", line)),
+                Some(Event::RoundTrip) => {}
+                Some(Event::Closed) | None => return Err("login: connection closed".to_string()),
+            }
+        }
+    })
+    .await;
+    match outcome {
+        Ok(Ok(())) => {}
+        Ok(Err(failure)) => {
+            reader.abort();
+            report.received = received.load(Ordering::Relaxed);
+            return report.failed(failure);
+        }
+        Err(_) => {
+            reader.abort();
+            return report.failed("login: timed out".to_string());
+        }
+    }
+    report.login = Some(started.elapsed());
+    progress.logged_in.fetch_add(1, Ordering::Relaxed);
+
+    let mut track = Track::random(&mut rng);
+    if rng.gen_bool(cli.flight_plan_chance.clamp(0.0, 1.0)) {
+        let plan = flight_plan(&track, &mut rng);
+        let _ = send(&mut writer, &plan.to_packet(&callsign), &mut report).await;
+    }
+
+    // Probes are answered in order, so the oldest one is the one answered
+    let mut probes: VecDeque<Instant> = VecDeque::new();
+    let interval = Duration::from_secs_f64(cli.position_interval);
+    let mut ticker = tokio::time::interval(interval);
+    let mut messages = 0;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(end) => break,
+            _ = ticker.tick() => {
+                track.advance(interval);
+                let probe = Packet {
+                    packet_type: PacketType::Request,
+                    command: "CQ".to_string(),
+                    source: callsign.clone(),
+                    destination: callsign.clone(),
+                    data: vec!["RN".to_string()],
+                };
+                let mut packets = vec![track.to_packet(&callsign), probe];
+                if rng.gen_bool(cli.text_chance.clamp(0.0, 1.0)) {
+                    messages += 1;
+                    packets.push(Packet {
+                        packet_type: PacketType::Client,
+                        command: "TM".to_string(),
+                        source: callsign.clone(),
+                        destination: "@22800".to_string(),
+                        data: vec![format!("Load test message {}", messages)],
+                    });
+                }
+                for packet in &packets {
+                    if send(&mut writer, packet, &mut report).await.is_err() {
+                        report.disconnected = true;
+                        break;
+                    }
+                }
+                probes.push_back(Instant::now());
+            }
+            event = events.recv() => match event {
+                Some(Event::RoundTrip) => {
+                    if let Some(sent) = probes.pop_front() {
+                        report.round_trips.push(sent.elapsed());
+                    }
+                }
+                Some(Event::Error(_)) => report.errors += 1,
+                Some(Event::LoggedIn) => {}
+                Some(Event::Closed) | None => report.disconnected = true,
+            },
+        }
+        if report.disconnected {
+            break;
+        }
+    }
+
+    if !report.disconnected {
+        // #DP(callsign):(CID)
+        let logoff = Packet {
+            packet_type: PacketType::Client,
+            command: "DP".to_string(),
+            source: callsign.clone(),
+            destination: cid,
+            data: Vec::new(),
+        };
+        let _ = send(&mut writer, &logoff, &mut report).await;
+    }
+    reader.abort();
+    report.received = received.load(Ordering::Relaxed);
+    report
+}
+
+impl PilotReport {
+    fn failed(mut self, failure: String) -> Self {
+        self.failure = Some(failure);
+        self
+    }
+}
+
+async fn send(
+    writer: &mut OwnedWriteHalf,
+    packet: &Packet,
+    report: &mut PilotReport,
+) -> std::io::Result<()> {
+    writer.write_all(packet.format().as_bytes()).await?;
+    report.sent += 1;
+    Ok(())
+}
+
+/// Count everything the server sends and pass on what the pilot reacts to
+async fn read_packets(
+    reader: tokio::net::tcp::OwnedReadHalf,
+    callsign: String,
+    events: mpsc::UnboundedSender<Event>,
+    received: Arc<AtomicU64>,
+) {
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    while matches!(reader.read_line(&mut line).await, Ok(n) if n > 0) {
+        received.fetch_add(1, Ordering::Relaxed);
+        let event = match Packet::parse(&line) {
+            Ok(packet) if packet.destination == callsign => classify(&packet, &callsign),
+            _ => None,
+        };
+        if let Some(event) = event {
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+        line.clear();
+    }
+    let _ = events.send(Event::Closed);
+}
+
+/// $CQSERVER:(callsign):CAPS follows a successful login, and the server
+/// answers a pilot's RN request to itself with $CR(callsign):(callsign):RN.
+/// The 008 "No flightplan" notice after login is not an error.
+fn classify(packet: &Packet, callsign: &str) -> Option<Event> {
+    let first = packet.data.first().map(String::as_str);
+    match (packet.command.as_str(), first) {
+        ("CQ", Some("CAPS")) if packet.source == "SERVER" => Some(Event::LoggedIn),
+        ("CR", Some("RN")) if packet.source == callsign => Some(Event::RoundTrip),
+        ("ER", Some("008")) => None,
+        ("ER", _) => Some(Event::Error(packet.format().trim_end().to_string())),
+        _ => None,
+    }
+}
+
+/// A plausible plan from near the pilot's position
+fn flight_plan(track: &Track, rng: &mut impl Rng) -> FlightPlan {
+    const AIRPORTS: [&str; 8] = [
+        "ZBAA", "ZSPD", "ZGGG", "RJTT", "VHHH", "EGLL", "KJFK", "YSSY",
+    ];
+    let departure = AIRPORTS[rng.gen_range(0..AIRPORTS.len())];
+    let destination = AIRPORTS[rng.gen_range(0..AIRPORTS.len())];
+    FlightPlan {
+        flight_rules: "I".to_string(),
+        aircraft: "B738".to_string(),
+        cruise_speed: format!("{:.0}", track.ground_speed),
+        departure: departure.to_string(),
+        departure_time: "1200".to_string(),
+        actual_departure_time: "1200".to_string(),
+        altitude: format!("FL{}", track.altitude / 100),
+        destination: destination.to_string(),
+        hours_enroute: rng.gen_range(1..12).to_string(),
+        minutes_enroute: rng.gen_range(0..60).to_string(),
+        hours_fuel: "14".to_string(),
+        minutes_fuel: "0".to_string(),
+        alternate: String::new(),
+        remarks: "/V/ LOAD TEST".to_string(),
+        route: "DCT".to_string(),
+        route_elements: Vec::new(),
+    }
+    .with_route_elements()
+}
+
+/// The value below which `p` percent of the sorted samples fall
+fn percentile(sorted: &[Duration], p: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// "p50 12ms  p95 40ms  p99 81ms  max 120ms (998 samples)"
+fn describe(samples: &mut [Duration]) -> String {
+    samples.sort();
+    let Some(max) = samples.last() else {
+        return "no samples".to_string();
+    };
+    let ms = |d: Option<Duration>| d.map_or(0.0, |d| d.as_secs_f64() * 1000.0);
+    format!(
+        "p50 {:.1}ms  p95 {:.1}ms  p99 {:.1}ms  max {:.1}ms ({} samples)",
+        ms(percentile(samples, 50.0)),
+        ms(percentile(samples, 95.0)),
+        ms(percentile(samples, 99.0)),
+        ms(Some(*max)),
+        samples.len()
+    )
+}
+
+fn print_summary(reports: &[PilotReport], cli: &Cli) {
+    let logged_in = reports.iter().filter(|r| r.login.is_some()).count();
+    let mut logins: Vec<_> = reports.iter().filter_map(|r| r.login).collect();
+    let mut round_trips: Vec<_> = reports
+        .iter()
+        .flat_map(|r| r.round_trips.iter().copied())
+        .collect();
+    let sum = |field: fn(&PilotReport) -> u64| reports.iter().map(field).sum::<u64>();
+
+    println!();
+    println!(
+        "Pilots:       {} started, {} logged in",
+        cli.clients, logged_in
+    );
+    println!("Login:        {}", describe(&mut logins));
+    println!("Round trip:   {}", describe(&mut round_trips));
+    println!(
+        "Packets:      {} sent, {} received",
+        sum(|r| r.sent),
+        sum(|r| r.received)
+    );
+    println!(
+        "Errors:       {} $ER, {} disconnected by the server",
+        sum(|r| r.errors),
+        reports.iter().filter(|r| r.disconnected).count()
+    );
+
+    let mut failures: HashMap<&str, usize> = HashMap::new();
+    for failure in reports.iter().filter_map(|r| r.failure.as_deref()) {
+        *failures.entry(failure).or_default() += 1;
+    }
+    let mut failures: Vec<_> = failures.into_iter().collect();
+    failures.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    for (failure, count) in failures.iter().take(5) {
+        println!("Failed:       {} × {}", count, failure);
+    }
+}
+
+fn write_csv(path: &PathBuf, reports: &[PilotReport]) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record([
+        "callsign",
+        "login_ms",
+        "round_trips",
+        "round_trip_p50_ms",
+        "round_trip_max_ms",
+        "sent",
+        "received",
+        "errors",
+        "disconnected",
+        "failure",
+    ])?;
+    let ms = |d: Option<Duration>| d.map_or(String::new(), |d| d.as_millis().to_string());
+    for report in reports {
+        let mut round_trips = report.round_trips.clone();
+        round_trips.sort();
+        writer.write_record([
+            report.callsign.clone(),
+            ms(report.login),
+            round_trips.len().to_string(),
+            ms(percentile(&round_trips, 50.0)),
+            ms(round_trips.last().copied()),
+            report.sent.to_string(),
+            report.received.to_string(),
+            report.errors.to_string(),
+            report.disconnected.to_string(),
+            report.failure.clone().unwrap_or_default(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn parse_positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok(value),
+        _ => Err(format!("expected a positive number, got {:?}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openfsd::client::Client;
+
+    fn at(track: &Track) -> Client {
+        let mut client = Client::new("127.0.0.1:50001".parse().unwrap());
+        client.latitude = Some(track.latitude);
+        client.longitude = Some(track.longitude);
+        client
+    }
+
+    #[test]
+    fn test_track_follows_great_circle() {
+        let start = Track {
+            latitude: 40.0,
+            longitude: 175.0,
+            heading: 60.0,
+            ground_speed: 480.0,
+            altitude: 35000,
+        };
+        let mut track = start.clone();
+        track.advance(Duration::from_secs(3600));
+
+        let flown = at(&start).distance_nm(&at(&track)).unwrap();
+        assert!((flown - 480.0).abs() < 0.01, "{}", flown);
+        // Crossed the antimeridian, turning right on an eastbound great circle
+        assert!(track.longitude < -170.0, "{}", track.longitude);
+        assert!(
+            track.heading > 60.0 && track.heading < 90.0,
+            "{}",
+            track.heading
+        );
+
+        // Two half hours end up where one hour does
+        let mut halves = start.clone();
+        halves.advance(Duration::from_secs(1800));
+        halves.advance(Duration::from_secs(1800));
+        assert!(at(&halves).distance_nm(&at(&track)).unwrap() < 0.01);
+    }
+
+    #[test]
+    fn test_position_packet() {
+        let track = Track {
+            latitude: 31.14342,
+            longitude: 121.80525,
+            heading: 0.0,
+            ground_speed: 250.4,
+            altitude: 3000,
+        };
+        let packet = track.to_packet("LT0001");
+        assert_eq!(
+            packet.format(),
+            "@N:LT0001:2000:1:31.14342:121.80525:3000:250:0:0\r\n"
+        );
+        let parsed = Packet::parse(&packet.format()).unwrap();
+        assert_eq!(parsed.destination, "LT0001");
+        assert_eq!(parsed.data[2], "31.14342");
+    }
+
+    #[test]
+    fn test_classify() {
+        let event = |raw: &str| classify(&Packet::parse(raw).unwrap(), "LT0001");
+        assert!(matches!(
+            event("$CQSERVER:LT0001:CAPS"),
+            Some(Event::LoggedIn)
+        ));
+        assert!(matches!(
+            event("$CRLT0001:LT0001:RN:Load Test 1::1"),
+            Some(Event::RoundTrip)
+        ));
+        assert!(matches!(
+            event("$ERserver:LT0001:003::Invalid password"),
+            Some(Event::Error(_))
+        ));
+        assert!(event("$ERserver:LT0001:008:LT0001:No flightplan").is_none());
+        assert!(event("$CRLT0002:LT0001:RN:Someone Else::1").is_none());
+        assert!(event("#TMserver:LT0001:Welcome").is_none());
+    }
+
+    #[test]
+    fn test_percentile() {
+        let samples: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&samples, 50.0), Some(Duration::from_millis(50)));
+        assert_eq!(percentile(&samples, 99.0), Some(Duration::from_millis(99)));
+        assert_eq!(percentile(&samples, 0.0), Some(Duration::from_millis(1)));
+        assert_eq!(
+            percentile(&samples[..1], 95.0),
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(percentile(&[], 50.0), None);
+    }
+}
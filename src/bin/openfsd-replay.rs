@@ -0,0 +1,453 @@
+/// OpenFSD Replay Tool
+///
+/// Plays captured client sessions back against a server and reports where
+/// the server's responses differ from the capture
+use clap::Parser;
+use openfsd::capture::{self, CaptureLine, Direction};
+use openfsd::packet::{Packet, PacketType};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+type Result<T, E = Box<dyn std::error::Error + Send + Sync>> = std::result::Result<T, E>;
+
+/// Replay captured FSD sessions against a server
+///
+/// Every capture file is replayed as its own client, with the packets it
+/// sent at their original pace. The packets the server sent in the capture
+/// are the expected responses.
+#[derive(Debug, Parser)]
+#[command(name = "openfsd-replay", version)]
+struct Cli {
+    /// Capture files, one per client
+    #[arg(required = true)]
+    captures: Vec<PathBuf>,
+
+    /// Server to connect to
+    #[arg(long, default_value = "127.0.0.1:6809")]
+    server: String,
+
+    /// Replay this many times faster than recorded, e.g. 2 or 0.5
+    #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+    speed: f64,
+
+    /// TOML file mapping captured callsigns and CIDs to the ones to use
+    #[arg(long, value_name = "FILE")]
+    map: Option<PathBuf>,
+
+    /// Seconds to keep listening for responses after the last packet
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+
+    /// Commands whose data changes every session; only their sender and
+    /// recipient are compared
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        value_delimiter = ',',
+        default_value = "DI,ZC,ZR"
+    )]
+    ignore_data: Vec<String>,
+}
+
+/// Callsigns and network IDs to replay as, so replays don't collide with
+/// the users they were captured from
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Mapping {
+    callsigns: HashMap<String, String>,
+    cids: HashMap<String, String>,
+    /// Passwords by mapped CID, as captures don't keep them
+    passwords: HashMap<String, String>,
+}
+
+impl Mapping {
+    fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        toml::from_str(&text)
+            .map_err(|e| format!("invalid mapping {}: {}", path.display(), e).into())
+    }
+
+    fn field(&self, value: &str) -> String {
+        self.callsigns
+            .get(value)
+            .or_else(|| self.cids.get(value))
+            .cloned()
+            .unwrap_or_else(|| value.to_string())
+    }
+
+    /// Rewrite the callsigns and CIDs in a packet, and fill in the password
+    /// of logins
+    fn apply(&self, line: &str) -> String {
+        let Ok(mut packet) = Packet::parse(line) else {
+            return line.to_string();
+        };
+        packet.source = self.field(&packet.source);
+        packet.destination = self.field(&packet.destination);
+        for value in &mut packet.data {
+            *value = self.field(value);
+        }
+
+        // #AP(callsign):SERVER:(CID):(password):... and
+        // #AA(callsign):SERVER:(name):(CID):(password):...
+        let cid_index = match (&packet.packet_type, packet.command.as_str()) {
+            (PacketType::Client, "AP") => Some(0),
+            (PacketType::Client, "AA") => Some(1),
+            _ => None,
+        };
+        if let Some(index) = cid_index {
+            let password = packet
+                .data
+                .get(index)
+                .and_then(|cid| self.passwords.get(cid));
+            if let (Some(password), Some(field)) =
+                (password.cloned(), packet.data.get_mut(index + 1))
+            {
+                *field = password;
+            }
+        }
+
+        packet.format().trim_end().to_string()
+    }
+}
+
+/// A response that didn't come back as captured
+#[derive(Debug, PartialEq)]
+enum Mismatch {
+    Missing(String),
+    Differs { expected: String, received: String },
+}
+
+/// What a replayed client sent and got back
+struct Session {
+    sent: usize,
+    expected: Vec<String>,
+    received: Vec<String>,
+    /// Why the replay stopped early, if it did
+    error: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Replay every capture at once; false if any of them didn't match
+async fn run(cli: Cli) -> Result<bool> {
+    let mapping = match &cli.map {
+        Some(path) => Mapping::load(path)?,
+        None => Mapping::default(),
+    };
+    let mut captures = Vec::new();
+    for path in &cli.captures {
+        let file = std::fs::File::open(path)
+            .map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+        let lines = capture::read_capture(std::io::BufReader::new(file))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        captures.push((path.clone(), lines));
+    }
+
+    let mapping = std::sync::Arc::new(mapping);
+    let listen = Duration::from_secs(cli.timeout);
+    let mut replays = JoinSet::new();
+    for (index, (path, lines)) in captures.into_iter().enumerate() {
+        let mapping = mapping.clone();
+        let server = cli.server.clone();
+        let speed = cli.speed;
+        replays.spawn(async move {
+            let session = replay(&server, &lines, speed, &mapping, listen).await;
+            (index, path, session)
+        });
+    }
+
+    let mut results = Vec::new();
+    while let Some(result) = replays.join_next().await {
+        results.push(result?);
+    }
+    results.sort_by_key(|(index, _, _)| *index);
+
+    let mut all_matched = true;
+    for (_, path, session) in results {
+        let session = match session {
+            Ok(session) => session,
+            Err(e) => {
+                println!("{}: ❌ {}", path.display(), e);
+                all_matched = false;
+                continue;
+            }
+        };
+        let mismatches = compare(&session.expected, &session.received, &cli.ignore_data);
+        let matched = session.expected.len() - mismatches.len();
+        println!(
+            "{}: {} sent, {} of {} responses as captured, {} received",
+            path.display(),
+            session.sent,
+            matched,
+            session.expected.len(),
+            session.received.len()
+        );
+        if let Some(error) = &session.error {
+            println!("  stopped early: {}", error);
+            all_matched = false;
+        }
+        for mismatch in &mismatches {
+            match mismatch {
+                Mismatch::Missing(expected) => println!("  missing   {}", expected),
+                Mismatch::Differs { expected, received } => {
+                    println!("  expected  {}", expected);
+                    println!("  received  {}", received);
+                }
+            }
+        }
+        all_matched &= mismatches.is_empty();
+    }
+    Ok(all_matched)
+}
+
+/// Play one capture as a client and collect everything the server sends
+async fn replay(
+    server: &str,
+    lines: &[CaptureLine],
+    speed: f64,
+    mapping: &Mapping,
+    listen: Duration,
+) -> Result<Session> {
+    let stream = TcpStream::connect(server)
+        .await
+        .map_err(|e| format!("cannot connect to {}: {}", server, e))?;
+    let (reader, mut writer) = stream.into_split();
+
+    let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+    let mut reader = tokio::spawn(async move {
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        while matches!(reader.read_line(&mut line).await, Ok(n) if n > 0) {
+            let _ = received_tx.send(line.trim_end().to_string());
+            line.clear();
+        }
+    });
+
+    let start = Instant::now();
+    let mut session = Session {
+        sent: 0,
+        expected: Vec::new(),
+        received: Vec::new(),
+        error: None,
+    };
+    for (line, due) in lines.iter().zip(schedule(lines, speed)) {
+        let packet = mapping.apply(&line.packet);
+        match line.direction {
+            Direction::Out => session.expected.push(packet),
+            Direction::In => {
+                tokio::time::sleep_until(start + due).await;
+                if let Err(e) = writer.write_all(format!("{}\r\n", packet).as_bytes()).await {
+                    session.error = Some(format!("sending {}: {}", packet, e));
+                    break;
+                }
+                session.sent += 1;
+            }
+        }
+    }
+
+    // Give the server time to answer the last packets; stop early if it
+    // hangs up
+    let _ = tokio::time::timeout(listen, &mut reader).await;
+    reader.abort();
+    while let Ok(line) = received_rx.try_recv() {
+        session.received.push(line);
+    }
+    Ok(session)
+}
+
+/// When each line is due, counted from the first one and scaled by `speed`
+fn schedule(lines: &[CaptureLine], speed: f64) -> Vec<Duration> {
+    let Some(first) = lines.first() else {
+        return Vec::new();
+    };
+    lines
+        .iter()
+        .map(|line| {
+            (line.at - first.at)
+                .to_std()
+                .unwrap_or_default()
+                .div_f64(speed)
+        })
+        .collect()
+}
+
+/// Match every expected response with an unused received packet of the
+/// same command, sender and recipient, preferring one with the same data
+///
+/// Received packets that were never captured, such as heartbeats, are not
+/// mismatches.
+fn compare(expected: &[String], received: &[String], ignore_data: &[String]) -> Vec<Mismatch> {
+    let parsed: Vec<_> = received
+        .iter()
+        .map(|line| Packet::parse(line).ok())
+        .collect();
+    let mut used = vec![false; received.len()];
+    let mut mismatches = Vec::new();
+
+    for line in expected {
+        let want = Packet::parse(line).ok();
+        // What can't be parsed is compared as is
+        let same_packet = |index: usize| match (&want, &parsed[index]) {
+            (Some(want), Some(got)) => {
+                got.packet_type == want.packet_type
+                    && got.command == want.command
+                    && got.source == want.source
+                    && got.destination == want.destination
+            }
+            _ => received[index] == *line,
+        };
+        let ignored = want
+            .as_ref()
+            .is_some_and(|want| ignore_data.contains(&want.command));
+
+        let unused = || (0..received.len()).filter(|&index| !used[index]);
+        let exact =
+            unused().find(|&index| same_packet(index) && (ignored || received[index] == *line));
+        match exact.or_else(|| unused().find(|&index| same_packet(index))) {
+            Some(index) => {
+                used[index] = true;
+                if exact.is_none() {
+                    mismatches.push(Mismatch::Differs {
+                        expected: line.clone(),
+                        received: received[index].clone(),
+                    });
+                }
+            }
+            None => mismatches.push(Mismatch::Missing(line.clone())),
+        }
+    }
+    mismatches
+}
+
+/// A replay speed for `--speed`; it must be positive
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err(format!("expected a positive number, got {:?}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(text: &str) -> Vec<CaptureLine> {
+        capture::read_capture(text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_schedule() {
+        let lines = capture(
+            "2025-06-01T12:00:00Z in #APCCA1501:SERVER:1234567:x:1:100:1:Jane Doe\n\
+             2025-06-01T12:00:00.500Z out #TMserver:CCA1501:Welcome\n\
+             2025-06-01T12:00:05Z in #TMCCA1501:@12345:Hello\n",
+        );
+        assert_eq!(
+            schedule(&lines, 1.0),
+            [0, 500, 5000].map(Duration::from_millis)
+        );
+        assert_eq!(
+            schedule(&lines, 2.0),
+            [0, 250, 2500].map(Duration::from_millis)
+        );
+        assert_eq!(schedule(&lines, 0.5)[2], Duration::from_secs(10));
+        assert!(schedule(&[], 1.0).is_empty());
+
+        assert_eq!(parse_speed("2"), Ok(2.0));
+        assert!(parse_speed("0").is_err());
+        assert!(parse_speed("-1").is_err());
+        assert!(parse_speed("fast").is_err());
+    }
+
+    #[test]
+    fn test_mapping() {
+        let mapping: Mapping = toml::from_str(
+            r#"
+            [callsigns]
+            CCA1501 = "RPL1501"
+
+            [cids]
+            1234567 = "1000001"
+
+            [passwords]
+            1000001 = "Replay-Pass-1"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            mapping.apply("#APCCA1501:SERVER:1234567:***:1:100:1:Jane Doe"),
+            "#APRPL1501:SERVER:1000001:Replay-Pass-1:1:100:1:Jane Doe"
+        );
+        assert_eq!(
+            mapping.apply("#TMCCA1501:CSN6311:Hello CCA1501"),
+            "#TMRPL1501:CSN6311:Hello CCA1501"
+        );
+        assert_eq!(
+            mapping.apply("@N:CCA1501:2000:1:31.1:121.3:3000:250:0:0"),
+            "@N:RPL1501:2000:1:31.1:121.3:3000:250:0:0"
+        );
+        // Unknown CIDs keep the captured password
+        assert_eq!(
+            mapping.apply("#AACCA_CTR:SERVER:Jane Doe:7654321:pw:5:100"),
+            "#AACCA_CTR:SERVER:Jane Doe:7654321:pw:5:100"
+        );
+        assert_eq!(mapping.apply("not a packet"), "not a packet");
+        assert!(toml::from_str::<Mapping>("[people]\nx = \"y\"").is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        let lines = |lines: &[&str]| lines.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let expected = lines(&[
+            "$DISERVER:CLIENT:VATSIM FSD V3.13:abc123",
+            "#TMserver:CCA1501:Welcome",
+            "$CRSERVER:CCA1501:ATC:Y:CCA1501",
+            "#TMserver:CCA1501:Goodbye",
+        ]);
+        let received = lines(&[
+            "$DISERVER:CLIENT:VATSIM FSD V3.13:f00d42",
+            "#DLSERVER:*:0:0",
+            "$CRSERVER:CCA1501:ATC:N:CCA1501",
+            "#TMserver:CCA1501:Welcome",
+        ]);
+        let ignore = lines(&["DI"]);
+
+        assert_eq!(
+            compare(&expected, &received, &ignore),
+            vec![
+                Mismatch::Differs {
+                    expected: expected[2].clone(),
+                    received: received[2].clone(),
+                },
+                Mismatch::Missing(expected[3].clone()),
+            ]
+        );
+        assert_eq!(compare(&expected, &received, &[]).len(), 3);
+
+        // A later packet with the same data wins over an earlier one without
+        let notices = lines(&["#TMserver:CCA1501:Rules", "#TMserver:CCA1501:Welcome"]);
+        assert!(compare(&expected[1..2], &notices, &ignore).is_empty());
+        assert!(compare(&expected[..2], &received, &ignore).is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+//! Callsigns, shared rather than copied
+//!
+//! A client's callsign is kept on the client, as the key of the callsign
+//! map and in the registry's index. [`Callsign`] holds it once behind an
+//! `Arc`, so each of those is a reference count instead of a new `String`.
+//! It hashes and compares by its uppercase form, kept next to it, so
+//! `dlh123` and `DLH123` are one callsign, and maps keyed by it are looked
+//! up with a plain `&str` through [`Borrow`]. Callsigns on the wire are
+//! uppercase already; [`Callsign::key`] only allocates for those that
+//! aren't.
+
+use serde::{Serialize, Serializer};
+use std::borrow::{Borrow, Cow};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// A callsign as the client sent it, compared without regard to case
+#[derive(Clone)]
+pub struct Callsign {
+    name: Arc<str>,
+    /// The uppercase form, the same allocation as `name` if that is
+    /// uppercase already
+    key: Arc<str>,
+}
+
+impl Callsign {
+    pub fn new(name: &str) -> Self {
+        let name: Arc<str> = Arc::from(name);
+        let key = match Self::key(&name) {
+            Cow::Borrowed(_) => name.clone(),
+            Cow::Owned(key) => Arc::from(key),
+        };
+        Self { name, key }
+    }
+
+    /// The form `name` is looked up by in maps keyed by callsign
+    pub fn key(name: &str) -> Cow<'_, str> {
+        if name.bytes().any(|b| b.is_ascii_lowercase()) {
+            Cow::Owned(name.to_ascii_uppercase())
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
+
+    /// The callsign as the client sent it
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Deref for Callsign {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Borrow<str> for Callsign {
+    fn borrow(&self) -> &str {
+        &self.key
+    }
+}
+
+impl PartialEq for Callsign {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Callsign {}
+
+impl PartialEq<str> for Callsign {
+    fn eq(&self, other: &str) -> bool {
+        self.key.eq_ignore_ascii_case(other)
+    }
+}
+
+impl PartialEq<&str> for Callsign {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+impl Hash for Callsign {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // As the key's str, so lookups through Borrow<str> find it
+        self.key.hash(state);
+    }
+}
+
+impl PartialOrd for Callsign {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Callsign {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+impl fmt::Debug for Callsign {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.name, f)
+    }
+}
+
+impl fmt::Display for Callsign {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl From<&str> for Callsign {
+    fn from(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
+impl From<String> for Callsign {
+    fn from(name: String) -> Self {
+        Self::new(&name)
+    }
+}
+
+impl From<&String> for Callsign {
+    fn from(name: &String) -> Self {
+        Self::new(name)
+    }
+}
+
+impl Serialize for Callsign {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_uppercase_callsign_shares_its_key() {
+        let callsign = Callsign::new("DLH123");
+        assert!(Arc::ptr_eq(&callsign.name, &callsign.key));
+        let clone = callsign.clone();
+        assert!(Arc::ptr_eq(&callsign.name, &clone.name));
+
+        let lower = Callsign::new("dlh123");
+        assert_eq!(lower.as_str(), "dlh123");
+        assert_eq!(lower.to_string(), "dlh123");
+        assert_eq!(lower, callsign);
+        assert!(lower == "Dlh123");
+    }
+
+    #[test]
+    fn test_map_lookup_by_str() {
+        let mut map = HashMap::new();
+        map.insert(Callsign::new("cca1501"), 1);
+        assert_eq!(map.get("CCA1501"), Some(&1));
+        assert_eq!(map.get(Callsign::key("cca1501").as_ref()), Some(&1));
+        assert!(matches!(Callsign::key("CCA1501"), Cow::Borrowed(_)));
+        assert_eq!(map.remove("CCA1501"), Some(1));
+    }
+}
@@ -0,0 +1,159 @@
+//! Wire captures: the raw FSD lines of one connection, as read by
+//! openfsd-replay
+//!
+//! Each line of a capture is a timestamp, the direction and the packet
+//! exactly as it went over the wire:
+//!
+//! ```text
+//! 2025-06-01T12:00:00.000Z in #APCCA1501:SERVER:1234567:***:1:100:1:Jane Doe
+//! 2025-06-01T12:00:00.250Z out #TMserver:CCA1501:Welcome
+//! ```
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::fmt;
+use std::io::BufRead;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("line {line}: {message}")]
+    Invalid { line: usize, message: String },
+    #[error("failed to read capture: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Which way a packet went, seen from the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the client
+    In,
+    /// Sent by the server
+    Out,
+}
+
+/// One captured packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureLine {
+    pub at: DateTime<Utc>,
+    pub direction: Direction,
+    /// The packet without its line ending
+    pub packet: String,
+}
+
+impl fmt::Display for CaptureLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::In => "in",
+            Direction::Out => "out",
+        };
+        write!(
+            f,
+            "{} {} {}",
+            self.at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            direction,
+            self.packet
+        )
+    }
+}
+
+impl CaptureLine {
+    /// Parse one line of a capture
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let mut fields = line.splitn(3, ' ');
+        let (Some(at), Some(direction), Some(packet)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err("expected a timestamp, a direction and a packet".to_string());
+        };
+
+        let at = DateTime::parse_from_rfc3339(at)
+            .map_err(|e| format!("invalid timestamp {:?}: {}", at, e))?
+            .with_timezone(&Utc);
+        let direction = match direction {
+            "in" => Direction::In,
+            "out" => Direction::Out,
+            other => return Err(format!("direction must be in or out, got {:?}", other)),
+        };
+        if packet.is_empty() {
+            return Err("empty packet".to_string());
+        }
+
+        Ok(Self {
+            at,
+            direction,
+            packet: packet.to_string(),
+        })
+    }
+}
+
+/// Read a whole capture, skipping blank lines
+///
+/// Lines must be in time order, since replays rely on it.
+pub fn read_capture(reader: impl BufRead) -> Result<Vec<CaptureLine>, CaptureError> {
+    let mut lines: Vec<CaptureLine> = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |message| CaptureError::Invalid {
+            line: index + 1,
+            message,
+        };
+
+        let parsed = CaptureLine::parse(&line).map_err(invalid)?;
+        if lines.last().is_some_and(|last| parsed.at < last.at) {
+            return Err(invalid(
+                "timestamp is earlier than the line before".to_string(),
+            ));
+        }
+        lines.push(parsed);
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let line = "2025-06-01T12:00:00.250Z out #TMserver:CCA1501:Hello: with spaces";
+        let parsed = CaptureLine::parse(line).unwrap();
+        assert_eq!(parsed.direction, Direction::Out);
+        assert_eq!(parsed.packet, "#TMserver:CCA1501:Hello: with spaces");
+        assert_eq!(parsed.at.timestamp_millis() % 1000, 250);
+        assert_eq!(parsed.to_string(), line);
+    }
+
+    #[test]
+    fn test_read_capture() {
+        let capture = "\
+2025-06-01T12:00:00Z in $IDCCA1501:SERVER:69d7:vPilot:3:2:1234567:1\r\n\
+\r\n\
+2025-06-01T12:00:01+00:00 in #APCCA1501:SERVER:1234567:***:1:100:1:Jane Doe\n";
+        let lines = read_capture(capture.as_bytes()).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[1].packet,
+            "#APCCA1501:SERVER:1234567:***:1:100:1:Jane Doe"
+        );
+        assert_eq!((lines[1].at - lines[0].at).num_seconds(), 1);
+
+        let err = |capture: &str| read_capture(capture.as_bytes()).unwrap_err().to_string();
+        assert_eq!(
+            err("2025-06-01T12:00:00Z sideways #TM"),
+            "line 1: direction must be in or out, got \"sideways\""
+        );
+        assert!(err("\nyesterday in #TM").starts_with("line 2: invalid timestamp"));
+        assert_eq!(
+            err("2025-06-01T12:00:00Z in"),
+            "line 1: expected a timestamp, a direction and a packet"
+        );
+        assert_eq!(
+            err("2025-06-01T12:00:01Z in #TM\n2025-06-01T12:00:00Z in #TM"),
+            "line 2: timestamp is earlier than the line before"
+        );
+    }
+}
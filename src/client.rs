@@ -1,5 +1,18 @@
-use crate::packet::Packet;
+use crate::airports::Airports;
+use crate::callsign::Callsign;
+use crate::error::ServerError;
+use crate::flight_plan::FlightPlan;
+use crate::flight_progress::{self, Eta, FlightProgress};
+use crate::packet::{Packet, PositionUpdate};
+use crate::plane_info::PlaneInfo;
+use crate::server::session::SessionCounters;
+use crate::tracks::Decimator;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashSet};
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
@@ -18,32 +31,190 @@ pub enum ClientState {
 }
 
 /// Client type
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ClientType {
     Pilot,
     Atc,
     Observer,
 }
 
+/// How a client receives other clients' positions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Delivery {
+    /// Each update in range, as it arrives
+    #[default]
+    RealTime,
+    /// Everyone's latest position at once, every snapshot interval
+    Snapshot,
+}
+
+/// ATC facility type, as sent in the facility field of ATC updates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Facility {
+    Observer,
+    Fss,
+    Delivery,
+    Ground,
+    Tower,
+    Approach,
+    Center,
+}
+
+impl Facility {
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "0" => Some(Facility::Observer),
+            "1" => Some(Facility::Fss),
+            "2" => Some(Facility::Delivery),
+            "3" => Some(Facility::Ground),
+            "4" => Some(Facility::Tower),
+            "5" => Some(Facility::Approach),
+            "6" => Some(Facility::Center),
+            _ => None,
+        }
+    }
+
+    /// Lowest controller rating that may staff the facility: S1 for
+    /// delivery and ground, S2 for tower, S3 for approach, C1 above that
+    pub fn minimum_rating(self) -> i32 {
+        match self {
+            Facility::Observer => 1,
+            Facility::Delivery | Facility::Ground => 2,
+            Facility::Tower => 3,
+            Facility::Approach => 4,
+            Facility::Center | Facility::Fss => 5,
+        }
+    }
+}
+
+/// A rating a supervisor raised for a mentoring session, see `.elevate`
+#[derive(Debug, Clone)]
+pub struct Elevation {
+    /// The rating from the account, put back when the elevation ends
+    pub stored_rating: Option<i32>,
+    pub until: Instant,
+    /// Connection of the mentor who granted it, which ends it by leaving
+    pub mentor_addr: SocketAddr,
+    /// CID of the mentor
+    pub mentor: String,
+}
+
+/// A frequency as FSD sends it, without the leading 1 and the decimal
+/// point, in MHz: 24550 is "124.550"
+pub fn format_frequency(frequency: u32) -> String {
+    format!("{}.{:03}", 100 + frequency / 1000, frequency % 1000)
+}
+
 /// Represents a connected client
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Client {
-    pub callsign: Option<String>,
+    /// Random identifier of this connection, used to group recorded tracks
+    pub session_id: String,
+    pub callsign: Option<Callsign>,
     pub addr: SocketAddr,
     pub state: ClientState,
     pub client_type: Option<ClientType>,
     pub real_name: Option<String>,
     pub network_id: Option<String>,
     pub rating: Option<i32>,
+    /// Protocol revision the client logged in with
+    pub protocol_revision: Option<u32>,
+    /// Ephemeral guest login (excluded from statistics, never a supervisor)
+    pub is_guest: bool,
     pub client_string: Option<String>,
+    pub client_id: Option<String>,
+    /// Client software is not on the whitelist (only possible without enforcement)
+    pub unverified_client: bool,
+    /// Outstanding $ZC challenge awaiting a $ZR response
+    pub auth_challenge: Option<String>,
     pub latitude: Option<f64>,
     pub longitude: Option<f64>,
     pub altitude: Option<i32>,
+    /// Groundspeed in knots from the latest position update
+    pub groundspeed: Option<u32>,
+    /// Heading in degrees from the latest position update
+    pub heading: Option<u32>,
+    /// Whether the aircraft is on the ground, as its latest position update
+    /// says or as it looks from its speed and altitude
+    pub on_ground: Option<bool>,
+    /// Altitude in feet where the aircraft was last reported on the ground
+    pub field_elevation: Option<i32>,
+    /// Off-block time and latest update, for arrival estimates
+    pub progress: FlightProgress,
+    /// Facility from the latest ATC update
+    pub facility: Option<Facility>,
+    /// Frequency from the latest ATC update as sent, e.g. 24550 for 124.550
+    pub frequency: Option<u32>,
+    /// Visibility range declared in the latest ATC update
+    pub declared_range_nm: Option<u32>,
+    /// Extra points a controller sees traffic around, set with `'` packets
+    /// and kept by index as (latitude, longitude)
+    pub visibility_centers: BTreeMap<usize, (f64, f64)>,
+    /// Capabilities listed in the client's CAPS response, e.g. "ACCONFIG"
+    pub capabilities: HashSet<String>,
+    /// Latest position update, for position snapshots
+    pub last_position: Option<Arc<PositionUpdate>>,
+    /// When the first and the latest position update arrived, for the
+    /// average interval between them
+    pub first_position_at: Option<Instant>,
+    pub last_position_at: Option<Instant>,
+    /// Transponder code from the latest position update
+    pub squawk: Option<String>,
+    /// Beacon code assigned by a controller
+    pub assigned_squawk: Option<String>,
+    /// Callsign of the controller tracking this aircraft
+    pub tracking_controller: Option<String>,
+    /// When the client logged in
+    pub logged_in_at: Option<Instant>,
+    /// Wall-clock time of the login, for status feeds
+    pub logon_time: Option<DateTime<Utc>>,
+    /// Flight plan currently on file for this connection
+    pub flight_plan: Option<FlightPlan>,
+    /// Pilot has been reminded to file a flight plan
+    pub flight_plan_reminded: bool,
+    /// The flight plan was activated from a prefile before the pilot sent a
+    /// position, so controllers in range have yet to receive it
+    pub flight_plan_unsent: bool,
+    /// Aircraft the pilot's client last described in a `#SB` reply
+    pub plane_info: Option<PlaneInfo>,
+    /// Text ATIS a controller last uploaded
+    pub atis: Vec<String>,
+    /// When the ATIS was last uploaded
+    pub atis_updated_at: Option<DateTime<Utc>>,
+    /// ATIS lines received since the last end marker
+    pub atis_upload: Vec<String>,
+    /// The last ATIS upload asked not to have it rebroadcast
+    pub atis_no_rebroadcast: bool,
+    /// When the ATIS was last rebroadcast, `None` if not since it changed
+    pub atis_broadcast_at: Option<Instant>,
+    /// Until when a supervisor muted the client: its text messages to
+    /// frequencies and broadcasts are dropped
+    pub muted_until: Option<Instant>,
+    /// Raised rating in force, with `rating` holding the raised one
+    pub elevation: Option<Elevation>,
+    /// Stations whose new METARs are pushed to this client, with the METAR
+    /// it was sent last
+    pub metar_subscriptions: BTreeMap<String, Option<String>>,
+    /// How positions reach this client, chosen at login
+    pub delivery: Delivery,
+    /// Sampling state for flight track recording
+    pub track_decimator: Decimator,
+    /// When the connection was accepted
+    pub connected_at: Instant,
+    /// Traffic of this connection, for the summary when it closes
+    pub counters: Arc<SessionCounters>,
+    /// Token of the keepalive `$PI` awaiting its `$PO`, and when it was sent
+    pub ping_sent: Option<(String, Instant)>,
+    /// Round trip of the latest answered keepalive
+    pub ping: Option<Duration>,
+    /// Span for everything logged about this connection
+    pub span: tracing::Span,
 }
 
 impl Client {
     pub fn new(addr: SocketAddr) -> Self {
         Self {
+            session_id: generate_session_id(),
             callsign: None,
             addr,
             state: ClientState::Connected,
@@ -51,10 +222,68 @@ impl Client {
             real_name: None,
             network_id: None,
             rating: None,
+            protocol_revision: None,
+            is_guest: false,
             client_string: None,
+            client_id: None,
+            unverified_client: false,
+            auth_challenge: None,
             latitude: None,
             longitude: None,
             altitude: None,
+            groundspeed: None,
+            heading: None,
+            on_ground: None,
+            field_elevation: None,
+            progress: FlightProgress::default(),
+            facility: None,
+            frequency: None,
+            declared_range_nm: None,
+            visibility_centers: BTreeMap::new(),
+            capabilities: HashSet::new(),
+            last_position: None,
+            first_position_at: None,
+            last_position_at: None,
+            squawk: None,
+            assigned_squawk: None,
+            tracking_controller: None,
+            logged_in_at: None,
+            logon_time: None,
+            flight_plan: None,
+            plane_info: None,
+            flight_plan_reminded: false,
+            flight_plan_unsent: false,
+            atis: Vec::new(),
+            atis_updated_at: None,
+            atis_upload: Vec::new(),
+            atis_no_rebroadcast: false,
+            atis_broadcast_at: None,
+            muted_until: None,
+            elevation: None,
+            metar_subscriptions: BTreeMap::new(),
+            delivery: Delivery::RealTime,
+            track_decimator: Decimator::default(),
+            connected_at: Instant::now(),
+            counters: Arc::default(),
+            ping_sent: None,
+            ping: None,
+            span: tracing::info_span!(
+                "conn",
+                %addr,
+                callsign = tracing::field::Empty,
+                cid = tracing::field::Empty
+            ),
+        }
+    }
+
+    /// Put the callsign and CID on the connection's span the first time they
+    /// are known; recording them again would repeat them in text logs
+    pub fn record_identity(&self, callsign: &str, network_id: Option<&str>) {
+        if self.callsign.is_none() {
+            self.span.record("callsign", callsign);
+        }
+        if let (None, Some(network_id)) = (&self.network_id, network_id) {
+            self.span.record("cid", network_id);
         }
     }
 
@@ -65,6 +294,181 @@ impl Client {
     pub fn callsign(&self) -> Option<&str> {
         self.callsign.as_deref()
     }
+
+    /// Whether the client only watches: logged in as an observer, with an
+    /// `_OBS` callsign or staffing the observer facility
+    pub fn is_observer(&self) -> bool {
+        match self.client_type {
+            Some(ClientType::Observer) => true,
+            Some(ClientType::Atc) => {
+                self.facility == Some(Facility::Observer)
+                    || self
+                        .callsign()
+                        .is_some_and(|callsign| callsign.to_ascii_uppercase().ends_with("_OBS"))
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the client only serves an ATIS: a controller with an `_ATIS`
+    /// callsign
+    pub fn is_atis(&self) -> bool {
+        self.client_type == Some(ClientType::Atc)
+            && self
+                .callsign()
+                .is_some_and(|callsign| callsign.to_ascii_uppercase().ends_with("_ATIS"))
+    }
+
+    /// Note a position update arriving at `now`
+    pub fn position_received(&mut self, now: Instant) {
+        self.first_position_at.get_or_insert(now);
+        self.last_position_at = Some(now);
+    }
+
+    /// Average time between position updates, once there were two
+    pub fn position_interval(&self) -> Option<Duration> {
+        let elapsed = self.last_position_at? - self.first_position_at?;
+        let updates = self.counters.position_updates.load(Ordering::Relaxed);
+        let intervals = u32::try_from(updates.checked_sub(1)?).ok()?;
+        (intervals > 0).then(|| elapsed / intervals)
+    }
+
+    /// Whether a supervisor's mute is still in force at `now`
+    pub fn is_muted(&self, now: Instant) -> bool {
+        self.muted_until.is_some_and(|until| now < until)
+    }
+
+    /// The rating the client may use at `now`: the stored one once an
+    /// elevation has run out, even before it is reverted
+    pub fn effective_rating(&self, now: Instant) -> Option<i32> {
+        match &self.elevation {
+            Some(elevation) if now >= elevation.until => elevation.stored_rating,
+            _ => self.rating,
+        }
+    }
+
+    /// Whether the client listed `name` in its CAPS response
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.contains(name)
+    }
+
+    /// Approximate memory the server keeps for this client, in bytes: the
+    /// struct itself and the text it holds
+    pub fn memory_estimate(&self) -> usize {
+        let strings: usize = [
+            &self.real_name,
+            &self.network_id,
+            &self.client_string,
+            &self.client_id,
+            &self.auth_challenge,
+            &self.squawk,
+            &self.assigned_squawk,
+            &self.tracking_controller,
+        ]
+        .into_iter()
+        .flatten()
+        .map(String::len)
+        .sum();
+        let callsign = self.callsign.as_deref().map_or(0, str::len);
+        let atis: usize = self
+            .atis
+            .iter()
+            .chain(&self.atis_upload)
+            .map(String::len)
+            .sum();
+        let flight_plan = self.flight_plan.as_ref().map_or(0, FlightPlan::text_len);
+        let plane_info = self.plane_info.as_ref().map_or(0, PlaneInfo::text_len);
+        let position = self
+            .last_position
+            .as_ref()
+            .map_or(0, |update| update.line().len());
+        let metars: usize = self
+            .metar_subscriptions
+            .iter()
+            .map(|(station, metar)| station.len() + metar.as_ref().map_or(0, String::len))
+            .sum();
+        std::mem::size_of::<Self>()
+            + self.session_id.len()
+            + callsign
+            + strings
+            + atis
+            + flight_plan
+            + plane_info
+            + position
+            + metars
+    }
+
+    /// ICAO type designator of the aircraft: the flight plan's if one is on
+    /// file, otherwise the one from the pilot's `#SB` reply
+    pub fn aircraft_type(&self) -> Option<&str> {
+        self.flight_plan
+            .as_ref()
+            .map(FlightPlan::aircraft_type)
+            .filter(|aircraft| !aircraft.is_empty())
+            .or_else(|| self.plane_info.as_ref()?.equipment.as_deref())
+    }
+
+    /// When the filed flight should arrive, from progress towards its
+    /// destination if that is among `airports`
+    pub fn eta(&self, airports: &Airports, now: DateTime<Utc>) -> Option<Eta> {
+        let plan = self.flight_plan.as_ref()?;
+        let position = flight_progress::Position {
+            point: self.latitude.zip(self.longitude),
+            groundspeed: self.groundspeed,
+        };
+        let destination = airports.position(&plan.destination);
+        flight_progress::eta(plan, &self.progress, position, destination, now)
+    }
+
+    /// Great-circle distance to another client in nautical miles, if both positions are known
+    pub fn distance_nm(&self, other: &Client) -> Option<f64> {
+        Some(distance_nm(
+            (self.latitude?, self.longitude?),
+            (other.latitude?, other.longitude?),
+        ))
+    }
+
+    /// Points this client sees traffic around: its position if known, then
+    /// any visibility centers it set
+    pub fn visibility_points(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        let position = self.latitude.zip(self.longitude);
+        position
+            .into_iter()
+            .chain(self.visibility_centers.values().copied())
+    }
+}
+
+#[cfg(test)]
+impl Client {
+    /// Logged-in client at `addr` with `callsign` and `client_type`; tests
+    /// fill in the rest
+    pub fn active_for_tests(
+        addr: impl Into<SocketAddr>,
+        callsign: &str,
+        client_type: ClientType,
+    ) -> Self {
+        let mut client = Self::new(addr.into());
+        client.state = ClientState::Active;
+        client.callsign = Some(callsign.into());
+        client.client_type = Some(client_type);
+        client
+    }
+}
+
+/// Great-circle distance between two (latitude, longitude) points in nautical miles
+pub fn distance_nm((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    const EARTH_RADIUS_NM: f64 = 3440.065;
+
+    let (lat1, lon1) = (lat1.to_radians(), lon1.to_radians());
+    let (lat2, lon2) = (lat2.to_radians(), lon2.to_radians());
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_NM * a.sqrt().asin()
+}
+
+fn generate_session_id() -> String {
+    use rand::Rng;
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
 }
 
 /// Client connection handler
@@ -79,35 +483,36 @@ impl ClientConnection {
         Self { stream, addr, tx }
     }
 
-    /// Handle the client connection
-    pub async fn handle(self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Handle the client connection until it closes; fails with
+    /// [`ServerError::Shutdown`] if the server stops taking packets
+    pub async fn handle(self) -> Result<(), ServerError> {
         let (reader, writer) = self.stream.into_split();
         let mut reader = BufReader::new(reader);
         let mut line = String::new();
 
-        log::info!("Client connected from {}", self.addr);
+        tracing::info!("Client connected from {}", self.addr);
 
         loop {
             line.clear();
             let bytes_read = reader.read_line(&mut line).await?;
 
             if bytes_read == 0 {
-                log::info!("Client {} disconnected", self.addr);
+                tracing::info!("Client {} disconnected", self.addr);
                 break;
             }
 
             match Packet::parse(&line) {
                 Ok(packet) => {
-                    log::debug!("Received packet from {}: {}", self.addr, packet);
+                    tracing::debug!("Received packet from {}: {}", self.addr, packet);
 
                     // Send packet to server for processing
                     if self.tx.send(packet).await.is_err() {
-                        log::error!("Failed to send packet to server");
-                        break;
+                        tracing::error!("Failed to send packet to server");
+                        return Err(ServerError::Shutdown);
                     }
                 }
                 Err(e) => {
-                    log::warn!("Failed to parse packet from {}: {}", self.addr, e);
+                    tracing::warn!("Failed to parse packet from {}: {}", self.addr, e);
                 }
             }
         }
@@ -116,10 +521,22 @@ impl ClientConnection {
     }
 
     /// Send a packet to the client
-    pub async fn send_packet(&mut self, packet: &Packet) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn send_packet(&mut self, packet: &Packet) -> Result<(), ServerError> {
         let formatted = packet.format();
         self.stream.write_all(formatted.as_bytes()).await?;
         self.stream.flush().await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_frequency() {
+        assert_eq!(format_frequency(25100), "125.100");
+        assert_eq!(format_frequency(18050), "118.050");
+        assert_eq!(format_frequency(99998), "199.998");
+    }
+}
@@ -1,40 +1,1732 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::level_filters::LevelFilter;
 
-#[derive(Debug, Deserialize, Clone)]
+/// Read when no configuration file is given explicitly
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Environment variables starting with this and containing `__` set
+/// configuration keys, e.g. `OPENFSD_SERVER__PORT` sets `server.port`
+pub const ENV_PREFIX: &str = "OPENFSD_";
+
+/// Separates the section from the key in environment variable names
+const ENV_SEPARATOR: &str = "__";
+
+/// Suffix for variables naming a file that holds the value, e.g. a secret
+const ENV_FILE_SUFFIX: &str = "_FILE";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Configuration file {} does not exist", .0.display())]
+    NotFound(PathBuf),
+    #[error("Failed to read {}: {}", .path.display(), .source)]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to parse {}: {}", .path.display(), .source)]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("{} already exists, use --force to overwrite it", .0.display())]
+    Exists(PathBuf),
+    #[error("Failed to write {}: {}", .path.display(), .source)]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid value in {var}: {message}")]
+    Env { var: String, message: String },
+    #[error("Failed to read {} from {var}: {source}", .path.display())]
+    EnvFile {
+        var: String,
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+/// Settings given on the command line, which beat every other source
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub database_url: Option<String>,
+    pub log_level: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
+    /// Where the server listens and how it introduces itself
     pub server: ServerConfig,
+    /// Log levels and the optional log file
+    #[serde(default)]
     pub logging: LoggingConfig,
+    /// Database holding users, the whitelist and recorded data
+    #[serde(default)]
     pub database: DatabaseConfig,
+    /// FSD dialect spoken with clients
+    #[serde(default)]
+    pub protocol: ProtocolConfig,
+    /// Optional behaviour that can be switched on or off
+    #[serde(default)]
+    pub features: FeaturesConfig,
+    /// Client software allowed to connect
+    #[serde(default)]
+    pub whitelist: WhitelistConfig,
+    /// How clients prove who they are
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// Recording of pilot position tracks
+    #[serde(default)]
+    pub tracks: TracksConfig,
+    /// Keepalive packets sent to every client
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    /// When quiet clients are disconnected, by type
+    #[serde(default)]
+    pub idle: IdleConfig,
+    /// Where METARs and TAFs come from
+    #[serde(default)]
+    pub weather: WeatherConfig,
+    /// Account and address restrictions
+    #[serde(default)]
+    pub security: SecurityConfig,
+    /// Protection against misbehaving clients
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Ranges, in nautical miles, within which clients see each other
+    #[serde(default)]
+    pub visibility: VisibilityConfig,
+    /// Encrypted FSD connections
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// Voice server announced to clients
+    #[serde(default)]
+    pub voice: VoiceConfig,
+    /// Local socket the admin tool uses to reach the running server
+    #[serde(default)]
+    pub control: ControlConfig,
+    /// Prometheus endpoint
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    /// Status file in the whazzup format read by servinfo tools
+    #[serde(default)]
+    pub whazzup: WhazzupConfig,
+    /// HTTP listener for the JSON datafeed and whazzup.txt
+    #[serde(default)]
+    pub status: StatusConfig,
+    /// Authenticated REST API on the status listener
+    #[serde(default)]
+    pub api: ApiConfig,
+    /// Listener bridging WebSocket clients to the FSD protocol
+    #[serde(default)]
+    pub websocket: WebSocketConfig,
+    /// HTTP callbacks for server events
+    #[serde(default)]
+    pub webhooks: WebhooksConfig,
+    /// Built-in packet middlewares: logging and text filtering
+    #[serde(default)]
+    pub middleware: MiddlewareConfig,
+    /// Raw copies of client connections for debugging and replays
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    /// Warnings about slow handlers, lock waits and client writes
+    #[serde(default)]
+    pub diagnostics: DiagnosticsConfig,
+    /// Artificial latency and packet loss for testing clients
+    #[serde(default)]
+    pub chaos: ChaosConfig,
+    /// Queue sizes and the threads running the server
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    /// JSON snapshots of the server state taken on SIGUSR1 or by the admin tool
+    #[serde(default)]
+    pub dump: DumpConfig,
+    /// OpenTelemetry export of spans and metrics over OTLP/gRPC
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    /// Keys in the file that are not part of the schema, e.g. `server.motto`
+    #[serde(skip)]
+    pub unknown_keys: Vec<String>,
+    /// File the configuration was read from, if any
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ServerConfig {
+    /// Address to bind to
     pub address: String,
+    /// Port to listen on
     pub port: u16,
+    /// Server name shown to clients
     pub name: String,
+    /// Version reported to clients
     pub version: String,
+    /// Connections accepted at the same time
     pub max_clients: usize,
+    /// Message of the day, unless one is set in the database
+    #[serde(default)]
+    pub motd: Vec<String>,
+    /// Extra lines for controllers, unless set in the database
+    #[serde(default)]
+    pub atc_motd: Vec<String>,
+    /// Host name clients connect to, listed in the status feeds (default:
+    /// `address`)
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Where the server is, listed in the status feeds
+    #[serde(default)]
+    pub location: String,
+    /// Callsign the server sends its own packets from (default: `SERVER`)
+    #[serde(default)]
+    pub server_callsign: Option<String>,
+    /// Text of the `$DI` identification (default: the dialect's, e.g.
+    /// `VATSIM FSD V3.13`)
+    #[serde(default)]
+    pub ident_banner: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct LoggingConfig {
+    /// Level or filter for every output, e.g. "info" or "info,sea_orm=warn"
     pub level: String,
+    /// Most verbose level written to the console (default: all that `level` lets through)
+    pub console_level: Option<String>,
+    /// Also write the log to this file
+    pub file: Option<PathBuf>,
+    /// Most verbose level written to the file (default: all that `level` lets through)
+    pub file_level: Option<String>,
+    /// When to start a new log file
+    pub rotation: LogRotation,
+    /// Size at which the file is rotated with `rotation = "size"`
+    pub max_size_mb: u64,
+    /// Rotated files kept besides the current one
+    pub keep_files: usize,
+    /// Line format of every output
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            console_level: None,
+            file: None,
+            file_level: None,
+            rotation: LogRotation::Daily,
+            max_size_mb: 100,
+            keep_files: 7,
+            format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Daily,
+    Size,
+    /// Keep appending to the same file
+    Never,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, with span and event fields as keys
+    Json,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct DatabaseConfig {
+    /// Connection URL; the scheme (sqlite://, postgres://, mysql://) picks the backend
     pub url: String,
+    /// Most connections kept in the pool
+    pub max_connections: u32,
+    /// Connections kept open even when idle
+    pub min_connections: u32,
+    /// Seconds to wait when opening a new connection
+    pub connect_timeout: u64,
+    /// Seconds to wait for a free connection from the pool
+    pub acquire_timeout: u64,
+    /// Seconds before an idle connection is closed
+    pub idle_timeout: u64,
+    /// Seconds before any connection is recycled
+    pub max_lifetime: u64,
+    /// Log every SQL statement at debug level
+    pub sqlx_logging: bool,
+    /// Seconds to keep retrying the initial connection (0 = single attempt)
+    pub startup_retry_timeout: u64,
+    /// Initial delay between startup connection attempts, doubled each retry
+    pub startup_retry_backoff_ms: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: "sqlite://openfsd.db".to_string(),
+            max_connections: 20,
+            min_connections: 1,
+            connect_timeout: 8,
+            acquire_timeout: 8,
+            idle_timeout: 600,
+            max_lifetime: 1800,
+            sqlx_logging: false,
+            startup_retry_timeout: 60,
+            startup_retry_backoff_ms: 500,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct WhitelistConfig {
+    /// Reject client IDs that are not on the whitelist
+    pub enforce: bool,
+    /// Without enforcement, log a warning for every unknown client ID
+    pub log_unknown: bool,
+    /// Accept clients whose version cannot be parsed from the client string
+    pub allow_unparseable_version: bool,
+}
+
+impl Default for WhitelistConfig {
+    fn default() -> Self {
+        Self {
+            enforce: true,
+            log_unknown: true,
+            allow_unparseable_version: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ProtocolConfig {
+    /// FSD flavour spoken with every client; a server can only speak one
+    pub dialect: Dialect,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    #[default]
+    Vatsim,
+    Ivao,
+    /// Plain FSD without network-specific extensions
+    Generic,
+}
+
+impl Dialect {
+    /// Text of the $DI server identification
+    pub fn banner(&self) -> &'static str {
+        match self {
+            Dialect::Vatsim => "VATSIM FSD V3.13",
+            Dialect::Ivao => "IVAO FSD V3.13",
+            Dialect::Generic => "FSD V3.13",
+        }
+    }
+
+    /// Whether clients are sent $ZC auth challenges
+    pub fn issues_challenges(&self) -> bool {
+        *self == Dialect::Vatsim
+    }
+
+    /// Whether the IVAO-only `!`, `&` and `-` packets are accepted
+    pub fn accepts_ivao_packets(&self) -> bool {
+        *self == Dialect::Ivao
+    }
+
+    /// Text of a #TM whose message was split into `fields` at its colons
+    ///
+    /// IVAO clients escape colons in messages as `::`, elsewhere every
+    /// colon is kept as sent.
+    pub fn message_text(&self, fields: &[String]) -> String {
+        let text = fields.join(":");
+        match self {
+            Dialect::Ivao => text.replace("::", ":"),
+            Dialect::Vatsim | Dialect::Generic => text,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct FeaturesConfig {
+    /// Let observers (_OBS callsigns or rating 1) log in as ATC
+    pub allow_observers: bool,
+    /// Pilots must file a flight plan soon after logging in
+    pub require_flight_plan: bool,
+    /// Minutes a pilot has to file before `missing_flight_plan_action`
+    pub flight_plan_grace_minutes: u32,
+    /// What happens to pilots still without a flight plan
+    pub missing_flight_plan_action: MissingFlightPlanAction,
+    /// Answer unknown commands with an error instead of ignoring them
+    pub strict_mode: bool,
+    /// Observers following a pilot with `.follow` wait for the pilot to
+    /// `.accept` them
+    pub follow_requires_consent: bool,
+    /// Most observers following one pilot, 0 to turn `.follow` off
+    pub max_followers: usize,
+    /// Minutes between sending controllers' ATIS to their frequency again,
+    /// 0 to never rebroadcast it
+    pub atis_rebroadcast_minutes: u64,
+    /// Scheduled announcements due longer ago than this are skipped
+    pub announcement_grace_minutes: u64,
+}
+
+impl Default for FeaturesConfig {
+    fn default() -> Self {
+        Self {
+            allow_observers: true,
+            require_flight_plan: false,
+            flight_plan_grace_minutes: 10,
+            missing_flight_plan_action: MissingFlightPlanAction::Warn,
+            strict_mode: false,
+            follow_requires_consent: false,
+            max_followers: 3,
+            atis_rebroadcast_minutes: 15,
+            announcement_grace_minutes: 5,
+        }
+    }
+}
+
+impl FeaturesConfig {
+    /// How often the ATIS is rebroadcast, if at all
+    pub fn atis_rebroadcast(&self) -> Option<Duration> {
+        (self.atis_rebroadcast_minutes > 0)
+            .then(|| Duration::from_secs(self.atis_rebroadcast_minutes * 60))
+    }
+
+    /// How late a scheduled announcement may still be sent
+    pub fn announcement_grace(&self) -> chrono::Duration {
+        i64::try_from(self.announcement_grace_minutes)
+            .ok()
+            .and_then(chrono::Duration::try_minutes)
+            .unwrap_or(chrono::Duration::MAX)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingFlightPlanAction {
+    /// Remind the pilot once
+    Warn,
+    /// Disconnect the pilot
+    Kick,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Expect every client to answer $ZC auth challenges
+    pub require_challenge: bool,
+    /// How login tokens in the password field are recognised
+    pub token_login: TokenLoginMode,
+    /// Let unknown network IDs log in as ephemeral guests (development only)
+    pub allow_guest: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenLoginMode {
+    /// Only passwords starting with "TOKEN-" are treated as login tokens
+    #[default]
+    Prefix,
+    /// Try every password as a login token first, then as a password
+    TokenThenPassword,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TracksConfig {
+    /// Record position tracks for every pilot
+    pub enabled: bool,
+    /// Callsigns to record even when `enabled` is off
+    pub callsigns: Vec<String>,
+    /// Record every Nth position update (0 = only use the interval)
+    pub sample_every: u32,
+    /// Seconds after which the next update is always recorded
+    pub sample_interval_secs: u64,
+    /// Days to keep recorded tracks (0 = keep forever)
+    pub retention_days: u32,
+    /// Samples buffered for the database writer before new ones are dropped
+    pub queue_capacity: usize,
+}
+
+impl Default for TracksConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            callsigns: Vec::new(),
+            sample_every: 10,
+            sample_interval_secs: 15,
+            retention_days: 30,
+            queue_capacity: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    /// Send a keepalive packet to every client
+    pub enabled: bool,
+    /// Seconds between keepalives
+    pub interval_secs: u64,
+    /// Callsign the keepalive is sent from
+    pub source: String,
+    /// Packet used as the keepalive
+    pub style: HeartbeatStyle,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 30,
+            source: "SERVER".to_string(),
+            style: HeartbeatStyle::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HeartbeatStyle {
+    /// `#DL` delete packet, which clients ignore for unknown callsigns
+    #[default]
+    Dl,
+    /// Empty `#TM` text message
+    Tm,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct IdleConfig {
+    /// Seconds a pilot may go without a position update, 0 for no limit
+    pub pilot_position_timeout_secs: u64,
+    /// Seconds a controller may go without a position update, 0 for no limit
+    pub atc_position_timeout_secs: u64,
+    /// Seconds an observer may go without sending anything, 0 for no limit
+    pub observer_timeout_secs: u64,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        Self {
+            pilot_position_timeout_secs: 300,
+            atc_position_timeout_secs: 300,
+            observer_timeout_secs: 4 * 3600,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct WeatherConfig {
+    /// Where METARs and TAFs come from
+    pub provider: WeatherProvider,
+    /// URL for the custom provider; `{icao}` is replaced by the station and
+    /// `{product}` by "metar" or "taf"
+    pub url_template: Option<String>,
+    /// Reports for the static provider, one per line starting with the station
+    pub static_file: Option<PathBuf>,
+    /// Seconds a fetched METAR is reused
+    pub metar_cache_ttl_secs: u64,
+    /// Seconds a fetched TAF is reused
+    pub taf_cache_ttl_secs: u64,
+    /// Seconds to wait for the provider
+    pub request_timeout_secs: u64,
+    /// Header that carries `api_key`
+    pub api_key_header: String,
+    /// Sent with every request if set
+    pub api_key: Option<String>,
+    /// Seconds between fetches of the stations clients subscribed to, whose
+    /// METAR is pushed to them when it changes
+    pub metar_refresh_secs: u64,
+    /// Stations one client may subscribe to
+    pub max_metar_subscriptions: usize,
+    /// Providers tried in order when `provider` fails or has no report
+    pub fallbacks: Vec<WeatherFallbackConfig>,
+    /// Minutes after it was fetched that a cached report may still be
+    /// served, marked stale, when every provider fails; 0 never serves one
+    pub stale_max_minutes: u64,
+    /// Seconds a provider that failed is skipped
+    pub provider_cooldown_secs: u64,
+}
+
+/// A weather provider to fall back on
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct WeatherFallbackConfig {
+    /// Shown in metrics and logs; "fallback1", "fallback2", ... if unset
+    #[serde(default)]
+    pub name: Option<String>,
+    /// As `url_template` of the custom provider
+    pub url_template: String,
+    /// Seconds to wait for this provider; `request_timeout_secs` if unset
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Sent in `api_key_header` if set
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+impl Default for WeatherConfig {
+    fn default() -> Self {
+        Self {
+            provider: WeatherProvider::default(),
+            url_template: None,
+            static_file: None,
+            metar_cache_ttl_secs: 600,
+            taf_cache_ttl_secs: 1800,
+            request_timeout_secs: 10,
+            api_key_header: "X-API-Key".to_string(),
+            api_key: None,
+            metar_refresh_secs: 300,
+            max_metar_subscriptions: 10,
+            fallbacks: Vec::new(),
+            stale_max_minutes: 60,
+            provider_cooldown_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WeatherProvider {
+    /// aviationweather.gov
+    #[default]
+    Noaa,
+    /// Any service reachable through `url_template`
+    Custom,
+    /// Reports read from `static_file`, for networks without internet access
+    Static,
+}
+
+impl WeatherProvider {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WeatherProvider::Noaa => "noaa",
+            WeatherProvider::Custom => "custom",
+            WeatherProvider::Static => "static",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SecurityConfig {
+    /// Shortest password accepted for new accounts
+    pub min_password_length: usize,
+    /// Addresses that may not connect at all
+    pub blocked_ips: Vec<String>,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            min_password_length: 8,
+            blocked_ips: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Sustained packets per second accepted from one client
+    pub packets_per_second: u32,
+    /// Packets a client may send in a burst above the sustained rate
+    pub burst: u32,
+    /// Text messages one client may send in any minute
+    pub text_messages_per_minute: u32,
+    /// Failed logins for a network ID before it is locked out
+    pub login_failures_before_lockout: u32,
+    /// How long a locked-out network ID is refused
+    pub lockout_minutes: u32,
+    /// Simultaneous connections from one address
+    pub max_connections_per_ip: u32,
+    /// Simultaneous connections from one address that haven't logged in yet
+    pub max_unauthenticated_per_ip: u32,
+    /// Malformed packets one connection may send before it is disconnected
+    pub max_parse_errors: u32,
+    /// Malformed packets one connection may send in any second
+    pub parse_errors_per_second: u32,
+    /// Malformed packets answered with a syntax error before the rest are only logged
+    pub parse_error_replies: u32,
+    /// Longest line read from a client, in bytes; longer ones are discarded
+    /// as malformed
+    pub max_line_bytes: usize,
+    /// Longest text kept per field, such as an ATIS line or a flight plan's
+    /// remarks, in bytes
+    pub max_field_bytes: usize,
+    /// ATIS lines kept per controller
+    pub max_atis_lines: usize,
+    /// Approximate memory one client may make the server keep, in bytes
+    pub max_client_bytes: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            packets_per_second: 20,
+            burst: 40,
+            text_messages_per_minute: 30,
+            login_failures_before_lockout: 5,
+            lockout_minutes: 15,
+            max_connections_per_ip: 16,
+            max_unauthenticated_per_ip: 4,
+            max_parse_errors: 20,
+            parse_errors_per_second: 5,
+            parse_error_replies: 3,
+            max_line_bytes: 4096,
+            max_field_bytes: 1024,
+            max_atis_lines: 32,
+            max_client_bytes: 32 * 1024,
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// Names of limits set to zero, which would block every client
+    fn zero_limits(&self) -> Vec<&'static str> {
+        [
+            ("packets_per_second", self.packets_per_second),
+            ("burst", self.burst),
+            ("text_messages_per_minute", self.text_messages_per_minute),
+            (
+                "login_failures_before_lockout",
+                self.login_failures_before_lockout,
+            ),
+            ("lockout_minutes", self.lockout_minutes),
+            ("max_connections_per_ip", self.max_connections_per_ip),
+            (
+                "max_unauthenticated_per_ip",
+                self.max_unauthenticated_per_ip,
+            ),
+            ("max_parse_errors", self.max_parse_errors),
+            ("parse_errors_per_second", self.parse_errors_per_second),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name, value == 0))
+        .chain(
+            [
+                ("max_line_bytes", self.max_line_bytes),
+                ("max_field_bytes", self.max_field_bytes),
+                ("max_client_bytes", self.max_client_bytes),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name, value == 0)),
+        )
+        .filter(|(_, zero)| *zero)
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct VisibilityConfig {
+    /// Range of pilots, whose clients don't declare one
+    pub pilot_range_nm: u32,
+    /// Range of observers that don't declare one
+    pub obs_range_nm: u32,
+    /// Ranges of controllers that don't declare one, by facility
+    pub atc: AtcRangeConfig,
+    /// Hard cap on every range, declared or not
+    pub max_range_nm: u32,
+    /// Send observers every position at once each snapshot interval rather
+    /// than each update in range as it arrives
+    pub snapshot_observers: bool,
+    /// Milliseconds between position snapshots
+    pub snapshot_interval_ms: u64,
+    /// Most visibility centers a controller may set besides its position
+    pub max_centers: usize,
+}
+
+impl Default for VisibilityConfig {
+    fn default() -> Self {
+        Self {
+            pilot_range_nm: 50,
+            obs_range_nm: 300,
+            atc: AtcRangeConfig::default(),
+            max_range_nm: 1500,
+            snapshot_observers: false,
+            snapshot_interval_ms: 1000,
+            max_centers: 4,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AtcRangeConfig {
+    /// Clearance delivery
+    pub del: u32,
+    /// Ground
+    pub gnd: u32,
+    /// Tower
+    pub twr: u32,
+    /// Approach and departure
+    pub app: u32,
+    /// Center
+    pub ctr: u32,
+    /// Flight service station
+    pub fss: u32,
+}
+
+impl Default for AtcRangeConfig {
+    fn default() -> Self {
+        Self {
+            del: 20,
+            gnd: 20,
+            twr: 50,
+            app: 150,
+            ctr: 600,
+            fss: 1500,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// Serve FSD over TLS; needs both `cert_path` and `key_path`
+    pub enabled: bool,
+    /// PEM certificate chain
+    pub cert_path: Option<PathBuf>,
+    /// PEM private key
+    pub key_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct VoiceConfig {
+    /// Voice server announced to clients
+    pub server: String,
+}
+
+impl Default for VoiceConfig {
+    fn default() -> Self {
+        Self {
+            server: "voice.vatsim.net/uk".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ControlConfig {
+    /// Accept admin tool commands such as listing and kicking clients
+    pub enabled: bool,
+    /// Address to listen on; anyone who can reach it may try the secret
+    pub address: String,
+    /// Shared secret sent with every command; required when enabled
+    pub secret: Option<String>,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1:6810".to_string(),
+            secret: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Serve Prometheus metrics over HTTP; needs the `prometheus` feature
+    pub enabled: bool,
+    /// Address to listen on; the endpoint has no authentication
+    pub address: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1:9180".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct WhazzupConfig {
+    /// Write the connected clients to a whazzup.txt file
+    pub enabled: bool,
+    /// File to write; it is replaced in one step, never left half-written
+    pub path: PathBuf,
+    /// Seconds between updates
+    pub interval_secs: u64,
+}
+
+impl Default for WhazzupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("whazzup.txt"),
+            interval_secs: 15,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct StatusConfig {
+    /// Serve the status feeds over HTTP; needs the `http` feature
+    pub enabled: bool,
+    /// Address to listen on; the feeds are public
+    pub address: String,
+    /// Seconds a built feed is reused before the clients are read again
+    pub cache_secs: u64,
+}
+
+impl Default for StatusConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1:8080".to_string(),
+            cache_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ApiConfig {
+    /// Serve /api/ on the status listener, which has to be enabled too
+    pub enabled: bool,
+    /// Bearer token every request must carry; required when enabled
+    pub token: Option<String>,
+    /// Serve POST /api/register to create pilot accounts
+    pub registration: bool,
+    /// Lets registrations through without the token when set
+    pub invite_code: Option<String>,
+    /// Registrations accepted from one IP address per hour
+    pub registrations_per_hour: u32,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            token: None,
+            registration: false,
+            invite_code: None,
+            registrations_per_hour: 5,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    /// Accept FSD sessions over WebSockets; needs the `websocket` feature
+    pub enabled: bool,
+    /// Address to listen on
+    pub address: String,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1:6810".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    /// Events waiting per target; further ones are dropped
+    pub queue_size: usize,
+    /// Tries per event, the first one included
+    pub max_attempts: u32,
+    /// Seconds before the first retry, doubling for each one after it
+    pub retry_delay_secs: u64,
+    /// Seconds one delivery may take
+    pub timeout_secs: u64,
+    /// Endpoints, each a `[[webhooks.targets]]` table
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<WebhookTarget>,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            queue_size: 100,
+            max_attempts: 5,
+            retry_delay_secs: 2,
+            timeout_secs: 10,
+            targets: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct WebhookTarget {
+    /// http:// or https:// URL the events are POSTed to
+    pub url: String,
+    /// Events sent to this target
+    pub events: Vec<WebhookEvent>,
+    /// JSON body with `{field}` placeholders; the plain event otherwise
+    pub template: Option<String>,
+    /// Only flight plans from or to these airports
+    pub airports: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct MiddlewareConfig {
+    /// Log every packet sent and received, except position updates, under
+    /// the `openfsd::packets` target
+    pub log_packets: bool,
+    pub text_filter: TextFilterConfig,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TextFilterConfig {
+    /// Regular expressions text messages are checked against; `(?i)` at the
+    /// start ignores case
+    pub patterns: Vec<String>,
+    /// What happens to a message matching one
+    pub action: TextFilterAction,
+    /// What `mask` replaces each match with
+    pub replacement: String,
+}
+
+impl Default for TextFilterConfig {
+    fn default() -> Self {
+        Self {
+            patterns: Vec::new(),
+            action: TextFilterAction::default(),
+            replacement: "***".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TextFilterAction {
+    /// Pass the message on with the matches replaced
+    #[default]
+    Mask,
+    /// Drop the message without telling the sender
+    Drop,
+    /// Drop the message and send the sender an error
+    Reject,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CaptureConfig {
+    /// Write every packet of the captured connections to files
+    pub enabled: bool,
+    /// Directory for the capture files, one or more per connection
+    pub directory: PathBuf,
+    /// Only connections logged in as one of these callsigns
+    pub callsigns: Vec<String>,
+    /// Only packets with one of these commands, e.g. "AP" or "TM"
+    pub commands: Vec<String>,
+    /// Size in megabytes at which a connection continues in a new file
+    pub max_file_mb: u64,
+    /// Megabytes the directory may hold before capturing stops
+    pub max_total_mb: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: PathBuf::from("captures"),
+            callsigns: Vec::new(),
+            commands: Vec::new(),
+            max_file_mb: 10,
+            max_total_mb: 500,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct DumpConfig {
+    /// Directory the state dumps are written to
+    pub directory: PathBuf,
+    /// Replace client IP addresses in dumps, keeping only the ports
+    pub redact_ips: bool,
+}
+
+impl Default for DumpConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("dumps"),
+            redact_ips: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ChaosConfig {
+    /// Delay and drop packets sent to clients, to test clients against a
+    /// poor connection; never on a server people fly on
+    pub enabled: bool,
+    /// Has to be set as well, or the server refuses to start with `enabled`
+    pub acknowledge_unsafe: bool,
+    /// Milliseconds every write to a client is held back
+    pub delay_ms: u64,
+    /// Up to this many milliseconds more, chosen at random for each write
+    pub jitter_ms: u64,
+    /// Chance from 0 to 1 that a packet is dropped; login and disconnect
+    /// packets never are
+    pub drop_rate: f64,
+    /// Only connections logged in as one of these callsigns; empty affects all
+    pub callsigns: Vec<String>,
+}
+
+/// Thresholds in milliseconds above which a warning is logged; 0 turns one off
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct DiagnosticsConfig {
+    /// Handling a single packet
+    pub slow_packet_ms: u64,
+    /// Waiting for the clients or callsign write lock
+    pub slow_lock_ms: u64,
+    /// Writing a batch of packets to a client
+    pub slow_write_ms: u64,
+}
+
+impl DiagnosticsConfig {
+    pub const DEFAULT_SLOW_PACKET_MS: u64 = 100;
+    pub const DEFAULT_SLOW_LOCK_MS: u64 = 50;
+    pub const DEFAULT_SLOW_WRITE_MS: u64 = 250;
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        Self {
+            slow_packet_ms: Self::DEFAULT_SLOW_PACKET_MS,
+            slow_lock_ms: Self::DEFAULT_SLOW_LOCK_MS,
+            slow_write_ms: Self::DEFAULT_SLOW_WRITE_MS,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    /// Packets from all connections that may wait for the processor before
+    /// reading from clients pauses
+    pub packet_queue: usize,
+    /// Messages a connection may fall behind before it is closed; rounded up
+    /// to a power of two
+    pub broadcast_capacity: usize,
+    /// Threads running the server; one per CPU core if unset
+    pub worker_threads: Option<usize>,
+    /// Seconds a server that handed its listener to a new process on SIGUSR2
+    /// keeps serving its clients before closing their connections
+    pub upgrade_drain_secs: u64,
+    /// Seed for the server's random numbers, such as auth challenges, so
+    /// tests get the same ones every run; drawn from the OS if unset
+    pub random_seed: Option<u64>,
+}
+
+impl RuntimeConfig {
+    pub const DEFAULT_PACKET_QUEUE: usize = 1000;
+    pub const DEFAULT_BROADCAST_CAPACITY: usize = 1024;
+    /// Room for the replies to a login, which are queued all at once
+    pub const MIN_BROADCAST_CAPACITY: usize = 64;
+    pub const DEFAULT_UPGRADE_DRAIN_SECS: u64 = 1800;
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            packet_queue: Self::DEFAULT_PACKET_QUEUE,
+            broadcast_capacity: Self::DEFAULT_BROADCAST_CAPACITY,
+            worker_threads: None,
+            upgrade_drain_secs: Self::DEFAULT_UPGRADE_DRAIN_SECS,
+            random_seed: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// Export the connection spans and the metrics to an OpenTelemetry
+    /// collector; needs the `telemetry` feature
+    pub enabled: bool,
+    /// OTLP/gRPC endpoint of the collector
+    pub endpoint: String,
+    /// gRPC metadata sent with every export, e.g. an API key
+    pub headers: BTreeMap<String, String>,
+    /// `service.name` the data is reported under
+    pub service_name: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: "http://localhost:4317".to_string(),
+            headers: BTreeMap::new(),
+            service_name: "openfsd".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    ClientConnected,
+    ClientDisconnected,
+    FlightPlanFiled,
+    SupervisorCalled,
+    ClientKicked,
 }
 
 impl Config {
+    /// Build the effective configuration from every source
+    ///
+    /// Later layers win: built-in defaults, then the file, then the
+    /// environment variables in `env`, then `overrides`. A `path` given
+    /// explicitly must exist; without one `config.toml` is used if present.
+    pub fn load(
+        path: Option<&Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+        overrides: &Overrides,
+    ) -> Result<Self, ConfigError> {
+        let mut config = match path {
+            Some(path) if !path.exists() => return Err(ConfigError::NotFound(path.to_path_buf())),
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            None => Self::default(),
+        };
+        config.apply_env(env)?;
+        config.apply_overrides(overrides);
+        Ok(config)
+    }
+
     /// Load configuration from a TOML file
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
-        let content = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let mut config = Self::parse(&content).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        config.path = Some(path.to_path_buf());
         Ok(config)
     }
+
+    /// Apply settings from environment variables
+    ///
+    /// `DATABASE_URL` is honoured like in the admin tool. After it, every
+    /// `OPENFSD_SECTION__KEY` variable sets `section.key`, converted to the
+    /// type of that key; `OPENFSD_SECTION__KEY_FILE` reads the value from a
+    /// file instead. Names that match no key end up in `unknown_keys`.
+    pub fn apply_env(
+        &mut self,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), ConfigError> {
+        let mut vars: Vec<(String, String)> = env
+            .into_iter()
+            .filter(|(name, _)| {
+                name == "DATABASE_URL"
+                    || (name.starts_with(ENV_PREFIX) && name.contains(ENV_SEPARATOR))
+            })
+            .collect();
+        if vars.is_empty() {
+            return Ok(());
+        }
+        // DATABASE_URL first so OPENFSD_DATABASE__URL can override it
+        vars.sort_by_key(|(name, _)| (name != "DATABASE_URL", name.clone()));
+
+        let mut table = self.to_table();
+        for (var, value) in vars {
+            let (name, value) = match var.strip_suffix(ENV_FILE_SUFFIX) {
+                Some(name) => (name.to_string(), read_env_file(&var, &value)?),
+                None => (var.clone(), value),
+            };
+            let path: Vec<String> = match name.strip_prefix(ENV_PREFIX) {
+                Some(key) => key.split(ENV_SEPARATOR).map(str::to_lowercase).collect(),
+                None if value.is_empty() => continue,
+                None => vec!["database".to_string(), "url".to_string()],
+            };
+
+            set_env_value(&mut table, &path, value).map_err(|message| ConfigError::Env {
+                var: var.clone(),
+                message,
+            })?;
+            let config = toml::Value::Table(table)
+                .try_into::<Config>()
+                .map_err(|e| ConfigError::Env {
+                    var: var.clone(),
+                    message: e.message().to_string(),
+                })?;
+            *self = Config {
+                unknown_keys: std::mem::take(&mut self.unknown_keys),
+                path: self.path.take(),
+                ..config
+            };
+
+            // Keys the schema doesn't have are dropped by the round trip
+            table = self.to_table();
+            if !has_key(&table, &path) {
+                self.unknown_keys.push(var);
+            }
+        }
+        Ok(())
+    }
+
+    /// All settings as a TOML table; unset optional keys are left out
+    fn to_table(&self) -> toml::Table {
+        match toml::Value::try_from(self) {
+            Ok(toml::Value::Table(table)) => table,
+            _ => toml::Table::new(),
+        }
+    }
+
+    pub fn apply_overrides(&mut self, overrides: &Overrides) {
+        if let Some(address) = &overrides.address {
+            self.server.address = address.clone();
+        }
+        if let Some(port) = overrides.port {
+            self.server.port = port;
+        }
+        if let Some(url) = &overrides.database_url {
+            self.database.url = url.clone();
+        }
+        if let Some(level) = &overrides.log_level {
+            self.logging.level = level.clone();
+        }
+    }
+
+    /// Parse a TOML document, recording keys the schema doesn't know in
+    /// `unknown_keys` instead of failing
+    pub fn parse(content: &str) -> Result<Self, toml::de::Error> {
+        let mut config: Config = toml::from_str(content)?;
+        let input: toml::Table = toml::from_str(content)?;
+        // Every field is serialized back, so keys missing afterwards were ignored
+        if let Ok(toml::Value::Table(known)) = toml::Value::try_from(&config) {
+            collect_unknown_keys(&input, &known, "", &mut config.unknown_keys);
+            config.unknown_keys.sort();
+        }
+        Ok(config)
+    }
+
+    /// Problems that should stop the server from starting
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if !is_valid_log_filter(&self.logging.level) {
+            problems.push(format!(
+                "logging.level: unknown log level \"{}\"",
+                self.logging.level
+            ));
+        }
+        for (name, level) in [
+            ("console_level", &self.logging.console_level),
+            ("file_level", &self.logging.file_level),
+        ] {
+            if let Some(level) = level {
+                if level.parse::<LevelFilter>().is_err() {
+                    problems.push(format!("logging.{}: unknown log level \"{}\"", name, level));
+                }
+            }
+        }
+        if self.logging.file.is_some() {
+            if self.logging.rotation == LogRotation::Size && self.logging.max_size_mb == 0 {
+                problems.push("logging.max_size_mb must not be 0".to_string());
+            }
+            if self.logging.rotation != LogRotation::Never && self.logging.keep_files == 0 {
+                problems.push("logging.keep_files must not be 0".to_string());
+            }
+        }
+        if self.server.port == 0 {
+            problems.push("server.port must not be 0".to_string());
+        }
+        if self.server.max_clients == 0 {
+            problems.push("server.max_clients must not be 0".to_string());
+        }
+        if let Some(callsign) = &self.server.server_callsign {
+            if callsign.is_empty() || callsign.contains(|c: char| c == ':' || !c.is_ascii_graphic())
+            {
+                problems.push(format!(
+                    "server.server_callsign: \"{}\" is not a valid callsign",
+                    callsign
+                ));
+            }
+        }
+        if let Some(banner) = &self.server.ident_banner {
+            if banner.is_empty() || banner.contains(|c: char| c == ':' || c.is_control()) {
+                problems.push(
+                    "server.ident_banner must not be empty or contain colons or line breaks"
+                        .to_string(),
+                );
+            }
+        }
+        if self.database.url.trim().is_empty() {
+            problems.push("database.url must not be empty".to_string());
+        }
+        if self.database.max_connections == 0 {
+            problems.push("database.max_connections must not be 0".to_string());
+        } else if self.database.min_connections > self.database.max_connections {
+            problems.push(
+                "database.min_connections must not exceed database.max_connections".to_string(),
+            );
+        }
+        if self.auth.require_challenge && !self.protocol.dialect.issues_challenges() {
+            problems
+                .push("auth.require_challenge is only supported by the vatsim dialect".to_string());
+        }
+        if self.features.require_flight_plan && self.features.flight_plan_grace_minutes == 0 {
+            problems.push("features.flight_plan_grace_minutes must not be 0".to_string());
+        }
+        if self.heartbeat.enabled && self.heartbeat.interval_secs == 0 {
+            problems.push("heartbeat.interval_secs must not be 0".to_string());
+        }
+        match self.weather.provider {
+            WeatherProvider::Custom => match &self.weather.url_template {
+                Some(template) if template.contains("{icao}") => {}
+                _ => problems.push(
+                    "weather.url_template with {icao} is required for the custom provider"
+                        .to_string(),
+                ),
+            },
+            WeatherProvider::Static if self.weather.static_file.is_none() => {
+                problems.push("weather.static_file is required for the static provider".to_string())
+            }
+            _ => {}
+        }
+        if self.weather.request_timeout_secs == 0 {
+            problems.push("weather.request_timeout_secs must not be 0".to_string());
+        }
+        if self.weather.metar_refresh_secs == 0 {
+            problems.push("weather.metar_refresh_secs must not be 0".to_string());
+        }
+        for (i, fallback) in self.weather.fallbacks.iter().enumerate() {
+            if !fallback.url_template.contains("{icao}") {
+                problems.push(format!(
+                    "weather.fallbacks[{}].url_template must contain {{icao}}",
+                    i
+                ));
+            }
+            if fallback.timeout_secs == Some(0) {
+                problems.push(format!(
+                    "weather.fallbacks[{}].timeout_secs must not be 0",
+                    i
+                ));
+            }
+        }
+        for name in self.limits.zero_limits() {
+            problems.push(format!("limits.{} must not be 0", name));
+        }
+        if self.visibility.max_range_nm == 0 {
+            problems.push("visibility.max_range_nm must not be 0".to_string());
+        }
+        if self.visibility.snapshot_interval_ms == 0 {
+            problems.push("visibility.snapshot_interval_ms must not be 0".to_string());
+        }
+        for ip in &self.security.blocked_ips {
+            if ip.parse::<IpAddr>().is_err() {
+                problems.push(format!("security.blocked_ips: invalid address \"{}\"", ip));
+            }
+        }
+        if self.control.enabled {
+            if self.control.address.parse::<SocketAddr>().is_err() {
+                problems.push(format!(
+                    "control.address: invalid socket address \"{}\"",
+                    self.control.address
+                ));
+            }
+            if self.control.secret.as_deref().is_none_or(str::is_empty) {
+                problems.push("control.secret is required when control is enabled".to_string());
+            }
+        }
+        if self.metrics.enabled {
+            if self.metrics.address.parse::<SocketAddr>().is_err() {
+                problems.push(format!(
+                    "metrics.address: invalid socket address \"{}\"",
+                    self.metrics.address
+                ));
+            }
+            if !cfg!(feature = "prometheus") {
+                problems
+                    .push("metrics.enabled needs a build with the prometheus feature".to_string());
+            }
+        }
+        if self.telemetry.enabled {
+            if self.telemetry.endpoint.is_empty() {
+                problems.push("telemetry.endpoint must not be empty".to_string());
+            }
+            if self.telemetry.service_name.is_empty() {
+                problems.push("telemetry.service_name must not be empty".to_string());
+            }
+            for name in self.telemetry.headers.keys() {
+                if !is_valid_metadata_key(name) {
+                    problems.push(format!(
+                        "telemetry.headers: invalid header name \"{}\"",
+                        name
+                    ));
+                }
+            }
+            if !cfg!(feature = "telemetry") {
+                problems
+                    .push("telemetry.enabled needs a build with the telemetry feature".to_string());
+            }
+        }
+        if self.runtime.packet_queue == 0 {
+            problems.push("runtime.packet_queue must not be 0".to_string());
+        }
+        if self.runtime.broadcast_capacity < RuntimeConfig::MIN_BROADCAST_CAPACITY {
+            problems.push(format!(
+                "runtime.broadcast_capacity must be at least {}",
+                RuntimeConfig::MIN_BROADCAST_CAPACITY
+            ));
+        }
+        if self.runtime.worker_threads == Some(0) {
+            problems.push("runtime.worker_threads must not be 0".to_string());
+        }
+        if self.whazzup.enabled && self.whazzup.interval_secs == 0 {
+            problems.push("whazzup.interval_secs must not be 0".to_string());
+        }
+        if self.status.enabled {
+            if self.status.address.parse::<SocketAddr>().is_err() {
+                problems.push(format!(
+                    "status.address: invalid socket address \"{}\"",
+                    self.status.address
+                ));
+            }
+            if !cfg!(feature = "http") {
+                problems.push("status.enabled needs a build with the http feature".to_string());
+            }
+        }
+        if self.api.enabled {
+            if !self.status.enabled {
+                problems.push("api.enabled needs status.enabled".to_string());
+            }
+            if self.api.token.as_deref().is_none_or(str::is_empty) {
+                problems.push("api.token is required when the API is enabled".to_string());
+            }
+        }
+        if self.websocket.enabled {
+            if self.websocket.address.parse::<SocketAddr>().is_err() {
+                problems.push(format!(
+                    "websocket.address: invalid socket address \"{}\"",
+                    self.websocket.address
+                ));
+            }
+            if !cfg!(feature = "websocket") {
+                problems
+                    .push("websocket.enabled needs a build with the websocket feature".to_string());
+            }
+        }
+        for (i, target) in self.webhooks.targets.iter().enumerate() {
+            let scheme = url::Url::parse(&target.url).map(|url| url.scheme().to_string());
+            if !matches!(scheme.as_deref(), Ok("http" | "https")) {
+                problems.push(format!(
+                    "webhooks.targets[{}].url: not an http(s) URL \"{}\"",
+                    i, target.url
+                ));
+            }
+            if target.events.is_empty() {
+                problems.push(format!("webhooks.targets[{}].events must not be empty", i));
+            }
+            if let Some(template) = &target.template {
+                if !crate::webhooks::template_is_valid(template) {
+                    problems.push(format!(
+                        "webhooks.targets[{}].template does not render to JSON",
+                        i
+                    ));
+                }
+            }
+        }
+        if !self.webhooks.targets.is_empty() {
+            if self.webhooks.queue_size == 0 {
+                problems.push("webhooks.queue_size must not be 0".to_string());
+            }
+            if self.webhooks.max_attempts == 0 {
+                problems.push("webhooks.max_attempts must not be 0".to_string());
+            }
+        }
+        for pattern in &self.middleware.text_filter.patterns {
+            if let Err(e) = regex::Regex::new(pattern) {
+                problems.push(format!(
+                    "middleware.text_filter.patterns: invalid pattern \"{}\": {}",
+                    pattern, e
+                ));
+            }
+        }
+        if self.capture.enabled {
+            if self.capture.max_file_mb == 0 {
+                problems.push("capture.max_file_mb must not be 0".to_string());
+            }
+            if self.capture.max_total_mb < self.capture.max_file_mb {
+                problems
+                    .push("capture.max_total_mb must be at least capture.max_file_mb".to_string());
+            }
+        }
+        if self.chaos.enabled && !self.chaos.acknowledge_unsafe {
+            problems.push(
+                "chaos.enabled needs chaos.acknowledge_unsafe = true; it degrades every affected connection"
+                    .to_string(),
+            );
+        }
+        if !(0.0..=1.0).contains(&self.chaos.drop_rate) {
+            problems.push("chaos.drop_rate must be between 0 and 1".to_string());
+        }
+        if self.api.registration {
+            if !self.api.enabled {
+                problems.push("api.registration needs api.enabled".to_string());
+            }
+            if self.api.registrations_per_hour == 0 {
+                problems.push("api.registrations_per_hour must be at least 1".to_string());
+            }
+        }
+        if self.tls.enabled {
+            if self.tls.cert_path.is_none() {
+                problems.push("tls.cert_path is required when TLS is enabled".to_string());
+            }
+            if self.tls.key_path.is_none() {
+                problems.push("tls.key_path is required when TLS is enabled".to_string());
+            }
+        }
+
+        problems
+    }
+}
+
+/// Dotted paths of keys in `input` that don't appear in `known`
+fn collect_unknown_keys(
+    input: &toml::Table,
+    known: &toml::Table,
+    prefix: &str,
+    unknown: &mut Vec<String>,
+) {
+    for (key, value) in input {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match (value, known.get(key)) {
+            (_, None) => unknown.push(path),
+            (toml::Value::Table(input), Some(toml::Value::Table(known))) => {
+                collect_unknown_keys(input, known, &path, unknown)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Read the value of a `_FILE` variable, without the trailing newline
+fn read_env_file(var: &str, path: &str) -> Result<String, ConfigError> {
+    fs::read_to_string(path)
+        .map(|value| value.trim_end_matches(['\r', '\n']).to_string())
+        .map_err(|source| ConfigError::EnvFile {
+            var: var.to_string(),
+            path: PathBuf::from(path),
+            source,
+        })
+}
+
+/// Set the key at `path` from an environment variable value, converted to the
+/// type the key currently has
+fn set_env_value(table: &mut toml::Table, path: &[String], value: String) -> Result<(), String> {
+    let (key, sections) = path.split_last().ok_or("missing key")?;
+    let mut table = table;
+    for section in sections {
+        table = match table
+            .entry(section.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+        {
+            toml::Value::Table(table) => table,
+            _ => return Err(format!("{} is not a section", section)),
+        };
+    }
+
+    let value = match table.get(key) {
+        Some(toml::Value::Integer(_)) => value
+            .trim()
+            .parse()
+            .map(toml::Value::Integer)
+            .map_err(|_| format!("expected an integer, got \"{}\"", value))?,
+        Some(toml::Value::Float(_)) => value
+            .trim()
+            .parse()
+            .map(toml::Value::Float)
+            .map_err(|_| format!("expected a number, got \"{}\"", value))?,
+        Some(toml::Value::Boolean(_)) => value
+            .trim()
+            .parse()
+            .map(toml::Value::Boolean)
+            .map_err(|_| format!("expected true or false, got \"{}\"", value))?,
+        // Comma-separated, e.g. OPENFSD_TRACKS__CALLSIGNS=CCA1501,CSN6311
+        Some(toml::Value::Array(_)) => toml::Value::Array(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| toml::Value::String(item.to_string()))
+                .collect(),
+        ),
+        Some(toml::Value::Table(_)) => return Err(format!("{} is a section", key)),
+        _ => toml::Value::String(value),
+    };
+    table.insert(key.clone(), value);
+    Ok(())
+}
+
+fn has_key(table: &toml::Table, path: &[String]) -> bool {
+    match path {
+        [] => true,
+        [key, rest @ ..] => match table.get(key) {
+            Some(toml::Value::Table(table)) => has_key(table, rest),
+            Some(_) => rest.is_empty(),
+            None => false,
+        },
+    }
+}
+
+/// Whether every level in the filter is a known one
+fn is_valid_log_filter(filter: &str) -> bool {
+    filter.split(',').all(|directive| {
+        let level = directive.rsplit('=').next().unwrap_or_default().trim();
+        level.parse::<LevelFilter>().is_ok()
+    })
+}
+
+/// Whether `name` can be sent as gRPC metadata: lowercase, with no spaces
+/// or separators
+fn is_valid_metadata_key(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"-_.".contains(&b))
 }
 
 impl Default for Config {
@@ -46,13 +1738,44 @@ impl Default for Config {
                 name: "OpenFSD".to_string(),
                 version: env!("CARGO_PKG_VERSION").to_string(),
                 max_clients: 1000,
+                motd: Vec::new(),
+                atc_motd: Vec::new(),
+                hostname: None,
+                location: String::new(),
+                server_callsign: None,
+                ident_banner: None,
             },
-            logging: LoggingConfig {
-                level: "info".to_string(),
-            },
-            database: DatabaseConfig {
-                url: "sqlite://openfsd.db".to_string(),
-            },
+            logging: LoggingConfig::default(),
+            database: DatabaseConfig::default(),
+            protocol: ProtocolConfig::default(),
+            features: FeaturesConfig::default(),
+            whitelist: WhitelistConfig::default(),
+            auth: AuthConfig::default(),
+            tracks: TracksConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            idle: IdleConfig::default(),
+            weather: WeatherConfig::default(),
+            security: SecurityConfig::default(),
+            limits: LimitsConfig::default(),
+            visibility: VisibilityConfig::default(),
+            tls: TlsConfig::default(),
+            voice: VoiceConfig::default(),
+            control: ControlConfig::default(),
+            metrics: MetricsConfig::default(),
+            whazzup: WhazzupConfig::default(),
+            status: StatusConfig::default(),
+            api: ApiConfig::default(),
+            websocket: WebSocketConfig::default(),
+            webhooks: WebhooksConfig::default(),
+            middleware: MiddlewareConfig::default(),
+            capture: CaptureConfig::default(),
+            diagnostics: DiagnosticsConfig::default(),
+            chaos: ChaosConfig::default(),
+            runtime: RuntimeConfig::default(),
+            dump: DumpConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            unknown_keys: Vec::new(),
+            path: None,
         }
     }
 }
@@ -60,11 +1783,869 @@ impl Default for Config {
 impl From<Config> for crate::server::ServerConfig {
     fn from(config: Config) -> Self {
         Self {
+            hostname: config
+                .server
+                .hostname
+                .unwrap_or_else(|| config.server.address.clone()),
+            location: config.server.location,
+            server_callsign: config
+                .server
+                .server_callsign
+                .unwrap_or_else(|| crate::server::DEFAULT_SERVER_CALLSIGN.to_string()),
+            ident_banner: config.server.ident_banner,
             address: config.server.address,
             port: config.server.port,
             server_name: config.server.name,
             server_version: config.server.version,
             max_clients: config.server.max_clients,
+            motd: config.server.motd,
+            atc_motd: config.server.atc_motd,
+            whitelist: config.whitelist,
+            dialect: config.protocol.dialect,
+            features: config.features,
+            auth: config.auth,
+            tracks: config.tracks,
+            heartbeat: config.heartbeat,
+            idle: config.idle,
+            limits: config.limits,
+            visibility: config.visibility,
+            control: config.control,
+            metrics: config.metrics,
+            whazzup: config.whazzup,
+            status: config.status,
+            api: config.api,
+            security: config.security,
+            websocket: config.websocket,
+            webhooks: config.webhooks,
+            capture: config.capture,
+            diagnostics: config.diagnostics,
+            chaos: config.chaos,
+            runtime: config.runtime,
+            dump: config.dump,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_without_database_section() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+
+            [logging]
+            level = "info"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.database.url, "sqlite://openfsd.db");
+        assert_eq!(config.database.max_lifetime, 1800);
+    }
+
+    #[test]
+    fn test_config_partial_database_section() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+
+            [logging]
+            level = "info"
+
+            [database]
+            url = "postgres://openfsd@localhost/openfsd"
+            max_connections = 50
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.database.url, "postgres://openfsd@localhost/openfsd");
+        assert_eq!(config.database.max_connections, 50);
+        assert_eq!(config.database.min_connections, 1);
+    }
+
+    #[test]
+    fn test_minimal_config() {
+        let config = Config::parse(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.logging.level, "info");
+        assert_eq!(config.limits.packets_per_second, 20);
+        assert!(!config.tls.enabled);
+        assert!(config.unknown_keys.is_empty());
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_full_config() {
+        // The shipped example sets every section
+        let config = Config::parse(include_str!("../config.toml")).unwrap();
+        assert!(config.unknown_keys.is_empty(), "{:?}", config.unknown_keys);
+        assert!(config.validate().is_empty());
+
+        let config = Config::parse(
+            r#"
+            [server]
+            address = "127.0.0.1"
+            port = 6810
+            name = "Test"
+            version = "1.0"
+            max_clients = 10
+
+            [logging]
+            level = "debug,sea_orm=warn"
+
+            [weather]
+            provider = "static"
+            static_file = "/var/lib/openfsd/metars.txt"
+
+            [security]
+            blocked_ips = ["192.0.2.1", "2001:db8::1"]
+
+            [limits]
+            burst = 100
+
+            [tls]
+            enabled = true
+            cert_path = "/etc/openfsd/cert.pem"
+            key_path = "/etc/openfsd/key.pem"
+
+            [voice]
+            server = "voice.example.org"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.weather.provider, WeatherProvider::Static);
+        assert_eq!(config.weather.metar_cache_ttl_secs, 600);
+        assert_eq!(config.limits.burst, 100);
+        assert_eq!(config.voice.server, "voice.example.org");
+        assert!(config.unknown_keys.is_empty(), "{:?}", config.unknown_keys);
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_keys_are_reported() {
+        let config = Config::parse(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+            motto = "Fly safe"
+
+            [logging]
+            level = "info"
+
+            [radar]
+            enabled = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.unknown_keys, vec!["radar", "server.motto"]);
+    }
+
+    #[test]
+    fn test_features_section() {
+        let config = Config::parse(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+
+            [features]
+            allow_observers = false
+            require_flight_plan = true
+            missing_flight_plan_action = "kick"
+            "#,
+        )
+        .unwrap();
+        assert!(!config.features.allow_observers);
+        assert_eq!(
+            config.features.missing_flight_plan_action,
+            MissingFlightPlanAction::Kick
+        );
+        assert_eq!(config.features.flight_plan_grace_minutes, 10);
+        assert!(!config.features.strict_mode);
+        assert!(config.validate().is_empty());
+
+        let mut no_grace = config;
+        no_grace.features.flight_plan_grace_minutes = 0;
+        assert_eq!(
+            no_grace.validate(),
+            vec!["features.flight_plan_grace_minutes must not be 0"]
+        );
+    }
+
+    #[test]
+    fn test_control_section() {
+        let mut config = Config::default();
+        assert!(!config.control.enabled);
+        assert!(config.validate().is_empty());
+
+        config.control.enabled = true;
+        config.control.address = "localhost".to_string();
+        assert_eq!(
+            config.validate(),
+            vec![
+                "control.address: invalid socket address \"localhost\"",
+                "control.secret is required when control is enabled",
+            ]
+        );
+
+        config.control.address = "127.0.0.1:6810".to_string();
+        config.control.secret = Some("s3cret".to_string());
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_metrics_section() {
+        let mut config = Config::default();
+        assert!(!config.metrics.enabled);
+
+        config.metrics.enabled = true;
+        config.metrics.address = "localhost".to_string();
+        let mut problems = vec!["metrics.address: invalid socket address \"localhost\""];
+        if !cfg!(feature = "prometheus") {
+            problems.push("metrics.enabled needs a build with the prometheus feature");
+        }
+        assert_eq!(config.validate(), problems);
+    }
+
+    #[test]
+    fn test_telemetry_section() {
+        let mut config = Config::parse(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+
+            [telemetry]
+            enabled = true
+            endpoint = ""
+
+            [telemetry.headers]
+            x-api-key = "secret"
+            X-Tenant = "a"
+            "#,
+        )
+        .unwrap();
+        assert!(config.unknown_keys.is_empty(), "{:?}", config.unknown_keys);
+        assert_eq!(config.telemetry.headers["x-api-key"], "secret");
+        assert_eq!(config.telemetry.service_name, "openfsd");
+
+        let mut problems = vec![
+            "telemetry.endpoint must not be empty",
+            "telemetry.headers: invalid header name \"X-Tenant\"",
+        ];
+        if !cfg!(feature = "telemetry") {
+            problems.push("telemetry.enabled needs a build with the telemetry feature");
+        }
+        assert_eq!(config.validate(), problems);
+        config.telemetry.enabled = false;
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_status_section() {
+        let mut config = Config::default();
+        assert!(!config.status.enabled);
+
+        config.status.enabled = true;
+        config.status.address = "8080".to_string();
+        let mut problems = vec!["status.address: invalid socket address \"8080\""];
+        if !cfg!(feature = "http") {
+            problems.push("status.enabled needs a build with the http feature");
+        }
+        assert_eq!(config.validate(), problems);
+    }
+
+    #[test]
+    fn test_websocket_section() {
+        let mut config = Config::default();
+        assert!(!config.websocket.enabled);
+
+        config.websocket.enabled = true;
+        assert_eq!(config.validate().is_empty(), cfg!(feature = "websocket"));
+
+        config.websocket.address = "localhost".to_string();
+        assert!(config
+            .validate()
+            .contains(&"websocket.address: invalid socket address \"localhost\"".to_string()));
+    }
+
+    #[test]
+    fn test_webhooks_section() {
+        let config = Config::parse(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+
+            [[webhooks.targets]]
+            url = "https://discord.example.com/api/webhooks/1/abc"
+            events = ["client_connected", "flight_plan_filed"]
+            template = '{"content": "{callsign} {event}"}'
+            airports = ["ZBAA"]
+
+            [[webhooks.targets]]
+            url = "ftp://example.com"
+            events = []
+            template = "{callsign}"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.webhooks.targets.len(), 2);
+        assert_eq!(
+            config.webhooks.targets[0].events,
+            [WebhookEvent::ClientConnected, WebhookEvent::FlightPlanFiled]
+        );
+        assert_eq!(config.webhooks.max_attempts, 5);
+        assert_eq!(
+            config.validate(),
+            [
+                "webhooks.targets[1].url: not an http(s) URL \"ftp://example.com\"",
+                "webhooks.targets[1].events must not be empty",
+                "webhooks.targets[1].template does not render to JSON",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_weather_fallbacks() {
+        let config = Config::parse(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+
+            [weather]
+            stale_max_minutes = 30
+
+            [[weather.fallbacks]]
+            name = "mirror"
+            url_template = "https://wx.example.org/{product}/{icao}.txt"
+
+            [[weather.fallbacks]]
+            url_template = "https://wx.example.org/latest"
+            timeout_secs = 0
+            "#,
+        )
+        .unwrap();
+        let weather = &config.weather;
+        assert_eq!(weather.provider, WeatherProvider::Noaa);
+        assert_eq!(weather.stale_max_minutes, 30);
+        assert_eq!(weather.provider_cooldown_secs, 60);
+        assert_eq!(weather.fallbacks[0].name.as_deref(), Some("mirror"));
+        assert_eq!(weather.fallbacks[0].timeout_secs, None);
+        assert_eq!(
+            config.validate(),
+            [
+                "weather.fallbacks[1].url_template must contain {icao}",
+                "weather.fallbacks[1].timeout_secs must not be 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_capture_section() {
+        let mut config = Config::default();
+        assert!(!config.capture.enabled);
+        assert_eq!(config.capture.directory, PathBuf::from("captures"));
+
+        config.capture.enabled = true;
+        assert!(config.validate().is_empty());
+
+        config.capture.max_file_mb = 1000;
+        assert_eq!(
+            config.validate(),
+            ["capture.max_total_mb must be at least capture.max_file_mb"]
+        );
+        config.capture.max_file_mb = 0;
+        assert_eq!(config.validate(), ["capture.max_file_mb must not be 0"]);
+    }
+
+    #[test]
+    fn test_chaos_needs_acknowledgement() {
+        let mut config = Config::default();
+        assert!(!config.chaos.enabled);
+
+        config.chaos.enabled = true;
+        config.chaos.drop_rate = 0.1;
+        assert_eq!(
+            config.validate(),
+            ["chaos.enabled needs chaos.acknowledge_unsafe = true; it degrades every affected connection"]
+        );
+        config.chaos.acknowledge_unsafe = true;
+        assert!(config.validate().is_empty());
+
+        config.chaos.drop_rate = 1.5;
+        assert_eq!(
+            config.validate(),
+            ["chaos.drop_rate must be between 0 and 1"]
+        );
+    }
+
+    #[test]
+    fn test_text_filter_patterns_must_compile() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+
+            [middleware.text_filter]
+            patterns = ["(?i)darn", "(unclosed"]
+            action = "reject"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.middleware.text_filter.action,
+            TextFilterAction::Reject
+        );
+        assert_eq!(config.middleware.text_filter.replacement, "***");
+        let problems = config.validate();
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0]
+            .starts_with("middleware.text_filter.patterns: invalid pattern \"(unclosed\""));
+
+        config.middleware.text_filter.patterns.pop();
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_api_section() {
+        let mut config = Config::default();
+        assert!(!config.api.enabled);
+
+        config.api.enabled = true;
+        assert_eq!(
+            config.validate(),
+            [
+                "api.enabled needs status.enabled",
+                "api.token is required when the API is enabled",
+            ]
+        );
+
+        config.status.enabled = true;
+        config.api.token = Some("t0ken".to_string());
+        assert_eq!(config.validate().is_empty(), cfg!(feature = "http"));
+
+        let mut config = Config::default();
+        config.api.registration = true;
+        config.api.registrations_per_hour = 0;
+        assert_eq!(
+            config.validate(),
+            [
+                "api.registration needs api.enabled",
+                "api.registrations_per_hour must be at least 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_protocol_section() {
+        let parse = |protocol: &str| {
+            Config::parse(&format!(
+                r#"
+                [server]
+                address = "0.0.0.0"
+                port = 6809
+                name = "OpenFSD"
+                version = "0.1.0"
+                max_clients = 1000
+
+                {}
+                "#,
+                protocol
+            ))
+        };
+
+        assert_eq!(parse("").unwrap().protocol.dialect, Dialect::Vatsim);
+        let ivao = parse("[protocol]\ndialect = \"ivao\"").unwrap();
+        assert_eq!(ivao.protocol.dialect, Dialect::Ivao);
+        assert!(ivao.validate().is_empty());
+
+        // One dialect per server
+        assert!(parse("[protocol]\ndialect = [\"vatsim\", \"ivao\"]").is_err());
+        let mixed = parse("[protocol]\ndialect = \"ivao\"\n[auth]\nrequire_challenge = true");
+        assert_eq!(
+            mixed.unwrap().validate(),
+            vec!["auth.require_challenge is only supported by the vatsim dialect"]
+        );
+    }
+
+    #[test]
+    fn test_message_escaping_per_dialect() {
+        // "Ready at 12::30" split at its colons
+        let fields = vec!["Ready at 12".to_string(), String::new(), "30".to_string()];
+        assert_eq!(Dialect::Ivao.message_text(&fields), "Ready at 12:30");
+        assert_eq!(Dialect::Vatsim.message_text(&fields), "Ready at 12::30");
+        assert_eq!(Dialect::Generic.message_text(&fields), "Ready at 12::30");
+    }
+
+    #[test]
+    fn test_logging_section() {
+        let config = Config::parse(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+
+            [logging]
+            file = "/var/log/openfsd.log"
+            file_level = "debug"
+            console_level = "loud"
+            rotation = "size"
+            max_size_mb = 0
+            format = "json"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.logging.level, "info");
+        assert_eq!(config.logging.file_level.as_deref(), Some("debug"));
+        assert_eq!(config.logging.format, LogFormat::Json);
+        assert_eq!(config.logging.keep_files, 7);
+        assert_eq!(
+            config.validate(),
+            vec![
+                "logging.console_level: unknown log level \"loud\"",
+                "logging.max_size_mb must not be 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_visibility_section() {
+        let config = Config::parse(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+
+            [visibility]
+            pilot_range_nm = 80
+            max_range_nm = 0
+
+            [visibility.atc]
+            ctr = 400
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.visibility.pilot_range_nm, 80);
+        assert_eq!(config.visibility.obs_range_nm, 300);
+        assert_eq!(config.visibility.atc.ctr, 400);
+        assert_eq!(config.visibility.atc.twr, 50);
+        assert!(config.unknown_keys.is_empty());
+        assert_eq!(
+            config.validate(),
+            vec!["visibility.max_range_nm must not be 0"]
+        );
+    }
+
+    #[test]
+    fn test_limits_section() {
+        let config = Config::parse(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+
+            [limits]
+            packets_per_second = 50
+            text_messages_per_minute = 10
+            lockout_minutes = 0
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.limits.packets_per_second, 50);
+        assert_eq!(config.limits.burst, 40);
+        assert_eq!(config.limits.text_messages_per_minute, 10);
+        assert_eq!(
+            config.validate(),
+            vec!["limits.lockout_minutes must not be 0"]
+        );
+
+        // Negative values don't fit the unsigned fields
+        assert!(Config::parse(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+
+            [limits]
+            burst = -1
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_runtime_section() {
+        let mut config = Config::parse(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 6809
+            name = "OpenFSD"
+            version = "0.1.0"
+            max_clients = 1000
+
+            [runtime]
+            packet_queue = 1
+            broadcast_capacity = 100
+            worker_threads = 2
+            upgrade_drain_secs = 60
+            "#,
+        )
+        .unwrap();
+        assert!(config.unknown_keys.is_empty(), "{:?}", config.unknown_keys);
+        assert!(config.validate().is_empty());
+
+        let server = crate::server::ServerConfig::from(config.clone());
+        assert_eq!(server.runtime.packet_queue, 1);
+        assert_eq!(server.runtime.broadcast_capacity, 100);
+        assert_eq!(server.runtime.worker_threads, Some(2));
+        assert_eq!(server.runtime.upgrade_drain_secs, 60);
+
+        config.runtime = RuntimeConfig {
+            packet_queue: 0,
+            broadcast_capacity: 8,
+            worker_threads: Some(0),
+            upgrade_drain_secs: 0,
+            random_seed: None,
+        };
+        assert_eq!(
+            config.validate(),
+            [
+                "runtime.packet_queue must not be 0",
+                "runtime.broadcast_capacity must be at least 64",
+                "runtime.worker_threads must not be 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_lists_every_problem() {
+        let mut config = Config::default();
+        config.logging.level = "verbose".to_string();
+        config.server.port = 0;
+        config.server.server_callsign = Some("FSD SERVER".to_string());
+        config.server.ident_banner = Some("OpenFSD:1".to_string());
+        config.database.url = " ".to_string();
+        config.tls.enabled = true;
+        config.tls.cert_path = Some(PathBuf::from("cert.pem"));
+
+        assert_eq!(
+            config.validate(),
+            vec![
+                "logging.level: unknown log level \"verbose\"",
+                "server.port must not be 0",
+                "server.server_callsign: \"FSD SERVER\" is not a valid callsign",
+                "server.ident_banner must not be empty or contain colons or line breaks",
+                "database.url must not be empty",
+                "tls.key_path is required when TLS is enabled",
+            ]
+        );
+    }
+
+    fn no_env() -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_load_layers() {
+        let dir = std::env::temp_dir().join(format!("openfsd-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(
+            &path,
+            r#"
+            [server]
+            address = "127.0.0.1"
+            port = 7000
+            name = "File"
+            version = "0.1.0"
+            max_clients = 10
+
+            [database]
+            url = "sqlite://file.db"
+            "#,
+        )
+        .unwrap();
+
+        // File over defaults
+        let config = Config::load(Some(&path), no_env(), &Overrides::default()).unwrap();
+        assert_eq!(config.path.as_deref(), Some(path.as_path()));
+        assert_eq!(config.server.port, 7000);
+        assert_eq!(config.database.url, "sqlite://file.db");
+        assert_eq!(config.logging.level, "info");
+
+        // Environment over the file
+        let vars = env(&[
+            ("DATABASE_URL", "sqlite://env.db"),
+            ("OPENFSD_SERVER__PORT", "7002"),
+        ]);
+        let config = Config::load(Some(&path), vars.clone(), &Overrides::default()).unwrap();
+        assert_eq!(config.database.url, "sqlite://env.db");
+        assert_eq!(config.server.port, 7002);
+
+        // Command line over the environment
+        let overrides = Overrides {
+            address: Some("0.0.0.0".to_string()),
+            port: Some(7001),
+            database_url: Some("sqlite://cli.db".to_string()),
+            log_level: Some("debug".to_string()),
+        };
+        let config = Config::load(Some(&path), vars, &overrides).unwrap();
+        assert_eq!(config.server.address, "0.0.0.0");
+        assert_eq!(config.server.port, 7001);
+        assert_eq!(config.database.url, "sqlite://cli.db");
+        assert_eq!(config.logging.level, "debug");
+        assert_eq!(config.server.name, "File");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing_explicit_path() {
+        let path = Path::new("/nonexistent/openfsd.toml");
+        let err = Config::load(Some(path), no_env(), &Overrides::default()).unwrap_err();
+        assert!(matches!(err, ConfigError::NotFound(_)));
+        assert_eq!(
+            err.to_string(),
+            "Configuration file /nonexistent/openfsd.toml does not exist"
+        );
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let mut config = Config::default();
+        config
+            .apply_env(env(&[
+                ("OPENFSD_SERVER__PORT", "7000"),
+                ("OPENFSD_SERVER__MOTD", "Welcome, Have fun"),
+                ("OPENFSD_AUTH__ALLOW_GUEST", "true"),
+                ("OPENFSD_AUTH__TOKEN_LOGIN", "token_then_password"),
+                ("OPENFSD_TLS__CERT_PATH", "/etc/openfsd/cert.pem"),
+                ("OPENFSD_BOOTSTRAP_CID", "1000000"),
+                ("DATABASE_URL", "sqlite://plain.db"),
+                ("OPENFSD_DATABASE__URL", "postgres://openfsd@db/openfsd"),
+                ("OPENFSD_SERVER__MOTTO", "Fly safe"),
+                ("PATH", "/usr/bin"),
+            ]))
+            .unwrap();
+
+        assert_eq!(config.server.port, 7000);
+        assert_eq!(config.server.motd, vec!["Welcome", "Have fun"]);
+        assert!(config.auth.allow_guest);
+        assert_eq!(config.auth.token_login, TokenLoginMode::TokenThenPassword);
+        assert_eq!(
+            config.tls.cert_path.as_deref(),
+            Some(Path::new("/etc/openfsd/cert.pem"))
+        );
+        assert_eq!(config.database.url, "postgres://openfsd@db/openfsd");
+        assert_eq!(config.unknown_keys, vec!["OPENFSD_SERVER__MOTTO"]);
+    }
+
+    #[test]
+    fn test_env_conversion_errors_name_the_variable() {
+        for (var, value) in [
+            ("OPENFSD_SERVER__PORT", "http"),
+            ("OPENFSD_SERVER__PORT", "70000"),
+            ("OPENFSD_AUTH__ALLOW_GUEST", "yes"),
+            ("OPENFSD_SERVER__NAME__FIRST", "x"),
+        ] {
+            let err = Config::default().apply_env(env(&[(var, value)]));
+            match err {
+                Err(ConfigError::Env { var: name, .. }) => assert_eq!(name, var),
+                other => panic!("{}={} gave {:?}", var, value, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_env_file_variant() {
+        let path = std::env::temp_dir().join(format!("openfsd-secret-{}", std::process::id()));
+        fs::write(&path, "postgres://openfsd:s3cret@db/openfsd\n").unwrap();
+
+        let mut config = Config::default();
+        config
+            .apply_env(env(&[(
+                "OPENFSD_DATABASE__URL_FILE",
+                path.to_str().unwrap(),
+            )]))
+            .unwrap();
+        assert_eq!(config.database.url, "postgres://openfsd:s3cret@db/openfsd");
+        fs::remove_file(&path).unwrap();
+
+        let err = config
+            .apply_env(env(&[(
+                "OPENFSD_DATABASE__URL_FILE",
+                "/nonexistent/secret",
+            )]))
+            .unwrap_err();
+        assert!(err.to_string().contains("OPENFSD_DATABASE__URL_FILE"));
+    }
+}
@@ -0,0 +1,379 @@
+use crate::config::{Config, ConfigError};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::path::Path;
+
+/// Source of the configuration types, read for their fields and doc comments
+/// so the generated files follow every change to the schema
+const CONFIG_SOURCE: &str = include_str!("config.rs");
+
+/// Type the schema starts from
+const ROOT: &str = "Config";
+
+/// A fully commented configuration file with every key set to its default
+///
+/// Keys without a default, such as `logging.file`, are listed commented out.
+pub fn template() -> String {
+    let schema = Schema::parse(CONFIG_SOURCE);
+    let defaults = defaults();
+    let mut out = String::from(
+        "# OpenFSD configuration\n\
+         #\n\
+         # Every key is listed with its default value. Only [server] is\n\
+         # required; remove anything you don't want to change.\n",
+    );
+    schema.visit(ROOT, "", Some(&defaults), &mut |entry| match entry {
+        Entry::Section { path, field } => {
+            out.push('\n');
+            write_comment(&mut out, &field.docs);
+            let _ = writeln!(out, "[{}]", path);
+        }
+        Entry::Key {
+            field,
+            value,
+            first,
+            ..
+        } => {
+            if !first {
+                out.push('\n');
+            }
+            write_comment(&mut out, &field.docs);
+            let _ = match value {
+                Some(value) => writeln!(out, "{} = {}", field.name, value),
+                None => writeln!(
+                    out,
+                    "# {} = <{}>",
+                    field.name,
+                    schema.describe_type(&field.ty)
+                ),
+            };
+        }
+    });
+    out
+}
+
+/// Every configuration key with its type, default and description
+pub fn reference() -> String {
+    let schema = Schema::parse(CONFIG_SOURCE);
+    let defaults = defaults();
+    let mut out = String::new();
+    schema.visit(ROOT, "", Some(&defaults), &mut |entry| {
+        let Entry::Key {
+            path, field, value, ..
+        } = entry
+        else {
+            return;
+        };
+        let ty = schema.describe_type(&field.ty);
+        let _ = match value {
+            Some(value) => writeln!(out, "{}: {} = {}", path, ty, value),
+            None => writeln!(out, "{}: {} (unset)", path, ty),
+        };
+        for line in &field.docs {
+            let _ = writeln!(out, "    {}", line);
+        }
+    });
+    out
+}
+
+/// Write [`template`] to `path`, which must not exist yet unless `force` is set
+pub fn write_template(path: &Path, force: bool) -> Result<(), ConfigError> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = options.open(path).map_err(|source| match source.kind() {
+        std::io::ErrorKind::AlreadyExists => ConfigError::Exists(path.to_path_buf()),
+        _ => ConfigError::Write {
+            path: path.to_path_buf(),
+            source,
+        },
+    })?;
+    file.write_all(template().as_bytes())
+        .map_err(|source| ConfigError::Write {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
+fn defaults() -> toml::Table {
+    match toml::Value::try_from(Config::default()) {
+        Ok(toml::Value::Table(table)) => table,
+        _ => toml::Table::new(),
+    }
+}
+
+fn write_comment(out: &mut String, docs: &[String]) {
+    for line in docs {
+        let _ = match line.is_empty() {
+            true => writeln!(out, "#"),
+            false => writeln!(out, "# {}", line),
+        };
+    }
+}
+
+/// A field of a configuration struct
+#[derive(Debug)]
+struct Field {
+    name: String,
+    ty: String,
+    docs: Vec<String>,
+}
+
+enum Entry<'a> {
+    /// A nested struct, written as a `[path]` table
+    Section { path: String, field: &'a Field },
+    /// A value; `first` is set for the first key of its table
+    Key {
+        path: String,
+        field: &'a Field,
+        value: Option<&'a toml::Value>,
+        first: bool,
+    },
+}
+
+/// Structs and enums of the configuration, as declared in the source
+#[derive(Debug, Default)]
+struct Schema {
+    structs: HashMap<String, Vec<Field>>,
+    /// Variants as they are spelled in TOML
+    enums: HashMap<String, Vec<String>>,
+}
+
+impl Schema {
+    /// Read the declarations from Rust source
+    ///
+    /// Only handles what the configuration module uses: one field or variant
+    /// per line, `#[serde(skip)]` fields and `rename_all` on enums.
+    fn parse(source: &str) -> Self {
+        enum Item {
+            Struct(String),
+            Enum(String),
+        }
+
+        let mut schema = Schema::default();
+        let mut current = None;
+        let mut docs = Vec::new();
+        let mut skip = false;
+        let mut rename_all = None;
+        for line in source.lines() {
+            let trimmed = line.trim();
+            if trimmed == "#[cfg(test)]" {
+                break;
+            }
+            if let Some(doc) = trimmed.strip_prefix("///") {
+                docs.push(doc.strip_prefix(' ').unwrap_or(doc).to_string());
+                continue;
+            }
+            if trimmed.starts_with("#[") {
+                skip |= trimmed == "#[serde(skip)]";
+                if let Some(case) = trimmed
+                    .strip_prefix("#[serde(rename_all = \"")
+                    .and_then(|rest| rest.strip_suffix("\")]"))
+                {
+                    rename_all = Some(case.to_string());
+                }
+                continue;
+            }
+
+            if let Some(name) = declared_name(trimmed, "pub struct ") {
+                schema.structs.insert(name.clone(), Vec::new());
+                current = Some(Item::Struct(name));
+            } else if let Some(name) = declared_name(trimmed, "pub enum ") {
+                schema.enums.insert(name.clone(), Vec::new());
+                current = Some(Item::Enum(name));
+            } else if line == "}" {
+                current = None;
+                rename_all = None;
+            } else {
+                match &current {
+                    Some(Item::Struct(name)) if !skip => {
+                        let field = trimmed
+                            .strip_prefix("pub ")
+                            .and_then(|field| field.strip_suffix(','))
+                            .and_then(|field| field.split_once(": "));
+                        if let Some((field, ty)) = field {
+                            schema.structs.entry(name.clone()).or_default().push(Field {
+                                name: field.to_string(),
+                                ty: ty.to_string(),
+                                docs: std::mem::take(&mut docs),
+                            });
+                        }
+                    }
+                    Some(Item::Enum(name)) => {
+                        let variant = trimmed.strip_suffix(',').unwrap_or(trimmed);
+                        if !variant.is_empty() && variant.chars().all(char::is_alphanumeric) {
+                            let variant = rename(variant, rename_all.as_deref());
+                            schema.enums.entry(name.clone()).or_default().push(variant);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            docs.clear();
+            skip = false;
+        }
+        schema
+    }
+
+    /// Call `f` for every key and nested table of `struct_name`, keys first
+    /// as TOML puts everything after a table header into that table
+    fn visit<'a>(
+        &'a self,
+        struct_name: &str,
+        path: &str,
+        values: Option<&'a toml::Table>,
+        f: &mut dyn FnMut(Entry<'a>),
+    ) {
+        let Some(fields) = self.structs.get(struct_name) else {
+            return;
+        };
+        let join = |name: &str| match path {
+            "" => name.to_string(),
+            _ => format!("{}.{}", path, name),
+        };
+        let (tables, keys): (Vec<_>, Vec<_>) = fields
+            .iter()
+            .partition(|field| self.structs.contains_key(&field.ty));
+
+        for (i, field) in keys.into_iter().enumerate() {
+            f(Entry::Key {
+                path: join(&field.name),
+                field,
+                value: values.and_then(|values| values.get(&field.name)),
+                first: i == 0,
+            });
+        }
+        for field in tables {
+            let path = join(&field.name);
+            f(Entry::Section {
+                path: path.clone(),
+                field,
+            });
+            let values = values
+                .and_then(|values| values.get(&field.name))
+                .and_then(toml::Value::as_table);
+            self.visit(&field.ty, &path, values, f);
+        }
+    }
+
+    /// Type of a key as written in TOML
+    fn describe_type(&self, ty: &str) -> String {
+        if let Some(inner) = generic_argument(ty, "Option") {
+            return self.describe_type(inner);
+        }
+        if let Some(inner) = generic_argument(ty, "Vec") {
+            return format!("array of {}", self.describe_type(inner));
+        }
+        if let Some(inner) = generic_argument(ty, "BTreeMap") {
+            let value = inner.split_once(", ").map_or(inner, |(_, value)| value);
+            return format!("table of {}", self.describe_type(value));
+        }
+        match ty {
+            "String" => "string".to_string(),
+            "PathBuf" => "path".to_string(),
+            "bool" => "boolean".to_string(),
+            "u8" | "u16" | "u32" | "u64" | "usize" => "integer".to_string(),
+            _ => match self.enums.get(ty) {
+                Some(variants) => {
+                    let variants: Vec<String> =
+                        variants.iter().map(|v| format!("\"{}\"", v)).collect();
+                    format!("one of {}", variants.join(", "))
+                }
+                None => ty.to_string(),
+            },
+        }
+    }
+}
+
+/// `Name` from `pub struct Name {`
+fn declared_name(line: &str, keyword: &str) -> Option<String> {
+    let name = line.strip_prefix(keyword)?.strip_suffix(" {")?;
+    Some(name.to_string())
+}
+
+/// `T` from `Wrapper<T>`
+fn generic_argument<'a>(ty: &'a str, wrapper: &str) -> Option<&'a str> {
+    ty.strip_prefix(wrapper)?
+        .strip_prefix('<')?
+        .strip_suffix('>')
+}
+
+/// Spell a variant the way `#[serde(rename_all = ...)]` does
+fn rename(variant: &str, rename_all: Option<&str>) -> String {
+    match rename_all {
+        Some("lowercase") => variant.to_lowercase(),
+        Some("snake_case") => {
+            let mut renamed = String::new();
+            for (i, c) in variant.chars().enumerate() {
+                if c.is_uppercase() && i > 0 {
+                    renamed.push('_');
+                }
+                renamed.push(c.to_ascii_lowercase());
+            }
+            renamed
+        }
+        _ => variant.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("openfsd-{}-{}.toml", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_template_parses_to_defaults() {
+        let path = temp_path("init");
+        write_template(&path, false).unwrap();
+        let config = Config::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert!(config.unknown_keys.is_empty());
+        assert_eq!(
+            toml::Value::try_from(&config).unwrap(),
+            toml::Value::Table(defaults())
+        );
+    }
+
+    #[test]
+    fn test_template_is_commented() {
+        let template = template();
+        assert!(template.contains("# Log levels and the optional log file\n[logging]\n"));
+        assert!(template.contains("# Clearance delivery\ndel = 20\n"));
+        assert!(template.contains("# Also write the log to this file\n# file = <path>\n"));
+
+        let reference = reference();
+        assert!(reference.contains("visibility.atc.twr: integer = 50\n    Tower\n"));
+        assert!(reference.contains(
+            "auth.token_login: one of \"prefix\", \"token_then_password\" = \"prefix\"\n"
+        ));
+        // Runtime state is not configuration
+        assert!(!reference.contains("unknown_keys"));
+    }
+
+    #[test]
+    fn test_init_does_not_overwrite() {
+        let path = temp_path("existing");
+        std::fs::write(&path, "[server]\n").unwrap();
+        let refused = write_template(&path, false);
+        let kept = std::fs::read_to_string(&path).unwrap();
+        let forced = write_template(&path, true);
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(refused, Err(ConfigError::Exists(_))));
+        assert_eq!(kept, "[server]\n");
+        assert!(forced.is_ok());
+        assert_eq!(written, template());
+    }
+}
@@ -0,0 +1,625 @@
+//! Status feed in the VATSIM JSON v3 datafeed schema
+//!
+//! Map sites and traffic tools written against VATSIM's
+//! `vatsim-data.json` can read this server without changes, so the field
+//! names and types below follow the real feed exactly. Values the server
+//! never learns, such as pilots' altimeter settings or a flight plan's
+//! revision, are zero, and `prefiles` is always empty because prefiled plans
+//! aren't part of the connected clients. Pilots carry five fields of ours,
+//! `aircraft_short`, `airline`, `livery`, `on_ground` and `eta`, which feed
+//! readers ignore.
+
+use crate::airports::Airports;
+use crate::client::{format_frequency, Client, ClientType, Facility};
+use crate::flight_plan;
+use crate::flight_progress::Eta;
+use crate::server::ServerConfig;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+/// Schema version announced in `general`
+pub const VERSION: u32 = 3;
+
+/// Frequency VATSIM lists for observers and controllers without one
+const NO_FREQUENCY: &str = "199.998";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DataFeed {
+    pub general: General,
+    pub pilots: Vec<Pilot>,
+    pub controllers: Vec<Controller>,
+    pub atis: Vec<Atis>,
+    pub servers: Vec<Server>,
+    pub prefiles: Vec<Prefile>,
+    pub facilities: Vec<Reference>,
+    pub ratings: Vec<Reference>,
+    pub pilot_ratings: Vec<NamedReference>,
+    pub military_ratings: Vec<NamedReference>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct General {
+    pub version: u32,
+    /// Minutes until the feed is worth fetching again
+    pub reload: u64,
+    /// `update_timestamp` as YYYYMMDDHHMMSS
+    pub update: String,
+    pub update_timestamp: DateTime<Utc>,
+    pub connected_clients: usize,
+    pub unique_users: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pilot {
+    pub cid: u64,
+    pub name: String,
+    pub callsign: String,
+    pub server: String,
+    pub pilot_rating: i32,
+    pub military_rating: i32,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: i32,
+    pub groundspeed: u32,
+    pub transponder: String,
+    pub heading: u32,
+    pub qnh_i_hg: f64,
+    pub qnh_mb: i32,
+    pub flight_plan: Option<FlightPlan>,
+    pub logon_time: DateTime<Utc>,
+    pub last_updated: DateTime<Utc>,
+    /// Type designator from the flight plan, or from the pilot's `#SB`
+    /// reply when none is filed
+    #[serde(default)]
+    pub aircraft_short: Option<String>,
+    /// From the pilot's `#SB` reply
+    #[serde(default)]
+    pub airline: Option<String>,
+    #[serde(default)]
+    pub livery: Option<String>,
+    #[serde(default)]
+    pub on_ground: Option<bool>,
+    /// Expected arrival, from the flight plan or the progress since
+    #[serde(default)]
+    pub eta: Option<Eta>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlightPlan {
+    pub flight_rules: String,
+    pub aircraft: String,
+    pub aircraft_faa: String,
+    /// Bare type designator, e.g. "B77W"
+    pub aircraft_short: String,
+    pub departure: String,
+    pub arrival: String,
+    pub alternate: String,
+    pub cruise_tas: String,
+    pub altitude: String,
+    pub deptime: String,
+    /// HHMM
+    pub enroute_time: String,
+    /// HHMM
+    pub fuel_time: String,
+    pub remarks: String,
+    pub route: String,
+    pub revision_id: u32,
+    pub assigned_transponder: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Controller {
+    pub cid: u64,
+    pub name: String,
+    pub callsign: String,
+    /// MHz with three decimals, e.g. "118.300"
+    pub frequency: String,
+    pub facility: u8,
+    pub rating: i32,
+    pub server: String,
+    pub visual_range: u32,
+    pub text_atis: Option<Vec<String>>,
+    pub last_updated: DateTime<Utc>,
+    pub logon_time: DateTime<Utc>,
+}
+
+/// An `_ATIS` station: a controller entry with the current letter
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Atis {
+    #[serde(flatten)]
+    pub controller: Controller,
+    pub atis_code: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Server {
+    pub ident: String,
+    pub hostname_or_ip: String,
+    pub location: String,
+    pub name: String,
+    pub clients_connection_allowed: i32,
+    pub client_connections_allowed: bool,
+    pub is_sweatbox: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Prefile {
+    pub cid: u64,
+    pub name: String,
+    pub callsign: String,
+    pub flight_plan: FlightPlan,
+    pub last_updated: DateTime<Utc>,
+}
+
+/// An entry of the `facilities` and `ratings` lookup tables
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reference {
+    pub id: i32,
+    pub short: String,
+    pub long: String,
+}
+
+/// An entry of the `pilot_ratings` and `military_ratings` lookup tables
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NamedReference {
+    pub id: i32,
+    pub short_name: String,
+    pub long_name: String,
+}
+
+/// The feed for the logged-in clients, sorted by callsign
+pub fn build(
+    clients: &HashMap<SocketAddr, Client>,
+    config: &ServerConfig,
+    airports: &Airports,
+    now: DateTime<Utc>,
+) -> DataFeed {
+    let mut listed: Vec<&Client> = clients
+        .values()
+        .filter(|client| client.is_active() && client.callsign.is_some())
+        .collect();
+    listed.sort_by(|a, b| a.callsign.cmp(&b.callsign));
+
+    let mut pilots = Vec::new();
+    let mut controllers = Vec::new();
+    let mut atis = Vec::new();
+    for client in &listed {
+        if client.client_type == Some(ClientType::Pilot) {
+            pilots.push(pilot(client, &config.server_name, airports, now));
+        } else if client
+            .callsign
+            .as_deref()
+            .is_some_and(|callsign| callsign.ends_with("_ATIS"))
+        {
+            atis.push(Atis {
+                controller: controller(client, &config.server_name, now),
+                atis_code: None,
+            });
+        } else {
+            controllers.push(controller(client, &config.server_name, now));
+        }
+    }
+
+    let unique_users: HashSet<&str> = listed
+        .iter()
+        .filter_map(|client| client.network_id.as_deref())
+        .collect();
+
+    DataFeed {
+        general: General {
+            version: VERSION,
+            reload: config.status.cache_secs.div_ceil(60).max(1),
+            update: now.format("%Y%m%d%H%M%S").to_string(),
+            update_timestamp: now,
+            connected_clients: listed.len(),
+            unique_users: unique_users.len(),
+        },
+        pilots,
+        controllers,
+        atis,
+        servers: vec![Server {
+            ident: config.server_name.clone(),
+            hostname_or_ip: config.hostname.clone(),
+            location: config.location.clone(),
+            name: config.server_name.clone(),
+            clients_connection_allowed: 1,
+            client_connections_allowed: true,
+            is_sweatbox: false,
+        }],
+        prefiles: Vec::new(),
+        facilities: facilities(),
+        ratings: ratings(),
+        pilot_ratings: pilot_ratings(),
+        military_ratings: military_ratings(),
+    }
+}
+
+fn cid(client: &Client) -> u64 {
+    client
+        .network_id
+        .as_deref()
+        .and_then(|id| id.parse().ok())
+        .unwrap_or_default()
+}
+
+fn pilot(client: &Client, server: &str, airports: &Airports, now: DateTime<Utc>) -> Pilot {
+    Pilot {
+        cid: cid(client),
+        name: client.real_name.clone().unwrap_or_default(),
+        callsign: client.callsign().unwrap_or_default().to_string(),
+        server: server.to_string(),
+        pilot_rating: client.rating.unwrap_or_default(),
+        military_rating: 0,
+        latitude: client.latitude.unwrap_or_default(),
+        longitude: client.longitude.unwrap_or_default(),
+        altitude: client.altitude.unwrap_or_default(),
+        groundspeed: client.groundspeed.unwrap_or_default(),
+        transponder: client.squawk.clone().unwrap_or_default(),
+        heading: client.heading.unwrap_or_default(),
+        qnh_i_hg: 0.0,
+        qnh_mb: 0,
+        flight_plan: client
+            .flight_plan
+            .as_ref()
+            .map(|plan| flight_plan(plan, client.assigned_squawk.as_deref())),
+        logon_time: client.logon_time.unwrap_or(now),
+        last_updated: now,
+        aircraft_short: client.aircraft_type().map(str::to_string),
+        airline: client
+            .plane_info
+            .as_ref()
+            .and_then(|info| info.airline.clone()),
+        livery: client
+            .plane_info
+            .as_ref()
+            .and_then(|info| info.livery.clone()),
+        on_ground: client.on_ground,
+        eta: client.eta(airports, now),
+    }
+}
+
+/// The plan as filed; clients send one aircraft field, which stands in for
+/// both the ICAO and the FAA form
+fn flight_plan(plan: &flight_plan::FlightPlan, assigned_squawk: Option<&str>) -> FlightPlan {
+    let hhmm = |hours: &str, minutes: &str| {
+        let number = |value: &str| value.trim().parse::<u32>().unwrap_or_default();
+        format!("{:02}{:02}", number(hours), number(minutes))
+    };
+    FlightPlan {
+        flight_rules: plan.flight_rules.clone(),
+        aircraft: plan.aircraft.clone(),
+        aircraft_faa: plan.aircraft.clone(),
+        aircraft_short: plan.aircraft_type().to_string(),
+        departure: plan.departure.clone(),
+        arrival: plan.destination.clone(),
+        alternate: plan.alternate.clone(),
+        cruise_tas: plan.cruise_speed.clone(),
+        altitude: plan.altitude.clone(),
+        deptime: plan.departure_time.clone(),
+        enroute_time: hhmm(&plan.hours_enroute, &plan.minutes_enroute),
+        fuel_time: hhmm(&plan.hours_fuel, &plan.minutes_fuel),
+        remarks: plan.remarks.clone(),
+        route: plan.route.clone(),
+        revision_id: 0,
+        assigned_transponder: assigned_squawk.unwrap_or("0000").to_string(),
+    }
+}
+
+fn controller(client: &Client, server: &str, now: DateTime<Utc>) -> Controller {
+    let is_observer = client.client_type == Some(ClientType::Observer);
+    Controller {
+        cid: cid(client),
+        name: client.real_name.clone().unwrap_or_default(),
+        callsign: client.callsign().unwrap_or_default().to_string(),
+        frequency: match client.frequency {
+            Some(frequency) if !is_observer => format_frequency(frequency),
+            _ => NO_FREQUENCY.to_string(),
+        },
+        facility: match client.facility {
+            Some(facility) if !is_observer => facility as u8,
+            _ => Facility::Observer as u8,
+        },
+        rating: client.rating.unwrap_or_default(),
+        server: server.to_string(),
+        visual_range: client.declared_range_nm.unwrap_or_default(),
+        text_atis: (!client.atis.is_empty()).then(|| client.atis.clone()),
+        last_updated: client.atis_updated_at.unwrap_or(now),
+        logon_time: client.logon_time.unwrap_or(now),
+    }
+}
+
+fn reference(id: i32, short: &str, long: &str) -> Reference {
+    Reference {
+        id,
+        short: short.to_string(),
+        long: long.to_string(),
+    }
+}
+
+fn named_reference(id: i32, short_name: &str, long_name: &str) -> NamedReference {
+    NamedReference {
+        id,
+        short_name: short_name.to_string(),
+        long_name: long_name.to_string(),
+    }
+}
+
+fn facilities() -> Vec<Reference> {
+    vec![
+        reference(0, "OBS", "Observer"),
+        reference(1, "FSS", "Flight Service Station"),
+        reference(2, "DEL", "Clearance Delivery"),
+        reference(3, "GND", "Ground"),
+        reference(4, "TWR", "Tower"),
+        reference(5, "APP", "Approach/Departure"),
+        reference(6, "CTR", "Enroute"),
+    ]
+}
+
+fn ratings() -> Vec<Reference> {
+    vec![
+        reference(-1, "INAC", "Inactive"),
+        reference(0, "SUS", "Suspended"),
+        reference(1, "OBS", "Observer"),
+        reference(2, "S1", "Tower Trainee"),
+        reference(3, "S2", "Tower Controller"),
+        reference(4, "S3", "Senior Student"),
+        reference(5, "C1", "Enroute Controller"),
+        reference(6, "C2", "Controller 2 (not in use)"),
+        reference(7, "C3", "Senior Controller"),
+        reference(8, "I1", "Instructor"),
+        reference(9, "I2", "Instructor 2 (not in use)"),
+        reference(10, "I3", "Senior Instructor"),
+        reference(11, "SUP", "Supervisor"),
+        reference(12, "ADM", "Administrator"),
+    ]
+}
+
+fn pilot_ratings() -> Vec<NamedReference> {
+    vec![
+        named_reference(0, "NEW", "Basic Member"),
+        named_reference(1, "PPL", "Private Pilot License"),
+        named_reference(3, "IR", "Instrument Rating"),
+        named_reference(7, "CMEL", "Commercial Multi-Engine License"),
+        named_reference(15, "ATPL", "Airline Transport Pilot License"),
+        named_reference(31, "FI", "Flight Instructor"),
+        named_reference(63, "FE", "Flight Examiner"),
+    ]
+}
+
+fn military_ratings() -> Vec<NamedReference> {
+    vec![
+        named_reference(0, "M0", "No Military Rating"),
+        named_reference(1, "M1", "Military Pilot License"),
+        named_reference(3, "M2", "Military Instrument Rating"),
+        named_reference(7, "M3", "Military Multi-Engine Rating"),
+        named_reference(15, "M4", "Military Mission Ready Pilot"),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airports::Airport;
+    use crate::flight_progress::EtaBasis;
+    use crate::packet::Packet;
+    use chrono::TimeZone;
+    use serde_json::Value;
+
+    /// Trimmed, anonymised copy of VATSIM's own feed
+    const VATSIM_SAMPLE: &str = include_str!("../tests/fixtures/vatsim-data-v3.json");
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 6, 1, hour, minute, 0).unwrap()
+    }
+
+    fn client(port: u16, callsign: &str, client_type: ClientType) -> Client {
+        let mut client = Client::active_for_tests(([127, 0, 0, 1], port), callsign, client_type);
+        client.network_id = Some(format!("{}", 1_000_000 + u32::from(port)));
+        client.logon_time = Some(at(10, 0));
+        client
+    }
+
+    fn clients() -> HashMap<SocketAddr, Client> {
+        let mut pilot = client(1, "CCA1501", ClientType::Pilot);
+        pilot.real_name = Some("Li Wei ZBAA".to_string());
+        pilot.latitude = Some(40.08);
+        pilot.longitude = Some(116.58);
+        pilot.altitude = Some(35000);
+        pilot.groundspeed = Some(452);
+        pilot.heading = Some(184);
+        pilot.on_ground = Some(false);
+        pilot.squawk = Some("4521".to_string());
+        pilot.assigned_squawk = Some("4521".to_string());
+        pilot.flight_plan = flight_plan::FlightPlan::from_packet(
+            &Packet::parse(
+                "$FPCCA1501:*A:I:H/B744/L:490:ZBAA:1130:1135:FL350:ZSPD:1:55:3:30:ZSHC:/v/:CDY W40 DOGAR",
+            )
+            .unwrap(),
+        );
+
+        let mut approach = client(2, "ZSPD_APP", ClientType::Atc);
+        approach.rating = Some(5);
+        approach.frequency = Some(25100);
+        approach.facility = Some(Facility::Approach);
+        approach.declared_range_nm = Some(150);
+
+        let mut atis = client(3, "ZSPD_ATIS", ClientType::Atc);
+        atis.frequency = Some(27000);
+        atis.facility = Some(Facility::Tower);
+        atis.atis = vec!["Pudong information A".to_string()];
+        atis.atis_updated_at = Some(at(10, 5));
+
+        let mut observer = client(4, "ZSHA_OBS", ClientType::Observer);
+        // The same person on a second connection
+        observer.network_id = approach.network_id.clone();
+
+        [pilot, approach, atis, observer]
+            .into_iter()
+            .map(|client| (client.addr, client))
+            .collect()
+    }
+
+    fn feed() -> DataFeed {
+        let config = ServerConfig {
+            server_name: "OPENFSD".to_string(),
+            hostname: "fsd.example.com".to_string(),
+            ..ServerConfig::default()
+        };
+        build(&clients(), &config, &Airports::default(), at(12, 0))
+    }
+
+    #[test]
+    fn test_deserializes_vatsim_sample() {
+        let sample: DataFeed = serde_json::from_str(VATSIM_SAMPLE).unwrap();
+        assert_eq!(sample.general.version, VERSION);
+        assert_eq!(sample.pilots.len(), 2);
+
+        let plan = sample.pilots[0].flight_plan.as_ref().unwrap();
+        assert_eq!(plan.aircraft_short, "B77W");
+        assert_eq!(plan.enroute_time, "0745");
+        assert!(sample.pilots[1].flight_plan.is_none());
+
+        assert_eq!(sample.controllers[0].frequency, "129.425");
+        assert_eq!(sample.controllers[1].text_atis, None);
+        assert_eq!(sample.atis[0].controller.callsign, "EGLL_ATIS");
+        assert_eq!(sample.atis[0].atis_code.as_deref(), Some("K"));
+        assert!(sample.servers[1].is_sweatbox);
+        assert_eq!(sample.prefiles[0].flight_plan.departure, "EDDF");
+        assert_eq!(sample.facilities, facilities());
+        assert_eq!(sample.ratings, ratings());
+    }
+
+    /// Every object in the built feed has exactly the keys of its
+    /// counterpart in VATSIM's, besides the pilots' aircraft fields of ours
+    #[test]
+    fn test_field_names_match_vatsim() {
+        fn keys(value: &Value) -> Vec<&String> {
+            let ours = ["aircraft_short", "airline", "livery", "on_ground", "eta"];
+            let mut keys: Vec<&String> = value
+                .as_object()
+                .unwrap()
+                .keys()
+                .filter(|key| !ours.contains(&key.as_str()))
+                .collect();
+            keys.sort();
+            keys
+        }
+
+        let sample: Value = serde_json::from_str(VATSIM_SAMPLE).unwrap();
+        let built = serde_json::to_value(feed()).unwrap();
+        for path in [
+            "",
+            "/general",
+            "/pilots/0",
+            "/pilots/0/flight_plan",
+            "/controllers/0",
+            "/atis/0",
+            "/servers/0",
+            "/facilities/0",
+            "/pilot_ratings/0",
+        ] {
+            assert_eq!(
+                keys(built.pointer(path).unwrap()),
+                keys(sample.pointer(path).unwrap()),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        let feed = feed();
+        assert_eq!(feed.general.update, "20250601120000");
+        assert_eq!(feed.general.connected_clients, 4);
+        assert_eq!(feed.general.unique_users, 3);
+
+        let pilot = &feed.pilots[0];
+        assert_eq!((pilot.cid, pilot.callsign.as_str()), (1000001, "CCA1501"));
+        assert_eq!(pilot.logon_time, at(10, 0));
+        assert_eq!((pilot.heading, pilot.groundspeed), (184, 452));
+        assert_eq!(pilot.on_ground, Some(false));
+        // Filed to leave at 1130 for 1:55, and late
+        let eta = pilot.eta.unwrap();
+        assert_eq!((eta.time, eta.basis), (at(13, 55), EtaBasis::Planned));
+        let plan = pilot.flight_plan.as_ref().unwrap();
+        assert_eq!(plan.aircraft_short, "B744");
+        assert_eq!(plan.arrival, "ZSPD");
+        assert_eq!(
+            (plan.enroute_time.as_str(), plan.fuel_time.as_str()),
+            ("0155", "0330")
+        );
+        assert_eq!(plan.assigned_transponder, "4521");
+
+        let callsigns: Vec<&str> = feed
+            .controllers
+            .iter()
+            .map(|c| c.callsign.as_str())
+            .collect();
+        assert_eq!(callsigns, ["ZSHA_OBS", "ZSPD_APP"]);
+        assert_eq!(feed.controllers[0].frequency, NO_FREQUENCY);
+        assert_eq!(feed.controllers[0].facility, 0);
+        assert_eq!(feed.controllers[1].frequency, "125.100");
+        assert_eq!(feed.controllers[1].facility, 5);
+        assert_eq!(feed.controllers[1].text_atis, None);
+
+        assert_eq!(feed.atis[0].controller.callsign, "ZSPD_ATIS");
+        assert_eq!(
+            feed.atis[0].controller.text_atis.as_deref(),
+            Some(&["Pudong information A".to_string()][..])
+        );
+        assert_eq!(feed.servers[0].hostname_or_ip, "fsd.example.com");
+    }
+
+    #[test]
+    fn test_aircraft_from_plane_info() {
+        let mut clients = clients();
+        let info = crate::plane_info::PlaneInfo::from_packet(
+            &Packet::parse("#SBCCA1501:AFR44:PI:GEN:EQUIPMENT=A21N:AIRLINE=CCA:LIVERY=B-30EE")
+                .unwrap(),
+        );
+        let pilot = clients
+            .values_mut()
+            .find(|c| c.client_type == Some(ClientType::Pilot));
+        pilot.unwrap().plane_info = info;
+        let config = ServerConfig::default();
+        let airports = Airports::default();
+
+        // The filed plan wins over what the client says
+        let pilot = &build(&clients, &config, &airports, at(12, 0)).pilots[0];
+        assert_eq!(pilot.aircraft_short.as_deref(), Some("B744"));
+        assert_eq!(pilot.airline.as_deref(), Some("CCA"));
+        assert_eq!(pilot.livery.as_deref(), Some("B-30EE"));
+
+        for client in clients.values_mut() {
+            client.flight_plan = None;
+        }
+        let pilot = &build(&clients, &config, &airports, at(12, 0)).pilots[0];
+        assert_eq!(pilot.aircraft_short.as_deref(), Some("A21N"));
+    }
+
+    #[test]
+    fn test_eta_from_destination() {
+        let mut clients = clients();
+        for client in clients.values_mut() {
+            client.progress.update(client.groundspeed, at(12, 0));
+        }
+        let airports = Airports::from_iter([Airport {
+            icao: "ZSPD".to_string(),
+            name: "Shanghai Pudong International Airport".to_string(),
+            latitude: 31.1434,
+            longitude: 121.8052,
+            elevation_ft: Some(13),
+        }]);
+        let config = ServerConfig::default();
+
+        // About 583nm left at 452kt
+        let pilot = &build(&clients, &config, &airports, at(12, 0)).pilots[0];
+        let eta = pilot.eta.unwrap();
+        assert_eq!(eta.basis, EtaBasis::Actual);
+        assert_eq!(eta.time.format("%H%M").to_string(), "1318");
+    }
+}
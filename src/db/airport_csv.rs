@@ -0,0 +1,184 @@
+use crate::db::entities::airport;
+use crate::db::service;
+use crate::db::user_csv::RowError;
+use sea_orm::*;
+use std::collections::HashSet;
+use std::io;
+use thiserror::Error;
+
+/// Rows written per insert statement
+const BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Missing column: {0}")]
+    MissingColumn(&'static str),
+    #[error("Database error: {0}")]
+    Database(#[from] DbErr),
+}
+
+/// Outcome of an import
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// Rows without an ICAO code, such as private strips, and closed airports
+    pub skipped: usize,
+    pub errors: Vec<RowError>,
+}
+
+/// Positions of the known columns in the header
+///
+/// Both the OurAirports names (`ident`, `latitude_deg`, ...) and plain ones
+/// (`icao`, `latitude`, ...) are understood.
+struct Header {
+    icao: Vec<usize>,
+    name: usize,
+    latitude: usize,
+    longitude: usize,
+    elevation: Option<usize>,
+    kind: Option<usize>,
+}
+
+impl Header {
+    fn parse(record: &csv::StringRecord) -> Result<Self, ImportError> {
+        let find = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| record.iter().position(|column| column == *name))
+        };
+        // OurAirports keeps the ICAO code in `icao_code` when the ident is
+        // something else
+        let icao: Vec<usize> = ["icao_code", "icao", "ident"]
+            .iter()
+            .filter_map(|name| find(&[name]))
+            .collect();
+        if icao.is_empty() {
+            return Err(ImportError::MissingColumn("icao or ident"));
+        }
+        Ok(Header {
+            icao,
+            name: find(&["name"]).ok_or(ImportError::MissingColumn("name"))?,
+            latitude: find(&["latitude_deg", "latitude", "lat"])
+                .ok_or(ImportError::MissingColumn("latitude"))?,
+            longitude: find(&["longitude_deg", "longitude", "lon"])
+                .ok_or(ImportError::MissingColumn("longitude"))?,
+            elevation: find(&["elevation_ft", "elevation"]),
+            kind: find(&["type"]),
+        })
+    }
+
+    /// The airport in a record, `Ok(None)` for one to skip, or the message
+    /// for the report
+    fn row(&self, record: &csv::StringRecord) -> Result<Option<airport::Model>, String> {
+        let get = |index: usize| record.get(index).unwrap_or_default();
+
+        if self.kind.is_some_and(|kind| get(kind) == "closed") {
+            return Ok(None);
+        }
+        let Some(icao) = self
+            .icao
+            .iter()
+            .map(|index| get(*index).to_ascii_uppercase())
+            .find(|code| is_icao(code))
+        else {
+            return Ok(None);
+        };
+
+        let coordinate = |index: usize, column: &str, max: f64| {
+            get(index)
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.abs() <= max)
+                .ok_or_else(|| format!("{} {:?} of {} is invalid", column, get(index), icao))
+        };
+        let latitude = coordinate(self.latitude, "latitude", 90.0)?;
+        let longitude = coordinate(self.longitude, "longitude", 180.0)?;
+        let elevation_ft = match self.elevation.map(get).unwrap_or_default() {
+            "" => None,
+            value => Some(
+                value
+                    .parse::<f64>()
+                    .map_err(|_| format!("elevation {:?} of {} is invalid", value, icao))?
+                    .round() as i32,
+            ),
+        };
+        Ok(Some(airport::Model {
+            name: get(self.name).to_string(),
+            icao,
+            latitude,
+            longitude,
+            elevation_ft,
+        }))
+    }
+}
+
+/// Four letters and digits, starting with a letter
+fn is_icao(code: &str) -> bool {
+    code.len() == 4
+        && code.starts_with(|c: char| c.is_ascii_uppercase())
+        && code
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+}
+
+/// Import airports from CSV, such as OurAirports' `airports.csv`
+///
+/// The file replaces the airports stored before, in one transaction along
+/// with an audit entry. Rows without a four-character ICAO code and closed
+/// airports are skipped; rows with unreadable coordinates or elevations are
+/// reported and left out, as is any repeat of a code. `progress` is called
+/// with the number of rows written so far and the total.
+pub async fn import_airports<R: io::Read>(
+    db: &DatabaseConnection,
+    reader: R,
+    actor: &str,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<ImportReport, ImportError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(reader);
+    let header = Header::parse(reader.headers()?)?;
+
+    let mut report = ImportReport::default();
+    let mut airports = Vec::new();
+    let mut seen = HashSet::new();
+    for (i, record) in reader.records().enumerate() {
+        let line = i as u64 + 2;
+        let row = record
+            .map_err(|e| e.to_string())
+            .and_then(|record| header.row(&record));
+        match row {
+            Ok(Some(airport)) if !seen.insert(airport.icao.clone()) => {
+                report.errors.push(RowError {
+                    line,
+                    message: format!("{} appears more than once", airport.icao),
+                })
+            }
+            Ok(Some(airport)) => airports.push(airport),
+            Ok(None) => report.skipped += 1,
+            Err(message) => report.errors.push(RowError { line, message }),
+        }
+    }
+
+    let total = airports.len();
+    let txn = db.begin().await?;
+    airport::Entity::delete_many().exec(&txn).await?;
+    for (batch, airports) in airports.chunks(BATCH_SIZE).enumerate() {
+        let models = airports.iter().cloned().map(airport::ActiveModel::from);
+        airport::Entity::insert_many(models).exec(&txn).await?;
+        progress(batch * BATCH_SIZE + airports.len(), total);
+    }
+    report.imported = total;
+    let details = format!(
+        "{} imported, {} skipped, {} rejected",
+        report.imported,
+        report.skipped,
+        report.errors.len()
+    );
+    service::record_audit_event(&txn, actor, "airport.import", None, Some(&details)).await?;
+    txn.commit().await?;
+    Ok(report)
+}
@@ -0,0 +1,155 @@
+use crate::auth::password;
+use crate::db::entities::user;
+use crate::db::service;
+use rand::distributions::{Alphanumeric, DistString};
+use sea_orm::{DatabaseConnection, DbErr, EntityTrait, PaginatorTrait};
+
+/// ATC rating given to the bootstrap account (ADM, implies supervisor)
+const BOOTSTRAP_ATC_RATING: i32 = 12;
+
+/// Network ID used when OPENFSD_BOOTSTRAP_CID is not set
+const DEFAULT_BOOTSTRAP_CID: &str = "1000000";
+
+/// Credentials for the first-run account, usually read from the environment
+#[derive(Debug, Clone, Default)]
+pub struct BootstrapSettings {
+    pub network_id: Option<String>,
+    pub password: Option<String>,
+    pub real_name: Option<String>,
+}
+
+impl BootstrapSettings {
+    /// Read OPENFSD_BOOTSTRAP_CID, OPENFSD_BOOTSTRAP_PASSWORD and OPENFSD_BOOTSTRAP_NAME
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Self {
+            network_id: var("OPENFSD_BOOTSTRAP_CID"),
+            password: var("OPENFSD_BOOTSTRAP_PASSWORD"),
+            real_name: var("OPENFSD_BOOTSTRAP_NAME"),
+        }
+    }
+}
+
+/// The supervisor account created on first run
+#[derive(Debug, Clone)]
+pub struct BootstrapAccount {
+    pub user: user::Model,
+    /// Set when no password was configured and a random one was made up
+    pub generated_password: Option<String>,
+}
+
+/// Create a supervisor account if the users table is empty
+///
+/// Does nothing once any user exists, so it is safe to run on every startup.
+/// Without a configured password a random one is generated and logged once.
+pub async fn bootstrap_admin(
+    db: &DatabaseConnection,
+    settings: BootstrapSettings,
+) -> Result<Option<BootstrapAccount>, DbErr> {
+    if user::Entity::find().count(db).await? > 0 {
+        return Ok(None);
+    }
+
+    let network_id = settings
+        .network_id
+        .unwrap_or_else(|| DEFAULT_BOOTSTRAP_CID.to_string());
+    let real_name = settings
+        .real_name
+        .unwrap_or_else(|| "Administrator".to_string());
+    let (password, generated_password) = match settings.password {
+        Some(password) => (password, None),
+        None => {
+            let password = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+            (password.clone(), Some(password))
+        }
+    };
+
+    let password_hash = password::hash_password(&password)
+        .map_err(|e| DbErr::Custom(format!("failed to hash bootstrap password: {}", e)))?;
+    let user = service::create_user(
+        db,
+        network_id,
+        password_hash,
+        real_name,
+        BOOTSTRAP_ATC_RATING,
+        1,
+    )
+    .await?;
+
+    if generated_password.is_some() {
+        tracing::warn!(
+            "Created bootstrap supervisor {} with generated password: {} (shown only once, change it!)",
+            user.network_id,
+            password
+        );
+    } else {
+        tracing::info!("Created bootstrap supervisor {}", user.network_id);
+    }
+
+    Ok(Some(BootstrapAccount {
+        user,
+        generated_password,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthenticatedUser;
+    use crate::config::AuthConfig;
+
+    async fn login(db: &DatabaseConnection, network_id: &str, password: &str) -> bool {
+        matches!(
+            crate::auth::authenticate(
+                db,
+                network_id,
+                password,
+                "",
+                &AuthConfig::default(),
+                chrono::Utc::now()
+            )
+            .await,
+            Ok(AuthenticatedUser { .. })
+        )
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_from_settings() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let settings = BootstrapSettings {
+            network_id: Some("1234567".to_string()),
+            password: Some("bootstrap-password".to_string()),
+            real_name: Some("Ops Team".to_string()),
+        };
+
+        let account = bootstrap_admin(&db, settings.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.generated_password, None);
+        let user = account.user;
+        assert_eq!(user.network_id, "1234567");
+        assert_eq!(user.real_name, "Ops Team");
+        assert!(user.is_supervisor());
+        assert!(login(&db, "1234567", "bootstrap-password").await);
+
+        // Running again is a no-op
+        assert!(bootstrap_admin(&db, settings).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_generated_password() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+
+        let account = bootstrap_admin(&db, BootstrapSettings::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.user.network_id, DEFAULT_BOOTSTRAP_CID);
+        assert!(account.user.is_supervisor());
+
+        let generated = account.generated_password.expect("password generated");
+        assert!(login(&db, DEFAULT_BOOTSTRAP_CID, &generated).await);
+        assert!(!login(&db, DEFAULT_BOOTSTRAP_CID, "").await);
+    }
+}
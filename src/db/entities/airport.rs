@@ -0,0 +1,26 @@
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "airports")]
+pub struct Model {
+    /// ICAO code, e.g. "ZSPD"
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub icao: String,
+    pub name: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Feet above sea level, where the source lists it
+    pub elevation_ft: Option<i32>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    /// (latitude, longitude)
+    pub fn position(&self) -> (f64, f64) {
+        (self.latitude, self.longitude)
+    }
+}
@@ -0,0 +1,47 @@
+use crate::client::ClientType;
+use sea_orm::entity::prelude::*;
+
+/// Who an announcement can be sent to
+pub const TARGETS: [&str; 3] = ["all", "pilots", "atc"];
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "announcements")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub text: String,
+    /// One of [`TARGETS`]
+    pub target: String,
+    /// When it is next sent; moved on after each repeat
+    pub fire_at: DateTimeUtc,
+    /// Seconds between repeats, unset for a one-shot announcement
+    pub repeat_secs: Option<i64>,
+    /// When it was last sent
+    pub fired_at: Option<DateTimeUtc>,
+    /// A one-shot announcement was sent or skipped
+    pub done: bool,
+    pub created_by: String,
+    pub created_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    /// Whether a client of this type is one the announcement is for
+    pub fn is_for(&self, client_type: &ClientType) -> bool {
+        match self.target.as_str() {
+            "pilots" => *client_type == ClientType::Pilot,
+            "atc" => *client_type == ClientType::Atc,
+            _ => true,
+        }
+    }
+
+    pub fn repeat(&self) -> Option<chrono::Duration> {
+        self.repeat_secs
+            .filter(|&secs| secs > 0)
+            .map(chrono::Duration::seconds)
+    }
+}
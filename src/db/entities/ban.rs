@@ -0,0 +1,43 @@
+use ipnet::IpNet;
+use sea_orm::entity::prelude::*;
+use std::net::IpAddr;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "bans")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub network_id: Option<String>,
+    /// A single address or a range in CIDR notation
+    pub ip: Option<String>,
+    pub reason: String,
+    /// CID of the staff member, or the tool that added the ban
+    pub created_by: String,
+    pub created_at: DateTimeUtc,
+    /// Unset for permanent bans
+    pub expires_at: Option<DateTimeUtc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    pub fn is_active(&self, now: DateTimeUtc) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+
+    /// Whether `addr` is the banned address or falls in the banned range
+    pub fn covers_ip(&self, addr: IpAddr) -> bool {
+        let Some(ip) = self.ip.as_deref() else {
+            return false;
+        };
+        // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+        let addr = addr.to_canonical();
+        match ip.parse::<IpNet>() {
+            Ok(range) => range.contains(&addr),
+            Err(_) => ip.parse::<IpAddr>().is_ok_and(|ip| ip == addr),
+        }
+    }
+}
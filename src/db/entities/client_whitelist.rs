@@ -9,6 +9,8 @@ pub struct Model {
     pub client_id: String,
     pub client_name: String,
     pub enabled: bool,
+    pub min_version: Option<String>,
+    pub client_key: Option<String>,
     pub created_at: DateTimeUtc,
 }
 
@@ -0,0 +1,23 @@
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "flight_tracks")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    pub session_id: String,
+    pub callsign: String,
+    pub network_id: String,
+    pub recorded_at: DateTimeUtc,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: i32,
+    pub groundspeed: i32,
+    /// Aircraft type from the flight plan on file when the sample was taken
+    pub aircraft: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
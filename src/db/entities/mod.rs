@@ -1,5 +1,29 @@
+pub mod airport;
+pub mod announcement;
+pub mod audit_log;
+pub mod ban;
 pub mod client_whitelist;
+pub mod flight_track;
+pub mod login_token;
+pub mod position_snapshot;
+pub mod prefiled_flight_plan;
+pub mod server_message;
+pub mod session;
+pub mod stats_daily;
 pub mod user;
+pub mod user_note;
 
+pub use airport::Entity as Airport;
+pub use announcement::Entity as Announcement;
+pub use audit_log::Entity as AuditLog;
+pub use ban::Entity as Ban;
 pub use client_whitelist::Entity as ClientWhitelist;
+pub use flight_track::Entity as FlightTrack;
+pub use login_token::Entity as LoginToken;
+pub use position_snapshot::Entity as PositionSnapshot;
+pub use prefiled_flight_plan::Entity as PrefiledFlightPlan;
+pub use server_message::Entity as ServerMessage;
+pub use session::Entity as Session;
+pub use stats_daily::Entity as StatsDaily;
 pub use user::Entity as User;
+pub use user_note::Entity as UserNote;
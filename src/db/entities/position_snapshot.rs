@@ -0,0 +1,39 @@
+use crate::flight_plan::FlightPlan;
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "position_snapshots")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub network_id: String,
+    pub callsign: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: i32,
+    pub squawk: Option<String>,
+    pub assigned_squawk: Option<String>,
+    pub tracking_controller: Option<String>,
+    /// $FP fields joined with ':'
+    #[sea_orm(column_type = "Text", nullable)]
+    pub flight_plan: Option<String>,
+    pub updated_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    /// The flight plan that was on file when the snapshot was taken
+    pub fn flight_plan(&self) -> Option<FlightPlan> {
+        let fields: Vec<String> = self
+            .flight_plan
+            .as_deref()?
+            .split(':')
+            .map(String::from)
+            .collect();
+        FlightPlan::from_fields(&fields)
+    }
+}
@@ -0,0 +1,62 @@
+use crate::flight_plan::FlightPlan;
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "prefiled_flight_plans")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub network_id: String,
+    pub callsign: String,
+    pub flight_rules: String,
+    pub aircraft: String,
+    pub cruise_speed: String,
+    pub departure: String,
+    pub departure_time: String,
+    pub actual_departure_time: String,
+    pub altitude: String,
+    pub destination: String,
+    pub hours_enroute: String,
+    pub minutes_enroute: String,
+    pub hours_fuel: String,
+    pub minutes_fuel: String,
+    pub alternate: String,
+    #[sea_orm(column_type = "Text")]
+    pub remarks: String,
+    #[sea_orm(column_type = "Text")]
+    pub route: String,
+    pub expires_at: DateTimeUtc,
+    pub consumed_at: Option<DateTimeUtc>,
+    pub created_at: DateTimeUtc,
+    pub updated_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    /// The stored plan in the form used by live connections
+    pub fn flight_plan(&self) -> FlightPlan {
+        FlightPlan {
+            flight_rules: self.flight_rules.clone(),
+            aircraft: self.aircraft.clone(),
+            cruise_speed: self.cruise_speed.clone(),
+            departure: self.departure.clone(),
+            departure_time: self.departure_time.clone(),
+            actual_departure_time: self.actual_departure_time.clone(),
+            altitude: self.altitude.clone(),
+            destination: self.destination.clone(),
+            hours_enroute: self.hours_enroute.clone(),
+            minutes_enroute: self.minutes_enroute.clone(),
+            hours_fuel: self.hours_fuel.clone(),
+            minutes_fuel: self.minutes_fuel.clone(),
+            alternate: self.alternate.clone(),
+            remarks: self.remarks.clone(),
+            route: self.route.clone(),
+            route_elements: Vec::new(),
+        }
+        .with_route_elements()
+    }
+}
@@ -0,0 +1,34 @@
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "sessions")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    /// Matches `Client::session_id` and the session of recorded tracks
+    #[sea_orm(unique)]
+    pub session_id: String,
+    pub network_id: String,
+    pub callsign: String,
+    pub client_type: String,
+    pub address: String,
+    pub connected_at: DateTimeUtc,
+    /// The rest is filled in when the session ends
+    pub disconnected_at: Option<DateTimeUtc>,
+    /// E.g. "closed" or "kicked:admin"
+    pub disconnect_reason: Option<String>,
+    pub client_software: Option<String>,
+    /// Since the connection was accepted, which is before `connected_at`
+    pub duration_secs: Option<i64>,
+    pub packets_in: Option<i64>,
+    pub packets_out: Option<i64>,
+    pub bytes_in: Option<i64>,
+    pub bytes_out: Option<i64>,
+    pub position_updates: Option<i64>,
+    pub parse_errors: Option<i64>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
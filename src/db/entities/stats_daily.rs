@@ -0,0 +1,35 @@
+use crate::stats::DailyStats;
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "stats_daily")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    #[sea_orm(unique)]
+    pub date: Date,
+    pub peak_clients: i32,
+    pub unique_cids: i32,
+    pub connections: i64,
+    pub messages: i64,
+    pub flight_plans: i64,
+    pub updated_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    pub fn daily_stats(&self) -> DailyStats {
+        DailyStats {
+            date: self.date,
+            peak_clients: self.peak_clients,
+            unique_cids: self.unique_cids,
+            connections: self.connections,
+            messages: self.messages,
+            flight_plans: self.flight_plans,
+        }
+    }
+}
@@ -13,9 +13,34 @@ pub struct Model {
     pub pilot_rating: i32,
     pub created_at: DateTimeUtc,
     pub updated_at: DateTimeUtc,
+    pub last_login_at: Option<DateTimeUtc>,
+    /// Contact address, only set by imports so far
+    pub email: Option<String>,
+    /// Set when the account is soft-deleted
+    pub deleted_at: Option<DateTimeUtc>,
+    /// Set while the account is disabled; it is kept but can't log in
+    pub disabled_at: Option<DateTimeUtc>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {}
 
 impl ActiveModelBehavior for ActiveModel {}
+
+/// Lowest ATC rating with supervisor privileges (SUP)
+pub const SUPERVISOR_RATING: i32 = 11;
+
+impl Model {
+    /// Supervisors (SUP) and administrators (ADM) may moderate the network
+    pub fn is_supervisor(&self) -> bool {
+        self.atc_rating >= SUPERVISOR_RATING
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled_at.is_some()
+    }
+}
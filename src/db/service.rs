@@ -1,24 +1,138 @@
-use crate::db::entities::{client_whitelist, user};
+use crate::client::Client;
+use crate::db::entities::{
+    announcement, audit_log, ban, client_whitelist, flight_track, login_token, position_snapshot,
+    prefiled_flight_plan, server_message, session, stats_daily, user, user_note,
+};
+use crate::flight_plan::FlightPlan;
+use crate::stats::DailyStats;
+use crate::tracks::TrackSample;
+use sea_orm::sea_query::Expr;
 use sea_orm::*;
+use std::net::IpAddr;
+use std::time::Duration;
 
-/// Check if a client ID is whitelisted
-pub async fn is_client_whitelisted(
+/// Pause before retrying a login record after a transient error
+const LOGIN_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Find an enabled whitelist entry for a client ID
+pub async fn find_whitelisted_client(
     db: &DatabaseConnection,
     client_id: &str,
-) -> Result<bool, DbErr> {
-    let result = client_whitelist::Entity::find()
+) -> Result<Option<client_whitelist::Model>, DbErr> {
+    client_whitelist::Entity::find()
         .filter(client_whitelist::Column::ClientId.eq(client_id))
         .filter(client_whitelist::Column::Enabled.eq(true))
         .one(db)
-        .await?;
+        .await
+}
 
-    Ok(result.is_some())
+/// Find a whitelist entry whether or not it is enabled
+pub async fn find_whitelist_entry(
+    db: &DatabaseConnection,
+    client_id: &str,
+) -> Result<Option<client_whitelist::Model>, DbErr> {
+    client_whitelist::Entity::find()
+        .filter(client_whitelist::Column::ClientId.eq(client_id))
+        .one(db)
+        .await
 }
 
-/// Find user by network ID
+/// Look up the auth challenge key of an enabled whitelisted client
+pub async fn find_client_key(
+    db: &DatabaseConnection,
+    client_id: &str,
+) -> Result<Option<String>, DbErr> {
+    Ok(find_whitelisted_client(db, client_id)
+        .await?
+        .and_then(|entry| entry.client_key))
+}
+
+/// One page of a listing plus the total number of matches
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: u64,
+    /// Zero-based page number
+    pub page: u64,
+    pub per_page: u64,
+}
+
+impl<T> Page<T> {
+    pub fn page_count(&self) -> u64 {
+        self.total.div_ceil(self.per_page)
+    }
+}
+
+/// Fetch one page of a query that is already ordered by a unique key
+async fn fetch_page<E>(
+    db: &DatabaseConnection,
+    query: Select<E>,
+    page: u64,
+    per_page: u64,
+) -> Result<Page<E::Model>, DbErr>
+where
+    E: EntityTrait,
+    E::Model: Sync,
+{
+    let per_page = per_page.max(1);
+    let paginator = query.paginate(db, per_page);
+    let total = paginator.num_items().await?;
+    let items = paginator.fetch_page(page).await?;
+
+    Ok(Page {
+        items,
+        total,
+        page,
+        per_page,
+    })
+}
+
+/// Filters for whitelist listings; unset fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct WhitelistFilter {
+    pub client_id_prefix: Option<String>,
+    pub name_contains: Option<String>,
+    pub enabled: Option<bool>,
+}
+
+/// List whitelist entries by client ID
+pub async fn list_whitelist(
+    db: &DatabaseConnection,
+    filter: &WhitelistFilter,
+    page: u64,
+    per_page: u64,
+) -> Result<Page<client_whitelist::Model>, DbErr> {
+    let mut query = client_whitelist::Entity::find();
+    if let Some(prefix) = &filter.client_id_prefix {
+        query = query.filter(client_whitelist::Column::ClientId.starts_with(prefix));
+    }
+    if let Some(name) = &filter.name_contains {
+        query = query.filter(client_whitelist::Column::ClientName.contains(name));
+    }
+    if let Some(enabled) = filter.enabled {
+        query = query.filter(client_whitelist::Column::Enabled.eq(enabled));
+    }
+
+    let query = query.order_by_asc(client_whitelist::Column::ClientId);
+    fetch_page(db, query, page, per_page).await
+}
+
+/// Find user by network ID, ignoring soft-deleted accounts
 pub async fn find_user_by_network_id(
     db: &DatabaseConnection,
     network_id: &str,
+) -> Result<Option<user::Model>, DbErr> {
+    user::Entity::find()
+        .filter(user::Column::NetworkId.eq(network_id))
+        .filter(user::Column::DeletedAt.is_null())
+        .one(db)
+        .await
+}
+
+/// Find user by network ID, including soft-deleted accounts
+pub async fn find_user_including_deleted(
+    db: &DatabaseConnection,
+    network_id: &str,
 ) -> Result<Option<user::Model>, DbErr> {
     user::Entity::find()
         .filter(user::Column::NetworkId.eq(network_id))
@@ -26,6 +140,165 @@ pub async fn find_user_by_network_id(
         .await
 }
 
+/// Filters for user listings; unset fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct UserFilter {
+    pub network_id_prefix: Option<String>,
+    pub name_contains: Option<String>,
+    pub atc_rating: Option<i32>,
+    pub pilot_rating: Option<i32>,
+    /// `Some(true)` for active accounts only, `Some(false)` for soft-deleted ones
+    pub active: Option<bool>,
+}
+
+/// List users by network ID
+pub async fn list_users(
+    db: &DatabaseConnection,
+    filter: &UserFilter,
+    page: u64,
+    per_page: u64,
+) -> Result<Page<user::Model>, DbErr> {
+    let mut query = user::Entity::find();
+    if let Some(prefix) = &filter.network_id_prefix {
+        query = query.filter(user::Column::NetworkId.starts_with(prefix));
+    }
+    if let Some(name) = &filter.name_contains {
+        query = query.filter(user::Column::RealName.contains(name));
+    }
+    if let Some(rating) = filter.atc_rating {
+        query = query.filter(user::Column::AtcRating.eq(rating));
+    }
+    if let Some(rating) = filter.pilot_rating {
+        query = query.filter(user::Column::PilotRating.eq(rating));
+    }
+    match filter.active {
+        Some(true) => query = query.filter(user::Column::DeletedAt.is_null()),
+        Some(false) => query = query.filter(user::Column::DeletedAt.is_not_null()),
+        None => {}
+    }
+
+    // Network IDs are unique, so pages never overlap
+    let query = query.order_by_asc(user::Column::NetworkId);
+    fetch_page(db, query, page, per_page).await
+}
+
+/// Soft-delete a user, returning false if there was no active account
+pub async fn soft_delete_user(db: &DatabaseConnection, network_id: &str) -> Result<bool, DbErr> {
+    set_user_deleted_at(db, network_id, Some(chrono::Utc::now())).await
+}
+
+/// Restore a soft-deleted user, returning false if it wasn't deleted
+pub async fn restore_user(db: &DatabaseConnection, network_id: &str) -> Result<bool, DbErr> {
+    set_user_deleted_at(db, network_id, None).await
+}
+
+async fn set_user_deleted_at(
+    db: &DatabaseConnection,
+    network_id: &str,
+    deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<bool, DbErr> {
+    // Only flip the flag in one direction so the result says whether it changed
+    let currently_deleted = if deleted_at.is_some() {
+        user::Column::DeletedAt.is_null()
+    } else {
+        user::Column::DeletedAt.is_not_null()
+    };
+    let result = user::Entity::update_many()
+        .col_expr(user::Column::DeletedAt, Expr::value(deleted_at))
+        .col_expr(user::Column::UpdatedAt, Expr::value(chrono::Utc::now()))
+        .filter(user::Column::NetworkId.eq(network_id))
+        .filter(currently_deleted)
+        .exec(db)
+        .await?;
+    Ok(result.rows_affected > 0)
+}
+
+/// Replace a user's password hash and record who did it in the audit log
+///
+/// Returns false if the user doesn't exist or is deleted.
+pub async fn update_password(
+    db: &DatabaseConnection,
+    network_id: &str,
+    password_hash: String,
+    actor: &str,
+) -> Result<bool, DbErr> {
+    let txn = db.begin().await?;
+    let result = user::Entity::update_many()
+        .col_expr(user::Column::PasswordHash, Expr::value(password_hash))
+        .col_expr(user::Column::UpdatedAt, Expr::value(chrono::Utc::now()))
+        .filter(user::Column::NetworkId.eq(network_id))
+        .filter(user::Column::DeletedAt.is_null())
+        .exec(&txn)
+        .await?;
+    if result.rows_affected == 0 {
+        return Ok(false);
+    }
+    record_audit_event(&txn, actor, "user.passwd", Some(network_id), None).await?;
+    txn.commit().await?;
+    Ok(true)
+}
+
+/// Disable an account, returning false if it is unknown, deleted or
+/// already disabled
+pub async fn disable_user(db: &DatabaseConnection, network_id: &str) -> Result<bool, DbErr> {
+    set_user_disabled_at(db, network_id, Some(chrono::Utc::now())).await
+}
+
+/// Enable a disabled account, returning false if it wasn't disabled
+pub async fn enable_user(db: &DatabaseConnection, network_id: &str) -> Result<bool, DbErr> {
+    set_user_disabled_at(db, network_id, None).await
+}
+
+async fn set_user_disabled_at(
+    db: &DatabaseConnection,
+    network_id: &str,
+    disabled_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<bool, DbErr> {
+    let currently_disabled = if disabled_at.is_some() {
+        user::Column::DisabledAt.is_null()
+    } else {
+        user::Column::DisabledAt.is_not_null()
+    };
+    let result = user::Entity::update_many()
+        .col_expr(user::Column::DisabledAt, Expr::value(disabled_at))
+        .col_expr(user::Column::UpdatedAt, Expr::value(chrono::Utc::now()))
+        .filter(user::Column::NetworkId.eq(network_id))
+        .filter(user::Column::DeletedAt.is_null())
+        .filter(currently_disabled)
+        .exec(db)
+        .await?;
+    Ok(result.rows_affected > 0)
+}
+
+/// Give a soft-deleted CID a fresh account
+///
+/// The old row is undeleted and its password, name and ratings are reset,
+/// so rows referencing it stay valid. Returns `None` if the CID is not a
+/// soft-deleted account.
+pub async fn reactivate_user(
+    db: &DatabaseConnection,
+    network_id: &str,
+    password_hash: String,
+    real_name: String,
+    atc_rating: i32,
+    pilot_rating: i32,
+) -> Result<Option<user::Model>, DbErr> {
+    let user = match find_user_including_deleted(db, network_id).await? {
+        Some(user) if user.is_deleted() => user,
+        _ => return Ok(None),
+    };
+
+    let mut user: user::ActiveModel = user.into();
+    user.password_hash = Set(password_hash);
+    user.real_name = Set(real_name);
+    user.atc_rating = Set(atc_rating);
+    user.pilot_rating = Set(pilot_rating);
+    user.deleted_at = Set(None);
+    user.disabled_at = Set(None);
+    user.updated_at = Set(chrono::Utc::now());
+    user.update(db).await.map(Some)
+}
+
 /// Create a new user
 pub async fn create_user(
     db: &DatabaseConnection,
@@ -55,14 +328,1636 @@ pub async fn add_client_to_whitelist(
     db: &DatabaseConnection,
     client_id: String,
     client_name: String,
+    min_version: Option<String>,
+    client_key: Option<String>,
 ) -> Result<client_whitelist::Model, DbErr> {
     let whitelist_entry = client_whitelist::ActiveModel {
         client_id: Set(client_id),
         client_name: Set(client_name),
         enabled: Set(true),
+        min_version: Set(min_version),
+        client_key: Set(client_key),
         created_at: Set(chrono::Utc::now().into()),
         ..Default::default()
     };
 
     whitelist_entry.insert(db).await
 }
+
+/// Update the minimum accepted version of a whitelisted client
+pub async fn update_client_min_version(
+    db: &DatabaseConnection,
+    client_id: &str,
+    min_version: Option<String>,
+) -> Result<Option<client_whitelist::Model>, DbErr> {
+    let entry = client_whitelist::Entity::find()
+        .filter(client_whitelist::Column::ClientId.eq(client_id))
+        .one(db)
+        .await?;
+
+    match entry {
+        Some(entry) => {
+            let mut entry: client_whitelist::ActiveModel = entry.into();
+            entry.min_version = Set(min_version);
+            entry.update(db).await.map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Enable or disable a whitelisted client, returning false if it is missing
+/// or already in that state
+pub async fn set_client_enabled(
+    db: &DatabaseConnection,
+    client_id: &str,
+    enabled: bool,
+) -> Result<bool, DbErr> {
+    let result = client_whitelist::Entity::update_many()
+        .col_expr(client_whitelist::Column::Enabled, Expr::value(enabled))
+        .filter(client_whitelist::Column::ClientId.eq(client_id))
+        .filter(client_whitelist::Column::Enabled.eq(!enabled))
+        .exec(db)
+        .await?;
+    Ok(result.rows_affected > 0)
+}
+
+/// Remove a client from the whitelist, returning false if it wasn't listed
+pub async fn remove_client_from_whitelist(
+    db: &DatabaseConnection,
+    client_id: &str,
+) -> Result<bool, DbErr> {
+    let result = client_whitelist::Entity::delete_many()
+        .filter(client_whitelist::Column::ClientId.eq(client_id))
+        .exec(db)
+        .await?;
+    Ok(result.rows_affected > 0)
+}
+
+/// Store a login token hash for a network ID
+pub async fn create_login_token(
+    db: &DatabaseConnection,
+    token_hash: String,
+    network_id: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+) -> Result<login_token::Model, DbErr> {
+    let token = login_token::ActiveModel {
+        token_hash: Set(token_hash),
+        network_id: Set(network_id),
+        expires_at: Set(expires_at),
+        used_at: Set(None),
+        created_at: Set(chrono::Utc::now()),
+        ..Default::default()
+    };
+
+    token.insert(db).await
+}
+
+/// Consume an unused, unexpired login token, returning whether it was valid
+///
+/// The token is marked used in the same statement that checks it, so a token
+/// can never be consumed twice.
+pub async fn consume_login_token(
+    db: &DatabaseConnection,
+    token_hash: &str,
+    network_id: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<bool, DbErr> {
+    let result = login_token::Entity::update_many()
+        .col_expr(login_token::Column::UsedAt, Expr::value(now))
+        .filter(login_token::Column::TokenHash.eq(token_hash))
+        .filter(login_token::Column::NetworkId.eq(network_id))
+        .filter(login_token::Column::UsedAt.is_null())
+        .filter(login_token::Column::ExpiresAt.gt(now))
+        .exec(db)
+        .await?;
+
+    Ok(result.rows_affected == 1)
+}
+
+/// Store a prefiled flight plan, replacing any earlier prefile for the same CID and callsign
+pub async fn upsert_prefiled_flight_plan(
+    db: &DatabaseConnection,
+    network_id: &str,
+    callsign: &str,
+    plan: &FlightPlan,
+    expires_at: chrono::DateTime<chrono::Utc>,
+) -> Result<prefiled_flight_plan::Model, DbErr> {
+    let callsign = callsign.to_uppercase();
+    let existing = prefiled_flight_plan::Entity::find()
+        .filter(prefiled_flight_plan::Column::NetworkId.eq(network_id))
+        .filter(prefiled_flight_plan::Column::Callsign.eq(&callsign))
+        .one(db)
+        .await?;
+
+    let now = chrono::Utc::now();
+    let mut prefile: prefiled_flight_plan::ActiveModel = match existing {
+        Some(existing) => existing.into(),
+        None => prefiled_flight_plan::ActiveModel {
+            network_id: Set(network_id.to_string()),
+            callsign: Set(callsign),
+            created_at: Set(now),
+            ..Default::default()
+        },
+    };
+
+    prefile.flight_rules = Set(plan.flight_rules.clone());
+    prefile.aircraft = Set(plan.aircraft.clone());
+    prefile.cruise_speed = Set(plan.cruise_speed.clone());
+    prefile.departure = Set(plan.departure.clone());
+    prefile.departure_time = Set(plan.departure_time.clone());
+    prefile.actual_departure_time = Set(plan.actual_departure_time.clone());
+    prefile.altitude = Set(plan.altitude.clone());
+    prefile.destination = Set(plan.destination.clone());
+    prefile.hours_enroute = Set(plan.hours_enroute.clone());
+    prefile.minutes_enroute = Set(plan.minutes_enroute.clone());
+    prefile.hours_fuel = Set(plan.hours_fuel.clone());
+    prefile.minutes_fuel = Set(plan.minutes_fuel.clone());
+    prefile.alternate = Set(plan.alternate.clone());
+    prefile.remarks = Set(plan.remarks.clone());
+    prefile.route = Set(plan.route.clone());
+    prefile.expires_at = Set(expires_at);
+    prefile.consumed_at = Set(None);
+    prefile.updated_at = Set(now);
+
+    prefile.save(db).await?.try_into_model()
+}
+
+/// Find the unconsumed, unexpired prefile for a CID and callsign
+pub async fn find_prefiled_flight_plan(
+    db: &DatabaseConnection,
+    network_id: &str,
+    callsign: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<Option<prefiled_flight_plan::Model>, DbErr> {
+    prefiled_flight_plan::Entity::find()
+        .filter(prefiled_flight_plan::Column::NetworkId.eq(network_id))
+        .filter(prefiled_flight_plan::Column::Callsign.eq(callsign.to_uppercase()))
+        .filter(prefiled_flight_plan::Column::ConsumedAt.is_null())
+        .filter(prefiled_flight_plan::Column::ExpiresAt.gt(now))
+        .one(db)
+        .await
+}
+
+/// Mark a prefile as used, returning false if it was already consumed
+pub async fn consume_prefiled_flight_plan<C: ConnectionTrait>(
+    db: &C,
+    id: i32,
+) -> Result<bool, DbErr> {
+    let result = prefiled_flight_plan::Entity::update_many()
+        .col_expr(
+            prefiled_flight_plan::Column::ConsumedAt,
+            Expr::value(chrono::Utc::now()),
+        )
+        .filter(prefiled_flight_plan::Column::Id.eq(id))
+        .filter(prefiled_flight_plan::Column::ConsumedAt.is_null())
+        .exec(db)
+        .await?;
+
+    Ok(result.rows_affected == 1)
+}
+
+/// Remove a prefile, returning false if there was none with this id
+pub async fn delete_prefiled_flight_plan(db: &DatabaseConnection, id: i32) -> Result<bool, DbErr> {
+    let result = prefiled_flight_plan::Entity::delete_by_id(id)
+        .exec(db)
+        .await?;
+    Ok(result.rows_affected == 1)
+}
+
+/// Persist the last-known state of a pilot connection
+///
+/// Returns false without writing if the client has not logged in or has not
+/// reported a position yet.
+pub async fn save_position_snapshot(
+    db: &DatabaseConnection,
+    client: &Client,
+) -> Result<bool, DbErr> {
+    let (Some(network_id), Some(callsign), Some(latitude), Some(longitude)) = (
+        client.network_id.as_deref(),
+        client.callsign(),
+        client.latitude,
+        client.longitude,
+    ) else {
+        return Ok(false);
+    };
+
+    let existing = position_snapshot::Entity::find()
+        .filter(position_snapshot::Column::NetworkId.eq(network_id))
+        .filter(position_snapshot::Column::Callsign.eq(callsign))
+        .one(db)
+        .await?;
+
+    let mut snapshot: position_snapshot::ActiveModel = match existing {
+        Some(existing) => existing.into(),
+        None => position_snapshot::ActiveModel {
+            network_id: Set(network_id.to_string()),
+            callsign: Set(callsign.to_string()),
+            ..Default::default()
+        },
+    };
+
+    snapshot.latitude = Set(latitude);
+    snapshot.longitude = Set(longitude);
+    snapshot.altitude = Set(client.altitude.unwrap_or_default());
+    snapshot.squawk = Set(client.squawk.clone());
+    snapshot.assigned_squawk = Set(client.assigned_squawk.clone());
+    snapshot.tracking_controller = Set(client.tracking_controller.clone());
+    snapshot.flight_plan = Set(client
+        .flight_plan
+        .as_ref()
+        .map(|plan| plan.fields().join(":")));
+    snapshot.updated_at = Set(chrono::Utc::now());
+
+    snapshot.save(db).await?;
+    Ok(true)
+}
+
+/// Find a snapshot for a CID and callsign taken within `max_age`
+pub async fn find_position_snapshot(
+    db: &DatabaseConnection,
+    network_id: &str,
+    callsign: &str,
+    max_age: chrono::Duration,
+) -> Result<Option<position_snapshot::Model>, DbErr> {
+    position_snapshot::Entity::find()
+        .filter(position_snapshot::Column::NetworkId.eq(network_id))
+        .filter(position_snapshot::Column::Callsign.eq(callsign))
+        .filter(position_snapshot::Column::UpdatedAt.gt(chrono::Utc::now() - max_age))
+        .one(db)
+        .await
+}
+
+/// Forget the snapshot of a pilot that logged off cleanly
+pub async fn delete_position_snapshot<C: ConnectionTrait>(
+    db: &C,
+    network_id: &str,
+    callsign: &str,
+) -> Result<(), DbErr> {
+    position_snapshot::Entity::delete_many()
+        .filter(position_snapshot::Column::NetworkId.eq(network_id))
+        .filter(position_snapshot::Column::Callsign.eq(callsign))
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+/// Delete snapshots older than `max_age`, returning how many were removed
+pub async fn delete_stale_position_snapshots(
+    db: &DatabaseConnection,
+    max_age: chrono::Duration,
+) -> Result<u64, DbErr> {
+    let result = position_snapshot::Entity::delete_many()
+        .filter(position_snapshot::Column::UpdatedAt.lte(chrono::Utc::now() - max_age))
+        .exec(db)
+        .await?;
+    Ok(result.rows_affected)
+}
+
+/// Add flushed statistics to the row for their day
+pub async fn save_daily_stats(
+    db: &DatabaseConnection,
+    stats: &DailyStats,
+) -> Result<stats_daily::Model, DbErr> {
+    let existing = stats_daily::Entity::find()
+        .filter(stats_daily::Column::Date.eq(stats.date))
+        .one(db)
+        .await?;
+
+    let (stats, mut row): (DailyStats, stats_daily::ActiveModel) = match existing {
+        Some(existing) => (stats.merge(&existing.daily_stats()), existing.into()),
+        None => (
+            stats.clone(),
+            stats_daily::ActiveModel {
+                date: Set(stats.date),
+                ..Default::default()
+            },
+        ),
+    };
+
+    row.peak_clients = Set(stats.peak_clients);
+    row.unique_cids = Set(stats.unique_cids);
+    row.connections = Set(stats.connections);
+    row.messages = Set(stats.messages);
+    row.flight_plans = Set(stats.flight_plans);
+    row.updated_at = Set(chrono::Utc::now());
+
+    row.save(db).await?.try_into_model()
+}
+
+/// Daily statistics for the last `days` days, newest first
+pub async fn list_daily_stats(
+    db: &DatabaseConnection,
+    days: u32,
+) -> Result<Vec<stats_daily::Model>, DbErr> {
+    let since = chrono::Utc::now().date_naive() - chrono::Duration::days(i64::from(days));
+    stats_daily::Entity::find()
+        .filter(stats_daily::Column::Date.gt(since))
+        .order_by_desc(stats_daily::Column::Date)
+        .all(db)
+        .await
+}
+
+/// Append a batch of recorded track samples
+pub async fn insert_track_samples(
+    db: &DatabaseConnection,
+    samples: &[TrackSample],
+) -> Result<(), DbErr> {
+    if samples.is_empty() {
+        return Ok(());
+    }
+
+    let rows = samples.iter().map(|sample| flight_track::ActiveModel {
+        session_id: Set(sample.session_id.clone()),
+        callsign: Set(sample.callsign.clone()),
+        network_id: Set(sample.network_id.clone()),
+        recorded_at: Set(sample.recorded_at),
+        latitude: Set(sample.latitude),
+        longitude: Set(sample.longitude),
+        altitude: Set(sample.altitude),
+        groundspeed: Set(sample.groundspeed),
+        aircraft: Set(sample.aircraft.clone()),
+        ..Default::default()
+    });
+    flight_track::Entity::insert_many(rows).exec(db).await?;
+    Ok(())
+}
+
+/// Delete track samples recorded before `cutoff`, returning how many were removed
+pub async fn delete_track_samples_before(
+    db: &DatabaseConnection,
+    cutoff: chrono::DateTime<chrono::Utc>,
+) -> Result<u64, DbErr> {
+    let result = flight_track::Entity::delete_many()
+        .filter(flight_track::Column::RecordedAt.lt(cutoff))
+        .exec(db)
+        .await?;
+    Ok(result.rows_affected)
+}
+
+/// First and last sample of a recorded session plus the sample count
+pub async fn find_track_bounds(
+    db: &DatabaseConnection,
+    session_id: &str,
+) -> Result<Option<(flight_track::Model, flight_track::Model, u64)>, DbErr> {
+    let session =
+        || flight_track::Entity::find().filter(flight_track::Column::SessionId.eq(session_id));
+
+    let Some(first) = session()
+        .order_by_asc(flight_track::Column::Id)
+        .one(db)
+        .await?
+    else {
+        return Ok(None);
+    };
+    let last = session()
+        .order_by_desc(flight_track::Column::Id)
+        .one(db)
+        .await?
+        .unwrap_or_else(|| first.clone());
+    let count = session().count(db).await?;
+
+    Ok(Some((first, last, count)))
+}
+
+/// Latest aircraft type recorded for a session
+pub async fn find_track_aircraft(
+    db: &DatabaseConnection,
+    session_id: &str,
+) -> Result<Option<String>, DbErr> {
+    Ok(flight_track::Entity::find()
+        .filter(flight_track::Column::SessionId.eq(session_id))
+        .filter(flight_track::Column::Aircraft.is_not_null())
+        .order_by_desc(flight_track::Column::Id)
+        .one(db)
+        .await?
+        .and_then(|sample| sample.aircraft))
+}
+
+/// Up to `limit` samples of a session with an id above `after_id`, oldest first
+pub async fn list_track_samples_after(
+    db: &DatabaseConnection,
+    session_id: &str,
+    after_id: i64,
+    limit: u64,
+) -> Result<Vec<flight_track::Model>, DbErr> {
+    flight_track::Entity::find()
+        .filter(flight_track::Column::SessionId.eq(session_id))
+        .filter(flight_track::Column::Id.gt(after_id))
+        .order_by_asc(flight_track::Column::Id)
+        .limit(limit)
+        .all(db)
+        .await
+}
+
+/// Find an editable server message such as the MOTD
+pub async fn find_server_message(
+    db: &DatabaseConnection,
+    key: &str,
+) -> Result<Option<server_message::Model>, DbErr> {
+    server_message::Entity::find()
+        .filter(server_message::Column::Key.eq(key))
+        .one(db)
+        .await
+}
+
+/// Create or replace a server message
+pub async fn set_server_message(
+    db: &DatabaseConnection,
+    key: &str,
+    lines: &[String],
+    updated_by: Option<&str>,
+) -> Result<server_message::Model, DbErr> {
+    let mut message: server_message::ActiveModel = match find_server_message(db, key).await? {
+        Some(existing) => existing.into(),
+        None => server_message::ActiveModel {
+            key: Set(key.to_string()),
+            ..Default::default()
+        },
+    };
+
+    message.lines = Set(lines.join("\n"));
+    message.updated_by = Set(updated_by.map(str::to_string));
+    message.updated_at = Set(chrono::Utc::now());
+
+    message.save(db).await?.try_into_model()
+}
+
+/// Append an entry to the audit trail
+pub async fn record_audit_event<C: ConnectionTrait>(
+    db: &C,
+    actor: &str,
+    action: &str,
+    target: Option<&str>,
+    details: Option<&str>,
+) -> Result<(), DbErr> {
+    audit_log::ActiveModel {
+        actor: Set(actor.to_string()),
+        action: Set(action.to_string()),
+        target: Set(target.map(str::to_string)),
+        details: Set(details.map(str::to_string)),
+        created_at: Set(chrono::Utc::now()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+    Ok(())
+}
+
+/// Audit trail entries about a CID or callsign, newest first
+pub async fn list_audit_events(
+    db: &DatabaseConnection,
+    target: &str,
+) -> Result<Vec<audit_log::Model>, DbErr> {
+    audit_log::Entity::find()
+        .filter(audit_log::Column::Target.eq(target))
+        .order_by_desc(audit_log::Column::Id)
+        .all(db)
+        .await
+}
+
+/// Add a supervisor note to a user account
+///
+/// Text beyond `MAX_NOTE_LENGTH` characters is cut off. The note and its
+/// audit entry are written together. Returns `None` if the user doesn't exist.
+pub async fn add_user_note(
+    db: &DatabaseConnection,
+    network_id: &str,
+    author_network_id: &str,
+    text: &str,
+) -> Result<Option<user_note::Model>, DbErr> {
+    let Some(user) = find_user_by_network_id(db, network_id).await? else {
+        return Ok(None);
+    };
+    let text: String = text
+        .trim()
+        .chars()
+        .take(user_note::MAX_NOTE_LENGTH)
+        .collect();
+
+    let txn = db.begin().await?;
+    let note = user_note::ActiveModel {
+        user_id: Set(user.id),
+        author_network_id: Set(author_network_id.to_string()),
+        text: Set(text.clone()),
+        created_at: Set(chrono::Utc::now()),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    record_audit_event(
+        &txn,
+        author_network_id,
+        "note.add",
+        Some(network_id),
+        Some(&text),
+    )
+    .await?;
+    txn.commit().await?;
+
+    Ok(Some(note))
+}
+
+/// The most recent notes on a user account, newest first
+pub async fn list_user_notes(
+    db: &DatabaseConnection,
+    network_id: &str,
+    limit: u64,
+) -> Result<Vec<user_note::Model>, DbErr> {
+    user_note::Entity::find()
+        .inner_join(user::Entity)
+        .filter(user::Column::NetworkId.eq(network_id))
+        .order_by_desc(user_note::Column::Id)
+        .limit(limit)
+        .all(db)
+        .await
+}
+
+/// Filters for session listings; unset fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct SessionFilter {
+    pub network_id: Option<String>,
+    /// Sessions that started at or after this time
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Sessions that started before this time
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// List sessions, newest first
+pub async fn list_sessions(
+    db: &DatabaseConnection,
+    filter: &SessionFilter,
+    page: u64,
+    per_page: u64,
+) -> Result<Page<session::Model>, DbErr> {
+    let mut query = session::Entity::find();
+    if let Some(network_id) = &filter.network_id {
+        query = query.filter(session::Column::NetworkId.eq(network_id));
+    }
+    if let Some(from) = filter.from {
+        query = query.filter(session::Column::ConnectedAt.gte(from));
+    }
+    if let Some(to) = filter.to {
+        query = query.filter(session::Column::ConnectedAt.lt(to));
+    }
+
+    // The id breaks ties between sessions that started at the same time
+    let query = query
+        .order_by_desc(session::Column::ConnectedAt)
+        .order_by_desc(session::Column::Id);
+    fetch_page(db, query, page, per_page).await
+}
+
+/// Everything persisted after a successful login
+#[derive(Debug, Clone)]
+pub struct LoginRecord {
+    pub session_id: String,
+    pub network_id: String,
+    pub callsign: String,
+    pub client_type: String,
+    pub address: String,
+    pub logged_in_at: chrono::DateTime<chrono::Utc>,
+    /// Position snapshot the login resumes, used up with the login
+    pub snapshot_id: Option<i32>,
+    /// Prefiled flight plan the login activates, consumed with the login
+    pub prefile_id: Option<i32>,
+}
+
+/// What a login took over; false where another login got there first
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LoginClaims {
+    pub snapshot: bool,
+    pub prefile: bool,
+}
+
+/// Use up a position snapshot and consume a prefile for a login
+///
+/// Both are conditional writes, so of two logins racing for the same
+/// snapshot or prefile only one gets it.
+pub async fn claim_for_login<C: ConnectionTrait>(
+    db: &C,
+    snapshot_id: Option<i32>,
+    prefile_id: Option<i32>,
+) -> Result<LoginClaims, DbErr> {
+    let snapshot = match snapshot_id {
+        Some(id) => {
+            position_snapshot::Entity::delete_by_id(id)
+                .exec(db)
+                .await?
+                .rows_affected
+                == 1
+        }
+        None => false,
+    };
+    let prefile = match prefile_id {
+        Some(id) => consume_prefiled_flight_plan(db, id).await?,
+        None => false,
+    };
+    Ok(LoginClaims { snapshot, prefile })
+}
+
+/// Persist the bookkeeping of a login in a single transaction
+///
+/// Either all of last login time, snapshot and prefile claims and session
+/// row are stored, or none. Transient errors are retried once. Returns what
+/// the login claimed, which is only to be used once this has returned.
+pub async fn record_login(
+    db: &DatabaseConnection,
+    record: &LoginRecord,
+) -> Result<LoginClaims, DbErr> {
+    match record_login_once(db, record).await {
+        Err(e) if is_transient(&e) => {
+            tracing::warn!(
+                "Retrying login record for {} after transient error: {}",
+                record.callsign,
+                e
+            );
+            tokio::time::sleep(LOGIN_RETRY_DELAY).await;
+            record_login_once(db, record).await
+        }
+        result => result,
+    }
+}
+
+async fn record_login_once(
+    db: &DatabaseConnection,
+    record: &LoginRecord,
+) -> Result<LoginClaims, DbErr> {
+    let txn = db.begin().await?;
+
+    user::Entity::update_many()
+        .col_expr(user::Column::LastLoginAt, Expr::value(record.logged_in_at))
+        .filter(user::Column::NetworkId.eq(&record.network_id))
+        .exec(&txn)
+        .await?;
+
+    let claims = claim_for_login(&txn, record.snapshot_id, record.prefile_id).await?;
+
+    session::ActiveModel {
+        session_id: Set(record.session_id.clone()),
+        network_id: Set(record.network_id.clone()),
+        callsign: Set(record.callsign.clone()),
+        client_type: Set(record.client_type.clone()),
+        address: Set(record.address.clone()),
+        connected_at: Set(record.logged_in_at),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+
+    txn.commit().await?;
+    Ok(claims)
+}
+
+/// Everything persisted about a session when it ends
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionEnd {
+    pub session_id: String,
+    pub disconnected_at: chrono::DateTime<chrono::Utc>,
+    pub disconnect_reason: String,
+    pub client_software: Option<String>,
+    pub duration_secs: i64,
+    pub packets_in: i64,
+    pub packets_out: i64,
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+    pub position_updates: i64,
+    pub parse_errors: i64,
+}
+
+/// Close the row of a session; sessions that never logged in have none
+pub async fn record_session_end(db: &DatabaseConnection, end: &SessionEnd) -> Result<(), DbErr> {
+    session::Entity::update_many()
+        .set(session::ActiveModel {
+            disconnected_at: Set(Some(end.disconnected_at)),
+            disconnect_reason: Set(Some(end.disconnect_reason.clone())),
+            client_software: Set(end.client_software.clone()),
+            duration_secs: Set(Some(end.duration_secs)),
+            packets_in: Set(Some(end.packets_in)),
+            packets_out: Set(Some(end.packets_out)),
+            bytes_in: Set(Some(end.bytes_in)),
+            bytes_out: Set(Some(end.bytes_out)),
+            position_updates: Set(Some(end.position_updates)),
+            parse_errors: Set(Some(end.parse_errors)),
+            ..Default::default()
+        })
+        .filter(session::Column::SessionId.eq(&end.session_id))
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+/// Errors worth a retry: lost connections, pool timeouts, lock conflicts
+fn is_transient(err: &DbErr) -> bool {
+    match err {
+        DbErr::ConnectionAcquire(_) | DbErr::Conn(_) => true,
+        DbErr::Exec(e) | DbErr::Query(e) => {
+            let message = e.to_string().to_lowercase();
+            ["database is locked", "deadlock", "could not serialize"]
+                .iter()
+                .any(|pattern| message.contains(pattern))
+        }
+        _ => false,
+    }
+}
+
+/// A ban to add; at least one of `network_id` and `ip` should be set
+#[derive(Debug, Clone, Default)]
+pub struct NewBan {
+    pub network_id: Option<String>,
+    /// A single address or a CIDR range
+    pub ip: Option<String>,
+    pub reason: String,
+    /// Unset for a permanent ban
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Add a ban and its audit entry
+pub async fn add_ban(
+    db: &DatabaseConnection,
+    ban: NewBan,
+    actor: &str,
+) -> Result<ban::Model, DbErr> {
+    let target = ban.network_id.clone().or(ban.ip.clone());
+    let txn = db.begin().await?;
+    let ban = ban::ActiveModel {
+        network_id: Set(ban.network_id),
+        ip: Set(ban.ip),
+        reason: Set(ban.reason),
+        created_by: Set(actor.to_string()),
+        created_at: Set(chrono::Utc::now()),
+        expires_at: Set(ban.expires_at),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    record_audit_event(&txn, actor, "ban.add", target.as_deref(), Some(&ban.reason)).await?;
+    txn.commit().await?;
+    Ok(ban)
+}
+
+/// Lift a ban, returning false if there was none with this ID
+pub async fn remove_ban(db: &DatabaseConnection, id: i32, actor: &str) -> Result<bool, DbErr> {
+    let txn = db.begin().await?;
+    let Some(ban) = ban::Entity::find_by_id(id).one(&txn).await? else {
+        return Ok(false);
+    };
+    ban::Entity::delete_by_id(id).exec(&txn).await?;
+    let target = ban.network_id.or(ban.ip);
+    let details = format!("ban {}: {}", id, ban.reason);
+    record_audit_event(&txn, actor, "ban.remove", target.as_deref(), Some(&details)).await?;
+    txn.commit().await?;
+    Ok(true)
+}
+
+/// List bans, newest first, optionally leaving out expired ones
+pub async fn list_bans(
+    db: &DatabaseConnection,
+    active_only: bool,
+    page: u64,
+    per_page: u64,
+) -> Result<Page<ban::Model>, DbErr> {
+    let mut query = ban::Entity::find();
+    if active_only {
+        query = query.filter(active_ban(chrono::Utc::now()));
+    }
+    let query = query.order_by_desc(ban::Column::Id);
+    fetch_page(db, query, page, per_page).await
+}
+
+/// The ban keeping a CID or address out at `now`, if any
+pub async fn find_active_ban(
+    db: &DatabaseConnection,
+    network_id: &str,
+    addr: IpAddr,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<Option<ban::Model>, DbErr> {
+    // Ranges can't be matched in SQL portably, so address bans are checked here
+    let bans = ban::Entity::find()
+        .filter(active_ban(now))
+        .filter(
+            Condition::any()
+                .add(ban::Column::NetworkId.eq(network_id))
+                .add(ban::Column::Ip.is_not_null()),
+        )
+        .order_by_asc(ban::Column::Id)
+        .all(db)
+        .await?;
+    Ok(bans
+        .into_iter()
+        .find(|ban| ban.network_id.as_deref() == Some(network_id) || ban.covers_ip(addr)))
+}
+
+fn active_ban(now: chrono::DateTime<chrono::Utc>) -> Condition {
+    Condition::any()
+        .add(ban::Column::ExpiresAt.is_null())
+        .add(ban::Column::ExpiresAt.gt(now))
+}
+
+/// An announcement to schedule
+#[derive(Debug, Clone)]
+pub struct NewAnnouncement {
+    pub text: String,
+    /// One of [`announcement::TARGETS`]
+    pub target: String,
+    pub fire_at: chrono::DateTime<chrono::Utc>,
+    /// Unset to send it once
+    pub repeat: Option<chrono::Duration>,
+}
+
+/// Schedule an announcement and write its audit entry
+pub async fn add_announcement(
+    db: &DatabaseConnection,
+    announcement: NewAnnouncement,
+    actor: &str,
+) -> Result<announcement::Model, DbErr> {
+    let txn = db.begin().await?;
+    let announcement = announcement::ActiveModel {
+        text: Set(announcement.text),
+        target: Set(announcement.target),
+        fire_at: Set(announcement.fire_at),
+        repeat_secs: Set(announcement.repeat.map(|repeat| repeat.num_seconds())),
+        fired_at: Set(None),
+        done: Set(false),
+        created_by: Set(actor.to_string()),
+        created_at: Set(chrono::Utc::now()),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    record_audit_event(
+        &txn,
+        actor,
+        "announcement.add",
+        Some(&announcement.target),
+        Some(&announcement.text),
+    )
+    .await?;
+    txn.commit().await?;
+    Ok(announcement)
+}
+
+/// Delete an announcement, returning false if there was none with this ID
+pub async fn remove_announcement(
+    db: &DatabaseConnection,
+    id: i32,
+    actor: &str,
+) -> Result<bool, DbErr> {
+    let txn = db.begin().await?;
+    let Some(announcement) = announcement::Entity::find_by_id(id).one(&txn).await? else {
+        return Ok(false);
+    };
+    announcement::Entity::delete_by_id(id).exec(&txn).await?;
+    let details = format!("announcement {}: {}", id, announcement.text);
+    record_audit_event(
+        &txn,
+        actor,
+        "announcement.remove",
+        Some(&announcement.target),
+        Some(&details),
+    )
+    .await?;
+    txn.commit().await?;
+    Ok(true)
+}
+
+/// List announcements by when they are next sent, optionally leaving out
+/// one-shot ones already sent or skipped
+pub async fn list_announcements(
+    db: &DatabaseConnection,
+    pending_only: bool,
+    page: u64,
+    per_page: u64,
+) -> Result<Page<announcement::Model>, DbErr> {
+    let mut query = announcement::Entity::find();
+    if pending_only {
+        query = query.filter(announcement::Column::Done.eq(false));
+    }
+    let query = query
+        .order_by_asc(announcement::Column::FireAt)
+        .order_by_asc(announcement::Column::Id);
+    fetch_page(db, query, page, per_page).await
+}
+
+/// Announcements whose time has come by `now`, oldest first
+pub async fn find_due_announcements(
+    db: &DatabaseConnection,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<announcement::Model>, DbErr> {
+    announcement::Entity::find()
+        .filter(announcement::Column::Done.eq(false))
+        .filter(announcement::Column::FireAt.lte(now))
+        .order_by_asc(announcement::Column::FireAt)
+        .all(db)
+        .await
+}
+
+/// Record that an announcement came due: `fired_at` if it was sent, and
+/// `next_fire_at` for a repeat, or none for a one-shot that is done
+pub async fn finish_announcement(
+    db: &DatabaseConnection,
+    id: i32,
+    fired_at: Option<chrono::DateTime<chrono::Utc>>,
+    next_fire_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<(), DbErr> {
+    let mut update = announcement::Entity::update_many()
+        .col_expr(
+            announcement::Column::Done,
+            Expr::value(next_fire_at.is_none()),
+        )
+        .filter(announcement::Column::Id.eq(id));
+    if let Some(fired_at) = fired_at {
+        update = update.col_expr(announcement::Column::FiredAt, Expr::value(fired_at));
+    }
+    if let Some(next_fire_at) = next_fire_at {
+        update = update.col_expr(announcement::Column::FireAt, Expr::value(next_fire_at));
+    }
+    // Removed while it was being sent is fine
+    update.exec(db).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_find_client_key() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        add_client_to_whitelist(
+            &db,
+            "abcd".to_string(),
+            "Keyed Client".to_string(),
+            None,
+            Some("secret".to_string()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            find_client_key(&db, "abcd").await.unwrap(),
+            Some("secret".to_string())
+        );
+        // Seeded entries have no key, unknown clients have no entry
+        assert_eq!(find_client_key(&db, "69d7").await.unwrap(), None);
+        assert_eq!(find_client_key(&db, "ffff").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_prefiled_flight_plan_lifecycle() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let in_an_hour = chrono::Utc::now() + chrono::Duration::hours(1);
+        let mut plan = FlightPlan {
+            departure: "ZBAA".to_string(),
+            destination: "ZSPD".to_string(),
+            ..FlightPlan::default()
+        };
+
+        upsert_prefiled_flight_plan(&db, "1234567", "cca1501", &plan, in_an_hour)
+            .await
+            .unwrap();
+        plan.destination = "ZGGG".to_string();
+        let prefile = upsert_prefiled_flight_plan(&db, "1234567", "CCA1501", &plan, in_an_hour)
+            .await
+            .unwrap();
+        assert_eq!(prefile.callsign, "CCA1501");
+
+        let found = find_prefiled_flight_plan(&db, "1234567", "CCA1501", chrono::Utc::now())
+            .await
+            .unwrap()
+            .expect("prefile is active");
+        assert_eq!(found.id, prefile.id);
+        assert_eq!(found.flight_plan(), plan);
+        assert!(
+            find_prefiled_flight_plan(&db, "7654321", "CCA1501", chrono::Utc::now())
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        assert!(consume_prefiled_flight_plan(&db, found.id).await.unwrap());
+        assert!(!consume_prefiled_flight_plan(&db, found.id).await.unwrap());
+        assert!(
+            find_prefiled_flight_plan(&db, "1234567", "CCA1501", chrono::Utc::now())
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expired_prefile_ignored() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let an_hour_ago = chrono::Utc::now() - chrono::Duration::hours(1);
+        upsert_prefiled_flight_plan(
+            &db,
+            "1234567",
+            "CCA1501",
+            &FlightPlan::default(),
+            an_hour_ago,
+        )
+        .await
+        .unwrap();
+
+        assert!(
+            find_prefiled_flight_plan(&db, "1234567", "CCA1501", chrono::Utc::now())
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_position_snapshot_expiry() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let mut client = Client::new("127.0.0.1:50001".parse().unwrap());
+        client.network_id = Some("1234567".to_string());
+        client.callsign = Some("CCA1501".into());
+
+        // Nothing to save before the first position update
+        assert!(!save_position_snapshot(&db, &client).await.unwrap());
+
+        client.latitude = Some(31.14);
+        client.longitude = Some(121.8);
+        client.assigned_squawk = Some("4521".to_string());
+        assert!(save_position_snapshot(&db, &client).await.unwrap());
+
+        let ttl = chrono::Duration::minutes(15);
+        let snapshot = find_position_snapshot(&db, "1234567", "CCA1501", ttl)
+            .await
+            .unwrap()
+            .expect("fresh snapshot");
+        assert_eq!(snapshot.assigned_squawk.as_deref(), Some("4521"));
+        assert!(snapshot.flight_plan().is_none());
+
+        // Snapshots older than the TTL are ignored and cleaned up
+        assert!(
+            find_position_snapshot(&db, "1234567", "CCA1501", chrono::Duration::zero())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(
+            delete_stale_position_snapshots(&db, chrono::Duration::zero())
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_daily_stats_accumulate() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let flushed = DailyStats {
+            date: chrono::Utc::now().date_naive(),
+            peak_clients: 4,
+            unique_cids: 3,
+            connections: 5,
+            messages: 10,
+            flight_plans: 1,
+        };
+
+        save_daily_stats(&db, &flushed).await.unwrap();
+        let row = save_daily_stats(&db, &flushed).await.unwrap();
+        assert_eq!(row.peak_clients, 4);
+        assert_eq!(row.connections, 10);
+        assert_eq!(row.messages, 20);
+
+        let rows = list_daily_stats(&db, 30).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].flight_plans, 2);
+    }
+
+    #[tokio::test]
+    async fn test_update_password() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        create_user(
+            &db,
+            "1234567".to_string(),
+            "old".to_string(),
+            "Test User".to_string(),
+            1,
+            1,
+        )
+        .await
+        .unwrap();
+
+        assert!(update_password(&db, "1234567", "new".to_string(), "admin")
+            .await
+            .unwrap());
+        assert!(!update_password(&db, "7654321", "new".to_string(), "admin")
+            .await
+            .unwrap());
+        let user = find_user_by_network_id(&db, "1234567")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.password_hash, "new");
+
+        let events = list_audit_events(&db, "1234567").await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, "user.passwd");
+        assert_eq!(events[0].actor, "admin");
+    }
+
+    #[tokio::test]
+    async fn test_disable_user() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        create_user(
+            &db,
+            "1234567".to_string(),
+            "hash".to_string(),
+            "Test User".to_string(),
+            1,
+            1,
+        )
+        .await
+        .unwrap();
+
+        assert!(!disable_user(&db, "7654321").await.unwrap());
+        assert!(disable_user(&db, "1234567").await.unwrap());
+        assert!(!disable_user(&db, "1234567").await.unwrap());
+        // Disabled accounts are still listed and found
+        let user = find_user_by_network_id(&db, "1234567")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(user.is_disabled());
+
+        assert!(enable_user(&db, "1234567").await.unwrap());
+        assert!(!enable_user(&db, "1234567").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_active_bans() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let now = chrono::Utc::now();
+        let expired = add_ban(
+            &db,
+            NewBan {
+                network_id: Some("1234567".to_string()),
+                reason: "Expired".to_string(),
+                expires_at: Some(now - chrono::Duration::hours(1)),
+                ..Default::default()
+            },
+            "test",
+        )
+        .await
+        .unwrap();
+        let range = add_ban(
+            &db,
+            NewBan {
+                ip: Some("203.0.113.0/24".to_string()),
+                reason: "Range".to_string(),
+                expires_at: Some(now + chrono::Duration::days(7)),
+                ..Default::default()
+            },
+            "test",
+        )
+        .await
+        .unwrap();
+
+        let all = list_bans(&db, false, 0, 50).await.unwrap();
+        assert_eq!(all.total, 2);
+        let active = list_bans(&db, true, 0, 50).await.unwrap();
+        assert_eq!(active.items, vec![range.clone()]);
+
+        let inside: IpAddr = "203.0.113.7".parse().unwrap();
+        let outside: IpAddr = "198.51.100.7".parse().unwrap();
+        let mapped: IpAddr = "::ffff:203.0.113.7".parse().unwrap();
+        assert_eq!(
+            find_active_ban(&db, "7654321", inside, now).await.unwrap(),
+            Some(range.clone())
+        );
+        assert_eq!(
+            find_active_ban(&db, "7654321", mapped, now).await.unwrap(),
+            Some(range.clone())
+        );
+        // The CID ban has run out
+        assert!(find_active_ban(&db, "1234567", outside, now)
+            .await
+            .unwrap()
+            .is_none());
+
+        assert!(remove_ban(&db, range.id, "test").await.unwrap());
+        assert!(!remove_ban(&db, range.id, "test").await.unwrap());
+        assert!(find_active_ban(&db, "7654321", inside, now)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(remove_ban(&db, expired.id, "test").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_soft_deleted_cid_is_not_reused() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        create_user(
+            &db,
+            "1234567".to_string(),
+            "old".to_string(),
+            "Old Name".to_string(),
+            5,
+            3,
+        )
+        .await
+        .unwrap();
+        assert!(soft_delete_user(&db, "1234567").await.unwrap());
+        assert!(!soft_delete_user(&db, "1234567").await.unwrap());
+
+        assert!(find_user_by_network_id(&db, "1234567")
+            .await
+            .unwrap()
+            .is_none());
+        let active = UserFilter {
+            active: Some(true),
+            ..UserFilter::default()
+        };
+        assert_eq!(list_users(&db, &active, 0, 10).await.unwrap().total, 0);
+        assert_eq!(
+            list_users(&db, &UserFilter::default(), 0, 10)
+                .await
+                .unwrap()
+                .total,
+            1
+        );
+
+        // A plain insert can't take over the CID
+        assert!(create_user(
+            &db,
+            "1234567".to_string(),
+            "new".to_string(),
+            "New".to_string(),
+            1,
+            1
+        )
+        .await
+        .is_err());
+
+        let user = reactivate_user(
+            &db,
+            "1234567",
+            "new".to_string(),
+            "New Name".to_string(),
+            1,
+            1,
+        )
+        .await
+        .unwrap()
+        .expect("deleted account reactivated");
+        assert!(!user.is_deleted());
+        assert_eq!(user.password_hash, "new");
+        assert_eq!(user.atc_rating, 1);
+
+        // Active accounts are never reset
+        assert!(
+            reactivate_user(&db, "1234567", "x".to_string(), "X".to_string(), 1, 1)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    async fn login_fixture(db: &DatabaseConnection) -> LoginRecord {
+        create_user(
+            db,
+            "1234567".to_string(),
+            "hash".to_string(),
+            "Pilot".to_string(),
+            1,
+            1,
+        )
+        .await
+        .unwrap();
+
+        let mut pilot = Client::new("127.0.0.1:50001".parse().unwrap());
+        pilot.network_id = Some("1234567".to_string());
+        pilot.callsign = Some("CCA1501".into());
+        pilot.latitude = Some(31.14);
+        pilot.longitude = Some(121.8);
+        assert!(save_position_snapshot(db, &pilot).await.unwrap());
+        let snapshot = find_position_snapshot(db, "1234567", "CCA1501", chrono::Duration::hours(1))
+            .await
+            .unwrap()
+            .unwrap();
+        let prefile = upsert_prefiled_flight_plan(
+            db,
+            "1234567",
+            "CCA1501",
+            &FlightPlan::default(),
+            chrono::Utc::now() + chrono::Duration::hours(1),
+        )
+        .await
+        .unwrap();
+
+        LoginRecord {
+            session_id: pilot.session_id.clone(),
+            network_id: "1234567".to_string(),
+            callsign: "CCA1501".to_string(),
+            client_type: "pilot".to_string(),
+            address: "127.0.0.1".to_string(),
+            logged_in_at: chrono::Utc::now(),
+            snapshot_id: Some(snapshot.id),
+            prefile_id: Some(prefile.id),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_login() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let record = login_fixture(&db).await;
+
+        let claims = record_login(&db, &record).await.unwrap();
+        assert_eq!(
+            claims,
+            LoginClaims {
+                snapshot: true,
+                prefile: true
+            }
+        );
+
+        let user = find_user_by_network_id(&db, "1234567")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(user.last_login_at.is_some());
+        assert_eq!(session::Entity::find().count(&db).await.unwrap(), 1);
+        assert!(
+            find_position_snapshot(&db, "1234567", "CCA1501", chrono::Duration::hours(1))
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            find_prefiled_flight_plan(&db, "1234567", "CCA1501", chrono::Utc::now())
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_racing_logins_claim_once() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let record = login_fixture(&db).await;
+        let other = LoginRecord {
+            session_id: "other".to_string(),
+            ..record.clone()
+        };
+
+        let (first, second) = tokio::join!(record_login(&db, &record), record_login(&db, &other));
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert!(first.snapshot ^ second.snapshot);
+        assert!(first.prefile ^ second.prefile);
+        assert_eq!(session::Entity::find().count(&db).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_record_session_end() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let record = login_fixture(&db).await;
+        record_login(&db, &record).await.unwrap();
+
+        let end = SessionEnd {
+            session_id: record.session_id.clone(),
+            disconnected_at: chrono::Utc::now(),
+            disconnect_reason: "kicked:admin".to_string(),
+            client_software: Some("vPilot 3.0".to_string()),
+            duration_secs: 120,
+            packets_in: 40,
+            packets_out: 55,
+            bytes_in: 2_000,
+            bytes_out: 3_500,
+            position_updates: 24,
+            parse_errors: 1,
+        };
+        record_session_end(&db, &end).await.unwrap();
+        // Connections that never logged in have no row to close
+        let unknown = SessionEnd {
+            session_id: "unknown".to_string(),
+            ..end.clone()
+        };
+        record_session_end(&db, &unknown).await.unwrap();
+
+        let row = session::Entity::find().one(&db).await.unwrap().unwrap();
+        assert_eq!(row.disconnect_reason.as_deref(), Some("kicked:admin"));
+        assert_eq!(row.client_software.as_deref(), Some("vPilot 3.0"));
+        assert_eq!(row.duration_secs, Some(120));
+        assert_eq!(row.packets_in, Some(40));
+        assert_eq!(row.bytes_out, Some(3_500));
+        assert_eq!(row.position_updates, Some(24));
+        assert_eq!(row.parse_errors, Some(1));
+        assert!(row.disconnected_at.is_some());
+        assert_eq!(session::Entity::find().count(&db).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_record_login_rolls_back_on_failure() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let record = login_fixture(&db).await;
+
+        // A duplicate session id fails the insert after the user update and
+        // the claims have already run inside the transaction
+        session::ActiveModel {
+            session_id: Set(record.session_id.clone()),
+            network_id: Set("7654321".to_string()),
+            callsign: Set("CES2001".to_string()),
+            client_type: Set("pilot".to_string()),
+            address: Set("127.0.0.1".to_string()),
+            connected_at: Set(chrono::Utc::now()),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await
+        .unwrap();
+
+        assert!(record_login(&db, &record).await.is_err());
+
+        let user = find_user_by_network_id(&db, "1234567")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(user.last_login_at.is_none());
+        assert_eq!(session::Entity::find().count(&db).await.unwrap(), 1);
+        assert!(
+            find_position_snapshot(&db, "1234567", "CCA1501", chrono::Duration::hours(1))
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            find_prefiled_flight_plan(&db, "1234567", "CCA1501", chrono::Utc::now())
+                .await
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_long_notes_are_truncated() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        create_user(
+            &db,
+            "1234567".to_string(),
+            "hash".to_string(),
+            "Pilot".to_string(),
+            1,
+            1,
+        )
+        .await
+        .unwrap();
+
+        let long_text = "é".repeat(user_note::MAX_NOTE_LENGTH + 100);
+        let note = add_user_note(&db, "1234567", "1000000", &long_text)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(note.text.chars().count(), user_note::MAX_NOTE_LENGTH);
+
+        add_user_note(&db, "1234567", "1000000", "Warned for phraseology")
+            .await
+            .unwrap();
+        let notes = list_user_notes(&db, "1234567", 10).await.unwrap();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].text, "Warned for phraseology");
+
+        let audit = list_audit_events(&db, "1234567").await.unwrap();
+        assert_eq!(audit.len(), 2);
+        assert!(audit.iter().all(|event| event.action == "note.add"));
+
+        assert!(add_user_note(&db, "7654321", "1000000", "Unknown")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_user_pages_are_stable() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        for i in 0..7 {
+            create_user(
+                &db,
+                format!("12000{:02}", 6 - i),
+                "hash".to_string(),
+                format!("User {}", i),
+                1,
+                1,
+            )
+            .await
+            .unwrap();
+        }
+
+        let mut seen = Vec::new();
+        for page in 0..3 {
+            let result = list_users(&db, &UserFilter::default(), page, 3)
+                .await
+                .unwrap();
+            assert_eq!(result.total, 7);
+            assert_eq!(result.page_count(), 3);
+            seen.extend(result.items.into_iter().map(|user| user.network_id));
+        }
+
+        let mut sorted = seen.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(seen, sorted);
+        assert_eq!(seen.len(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_user_filters_compose() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        for (network_id, name, atc_rating) in [
+            ("1200001", "Alice Zhang", 5),
+            ("1200002", "Bob Zhang", 1),
+            ("1300001", "Carol Zhang", 5),
+            ("1200003", "Dave Li", 5),
+        ] {
+            create_user(
+                &db,
+                network_id.to_string(),
+                "hash".to_string(),
+                name.to_string(),
+                atc_rating,
+                1,
+            )
+            .await
+            .unwrap();
+        }
+        soft_delete_user(&db, "1200003").await.unwrap();
+
+        let filter = UserFilter {
+            network_id_prefix: Some("12".to_string()),
+            name_contains: Some("Zhang".to_string()),
+            atc_rating: Some(5),
+            ..UserFilter::default()
+        };
+        let result = list_users(&db, &filter, 0, 10).await.unwrap();
+        assert_eq!(result.total, 1);
+        assert_eq!(result.items[0].network_id, "1200001");
+
+        let deleted = UserFilter {
+            active: Some(false),
+            atc_rating: Some(5),
+            ..UserFilter::default()
+        };
+        let result = list_users(&db, &deleted, 0, 10).await.unwrap();
+        assert_eq!(result.items[0].network_id, "1200003");
+        assert_eq!(result.total, 1);
+    }
+
+    #[tokio::test]
+    async fn test_session_and_whitelist_filters() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let start = chrono::Utc::now() - chrono::Duration::days(3);
+        for (i, network_id) in ["1234567", "7654321", "1234567", "1234567"]
+            .into_iter()
+            .enumerate()
+        {
+            session::ActiveModel {
+                session_id: Set(format!("session{}", i)),
+                network_id: Set(network_id.to_string()),
+                callsign: Set("CCA1501".to_string()),
+                client_type: Set("pilot".to_string()),
+                address: Set("127.0.0.1".to_string()),
+                connected_at: Set(start + chrono::Duration::days(i as i64)),
+                ..Default::default()
+            }
+            .insert(&db)
+            .await
+            .unwrap();
+        }
+
+        let filter = SessionFilter {
+            network_id: Some("1234567".to_string()),
+            from: Some(start + chrono::Duration::hours(12)),
+            ..SessionFilter::default()
+        };
+        let result = list_sessions(&db, &filter, 0, 10).await.unwrap();
+        let ids: Vec<_> = result.items.iter().map(|s| s.session_id.as_str()).collect();
+        assert_eq!(ids, vec!["session3", "session2"]);
+
+        // The migration seeds well-known clients, so use a prefix of our own
+        for (client_id, name, enabled) in
+            [("f001", "Test Radar", true), ("f002", "Test Pilot", false)]
+        {
+            add_client_to_whitelist(&db, client_id.to_string(), name.to_string(), None, None)
+                .await
+                .unwrap();
+            if !enabled {
+                client_whitelist::Entity::update_many()
+                    .col_expr(client_whitelist::Column::Enabled, Expr::value(false))
+                    .filter(client_whitelist::Column::ClientId.eq(client_id))
+                    .exec(&db)
+                    .await
+                    .unwrap();
+            }
+        }
+        let filter = WhitelistFilter {
+            client_id_prefix: Some("f0".to_string()),
+            name_contains: Some("Test".to_string()),
+            enabled: Some(true),
+        };
+        let result = list_whitelist(&db, &filter, 0, 10).await.unwrap();
+        assert_eq!(result.total, 1);
+        assert_eq!(result.items[0].client_id, "f001");
+    }
+}
@@ -0,0 +1,462 @@
+use crate::auth::password;
+use crate::config::SecurityConfig;
+use crate::db::entities::user;
+use crate::db::service;
+use sea_orm::*;
+use std::collections::HashSet;
+use std::io;
+use std::str::FromStr;
+use thiserror::Error;
+
+const ATC_RATINGS: std::ops::RangeInclusive<i32> = 1..=12;
+const PILOT_RATINGS: std::ops::RangeInclusive<i32> = 1..=11;
+
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Missing column: {0}")]
+    MissingColumn(&'static str),
+    #[error("Line {line}: {cid} already exists")]
+    Duplicate { line: u64, cid: String },
+    #[error("Password hash error: {0}")]
+    Hash(argon2::password_hash::Error),
+    #[error("Database error: {0}")]
+    Database(#[from] DbErr),
+}
+
+/// What to do with a row whose CID already has an account
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnDuplicate {
+    /// Leave the account alone
+    #[default]
+    Skip,
+    /// Overwrite its name, ratings, email and password
+    Update,
+    /// Abort the import before anything is written
+    Fail,
+}
+
+impl FromStr for OnDuplicate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(OnDuplicate::Skip),
+            "update" => Ok(OnDuplicate::Update),
+            "fail" => Ok(OnDuplicate::Fail),
+            _ => Err(format!("expected skip, update or fail, got {:?}", s)),
+        }
+    }
+}
+
+/// A row that was not imported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowError {
+    /// Line in the file, counting the header as line 1
+    pub line: u64,
+    pub message: String,
+}
+
+/// Outcome of an import; after a dry run the counts are what would happen
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub errors: Vec<RowError>,
+}
+
+/// A validated row
+struct Row {
+    cid: String,
+    name: String,
+    atc_rating: i32,
+    pilot_rating: i32,
+    password: Password,
+    email: Option<String>,
+}
+
+enum Password {
+    Plain(String),
+    Hash(String),
+}
+
+/// Positions of the known columns in the header
+struct Header {
+    cid: usize,
+    name: usize,
+    atc_rating: Option<usize>,
+    pilot_rating: Option<usize>,
+    password: Option<usize>,
+    password_hash: Option<usize>,
+    email: Option<usize>,
+}
+
+impl Header {
+    fn parse(record: &csv::StringRecord) -> Result<Self, ImportError> {
+        let find = |name: &str| record.iter().position(|column| column == name);
+        let header = Header {
+            cid: find("cid").ok_or(ImportError::MissingColumn("cid"))?,
+            name: find("name").ok_or(ImportError::MissingColumn("name"))?,
+            atc_rating: find("atc_rating"),
+            pilot_rating: find("pilot_rating"),
+            password: find("password"),
+            password_hash: find("password_hash"),
+            email: find("email"),
+        };
+        if header.password.is_none() && header.password_hash.is_none() {
+            return Err(ImportError::MissingColumn("password or password_hash"));
+        }
+        Ok(header)
+    }
+
+    /// Validate a record; the error is the message for the report
+    fn row(&self, record: &csv::StringRecord, security: &SecurityConfig) -> Result<Row, String> {
+        let get = |index: Option<usize>| {
+            index
+                .and_then(|index| record.get(index))
+                .filter(|value| !value.is_empty())
+        };
+
+        let cid = get(Some(self.cid)).ok_or("cid is empty")?;
+        if !cid.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("cid {:?} is not a number", cid));
+        }
+        let name = get(Some(self.name)).ok_or("name is empty")?;
+        let atc_rating = rating(get(self.atc_rating), "atc_rating", ATC_RATINGS)?;
+        let pilot_rating = rating(get(self.pilot_rating), "pilot_rating", PILOT_RATINGS)?;
+
+        let password = match (get(self.password), get(self.password_hash)) {
+            (Some(_), Some(_)) => return Err("both password and password_hash are set".into()),
+            (None, None) => return Err("password or password_hash is required".into()),
+            (Some(plain), None) => {
+                password::check_policy(plain, security).map_err(|e| e.to_string())?;
+                Password::Plain(plain.to_string())
+            }
+            (None, Some(hash)) => {
+                argon2::PasswordHash::new(hash)
+                    .map_err(|_| "password_hash is not an Argon2 hash".to_string())?;
+                Password::Hash(hash.to_string())
+            }
+        };
+
+        let email = get(self.email);
+        if let Some(email) = email {
+            let valid = email.split_once('@').is_some_and(|(local, domain)| {
+                !local.is_empty() && domain.contains('.') && !email.contains(char::is_whitespace)
+            });
+            if !valid {
+                return Err(format!("email {:?} is not an address", email));
+            }
+        }
+
+        Ok(Row {
+            cid: cid.to_string(),
+            name: name.to_string(),
+            atc_rating,
+            pilot_rating,
+            password,
+            email: email.map(str::to_string),
+        })
+    }
+}
+
+/// A rating column; missing or empty means 1
+fn rating(
+    value: Option<&str>,
+    column: &str,
+    range: std::ops::RangeInclusive<i32>,
+) -> Result<i32, String> {
+    let Some(value) = value else {
+        return Ok(1);
+    };
+    match value.parse() {
+        Ok(rating) if range.contains(&rating) => Ok(rating),
+        _ => Err(format!(
+            "{} {:?} is not between {} and {}",
+            column,
+            value,
+            range.start(),
+            range.end()
+        )),
+    }
+}
+
+/// Import accounts from CSV
+///
+/// Every row is checked before anything is written: rows with errors are
+/// reported and left out, the rest are written in one transaction along with
+/// an audit entry. Plaintext passwords must meet the `[security]` policy and
+/// are hashed; `password_hash` values are stored as they are. `progress` is
+/// called with the number of rows written so far and the total.
+pub async fn import_users<R: io::Read>(
+    db: &DatabaseConnection,
+    reader: R,
+    on_duplicate: OnDuplicate,
+    dry_run: bool,
+    security: &SecurityConfig,
+    actor: &str,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<ImportReport, ImportError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(reader);
+    let header = Header::parse(reader.headers()?)?;
+
+    let mut report = ImportReport::default();
+    // Rows to write, with the account an update applies to
+    let mut writes = Vec::new();
+    let mut seen = HashSet::new();
+    for (i, record) in reader.records().enumerate() {
+        let line = i as u64 + 2;
+        let row = record
+            .map_err(|e| e.to_string())
+            .and_then(|record| header.row(&record, security));
+        let row = match row {
+            Ok(row) if !seen.insert(row.cid.clone()) => {
+                Err(format!("{} appears more than once", row.cid))
+            }
+            row => row,
+        };
+        let row = match row {
+            Ok(row) => row,
+            Err(message) => {
+                report.errors.push(RowError { line, message });
+                continue;
+            }
+        };
+
+        match service::find_user_including_deleted(db, &row.cid).await? {
+            None => {
+                report.created += 1;
+                writes.push((row, None));
+            }
+            Some(user) if user.is_deleted() => report.errors.push(RowError {
+                line,
+                message: format!("{} belongs to a deleted account", row.cid),
+            }),
+            Some(_) if on_duplicate == OnDuplicate::Fail => {
+                return Err(ImportError::Duplicate { line, cid: row.cid });
+            }
+            Some(_) if on_duplicate == OnDuplicate::Skip => report.skipped += 1,
+            Some(user) => {
+                report.updated += 1;
+                writes.push((row, Some(user)));
+            }
+        }
+    }
+    if dry_run {
+        return Ok(report);
+    }
+
+    let total = writes.len();
+    let txn = db.begin().await?;
+    for (done, (row, existing)) in writes.into_iter().enumerate() {
+        let password_hash = match row.password {
+            Password::Plain(plain) => password::hash_password(&plain).map_err(ImportError::Hash)?,
+            Password::Hash(hash) => hash,
+        };
+        let now = chrono::Utc::now();
+        let mut user = match existing {
+            Some(user) => user.into(),
+            None => user::ActiveModel {
+                network_id: Set(row.cid),
+                created_at: Set(now),
+                ..Default::default()
+            },
+        };
+        user.password_hash = Set(password_hash);
+        user.real_name = Set(row.name);
+        user.atc_rating = Set(row.atc_rating);
+        user.pilot_rating = Set(row.pilot_rating);
+        user.email = Set(row.email);
+        user.updated_at = Set(now);
+        user.save(&txn).await?;
+        progress(done + 1, total);
+    }
+    let details = format!(
+        "{} created, {} updated, {} skipped, {} rejected",
+        report.created,
+        report.updated,
+        report.skipped,
+        report.errors.len()
+    );
+    service::record_audit_event(&txn, actor, "user.import", None, Some(&details)).await?;
+    txn.commit().await?;
+    Ok(report)
+}
+
+/// Write active accounts to CSV, ordered by CID, returning how many
+///
+/// Password hashes are only included with `include_hashes`; a file with
+/// them can be imported elsewhere with the same passwords.
+pub async fn export_users<W: io::Write>(
+    db: &DatabaseConnection,
+    writer: W,
+    include_hashes: bool,
+) -> Result<usize, ImportError> {
+    let users = user::Entity::find()
+        .filter(user::Column::DeletedAt.is_null())
+        .order_by_asc(user::Column::NetworkId)
+        .all(db)
+        .await?;
+
+    let mut writer = csv::Writer::from_writer(writer);
+    let mut header = vec!["cid", "name", "atc_rating", "pilot_rating", "email"];
+    if include_hashes {
+        header.push("password_hash");
+    }
+    writer.write_record(&header)?;
+    for user in &users {
+        let atc_rating = user.atc_rating.to_string();
+        let pilot_rating = user.pilot_rating.to_string();
+        let mut record = vec![
+            user.network_id.as_str(),
+            user.real_name.as_str(),
+            atc_rating.as_str(),
+            pilot_rating.as_str(),
+            user.email.as_deref().unwrap_or_default(),
+        ];
+        if include_hashes {
+            record.push(&user.password_hash);
+        }
+        writer.write_record(&record)?;
+    }
+    writer.flush().map_err(csv::Error::from)?;
+    Ok(users.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = "\
+cid,name,atc_rating,pilot_rating,password,email
+1000001,Jane Doe,3,1,correct horse,jane@example.com
+1000002,John Smith,15,1,battery staple,
+1000003,\"Doe, Max\",,,staple horse,max@example.com
+";
+
+    fn security() -> SecurityConfig {
+        SecurityConfig {
+            min_password_length: 8,
+            ..SecurityConfig::default()
+        }
+    }
+
+    async fn import(
+        db: &DatabaseConnection,
+        csv: &str,
+        on_duplicate: OnDuplicate,
+        dry_run: bool,
+    ) -> Result<ImportReport, ImportError> {
+        import_users(
+            db,
+            csv.as_bytes(),
+            on_duplicate,
+            dry_run,
+            &security(),
+            "test",
+            |_, _| {},
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_import_reports_bad_rows() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+
+        let dry_run = import(&db, FIXTURE, OnDuplicate::Skip, true).await.unwrap();
+        assert_eq!(dry_run.created, 2);
+        assert!(service::find_user_by_network_id(&db, "1000001")
+            .await
+            .unwrap()
+            .is_none());
+
+        let report = import(&db, FIXTURE, OnDuplicate::Skip, false)
+            .await
+            .unwrap();
+        assert_eq!(report.created, 2);
+        assert_eq!(
+            report.errors,
+            vec![RowError {
+                line: 3,
+                message: "atc_rating \"15\" is not between 1 and 12".to_string()
+            }]
+        );
+
+        let jane = service::find_user_by_network_id(&db, "1000001")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(jane.atc_rating, 3);
+        assert_eq!(jane.email.as_deref(), Some("jane@example.com"));
+        assert!(password::verify_password("correct horse", &jane.password_hash).unwrap());
+        let max = service::find_user_by_network_id(&db, "1000003")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(max.real_name, "Doe, Max");
+        assert_eq!((max.atc_rating, max.pilot_rating), (1, 1));
+        assert!(service::find_user_by_network_id(&db, "1000002")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_import_duplicates() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        import(&db, FIXTURE, OnDuplicate::Skip, false)
+            .await
+            .unwrap();
+
+        let again = import(&db, FIXTURE, OnDuplicate::Skip, false)
+            .await
+            .unwrap();
+        assert_eq!((again.created, again.skipped), (0, 2));
+        assert!(matches!(
+            import(&db, FIXTURE, OnDuplicate::Fail, false).await,
+            Err(ImportError::Duplicate { line: 2, .. })
+        ));
+
+        let renamed = "cid,name,password_hash\n1000001,Jane Roe,$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ$aGFzaGhhc2hoYXNoaGFzaA\n";
+        let report = import(&db, renamed, OnDuplicate::Update, false)
+            .await
+            .unwrap();
+        assert_eq!(report.updated, 1);
+        let jane = service::find_user_by_network_id(&db, "1000001")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(jane.real_name, "Jane Roe");
+        assert!(jane.password_hash.starts_with("$argon2id$"));
+    }
+
+    #[tokio::test]
+    async fn test_export_leaves_out_hashes() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        import(&db, FIXTURE, OnDuplicate::Skip, false)
+            .await
+            .unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(export_users(&db, &mut out, false).await.unwrap(), 2);
+        let csv = String::from_utf8(out).unwrap();
+        assert_eq!(
+            csv,
+            "cid,name,atc_rating,pilot_rating,email\n\
+             1000001,Jane Doe,3,1,jane@example.com\n\
+             1000003,\"Doe, Max\",1,1,max@example.com\n"
+        );
+
+        let mut out = Vec::new();
+        export_users(&db, &mut out, true).await.unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        assert!(csv.starts_with("cid,name,atc_rating,pilot_rating,email,password_hash\n"));
+        // The hash has commas of its own
+        assert!(csv.contains(",\"$argon2"));
+    }
+}
@@ -0,0 +1,108 @@
+use crate::auth::AuthError;
+use crate::config::ConfigError;
+use crate::packet::PacketError;
+use crate::server::BuildError;
+use sea_orm::DbErr;
+use thiserror::Error;
+
+/// Why the server, or a part of it, stopped
+///
+/// Returned by [`Server::run`](crate::server::Server::run), the database
+/// setup in [`crate::db`] and the connection handlers, so callers can tell a
+/// port that is taken from a database that is down.
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("Failed to listen on {address}: {source}")]
+    Bind {
+        address: String,
+        source: std::io::Error,
+    },
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Database error: {0}")]
+    Database(#[from] DbErr),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Build(#[from] BuildError),
+    #[error("Failed to start metrics: {0}")]
+    Metrics(String),
+    #[error("Invalid packet: {0}")]
+    Packet(#[from] PacketError),
+    #[error("Authentication failed: {0}")]
+    Auth(#[from] AuthError),
+    #[error("The server is shutting down")]
+    Shutdown,
+}
+
+impl ServerError {
+    /// A bind failure for `address`
+    pub(crate) fn bind(address: impl ToString) -> impl FnOnce(std::io::Error) -> Self {
+        let address = address.to_string();
+        move |source| ServerError::Bind { address, source }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, DatabaseConfig};
+    use crate::packet::Packet;
+    use crate::server::ServerBuilder;
+    use std::io::ErrorKind;
+
+    #[tokio::test]
+    async fn test_port_in_use() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Config::default();
+        config.server.address = "127.0.0.1".to_string();
+        config.server.port = taken.local_addr().unwrap().port();
+        let server = ServerBuilder::new()
+            .with_config(config)
+            .with_database(crate::db::init_ephemeral().await.unwrap())
+            .build()
+            .unwrap();
+
+        match server.run().await {
+            Err(ServerError::Bind { address, source }) => {
+                assert_eq!(address, taken.local_addr().unwrap().to_string());
+                assert_eq!(source.kind(), ErrorKind::AddrInUse);
+            }
+            other => panic!("expected a bind error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_database() {
+        let config = DatabaseConfig {
+            url: "oracle://db/openfsd".to_string(),
+            startup_retry_timeout: 0,
+            ..DatabaseConfig::default()
+        };
+        let result = crate::db::init(&config).await;
+        assert!(matches!(result, Err(ServerError::Database(_))));
+    }
+
+    #[test]
+    fn test_conversions() {
+        let packet = Packet::parse("not a packet").map_err(ServerError::from);
+        assert!(matches!(packet, Err(ServerError::Packet(_))));
+
+        let missing = Config::load(
+            Some(std::path::Path::new("/nonexistent/openfsd.toml")),
+            Vec::new(),
+            &Default::default(),
+        )
+        .map_err(ServerError::from);
+        assert!(matches!(
+            missing,
+            Err(ServerError::Config(ConfigError::NotFound(_)))
+        ));
+
+        let build = ServerBuilder::new().build().map_err(ServerError::from);
+        assert!(matches!(
+            build,
+            Err(ServerError::Build(BuildError::MissingDatabase))
+        ));
+    }
+}
@@ -0,0 +1,332 @@
+pub mod route;
+
+use crate::packet::{Packet, PacketType};
+use route::RouteElement;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Number of data fields in a $FP packet
+const FLIGHT_PLAN_FIELDS: usize = 15;
+
+/// Flight rules clients may file: IFR, VFR, the two mixed kinds, special VFR
+/// and defense VFR
+const FLIGHT_RULES: &[&str] = &["I", "V", "Y", "Z", "S", "D"];
+
+/// Why a plan can't be accepted
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FlightPlanError {
+    #[error("{0} must not contain colons or line breaks")]
+    Separator(&'static str),
+    #[error("Unknown flight rules \"{0}\"")]
+    FlightRules(String),
+    #[error("{0} is required")]
+    Missing(&'static str),
+    #[error("{field} must be a number, not \"{value}\"")]
+    NotANumber { field: &'static str, value: String },
+    #[error("{field} must not be longer than {max} bytes")]
+    TooLong { field: &'static str, max: usize },
+}
+
+/// Flight plan as carried in $FP packets
+///
+/// Fields are kept as the raw strings sent by clients so plans can be relayed
+/// without loss. The route is also kept broken into elements, for maps.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlightPlan {
+    /// "I" (IFR), "V" (VFR), ...
+    pub flight_rules: String,
+    pub aircraft: String,
+    pub cruise_speed: String,
+    pub departure: String,
+    pub departure_time: String,
+    pub actual_departure_time: String,
+    pub altitude: String,
+    pub destination: String,
+    pub hours_enroute: String,
+    pub minutes_enroute: String,
+    pub hours_fuel: String,
+    pub minutes_fuel: String,
+    pub alternate: String,
+    pub remarks: String,
+    pub route: String,
+    /// `route` split into classified elements
+    pub route_elements: Vec<RouteElement>,
+}
+
+impl FlightPlan {
+    /// Parse the data fields of a $FP packet
+    ///
+    /// $FP(callsign):*A:(rules):(aircraft):(TAS):(dep):(dep time):(actual dep time):(alt):(dest):(hrs enroute):(min enroute):(hrs fuel):(min fuel):(alternate):(remarks):(route)
+    pub fn from_packet(packet: &Packet) -> Option<Self> {
+        Self::from_fields(&packet.data)
+    }
+
+    /// Build a plan from the 15 fields of a $FP packet, in protocol order
+    pub fn from_fields(fields: &[String]) -> Option<Self> {
+        if fields.len() < FLIGHT_PLAN_FIELDS {
+            return None;
+        }
+
+        let field = |i: usize| fields[i].clone();
+        Some(
+            Self {
+                flight_rules: field(0),
+                aircraft: field(1),
+                cruise_speed: field(2),
+                departure: field(3),
+                departure_time: field(4),
+                actual_departure_time: field(5),
+                altitude: field(6),
+                destination: field(7),
+                hours_enroute: field(8),
+                minutes_enroute: field(9),
+                hours_fuel: field(10),
+                minutes_fuel: field(11),
+                alternate: field(12),
+                remarks: field(13),
+                // Routes never contain colons, but don't drop anything if they do
+                route: fields[14..].join(":"),
+                route_elements: Vec::new(),
+            }
+            .with_route_elements(),
+        )
+    }
+
+    /// The plan with `route_elements` parsed from `route`
+    pub fn with_route_elements(mut self) -> Self {
+        self.route_elements = route::parse(&self.route);
+        self
+    }
+
+    /// The plan as $FP packet fields, in protocol order
+    pub fn fields(&self) -> Vec<String> {
+        vec![
+            self.flight_rules.clone(),
+            self.aircraft.clone(),
+            self.cruise_speed.clone(),
+            self.departure.clone(),
+            self.departure_time.clone(),
+            self.actual_departure_time.clone(),
+            self.altitude.clone(),
+            self.destination.clone(),
+            self.hours_enroute.clone(),
+            self.minutes_enroute.clone(),
+            self.hours_fuel.clone(),
+            self.minutes_fuel.clone(),
+            self.alternate.clone(),
+            self.remarks.clone(),
+            self.route.clone(),
+        ]
+    }
+
+    /// Check the plan before it is stored or relayed
+    ///
+    /// Every field has to fit in a $FP packet, the rules have to be known,
+    /// the aircraft and both airports have to be given, and the speed and
+    /// durations have to be numbers when present.
+    pub fn validate(&self) -> Result<(), FlightPlanError> {
+        if let Some((name, _)) = self
+            .named_fields()
+            .iter()
+            .find(|(_, value)| value.contains([':', '\r', '\n']))
+        {
+            return Err(FlightPlanError::Separator(name));
+        }
+
+        if !FLIGHT_RULES.contains(&self.flight_rules.to_ascii_uppercase().as_str()) {
+            return Err(FlightPlanError::FlightRules(self.flight_rules.clone()));
+        }
+        for (name, value) in [
+            ("aircraft", &self.aircraft),
+            ("departure", &self.departure),
+            ("destination", &self.destination),
+        ] {
+            if value.trim().is_empty() {
+                return Err(FlightPlanError::Missing(name));
+            }
+        }
+        for (name, value) in [
+            ("cruise_speed", &self.cruise_speed),
+            ("hours_enroute", &self.hours_enroute),
+            ("minutes_enroute", &self.minutes_enroute),
+            ("hours_fuel", &self.hours_fuel),
+            ("minutes_fuel", &self.minutes_fuel),
+        ] {
+            if !value.chars().all(|c| c.is_ascii_digit()) {
+                return Err(FlightPlanError::NotANumber {
+                    field: name,
+                    value: value.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuse plans with a field longer than `max` bytes
+    pub fn check_lengths(&self, max: usize) -> Result<(), FlightPlanError> {
+        match self
+            .named_fields()
+            .iter()
+            .find(|(_, value)| value.len() > max)
+        {
+            Some((field, _)) => Err(FlightPlanError::TooLong { field, max }),
+            None => Ok(()),
+        }
+    }
+
+    /// Bytes of text the plan holds, the route elements included
+    pub fn text_len(&self) -> usize {
+        let elements: usize = self
+            .route_elements
+            .iter()
+            .map(|element| element.text.len() + element.speed_level.as_ref().map_or(0, String::len))
+            .sum();
+        self.named_fields()
+            .iter()
+            .map(|(_, value)| value.len())
+            .sum::<usize>()
+            + elements
+    }
+
+    /// The fields with their names, in protocol order
+    fn named_fields(&self) -> [(&'static str, &String); FLIGHT_PLAN_FIELDS] {
+        [
+            ("flight_rules", &self.flight_rules),
+            ("aircraft", &self.aircraft),
+            ("cruise_speed", &self.cruise_speed),
+            ("departure", &self.departure),
+            ("departure_time", &self.departure_time),
+            ("actual_departure_time", &self.actual_departure_time),
+            ("altitude", &self.altitude),
+            ("destination", &self.destination),
+            ("hours_enroute", &self.hours_enroute),
+            ("minutes_enroute", &self.minutes_enroute),
+            ("hours_fuel", &self.hours_fuel),
+            ("minutes_fuel", &self.minutes_fuel),
+            ("alternate", &self.alternate),
+            ("remarks", &self.remarks),
+            ("route", &self.route),
+        ]
+    }
+
+    /// ICAO type designator from the aircraft field, which clients send with
+    /// prefixes and suffixes such as "H/B744/L" or "B738/M-SDE2/LB1"
+    pub fn aircraft_type(&self) -> &str {
+        self.aircraft
+            .split('/')
+            .find(|part| part.len() > 1)
+            .map(|part| part.split('-').next().unwrap_or(part))
+            .unwrap_or(&self.aircraft)
+    }
+
+    /// Build the $FP packet announcing this plan for a callsign
+    pub fn to_packet(&self, callsign: &str) -> Packet {
+        Packet {
+            packet_type: PacketType::Request,
+            command: "FP".to_string(),
+            source: callsign.to_string(),
+            destination: "*A".to_string(),
+            data: self.fields(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flight_plan_round_trip() {
+        let raw = "$FPCCA1501:*A:I:H/B744/L:490:ZBAA:0130:0:FL350:ZSPD:1:45:3:30:ZSHC:/V/ PBN/A1B1:ELKUR W40 YQG";
+        let packet = Packet::parse(raw).unwrap();
+        let plan = FlightPlan::from_packet(&packet).unwrap();
+
+        assert_eq!(plan.departure, "ZBAA");
+        assert_eq!(plan.destination, "ZSPD");
+        assert_eq!(plan.route, "ELKUR W40 YQG");
+        let elements: Vec<&str> = plan
+            .route_elements
+            .iter()
+            .map(|e| e.text.as_str())
+            .collect();
+        assert_eq!(elements, ["ELKUR", "W40", "YQG"]);
+        assert_eq!(plan.to_packet("CCA1501").format().trim_end(), raw);
+    }
+
+    #[test]
+    fn test_aircraft_type() {
+        let plan = |aircraft: &str| FlightPlan {
+            aircraft: aircraft.to_string(),
+            ..FlightPlan::default()
+        };
+        assert_eq!(plan("H/B744/L").aircraft_type(), "B744");
+        assert_eq!(plan("B738/L").aircraft_type(), "B738");
+        assert_eq!(plan("A320/M-SDE2E3FGHIJ1RWY/LB1").aircraft_type(), "A320");
+        assert_eq!(plan("C172").aircraft_type(), "C172");
+        assert_eq!(plan("").aircraft_type(), "");
+    }
+
+    #[test]
+    fn test_validate() {
+        let raw =
+            "$FPCCA1501:*A:I:H/B744/L:490:ZBAA:0130:0:FL350:ZSPD:1:45:3:30:ZSHC::ELKUR W40 YQG";
+        let valid = FlightPlan::from_packet(&Packet::parse(raw).unwrap()).unwrap();
+        assert_eq!(valid.validate(), Ok(()));
+
+        let with = |change: fn(&mut FlightPlan)| {
+            let mut plan = valid.clone();
+            change(&mut plan);
+            plan.validate()
+        };
+        assert_eq!(with(|plan| plan.flight_rules = "v".to_string()), Ok(()));
+        assert_eq!(with(|plan| plan.hours_fuel.clear()), Ok(()));
+        assert_eq!(
+            with(|plan| plan.route = "ELKUR:W40".to_string()),
+            Err(FlightPlanError::Separator("route"))
+        );
+        assert_eq!(
+            with(|plan| plan.remarks = "/V/\n$FPEVIL".to_string()),
+            Err(FlightPlanError::Separator("remarks"))
+        );
+        assert_eq!(
+            with(|plan| plan.flight_rules = "X".to_string()),
+            Err(FlightPlanError::FlightRules("X".to_string()))
+        );
+        assert_eq!(
+            with(|plan| plan.destination = " ".to_string()),
+            Err(FlightPlanError::Missing("destination"))
+        );
+        assert_eq!(
+            with(|plan| plan.cruise_speed = "N0490".to_string()),
+            Err(FlightPlanError::NotANumber {
+                field: "cruise_speed",
+                value: "N0490".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_check_lengths() {
+        let plan = FlightPlan {
+            remarks: "R".repeat(20),
+            route: "DCT".to_string(),
+            ..FlightPlan::default()
+        };
+        assert_eq!(plan.text_len(), 23);
+        assert_eq!(plan.check_lengths(20), Ok(()));
+        assert_eq!(
+            plan.check_lengths(19),
+            Err(FlightPlanError::TooLong {
+                field: "remarks",
+                max: 19
+            })
+        );
+    }
+
+    #[test]
+    fn test_flight_plan_too_short() {
+        let packet = Packet::parse("$FPCCA1501:*A:I:H/B744/L").unwrap();
+        assert!(FlightPlan::from_packet(&packet).is_none());
+    }
+}
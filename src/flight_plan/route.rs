@@ -0,0 +1,308 @@
+//! Flight plan routes broken into elements
+//!
+//! A route is free text, so pilots file anything from
+//! `CDY W40 DOGAR` to `N0450F350 DCT 5530N 5720N 5810N DCT`. Each
+//! space-separated token is classified by its shape alone, without a
+//! navigation database: airways, fixes, coordinates, SIDs and STARs, speed
+//! and level changes and `DCT`. Whatever fits none of them is `other`, so no
+//! route is ever refused here.
+
+use serde::{Deserialize, Serialize};
+
+/// What a route token looks like
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ElementKind {
+    /// `DCT`, direct to the next element
+    Direct,
+    /// E.g. `W40`, `UL613`, `J80`
+    Airway,
+    /// A named fix, navaid or airport, e.g. `DOGAR`, `CDY`, `EGLL`
+    Waypoint,
+    /// A latitude and longitude, e.g. `5130N00030W` or `5530N`
+    Coordinate,
+    /// A SID or STAR, e.g. `DOGAR1A`, `WAL2M`
+    Procedure,
+    /// Speed and level on their own, e.g. `N0450F350`
+    SpeedLevel,
+    Other,
+}
+
+/// One token of a route
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteElement {
+    /// The token as filed, less any speed and level change after a slash
+    pub text: String,
+    pub kind: ElementKind,
+    /// Speed and level change at this point, e.g. "N0450F350" from
+    /// `WAL/N0450F350`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed_level: Option<String>,
+    /// Decimal degrees, for coordinates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+}
+
+/// Split a route into its elements, in order
+pub fn parse(route: &str) -> Vec<RouteElement> {
+    route.split_whitespace().map(element).collect()
+}
+
+fn element(token: &str) -> RouteElement {
+    let token = token.to_ascii_uppercase();
+    let (text, speed_level) = match token.split_once('/') {
+        Some((text, change)) if !text.is_empty() && is_speed_level(change) => {
+            (text.to_string(), Some(change.to_string()))
+        }
+        _ => (token, None),
+    };
+    let position = coordinate(&text);
+    let kind = if position.is_some() {
+        ElementKind::Coordinate
+    } else {
+        classify(&text)
+    };
+    RouteElement {
+        text,
+        kind,
+        speed_level,
+        latitude: position.map(|(lat, _)| lat),
+        longitude: position.map(|(_, lon)| lon),
+    }
+}
+
+fn classify(text: &str) -> ElementKind {
+    let letters = text.bytes().take_while(u8::is_ascii_uppercase).count();
+    let digits = text[letters..]
+        .bytes()
+        .take_while(u8::is_ascii_digit)
+        .count();
+    let suffix = &text[letters + digits..];
+    let one_letter = suffix.len() == 1 && suffix.bytes().all(|b| b.is_ascii_uppercase());
+
+    if text == "DCT" {
+        ElementKind::Direct
+    } else if is_speed_level(text) {
+        ElementKind::SpeedLevel
+    } else if matches!(text, "IFR" | "VFR") {
+        ElementKind::Other
+    } else if (2..=5).contains(&letters) && digits == 0 && suffix.is_empty() {
+        ElementKind::Waypoint
+    } else if letters >= 3 && digits == 1 && (suffix.is_empty() || one_letter) {
+        ElementKind::Procedure
+    } else if (1..=2).contains(&letters)
+        && (1..=3).contains(&digits)
+        && (suffix.is_empty() || one_letter)
+    {
+        ElementKind::Airway
+    } else {
+        ElementKind::Other
+    }
+}
+
+/// A speed in knots, Mach or km/h followed by a flight level or altitude,
+/// e.g. N0450F350, M082F370, K0830S1010
+fn is_speed_level(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    let (speed_len, level) = match bytes.first() {
+        Some(b'N' | b'K') => (5, bytes.get(5..)),
+        Some(b'M') => (4, bytes.get(4..)),
+        _ => return false,
+    };
+    let Some(level) = level else {
+        return false;
+    };
+    let level_len = match level.first() {
+        Some(b'F' | b'A') => 4,
+        Some(b'S' | b'M') => 5,
+        _ => return false,
+    };
+    level.len() == level_len
+        && bytes[1..speed_len].iter().all(u8::is_ascii_digit)
+        && level[1..].iter().all(u8::is_ascii_digit)
+}
+
+/// Latitude and longitude of a coordinate token in one of the formats
+/// pilots file:
+///
+/// - `51N030W`: whole degrees
+/// - `5130N00030W`: degrees and minutes
+/// - `513015N0003045W`: degrees, minutes and seconds
+/// - `5530N`: ARINC 424 whole degrees, 55N 30W; the letter's place and
+///   value give the hundreds of longitude and the quadrant
+fn coordinate(text: &str) -> Option<(f64, f64)> {
+    if let Some(position) = arinc_coordinate(text) {
+        return Some(position);
+    }
+    let split = text.find(['N', 'S'])?;
+    let (lat, rest) = text.split_at(split);
+    let lat_sign = if rest.starts_with('S') { -1.0 } else { 1.0 };
+    let lon_text = &rest[1..];
+    let lon_sign = match lon_text.as_bytes().last()? {
+        b'E' => 1.0,
+        b'W' => -1.0,
+        _ => return None,
+    };
+    let lon = &lon_text[..lon_text.len() - 1];
+    if !(lat.bytes().all(|b| b.is_ascii_digit()) && lon.bytes().all(|b| b.is_ascii_digit())) {
+        return None;
+    }
+    let (lat, lon) = match (lat.len(), lon.len()) {
+        (2, 3) => (degrees(lat, "", "")?, degrees(lon, "", "")?),
+        (4, 5) => (
+            degrees(&lat[..2], &lat[2..], "")?,
+            degrees(&lon[..3], &lon[3..], "")?,
+        ),
+        (6, 7) => (
+            degrees(&lat[..2], &lat[2..4], &lat[4..])?,
+            degrees(&lon[..3], &lon[3..5], &lon[5..])?,
+        ),
+        _ => return None,
+    };
+    (lat <= 90.0 && lon <= 180.0).then_some((lat * lat_sign, lon * lon_sign))
+}
+
+/// `5530N` is 55N 30W and `55N30` is 55N 130W; N, E, S and W stand for the
+/// north-west, north-east, south-east and south-west quadrants
+fn arinc_coordinate(text: &str) -> Option<(f64, f64)> {
+    let bytes = text.as_bytes();
+    if bytes.len() != 5 || !text.is_ascii() {
+        return None;
+    }
+    let (letter, digits, hundred) = if bytes[2].is_ascii_digit() {
+        (bytes[4], [&text[..2], &text[2..4]], 0.0)
+    } else {
+        (bytes[2], [&text[..2], &text[3..]], 100.0)
+    };
+    let (lat_sign, lon_sign) = match letter {
+        b'N' => (1.0, -1.0),
+        b'E' => (1.0, 1.0),
+        b'S' => (-1.0, 1.0),
+        b'W' => (-1.0, -1.0),
+        _ => return None,
+    };
+    let lat = degrees(digits[0], "", "")?;
+    let lon = degrees(digits[1], "", "")? + hundred;
+    (lat <= 90.0 && lon <= 180.0).then_some((lat * lat_sign, lon * lon_sign))
+}
+
+/// Decimal degrees from all-digit degrees, minutes and seconds; minutes and
+/// seconds may be empty
+fn degrees(degrees: &str, minutes: &str, seconds: &str) -> Option<f64> {
+    let number = |text: &str, max: f64| -> Option<f64> {
+        if text.is_empty() {
+            return Some(0.0);
+        }
+        if !text.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let value: f64 = text.parse().ok()?;
+        (value < max).then_some(value)
+    };
+    if degrees.is_empty() {
+        return None;
+    }
+    Some(number(degrees, 181.0)? + number(minutes, 60.0)? / 60.0 + number(seconds, 60.0)? / 3600.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ElementKind::*;
+
+    /// The kinds of a route's elements
+    fn kinds(route: &str) -> Vec<ElementKind> {
+        parse(route)
+            .into_iter()
+            .map(|element| element.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_real_routes() {
+        for (route, expected) in [
+            // China, airways between navaids and fixes
+            ("CDY W40 DOGAR", &[Waypoint, Airway, Waypoint][..]),
+            ("ELKUR W40 YQG", &[Waypoint, Airway, Waypoint]),
+            // Europe, with a SID and a STAR
+            (
+                "CPT3F CPT UL9 KENET UL620 TOBOR LUMAN1A",
+                &[
+                    Procedure, Waypoint, Airway, Waypoint, Airway, Waypoint, Procedure,
+                ],
+            ),
+            (
+                "MARUN7F MARUN Y163 NATOR N850 ABESI UZ660 GARSA",
+                &[
+                    Procedure, Waypoint, Airway, Waypoint, Airway, Waypoint, Airway, Waypoint,
+                ],
+            ),
+            // US, a SID without a letter, jet and Q routes
+            (
+                "KORRY4 KORRY J80 SPA Q22 GRDEN",
+                &[Procedure, Waypoint, Airway, Waypoint, Airway, Waypoint],
+            ),
+            ("dct wavey dct", &[Direct, Waypoint, Direct]),
+            // ICAO form, starting with the speed and level
+            (
+                "N0450F350 DCT WAL UL975 SLANY",
+                &[SpeedLevel, Direct, Waypoint, Airway, Waypoint],
+            ),
+            (
+                "M082F370 TIGER L9 KONAN",
+                &[SpeedLevel, Waypoint, Airway, Waypoint],
+            ),
+            // Australia and Japan, RNAV routes
+            (
+                "TESAT Y59 RIVET H65 WOL",
+                &[Waypoint, Airway, Waypoint, Airway, Waypoint],
+            ),
+            ("SAMON Y884A ZUSHI", &[Waypoint, Airway, Waypoint]),
+            ("VFR", &[Other]),
+        ] {
+            assert_eq!(kinds(route), expected, "{}", route);
+        }
+    }
+
+    #[test]
+    fn test_oceanic_coordinates() {
+        let route = parse("PIKIL 5720N 5830N 5840N 5750N DORYY/N0480F360 DCT 5130N00030W 51N030W");
+        assert_eq!(
+            route.iter().map(|element| element.kind).collect::<Vec<_>>(),
+            [
+                Waypoint, Coordinate, Coordinate, Coordinate, Coordinate, Waypoint, Direct,
+                Coordinate, Coordinate
+            ]
+        );
+        let position = |i: usize| (route[i].latitude.unwrap(), route[i].longitude.unwrap());
+        assert_eq!(position(1), (57.0, -20.0));
+        assert_eq!(position(4), (57.0, -50.0));
+        assert_eq!(route[5].text, "DORYY");
+        assert_eq!(route[5].speed_level.as_deref(), Some("N0480F360"));
+        assert_eq!(position(7), (51.5, -0.5));
+        assert_eq!(position(8), (51.0, -30.0));
+
+        // The Pacific and the southern hemisphere
+        let route = parse("30N140W 3530S15045E 55N30 4720S15011E 335530N1404515E");
+        assert!(route.iter().all(|element| element.kind == Coordinate));
+        let position = |i: usize| (route[i].latitude.unwrap(), route[i].longitude.unwrap());
+        assert_eq!(position(0), (30.0, -140.0));
+        assert_eq!(position(1), (-35.5, 150.75));
+        // The letter in the middle adds 100 to the longitude
+        assert_eq!(position(2), (55.0, -130.0));
+        assert_eq!(position(4).0, 33.925);
+    }
+
+    #[test]
+    fn test_junk_is_other() {
+        let route = parse("  /v/ ??? 12345 9999N99999W ABCDEFG N0450 DCT W40/XYZ 北京  ");
+        assert_eq!(
+            route.iter().map(|element| element.kind).collect::<Vec<_>>(),
+            [Other, Other, Other, Other, Other, Other, Direct, Other, Other]
+        );
+        assert_eq!(route[7].text, "W40/XYZ");
+        assert!(parse("").is_empty());
+    }
+}
@@ -0,0 +1,291 @@
+//! When a flight is expected to arrive
+//!
+//! Before an aircraft moves, the estimate is the filed departure time plus
+//! the filed time enroute. Once it first goes faster than 50 knots, the
+//! off-block time takes the place of the filed departure time. While it is
+//! moving with a recent position and the destination's coordinates are
+//! known, the estimate comes from the great-circle distance left and the
+//! current groundspeed instead. Anything that would give a time that can't
+//! be right, such as a filed arrival that has already passed, gives no
+//! estimate at all.
+
+use crate::client::distance_nm;
+use crate::flight_plan::FlightPlan;
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Groundspeed in knots above which an aircraft has left its stand
+pub const OFF_BLOCK_GROUNDSPEED_KT: u32 = 50;
+
+/// A position older than this, in seconds, is too old to estimate from
+const STALE_AFTER_SECS: i64 = 60;
+
+/// Progress of a pilot's flight, from its position updates
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlightProgress {
+    /// When the aircraft first went faster than [`OFF_BLOCK_GROUNDSPEED_KT`]
+    pub off_block: Option<DateTime<Utc>>,
+    /// When the latest position update arrived
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// What an [`Eta`] is based on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EtaBasis {
+    /// The filed departure or the off-block time, plus the filed time enroute
+    Planned,
+    /// The distance left at the current groundspeed
+    Actual,
+}
+
+/// Estimated time of arrival
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Eta {
+    pub time: DateTime<Utc>,
+    pub basis: EtaBasis,
+}
+
+impl FlightProgress {
+    /// Take in a position update with `groundspeed` that arrived at `now`
+    pub fn update(&mut self, groundspeed: Option<u32>, now: DateTime<Utc>) {
+        self.updated_at = Some(now);
+        if self.off_block.is_none()
+            && groundspeed.is_some_and(|speed| speed > OFF_BLOCK_GROUNDSPEED_KT)
+        {
+            self.off_block = Some(now);
+        }
+    }
+
+    /// Whether the latest position is recent enough to estimate from
+    fn is_current(&self, now: DateTime<Utc>) -> bool {
+        self.updated_at
+            .is_some_and(|at| now - at <= Duration::seconds(STALE_AFTER_SECS))
+    }
+}
+
+/// Where a flight is, for [`eta`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Position {
+    /// (latitude, longitude)
+    pub point: Option<(f64, f64)>,
+    pub groundspeed: Option<u32>,
+}
+
+/// When the flight filed as `plan` should arrive, or `None` if that can't
+/// be told
+///
+/// `destination` is the arrival airport's (latitude, longitude), if known.
+pub fn eta(
+    plan: &FlightPlan,
+    progress: &FlightProgress,
+    position: Position,
+    destination: Option<(f64, f64)>,
+    now: DateTime<Utc>,
+) -> Option<Eta> {
+    actual_eta(progress, position, destination, now).or_else(|| planned_eta(plan, progress, now))
+}
+
+fn actual_eta(
+    progress: &FlightProgress,
+    position: Position,
+    destination: Option<(f64, f64)>,
+    now: DateTime<Utc>,
+) -> Option<Eta> {
+    if progress.off_block.is_none() || !progress.is_current(now) {
+        return None;
+    }
+    let groundspeed = position
+        .groundspeed
+        .filter(|speed| *speed > OFF_BLOCK_GROUNDSPEED_KT)?;
+    let remaining_nm = distance_nm(position.point?, destination?);
+    let seconds = remaining_nm / f64::from(groundspeed) * 3600.0;
+    Some(Eta {
+        time: now + Duration::seconds(seconds.round() as i64),
+        basis: EtaBasis::Actual,
+    })
+}
+
+fn planned_eta(plan: &FlightPlan, progress: &FlightProgress, now: DateTime<Utc>) -> Option<Eta> {
+    let enroute = enroute(plan)?;
+    let start = match progress.off_block {
+        Some(off_block) => off_block,
+        // A departure that is overdue is taken to be now
+        None => planned_departure(plan, now)?.max(now),
+    };
+    let time = start + enroute;
+    (time >= now).then_some(Eta {
+        time,
+        basis: EtaBasis::Planned,
+    })
+}
+
+/// The filed time enroute, if there is one
+pub fn enroute(plan: &FlightPlan) -> Option<Duration> {
+    let number = |value: &str| match value.trim() {
+        "" => Some(0),
+        value => value.parse::<i64>().ok().filter(|n| *n >= 0),
+    };
+    let minutes = number(&plan.hours_enroute)? * 60 + number(&plan.minutes_enroute)?;
+    (minutes > 0).then(|| Duration::minutes(minutes))
+}
+
+/// The filed departure time as HHMM UTC, on whichever day puts it within
+/// twelve hours of `now`
+pub fn planned_departure(plan: &FlightPlan, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let text = plan.departure_time.trim();
+    if text.len() != 4 || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let time = NaiveTime::from_hms_opt(text[..2].parse().ok()?, text[2..].parse().ok()?, 0)?;
+    let departure = now.date_naive().and_time(time).and_utc();
+    let twelve_hours = Duration::hours(12);
+    Some(if departure - now > twelve_hours {
+        departure - Duration::days(1)
+    } else if now - departure > twelve_hours {
+        departure + Duration::days(1)
+    } else {
+        departure
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const ZBAA: (f64, f64) = (40.0801, 116.5846);
+    const ZSPD: (f64, f64) = (31.1434, 121.8052);
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 6, 1, hour, minute, 0).unwrap()
+    }
+
+    fn plan(departure_time: &str, hours: &str, minutes: &str) -> FlightPlan {
+        FlightPlan {
+            departure: "ZBAA".into(),
+            destination: "ZSPD".into(),
+            departure_time: departure_time.into(),
+            hours_enroute: hours.into(),
+            minutes_enroute: minutes.into(),
+            ..FlightPlan::default()
+        }
+    }
+
+    fn planned(time: DateTime<Utc>) -> Option<Eta> {
+        Some(Eta {
+            time,
+            basis: EtaBasis::Planned,
+        })
+    }
+
+    #[test]
+    fn test_planned_before_departure() {
+        let plan = plan("1130", "1", "55");
+        let progress = FlightProgress::default();
+        let eta = |now| eta(&plan, &progress, Position::default(), Some(ZSPD), now);
+        assert_eq!(eta(at(10, 0)), planned(at(13, 25)));
+        // Late, so it can't leave before now
+        assert_eq!(eta(at(12, 0)), planned(at(13, 55)));
+    }
+
+    #[test]
+    fn test_planned_from_off_block() {
+        let plan = plan("1130", "1", "55");
+        let mut progress = FlightProgress::default();
+        progress.update(Some(0), at(11, 40));
+        progress.update(Some(15), at(11, 45));
+        assert_eq!(progress.off_block, None);
+        progress.update(Some(140), at(11, 50));
+        progress.update(Some(20), at(11, 51));
+        assert_eq!(progress.off_block, Some(at(11, 50)));
+
+        // No destination coordinates, so the plan is all there is
+        let position = Position {
+            point: Some(ZBAA),
+            groundspeed: Some(20),
+        };
+        assert_eq!(
+            eta(&plan, &progress, position, None, at(11, 51)),
+            planned(at(13, 45))
+        );
+    }
+
+    #[test]
+    fn test_actual_from_distance_left() {
+        let plan = plan("1130", "1", "55");
+        let mut progress = FlightProgress::default();
+        progress.update(Some(452), at(12, 0));
+        let position = Position {
+            point: Some(ZBAA),
+            groundspeed: Some(452),
+        };
+
+        let eta = eta(&plan, &progress, position, Some(ZSPD), at(12, 0)).unwrap();
+        assert_eq!(eta.basis, EtaBasis::Actual);
+        // About 583nm at 452kt
+        let minutes = (eta.time - at(12, 0)).num_minutes();
+        assert!((76..=78).contains(&minutes), "{} minutes", minutes);
+    }
+
+    #[test]
+    fn test_stale_position_falls_back_to_plan() {
+        let plan = plan("1130", "1", "55");
+        let mut progress = FlightProgress::default();
+        progress.update(Some(452), at(12, 0));
+        let position = Position {
+            point: Some(ZBAA),
+            groundspeed: Some(452),
+        };
+        assert_eq!(
+            eta(&plan, &progress, position, Some(ZSPD), at(12, 5)),
+            planned(at(13, 55))
+        );
+    }
+
+    #[test]
+    fn test_nothing_to_go_on() {
+        let progress = FlightProgress::default();
+        let none = |plan: FlightPlan, now| {
+            assert_eq!(
+                eta(&plan, &progress, Position::default(), Some(ZSPD), now),
+                None
+            )
+        };
+        // No time enroute or departure time, or an unreadable one
+        none(plan("1130", "", ""), at(10, 0));
+        none(plan("1130", "0", "0"), at(10, 0));
+        none(plan("1130", "x", "30"), at(10, 0));
+        none(plan("", "1", "55"), at(10, 0));
+        none(plan("2460", "1", "55"), at(10, 0));
+        none(plan("11:30", "1", "55"), at(10, 0));
+
+        // Filed arrival already passed
+        let mut progress = FlightProgress::default();
+        progress.update(Some(140), at(11, 30));
+        let plan = plan("1130", "1", "55");
+        assert_eq!(
+            eta(&plan, &progress, Position::default(), None, at(14, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_planned_departure_day() {
+        // Just after midnight, a departure late in the evening was yesterday
+        let now = Utc.with_ymd_and_hms(2025, 6, 2, 0, 30, 0).unwrap();
+        assert_eq!(
+            planned_departure(&plan("2350", "", ""), now),
+            Some(Utc.with_ymd_and_hms(2025, 6, 1, 23, 50, 0).unwrap())
+        );
+        // ...and late in the evening, an early departure is tomorrow
+        assert_eq!(
+            planned_departure(&plan("0015", "", ""), at(23, 0)),
+            Some(Utc.with_ymd_and_hms(2025, 6, 2, 0, 15, 0).unwrap())
+        );
+        assert_eq!(
+            planned_departure(&plan("1130", "", ""), at(10, 0)),
+            Some(at(11, 30))
+        );
+    }
+}
@@ -0,0 +1,576 @@
+//! Async FSD client for tools, bots and tests
+//!
+//! [`FsdClient`] performs the login handshake and sends the common packets
+//! as typed calls. Everything the server sends can be read as parsed
+//! [`Packet`]s through its [`Stream`] implementation or
+//! [`FsdClient::next_packet`]. CAPS requests, `$PI` pings and auth
+//! challenges addressed to the client are answered in the background, so a
+//! client that only sends still looks alive to the server.
+//!
+//! ```no_run
+//! use openfsd::fsd_client::{FsdClient, Login};
+//!
+//! # async fn example() -> Result<(), openfsd::fsd_client::FsdClientError> {
+//! let mut client = FsdClient::connect("127.0.0.1:6809").await?;
+//! client
+//!     .login_pilot(&Login::new("DLH123", "1234567", "password"))
+//!     .await?;
+//! client.send_text("*", "Hello").await?;
+//! while let Some(packet) = client.next_packet().await {
+//!     println!("{}", packet);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::auth::challenge::compute_response;
+use crate::flight_plan::FlightPlan;
+use crate::packet::{Packet, PacketType};
+use futures_core::Stream;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// How long the server may take to accept a login or answer a request
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Client ID sent in $ID unless the login names another
+pub const DEFAULT_CLIENT_ID: &str = "69d7";
+
+#[derive(Debug, Error)]
+pub enum FsdClientError {
+    #[error("Connection failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Server closed the connection")]
+    Closed,
+    #[error("No answer from the server to the {0} within {REPLY_TIMEOUT:?}")]
+    Timeout(&'static str),
+    #[error("Server refused with error {code}: {message}")]
+    Refused { code: String, message: String },
+    #[error("Not logged in")]
+    NotLoggedIn,
+}
+
+/// Who to log in as, and with which client software
+#[derive(Debug, Clone)]
+pub struct Login {
+    pub callsign: String,
+    /// Network ID
+    pub cid: String,
+    pub password: String,
+    pub real_name: String,
+    /// Pilot or controller rating
+    pub rating: u8,
+    /// Client ID sent in $ID; it must be whitelisted unless enforcement is off
+    pub client_id: String,
+    /// Client software name sent in $ID
+    pub client_name: String,
+    /// Key for answering the server's auth challenge
+    pub client_key: Option<String>,
+}
+
+impl Login {
+    /// Log in as `callsign` with the default client software
+    pub fn new(callsign: &str, cid: &str, password: &str) -> Self {
+        Self {
+            callsign: callsign.to_string(),
+            cid: cid.to_string(),
+            password: password.to_string(),
+            real_name: callsign.to_string(),
+            rating: 1,
+            client_id: DEFAULT_CLIENT_ID.to_string(),
+            client_name: "OpenFSD client".to_string(),
+            client_key: None,
+        }
+    }
+
+    /// Identify as the client software `client_id`, answering challenges
+    /// with `client_key` if it has one
+    pub fn with_client(mut self, client_id: &str, client_key: Option<&str>) -> Self {
+        self.client_id = client_id.to_string();
+        self.client_key = client_key.map(str::to_string);
+        self
+    }
+}
+
+/// A pilot position for [`FsdClient::send_position`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Feet
+    pub altitude: i32,
+    /// Knots
+    pub ground_speed: u32,
+    pub squawk: String,
+}
+
+impl Position {
+    /// Standing still at `altitude`, squawking 2000
+    pub fn new(latitude: f64, longitude: f64, altitude: i32) -> Self {
+        Self {
+            latitude,
+            longitude,
+            altitude,
+            ground_speed: 0,
+            squawk: "2000".to_string(),
+        }
+    }
+}
+
+/// The logged in identity, shared with the reader so it can answer
+#[derive(Debug, Default)]
+struct Identity {
+    callsign: Option<String>,
+    cid: String,
+    rating: u8,
+    client_key: Option<String>,
+    /// Callsign the server identified itself with in its $DI
+    server: Option<String>,
+}
+
+/// A connection to an FSD server
+pub struct FsdClient {
+    writer: Arc<tokio::sync::Mutex<OwnedWriteHalf>>,
+    identity: Arc<Mutex<Identity>>,
+    incoming: mpsc::UnboundedReceiver<Packet>,
+    /// Packets read while waiting for a reply, handed out before new ones
+    pending: VecDeque<Packet>,
+    reader: JoinHandle<()>,
+}
+
+impl FsdClient {
+    /// Connect to the server at `addr`
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self, FsdClientError> {
+        let stream = TcpStream::connect(addr).await?;
+        let (reader, writer) = stream.into_split();
+        let writer = Arc::new(tokio::sync::Mutex::new(writer));
+        let identity = Arc::new(Mutex::new(Identity::default()));
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read_packets(
+            reader,
+            writer.clone(),
+            identity.clone(),
+            incoming_tx,
+        ));
+        Ok(Self {
+            writer,
+            identity,
+            incoming,
+            pending: VecDeque::new(),
+            reader,
+        })
+    }
+
+    /// The callsign logged in with, if any
+    pub fn callsign(&self) -> Option<String> {
+        self.identity.lock().unwrap().callsign.clone()
+    }
+
+    /// Log in as a pilot and wait until the server accepts it
+    pub async fn login_pilot(&mut self, login: &Login) -> Result<(), FsdClientError> {
+        // #AP(callsign):SERVER:(network ID):(password):(rating):(protocol version):(num2):(full name)
+        let packet = Packet {
+            packet_type: PacketType::Client,
+            command: "AP".to_string(),
+            source: login.callsign.clone(),
+            destination: "SERVER".to_string(),
+            data: vec![
+                login.cid.clone(),
+                login.password.clone(),
+                login.rating.to_string(),
+                "100".to_string(),
+                "2".to_string(),
+                login.real_name.clone(),
+            ],
+        };
+        self.login(login, packet).await
+    }
+
+    /// Log in as a controller and wait until the server accepts it
+    pub async fn login_atc(&mut self, login: &Login) -> Result<(), FsdClientError> {
+        // #AA(callsign):SERVER:(full name):(network ID):(password):(rating):(protocol version)
+        let packet = Packet {
+            packet_type: PacketType::Client,
+            command: "AA".to_string(),
+            source: login.callsign.clone(),
+            destination: "SERVER".to_string(),
+            data: vec![
+                login.real_name.clone(),
+                login.cid.clone(),
+                login.password.clone(),
+                login.rating.to_string(),
+                "100".to_string(),
+            ],
+        };
+        self.login(login, packet).await
+    }
+
+    /// Identify, send the login packet and wait for the server's CAPS
+    /// request, which follows a successful login
+    async fn login(&mut self, login: &Login, packet: Packet) -> Result<(), FsdClientError> {
+        {
+            let mut identity = self.identity.lock().unwrap();
+            *identity = Identity {
+                callsign: Some(login.callsign.clone()),
+                cid: login.cid.clone(),
+                rating: login.rating,
+                client_key: login.client_key.clone(),
+                server: identity.server.take(),
+            };
+        }
+
+        // $ID(callsign):SERVER:(client ID):(client name):(major):(minor):(network ID):(unique ID)
+        let identification = Packet {
+            packet_type: PacketType::Request,
+            command: "ID".to_string(),
+            source: login.callsign.clone(),
+            destination: "SERVER".to_string(),
+            data: vec![
+                login.client_id.clone(),
+                login.client_name.clone(),
+                "3".to_string(),
+                "2".to_string(),
+                login.cid.clone(),
+                rand::thread_rng()
+                    .gen_range(100_000_000..999_999_999u32)
+                    .to_string(),
+            ],
+        };
+        self.send(&identification).await?;
+        self.send(&packet).await?;
+
+        let callsign = login.callsign.clone();
+        let identity = self.identity.clone();
+        let reply = self
+            .wait_for("login", |packet| {
+                let server = identity.lock().unwrap().server.clone();
+                packet.destination == callsign
+                    && ((packet.command == "CQ"
+                        && packet
+                            .source
+                            .eq_ignore_ascii_case(server.as_deref().unwrap_or("SERVER"))
+                        && packet.data.first().is_some_and(|d| d == "CAPS"))
+                        || is_error(packet))
+            })
+            .await;
+        match reply {
+            Ok(reply) if is_error(&reply) => {
+                self.identity.lock().unwrap().callsign = None;
+                Err(refused(&reply))
+            }
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.identity.lock().unwrap().callsign = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Log off, leaving the connection open
+    pub async fn logoff(&mut self) -> Result<(), FsdClientError> {
+        let callsign = self.logged_in()?;
+        let cid = self.identity.lock().unwrap().cid.clone();
+        // #DP(callsign):(network ID)
+        self.send(&Packet {
+            packet_type: PacketType::Client,
+            command: "DP".to_string(),
+            source: callsign,
+            destination: cid,
+            data: Vec::new(),
+        })
+        .await?;
+        self.identity.lock().unwrap().callsign = None;
+        Ok(())
+    }
+
+    /// Report the aircraft at `position`
+    pub async fn send_position(&mut self, position: &Position) -> Result<(), FsdClientError> {
+        let callsign = self.logged_in()?;
+        let rating = self.identity.lock().unwrap().rating;
+        // @N:(callsign):(squawk):(rating):(lat):(lon):(alt):(groundspeed):(pbh):(flags)
+        self.send(&Packet {
+            packet_type: PacketType::PilotUpdate,
+            command: "N".to_string(),
+            source: String::new(),
+            destination: callsign,
+            data: vec![
+                position.squawk.clone(),
+                rating.to_string(),
+                format!("{:.5}", position.latitude),
+                format!("{:.5}", position.longitude),
+                position.altitude.to_string(),
+                position.ground_speed.to_string(),
+                "0".to_string(),
+                "0".to_string(),
+            ],
+        })
+        .await
+    }
+
+    /// Send a text message to a callsign, a frequency like "@22800" or "*"
+    pub async fn send_text(&mut self, to: &str, message: &str) -> Result<(), FsdClientError> {
+        let callsign = self.logged_in()?;
+        self.send(&Packet {
+            packet_type: PacketType::Client,
+            command: "TM".to_string(),
+            source: callsign,
+            destination: to.to_string(),
+            data: vec![message.to_string()],
+        })
+        .await
+    }
+
+    /// File `plan` for the logged in callsign
+    pub async fn file_flight_plan(&mut self, plan: &FlightPlan) -> Result<(), FsdClientError> {
+        let callsign = self.logged_in()?;
+        self.send(&plan.to_packet(&callsign)).await
+    }
+
+    /// Ask the server for the METAR of `icao` and wait for it
+    pub async fn request_metar(&mut self, icao: &str) -> Result<String, FsdClientError> {
+        let callsign = self.logged_in()?;
+        // $AX(callsign):SERVER:METAR:(ICAO)
+        self.send(&Packet {
+            packet_type: PacketType::Request,
+            command: "AX".to_string(),
+            source: callsign.clone(),
+            destination: "SERVER".to_string(),
+            data: vec!["METAR".to_string(), icao.to_string()],
+        })
+        .await?;
+
+        let reply = self
+            .wait_for("METAR request", |packet| {
+                packet.destination == callsign
+                    && ((packet.command == "AR"
+                        && packet.data.first().is_some_and(|d| d == "METAR"))
+                        || (is_error(packet)
+                            && packet
+                                .data
+                                .get(1)
+                                .is_some_and(|d| d.eq_ignore_ascii_case(icao))))
+            })
+            .await?;
+        if is_error(&reply) {
+            return Err(refused(&reply));
+        }
+        Ok(reply.data[1..].join(":"))
+    }
+
+    /// Send any packet as it is
+    pub async fn send(&self, packet: &Packet) -> Result<(), FsdClientError> {
+        write_packet(&self.writer, packet).await
+    }
+
+    /// The next packet from the server, or `None` once it has closed the
+    /// connection
+    pub async fn next_packet(&mut self) -> Option<Packet> {
+        match self.pending.pop_front() {
+            Some(packet) => Some(packet),
+            None => self.incoming.recv().await,
+        }
+    }
+
+    /// Read until `matches` accepts a packet, keeping the others for the
+    /// stream
+    async fn wait_for(
+        &mut self,
+        what: &'static str,
+        mut matches: impl FnMut(&Packet) -> bool,
+    ) -> Result<Packet, FsdClientError> {
+        let deadline = tokio::time::Instant::now() + REPLY_TIMEOUT;
+        let mut skipped = Vec::new();
+        let result = loop {
+            match tokio::time::timeout_at(deadline, self.incoming.recv()).await {
+                Ok(Some(packet)) if matches(&packet) => break Ok(packet),
+                Ok(Some(packet)) => skipped.push(packet),
+                Ok(None) => break Err(FsdClientError::Closed),
+                Err(_) => break Err(FsdClientError::Timeout(what)),
+            }
+        };
+        self.pending.extend(skipped);
+        result
+    }
+
+    fn logged_in(&self) -> Result<String, FsdClientError> {
+        self.callsign().ok_or(FsdClientError::NotLoggedIn)
+    }
+}
+
+impl Stream for FsdClient {
+    type Item = Packet;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Packet>> {
+        if let Some(packet) = self.pending.pop_front() {
+            return Poll::Ready(Some(packet));
+        }
+        self.incoming.poll_recv(cx)
+    }
+}
+
+impl Drop for FsdClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn write_packet(
+    writer: &tokio::sync::Mutex<OwnedWriteHalf>,
+    packet: &Packet,
+) -> Result<(), FsdClientError> {
+    let mut writer = writer.lock().await;
+    writer.write_all(packet.format().as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Pass on every parsed packet, answering the ones a client must answer
+async fn read_packets(
+    reader: OwnedReadHalf,
+    writer: Arc<tokio::sync::Mutex<OwnedWriteHalf>>,
+    identity: Arc<Mutex<Identity>>,
+    incoming: mpsc::UnboundedSender<Packet>,
+) {
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    while matches!(reader.read_line(&mut line).await, Ok(n) if n > 0) {
+        if let Ok(packet) = Packet::parse(&line) {
+            // $DI(server):CLIENT:(banner):(token)
+            if packet.command == "DI" {
+                identity.lock().unwrap().server = Some(packet.destination.clone());
+            }
+            let answer = answer(&packet, &identity.lock().unwrap());
+            if let Some(answer) = answer {
+                if write_packet(&writer, &answer).await.is_err() {
+                    break;
+                }
+            }
+            if incoming.send(packet).is_err() {
+                break;
+            }
+        }
+        line.clear();
+    }
+}
+
+/// The reply to a CAPS request, ping or auth challenge for the logged in
+/// callsign
+fn answer(packet: &Packet, identity: &Identity) -> Option<Packet> {
+    let callsign = identity.callsign.as_deref()?;
+    if !packet.destination.eq_ignore_ascii_case(callsign) {
+        return None;
+    }
+    let reply = |command: &str, data: Vec<String>| Packet {
+        packet_type: PacketType::Request,
+        command: command.to_string(),
+        source: callsign.to_string(),
+        destination: packet.source.clone(),
+        data,
+    };
+    match (packet.command.as_str(), packet.data.first()) {
+        // $CQSERVER:(callsign):CAPS
+        ("CQ", Some(kind)) if kind == "CAPS" => Some(reply(
+            "CR",
+            ["CAPS", "ATCINFO=1", "MODELDESC=1", "ACCONFIG=1"]
+                .map(str::to_string)
+                .to_vec(),
+        )),
+        // $PI(from):(callsign):(timestamp)
+        ("PI", _) => Some(reply("PO", packet.data.clone())),
+        // $ZC(from):(callsign):(challenge)
+        ("ZC", Some(challenge)) => {
+            let key = identity.client_key.as_deref()?;
+            Some(reply("ZR", vec![compute_response(key, challenge)]))
+        }
+        _ => None,
+    }
+}
+
+/// $ER(from):(callsign):(code):(subject):(message), except the notice that
+/// a pilot has no flight plan yet
+fn is_error(packet: &Packet) -> bool {
+    packet.command == "ER" && packet.data.first().is_some_and(|code| code != "008")
+}
+
+fn refused(packet: &Packet) -> FsdClientError {
+    FsdClientError::Refused {
+        code: packet.data.first().cloned().unwrap_or_default(),
+        message: packet.data.get(2).cloned().unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged_in(client_key: Option<&str>) -> Identity {
+        Identity {
+            callsign: Some("DLH123".to_string()),
+            cid: "1234567".to_string(),
+            rating: 1,
+            client_key: client_key.map(str::to_string),
+            server: None,
+        }
+    }
+
+    fn answer_to(raw: &str, identity: &Identity) -> Option<String> {
+        answer(&Packet::parse(raw).unwrap(), identity).map(|p| p.format())
+    }
+
+    #[test]
+    fn test_answers() {
+        let identity = logged_in(Some("key"));
+        assert_eq!(
+            answer_to("$CQSERVER:DLH123:CAPS", &identity).unwrap(),
+            "$CRDLH123:SERVER:CAPS:ATCINFO=1:MODELDESC=1:ACCONFIG=1\r\n"
+        );
+        assert_eq!(
+            answer_to("$PIEDDF_TWR:DLH123:12345", &identity).unwrap(),
+            "$PODLH123:EDDF_TWR:12345\r\n"
+        );
+        assert_eq!(
+            answer_to("$ZCSERVER:DLH123:0123456789abcdef", &identity).unwrap(),
+            format!(
+                "$ZRDLH123:SERVER:{}\r\n",
+                compute_response("key", "0123456789abcdef")
+            )
+        );
+    }
+
+    #[test]
+    fn test_no_answer() {
+        let identity = logged_in(None);
+        // No key to answer with
+        assert!(answer_to("$ZCSERVER:DLH123:0123456789abcdef", &identity).is_none());
+        // Someone else's ping
+        assert!(answer_to("$PIEDDF_TWR:BAW456:12345", &identity).is_none());
+        assert!(answer_to("#TMserver:DLH123:Welcome", &identity).is_none());
+        // Not logged in yet
+        assert!(answer_to("$PIEDDF_TWR:DLH123:12345", &Identity::default()).is_none());
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = Packet::parse("$ERserver:DLH123:003::Invalid password").unwrap();
+        assert!(is_error(&error));
+        match refused(&error) {
+            FsdClientError::Refused { code, message } => {
+                assert_eq!(code, "003");
+                assert_eq!(message, "Invalid password");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let notice = Packet::parse("$ERserver:DLH123:008:DLH123:No flightplan").unwrap();
+        assert!(!is_error(&notice));
+    }
+}
@@ -0,0 +1,213 @@
+//! Minimal HTTP/1.0 client for the weather providers and webhooks
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use thiserror::Error;
+use url::Url;
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("No answer in time")]
+    Timeout,
+    #[error("HTTP {0}")]
+    Status(u16),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+/// Fetch `url` and return the body of a 200 response
+pub async fn get(
+    url: &str,
+    header: Option<(&str, &str)>,
+    timeout: Duration,
+) -> Result<String, HttpError> {
+    let header = header
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .unwrap_or_default();
+    let (status, body) = request(
+        url,
+        "GET",
+        format!("Accept: text/plain\r\n{}", header),
+        String::new(),
+        timeout,
+    )
+    .await?;
+    if status != 200 {
+        return Err(HttpError::Status(status));
+    }
+    Ok(body)
+}
+
+/// POST a JSON document to `url`; any 2xx answer counts as delivered
+pub async fn post_json(url: &str, body: String, timeout: Duration) -> Result<(), HttpError> {
+    let headers = format!(
+        "Content-Type: application/json\r\nContent-Length: {}\r\n",
+        body.len()
+    );
+    let (status, _) = request(url, "POST", headers, body, timeout).await?;
+    if !(200..300).contains(&status) {
+        return Err(HttpError::Status(status));
+    }
+    Ok(())
+}
+
+/// Send one request and return the status and body of the answer
+///
+/// HTTP/1.0 is used so responses are never chunked. The whole exchange,
+/// including DNS and the TLS handshake, must finish within `timeout`.
+async fn request(
+    url: &str,
+    method: &'static str,
+    headers: String,
+    body: String,
+    timeout: Duration,
+) -> Result<(u16, String), HttpError> {
+    let url = Url::parse(url).map_err(|e| HttpError::InvalidUrl(format!("{}: {}", url, e)))?;
+
+    // The socket timeouts end the blocking task soon after we give up on it
+    let exchange = tokio::task::spawn_blocking(move || {
+        request_blocking(&url, method, &headers, &body, timeout)
+    });
+    match tokio::time::timeout(timeout, exchange).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => Err(HttpError::Io(io::Error::other(e))),
+        Err(_) => Err(HttpError::Timeout),
+    }
+}
+
+fn request_blocking(
+    url: &Url,
+    method: &str,
+    headers: &str,
+    body: &str,
+    timeout: Duration,
+) -> Result<(u16, String), HttpError> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| HttpError::InvalidUrl(url.to_string()))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| HttpError::InvalidUrl(url.to_string()))?;
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| HttpError::InvalidUrl(url.to_string()))?;
+
+    let stream = TcpStream::connect_timeout(&addr, timeout).map_err(timeout_error)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: OpenFSD/{}\r\n{}\r\n{}",
+        method,
+        path,
+        host_header,
+        env!("CARGO_PKG_VERSION"),
+        headers,
+        body
+    );
+
+    let response = match url.scheme() {
+        "http" => exchange(stream, &request),
+        "https" => {
+            let name = ServerName::try_from(host.to_string())
+                .map_err(|_| HttpError::InvalidUrl(url.to_string()))?;
+            let connection = ClientConnection::new(tls_config(), name).map_err(io::Error::other)?;
+            exchange(StreamOwned::new(connection, stream), &request)
+        }
+        scheme => {
+            return Err(HttpError::InvalidUrl(format!(
+                "unsupported scheme {}",
+                scheme
+            )))
+        }
+    }
+    .map_err(timeout_error)?;
+
+    parse_response(&response)
+}
+
+/// Send the request and read until the server closes the connection
+fn exchange<S: Read + Write>(mut stream: S, request: &str) -> io::Result<Vec<u8>> {
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response) {
+        Ok(_) => Ok(response),
+        // Many servers close TLS connections without a close_notify
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !response.is_empty() => Ok(response),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_response(response: &[u8]) -> Result<(u16, String), HttpError> {
+    let response = String::from_utf8_lossy(response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response"))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP status"))?;
+    Ok((status, body.to_string()))
+}
+
+fn timeout_error(e: io::Error) -> HttpError {
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => HttpError::Timeout,
+        _ => HttpError::Io(e),
+    }
+}
+
+fn tls_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            let config = ClientConfig::builder_with_provider(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+            Arc::new(config)
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let (status, body) =
+            parse_response(b"HTTP/1.1 200 OK\r\nServer: test\r\n\r\nZBAA 121200Z\n").unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, "ZBAA 121200Z\n");
+        assert_eq!(
+            parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n").unwrap(),
+            (404, String::new())
+        );
+        assert!(matches!(parse_response(b"garbage"), Err(HttpError::Io(_))));
+    }
+}
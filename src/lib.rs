@@ -1,6 +1,80 @@
+//! OpenFSD, an FSD protocol server for flight simulation networks
+//!
+//! Besides the `openfsd` binary the crate can run a server inside another
+//! program. Build one with [`ServerBuilder`] and stop it with its
+//! [`CancellationToken`](server::CancellationToken):
+//!
+//! ```
+//! use openfsd::config::Config;
+//! use openfsd::server::{CancellationToken, ServerBuilder};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), openfsd::ServerError> {
+//! let mut config = Config::default();
+//! config.server.address = "127.0.0.1".to_string();
+//! # config.server.port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+//! let shutdown = CancellationToken::new();
+//! let server = ServerBuilder::new()
+//!     .with_config(config)
+//!     .with_database(openfsd::db::init_ephemeral().await?)
+//!     .with_shutdown_token(shutdown.clone())
+//!     .build()?;
+//!
+//! // Normally cancelled on a signal; run() then saves statistics and returns
+//! shutdown.cancel();
+//! server.run().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The protocol itself is in [`packet`]:
+//!
+//! ```
+//! use openfsd::packet::{Packet, PacketType};
+//!
+//! let packet = Packet::parse("#TMEDDF_TWR:DLH123:Contact ground").unwrap();
+//! assert_eq!(packet.packet_type, PacketType::Client);
+//! assert_eq!(packet.command, "TM");
+//! assert_eq!(packet.source, "EDDF_TWR");
+//! assert_eq!(packet.format(), "#TMEDDF_TWR:DLH123:Contact ground\r\n");
+//! ```
+
+/// Airport positions and elevations, looked up by ICAO code
+pub mod airports;
 pub mod auth;
+pub mod callsign;
+pub mod capture;
+/// Connected clients as the server tracks them
 pub mod client;
+/// Configuration file, environment and command line settings
 pub mod config;
+pub mod config_docs;
+pub mod datafeed;
+/// The error type of the server
+pub mod error;
+/// Database connection, migrations, entities and queries
 pub mod db;
+pub mod flight_plan;
+pub mod flight_progress;
+/// Async client for tools, bots and tests
+pub mod fsd_client;
+pub(crate) mod http_client;
+pub mod logging;
+pub(crate) mod map;
+pub(crate) mod metrics;
+pub mod motd;
+/// FSD packets and their text form
 pub mod packet;
+pub mod plane_info;
+/// The server and what it needs to run
 pub mod server;
+pub mod stats;
+#[cfg(feature = "telemetry")]
+pub(crate) mod telemetry;
+pub mod tracks;
+pub mod weather;
+pub(crate) mod webhooks;
+pub(crate) mod whazzup;
+
+pub use error::ServerError;
+pub use server::{Server, ServerBuilder};
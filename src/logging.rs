@@ -0,0 +1,413 @@
+use crate::config::{LogFormat, LogRotation, LoggingConfig, TelemetryConfig};
+#[cfg(feature = "telemetry")]
+use crate::telemetry::{Telemetry, TelemetryError};
+use flexi_logger::writers::{FileLogWriter, LogWriter};
+use flexi_logger::{
+    Age, Cleanup, Criterion, DeferredNow, FileSpec, FlexiLoggerError, Naming, Record,
+};
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter, ParseError};
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::{SubscriberInitExt, TryInitError};
+use tracing_subscriber::Layer;
+
+#[derive(Debug, Error)]
+pub enum LoggingError {
+    #[error("Invalid log filter: {0}")]
+    Filter(#[from] ParseError),
+    #[error("Failed to open the log file: {0}")]
+    File(#[from] FlexiLoggerError),
+    #[error("Failed to install the logger: {0}")]
+    Init(#[from] TryInitError),
+    #[cfg(feature = "telemetry")]
+    #[error("Failed to start the telemetry export: {0}")]
+    Telemetry(#[from] TelemetryError),
+}
+
+/// Flushes the pending spans and closes the log file when dropped
+pub struct LogGuard {
+    file: Option<FileOutput>,
+    #[cfg(feature = "telemetry")]
+    telemetry: Option<Telemetry>,
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "telemetry")]
+        drop(self.telemetry.take());
+        if let Some(file) = &self.file {
+            let _ = LogWriter::flush(&*file.0);
+            file.0.shutdown();
+        }
+    }
+}
+
+/// Start logging to the console and, if configured, to a file and the
+/// OpenTelemetry export
+///
+/// `filter` is the level or `RUST_LOG`-style filter for every output; the
+/// console and file levels of `config` can only narrow it. Records from
+/// dependencies that use the `log` crate go through the same filter. Keep
+/// the returned guard alive until the process exits so the file and the
+/// export are flushed.
+pub fn init(
+    config: &LoggingConfig,
+    telemetry: &TelemetryConfig,
+    filter: &str,
+) -> Result<LogGuard, LoggingError> {
+    let (subscriber, guard) = subscriber(config, telemetry, filter)?;
+    subscriber.try_init()?;
+    Ok(guard)
+}
+
+/// The subscriber `init` installs, without installing it
+///
+/// Without the `telemetry` feature `telemetry` is ignored; the configuration
+/// check reports it if it is enabled.
+pub fn subscriber(
+    config: &LoggingConfig,
+    telemetry: &TelemetryConfig,
+    filter: &str,
+) -> Result<(impl Subscriber + Send + Sync, LogGuard), LoggingError> {
+    let filter = EnvFilter::try_new(filter)?;
+    let console = layer(
+        config.format,
+        io::stderr,
+        parse_level(config.console_level.as_deref()),
+    );
+    let file = match &config.file {
+        Some(_) => Some(FileOutput(Arc::new(file_writer(config)?))),
+        None => None,
+    };
+    let file_layer = file.clone().map(|output| {
+        layer(
+            config.format,
+            move || output.clone(),
+            parse_level(config.file_level.as_deref()),
+        )
+    });
+
+    #[cfg(feature = "telemetry")]
+    let telemetry = match telemetry.enabled {
+        true => Some(Telemetry::start(telemetry)?),
+        false => None,
+    };
+    #[cfg(feature = "telemetry")]
+    let export = telemetry.as_ref().map(Telemetry::layer);
+    #[cfg(not(feature = "telemetry"))]
+    let export: Option<Box<dyn Layer<_> + Send + Sync>> = {
+        let _ = telemetry;
+        None
+    };
+
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(console)
+        .with(file_layer)
+        .with(export);
+    Ok((
+        subscriber,
+        LogGuard {
+            file,
+            #[cfg(feature = "telemetry")]
+            telemetry,
+        },
+    ))
+}
+
+/// One output in the configured format, limited to `level`
+pub(crate) fn layer<S, W>(
+    format: LogFormat,
+    writer: W,
+    level: LevelFilter,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(false);
+    match format {
+        LogFormat::Text => layer.event_format(TextFormat).with_filter(level).boxed(),
+        LogFormat::Json => layer
+            .fmt_fields(JsonFields::new())
+            .event_format(JsonFormat)
+            .with_filter(level)
+            .boxed(),
+    }
+}
+
+/// Writer for the log file, rotated and cleaned up as configured
+fn file_writer(config: &LoggingConfig) -> Result<FileLogWriter, FlexiLoggerError> {
+    let path = config.file.as_deref().unwrap_or("openfsd.log".as_ref());
+    let builder = FileLogWriter::builder(FileSpec::try_from(path)?)
+        .format(formatted_line)
+        .append();
+    let criterion = match config.rotation {
+        LogRotation::Daily => Criterion::Age(Age::Day),
+        LogRotation::Size => Criterion::Size(config.max_size_mb * 1024 * 1024),
+        LogRotation::Never => return builder.try_build(),
+    };
+    builder
+        .rotate(
+            criterion,
+            Naming::Timestamps,
+            Cleanup::KeepLogFiles(config.keep_files),
+        )
+        .try_build()
+}
+
+/// Events reach the file writer already formatted
+fn formatted_line(w: &mut dyn Write, _now: &mut DeferredNow, record: &Record) -> io::Result<()> {
+    write!(w, "{}", record.args())
+}
+
+/// Hands each formatted event to the file writer, which adds the line end
+#[derive(Clone)]
+struct FileOutput(Arc<FileLogWriter>);
+
+impl Write for FileOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        self.0.write(
+            &mut DeferredNow::new(),
+            &Record::builder().args(format_args!("{}", line)).build(),
+        )?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        LogWriter::flush(&*self.0)
+    }
+}
+
+/// Level a sink is limited to; unset means no limit beyond the filter
+fn parse_level(level: Option<&str>) -> LevelFilter {
+    level
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::TRACE)
+}
+
+/// `[2024-05-01T12:00:00Z INFO  openfsd::server] conn{addr=.. callsign=..}: message`
+pub(crate) struct TextFormat;
+
+impl<S, N> FormatEvent<S, N> for TextFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        // Records from the `log` crate carry their real target separately
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        write!(
+            writer,
+            "[{} {:<5} {}] ",
+            chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+            metadata.level().as_str(),
+            metadata.target()
+        )?;
+
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                write!(writer, "{}", span.name())?;
+                if let Some(fields) = span.extensions().get::<FormattedFields<N>>() {
+                    if !fields.is_empty() {
+                        write!(writer, "{{{}}}", fields)?;
+                    }
+                }
+                write!(writer, ":")?;
+            }
+            write!(writer, " ")?;
+        }
+
+        ctx.format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+/// One JSON object per line, with `timestamp` (RFC 3339, UTC), `level`,
+/// `target`, `message` and `span` (e.g. `conn:packet`) next to the fields of
+/// the enclosing spans and of the event, so `callsign` or `command` are keys
+/// of their own. A field of the event wins over a span field of the same name.
+pub(crate) struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let mut object = serde_json::Map::new();
+        object.insert(
+            "timestamp".to_string(),
+            chrono::Utc::now()
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                .into(),
+        );
+        object.insert("level".to_string(), metadata.level().as_str().into());
+        object.insert("target".to_string(), metadata.target().into());
+
+        if let Some(scope) = ctx.event_scope() {
+            let mut names = Vec::new();
+            for span in scope.from_root() {
+                names.push(span.name());
+                if let Some(fields) = span.extensions().get::<FormattedFields<JsonFields>>() {
+                    if let Ok(serde_json::Value::Object(fields)) = serde_json::from_str(fields) {
+                        object.extend(fields);
+                    }
+                }
+            }
+            object.insert("span".to_string(), names.join(":").into());
+        }
+
+        event.record(&mut JsonVisitor(&mut object));
+        writeln!(writer, "{}", serde_json::Value::Object(object))
+    }
+}
+
+/// Puts the fields of an event into a JSON object, numbers and booleans
+/// as such and everything else as strings
+struct JsonVisitor<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: serde_json::Value) {
+        // `log` records bring their metadata along as fields
+        if !field.name().starts_with("log.") {
+            self.0.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{:?}", value).into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_level_and_format() {
+        let dir = std::env::temp_dir().join(format!("openfsd-logs-{}", std::process::id()));
+        let path = dir.join("server.log");
+        let config = LoggingConfig {
+            console_level: Some("off".to_string()),
+            file: Some(path.clone()),
+            file_level: Some("warn".to_string()),
+            rotation: LogRotation::Never,
+            ..LoggingConfig::default()
+        };
+
+        let (subscriber, guard) = subscriber(&config, &TelemetryConfig::default(), "info").unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(target: "openfsd::server", "Client connected");
+            tracing::warn!(target: "openfsd::server", "Max clients reached");
+        });
+        drop(guard);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(content.starts_with('['));
+        assert!(content.ends_with("WARN  openfsd::server] Max clients reached\n"));
+        assert_eq!(content.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_json_format() {
+        let output = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let writer = output.clone();
+        let layer = layer(
+            LogFormat::Json,
+            move || OutputWriter(writer.clone()),
+            LevelFilter::INFO,
+        );
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let conn = tracing::info_span!(
+                "conn",
+                addr = "203.0.113.7:50123",
+                callsign = tracing::field::Empty
+            );
+            conn.record("callsign", "CCA1501");
+            let _conn = conn.enter();
+            let _packet = tracing::info_span!("packet", command = "AP").entered();
+            tracing::warn!(target: "openfsd::server", bytes = 52_u64, "Slow {}", "handler");
+        });
+
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "openfsd::server");
+        assert_eq!(line["message"], "Slow handler");
+        assert_eq!(line["span"], "conn:packet");
+        assert_eq!(line["addr"], "203.0.113.7:50123");
+        assert_eq!(line["callsign"], "CCA1501");
+        assert_eq!(line["command"], "AP");
+        assert_eq!(line["bytes"], 52);
+        let timestamp = line["timestamp"].as_str().unwrap();
+        assert!(
+            chrono::DateTime::parse_from_rfc3339(timestamp).is_ok(),
+            "{}",
+            timestamp
+        );
+    }
+
+    #[derive(Clone)]
+    struct OutputWriter(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for OutputWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -1,42 +1,404 @@
-mod auth;
-mod client;
-mod config;
-mod db;
-mod packet;
-mod server;
-
-use server::Server;
-use std::path::Path;
-
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Load configuration
-    let config = if Path::new("config.toml").exists() {
-        config::Config::from_file("config.toml")?
-    } else {
-        log::warn!("config.toml not found, using default configuration");
-        config::Config::default()
+use clap::Parser;
+use openfsd::server::{CancellationToken, Server, ServerBuilder};
+use openfsd::{config, config_docs, db, logging, weather, ServerError};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// A complete FSD server protocol implementation
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Configuration file [default: ./config.toml if it exists]
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Port to listen on
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Address to bind to
+    #[arg(long)]
+    address: Option<String>,
+
+    /// Database connection URL
+    #[arg(long, value_name = "URL")]
+    database_url: Option<String>,
+
+    /// Log level or filter, e.g. "debug" or "info,sea_orm=warn"
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<String>,
+
+    /// Validate the configuration and exit
+    #[arg(long)]
+    check_config: bool,
+
+    /// Use a throwaway in-memory database
+    #[arg(long)]
+    ephemeral: bool,
+
+    /// Don't apply database migrations; refuse to start if any are pending
+    #[arg(long, conflicts_with = "ephemeral")]
+    no_migrate: bool,
+
+    /// Write a commented configuration with every default and exit
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        default_missing_value = config::DEFAULT_CONFIG_PATH
+    )]
+    init: Option<PathBuf>,
+
+    /// Overwrite an existing file with --init
+    #[arg(long, requires = "init")]
+    force: bool,
+
+    /// List every configuration key with its type and default, then exit
+    #[arg(long)]
+    config_docs: bool,
+}
+
+impl Args {
+    fn overrides(&self) -> config::Overrides {
+        config::Overrides {
+            address: self.address.clone(),
+            port: self.port,
+            database_url: self.database_url.clone(),
+            log_level: self.log_level.clone(),
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    if args.config_docs {
+        print!("{}", config_docs::reference());
+        return Ok(());
+    }
+    if let Some(path) = &args.init {
+        if let Err(e) = config_docs::write_template(path, args.force) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        println!("Wrote {}", path.display());
+        return Ok(());
+    }
+
+    // Read the configuration first, as it says how many threads to run and
+    // how to log, but report any problems with it only once the logger is
+    // running
+    let loaded = load_config(&args);
+    let worker_threads = loaded
+        .as_ref()
+        .ok()
+        .and_then(|config| config.runtime.worker_threads);
+    build_runtime(worker_threads)?.block_on(run(args, loaded))
+}
+
+/// A multi-threaded runtime with `worker_threads` threads, or one per CPU
+/// core if unset
+fn build_runtime(worker_threads: Option<usize>) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    // 0 would panic; validation refuses it once the logger is running
+    if let Some(threads) = worker_threads.filter(|&threads| threads > 0) {
+        builder.worker_threads(threads);
+    }
+    builder.enable_all().build()
+}
+
+/// Start logging, check the configuration and run the server
+async fn run(
+    args: Args,
+    loaded: Result<config::Config, config::ConfigError>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut logging_config, mut telemetry_config) = match &loaded {
+        Ok(config) => (config.logging.clone(), config.telemetry.clone()),
+        Err(_) => (
+            config::LoggingConfig {
+                level: args.log_level.clone().unwrap_or_else(|| "info".to_string()),
+                ..config::LoggingConfig::default()
+            },
+            config::TelemetryConfig::default(),
+        ),
     };
+    if args.check_config {
+        logging_config.file = None;
+        telemetry_config.enabled = false;
+    }
+    let _logger = start_logging(&args, &logging_config, &telemetry_config);
 
-    // Initialize logger
-    env_logger::Builder::from_env(
-        env_logger::Env::default().default_filter_or(&config.logging.level),
-    )
-    .init();
+    let config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("{}", explain(&e.into()));
+            std::process::exit(1);
+        }
+    };
+
+    let problems = config.validate();
+    for problem in &problems {
+        tracing::error!("Invalid configuration: {}", problem);
+    }
+    if !problems.is_empty() {
+        std::process::exit(1);
+    }
+    if args.check_config {
+        match &config.path {
+            Some(path) => println!("Configuration in {} is valid", path.display()),
+            None => println!("No configuration file found, defaults are valid"),
+        }
+        return Ok(());
+    }
+
+    match &config.path {
+        Some(path) => tracing::info!("Loaded configuration from {}", path.display()),
+        None => tracing::warn!("config.toml not found, using default configuration"),
+    }
+    for key in &config.unknown_keys {
+        tracing::warn!("Ignoring unknown configuration key: {}", key);
+    }
+
+    tracing::info!("Starting OpenFSD Server...");
+    if let Err(e) = serve(&args, config).await {
+        tracing::error!("{}", explain(&e));
+        std::process::exit(1);
+    }
 
-    log::info!("Starting OpenFSD Server...");
+    Ok(())
+}
 
+/// Open the database and run the server until it fails or is interrupted
+async fn serve(args: &Args, config: config::Config) -> Result<(), ServerError> {
     // Initialize database
-    log::info!("Initializing database...");
-    let db = db::init(&config.database.url).await?;
-    log::info!("Database initialized successfully");
+    tracing::info!("Initializing database...");
+    let db = if args.ephemeral {
+        tracing::warn!("Ephemeral mode: using an in-memory database, all data is lost on exit");
+        db::init_ephemeral().await?
+    } else if args.no_migrate {
+        db::init_without_migrations(&config.database).await?
+    } else {
+        db::init(&config.database).await?
+    };
+    tracing::info!("Database initialized successfully");
+
+    // Make sure a fresh database has someone who can log in
+    db::bootstrap::bootstrap_admin(&db, db::bootstrap::BootstrapSettings::from_env()).await?;
 
     // Create and run server
-    let server_config = config.into();
-    let server = Server::new(server_config, db);
+    let shutdown = CancellationToken::new();
+    let server = ServerBuilder::new()
+        .with_config(config)
+        .with_database(db)
+        .with_shutdown_token(shutdown.clone())
+        .build()?;
+
+    // Carry on with the listener of the server this one takes over from
+    let inherited = openfsd::server::upgrade::inherited_listener()?;
+    let run = async {
+        match inherited {
+            Some(listener) => {
+                tracing::info!("Took over the FSD listener handed to this process");
+                server
+                    .run_with_listener(tokio::net::TcpListener::from_std(listener)?)
+                    .await
+            }
+            None => server.run().await,
+        }
+    };
 
-    // Run the server
-    server.run().await?;
+    // Run the server until it fails or is interrupted; SIGHUP reloads the
+    // settings that can change while running, SIGUSR1 dumps the state and
+    // SIGUSR2 hands the listener to a new process
+    tokio::pin!(run);
+    let mut hangup = UnixSignal::hangup();
+    let mut user_signal = UnixSignal::user_defined1();
+    let mut upgrade_signal = UnixSignal::user_defined2();
+    loop {
+        tokio::select! {
+            result = &mut run => {
+                result?;
+                break;
+            }
+            _ = tokio::signal::ctrl_c(), if !shutdown.is_cancelled() => shutdown.cancel(),
+            _ = hangup.recv() => reload(args, &server),
+            _ = user_signal.recv() => {
+                let dump = server.dump_state();
+                tokio::spawn(async move {
+                    if let Err(e) = dump.await {
+                        tracing::error!("{}", e);
+                    }
+                });
+            }
+            _ = upgrade_signal.recv() => {
+                let handover = server.hand_over();
+                tokio::spawn(async move {
+                    if let Err(e) = handover.await {
+                        tracing::error!("Could not hand the listener over: {}", e);
+                    }
+                });
+            }
+        }
+    }
 
     Ok(())
 }
+
+/// What went wrong, put so the operator knows what to fix
+fn explain(error: &ServerError) -> String {
+    match error {
+        ServerError::Bind { address, source } => {
+            let what = match address.rsplit_once(':') {
+                Some((host, port)) => format!("port {} on {}", port, host),
+                None => address.clone(),
+            };
+            match source.kind() {
+                ErrorKind::AddrInUse => {
+                    format!(
+                        "Cannot listen: {} is already in use, is another server running?",
+                        what
+                    )
+                }
+                ErrorKind::PermissionDenied => format!(
+                    "Cannot listen: not allowed to use {}, ports below 1024 need extra privileges",
+                    what
+                ),
+                ErrorKind::AddrNotAvailable => {
+                    format!("Cannot listen: {} is not an address of this machine", what)
+                }
+                _ => error.to_string(),
+            }
+        }
+        ServerError::Database(e) => format!(
+            "Database unavailable: {}; check [database] url or --database-url",
+            e
+        ),
+        ServerError::Config(e) => format!("Configuration problem: {}", e),
+        ServerError::Build(e) => format!("Cannot start the server: {}", e),
+        ServerError::Metrics(e) => format!(
+            "Cannot start the metrics endpoint: {}; check [metrics] address",
+            e
+        ),
+        ServerError::Io(e) => format!("Network error: {}", e),
+        ServerError::Packet(_) | ServerError::Auth(_) | ServerError::Shutdown => error.to_string(),
+    }
+}
+
+fn load_config(args: &Args) -> Result<config::Config, config::ConfigError> {
+    config::Config::load(
+        args.config.as_deref(),
+        std::env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        }),
+        &args.overrides(),
+    )
+}
+
+/// Start logging as configured, or to the console only if that fails
+fn start_logging(
+    args: &Args,
+    config: &config::LoggingConfig,
+    telemetry: &config::TelemetryConfig,
+) -> Option<logging::LogGuard> {
+    // RUST_LOG beats the file but not --log-level
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(filter) if args.log_level.is_none() => filter,
+        _ => config.level.clone(),
+    };
+    match logging::init(config, telemetry, &filter) {
+        Ok(guard) => Some(guard),
+        Err(e) => {
+            let guard = logging::init(
+                &config::LoggingConfig::default(),
+                &config::TelemetryConfig::default(),
+                "info",
+            )
+            .ok();
+            tracing::error!("Failed to set up logging as configured: {}", e);
+            guard
+        }
+    }
+}
+
+/// Re-read the configuration and apply what the running server supports
+fn reload(args: &Args, server: &Server) {
+    tracing::info!("Reloading configuration...");
+    let config = match load_config(args) {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Keeping the current configuration: {}", e);
+            return;
+        }
+    };
+    let problems = config.validate();
+    if !problems.is_empty() {
+        tracing::error!("Keeping the current configuration: {}", problems.join("; "));
+        return;
+    }
+
+    match weather::WeatherService::from_config(&config.weather) {
+        Ok(weather) => server.reload_weather(weather),
+        Err(e) => tracing::error!("Keeping the current weather settings: {}", e),
+    }
+    server.reload_heartbeat(config.heartbeat);
+    tracing::info!("Configuration reloaded");
+}
+
+/// Unix signal listener; never fires on platforms without signals
+struct UnixSignal {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl UnixSignal {
+    fn hangup() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::SignalKind;
+            Self::listen(SignalKind::hangup(), "Reloading on SIGHUP")
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    fn user_defined1() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::SignalKind;
+            Self::listen(SignalKind::user_defined1(), "Dumping the state on SIGUSR1")
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    fn user_defined2() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::SignalKind;
+            Self::listen(SignalKind::user_defined2(), "Upgrading on SIGUSR2")
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    /// Listen for `kind`; `action` is what it is for, named in the warning
+    /// if it can't be listened for
+    #[cfg(unix)]
+    fn listen(kind: tokio::signal::unix::SignalKind, action: &str) -> Self {
+        let signal = tokio::signal::unix::signal(kind)
+            .map_err(|e| tracing::warn!("{} is unavailable: {}", action, e))
+            .ok();
+        Self { signal }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            if signal.recv().await.is_some() {
+                return;
+            }
+            self.signal = None;
+        }
+        std::future::pending().await
+    }
+}
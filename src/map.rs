@@ -0,0 +1,240 @@
+//! Traffic map as GeoJSON
+//!
+//! A `FeatureCollection` with a `Point` for every logged-in pilot and
+//! controller that has sent a position, for web maps that want to draw the
+//! traffic without reading the full datafeed. Coordinates are
+//! `[longitude, latitude]` as GeoJSON requires. Controllers carry their
+//! visibility range so the map can draw it as a circle.
+
+use crate::client::{format_frequency, Client, ClientType, Facility};
+use crate::server::visibility::effective_range_nm;
+use crate::server::ServerConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum FeatureCollection {
+    FeatureCollection { features: Vec<Feature> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Feature {
+    Feature {
+        geometry: Geometry,
+        properties: Properties,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Geometry {
+    /// `[longitude, latitude]`
+    Point { coordinates: [f64; 2] },
+}
+
+/// What a point stands for, told apart by `kind`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Properties {
+    Pilot {
+        callsign: String,
+        /// Feet
+        altitude: i32,
+        /// Knots
+        groundspeed: u32,
+        /// Degrees
+        heading: u32,
+        on_ground: bool,
+        squawk: String,
+        /// From the filed flight plan, if there is one
+        departure: Option<String>,
+        arrival: Option<String>,
+    },
+    Controller {
+        callsign: String,
+        /// E.g. "TWR"
+        facility: String,
+        /// MHz with three decimals, e.g. "118.300"
+        frequency: Option<String>,
+        range_nm: u32,
+    },
+}
+
+/// The map of the logged-in clients, sorted by callsign
+pub fn build(clients: &HashMap<SocketAddr, Client>, config: &ServerConfig) -> FeatureCollection {
+    let mut listed: Vec<&Client> = clients
+        .values()
+        .filter(|client| client.is_active() && client.callsign.is_some())
+        .collect();
+    listed.sort_by(|a, b| a.callsign.cmp(&b.callsign));
+
+    let features = listed
+        .into_iter()
+        .filter_map(|client| {
+            let properties = match client.client_type {
+                Some(ClientType::Pilot) => pilot(client),
+                Some(ClientType::Atc) => controller(client, config)?,
+                _ => return None,
+            };
+            Some(Feature::Feature {
+                geometry: Geometry::Point {
+                    coordinates: [client.longitude?, client.latitude?],
+                },
+                properties,
+            })
+        })
+        .collect();
+    FeatureCollection::FeatureCollection { features }
+}
+
+fn pilot(client: &Client) -> Properties {
+    let plan = client.flight_plan.as_ref();
+    let airport = |icao: &str| (!icao.is_empty()).then(|| icao.to_string());
+    Properties::Pilot {
+        callsign: client.callsign().unwrap_or_default().to_string(),
+        altitude: client.altitude.unwrap_or_default(),
+        groundspeed: client.groundspeed.unwrap_or_default(),
+        heading: client.heading.unwrap_or_default(),
+        on_ground: client.on_ground.unwrap_or_default(),
+        squawk: client.squawk.clone().unwrap_or_default(),
+        departure: plan.and_then(|plan| airport(&plan.departure)),
+        arrival: plan.and_then(|plan| airport(&plan.destination)),
+    }
+}
+
+/// `None` for an observer, which has nothing to show on a map
+fn controller(client: &Client, config: &ServerConfig) -> Option<Properties> {
+    let facility = match client.facility? {
+        Facility::Observer => return None,
+        Facility::Fss => "FSS",
+        Facility::Delivery => "DEL",
+        Facility::Ground => "GND",
+        Facility::Tower => "TWR",
+        Facility::Approach => "APP",
+        Facility::Center => "CTR",
+    };
+    Some(Properties::Controller {
+        callsign: client.callsign().unwrap_or_default().to_string(),
+        facility: facility.to_string(),
+        frequency: client.frequency.map(format_frequency),
+        range_nm: effective_range_nm(&config.visibility, client),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientState;
+    use crate::flight_plan::FlightPlan;
+    use serde_json::Value;
+
+    /// The parts of RFC 7946 the map uses: a collection of features, each
+    /// with a point geometry and a properties object
+    fn assert_geojson(value: &Value) {
+        assert_eq!(value["type"], "FeatureCollection");
+        for feature in value["features"].as_array().unwrap() {
+            assert_eq!(feature["type"], "Feature");
+            assert!(feature["properties"].is_object(), "{}", feature);
+            let geometry = &feature["geometry"];
+            assert_eq!(geometry["type"], "Point");
+            let coordinates = geometry["coordinates"].as_array().unwrap();
+            assert_eq!(coordinates.len(), 2, "{}", feature);
+            let lon = coordinates[0].as_f64().unwrap();
+            let lat = coordinates[1].as_f64().unwrap();
+            assert!((-180.0..=180.0).contains(&lon), "{}", feature);
+            assert!((-90.0..=90.0).contains(&lat), "{}", feature);
+        }
+    }
+
+    fn keys(properties: &Value) -> Vec<&str> {
+        let mut keys: Vec<&str> = properties
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_map() {
+        let mut pilot = Client::active_for_tests(([127, 0, 0, 1], 1), "CCA1501", ClientType::Pilot);
+        pilot.latitude = Some(31.1434);
+        pilot.longitude = Some(121.8052);
+        pilot.altitude = Some(35000);
+        pilot.groundspeed = Some(460);
+        pilot.heading = Some(275);
+        pilot.squawk = Some("4521".to_string());
+        pilot.flight_plan = Some(FlightPlan {
+            departure: "ZSPD".to_string(),
+            destination: "ZBAA".to_string(),
+            ..FlightPlan::default()
+        });
+        let mut tower = Client::active_for_tests(([127, 0, 0, 1], 2), "ZSPD_TWR", ClientType::Atc);
+        tower.latitude = Some(31.1434);
+        tower.longitude = Some(121.8052);
+        tower.facility = Some(Facility::Tower);
+        tower.frequency = Some(18300);
+        tower.declared_range_nm = Some(30);
+        // Not on the map: no position yet, an observer, not logged in
+        let waiting = Client::active_for_tests(([127, 0, 0, 1], 3), "CES5101", ClientType::Pilot);
+        let mut observer =
+            Client::active_for_tests(([127, 0, 0, 1], 4), "ZSSS_OBS", ClientType::Observer);
+        observer.latitude = Some(31.2);
+        observer.longitude = Some(121.3);
+        let mut connecting =
+            Client::active_for_tests(([127, 0, 0, 1], 5), "CSN3101", ClientType::Pilot);
+        connecting.state = ClientState::Identified;
+        connecting.latitude = Some(23.4);
+        connecting.longitude = Some(113.3);
+
+        let clients = [pilot, tower, waiting, observer, connecting]
+            .into_iter()
+            .map(|client| (client.addr, client))
+            .collect();
+        let map = serde_json::to_value(build(&clients, &ServerConfig::default())).unwrap();
+        assert_geojson(&map);
+
+        let features = map["features"].as_array().unwrap();
+        assert_eq!(features.len(), 2);
+        assert_eq!(
+            features[0]["geometry"]["coordinates"],
+            serde_json::json!([121.8052, 31.1434])
+        );
+        let pilot = &features[0]["properties"];
+        assert_eq!(
+            keys(pilot),
+            [
+                "altitude",
+                "arrival",
+                "callsign",
+                "departure",
+                "groundspeed",
+                "heading",
+                "kind",
+                "on_ground",
+                "squawk"
+            ]
+        );
+        assert_eq!(pilot["kind"], "pilot");
+        assert_eq!(pilot["altitude"], 35000);
+        assert_eq!(pilot["heading"], 275);
+        assert_eq!(pilot["on_ground"], false);
+        assert_eq!(pilot["departure"], "ZSPD");
+        assert_eq!(pilot["arrival"], "ZBAA");
+
+        let tower = &features[1]["properties"];
+        assert_eq!(
+            keys(tower),
+            ["callsign", "facility", "frequency", "kind", "range_nm"]
+        );
+        assert_eq!(tower["kind"], "controller");
+        assert_eq!(tower["facility"], "TWR");
+        assert_eq!(tower["frequency"], "118.300");
+        assert_eq!(tower["range_nm"], 30);
+    }
+}
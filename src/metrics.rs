@@ -0,0 +1,419 @@
+//! Prometheus and OTLP metrics
+//!
+//! The server records through the small functions below, which go to the
+//! `metrics` facade and cost next to nothing until a recorder is installed.
+//! The Prometheus recorder and its HTTP endpoint need the `prometheus` cargo
+//! feature and `[metrics] enabled = true`; with `[telemetry]` running the
+//! same series are also exported over OTLP.
+
+use crate::client::{Client, ClientType};
+use crate::config::MetricsConfig;
+use crate::server::pipeline::PipelineReport;
+use crate::server::registry::ClientRegistry;
+use crate::server::ServerMessage;
+use metrics::{counter, gauge, histogram, Recorder};
+use metrics_util::layers::FanoutBuilder;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{broadcast, RwLock};
+use tokio::task::AbortHandle;
+
+/// How often the gauges without an update point of their own are sampled
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Commands with their own series; anything else a client sends is counted
+/// as "other" so it can't create series at will
+pub(crate) const KNOWN_COMMANDS: &[&str] = &[
+    "DI", "ID", "TM", "AA", "AP", "DA", "DP", "CQ", "CR", "FP", "AM", "AX", "AR", "ZC", "ZR", "PC",
+    "ER", "HO", "HA", "PI", "PO", "SB", "N", "S", "Y", "%",
+];
+
+/// Upper bounds of the handler time buckets, in seconds
+pub(crate) const DURATION_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// Upper bounds of the write batch buckets, in packets
+pub(crate) const BATCH_BUCKETS: &[f64] = &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0];
+
+#[derive(Debug, Error)]
+pub enum MetricsError {
+    #[error("Invalid metrics address: {0}")]
+    InvalidAddress(String),
+    #[cfg(not(feature = "prometheus"))]
+    #[error("OpenFSD was built without the prometheus feature")]
+    NotCompiled,
+    #[cfg(feature = "prometheus")]
+    #[error("Failed to start the metrics endpoint: {0}")]
+    Exporter(String),
+    #[error("A metrics recorder is already installed")]
+    AlreadyInstalled,
+}
+
+type BoxedRecorder = Box<dyn Recorder + Sync>;
+
+/// Install the recorder: the Prometheus endpoint on `config.address` if it
+/// is enabled, and the OTLP export if telemetry is running
+///
+/// The recorder is global, so this can only succeed once per process.
+pub fn install(config: &MetricsConfig) -> Result<(), MetricsError> {
+    let prometheus = match config.enabled {
+        true => Some(prometheus(config)?),
+        false => None,
+    };
+    #[cfg(feature = "telemetry")]
+    let otlp = crate::telemetry::recorder().map(|recorder| Box::new(recorder) as BoxedRecorder);
+    #[cfg(not(feature = "telemetry"))]
+    let otlp: Option<BoxedRecorder> = None;
+
+    let recorder: BoxedRecorder = match (prometheus, otlp) {
+        (Some(prometheus), Some(otlp)) => Box::new(
+            FanoutBuilder::default()
+                .add_recorder(prometheus)
+                .add_recorder(otlp)
+                .build(),
+        ),
+        (Some(recorder), None) | (None, Some(recorder)) => recorder,
+        (None, None) => return Ok(()),
+    };
+    metrics::set_global_recorder(recorder).map_err(|_| MetricsError::AlreadyInstalled)?;
+    describe();
+    Ok(())
+}
+
+/// The Prometheus recorder, with its endpoint listening on `config.address`
+fn prometheus(config: &MetricsConfig) -> Result<BoxedRecorder, MetricsError> {
+    let address: SocketAddr = config
+        .address
+        .parse()
+        .map_err(|_| MetricsError::InvalidAddress(config.address.clone()))?;
+
+    #[cfg(feature = "prometheus")]
+    {
+        use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+
+        let (recorder, exporter) = PrometheusBuilder::new()
+            .with_http_listener(address)
+            .set_buckets_for_metric(Matcher::Suffix("seconds".to_string()), DURATION_BUCKETS)
+            .and_then(|builder| {
+                builder.set_buckets_for_metric(
+                    Matcher::Full("openfsd_write_batch_size".to_string()),
+                    BATCH_BUCKETS,
+                )
+            })
+            .and_then(|builder| builder.build())
+            .map_err(|e| MetricsError::Exporter(e.to_string()))?;
+        let exporter = tokio::spawn(exporter);
+        *ENDPOINT.lock().unwrap() = Some(exporter.abort_handle());
+        Ok(Box::new(recorder))
+    }
+
+    #[cfg(not(feature = "prometheus"))]
+    {
+        let _ = (address, DURATION_BUCKETS, BATCH_BUCKETS);
+        Err(MetricsError::NotCompiled)
+    }
+}
+
+/// The task serving the Prometheus endpoint
+static ENDPOINT: Mutex<Option<AbortHandle>> = Mutex::new(None);
+
+/// Stop serving the Prometheus endpoint, freeing its port; the metrics are
+/// still recorded
+pub fn close_endpoint() {
+    if let Some(endpoint) = ENDPOINT.lock().unwrap().take() {
+        endpoint.abort();
+    }
+}
+
+fn describe() {
+    use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+
+    describe_gauge!("openfsd_clients", "Connected clients by type");
+    describe_gauge!(
+        "openfsd_broadcast_queue_depth",
+        "Messages waiting in the broadcast channel"
+    );
+    describe_gauge!(
+        "openfsd_packet_queue_depth",
+        "Packets waiting for the processor"
+    );
+    describe_gauge!(
+        "openfsd_packet_queue_high_water",
+        "Most packets that have waited for the processor at once"
+    );
+    describe_gauge!(
+        "openfsd_broadcast_queue_high_water",
+        "Most messages the slowest connection has been behind"
+    );
+    describe_gauge!(
+        "openfsd_packet_queue_capacity",
+        "Packets that may wait for the processor"
+    );
+    describe_gauge!(
+        "openfsd_broadcast_queue_capacity",
+        "Messages a connection may fall behind before it is closed"
+    );
+    describe_gauge!("openfsd_worker_threads", "Threads running the server");
+    describe_gauge!(
+        "openfsd_client_queue_depth_max",
+        "Messages waiting for the connection furthest behind"
+    );
+    describe_counter!(
+        "openfsd_broadcast_lagged_total",
+        "Times a connection fell too far behind and was closed"
+    );
+    describe_counter!(
+        "openfsd_broadcast_dropped_total",
+        "Messages missed by connections that fell too far behind"
+    );
+    describe_counter!("openfsd_logins_total", "Login attempts by result");
+    describe_counter!(
+        "openfsd_packets_received_total",
+        "Packets received by command"
+    );
+    describe_counter!("openfsd_packets_sent_total", "Packets sent by command");
+    describe_counter!(
+        "openfsd_received_bytes_total",
+        Unit::Bytes,
+        "Bytes read from clients"
+    );
+    describe_counter!(
+        "openfsd_sent_bytes_total",
+        Unit::Bytes,
+        "Bytes written to clients"
+    );
+    describe_counter!(
+        "openfsd_parse_errors_total",
+        "Lines that were not valid packets"
+    );
+    describe_counter!(
+        "openfsd_error_replies_total",
+        "$ER packets sent to clients, by error code"
+    );
+    describe_counter!(
+        "openfsd_kicks_total",
+        "Clients disconnected by the server, by reason"
+    );
+    describe_counter!(
+        "openfsd_ban_refusals_total",
+        "Logins refused by an active ban"
+    );
+    describe_counter!(
+        "openfsd_weather_fetches_total",
+        "Weather reports fetched from the provider, by product and result"
+    );
+    describe_counter!(
+        "openfsd_weather_cache_hits_total",
+        "Weather reports served from the cache, by product"
+    );
+    describe_counter!(
+        "openfsd_weather_served_total",
+        "Weather reports answered, by product and the provider, cache or stale cache they came from"
+    );
+    describe_counter!(
+        "openfsd_slow_operations_total",
+        "Packet handlers, lock waits and client writes over their warning threshold, by kind"
+    );
+    describe_counter!(
+        "openfsd_chaos_dropped_total",
+        "Packets to clients dropped on purpose under [chaos], by command"
+    );
+    describe_counter!(
+        "openfsd_capability_suppressed_total",
+        "Optional packets not sent to clients lacking the capability, by capability"
+    );
+    describe_histogram!(
+        "openfsd_chaos_delay_seconds",
+        Unit::Seconds,
+        "Time writes to clients were held back under [chaos]"
+    );
+    describe_histogram!(
+        "openfsd_handler_duration_seconds",
+        Unit::Seconds,
+        "Time spent handling a packet, by command"
+    );
+    describe_histogram!(
+        "openfsd_write_batch_size",
+        "Packets written to a client at once"
+    );
+}
+
+/// Label for a packet's command
+pub fn command_label(command: &str) -> &'static str {
+    KNOWN_COMMANDS
+        .iter()
+        .find(|known| **known == command)
+        .copied()
+        .unwrap_or("other")
+}
+
+pub fn packet_received(command: &str, bytes: usize) {
+    counter!("openfsd_packets_received_total", "command" => command_label(command)).increment(1);
+    counter!("openfsd_received_bytes_total").increment(bytes as u64);
+}
+
+pub fn packet_sent(command: &str, bytes: usize) {
+    counter!("openfsd_packets_sent_total", "command" => command_label(command)).increment(1);
+    counter!("openfsd_sent_bytes_total").increment(bytes as u64);
+}
+
+pub fn parse_error(bytes: usize) {
+    counter!("openfsd_parse_errors_total").increment(1);
+    counter!("openfsd_received_bytes_total").increment(bytes as u64);
+}
+
+/// An `$ER` packet sent to a client, labelled with its error code
+pub fn error_reply(code: &str) {
+    counter!("openfsd_error_replies_total", "code" => code.to_string()).increment(1);
+}
+
+pub fn login(succeeded: bool) {
+    let result = if succeeded { "success" } else { "failure" };
+    counter!("openfsd_logins_total", "result" => result).increment(1);
+}
+
+/// A client disconnected by the server, e.g. "admin" or "flood"
+pub fn kick(reason: &'static str) {
+    counter!("openfsd_kicks_total", "reason" => reason).increment(1);
+}
+
+pub fn ban_refusal() {
+    counter!("openfsd_ban_refusals_total").increment(1);
+}
+
+pub fn weather_fetch(product: &'static str, succeeded: bool) {
+    let result = if succeeded { "success" } else { "failure" };
+    counter!("openfsd_weather_fetches_total", "product" => product, "result" => result)
+        .increment(1);
+}
+
+pub fn weather_cache_hit(product: &'static str) {
+    counter!("openfsd_weather_cache_hits_total", "product" => product).increment(1);
+}
+
+pub fn weather_served(product: &'static str, source: &str) {
+    counter!("openfsd_weather_served_total", "product" => product, "source" => source.to_string())
+        .increment(1);
+}
+
+pub fn handler_duration(command: &str, duration: Duration) {
+    histogram!("openfsd_handler_duration_seconds", "command" => command_label(command))
+        .record(duration.as_secs_f64());
+}
+
+/// `packets` written to a client at once
+pub fn write_batch(packets: usize) {
+    histogram!("openfsd_write_batch_size").record(packets as f64);
+}
+
+/// A "packet", "lock" or "write" that took longer than its threshold
+pub fn slow_operation(kind: &'static str) {
+    counter!("openfsd_slow_operations_total", "kind" => kind).increment(1);
+}
+
+/// A packet to a client dropped under `[chaos]`
+pub fn chaos_dropped(command: &str) {
+    counter!("openfsd_chaos_dropped_total", "command" => command_label(command)).increment(1);
+}
+
+/// An optional packet not sent to a client lacking `capability`
+pub fn capability_suppressed(capability: &'static str) {
+    counter!("openfsd_capability_suppressed_total", "capability" => capability).increment(1);
+}
+
+/// A write to a client held back under `[chaos]`
+pub fn chaos_delay(delay: Duration) {
+    histogram!("openfsd_chaos_delay_seconds").record(delay.as_secs_f64());
+}
+
+/// A connection's queue overflowed and it missed `skipped` messages
+pub fn broadcast_lagged(skipped: u64) {
+    counter!("openfsd_broadcast_lagged_total").increment(1);
+    counter!("openfsd_broadcast_dropped_total").increment(skipped);
+}
+
+/// Sizes the server was started with
+pub fn runtime(packet_queue: usize, broadcast_capacity: usize, worker_threads: usize) {
+    gauge!("openfsd_packet_queue_capacity").set(packet_queue as f64);
+    gauge!("openfsd_broadcast_queue_capacity").set(broadcast_capacity as f64);
+    gauge!("openfsd_worker_threads").set(worker_threads as f64);
+}
+
+pub fn queues(report: &PipelineReport) {
+    gauge!("openfsd_packet_queue_depth").set(report.packets.depth as f64);
+    gauge!("openfsd_packet_queue_high_water").set(report.packets.high_water as f64);
+    gauge!("openfsd_broadcast_queue_high_water").set(report.broadcast.high_water as f64);
+    let deepest = report
+        .worst_clients
+        .first()
+        .map_or(0, |client| client.depth);
+    gauge!("openfsd_client_queue_depth_max").set(deepest as f64);
+}
+
+/// Update the client and broadcast queue gauges every `SAMPLE_INTERVAL`
+pub async fn sample(
+    clients: Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+) {
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let counts = count_clients(&*clients.read().await);
+        for (kind, count) in counts {
+            gauge!("openfsd_clients", "type" => kind).set(count as f64);
+        }
+        gauge!("openfsd_broadcast_queue_depth").set(broadcast_tx.len() as f64);
+    }
+}
+
+/// Clients by type; connections that haven't logged in yet are "pending"
+pub(crate) fn count_clients(clients: &HashMap<SocketAddr, Client>) -> [(&'static str, usize); 4] {
+    let mut counts = [("pilot", 0), ("atc", 0), ("observer", 0), ("pending", 0)];
+    for client in clients.values() {
+        let index = match client.client_type {
+            Some(ClientType::Pilot) if client.is_active() => 0,
+            Some(ClientType::Atc) if client.is_active() => 1,
+            Some(ClientType::Observer) if client.is_active() => 2,
+            _ => 3,
+        };
+        counts[index].1 += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_label() {
+        assert_eq!(command_label("AP"), "AP");
+        assert_eq!(command_label("%"), "%");
+        assert_eq!(command_label("Q7"), "other");
+    }
+
+    #[test]
+    fn test_count_clients() {
+        let mut clients = HashMap::new();
+        for port in 1..=3 {
+            let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+            clients.insert(addr, Client::new(addr));
+        }
+        for (port, client_type) in [(1, ClientType::Pilot), (2, ClientType::Atc)] {
+            let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+            let client = clients.get_mut(&addr).unwrap();
+            client.client_type = Some(client_type);
+            client.state = crate::client::ClientState::Active;
+        }
+
+        assert_eq!(
+            count_clients(&clients),
+            [("pilot", 1), ("atc", 1), ("observer", 0), ("pending", 1)]
+        );
+    }
+}
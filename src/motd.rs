@@ -0,0 +1,190 @@
+use crate::db::service;
+use sea_orm::DatabaseConnection;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Message of the day sent to every login
+pub const MOTD_KEY: &str = "motd";
+
+/// Additional lines sent to controllers after the MOTD
+pub const ATC_MOTD_KEY: &str = "atc_motd";
+
+/// How long a message read from the database is reused
+pub const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Sent when neither the database nor the config file has a MOTD
+pub const DEFAULT_MOTD: &[&str] = &[
+    "By using your VATSIM assigned identification number on this server you",
+    "hereby agree to the terms of the VATSIM Code of Regulations and the",
+    "VATSIM User Agreement and the VATSIM Code of Conduct which may be viewed",
+    "at http://www.vatsim.net/network/docs/",
+    "All logins are tracked and identification numbers are recorded.",
+    "Users must enter their real full first names and surnames when logging",
+    "onto any of the VATSIM.net servers.",
+];
+
+/// Cached lines of a message and when they were read
+type CacheEntry = (Instant, Option<Vec<String>>);
+
+/// Caches server messages edited in the database
+///
+/// Lookups hit the database at most once per TTL and key, so edits made
+/// with the admin tool apply to logins within a minute. Absent keys are
+/// cached too.
+#[derive(Debug)]
+pub struct MotdCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl MotdCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Lines stored under `key`, or `None` if the database has no such message
+    ///
+    /// On database errors the last cached value is used, if any.
+    pub async fn get(&self, db: &DatabaseConnection, key: &str) -> Option<Vec<String>> {
+        if let Some((fetched_at, lines)) = self.entries.lock().unwrap().get(key) {
+            if fetched_at.elapsed() < self.ttl {
+                return lines.clone();
+            }
+        }
+
+        match service::find_server_message(db, key).await {
+            Ok(message) => {
+                let lines = message.map(|message| message.lines());
+                self.entries
+                    .lock()
+                    .unwrap()
+                    .insert(key.to_string(), (Instant::now(), lines.clone()));
+                lines
+            }
+            Err(e) => {
+                tracing::error!("Failed to load server message {}: {}", key, e);
+                self.entries
+                    .lock()
+                    .unwrap()
+                    .get(key)
+                    .and_then(|(_, lines)| lines.clone())
+            }
+        }
+    }
+
+    /// Store a message and drop its cached value
+    pub async fn set(
+        &self,
+        db: &DatabaseConnection,
+        key: &str,
+        lines: &[String],
+        updated_by: Option<&str>,
+    ) -> Result<(), sea_orm::DbErr> {
+        service::set_server_message(db, key, lines, updated_by).await?;
+        self.invalidate(key);
+        Ok(())
+    }
+
+    pub fn invalidate(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    /// Lines to send at login: the database value, then the config file, then
+    /// the built-in default
+    pub async fn motd(&self, db: &DatabaseConnection, configured: &[String]) -> Vec<String> {
+        if let Some(lines) = self.get(db, MOTD_KEY).await {
+            return lines;
+        }
+        if !configured.is_empty() {
+            return configured.to_vec();
+        }
+        DEFAULT_MOTD.iter().map(|line| line.to_string()).collect()
+    }
+
+    /// Extra lines for controllers: the database value, then the config file
+    pub async fn atc_motd(&self, db: &DatabaseConnection, configured: &[String]) -> Vec<String> {
+        match self.get(db, ATC_MOTD_KEY).await {
+            Some(lines) => lines,
+            None => configured.to_vec(),
+        }
+    }
+}
+
+impl Default for MotdCache {
+    fn default() -> Self {
+        Self::new(CACHE_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_motd_precedence() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let cache = MotdCache::default();
+        let configured = lines(&["Welcome to the test network"]);
+
+        assert_eq!(cache.motd(&db, &[]).await.len(), DEFAULT_MOTD.len());
+        assert_eq!(cache.motd(&db, &configured).await, configured);
+
+        cache
+            .set(&db, MOTD_KEY, &lines(&["Event tonight", "at 1800Z"]), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.motd(&db, &configured).await,
+            lines(&["Event tonight", "at 1800Z"])
+        );
+
+        // An empty database value deliberately sends nothing
+        cache.set(&db, MOTD_KEY, &[], None).await.unwrap();
+        assert!(cache.motd(&db, &configured).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_atc_motd_falls_back_to_config() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let cache = MotdCache::default();
+
+        assert!(cache.atc_motd(&db, &[]).await.is_empty());
+        let configured = lines(&["Check the ATC roster"]);
+        assert_eq!(cache.atc_motd(&db, &configured).await, configured);
+    }
+
+    #[tokio::test]
+    async fn test_cache_invalidation() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let cache = MotdCache::default();
+        cache
+            .set(&db, MOTD_KEY, &lines(&["Old"]), Some("admin"))
+            .await
+            .unwrap();
+        assert_eq!(cache.get(&db, MOTD_KEY).await, Some(lines(&["Old"])));
+
+        // Written elsewhere (e.g. the admin tool): served from cache until it expires
+        service::set_server_message(&db, MOTD_KEY, &lines(&["New"]), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.get(&db, MOTD_KEY).await, Some(lines(&["Old"])));
+
+        cache.invalidate(MOTD_KEY);
+        assert_eq!(cache.get(&db, MOTD_KEY).await, Some(lines(&["New"])));
+
+        let expired = MotdCache::new(Duration::ZERO);
+        assert_eq!(expired.get(&db, MOTD_KEY).await, Some(lines(&["New"])));
+        service::set_server_message(&db, MOTD_KEY, &lines(&["Newer"]), None)
+            .await
+            .unwrap();
+        assert_eq!(expired.get(&db, MOTD_KEY).await, Some(lines(&["Newer"])));
+    }
+}
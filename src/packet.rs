@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::fmt;
 use thiserror::Error;
 
@@ -9,19 +11,25 @@ pub enum PacketError {
     MissingField(String),
     #[error("JSON parsing error: {0}")]
     JsonError(#[from] serde_json::Error),
+    #[error("Line longer than {0} bytes")]
+    TooLong(usize),
 }
 
 /// FSD packet types based on command prefix
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PacketType {
     /// $ prefix - Requests and responses
     Request,
     /// # prefix - Adding/removing clients, text messages
     Client,
-    /// % prefix - ATC update
+    /// % prefix - ATC update, parsed with `%` as its command
     AtcUpdate,
     /// @ prefix - Aircraft update
     PilotUpdate,
+    /// ' prefix - Secondary visibility center of a controller, parsed with
+    /// `'` as its command
+    VisibilityCenter,
     /// ! prefix - IVAO specific
     IvaoSpecific,
     /// & prefix - IVAO specific
@@ -30,8 +38,18 @@ pub enum PacketType {
     IvaoOther,
 }
 
+impl PacketType {
+    /// Whether the prefix only exists in the IVAO protocol
+    pub fn is_ivao(&self) -> bool {
+        matches!(
+            self,
+            PacketType::IvaoSpecific | PacketType::IvaoData | PacketType::IvaoOther
+        )
+    }
+}
+
 /// FSD packet representation
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Packet {
     pub packet_type: PacketType,
     pub command: String,
@@ -61,6 +79,7 @@ impl Packet {
             '#' => PacketType::Client,
             '%' => PacketType::AtcUpdate,
             '@' => PacketType::PilotUpdate,
+            '\'' => PacketType::VisibilityCenter,
             '!' => PacketType::IvaoSpecific,
             '&' => PacketType::IvaoData,
             '-' => PacketType::IvaoOther,
@@ -88,8 +107,18 @@ impl Packet {
         let command_ident = &without_prefix[..first_colon];
         let rest = &without_prefix[first_colon + 1..];
 
-        // Extract command and first identifier
-        let (command, first_ident) = Self::split_command_source(command_ident);
+        // Extract command and first identifier. ATC updates and visibility
+        // centers have no command, the identifier is the whole callsign
+        let (command, first_ident) = if packet_type == PacketType::AtcUpdate {
+            ("%".to_string(), command_ident.to_string())
+        } else if packet_type == PacketType::VisibilityCenter {
+            ("'".to_string(), command_ident.to_string())
+        } else if packet_type == PacketType::Client && command_ident.starts_with("SB") {
+            // #SB model descriptions, which would otherwise read as an S
+            ("SB".to_string(), command_ident[2..].to_string())
+        } else {
+            Self::split_command_source(command_ident)
+        };
 
         // Split remaining parts by colons
         let parts: Vec<&str> = rest.splitn(2, ':').collect();
@@ -100,6 +129,12 @@ impl Packet {
 
         let second_ident = parts[0].to_string();
 
+        let mut data: Vec<String> = if parts.len() > 1 {
+            parts[1].split(':').map(|s| s.to_string()).collect()
+        } else {
+            Vec::new()
+        };
+
         // Determine which is source and which is destination based on command
         // For server identification (DI), format is: command+destination:source
         // For most others (ID, TM, AA, AP, etc.), format is: command+source:destination
@@ -107,20 +142,28 @@ impl Packet {
         let (source, destination) = if command == "DI" {
             // Server identification: destination comes first
             (second_ident, first_ident)
-        } else if packet_type == PacketType::PilotUpdate || packet_type == PacketType::AtcUpdate {
-            // Position updates: first identifier is the destination (subject of update)
-            (String::new(), first_ident) // Source is implicit (the sender)
+        } else if packet_type == PacketType::PilotUpdate {
+            // Pilot updates are @(mode):(callsign):(squawk):..., some clients
+            // omit the colon after the mode. Either way data starts at the squawk
+            if first_ident.is_empty() {
+                (String::new(), second_ident)
+            } else {
+                data.insert(0, second_ident);
+                (String::new(), first_ident)
+            }
+        } else if matches!(
+            packet_type,
+            PacketType::AtcUpdate | PacketType::VisibilityCenter
+        ) {
+            // %(callsign):(frequency):... - data starts at the frequency and the
+            // source is implicit (the sender); '(callsign):(index):... likewise
+            data.insert(0, second_ident);
+            (String::new(), first_ident)
         } else {
             // Default case (ID, TM, AA, AP, etc.): source comes first
             (first_ident, second_ident)
         };
 
-        let data = if parts.len() > 1 {
-            parts[1].split(':').map(|s| s.to_string()).collect()
-        } else {
-            Vec::new()
-        };
-
         Ok(Packet {
             packet_type,
             command,
@@ -175,6 +218,7 @@ impl Packet {
             PacketType::Client => '#',
             PacketType::AtcUpdate => '%',
             PacketType::PilotUpdate => '@',
+            PacketType::VisibilityCenter => '\'',
             PacketType::IvaoSpecific => '!',
             PacketType::IvaoData => '&',
             PacketType::IvaoOther => '-',
@@ -187,11 +231,15 @@ impl Packet {
                 "{}{}{}:{}",
                 prefix, self.command, self.destination, self.source
             )
-        } else if self.packet_type == PacketType::PilotUpdate
-            || self.packet_type == PacketType::AtcUpdate
-        {
-            // Position updates: command+destination:data (no separate source field)
-            format!("{}{}{}", prefix, self.command, self.destination)
+        } else if self.packet_type == PacketType::PilotUpdate {
+            // Pilot updates: mode:callsign:data
+            format!("{}{}:{}", prefix, self.command, self.destination)
+        } else if matches!(
+            self.packet_type,
+            PacketType::AtcUpdate | PacketType::VisibilityCenter
+        ) {
+            // ATC updates: callsign:data (no command or separate source field)
+            format!("{}{}", prefix, self.destination)
         } else {
             // Default: command+source:destination
             format!(
@@ -207,7 +255,7 @@ impl Packet {
 
         // Validate total packet length
         if result.len() > 4096 {
-            log::warn!("Packet too long, truncating: {}", self.command);
+            tracing::warn!("Packet too long, truncating: {}", self.command);
             result.truncate(4090);
         }
 
@@ -260,6 +308,187 @@ impl fmt::Display for Packet {
     }
 }
 
+/// Longest packet line, without its `\r\n`
+const MAX_LINE: usize = 4096;
+
+/// A pilot (`@N`, `@S`, `@Y`) or ATC (`%`) position update, kept as a line
+///
+/// These make up most of the traffic, and the server only reads a few of
+/// their fields before passing them on. Fields are sliced out of the line on
+/// demand, and the line is forwarded as it is: byte for byte what parsing it
+/// into a [`Packet`] and formatting that would give.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PositionUpdate {
+    /// The update as sent to clients, with its `\r\n`
+    line: String,
+    /// Where the callsign ends in `line`
+    callsign_end: usize,
+}
+
+impl PositionUpdate {
+    /// Slice a raw line, or `None` if it is not a position update (or one
+    /// [`Packet::parse`] would reject)
+    pub fn parse(raw: &str) -> Option<Self> {
+        let raw = raw.trim_end_matches("\r\n").trim();
+        if raw.len() < 3 || raw.len() > MAX_LINE {
+            return None;
+        }
+
+        let mut line = String::with_capacity(raw.len() + 3);
+        let callsign_start = match raw.as_bytes()[0] {
+            // %(callsign):(frequency):...
+            b'%' => {
+                line.push_str(raw);
+                1
+            }
+            // @(mode):(callsign):(squawk):..., or without the colon after
+            // the mode, which is added back. @NV is a different command
+            b'@' if matches!(raw.as_bytes()[1], b'N' | b'S' | b'Y') && !raw.starts_with("@NV") => {
+                if raw.as_bytes()[2] == b':' {
+                    line.push_str(raw);
+                } else if !raw[2..].contains(':') {
+                    return None;
+                } else {
+                    line.push_str(&raw[..2]);
+                    line.push(':');
+                    line.push_str(&raw[2..]);
+                }
+                3
+            }
+            _ => return None,
+        };
+        // Formatting a packet cuts it short past this length
+        if line.len() > MAX_LINE {
+            return None;
+        }
+
+        let callsign_end = match line[callsign_start..].find(':') {
+            Some(colon) => callsign_start + colon,
+            // An ATC update needs a colon after the callsign
+            None if callsign_start == 1 => return None,
+            None => line.len(),
+        };
+        line.push_str("\r\n");
+        Some(Self { line, callsign_end })
+    }
+
+    /// The position update in a packet that was parsed already
+    pub fn from_packet(packet: &Packet) -> Option<Self> {
+        Self::parse(&packet.format())
+    }
+
+    pub fn packet_type(&self) -> PacketType {
+        match self.line.as_bytes()[0] {
+            b'%' => PacketType::AtcUpdate,
+            _ => PacketType::PilotUpdate,
+        }
+    }
+
+    /// The command [`Packet::parse`] would give, `%` for ATC updates
+    pub fn command(&self) -> &'static str {
+        match self.line.as_bytes()[..2] {
+            [b'@', b'N'] => "N",
+            [b'@', b'S'] => "S",
+            [b'@', b'Y'] => "Y",
+            _ => "%",
+        }
+    }
+
+    /// The callsign of the sender, the parsed packet's destination
+    pub fn callsign(&self) -> &str {
+        let start = if self.line.starts_with('%') { 1 } else { 3 };
+        &self.line[start..self.callsign_end]
+    }
+
+    /// The fields after the callsign, the parsed packet's data
+    pub fn fields(&self) -> impl Iterator<Item = &str> {
+        let end = self.line.len() - 2;
+        let data = (self.callsign_end < end).then(|| &self.line[self.callsign_end + 1..end]);
+        data.into_iter().flat_map(|data| data.split(':'))
+    }
+
+    /// The first `N` fields, `None` past the last one
+    pub fn leading_fields<const N: usize>(&self) -> [Option<&str>; N] {
+        let mut fields = self.fields();
+        std::array::from_fn(|_| fields.next())
+    }
+
+    /// The line to send to other clients
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// The update as a parsed packet, for what needs one
+    pub fn to_packet(&self) -> Packet {
+        Packet::parse(&self.line).expect("position updates are valid packets")
+    }
+}
+
+/// A packet from a client on its way to the processor
+#[derive(Debug, Clone)]
+pub enum Inbound {
+    /// Position updates skip the full parse
+    Position(PositionUpdate),
+    Packet(Packet),
+}
+
+impl Inbound {
+    /// Parse a raw line, only slicing it if it is a position update
+    pub fn parse(raw: &str) -> Result<Self, PacketError> {
+        match PositionUpdate::parse(raw) {
+            Some(update) => Ok(Inbound::Position(update)),
+            None => Packet::parse(raw).map(Inbound::Packet),
+        }
+    }
+
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            Inbound::Position(update) => update.packet_type(),
+            Inbound::Packet(packet) => packet.packet_type.clone(),
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        match self {
+            Inbound::Position(update) => update.command(),
+            Inbound::Packet(packet) => &packet.command,
+        }
+    }
+}
+
+impl From<Packet> for Inbound {
+    fn from(packet: Packet) -> Self {
+        Inbound::Packet(packet)
+    }
+}
+
+impl fmt::Display for Inbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inbound::Position(update) => f.write_str(update.line().trim_end()),
+            Inbound::Packet(packet) => packet.fmt(f),
+        }
+    }
+}
+
+/// `line` with the password of a login (`#AA` or `#AP`) replaced by `***`,
+/// for logs and captures
+pub fn redact_password(line: &str) -> Cow<'_, str> {
+    let field = if line.starts_with("#AA") {
+        4
+    } else if line.starts_with("#AP") {
+        3
+    } else {
+        return Cow::Borrowed(line);
+    };
+    let mut fields: Vec<&str> = line.split(':').collect();
+    match fields.get_mut(field) {
+        Some(password) if !password.is_empty() => *password = "***",
+        _ => return Cow::Borrowed(line),
+    }
+    Cow::Owned(fields.join(":"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -307,6 +536,137 @@ mod tests {
         assert_eq!(packet.packet_type, PacketType::PilotUpdate);
         assert_eq!(packet.command, "N");
         assert_eq!(packet.destination, "UAX123");
+        assert_eq!(packet.data[0], "1200");
+        assert_eq!(packet.data[2], "45.5");
+    }
+
+    #[test]
+    fn test_parse_position_update_with_mode_separator() {
+        let raw = "@S:UAX123:7000:1:45.5:-73.5:35000:450:123456789:50\r\n";
+        let packet = Packet::parse(raw).unwrap();
+
+        assert_eq!(packet.command, "S");
+        assert_eq!(packet.destination, "UAX123");
+        assert_eq!(packet.data[0], "7000");
+        assert_eq!(packet.format(), raw);
+    }
+
+    #[test]
+    fn test_parse_atc_update() {
+        let raw = "%ZBAA_TWR:18100:4:50:5:40.08:116.58:0\r\n";
+        let packet = Packet::parse(raw).unwrap();
+
+        assert_eq!(packet.packet_type, PacketType::AtcUpdate);
+        assert_eq!(packet.command, "%");
+        assert_eq!(packet.destination, "ZBAA_TWR");
+        assert_eq!(packet.data[0], "18100");
+        assert_eq!(packet.data[2], "50");
+        assert_eq!(packet.format(), raw);
+    }
+
+    #[test]
+    fn test_parse_visibility_center() {
+        let raw = "'EDGG_CTR:1:50.03:8.57\r\n";
+        let packet = Packet::parse(raw).unwrap();
+
+        assert_eq!(packet.packet_type, PacketType::VisibilityCenter);
+        assert_eq!(packet.command, "'");
+        assert_eq!(packet.destination, "EDGG_CTR");
+        assert_eq!(packet.data, ["1", "50.03", "8.57"]);
+        assert_eq!(packet.format(), raw);
+    }
+
+    #[test]
+    fn test_parse_model_description() {
+        let raw = "#SBBAW789:DLH123:PI:GEN:EQUIPMENT=B738\r\n";
+        let packet = Packet::parse(raw).unwrap();
+
+        assert_eq!(packet.command, "SB");
+        assert_eq!(packet.source, "BAW789");
+        assert_eq!(packet.destination, "DLH123");
+        assert_eq!(packet.format(), raw);
+
+        // A pilot update from a callsign starting with B is still one
+        let packet = Packet::parse("@SBAW789:2000:1:45.5:-73.5:35000:450:0:0").unwrap();
+        assert_eq!(packet.command, "S");
+    }
+
+    /// Lines a client may send as position updates, in every shape
+    const POSITION_LINES: &[&str] = &[
+        "@N:CCA1501:1200:1:40.08:116.58:5000:250:4194304:0\r\n",
+        "@S:UAX123:7000:1:45.5:-73.5:35000:450:123456789:50\n",
+        "@Y:DLH4AB:2000:3:50.03:8.57:364:0:0:-12\r\n",
+        "@NUAX123:1200:1:45.5:-73.5:35000:450:123456789:50\r\n",
+        "  @N:CCA1501:1200:1:40.1:116.6:9000:250:0:0  \r\n",
+        "@N:CCA1501",
+        "@N:CCA1501:",
+        "@NCCA1501:",
+        "@N::1200",
+        "@N:CCA1501:1200::::::",
+        "%ZBAA_TWR:18100:4:50:5:40.08:116.58:0\r\n",
+        "%ZBPE_CTR:25300:6:300:5:40.08:116.58:0",
+        "%ZBAA_TWR:",
+        "%:18100",
+    ];
+
+    #[test]
+    fn test_position_update_forwards_formatted_bytes() {
+        for line in POSITION_LINES {
+            let packet = Packet::parse(line).unwrap();
+            let update = PositionUpdate::parse(line).unwrap();
+
+            assert_eq!(update.line(), packet.format(), "{:?}", line);
+            assert_eq!(update.packet_type(), packet.packet_type, "{:?}", line);
+            assert_eq!(update.command(), packet.command, "{:?}", line);
+            assert_eq!(update.callsign(), packet.destination, "{:?}", line);
+            assert_eq!(
+                update.fields().collect::<Vec<_>>(),
+                packet.data,
+                "{:?}",
+                line
+            );
+            assert_eq!(update.to_packet().format(), packet.format(), "{:?}", line);
+            assert_eq!(PositionUpdate::from_packet(&packet), Some(update));
+        }
+    }
+
+    #[test]
+    fn test_position_update_leaves_other_lines() {
+        let too_long = format!("@N:CCA1501:{}", "1".repeat(MAX_LINE));
+        let just_fits = format!("@NCCA1501:{}", "1".repeat(MAX_LINE - 10));
+        for line in [
+            "#TMCCA1501:*:hello",
+            "@NVCCA1501:1",
+            "@C:CCA1501:1",
+            "@N",
+            "@NCCA1501",
+            "%ZBAA_TWR",
+            "%:",
+            "",
+            &too_long,
+            // Gets cut short when the colon is added back
+            &just_fits,
+        ] {
+            assert_eq!(PositionUpdate::parse(line), None, "{:?}", line);
+        }
+        assert!(matches!(
+            Inbound::parse("#TMCCA1501:*:hello"),
+            Ok(Inbound::Packet(_))
+        ));
+        assert!(Inbound::parse("@N").is_err());
+    }
+
+    #[test]
+    fn test_position_update_fields() {
+        let update = PositionUpdate::parse(POSITION_LINES[0]).unwrap();
+        let [squawk, _, latitude, .., last, missing] = update.leading_fields::<9>();
+        assert_eq!(squawk, Some("1200"));
+        assert_eq!(latitude, Some("40.08"));
+        assert_eq!(last, Some("0"));
+        assert_eq!(missing, None);
+
+        let bare = PositionUpdate::parse("@N:CCA1501").unwrap();
+        assert_eq!(bare.leading_fields::<1>(), [None]);
     }
 
     #[test]
@@ -323,4 +683,21 @@ mod tests {
         assert!(formatted.starts_with("$DISERVER:CLIENT:"));
         assert!(formatted.ends_with("\r\n"));
     }
+
+    #[test]
+    fn test_redact_password() {
+        assert_eq!(
+            redact_password("#APCCA1501:SERVER:1234567:hunter2:1:100:1:Jane Doe"),
+            "#APCCA1501:SERVER:1234567:***:1:100:1:Jane Doe"
+        );
+        assert_eq!(
+            redact_password("#AAZSPD_APP:SERVER:Li Wei:1234567:hunter2:5:100\r\n"),
+            "#AAZSPD_APP:SERVER:Li Wei:1234567:***:5:100\r\n"
+        );
+        assert!(matches!(
+            redact_password("#TMCCA1501:*:my password is hunter2"),
+            Cow::Borrowed(_)
+        ));
+        assert!(matches!(redact_password("#APCCA1501:SERVER"), Cow::Borrowed(_)));
+    }
 }
@@ -0,0 +1,135 @@
+//! Aircraft details pilot clients exchange in `#SB` plane-info replies
+//!
+//! Pilots ask each other what they fly with `#SB(from):(to):PI` so they can
+//! pick a matching model, and the answer lists it as key=value fields, e.g.
+//! `#SBDLH123:BAW789:PI:GEN:EQUIPMENT=B738:AIRLINE=DLH:LIVERY=DLH`. The
+//! server reads the answers as they pass through, which tells it the type of
+//! aircraft even without a flight plan.
+
+use crate::packet::Packet;
+use serde::{Deserialize, Serialize};
+
+/// What a pilot's client says it is flying
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlaneInfo {
+    /// ICAO type designator, e.g. "B738"
+    pub equipment: Option<String>,
+    /// ICAO airline code, e.g. "DLH"
+    pub airline: Option<String>,
+    /// Livery code or registration
+    pub livery: Option<String>,
+    /// Model the client picked, as some clients report it
+    pub csl: Option<String>,
+}
+
+impl PlaneInfo {
+    /// The details in a `#SB ... PI:GEN` reply, or `None` for other `#SB`
+    /// packets and replies without any
+    ///
+    /// Fields that aren't key=value pairs, have no value or an unknown key
+    /// are skipped.
+    pub fn from_packet(packet: &Packet) -> Option<Self> {
+        let data: Vec<&str> = packet.data.iter().map(String::as_str).collect();
+        let ["PI", "GEN", fields @ ..] = data.as_slice() else {
+            return None;
+        };
+        let mut info = PlaneInfo::default();
+        for field in fields {
+            let Some((key, value)) = field.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            let slot = match key.trim().to_ascii_uppercase().as_str() {
+                "EQUIPMENT" => &mut info.equipment,
+                "AIRLINE" => &mut info.airline,
+                "LIVERY" => &mut info.livery,
+                "CSL" => &mut info.csl,
+                _ => continue,
+            };
+            *slot = Some(value.to_string());
+        }
+        (info != PlaneInfo::default()).then_some(info)
+    }
+
+    /// The fields that are set, mutably, to cut them down to size
+    pub fn fields_mut(&mut self) -> impl Iterator<Item = &mut String> {
+        [
+            &mut self.equipment,
+            &mut self.airline,
+            &mut self.livery,
+            &mut self.csl,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Bytes of text held, for memory estimates
+    pub fn text_len(&self) -> usize {
+        [&self.equipment, &self.airline, &self.livery, &self.csl]
+            .into_iter()
+            .flatten()
+            .map(String::len)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Option<PlaneInfo> {
+        PlaneInfo::from_packet(&Packet::parse(line).unwrap())
+    }
+
+    #[test]
+    fn test_vpilot_reply() {
+        let info = parse("#SBDLH123:BAW789:PI:GEN:EQUIPMENT=B738:AIRLINE=DLH:LIVERY=DLH\r\n");
+        assert_eq!(
+            info,
+            Some(PlaneInfo {
+                equipment: Some("B738".into()),
+                airline: Some("DLH".into()),
+                livery: Some("DLH".into()),
+                csl: None,
+            })
+        );
+
+        // General aviation: no airline or livery
+        let info = parse("#SBN172SP:DLH123:PI:GEN:EQUIPMENT=C172:AIRLINE=:LIVERY=").unwrap();
+        assert_eq!(info.equipment.as_deref(), Some("C172"));
+        assert_eq!((info.airline, info.livery), (None, None));
+    }
+
+    #[test]
+    fn test_swift_reply() {
+        let info = parse(
+            "#SBCCA1501:AFR44:PI:GEN:EQUIPMENT=A21N:AIRLINE=CCA:LIVERY=B-30EE:CSL=A21N_CCA\r\n",
+        )
+        .unwrap();
+        assert_eq!(info.equipment.as_deref(), Some("A21N"));
+        assert_eq!(info.airline.as_deref(), Some("CCA"));
+        assert_eq!(info.livery.as_deref(), Some("B-30EE"));
+        assert_eq!(info.csl.as_deref(), Some("A21N_CCA"));
+    }
+
+    #[test]
+    fn test_other_sb_packets() {
+        // The request, and the older FSInn exchange
+        assert_eq!(parse("#SBBAW789:DLH123:PI"), None);
+        assert_eq!(parse("#SBBAW789:DLH123:FSIPIR:0:B738:DLH"), None);
+        assert_eq!(parse("#SBBAW789:DLH123:PI:GEN"), None);
+    }
+
+    #[test]
+    fn test_malformed_fields_are_skipped() {
+        let info =
+            parse("#SBDLH123:BAW789:PI:GEN:EQUIPMENT:=B744:FOO=BAR:equipment= B77W :LIVERY=")
+                .unwrap();
+        assert_eq!(info.equipment.as_deref(), Some("B77W"));
+        assert_eq!((info.airline, info.livery), (None, None));
+        assert_eq!(parse("#SBDLH123:BAW789:PI:GEN:junk:==:"), None);
+    }
+}
@@ -0,0 +1,294 @@
+//! Announcements scheduled ahead of time
+//!
+//! Event staff add them with `openfsd-admin announce add`. Every
+//! [`CHECK_INTERVAL`], [`run`] sends the ones whose time has come as
+//! `SERVER` messages to everyone, pilots or controllers. A one-shot
+//! announcement is then done, a repeating one moves on to its next time.
+//! One that comes due more than `announcement_grace_minutes` late, say
+//! because the server was down, is skipped with a warning rather than sent
+//! out of context.
+
+use crate::db::entities::announcement;
+use crate::db::service;
+use crate::server::clock::Clock;
+use crate::server::config::ServerMessage;
+use crate::server::handlers::command::send_lines;
+use crate::server::registry::ClientRegistry;
+use chrono::{DateTime, Utc};
+use sea_orm::{DatabaseConnection, DbErr};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, RwLock};
+
+/// How often announcements that came due are looked for
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Send announcements until the server stops
+pub async fn run(
+    db: Arc<DatabaseConnection>,
+    clients: Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: String,
+    grace: chrono::Duration,
+    clock: Arc<dyn Clock>,
+) {
+    let mut ticks = clock.interval(CHECK_INTERVAL);
+    loop {
+        ticks.tick().await;
+        let now = clock.utc();
+        if let Err(e) = fire_due(&db, &clients, &broadcast_tx, &server_callsign, grace, now).await {
+            tracing::error!("Failed to send scheduled announcements: {}", e);
+        }
+    }
+}
+
+/// Send or skip the announcements due by `now`, returning how many were
+/// sent
+pub async fn fire_due(
+    db: &DatabaseConnection,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+    grace: chrono::Duration,
+    now: DateTime<Utc>,
+) -> Result<usize, DbErr> {
+    let mut sent = 0;
+    for announcement in service::find_due_announcements(db, now).await? {
+        let late = now - announcement.fire_at;
+        let fired = late <= grace;
+        if fired {
+            let recipients = deliver(&announcement, clients, broadcast_tx, server_callsign).await;
+            tracing::info!(
+                "Sent announcement #{} to {} clients: {}",
+                announcement.id,
+                recipients,
+                announcement.text
+            );
+            sent += 1;
+        } else {
+            tracing::warn!(
+                "Skipped announcement #{} due at {}, {} minutes late: {}",
+                announcement.id,
+                announcement.fire_at.format("%Y-%m-%d %H:%M:%S UTC"),
+                late.num_minutes(),
+                announcement.text
+            );
+        }
+        let next = announcement
+            .repeat()
+            .map(|repeat| next_fire_at(announcement.fire_at, repeat, now));
+        service::finish_announcement(db, announcement.id, fired.then_some(now), next).await?;
+    }
+    Ok(sent)
+}
+
+/// The first time after `now` in the series starting at `fire_at`, so
+/// repeats missed while the server was down aren't caught up on
+pub fn next_fire_at(
+    fire_at: DateTime<Utc>,
+    repeat: chrono::Duration,
+    now: DateTime<Utc>,
+) -> DateTime<Utc> {
+    let missed = (now - fire_at).num_seconds().max(0) / repeat.num_seconds();
+    fire_at + repeat * (missed as i32 + 1)
+}
+
+/// Send the announcement to each logged-in client it is for, returning
+/// how many
+async fn deliver(
+    announcement: &announcement::Model,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+) -> usize {
+    let clients_map = clients.read().await;
+    let mut recipients = 0;
+    for (addr, client) in clients_map.iter() {
+        let (Some(callsign), Some(client_type)) = (client.callsign(), &client.client_type) else {
+            continue;
+        };
+        if !announcement.is_for(client_type) {
+            continue;
+        }
+        send_lines(
+            vec![announcement.text.clone()],
+            *addr,
+            callsign,
+            server_callsign,
+            broadcast_tx,
+        );
+        recipients += 1;
+    }
+    recipients
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientType};
+    use crate::db::service::NewAnnouncement;
+
+    struct Scheduler {
+        db: DatabaseConnection,
+        clients: Arc<RwLock<ClientRegistry>>,
+        broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+        rx: broadcast::Receiver<(SocketAddr, ServerMessage)>,
+        start: DateTime<Utc>,
+    }
+
+    impl Scheduler {
+        async fn new() -> Self {
+            let (broadcast_tx, rx) = broadcast::channel(64);
+            Self {
+                db: crate::db::init_ephemeral().await.unwrap(),
+                clients: Arc::new(RwLock::new(ClientRegistry::from_iter(
+                    [
+                        Client::active_for_tests(
+                            ([127, 0, 0, 1], 50001),
+                            "CCA1501",
+                            ClientType::Pilot,
+                        ),
+                        Client::active_for_tests(
+                            ([127, 0, 0, 1], 50002),
+                            "ZSPD_APP",
+                            ClientType::Atc,
+                        ),
+                    ]
+                    .map(|client| (client.addr, client)),
+                ))),
+                broadcast_tx,
+                rx,
+                start: "2026-10-17T12:00:00Z".parse().unwrap(),
+            }
+        }
+
+        fn at(&self, minutes: i64) -> DateTime<Utc> {
+            self.start + chrono::Duration::minutes(minutes)
+        }
+
+        async fn add(&self, text: &str, target: &str, minutes: i64, repeat_minutes: Option<i64>) {
+            let announcement = NewAnnouncement {
+                text: text.to_string(),
+                target: target.to_string(),
+                fire_at: self.at(minutes),
+                repeat: repeat_minutes.map(chrono::Duration::minutes),
+            };
+            service::add_announcement(&self.db, announcement, "test")
+                .await
+                .unwrap();
+        }
+
+        /// Run a check at `minutes` past the start and return what was sent
+        /// to whom
+        async fn check(&mut self, minutes: i64) -> Vec<String> {
+            let grace = chrono::Duration::minutes(5);
+            fire_due(
+                &self.db,
+                &self.clients,
+                &self.broadcast_tx,
+                "SERVER",
+                grace,
+                self.at(minutes),
+            )
+            .await
+            .unwrap();
+            let mut sent: Vec<String> = std::iter::from_fn(|| self.rx.try_recv().ok())
+                .map(|(_, msg)| match msg {
+                    ServerMessage::Direct(packet) => packet.to_string(),
+                    other => panic!("unexpected {:?}", other),
+                })
+                .collect();
+            sent.sort();
+            sent
+        }
+
+        async fn announcement(&self) -> announcement::Model {
+            let page = service::list_announcements(&self.db, false, 0, 10)
+                .await
+                .unwrap();
+            page.items.into_iter().next().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_one_shot_sent_once() {
+        let mut scheduler = Scheduler::new().await;
+        scheduler
+            .add("Event starts in 30 minutes", "all", 30, None)
+            .await;
+
+        assert!(scheduler.check(29).await.is_empty());
+        assert_eq!(
+            scheduler.check(30).await,
+            [
+                "#TMSERVER:CCA1501:Event starts in 30 minutes",
+                "#TMSERVER:ZSPD_APP:Event starts in 30 minutes",
+            ]
+        );
+        assert!(scheduler.check(31).await.is_empty());
+
+        let announcement = scheduler.announcement().await;
+        assert!(announcement.done);
+        assert_eq!(announcement.fired_at, Some(scheduler.at(30)));
+        let pending = service::list_announcements(&scheduler.db, true, 0, 10)
+            .await
+            .unwrap();
+        assert!(pending.items.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_repeating_sent_to_target_each_time() {
+        let mut scheduler = Scheduler::new().await;
+        scheduler
+            .add("Check in with ZSPD_APP", "pilots", 0, Some(60))
+            .await;
+        let sent = ["#TMSERVER:CCA1501:Check in with ZSPD_APP"];
+
+        assert_eq!(scheduler.check(2).await, sent);
+        assert!(scheduler.check(30).await.is_empty());
+        assert_eq!(scheduler.check(60).await, sent);
+        assert_eq!(scheduler.announcement().await.fire_at, scheduler.at(120));
+
+        // Down past a few repeats: nothing is caught up on, and the next
+        // one keeps to the series
+        assert!(scheduler.check(250).await.is_empty());
+        let announcement = scheduler.announcement().await;
+        assert!(!announcement.done);
+        assert_eq!(announcement.fire_at, scheduler.at(300));
+        assert_eq!(announcement.fired_at, Some(scheduler.at(60)));
+        assert_eq!(scheduler.check(301).await, sent);
+    }
+
+    #[tokio::test]
+    async fn test_late_one_shot_skipped() {
+        let mut scheduler = Scheduler::new().await;
+        scheduler.add("Event starts now", "atc", 1, None).await;
+        scheduler.add("Event starts soon", "atc", 0, None).await;
+
+        // Five minutes late is still in time, six is not
+        assert_eq!(
+            scheduler.check(6).await,
+            ["#TMSERVER:ZSPD_APP:Event starts now"]
+        );
+        let page = service::list_announcements(&scheduler.db, false, 0, 10)
+            .await
+            .unwrap();
+        let skipped = &page.items[0];
+        assert_eq!(skipped.text, "Event starts soon");
+        assert!(skipped.done);
+        assert_eq!(skipped.fired_at, None);
+        assert!(scheduler.check(7).await.is_empty());
+    }
+
+    #[test]
+    fn test_next_fire_at() {
+        let start: DateTime<Utc> = "2026-10-17T12:00:00Z".parse().unwrap();
+        let hour = chrono::Duration::hours(1);
+        assert_eq!(next_fire_at(start, hour, start), start + hour);
+        assert_eq!(
+            next_fire_at(start, hour, start + chrono::Duration::minutes(150)),
+            start + hour * 3
+        );
+    }
+}
@@ -0,0 +1,532 @@
+//! Authenticated REST API on the status listener
+//!
+//! Unlike the public feeds this exposes client addresses and software, so
+//! every request needs `Authorization: Bearer <[api] token>`:
+//!
+//! - `GET /api/server`: name, version, uptime and client counts
+//! - `GET /api/clients`: every logged-in client in full detail
+//! - `GET /api/clients/{callsign}`: one client
+//! - `GET /api/flightplans/{callsign}`: a connected pilot's filed plan
+//! - `POST /api/prefile`: store a plan for a pilot who hasn't connected yet
+//! - `DELETE /api/prefile/{id}`: withdraw a prefile
+//! - `POST /api/register`: create a pilot account, with `[api] registration`
+//!
+//! Registration alone may instead be authorized by `[api] invite_code` in
+//! the request body, so a sign-up page can use it without the token.
+
+use super::control::secrets_match;
+use super::registry::ClientRegistry;
+use super::status::StatusState;
+use crate::airports::Airports;
+use crate::auth::password;
+use crate::client::{format_frequency, Client, ClientType};
+use crate::db::entities::prefiled_flight_plan;
+use crate::db::service;
+use crate::flight_plan::FlightPlan;
+use crate::flight_progress::Eta;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{ConnectInfo, FromRequestParts, Path, State};
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub(super) fn routes(registration: bool) -> Router<Arc<StatusState>> {
+    let router = Router::new()
+        .route("/api/server", get(server))
+        .route("/api/clients", get(clients))
+        .route("/api/clients/{callsign}", get(client))
+        .route("/api/flightplans/{callsign}", get(flight_plan))
+        .route("/api/prefile", post(create_prefile))
+        .route("/api/prefile/{id}", delete(delete_prefile));
+    if registration {
+        router.route("/api/register", post(register))
+    } else {
+        router
+    }
+}
+
+/// Ratings a self-registered account starts with: observer and the first
+/// pilot rating
+const REGISTERED_ATC_RATING: i32 = 1;
+const REGISTERED_PILOT_RATING: i32 = 1;
+
+/// How long a prefile waits for its pilot unless the request says otherwise
+const DEFAULT_PREFILE_TTL_MINUTES: u32 = 24 * 60;
+
+/// Longest a prefile may wait for its pilot
+const MAX_PREFILE_TTL_MINUTES: u32 = 7 * 24 * 60;
+
+#[derive(Debug, Serialize)]
+struct ServerInfo {
+    name: String,
+    version: String,
+    hostname: String,
+    location: String,
+    uptime_secs: u64,
+    /// Logged-in clients by type, plus connections still logging in
+    clients: BTreeMap<&'static str, usize>,
+}
+
+/// A logged-in client with everything the server knows about it
+#[derive(Debug, Serialize)]
+struct ClientDetail {
+    callsign: String,
+    cid: Option<String>,
+    real_name: Option<String>,
+    /// "pilot", "atc" or "observer"
+    client_type: &'static str,
+    rating: Option<i32>,
+    address: SocketAddr,
+    is_guest: bool,
+    /// Client name and version from $ID
+    client_software: Option<String>,
+    client_id: Option<String>,
+    protocol_revision: Option<u32>,
+    logon_time: Option<DateTime<Utc>>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    altitude: Option<i32>,
+    groundspeed: Option<u32>,
+    heading: Option<u32>,
+    on_ground: Option<bool>,
+    squawk: Option<String>,
+    /// MHz, e.g. "125.100"
+    frequency: Option<String>,
+    facility: Option<u8>,
+    visual_range: Option<u32>,
+    atis: Vec<String>,
+    has_flight_plan: bool,
+    /// Type designator from the flight plan, or the pilot's `#SB` reply
+    aircraft_type: Option<String>,
+    airline: Option<String>,
+    livery: Option<String>,
+    /// Expected arrival, from the flight plan or the progress since
+    eta: Option<Eta>,
+}
+
+impl ClientDetail {
+    fn from_client(client: &Client, airports: &Airports) -> Option<Self> {
+        if !client.is_active() {
+            return None;
+        }
+        let client_type = match client.client_type.as_ref()? {
+            ClientType::Pilot => "pilot",
+            ClientType::Atc => "atc",
+            ClientType::Observer => "observer",
+        };
+        Some(Self {
+            callsign: client.callsign()?.to_string(),
+            cid: client.network_id.clone(),
+            real_name: client.real_name.clone(),
+            client_type,
+            rating: client.rating,
+            address: client.addr,
+            is_guest: client.is_guest,
+            client_software: client.client_string.clone(),
+            client_id: client.client_id.clone(),
+            protocol_revision: client.protocol_revision,
+            logon_time: client.logon_time,
+            latitude: client.latitude,
+            longitude: client.longitude,
+            altitude: client.altitude,
+            groundspeed: client.groundspeed,
+            heading: client.heading,
+            on_ground: client.on_ground,
+            squawk: client.squawk.clone(),
+            frequency: client.frequency.map(format_frequency),
+            facility: client.facility.map(|facility| facility as u8),
+            visual_range: client.declared_range_nm,
+            atis: client.atis.clone(),
+            has_flight_plan: client.flight_plan.is_some(),
+            aircraft_type: client.aircraft_type().map(str::to_string),
+            airline: client
+                .plane_info
+                .as_ref()
+                .and_then(|info| info.airline.clone()),
+            livery: client
+                .plane_info
+                .as_ref()
+                .and_then(|info| info.livery.clone()),
+            eta: client.eta(airports, Utc::now()),
+        })
+    }
+}
+
+/// Body of `POST /api/prefile`: the plan fields as `GET /api/flightplans`
+/// returns them, next to the pilot's CID and callsign
+#[derive(Debug, Deserialize)]
+struct PrefileRequest {
+    cid: String,
+    callsign: String,
+    #[serde(flatten)]
+    flight_plan: FlightPlan,
+    /// Minutes the plan waits for the pilot to connect
+    #[serde(default = "default_prefile_ttl_minutes")]
+    ttl_minutes: u32,
+}
+
+fn default_prefile_ttl_minutes() -> u32 {
+    DEFAULT_PREFILE_TTL_MINUTES
+}
+
+impl PrefileRequest {
+    fn validate(&self) -> Result<(), String> {
+        if self.cid.is_empty() || !self.cid.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(format!("Invalid CID \"{}\"", self.cid));
+        }
+        let callsign_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if self.callsign.is_empty() || !self.callsign.chars().all(callsign_char) {
+            return Err(format!("Invalid callsign \"{}\"", self.callsign));
+        }
+        if !(1..=MAX_PREFILE_TTL_MINUTES).contains(&self.ttl_minutes) {
+            return Err(format!(
+                "ttl_minutes must be between 1 and {}",
+                MAX_PREFILE_TTL_MINUTES
+            ));
+        }
+        self.flight_plan.validate().map_err(|e| e.to_string())
+    }
+}
+
+/// A stored prefile as the API returns it
+#[derive(Debug, Serialize)]
+struct PrefileRecord {
+    id: i32,
+    cid: String,
+    callsign: String,
+    #[serde(flatten)]
+    flight_plan: FlightPlan,
+    /// The plan is dropped if the pilot hasn't connected by then
+    expires_at: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl From<prefiled_flight_plan::Model> for PrefileRecord {
+    fn from(prefile: prefiled_flight_plan::Model) -> Self {
+        Self {
+            id: prefile.id,
+            flight_plan: prefile.flight_plan(),
+            cid: prefile.network_id,
+            callsign: prefile.callsign,
+            expires_at: prefile.expires_at,
+            created_at: prefile.created_at,
+            updated_at: prefile.updated_at,
+        }
+    }
+}
+
+/// Body of `POST /api/register`
+#[derive(Debug, Deserialize)]
+struct RegisterRequest {
+    cid: String,
+    password: String,
+    real_name: String,
+    /// Stands in for the bearer token when `[api] invite_code` is set
+    invite_code: Option<String>,
+}
+
+impl RegisterRequest {
+    fn validate(&self) -> Result<(), String> {
+        if self.cid.is_empty() || !self.cid.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("Invalid CID \"{}\", it must be a number", self.cid));
+        }
+        let name = self.real_name.trim();
+        if name.is_empty() || name.contains([':', '\r', '\n']) {
+            return Err("real_name must be set and may not contain ':' or line breaks".into());
+        }
+        Ok(())
+    }
+}
+
+/// An account created through the API
+#[derive(Debug, Serialize)]
+struct RegisteredUser {
+    cid: String,
+    real_name: String,
+    atc_rating: i32,
+    pilot_rating: i32,
+    created_at: DateTime<Utc>,
+}
+
+/// Proof that a request carried the configured token
+struct Authorized;
+
+impl FromRequestParts<Arc<StatusState>> for Authorized {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<StatusState>,
+    ) -> Result<Self, Self::Rejection> {
+        if has_token(&parts.headers, state) {
+            Ok(Authorized)
+        } else {
+            Err(unauthorized("A valid bearer token is required"))
+        }
+    }
+}
+
+/// Whether `headers` carry the configured bearer token
+fn has_token(headers: &HeaderMap, state: &StatusState) -> bool {
+    let expected = state.config.api.token.as_deref().unwrap_or_default();
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    given.is_some_and(|token| !expected.is_empty() && secrets_match(token, expected))
+}
+
+fn unauthorized(message: &str) -> Response {
+    (
+        [(WWW_AUTHENTICATE, "Bearer")],
+        error(StatusCode::UNAUTHORIZED, message),
+    )
+        .into_response()
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (status, Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+async fn server(_: Authorized, State(state): State<Arc<StatusState>>) -> Json<ServerInfo> {
+    let counts = crate::metrics::count_clients(&*state.clients.read().await);
+    Json(ServerInfo {
+        name: state.config.server_name.clone(),
+        version: state.config.server_version.clone(),
+        hostname: state.config.hostname.clone(),
+        location: state.config.location.clone(),
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        clients: counts.into_iter().collect(),
+    })
+}
+
+async fn clients(_: Authorized, State(state): State<Arc<StatusState>>) -> Json<Vec<ClientDetail>> {
+    let airports = state.airports.get();
+    let mut clients: Vec<ClientDetail> = state
+        .clients
+        .read()
+        .await
+        .values()
+        .filter_map(|client| ClientDetail::from_client(client, &airports))
+        .collect();
+    clients.sort_by(|a, b| a.callsign.cmp(&b.callsign));
+    Json(clients)
+}
+
+async fn client(
+    _: Authorized,
+    State(state): State<Arc<StatusState>>,
+    Path(callsign): Path<String>,
+) -> Response {
+    let clients = state.clients.read().await;
+    let airports = state.airports.get();
+    match find(&clients, &callsign).and_then(|client| ClientDetail::from_client(client, &airports))
+    {
+        Some(detail) => Json(detail).into_response(),
+        None => error(
+            StatusCode::NOT_FOUND,
+            "No client with that callsign is online",
+        ),
+    }
+}
+
+async fn flight_plan(
+    _: Authorized,
+    State(state): State<Arc<StatusState>>,
+    Path(callsign): Path<String>,
+) -> Response {
+    let clients = state.clients.read().await;
+    match find(&clients, &callsign).and_then(|client| client.flight_plan.as_ref()) {
+        Some(plan) => Json(plan).into_response(),
+        None => error(
+            StatusCode::NOT_FOUND,
+            "No flight plan is filed for that callsign",
+        ),
+    }
+}
+
+async fn create_prefile(
+    _: Authorized,
+    State(state): State<Arc<StatusState>>,
+    body: Result<Json<PrefileRequest>, JsonRejection>,
+) -> Response {
+    let request = match body {
+        Ok(Json(request)) => request,
+        Err(rejection) => return error(StatusCode::BAD_REQUEST, &rejection.body_text()),
+    };
+    if let Err(reason) = request.validate() {
+        return error(StatusCode::BAD_REQUEST, &reason);
+    }
+
+    let expires_at = Utc::now() + chrono::Duration::minutes(i64::from(request.ttl_minutes));
+    match service::upsert_prefiled_flight_plan(
+        &state.db,
+        &request.cid,
+        &request.callsign,
+        &request.flight_plan,
+        expires_at,
+    )
+    .await
+    {
+        Ok(prefile) => {
+            tracing::info!(
+                "Prefiled a flight plan for {} ({}) through the API",
+                prefile.callsign,
+                prefile.network_id
+            );
+            (StatusCode::CREATED, Json(PrefileRecord::from(prefile))).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to store a prefile for {}: {}", request.callsign, e);
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to store the prefile",
+            )
+        }
+    }
+}
+
+async fn delete_prefile(
+    _: Authorized,
+    State(state): State<Arc<StatusState>>,
+    Path(id): Path<i32>,
+) -> Response {
+    match service::delete_prefiled_flight_plan(&state.db, id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => error(StatusCode::NOT_FOUND, "No prefile with that id"),
+        Err(e) => {
+            tracing::error!("Failed to delete prefile {}: {}", id, e);
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to delete the prefile",
+            )
+        }
+    }
+}
+
+async fn register(
+    State(state): State<Arc<StatusState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Result<Json<RegisterRequest>, JsonRejection>,
+) -> Response {
+    // Every attempt counts, so the limit also slows down invite code guessing
+    if !state.registrations.try_register(peer.ip(), Instant::now()) {
+        tracing::warn!("Registration rate limit reached for {}", peer.ip());
+        return error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many registrations from this address, try again later",
+        );
+    }
+    let request = match body {
+        Ok(Json(request)) => request,
+        Err(rejection) => return error(StatusCode::BAD_REQUEST, &rejection.body_text()),
+    };
+    let invited = match (&state.config.api.invite_code, &request.invite_code) {
+        (Some(expected), Some(given)) => !expected.is_empty() && secrets_match(given, expected),
+        _ => false,
+    };
+    if !invited && !has_token(&headers, &state) {
+        return unauthorized("A valid bearer token or invite code is required");
+    }
+    if let Err(reason) = request.validate() {
+        return error(StatusCode::BAD_REQUEST, &reason);
+    }
+    if let Err(e) = password::check_policy(&request.password, &state.config.security) {
+        return error(StatusCode::BAD_REQUEST, &e.to_string());
+    }
+
+    // Soft-deleted accounts keep their CID; only an administrator may reuse it
+    match service::find_user_including_deleted(&state.db, &request.cid).await {
+        Ok(None) => {}
+        Ok(Some(_)) => return error(StatusCode::CONFLICT, "That CID is already registered"),
+        Err(e) => {
+            tracing::error!(
+                "Failed to look up CID {} for registration: {}",
+                request.cid,
+                e
+            );
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to register");
+        }
+    }
+    let password_hash = match password::hash_password(&request.password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            tracing::error!("Failed to hash a password for CID {}: {}", request.cid, e);
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to register");
+        }
+    };
+
+    let user = match service::create_user(
+        &state.db,
+        request.cid.clone(),
+        password_hash,
+        request.real_name.trim().to_string(),
+        REGISTERED_ATC_RATING,
+        REGISTERED_PILOT_RATING,
+    )
+    .await
+    {
+        Ok(user) => user,
+        // Lost a race with another registration for the same CID
+        Err(e)
+            if matches!(
+                e.sql_err(),
+                Some(sea_orm::SqlErr::UniqueConstraintViolation(_))
+            ) =>
+        {
+            return error(StatusCode::CONFLICT, "That CID is already registered");
+        }
+        Err(e) => {
+            tracing::error!("Failed to create user {}: {}", request.cid, e);
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to register");
+        }
+    };
+
+    let via = if invited { "invite code" } else { "token" };
+    let details = format!("from {} with the {}", peer.ip(), via);
+    if let Err(e) = service::record_audit_event(
+        &*state.db,
+        "api",
+        "user.register",
+        Some(&user.network_id),
+        Some(&details),
+    )
+    .await
+    {
+        tracing::error!("Failed to audit registration of {}: {}", user.network_id, e);
+    }
+    tracing::info!(
+        "Registered {} ({}) through the API {}",
+        user.network_id,
+        user.real_name,
+        details
+    );
+
+    let registered = RegisteredUser {
+        cid: user.network_id,
+        real_name: user.real_name,
+        atc_rating: user.atc_rating,
+        pilot_rating: user.pilot_rating,
+        created_at: user.created_at,
+    };
+    (StatusCode::CREATED, Json(registered)).into_response()
+}
+
+/// The logged-in client using `callsign`, in any case
+fn find<'a>(clients: &'a ClientRegistry, callsign: &str) -> Option<&'a Client> {
+    clients
+        .named(callsign)
+        .map(|(_, client)| client)
+        .filter(|client| client.is_active())
+}
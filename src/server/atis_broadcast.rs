@@ -0,0 +1,263 @@
+//! Controllers' ATIS sent to their frequency again and again
+//!
+//! Pilots who tune in after a controller uploaded its ATIS only hear it if
+//! they ask. Every `atis_rebroadcast_minutes`, [`run`] sends each
+//! controller's stored ATIS as `#TM`s from its callsign to its frequency,
+//! and an upload that changes the text is sent right away by the processor
+//! calling [`rebroadcast`]. A controller that ends its upload with
+//! `ATIS:E:(count):NOBROADCAST` is left out until an upload without it.
+
+use crate::client::Client;
+use crate::packet::{Packet, PacketType};
+use crate::server::clock::Clock;
+use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::registry::ClientRegistry;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+
+/// How often controllers whose ATIS is due are looked for
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Last field of an ATIS upload's end marker that opts out
+pub const OPT_OUT: &str = "NOBROADCAST";
+
+/// `$CR(callsign):SERVER:ATIS:E:(count)`, the end of an ATIS upload
+pub fn is_upload_end(packet: &Packet) -> bool {
+    packet.data.first().is_some_and(|data| data == "ATIS")
+        && packet.data.get(1).is_some_and(|data| data == "E")
+}
+
+/// Whether the client's ATIS should be sent by `now`: it has one and a
+/// frequency to send it to, didn't opt out, and it changed or `interval`
+/// passed since it was last sent
+pub fn is_due(client: &Client, interval: Duration, now: Instant) -> bool {
+    !client.atis.is_empty()
+        && client.frequency.is_some()
+        && !client.atis_no_rebroadcast
+        && client
+            .atis_broadcast_at
+            .is_none_or(|at| now.saturating_duration_since(at) >= interval)
+}
+
+/// The client's ATIS as `#TM(callsign):@(frequency):(line)`s
+pub fn packets(client: &Client) -> Vec<Packet> {
+    let (Some(callsign), Some(frequency)) = (client.callsign(), client.frequency) else {
+        return Vec::new();
+    };
+    client
+        .atis
+        .iter()
+        .map(|line| Packet {
+            packet_type: PacketType::Client,
+            command: "TM".to_string(),
+            source: callsign.to_string(),
+            destination: format!("@{}", frequency),
+            data: vec![line.clone()],
+        })
+        .collect()
+}
+
+/// Rebroadcast the ATIS until the server stops
+pub async fn run(
+    clients: Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+) {
+    let mut ticks = clock.interval(CHECK_INTERVAL);
+    loop {
+        ticks.tick().await;
+        rebroadcast(&clients, &broadcast_tx, interval, clock.now()).await;
+    }
+}
+
+/// Send the ATIS of each controller it is due for, returning to how many
+/// frequencies
+pub async fn rebroadcast(
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    interval: Duration,
+    now: Instant,
+) -> usize {
+    let mut sent = 0;
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    clients_map.for_each_mut(|addr, client| {
+        if !is_due(client, interval, now) {
+            return;
+        }
+        client.atis_broadcast_at = Some(now);
+        for packet in packets(client) {
+            let _ = broadcast_tx.send((*addr, ServerMessage::Packet(Arc::new(packet))));
+        }
+        sent += 1;
+    });
+    if sent > 0 {
+        tracing::debug!("Rebroadcast the ATIS of {} controllers", sent);
+    }
+    sent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ClientState, ClientType};
+    use crate::config::LimitsConfig;
+    use crate::server::clock::TestClock;
+    use crate::server::handlers::handle_response;
+
+    const ADDR: &str = "127.0.0.1:50001";
+    const INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+    fn controller() -> Client {
+        let mut client = Client::new(ADDR.parse().unwrap());
+        client.state = ClientState::Active;
+        client.client_type = Some(ClientType::Atc);
+        client.callsign = Some("ZSPD_APP".into());
+        client.frequency = Some(20650);
+        client
+    }
+
+    /// Upload an ATIS the way a controller client does, then let the
+    /// processor's immediate rebroadcast run
+    async fn upload(
+        clients: &Arc<RwLock<ClientRegistry>>,
+        broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+        lines: &[&str],
+        end: &str,
+    ) {
+        let texts = lines
+            .iter()
+            .map(|line| format!("$CRZSPD_APP:SERVER:ATIS:T:{}", line));
+        for line in texts.chain([end.to_string()]) {
+            let packet = Packet::parse(&line).unwrap();
+            let end = is_upload_end(&packet);
+            handle_response(
+                packet,
+                ADDR.parse().unwrap(),
+                clients,
+                broadcast_tx,
+                &LimitsConfig::default(),
+                "SERVER",
+            )
+            .await;
+            if end {
+                rebroadcast(
+                    clients,
+                    broadcast_tx,
+                    INTERVAL,
+                    tokio::time::Instant::now().into_std(),
+                )
+                .await;
+            }
+        }
+    }
+
+    /// The ATIS lines sent so far
+    fn sent(rx: &mut broadcast::Receiver<(SocketAddr, ServerMessage)>) -> Vec<String> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|(_, msg)| match msg {
+                ServerMessage::Packet(packet) => packet.to_string(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rebroadcast_on_interval_and_change() {
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([(
+            ADDR.parse().unwrap(),
+            controller(),
+        )])));
+        let (broadcast_tx, mut rx) = broadcast::channel(64);
+        tokio::spawn(run(
+            clients.clone(),
+            broadcast_tx.clone(),
+            INTERVAL,
+            Arc::new(TestClock::default()),
+        ));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(sent(&mut rx).is_empty());
+
+        // Sent as soon as it's uploaded
+        let end = "$CRZSPD_APP:SERVER:ATIS:E:2";
+        upload(
+            &clients,
+            &broadcast_tx,
+            &["Pudong Information Kilo", "Runway 34L"],
+            end,
+        )
+        .await;
+        let kilo = [
+            "#TMZSPD_APP:@20650:Pudong Information Kilo",
+            "#TMZSPD_APP:@20650:Runway 34L",
+        ];
+        assert_eq!(sent(&mut rx), kilo);
+
+        // Not again before the interval, then once it passed
+        tokio::time::sleep(INTERVAL - Duration::from_secs(30)).await;
+        assert!(sent(&mut rx).is_empty());
+        tokio::time::sleep(Duration::from_secs(30) + CHECK_INTERVAL).await;
+        assert_eq!(sent(&mut rx), kilo);
+
+        // The same text again doesn't count as a change
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        upload(
+            &clients,
+            &broadcast_tx,
+            &["Pudong Information Kilo", "Runway 34L"],
+            end,
+        )
+        .await;
+        assert!(sent(&mut rx).is_empty());
+
+        // A new one goes out right away and restarts the interval
+        upload(&clients, &broadcast_tx, &["Pudong Information Lima"], end).await;
+        let lima = ["#TMZSPD_APP:@20650:Pudong Information Lima"];
+        assert_eq!(sent(&mut rx), lima);
+        tokio::time::sleep(INTERVAL - Duration::from_secs(30)).await;
+        assert!(sent(&mut rx).is_empty());
+        tokio::time::sleep(Duration::from_secs(30) + CHECK_INTERVAL).await;
+        assert_eq!(sent(&mut rx), lima);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_opted_out_atis_not_rebroadcast() {
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([(
+            ADDR.parse().unwrap(),
+            controller(),
+        )])));
+        let (broadcast_tx, mut rx) = broadcast::channel(64);
+        tokio::spawn(run(
+            clients.clone(),
+            broadcast_tx.clone(),
+            INTERVAL,
+            Arc::new(TestClock::default()),
+        ));
+
+        let opted_out = "$CRZSPD_APP:SERVER:ATIS:E:1:nobroadcast";
+        upload(
+            &clients,
+            &broadcast_tx,
+            &["Pudong Information Kilo"],
+            opted_out,
+        )
+        .await;
+        tokio::time::sleep(INTERVAL * 2).await;
+        assert!(sent(&mut rx).is_empty());
+        assert_eq!(
+            clients.read().await[&ADDR.parse().unwrap()].atis,
+            ["Pudong Information Kilo"]
+        );
+
+        // Uploading without the flag opts back in
+        let end = "$CRZSPD_APP:SERVER:ATIS:E:1";
+        upload(&clients, &broadcast_tx, &["Pudong Information Kilo"], end).await;
+        assert_eq!(
+            sent(&mut rx),
+            ["#TMZSPD_APP:@20650:Pudong Information Kilo"]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use super::clock::{Clock, SystemClock};
+use super::middleware::{MiddlewareChain, PacketMiddleware};
+use super::{CancellationToken, Listeners, Server};
+use crate::config::Config;
+use crate::weather::{WeatherError, WeatherService};
+use sea_orm::DatabaseConnection;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::net::TcpListener;
+
+#[derive(Debug, Error)]
+pub enum BuildError {
+    #[error("No database connection was given")]
+    MissingDatabase,
+    #[error("Invalid configuration: {}", .0.join("; "))]
+    InvalidConfig(Vec<String>),
+    #[error("Failed to set up weather: {0}")]
+    Weather(#[from] WeatherError),
+}
+
+/// Constructs a [`Server`] from a configuration and a database connection
+///
+/// The database has to be migrated already, e.g. by [`crate::db::init`].
+/// Without a shutdown token the server gets its own, available from
+/// [`Server::shutdown_token`], and without a clock it runs on
+/// [`SystemClock`].
+///
+/// ```no_run
+/// use openfsd::config::Config;
+/// use openfsd::server::{CancellationToken, ServerBuilder};
+///
+/// # async fn example() -> Result<(), openfsd::ServerError> {
+/// let config = Config::default();
+/// let db = openfsd::db::init(&config.database).await?;
+/// let shutdown = CancellationToken::new();
+/// let server = ServerBuilder::new()
+///     .with_config(config)
+///     .with_database(db)
+///     .with_shutdown_token(shutdown.clone())
+///     .build()?;
+///
+/// // Stop the server from elsewhere with shutdown.cancel()
+/// server.run().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct ServerBuilder {
+    config: Config,
+    database: Option<DatabaseConnection>,
+    shutdown: Option<CancellationToken>,
+    clock: Option<Arc<dyn Clock>>,
+    middleware: MiddlewareChain,
+    listeners: Listeners,
+}
+
+impl ServerBuilder {
+    /// A builder with the default configuration
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `config` instead of the defaults
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Store data in `database`; required
+    pub fn with_database(mut self, database: DatabaseConnection) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    /// Stop [`Server::run`] when `token` is cancelled
+    pub fn with_shutdown_token(mut self, token: CancellationToken) -> Self {
+        self.shutdown = Some(token);
+        self
+    }
+
+    /// Take the time from `clock`, e.g. a
+    /// [`TestClock`](super::clock::TestClock) in tests
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Run `middleware` on the packets passing through, after the ones
+    /// added before it and the built-in ones under `[middleware]`
+    pub fn with_middleware(mut self, middleware: impl PacketMiddleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Serve the control socket on `listener` instead of binding
+    /// `[control] address`, e.g. to an ephemeral port in tests
+    pub fn with_control_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.control = Some(listener);
+        self
+    }
+
+    /// Serve the status feeds on `listener` instead of binding
+    /// `[status] address`
+    pub fn with_status_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.status = Some(listener);
+        self
+    }
+
+    /// Accept WebSocket clients on `listener` instead of binding
+    /// `[websocket] address`
+    pub fn with_websocket_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.websocket = Some(listener);
+        self
+    }
+
+    /// Check the configuration and create the server
+    pub fn build(self) -> Result<Server, BuildError> {
+        let database = self.database.ok_or(BuildError::MissingDatabase)?;
+        let problems = self.config.validate();
+        if !problems.is_empty() {
+            return Err(BuildError::InvalidConfig(problems));
+        }
+        let weather = WeatherService::from_config(&self.config.weather)?;
+        let mut middleware = MiddlewareChain::built_in(&self.config.middleware)
+            .map_err(|e| BuildError::InvalidConfig(vec![e.to_string()]))?;
+        middleware.append(&self.middleware);
+        Ok(Server::new(
+            self.config.into(),
+            database,
+            weather,
+            middleware,
+            self.listeners,
+            self.clock.unwrap_or_else(|| Arc::new(SystemClock)),
+            self.shutdown.unwrap_or_default(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_build_requires_database() {
+        let result = ServerBuilder::new().build();
+        assert!(matches!(result, Err(BuildError::MissingDatabase)));
+    }
+
+    #[tokio::test]
+    async fn test_build_rejects_invalid_config() {
+        let mut config = Config::default();
+        config.server.port = 0;
+        let db = crate::db::init_ephemeral().await.unwrap();
+
+        let result = ServerBuilder::new()
+            .with_config(config)
+            .with_database(db)
+            .build();
+        match result {
+            Err(BuildError::InvalidConfig(problems)) => {
+                assert!(
+                    problems.iter().any(|p| p.contains("server.port")),
+                    "{:?}",
+                    problems
+                );
+            }
+            other => panic!("expected an invalid config, got {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_on_shutdown() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = Config::default();
+        config.server.address = "127.0.0.1".to_string();
+        config.server.port = port;
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let shutdown = CancellationToken::new();
+        let server = ServerBuilder::new()
+            .with_config(config)
+            .with_database(db)
+            .with_shutdown_token(shutdown.clone())
+            .build()
+            .unwrap();
+
+        let run = tokio::spawn(async move { server.run().await.map_err(|e| e.to_string()) });
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_err()
+        {
+            assert!(Instant::now() < deadline, "server did not start");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        shutdown.cancel();
+        let result = tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("server did not stop");
+        assert_eq!(result.unwrap(), Ok(()));
+    }
+}
@@ -0,0 +1,178 @@
+//! Optional traffic, sent only to clients that said they take it
+//!
+//! Clients answer the server's `$CQ ... CAPS` with what they support, e.g.
+//! `$CRDLH123:SERVER:CAPS:ATCINFO=1:MODELDESC=1:ACCONFIG=1`, and the list is
+//! kept on the [`Client`](crate::client::Client). Aircraft configurations,
+//! `#SB` model descriptions and NEWINFO notices only reach clients that
+//! listed the capability they need. Older clients that didn't are left out,
+//! and each packet held back from one is counted in
+//! `openfsd_capability_suppressed_total`.
+
+use crate::metrics;
+use crate::packet::Packet;
+use crate::server::config::ServerMessage;
+use crate::server::registry::ClientRegistry;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Takes aircraft configurations (`ACC`)
+pub const ACCONFIG: &str = "ACCONFIG";
+/// Takes `#SB` model descriptions
+pub const MODELDESC: &str = "MODELDESC";
+/// Takes NEWINFO notices
+pub const NEWINFO: &str = "NEWINFO";
+
+/// The capabilities listed in the fields of a CAPS response, such as
+/// `ATCINFO=1`, in uppercase
+pub fn parse(fields: &[String]) -> HashSet<String> {
+    fields
+        .iter()
+        .filter_map(|field| {
+            let (name, value) = field.split_once('=')?;
+            (value.trim() == "1").then(|| name.trim().to_ascii_uppercase())
+        })
+        .collect()
+}
+
+/// The capability a client needs to be sent `packet`, if it is optional
+///
+/// A bare `ACC` request is left to the server to answer; one carrying a
+/// configuration is passed on.
+pub fn required(packet: &Packet) -> Option<&'static str> {
+    let kind = packet.data.first().map(String::as_str);
+    match (packet.command.as_str(), kind) {
+        ("CQ" | "CR", Some("ACC")) if packet.data.len() > 1 => Some(ACCONFIG),
+        ("CQ" | "CR", Some("NEWINFO")) => Some(NEWINFO),
+        ("SB", _) => Some(MODELDESC),
+        _ => None,
+    }
+}
+
+/// Pass `packet` from `sender_addr` on to its destination, or to everyone
+/// logged in if it is addressed to a group such as `@94836` or `*`, leaving
+/// out clients without `capability`
+pub fn forward(
+    packet: Packet,
+    capability: &'static str,
+    sender_addr: SocketAddr,
+    clients: &ClientRegistry,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+) {
+    let recipients: Vec<(SocketAddr, bool)> = match clients.named(&packet.destination) {
+        Some((addr, client)) => vec![(*addr, client.has_capability(capability))],
+        None if packet.destination.starts_with(['@', '*']) => clients
+            .iter()
+            .filter(|(addr, client)| **addr != sender_addr && client.is_active())
+            .map(|(addr, client)| (*addr, client.has_capability(capability)))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let packet = Arc::new(packet);
+    for (addr, capable) in recipients {
+        if capable {
+            let _ = broadcast_tx.send((addr, ServerMessage::Direct(packet.clone())));
+        } else {
+            tracing::trace!(
+                "Not sending {} to {}, which lacks {}",
+                packet.command,
+                addr,
+                capability
+            );
+            metrics::capability_suppressed(capability);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientState, ClientType};
+
+    const SENDER_ADDR: &str = "127.0.0.1:50001";
+    const MODERN_ADDR: &str = "127.0.0.1:50002";
+    const LEGACY_ADDR: &str = "127.0.0.1:50003";
+
+    fn pilot(addr: &str, callsign: &str, caps: &str) -> Client {
+        let mut client = Client::new(addr.parse().unwrap());
+        client.state = ClientState::Active;
+        client.client_type = Some(ClientType::Pilot);
+        client.callsign = Some(callsign.into());
+        let fields: Vec<String> = caps.split(':').map(str::to_string).collect();
+        client.capabilities = parse(&fields);
+        client
+    }
+
+    /// Forward each line from the sender and return who got what
+    fn deliver(lines: &[&str]) -> Vec<(SocketAddr, String)> {
+        let clients = ClientRegistry::from_iter(
+            [
+                pilot(SENDER_ADDR, "BAW789", "ACCONFIG=1"),
+                pilot(
+                    MODERN_ADDR,
+                    "DLH123",
+                    "ATCINFO=1:MODELDESC=1:ACCONFIG=1:NEWINFO=1",
+                ),
+                pilot(LEGACY_ADDR, "CCA456", ""),
+            ]
+            .map(|client| (client.addr, client)),
+        );
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+        for line in lines {
+            let packet = Packet::parse(line).unwrap();
+            let capability = required(&packet).unwrap();
+            forward(
+                packet,
+                capability,
+                SENDER_ADDR.parse().unwrap(),
+                &clients,
+                &broadcast_tx,
+            );
+        }
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|(addr, msg)| match msg {
+                ServerMessage::Direct(packet) => (addr, packet.command.clone()),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_caps_response() {
+        let packet = Packet::parse("$CRDLH123:SERVER:CAPS:ATCINFO=1:modeldesc=1:GPS=0").unwrap();
+        let caps = parse(&packet.data[1..]);
+        assert_eq!(caps, HashSet::from(["ATCINFO".into(), "MODELDESC".into()]));
+    }
+
+    #[test]
+    fn test_only_capable_clients_receive() {
+        let modern: SocketAddr = MODERN_ADDR.parse().unwrap();
+        let sent = deliver(&[
+            r#"$CQBAW789:@94836:ACC:{"config":{"gear_down":true}}"#,
+            "#SBBAW789:DLH123:PI:GEN:EQUIPMENT=B738",
+            "#SBBAW789:CCA456:PI:GEN:EQUIPMENT=B738",
+            "$CQBAW789:*:NEWINFO",
+        ]);
+        assert_eq!(
+            sent,
+            [
+                (modern, "CQ".to_string()),
+                (modern, "SB".to_string()),
+                (modern, "CQ".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plain_traffic_is_not_gated() {
+        for line in [
+            "$CQZSPD_APP:DLH123:ACC",
+            "$CQZSPD_APP:DLH123:RN",
+            "#TMZSPD_APP:DLH123:Hello",
+        ] {
+            assert_eq!(required(&Packet::parse(line).unwrap()), None, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,449 @@
+//! Writes the wire captures configured under `[capture]`
+//!
+//! Sessions hand every line they read or send to a [`Capturer`], which only
+//! queues it. A background thread applies the filters, redacts passwords and
+//! writes buffered files in the format of [`crate::capture`], so a capture
+//! can be fed straight to openfsd-replay. Once the directory holds
+//! `max_total_mb` of captures, capturing stops until the server restarts.
+
+use crate::capture::{CaptureLine, Direction};
+use crate::config::CaptureConfig;
+use crate::packet::{redact_password, Packet};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// Lines waiting for the writer; further ones are dropped
+const QUEUE_SIZE: usize = 10_000;
+
+/// Lines kept for a connection until its callsign shows whether it is
+/// captured, enough for the greeting and the login
+const MAX_HELD_LINES: usize = 32;
+
+const EXTENSION: &str = "cap";
+
+enum Record {
+    Line(SocketAddr, CaptureLine),
+    Closed(SocketAddr),
+}
+
+/// Queues lines for the capture writer
+#[derive(Clone)]
+pub struct Capturer {
+    tx: mpsc::Sender<Record>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Capturer {
+    /// Start the writer thread
+    pub fn start(config: &CaptureConfig) -> io::Result<Self> {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let writer = Writer::new(config)?;
+        let stopped = writer.stopped.clone();
+        std::thread::Builder::new()
+            .name("capture".to_string())
+            .spawn(move || writer.run(rx))?;
+        Ok(Self { tx, stopped })
+    }
+
+    /// A line read from the client
+    pub fn inbound(&self, addr: SocketAddr, line: &str) {
+        if !self.stopped.load(Ordering::Relaxed) {
+            self.push(addr, Direction::In, line);
+        }
+    }
+
+    /// A packet sent to the client
+    pub fn outbound(&self, addr: SocketAddr, packet: &Packet) {
+        if !self.stopped.load(Ordering::Relaxed) {
+            self.push(addr, Direction::Out, &packet.format());
+        }
+    }
+
+    /// A line sent to the client as it is
+    pub fn outbound_line(&self, addr: SocketAddr, line: &str) {
+        if !self.stopped.load(Ordering::Relaxed) {
+            self.push(addr, Direction::Out, line);
+        }
+    }
+
+    /// The connection is gone, so its file can be closed
+    pub async fn closed(&self, addr: SocketAddr) {
+        let _ = self.tx.send(Record::Closed(addr)).await;
+    }
+
+    fn push(&self, addr: SocketAddr, direction: Direction, line: &str) {
+        let line = CaptureLine {
+            at: Utc::now(),
+            direction,
+            packet: line.trim_end_matches(['\r', '\n']).to_string(),
+        };
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(Record::Line(addr, line)) {
+            tracing::warn!("Capture queue is full, dropping a line of {}", addr);
+        }
+    }
+}
+
+/// Capture state of one connection
+struct Connection {
+    started: DateTime<Utc>,
+    /// Whether the connection is captured, once its callsign is known
+    wanted: Option<bool>,
+    held: Vec<CaptureLine>,
+    file: Option<CaptureFile>,
+    /// Files written so far
+    parts: u32,
+}
+
+struct CaptureFile {
+    out: BufWriter<File>,
+    size: u64,
+}
+
+struct Writer {
+    config: CaptureConfig,
+    /// Bytes of captures in the directory
+    used: u64,
+    connections: HashMap<SocketAddr, Connection>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Writer {
+    fn new(config: &CaptureConfig) -> io::Result<Self> {
+        std::fs::create_dir_all(&config.directory)?;
+        Ok(Self {
+            config: config.clone(),
+            used: directory_size(&config.directory)?,
+            connections: HashMap::new(),
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    fn run(mut self, mut rx: mpsc::Receiver<Record>) {
+        while let Some(record) = rx.blocking_recv() {
+            self.handle(record);
+            // Flush once the queue is drained, so files are never far behind
+            while let Ok(record) = rx.try_recv() {
+                self.handle(record);
+            }
+            self.flush();
+            if self.stopped.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+    }
+
+    fn handle(&mut self, record: Record) {
+        match record {
+            Record::Line(addr, line) => self.capture(addr, line),
+            Record::Closed(addr) => {
+                if let Some(mut file) = self.connections.remove(&addr).and_then(|c| c.file) {
+                    let _ = file.out.flush();
+                }
+            }
+        }
+    }
+
+    fn capture(&mut self, addr: SocketAddr, line: CaptureLine) {
+        let callsigns_filtered = !self.config.callsigns.is_empty();
+        let connection = self.connections.entry(addr).or_insert_with(|| Connection {
+            started: line.at,
+            wanted: (!callsigns_filtered).then_some(true),
+            held: Vec::new(),
+            file: None,
+            parts: 0,
+        });
+        let packet = Packet::parse(&line.packet).ok();
+
+        // The first packet from the client carries its callsign
+        if connection.wanted.is_none() && line.direction == Direction::In {
+            if let Some(packet) = &packet {
+                connection.wanted = Some(
+                    self.config
+                        .callsigns
+                        .iter()
+                        .any(|callsign| callsign.eq_ignore_ascii_case(&packet.source)),
+                );
+            }
+        }
+        if connection.wanted == Some(false) {
+            connection.held.clear();
+            return;
+        }
+
+        if !self.config.commands.is_empty() {
+            let command = packet.as_ref().map(|p| p.command.as_str());
+            let wanted = command.is_some_and(|command| {
+                self.config
+                    .commands
+                    .iter()
+                    .any(|wanted| wanted.eq_ignore_ascii_case(command))
+            });
+            if !wanted {
+                return;
+            }
+        }
+
+        if connection.wanted.is_none() {
+            if connection.held.len() < MAX_HELD_LINES {
+                connection.held.push(line);
+            }
+            return;
+        }
+        for held in std::mem::take(&mut connection.held) {
+            self.write(addr, &held);
+        }
+        self.write(addr, &line);
+    }
+
+    fn write(&mut self, addr: SocketAddr, line: &CaptureLine) {
+        if self.stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        let text = format!(
+            "{}\n",
+            CaptureLine {
+                packet: redact_password(&line.packet).into_owned(),
+                ..line.clone()
+            }
+        );
+        let len = text.len() as u64;
+
+        if self.used + len > self.config.max_total_mb * 1024 * 1024 {
+            tracing::warn!(
+                "Captures in {} reached capture.max_total_mb ({} MB), capture disabled",
+                self.config.directory.display(),
+                self.config.max_total_mb
+            );
+            self.stop();
+            return;
+        }
+
+        let max_file = self.config.max_file_mb * 1024 * 1024;
+        let Some(connection) = self.connections.get_mut(&addr) else {
+            return;
+        };
+        let full = connection
+            .file
+            .as_ref()
+            .is_some_and(|file| file.size > 0 && file.size + len > max_file);
+        if connection.file.is_none() || full {
+            let path = file_path(&self.config.directory, addr, connection);
+            match File::create(&path) {
+                Ok(file) => {
+                    connection.file = Some(CaptureFile {
+                        out: BufWriter::new(file),
+                        size: 0,
+                    });
+                    connection.parts += 1;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to create capture file {}: {}", path.display(), e);
+                    connection.wanted = Some(false);
+                    connection.file = None;
+                    return;
+                }
+            }
+        }
+
+        let Some(file) = connection.file.as_mut() else {
+            return;
+        };
+        if let Err(e) = file.out.write_all(text.as_bytes()) {
+            tracing::error!("Failed to write the capture of {}: {}", addr, e);
+            connection.wanted = Some(false);
+            connection.file = None;
+            return;
+        }
+        file.size += len;
+        self.used += len;
+    }
+
+    fn flush(&mut self) {
+        for (addr, connection) in &mut self.connections {
+            if let Some(file) = &mut connection.file {
+                if let Err(e) = file.out.flush() {
+                    tracing::error!("Failed to write the capture of {}: {}", addr, e);
+                }
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.flush();
+        self.connections.clear();
+    }
+}
+
+/// File for the next part of a connection's capture, named after the time
+/// it connected and its address
+fn file_path(directory: &Path, addr: SocketAddr, connection: &Connection) -> PathBuf {
+    let ip = addr.ip().to_string().replace(':', "_");
+    let mut name = format!(
+        "{}-{}-{}",
+        connection.started.format("%Y%m%d-%H%M%S"),
+        ip,
+        addr.port()
+    );
+    if connection.parts > 0 {
+        name.push_str(&format!(".{}", connection.parts));
+    }
+    directory.join(format!("{}.{}", name, EXTENSION))
+}
+
+/// Bytes of the capture files already in `directory`
+fn directory_size(directory: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == EXTENSION) {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::read_capture;
+
+    fn test_config(name: &str) -> CaptureConfig {
+        let directory =
+            std::env::temp_dir().join(format!("openfsd-capture-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        CaptureConfig {
+            enabled: true,
+            directory,
+            ..CaptureConfig::default()
+        }
+    }
+
+    fn line(direction: Direction, packet: &str) -> CaptureLine {
+        CaptureLine {
+            at: Utc::now(),
+            direction,
+            packet: packet.to_string(),
+        }
+    }
+
+    fn session(writer: &mut Writer, addr: SocketAddr, callsign: &str) {
+        let lines = [
+            line(
+                Direction::Out,
+                "$DISERVER:CLIENT:VATSIM FSD V3.13:0123456789abcdef",
+            ),
+            line(
+                Direction::In,
+                &format!("#AP{}:SERVER:1234567:hunter2:1:100:1:Jane Doe", callsign),
+            ),
+            line(Direction::Out, &format!("#TMserver:{}:Welcome", callsign)),
+        ];
+        for line in lines {
+            writer.handle(Record::Line(addr, line));
+        }
+        writer.handle(Record::Closed(addr));
+    }
+
+    fn captures(config: &CaptureConfig) -> Vec<Vec<CaptureLine>> {
+        let mut paths: Vec<_> = std::fs::read_dir(&config.directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        paths
+            .iter()
+            .map(|path| read_capture(io::BufReader::new(File::open(path).unwrap())).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_capture_is_redacted_and_replayable() {
+        let config = test_config("redacted");
+        let mut writer = Writer::new(&config).unwrap();
+        session(&mut writer, "127.0.0.1:50001".parse().unwrap(), "CCA1501");
+
+        let captures = captures(&config);
+        assert_eq!(captures.len(), 1);
+        let packets: Vec<_> = captures[0].iter().map(|l| l.packet.as_str()).collect();
+        assert_eq!(
+            packets,
+            [
+                "$DISERVER:CLIENT:VATSIM FSD V3.13:0123456789abcdef",
+                "#APCCA1501:SERVER:1234567:***:1:100:1:Jane Doe",
+                "#TMserver:CCA1501:Welcome",
+            ]
+        );
+        assert_eq!(captures[0][1].direction, Direction::In);
+        std::fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut config = test_config("filters");
+        config.callsigns = vec!["cca1501".to_string()];
+        config.commands = vec!["AP".to_string(), "DI".to_string()];
+        let mut writer = Writer::new(&config).unwrap();
+        session(&mut writer, "127.0.0.1:50001".parse().unwrap(), "CCA1501");
+        session(&mut writer, "127.0.0.1:50002".parse().unwrap(), "CES2204");
+
+        let captures = captures(&config);
+        assert_eq!(captures.len(), 1);
+        let commands: Vec<_> = captures[0].iter().map(|l| &l.packet[..3]).collect();
+        assert_eq!(commands, ["$DI", "#AP"]);
+        std::fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_disk_cap_stops_capturing() {
+        let mut config = test_config("cap");
+        config.max_file_mb = 1;
+        config.max_total_mb = 1;
+        let mut writer = Writer::new(&config).unwrap();
+        let addr = "127.0.0.1:50001".parse().unwrap();
+        let packet = format!("#TMCCA1501:*:{}", "x".repeat(1000));
+        for _ in 0..2000 {
+            writer.handle(Record::Line(addr, line(Direction::In, &packet)));
+        }
+        writer.flush();
+
+        assert!(writer.stopped.load(Ordering::Relaxed));
+        let used = directory_size(&config.directory).unwrap();
+        assert!(used <= 1024 * 1024, "{}", used);
+        assert!(used > 1000 * 1024, "{}", used);
+        std::fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_file_rotation() {
+        let connection = Connection {
+            started: "2025-06-01T12:00:00Z".parse().unwrap(),
+            wanted: Some(true),
+            held: Vec::new(),
+            file: None,
+            parts: 0,
+        };
+        let addr = "[::1]:50001".parse().unwrap();
+        assert_eq!(
+            file_path(Path::new("captures"), addr, &connection),
+            Path::new("captures/20250601-120000-__1-50001.cap")
+        );
+        let connection = Connection {
+            parts: 2,
+            ..connection
+        };
+        assert_eq!(
+            file_path(Path::new("captures"), addr, &connection),
+            Path::new("captures/20250601-120000-__1-50001.2.cap")
+        );
+    }
+}
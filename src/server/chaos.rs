@@ -0,0 +1,302 @@
+//! Artificial latency and packet loss under `[chaos]`, for testing clients
+//! against a poor connection
+//!
+//! [`ChaosWriter`] wraps a connection's writer, so routing doesn't know about
+//! it: each packet may be dropped as it is queued, and each write is held
+//! back before it goes out. Packets a client needs to log in or to learn that
+//! someone left are never dropped. With `callsigns` set, a connection is
+//! left alone until its callsign shows that it is affected.
+
+use crate::config::ChaosConfig;
+use crate::metrics;
+use crate::packet::{Packet, PositionUpdate};
+use crate::server::connection::PacketWriter;
+use crate::server::registry::ClientRegistry;
+use crate::server::subscribers::PositionSnapshot;
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+/// Commands that are never dropped: the server identification, the auth
+/// challenge, logins, errors such as login refusals, and logoffs
+const PROTECTED_COMMANDS: &[&str] = &["DI", "ID", "ZC", "ZR", "AA", "AP", "ER", "DA", "DP"];
+
+/// The delay and loss settings, shared by every connection
+pub struct Chaos {
+    delay: Duration,
+    jitter_ms: u64,
+    drop_rate: f64,
+    /// Uppercase callsigns affected; all if empty
+    callsigns: HashSet<String>,
+    /// Callsign the server's own packets come from
+    server_callsign: String,
+}
+
+impl Chaos {
+    /// The settings if chaos is enabled and acknowledged
+    pub fn new(config: &ChaosConfig, server_callsign: &str) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        if !config.acknowledge_unsafe {
+            tracing::error!("Not injecting chaos: chaos.acknowledge_unsafe is not set");
+            return None;
+        }
+        Some(Self {
+            delay: Duration::from_millis(config.delay_ms),
+            jitter_ms: config.jitter_ms,
+            drop_rate: config.drop_rate.clamp(0.0, 1.0),
+            callsigns: config
+                .callsigns
+                .iter()
+                .map(|callsign| callsign.to_ascii_uppercase())
+                .collect(),
+            server_callsign: server_callsign.to_string(),
+        })
+    }
+
+    /// Whether `packet` is part of a login or logoff, which is never dropped
+    fn is_protected(&self, packet: &Packet) -> bool {
+        PROTECTED_COMMANDS.contains(&packet.command.as_str())
+            // The server's capabilities query completes a login
+            || (packet.command == "CQ"
+                && packet.source.eq_ignore_ascii_case(&self.server_callsign)
+                && packet.data.first().is_some_and(|d| d == "CAPS"))
+    }
+
+    /// How long to hold the next write back
+    pub fn delay(&self, rng: &mut impl Rng) -> Duration {
+        let jitter = match self.jitter_ms {
+            0 => 0,
+            max => rng.gen_range(0..=max),
+        };
+        self.delay + Duration::from_millis(jitter)
+    }
+
+    /// Whether to drop the next packet
+    pub fn drops(&self, rng: &mut impl Rng) -> bool {
+        self.drop_rate > 0.0 && rng.gen_bool(self.drop_rate)
+    }
+
+    fn affects(&self, callsign: &str) -> bool {
+        self.callsigns.is_empty() || self.callsigns.contains(&callsign.to_ascii_uppercase())
+    }
+}
+
+/// A connection's writer with the chaos settings applied
+pub struct ChaosWriter<W> {
+    inner: W,
+    chaos: Arc<Chaos>,
+    addr: SocketAddr,
+    clients: Arc<RwLock<ClientRegistry>>,
+    /// Whether the connection is affected, once that is known
+    affected: Option<bool>,
+    /// Whether anything was queued since the last flush
+    queued: bool,
+    rng: StdRng,
+}
+
+impl<W> ChaosWriter<W> {
+    pub fn new(
+        inner: W,
+        chaos: Arc<Chaos>,
+        addr: SocketAddr,
+        clients: Arc<RwLock<ClientRegistry>>,
+        rng: StdRng,
+    ) -> Self {
+        let affected = chaos.callsigns.is_empty().then_some(true);
+        Self {
+            inner,
+            chaos,
+            addr,
+            clients,
+            affected,
+            queued: false,
+            rng,
+        }
+    }
+
+    /// Whether to drop a packet with `command`, logging and counting it if so
+    fn drops(&mut self, command: &str) -> bool {
+        if self.affected != Some(true) || !self.chaos.drops(&mut self.rng) {
+            return false;
+        }
+        tracing::debug!(command, "Chaos dropped a packet to {}", self.addr);
+        metrics::chaos_dropped(command);
+        true
+    }
+}
+
+impl<W: PacketWriter> PacketWriter for ChaosWriter<W> {
+    async fn send(&mut self, packet: &Packet) -> io::Result<usize> {
+        if !self.chaos.is_protected(packet) && self.drops(&packet.command) {
+            return Ok(0);
+        }
+        self.queued = true;
+        self.inner.send(packet).await
+    }
+
+    async fn send_position(&mut self, update: &PositionUpdate) -> io::Result<usize> {
+        if self.drops(update.command()) {
+            return Ok(0);
+        }
+        self.queued = true;
+        self.inner.send_position(update).await
+    }
+
+    async fn send_snapshot(&mut self, snapshot: &PositionSnapshot) -> io::Result<usize> {
+        if self.drops("snapshot") {
+            return Ok(0);
+        }
+        self.queued = true;
+        self.inner.send_snapshot(snapshot).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if self.affected.is_none() {
+            let clients = self.clients.read().await;
+            let callsign = clients.get(&self.addr).and_then(|client| client.callsign());
+            self.affected = callsign.map(|callsign| self.chaos.affects(callsign));
+        }
+        if std::mem::take(&mut self.queued) && self.affected == Some(true) {
+            let delay = self.chaos.delay(&mut self.rng);
+            if !delay.is_zero() {
+                metrics::chaos_delay(delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+        self.inner.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    const SEED: u64 = 42;
+
+    /// Keeps the commands of what is sent
+    #[derive(Default)]
+    struct Recorder {
+        queued: Vec<String>,
+        written: Vec<String>,
+    }
+
+    impl PacketWriter for Recorder {
+        async fn send(&mut self, packet: &Packet) -> io::Result<usize> {
+            self.queued.push(packet.command.clone());
+            Ok(1)
+        }
+
+        async fn send_position(&mut self, update: &PositionUpdate) -> io::Result<usize> {
+            self.queued.push(update.command().to_string());
+            Ok(1)
+        }
+
+        async fn send_snapshot(&mut self, _snapshot: &PositionSnapshot) -> io::Result<usize> {
+            self.queued.push("snapshot".to_string());
+            Ok(1)
+        }
+
+        async fn flush(&mut self) -> io::Result<()> {
+            self.written.append(&mut self.queued);
+            Ok(())
+        }
+    }
+
+    fn chaos(delay_ms: u64, jitter_ms: u64, drop_rate: f64) -> Chaos {
+        Chaos::new(
+            &ChaosConfig {
+                enabled: true,
+                acknowledge_unsafe: true,
+                delay_ms,
+                jitter_ms,
+                drop_rate,
+                callsigns: Vec::new(),
+            },
+            "SERVER",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_needs_acknowledgement() {
+        let config = ChaosConfig {
+            enabled: true,
+            delay_ms: 100,
+            ..ChaosConfig::default()
+        };
+        assert!(Chaos::new(&config, "SERVER").is_none());
+    }
+
+    #[test]
+    fn test_delay_distribution() {
+        let chaos = chaos(100, 50, 0.0);
+        let mut rng = StdRng::seed_from_u64(SEED);
+        let samples: Vec<u64> = (0..10_000)
+            .map(|_| chaos.delay(&mut rng).as_millis() as u64)
+            .collect();
+        assert!(samples.iter().all(|ms| (100..=150).contains(ms)));
+        let mean = samples.iter().sum::<u64>() as f64 / samples.len() as f64;
+        assert!((mean - 125.0).abs() < 1.0, "mean delay {}ms", mean);
+        // Spread over the whole range, not bunched at one end
+        let low = samples.iter().filter(|ms| **ms < 125).count();
+        assert!((4500..5500).contains(&low), "{} below the middle", low);
+
+        assert_eq!(
+            self::chaos(40, 0, 0.0).delay(&mut rng),
+            Duration::from_millis(40)
+        );
+    }
+
+    #[test]
+    fn test_drop_rate() {
+        let chaos = chaos(0, 0, 0.3);
+        let mut rng = StdRng::seed_from_u64(SEED);
+        let dropped = (0..10_000).filter(|_| chaos.drops(&mut rng)).count();
+        assert!((2800..3200).contains(&dropped), "{} dropped", dropped);
+    }
+
+    #[tokio::test]
+    async fn test_login_and_logoff_never_dropped() {
+        let chaos = Arc::new(chaos(0, 0, 1.0));
+        let mut writer = ChaosWriter::new(
+            Recorder::default(),
+            chaos,
+            "127.0.0.1:6809".parse().unwrap(),
+            Arc::default(),
+            StdRng::seed_from_u64(SEED),
+        );
+        let lines = [
+            "$DISERVER:CLIENT:OpenFSD:abc123",
+            "$ZCSERVER:DLH123:0123456789abcdef",
+            "#APCCA1501:SERVER:1234567::1:100:1:Test Pilot",
+            "#AAZSPD_APP:SERVER:Test Controller:1234567::5:100",
+            "$ERSERVER:DLH123:006::Invalid password",
+            "#DPCCA1501:1234567",
+            "#DAZSPD_APP:1234567",
+            "$CQSERVER:DLH123:CAPS",
+            "#TMCCA1501:DLH123:Lost",
+            "$CQCCA1501:DLH123:CAPS",
+        ];
+        for _ in 0..100 {
+            for line in lines {
+                writer.send(&Packet::parse(line).unwrap()).await.unwrap();
+            }
+        }
+        writer.flush().await.unwrap();
+
+        let written = &writer.inner.written;
+        assert_eq!(written.len(), 800);
+        for command in ["DI", "ZC", "AP", "AA", "ER", "DP", "DA", "CQ"] {
+            let sent = written.iter().filter(|sent| *sent == command).count();
+            assert_eq!(sent, 100, "{}", command);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Where the server's tasks and handlers get the time
+//!
+//! Waiting goes through tokio's timer, so a test that pauses tokio's clock
+//! with `tokio::time::pause` and moves it with `tokio::time::advance` skips
+//! heartbeat intervals and the like without waiting for them. Wall-clock time,
+//! which ban expiries are checked against, doesn't follow tokio's clock, so
+//! [`TestClock`] derives it from tokio's clock to let tests move that forward
+//! as well.
+
+use chrono::{DateTime, Utc};
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+use tokio::time::{Interval, Sleep};
+
+pub trait Clock: Debug + Send + Sync {
+    /// Monotonic time, for timeouts and rate limits
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    /// Wall-clock time, for expiries and timestamps
+    fn utc(&self) -> DateTime<Utc>;
+
+    /// Wait for `duration`
+    fn sleep(&self, duration: Duration) -> Sleep {
+        tokio::time::sleep(duration)
+    }
+
+    /// Ticks every `period`, the first one right away
+    fn interval(&self, period: Duration) -> Interval {
+        tokio::time::interval(period)
+    }
+}
+
+/// The system's clocks
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Wall-clock time that moves with tokio's clock, for tests
+#[derive(Debug)]
+pub struct TestClock {
+    start: DateTime<Utc>,
+    started: tokio::time::Instant,
+}
+
+impl TestClock {
+    /// A clock reading `start` now
+    pub fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            start,
+            started: tokio::time::Instant::now(),
+        }
+    }
+}
+
+impl Default for TestClock {
+    fn default() -> Self {
+        Self::new(Utc::now())
+    }
+}
+
+impl Clock for TestClock {
+    fn utc(&self) -> DateTime<Utc> {
+        let elapsed = tokio::time::Instant::now().saturating_duration_since(self.started);
+        self.start + chrono::Duration::from_std(elapsed).unwrap_or(chrono::Duration::MAX)
+    }
+}
+
+/// How long until the next midnight UTC on `clock`
+pub(crate) fn until_next_utc_midnight(clock: &dyn Clock) -> Duration {
+    let now = clock.utc();
+    let midnight = (now.date_naive() + chrono::Duration::days(1))
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    (midnight - now).to_std().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wall_clock_follows_tokio() {
+        let start = DateTime::parse_from_rfc3339("2024-05-01T23:00:00Z")
+            .unwrap()
+            .to_utc();
+        let clock = TestClock::new(start);
+        assert_eq!(clock.utc(), start);
+        let started = clock.now();
+        assert_eq!(until_next_utc_midnight(&clock), Duration::from_secs(3600));
+
+        tokio::time::advance(Duration::from_secs(90 * 60)).await;
+        assert_eq!(clock.utc(), start + chrono::Duration::minutes(90));
+        assert_eq!(clock.now() - started, Duration::from_secs(90 * 60));
+        assert_eq!(
+            until_next_utc_midnight(&clock),
+            Duration::from_secs(23 * 3600 + 30 * 60)
+        );
+    }
+}
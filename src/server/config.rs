@@ -1,4 +1,20 @@
-use crate::packet::Packet;
+use crate::config::{
+    ApiConfig, AuthConfig, CaptureConfig, ChaosConfig, ControlConfig, DiagnosticsConfig, Dialect,
+    DumpConfig, FeaturesConfig, HeartbeatConfig, IdleConfig, LimitsConfig, MetricsConfig,
+    RuntimeConfig, SecurityConfig, StatusConfig, TracksConfig, VisibilityConfig, WebSocketConfig,
+    WebhooksConfig, WhazzupConfig, WhitelistConfig,
+};
+use crate::packet::{Packet, PositionUpdate};
+use crate::server::subscribers::PositionSnapshot;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+
+/// Callsign the server's own packets come from unless configured otherwise
+pub const DEFAULT_SERVER_CALLSIGN: &str = "SERVER";
+
+/// Address sent with messages that concern no one client, such as the
+/// server's own broadcasts and position snapshots
+pub const NO_CLIENT: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
 
 /// FSD Server configuration
 #[derive(Debug, Clone)]
@@ -7,7 +23,38 @@ pub struct ServerConfig {
     pub port: u16,
     pub server_name: String,
     pub server_version: String,
+    /// Host name listed in the status feeds
+    pub hostname: String,
+    pub location: String,
+    /// Callsign the server's own packets come from
+    pub server_callsign: String,
+    /// Text of the $DI identification, if not the dialect's
+    pub ident_banner: Option<String>,
     pub max_clients: usize,
+    pub motd: Vec<String>,
+    pub atc_motd: Vec<String>,
+    pub dialect: Dialect,
+    pub features: FeaturesConfig,
+    pub whitelist: WhitelistConfig,
+    pub auth: AuthConfig,
+    pub tracks: TracksConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub idle: IdleConfig,
+    pub limits: LimitsConfig,
+    pub visibility: VisibilityConfig,
+    pub control: ControlConfig,
+    pub metrics: MetricsConfig,
+    pub whazzup: WhazzupConfig,
+    pub status: StatusConfig,
+    pub api: ApiConfig,
+    pub security: SecurityConfig,
+    pub websocket: WebSocketConfig,
+    pub webhooks: WebhooksConfig,
+    pub capture: CaptureConfig,
+    pub diagnostics: DiagnosticsConfig,
+    pub chaos: ChaosConfig,
+    pub runtime: RuntimeConfig,
+    pub dump: DumpConfig,
 }
 
 impl Default for ServerConfig {
@@ -17,14 +64,65 @@ impl Default for ServerConfig {
             port: 6809,
             server_name: "OpenFSD".to_string(),
             server_version: "0.1.0".to_string(),
+            hostname: "0.0.0.0".to_string(),
+            location: String::new(),
+            server_callsign: DEFAULT_SERVER_CALLSIGN.to_string(),
+            ident_banner: None,
             max_clients: 1000,
+            motd: Vec::new(),
+            atc_motd: Vec::new(),
+            dialect: Dialect::default(),
+            features: FeaturesConfig::default(),
+            whitelist: WhitelistConfig::default(),
+            auth: AuthConfig::default(),
+            tracks: TracksConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            idle: IdleConfig::default(),
+            limits: LimitsConfig::default(),
+            visibility: VisibilityConfig::default(),
+            control: ControlConfig::default(),
+            metrics: MetricsConfig::default(),
+            whazzup: WhazzupConfig::default(),
+            status: StatusConfig::default(),
+            api: ApiConfig::default(),
+            security: SecurityConfig::default(),
+            websocket: WebSocketConfig::default(),
+            webhooks: WebhooksConfig::default(),
+            capture: CaptureConfig::default(),
+            diagnostics: DiagnosticsConfig::default(),
+            chaos: ChaosConfig::default(),
+            runtime: RuntimeConfig::default(),
+            dump: DumpConfig::default(),
         }
     }
 }
 
+impl ServerConfig {
+    /// Text of the $DI identification
+    pub fn ident_banner(&self) -> &str {
+        self.ident_banner
+            .as_deref()
+            .unwrap_or_else(|| self.dialect.banner())
+    }
+}
+
 /// Message sent from server to clients
+///
+/// Each message travels with a socket address: `Packet` goes to every client
+/// except that address, while `Direct` and `Disconnect` only affect it.
+/// `Broadcast` and `Snapshot` don't depend on it and go with [`NO_CLIENT`].
+/// Packets are shared, so a message costs the same however many clients the
+/// broadcast channel delivers it to.
 #[derive(Debug, Clone)]
 pub enum ServerMessage {
-    Packet(Packet),
-    Disconnect,
+    Packet(Arc<Packet>),
+    Direct(Arc<Packet>),
+    /// A packet from the server itself, for every client
+    Broadcast(Arc<Packet>),
+    /// A position update for one client, sent as it arrived
+    Position(Arc<PositionUpdate>),
+    /// Everyone's latest position, for the clients that take snapshots
+    Snapshot(Arc<PositionSnapshot>),
+    /// Close the connection, for the reason counted in `openfsd_kicks_total`
+    Disconnect(&'static str),
 }
@@ -1,20 +1,59 @@
-use crate::client::Client;
-use crate::packet::Packet;
+use crate::callsign::Callsign;
+use crate::client::{Client, ClientType};
+use crate::config::{Dialect, LimitsConfig};
+use crate::db::service;
+use crate::error::ServerError;
+use crate::metrics;
+use crate::packet::{redact_password, Inbound, Packet, PacketError, PositionUpdate};
+use crate::server::capture::Capturer;
+use crate::server::chaos::{Chaos, ChaosWriter};
+use crate::server::clock::Clock;
 use crate::server::config::ServerMessage;
-use std::collections::HashMap;
+use crate::server::diagnostics;
+use crate::server::elevation;
+use crate::server::follow::Follows;
+use crate::server::handler_stats::HandlerStats;
+use crate::server::health::Health;
+use crate::server::limits::{ConnectionLimiter, ParseErrorAction};
+use crate::server::middleware::{MiddlewareChain, PacketContext};
+use crate::server::pipeline::{ClientQueue, Pipeline};
+use crate::server::random::Random;
+use crate::server::registry::ClientRegistry;
+use crate::server::session::{DisconnectReason, SessionCounters, SessionSummary};
+use crate::server::subscribers::PositionSnapshot;
+use crate::stats::StatsCollector;
+use crate::webhooks::{Event, Webhooks};
+use sea_orm::DatabaseConnection;
+use std::future::Future;
+use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
 use tokio::sync::{broadcast, mpsc, RwLock};
+use tracing::Instrument;
 
-/// Generate a random 22-character hexadecimal token for server identification
-pub fn generate_token() -> String {
-    use rand::Rng;
-    let mut rng = rand::thread_rng();
-    (0..22)
-        .map(|_| format!("{:x}", rng.gen_range(0..16)))
-        .collect()
+/// How long the writer gets to send what is queued before a kick closes the
+/// connection
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Most bytes written to a client at once; a batch this big goes out even
+/// if more messages are waiting
+pub const WRITE_BATCH_BYTES: usize = 64 * 1024;
+
+/// $DI packet greeting a new connection from `server_callsign` with
+/// `banner` and a random `token`
+pub fn server_identification(server_callsign: &str, banner: &str, token: String) -> Packet {
+    // $DI(server):CLIENT:(banner):(token), which parses with the server as
+    // the destination
+    Packet {
+        packet_type: crate::packet::PacketType::Request,
+        command: "DI".to_string(),
+        destination: server_callsign.to_string(),
+        source: "CLIENT".to_string(),
+        data: vec![banner.to_string(), token],
+    }
 }
 
 /// Send a text message to a client
@@ -23,7 +62,7 @@ pub async fn send_text_message(
     from: &str,
     to: &str,
     message: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), ServerError> {
     let packet = Packet {
         packet_type: crate::packet::PacketType::Client,
         command: "TM".to_string(),
@@ -37,103 +76,898 @@ pub async fn send_text_message(
     Ok(())
 }
 
-/// Handle individual client connection
-pub async fn handle_client(
-    stream: TcpStream,
-    addr: SocketAddr,
-    packet_tx: mpsc::Sender<(SocketAddr, Packet)>,
-    mut broadcast_rx: broadcast::Receiver<(SocketAddr, ServerMessage)>,
-    clients: Arc<RwLock<HashMap<SocketAddr, Client>>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let (reader, mut writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
-    let mut line = String::new();
-
-    log::info!("Client connected from {}", addr);
-
-    // Send server identification (VATSIM protocol)
-    let server_ident = Packet {
-        packet_type: crate::packet::PacketType::Request,
-        command: "DI".to_string(),
-        destination: "SERVER".to_string(),
-        source: "CLIENT".to_string(),
-        data: vec![
-            "VATSIM FSD V3.13".to_string(),
-            generate_token(),
-        ],
-    };
-    let formatted = server_ident.format();
-    if let Err(e) = writer.write_all(formatted.as_bytes()).await {
-        log::error!("Failed to send server identification to {}: {}", addr, e);
-        return Err(e.into());
+/// A packet as a transport received it
+pub struct Frame {
+    /// The packet, or why it couldn't be read
+    pub packet: Result<Inbound, PacketError>,
+    /// Size on the wire, for the metrics
+    pub len: usize,
+    /// The line as received, for captures; the FSD form of a JSON packet
+    pub line: String,
+}
+
+/// Receiving half of a client connection
+pub trait FrameReader: Send {
+    /// The next frame from the client, or `None` once it has closed the
+    /// connection
+    fn next_frame(&mut self) -> impl Future<Output = io::Result<Option<Frame>>> + Send;
+}
+
+/// Sending half of a client connection
+///
+/// Packets are queued by `send` and `send_position` and go out on `flush`,
+/// so several can be written at once.
+pub trait PacketWriter: Send + 'static {
+    /// Queue one packet, returning its size on the wire
+    fn send(&mut self, packet: &Packet) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Queue another client's position update
+    fn send_position(
+        &mut self,
+        update: &PositionUpdate,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Queue a snapshot of everyone's positions
+    fn send_snapshot(
+        &mut self,
+        snapshot: &PositionSnapshot,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Write out everything queued
+    fn flush(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// Reads FSD lines from a byte stream such as a TCP socket
+///
+/// Lines longer than the limit are read past without being kept, so a
+/// client can't make the buffer grow by never ending a line.
+pub struct LineReader<R> {
+    reader: BufReader<R>,
+    line: Vec<u8>,
+    max_line_bytes: usize,
+}
+
+impl<R: AsyncRead + Unpin + Send> LineReader<R> {
+    pub fn new(reader: R, max_line_bytes: usize) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            line: Vec::new(),
+            max_line_bytes,
+        }
+    }
+
+    /// Throw away the rest of the current line, returning its size
+    async fn skip_line(&mut self) -> io::Result<usize> {
+        let mut skipped = 0;
+        loop {
+            let buffer = self.reader.fill_buf().await?;
+            if buffer.is_empty() {
+                return Ok(skipped);
+            }
+            let (used, done) = match buffer.iter().position(|&b| b == b'\n') {
+                Some(end) => (end + 1, true),
+                None => (buffer.len(), false),
+            };
+            self.reader.consume(used);
+            skipped += used;
+            if done {
+                return Ok(skipped);
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> FrameReader for LineReader<R> {
+    async fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        self.line.clear();
+        let limit = self.max_line_bytes as u64;
+        let len = (&mut self.reader)
+            .take(limit)
+            .read_until(b'\n', &mut self.line)
+            .await?;
+        if len == 0 {
+            return Ok(None);
+        }
+
+        if len as u64 == limit && self.line.last() != Some(&b'\n') {
+            let skipped = self.skip_line().await?;
+            return Ok(Some(Frame {
+                packet: Err(PacketError::TooLong(self.max_line_bytes)),
+                len: len + skipped,
+                // The start of the line is enough for a capture to show it
+                line: String::from_utf8_lossy(&self.line).into_owned(),
+            }));
+        }
+        let line = String::from_utf8(std::mem::take(&mut self.line))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some(Frame {
+            packet: Inbound::parse(&line),
+            len,
+            line,
+        }))
+    }
+}
+
+/// Writes FSD lines to a byte stream such as a TCP socket
+pub struct LineWriter<W> {
+    writer: W,
+    /// Lines queued since the last flush
+    buffer: Vec<u8>,
+}
+
+impl<W> LineWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send + 'static> PacketWriter for LineWriter<W> {
+    async fn send(&mut self, packet: &Packet) -> io::Result<usize> {
+        let formatted = packet.format();
+        self.buffer.extend_from_slice(formatted.as_bytes());
+        Ok(formatted.len())
     }
-    writer.flush().await?;
 
-    // Spawn task to handle outgoing messages
-    let write_handle = tokio::spawn(async move {
-        while let Ok((sender_addr, msg)) = broadcast_rx.recv().await {
-            // Don't send messages back to the sender (except for server-originated messages)
-            let is_server_message = sender_addr.port() == 0;
-            if !is_server_message && sender_addr == addr {
+    async fn send_position(&mut self, update: &PositionUpdate) -> io::Result<usize> {
+        self.buffer.extend_from_slice(update.line().as_bytes());
+        Ok(update.line().len())
+    }
+
+    async fn send_snapshot(&mut self, snapshot: &PositionSnapshot) -> io::Result<usize> {
+        self.buffer.extend_from_slice(snapshot.text.as_bytes());
+        Ok(snapshot.text.len())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.writer.write_all(&self.buffer).await?;
+        self.buffer.clear();
+        self.writer.flush().await
+    }
+}
+
+/// Everything a client session needs from the server, whatever transport
+/// the client connected over
+#[derive(Clone)]
+pub struct Sessions {
+    pub packet_tx: mpsc::Sender<(SocketAddr, Inbound)>,
+    pub broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+    pub clients: Arc<RwLock<ClientRegistry>>,
+    /// Observers following pilots, forgotten as either disconnects
+    pub follows: Arc<Follows>,
+    pub db: Arc<DatabaseConnection>,
+    pub stats: Arc<StatsCollector>,
+    pub handler_stats: Arc<HandlerStats>,
+    pub limits: LimitsConfig,
+    pub dialect: Dialect,
+    /// Callsign the server's own packets come from
+    pub server_callsign: String,
+    /// Text of the $DI identification
+    pub ident_banner: String,
+    pub max_clients: usize,
+    pub capture: Option<Capturer>,
+    /// Delay and loss injected into what clients are sent, if enabled
+    pub chaos: Option<Arc<Chaos>>,
+    pub health: Arc<Health>,
+    pub random: Arc<Random>,
+    pub clock: Arc<dyn Clock>,
+    pub pipeline: Arc<Pipeline>,
+    /// Run on what clients are sent
+    pub middleware: MiddlewareChain,
+    pub webhooks: Arc<Webhooks>,
+}
+
+impl Sessions {
+    /// Register a new connection from `addr` unless that would break a
+    /// connection limit; returns the span its session runs in
+    pub async fn admit(&self, addr: SocketAddr) -> Option<tracing::Span> {
+        if self.health.is_draining() {
+            tracing::info!("Draining, rejecting connection from {}", addr);
+            return None;
+        }
+        let mut clients = diagnostics::write_lock(&self.clients, "clients").await;
+        if clients.len() >= self.max_clients {
+            tracing::warn!("Max clients reached, rejecting connection from {}", addr);
+            return None;
+        }
+        if let Some(reason) = super::per_ip_limit_reached(&clients, addr.ip(), &self.limits) {
+            tracing::warn!("Rejecting connection from {}: {}", addr, reason);
+            return None;
+        }
+        // Listeners for different transports may see the same peer address
+        if clients.contains_key(&addr) {
+            tracing::warn!(
+                "Rejecting connection from {}: address already connected",
+                addr
+            );
+            return None;
+        }
+
+        let client = Client::new(addr);
+        let span = client.span.clone();
+        clients.insert(addr, client);
+        Some(span)
+    }
+
+    /// Run the session of an admitted connection until it closes
+    pub async fn serve(
+        self,
+        addr: SocketAddr,
+        reader: impl FrameReader,
+        writer: impl PacketWriter,
+    ) {
+        let handled = match self.chaos.clone() {
+            Some(chaos) => {
+                let rng = self.random.fork();
+                let writer = ChaosWriter::new(writer, chaos, addr, self.clients.clone(), rng);
+                self.handle_client(addr, reader, writer).await
+            }
+            None => self.handle_client(addr, reader, writer).await,
+        };
+        if let Err(e) = handled {
+            tracing::error!("Client {} error: {}", addr, e);
+        }
+    }
+
+    /// Handle individual client connection
+    async fn handle_client(
+        &self,
+        addr: SocketAddr,
+        mut reader: impl FrameReader,
+        mut writer: impl PacketWriter,
+    ) -> io::Result<()> {
+        let mut limiter = ConnectionLimiter::new(&self.limits);
+        let queue = self.pipeline.subscribe(addr);
+        let counters = match self.clients.read().await.get(&addr) {
+            Some(client) => client.counters.clone(),
+            None => Arc::default(),
+        };
+
+        tracing::info!("Client connected from {}", addr);
+
+        // Send server identification
+        let identification = server_identification(
+            &self.server_callsign,
+            &self.ident_banner,
+            self.random.token(),
+        );
+        let identified = async {
+            let len = writer.send(&identification).await?;
+            writer.flush().await.map(|_| len)
+        };
+        match identified.await {
+            Ok(len) => counters.sent(len),
+            Err(e) => {
+                tracing::error!("Failed to send server identification to {}: {}", addr, e);
+                self.pipeline.close(addr);
+                diagnostics::write_lock(&self.clients, "clients")
+                    .await
+                    .remove(&addr);
+                return Err(e);
+            }
+        }
+        let capture = self.capture.clone();
+        if let Some(capture) = &capture {
+            capture.outbound(addr, &identification);
+        }
+
+        // Spawn task to handle outgoing messages
+        let outbox = Outbox {
+            addr,
+            queue,
+            pipeline: self.pipeline.clone(),
+            handler_stats: self.handler_stats.clone(),
+            counters: counters.clone(),
+            capture: capture.clone(),
+            batch_bytes: WRITE_BATCH_BYTES,
+            clients: self.clients.clone(),
+            middleware: self.middleware.clone(),
+            callsign: OnceLock::new(),
+        };
+        let mut write_handle = tokio::spawn(outbox.run(writer).in_current_span());
+
+        // Handle incoming messages until the connection has to close
+        let reason = loop {
+            let frame = tokio::select! {
+                read = reader.next_frame() => match read {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break DisconnectReason::Closed,
+                    Err(e) => {
+                        tracing::warn!("Failed to read from {}: {}", addr, e);
+                        break DisconnectReason::from_read_error(&e);
+                    }
+                },
+                // Disconnected by the server, e.g. kicked
+                written = &mut write_handle => {
+                    break written.unwrap_or(DisconnectReason::WriteError);
+                }
+            };
+
+            let Frame { packet, len, line } = frame;
+            if let Some(capture) = &capture {
+                capture.inbound(addr, &line);
+            }
+
+            let now = self.clock.now();
+            if !limiter.allow_packet(now) {
+                tracing::warn!("Client {} is flooding the server, disconnecting", addr);
+                metrics::kick("flood");
+                self.emit_kicked(addr, "flooding").await;
+                break DisconnectReason::Kicked("flood");
+            }
+            // Some clients send blank lines to keep the connection alive
+            if line.trim().is_empty() {
+                counters.blank_line(len);
                 continue;
             }
 
-            match msg {
-                ServerMessage::Packet(packet) => {
-                    let formatted = packet.format();
-                    if let Err(e) = writer.write_all(formatted.as_bytes()).await {
-                        log::error!("Failed to send packet to {}: {}", addr, e);
-                        break;
+            match packet {
+                Ok(packet)
+                    if packet.packet_type().is_ivao() && !self.dialect.accepts_ivao_packets() =>
+                {
+                    tracing::warn!("Ignoring IVAO packet from {}: {}", addr, packet.command());
+                }
+                Ok(packet) => {
+                    tracing::debug!(
+                        command = packet.command(),
+                        bytes = len,
+                        "Received packet from {}: {}",
+                        addr,
+                        redact_password(&packet.to_string())
+                    );
+                    metrics::packet_received(packet.command(), len);
+                    self.handler_stats.record_received(packet.command());
+                    counters.received(len);
+
+                    if let Inbound::Packet(message) = &packet {
+                        if message.command == "TM" && !limiter.allow_text_message(now) {
+                            tracing::warn!(
+                                "Dropping text message from {}: rate limit reached",
+                                addr
+                            );
+                            counters.dropped();
+                            let reply = Packet {
+                                packet_type: crate::packet::PacketType::Client,
+                                command: "TM".to_string(),
+                                source: self.server_callsign.clone(),
+                                destination: message.source.clone(),
+                                data: vec!["You are sending messages too fast".to_string()],
+                            };
+                            let _ = self
+                                .broadcast_tx
+                                .send((addr, ServerMessage::Direct(Arc::new(reply))));
+                            continue;
+                        }
                     }
-                    if let Err(e) = writer.flush().await {
-                        log::error!("Failed to flush to {}: {}", addr, e);
-                        break;
+
+                    // Send packet to server for processing
+                    if self.packet_tx.send((addr, packet)).await.is_err() {
+                        tracing::error!("Failed to send packet to server");
+                        break DisconnectReason::ServerShutdown;
                     }
+                    self.pipeline.packet_queued(&self.packet_tx);
                 }
-                ServerMessage::Disconnect => {
-                    break;
+                Err(e) => {
+                    tracing::warn!("Failed to parse packet from {}: {}", addr, e);
+                    metrics::parse_error(len);
+                    self.handler_stats.record_parse_error();
+                    counters.parse_error(len);
+                    match limiter.record_parse_error(now) {
+                        ParseErrorAction::Reply => self.reply_syntax_error(addr, &e).await,
+                        ParseErrorAction::Ignore => {}
+                        ParseErrorAction::Disconnect => {
+                            tracing::warn!(
+                                "Client {} sent too many malformed packets, disconnecting",
+                                addr
+                            );
+                            metrics::kick("parse_errors");
+                            self.emit_kicked(addr, "malformed packets").await;
+                            // Queued behind the syntax errors, so those go out first
+                            let kick = ServerMessage::Disconnect("parse_errors");
+                            let _ = self.broadcast_tx.send((addr, kick));
+                            let flushed = tokio::time::timeout(FLUSH_TIMEOUT, &mut write_handle);
+                            break match flushed.await {
+                                Ok(written) => written.unwrap_or(DisconnectReason::WriteError),
+                                Err(_) => DisconnectReason::Kicked("parse_errors"),
+                            };
+                        }
+                    }
+                }
+            }
+        };
+
+        // Clean up
+        let client = diagnostics::write_lock(&self.clients, "clients")
+            .await
+            .remove(&addr);
+        if let Some(client) = client {
+            let summary = SessionSummary::new(&client, reason);
+            summary.log(addr);
+            if let Err(e) =
+                service::record_session_end(&self.db, &summary.to_record(&client.session_id)).await
+            {
+                tracing::error!("Failed to record the end of the session of {}: {}", addr, e);
+            }
+            if client.is_active() && !client.is_guest {
+                self.stats.record_disconnect();
+            }
+            if client.is_active() {
+                self.webhooks.emit(&Event::client_disconnected(&client));
+            }
+
+            // Keep the pilot's state around in case this was a crash
+            if client.is_active() && client.client_type == Some(ClientType::Pilot) {
+                if let Err(e) = service::save_position_snapshot(&self.db, &client).await {
+                    tracing::error!("Failed to save position snapshot for {}: {}", addr, e);
                 }
             }
         }
-    });
+        // Students this connection mentored go back to their own rating
+        elevation::mentor_left(
+            &self.clients,
+            addr,
+            &self.broadcast_tx,
+            &self.server_callsign,
+        )
+        .await;
+        self.follows
+            .disconnected(addr, &self.broadcast_tx, &self.server_callsign);
 
-    // Handle incoming messages
-    loop {
-        line.clear();
-        let bytes_read = reader.read_line(&mut line).await?;
+        write_handle.abort();
+        self.pipeline.close(addr);
+        if let Some(capture) = &capture {
+            capture.closed(addr).await;
+        }
+        Ok(())
+    }
+
+    /// Tell the client at `addr` that a packet it sent didn't parse
+    async fn reply_syntax_error(&self, addr: SocketAddr, error: &PacketError) {
+        let callsign = self.callsign(addr).await;
+        // $ERserver:(callsign):004::(reason)
+        let error_packet = Packet {
+            packet_type: crate::packet::PacketType::Request,
+            command: "ER".to_string(),
+            source: self.server_callsign.clone(),
+            destination: callsign.unwrap_or_else(|| "unknown".to_string()),
+            data: vec!["004".to_string(), String::new(), error.to_string()],
+        };
+        let _ = self
+            .broadcast_tx
+            .send((addr, ServerMessage::Direct(Arc::new(error_packet))));
+    }
 
-        if bytes_read == 0 {
-            log::info!("Client {} disconnected", addr);
-            break;
+    /// Report a client the connection loop disconnects to the webhooks
+    async fn emit_kicked(&self, addr: SocketAddr, reason: &str) {
+        if let Some(callsign) = self.callsign(addr).await {
+            self.webhooks.emit(&Event::client_kicked(&callsign, reason));
         }
+    }
+
+    async fn callsign(&self, addr: SocketAddr) -> Option<String> {
+        self.clients
+            .read()
+            .await
+            .get(&addr)
+            .and_then(|c| c.callsign().map(str::to_owned))
+    }
+}
+
+/// The writing half of a session
+struct Outbox {
+    addr: SocketAddr,
+    queue: ClientQueue,
+    pipeline: Arc<Pipeline>,
+    handler_stats: Arc<HandlerStats>,
+    counters: Arc<SessionCounters>,
+    capture: Option<Capturer>,
+    /// Bytes after which a batch is written even if more is waiting
+    batch_bytes: usize,
+    clients: Arc<RwLock<ClientRegistry>>,
+    middleware: MiddlewareChain,
+    /// The client's callsign, once it has logged in
+    callsign: OnceLock<Callsign>,
+}
 
-        match Packet::parse(&line) {
-            Ok(packet) => {
-                log::debug!("Received packet from {}: {}", addr, packet);
+impl Outbox {
+    /// Send messages to the client until the connection has to close,
+    /// returning why
+    ///
+    /// Once a message arrives, whatever else is queued already is taken
+    /// with it and all of it is written at once. A quiet connection still
+    /// gets each message right away, while a busy one makes one write per
+    /// batch rather than one per packet.
+    async fn run(self, mut writer: impl PacketWriter) -> DisconnectReason {
+        loop {
+            let mut packets = 0;
+            let mut bytes = 0;
+            // The queue is locked only while taking messages, so the sampler
+            // can read the depth of a writer that is stuck sending
+            let stop = {
+                let mut queue = self.queue.lock().await;
+                let mut received = queue.recv().await;
+                loop {
+                    let (target_addr, msg) = match received {
+                        Ok(message) => message,
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!(
+                                "Closing {}: it fell {} message(s) behind",
+                                self.addr,
+                                skipped
+                            );
+                            self.pipeline.lagged(skipped);
+                            break Some(DisconnectReason::Lagged);
+                        }
+                        Err(RecvError::Closed) => break Some(DisconnectReason::ServerShutdown),
+                    };
+                    match self.queue_message(&mut writer, target_addr, msg).await {
+                        Ok(Some(len)) => {
+                            packets += 1;
+                            bytes += len;
+                        }
+                        Ok(None) => {}
+                        Err(reason) => break Some(reason),
+                    }
+                    if bytes >= self.batch_bytes {
+                        break None;
+                    }
+                    received = match queue.try_recv() {
+                        Ok(message) => Ok(message),
+                        Err(TryRecvError::Empty) => break None,
+                        Err(TryRecvError::Lagged(skipped)) => Err(RecvError::Lagged(skipped)),
+                        Err(TryRecvError::Closed) => Err(RecvError::Closed),
+                    };
+                }
+            };
 
-                // Send packet to server for processing
-                if packet_tx.send((addr, packet)).await.is_err() {
-                    log::error!("Failed to send packet to server");
-                    break;
+            // What was queued before a kick still goes out
+            if packets > 0 {
+                let started = Instant::now();
+                if let Err(e) = writer.flush().await {
+                    tracing::error!("Failed to send packets to {}: {}", self.addr, e);
+                    return DisconnectReason::WriteError;
                 }
+                diagnostics::batch_written(packets, started.elapsed());
+                metrics::write_batch(packets);
             }
+            if let Some(reason) = stop {
+                return reason;
+            }
+        }
+    }
+
+    /// Queue `msg` if it is for this connection, returning its size on the
+    /// wire, or why the connection has to close
+    async fn queue_message(
+        &self,
+        writer: &mut impl PacketWriter,
+        target_addr: SocketAddr,
+        msg: ServerMessage,
+    ) -> Result<Option<usize>, DisconnectReason> {
+        // Packets for this client go through the middleware first
+        let msg = match msg {
+            ServerMessage::Packet(packet) if target_addr != self.addr => {
+                match self.outbound(packet).await {
+                    Some(packet) => ServerMessage::Packet(packet),
+                    None => return Ok(None),
+                }
+            }
+            ServerMessage::Broadcast(packet) => match self.outbound(packet).await {
+                Some(packet) => ServerMessage::Broadcast(packet),
+                None => return Ok(None),
+            },
+            ServerMessage::Direct(packet) if target_addr == self.addr => {
+                match self.outbound(packet).await {
+                    Some(packet) => ServerMessage::Direct(packet),
+                    None => return Ok(None),
+                }
+            }
+            msg => msg,
+        };
+        let (command, queued) = match &msg {
+            // Don't send messages back to the sender
+            ServerMessage::Packet(packet) if target_addr != self.addr => {
+                (packet.command.as_str(), writer.send(packet).await)
+            }
+            ServerMessage::Broadcast(packet) => {
+                (packet.command.as_str(), writer.send(packet).await)
+            }
+            ServerMessage::Direct(packet) if target_addr == self.addr => {
+                (packet.command.as_str(), writer.send(packet).await)
+            }
+            ServerMessage::Position(update) if target_addr == self.addr => {
+                (update.command(), writer.send_position(update).await)
+            }
+            ServerMessage::Snapshot(snapshot) if snapshot.recipients.contains(&self.addr) => {
+                ("snapshot", writer.send_snapshot(snapshot).await)
+            }
+            ServerMessage::Disconnect(reason) if target_addr == self.addr => {
+                return Err(DisconnectReason::Kicked(reason))
+            }
+            _ => return Ok(None),
+        };
+        let len = match queued {
+            Ok(len) => len,
             Err(e) => {
-                log::warn!("Failed to parse packet from {}: {}", addr, e);
+                tracing::error!("Failed to send packet to {}: {}", self.addr, e);
+                return Err(DisconnectReason::WriteError);
+            }
+        };
+
+        tracing::trace!(command, bytes = len, "Sent packet");
+        metrics::packet_sent(command, len);
+        self.counters.sent(len);
+        match &msg {
+            ServerMessage::Packet(packet)
+            | ServerMessage::Direct(packet)
+            | ServerMessage::Broadcast(packet) => {
+                if packet.command == "ER" {
+                    let code = packet.data.first().map_or("", String::as_str);
+                    self.handler_stats.record_error_reply(code);
+                }
+                if let Some(capture) = &self.capture {
+                    capture.outbound(self.addr, packet);
+                }
+            }
+            ServerMessage::Position(update) => {
+                if let Some(capture) = &self.capture {
+                    capture.outbound_line(self.addr, update.line());
+                }
             }
+            ServerMessage::Snapshot(snapshot) => {
+                if let Some(capture) = &self.capture {
+                    for update in &snapshot.updates {
+                        capture.outbound_line(self.addr, update.line());
+                    }
+                }
+            }
+            ServerMessage::Disconnect(_) => {}
         }
+        Ok(Some(len))
     }
 
-    // Clean up
-    {
-        let mut clients_map = clients.write().await;
-        if let Some(client) = clients_map.get(&addr) {
-            if let Some(callsign) = &client.callsign {
-                log::info!("Client {} ({}) disconnected", addr, callsign);
+    /// Pass `packet` through the middleware, returning what to send if
+    /// anything; a dropped packet counts against the client's counters
+    async fn outbound(&self, packet: Arc<Packet>) -> Option<Arc<Packet>> {
+        if self.middleware.is_empty() {
+            return Some(packet);
+        }
+        if self.callsign.get().is_none() {
+            let clients = self.clients.read().await;
+            if let Some(callsign) = clients.get(&self.addr).and_then(|c| c.callsign.clone()) {
+                let _ = self.callsign.set(callsign);
             }
         }
-        clients_map.remove(&addr);
+        let ctx = PacketContext {
+            addr: self.addr,
+            callsign: self.callsign.get().cloned(),
+        };
+        let packet = self.middleware.outbound(&ctx, packet).await;
+        if packet.is_none() {
+            self.counters.dropped();
+        }
+        packet
     }
+}
 
-    write_handle.abort();
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::config::ServerConfig;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+
+    #[test]
+    fn test_banner_per_dialect() {
+        let banner = |config: ServerConfig| {
+            let token = "0123456789abcdef012345".to_string();
+            server_identification(&config.server_callsign, config.ident_banner(), token)
+        };
+        let dialect = |dialect| ServerConfig {
+            dialect,
+            ..ServerConfig::default()
+        };
+        let vatsim = banner(dialect(Dialect::Vatsim));
+        assert_eq!(vatsim.data[0], "VATSIM FSD V3.13");
+        assert_eq!(
+            vatsim.format(),
+            "$DISERVER:CLIENT:VATSIM FSD V3.13:0123456789abcdef012345\r\n"
+        );
+        assert_eq!(banner(dialect(Dialect::Ivao)).data[0], "IVAO FSD V3.13");
+        assert_eq!(banner(dialect(Dialect::Generic)).data[0], "FSD V3.13");
+
+        let custom = banner(ServerConfig {
+            server_callsign: "OPENFSD".to_string(),
+            ident_banner: Some("OpenFSD Training".to_string()),
+            ..dialect(Dialect::Ivao)
+        });
+        assert_eq!(
+            custom.format(),
+            "$DIOPENFSD:CLIENT:OpenFSD Training:0123456789abcdef012345\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_position_written_as_parsed() {
+        for line in [
+            "@N:CCA1501:1200:1:40.08:116.58:5000:250:4194304:0\r\n",
+            "@SUAX123:7000:1:45.5:-73.5:35000:450:123456789:50\n",
+            " %ZBAA_TWR:18100:4:50:5:40.08:116.58:0 \r\n",
+        ] {
+            let mut reader = LineReader::new(line.as_bytes(), 4096);
+            let frame = reader.next_frame().await.unwrap().unwrap();
+            let Ok(Inbound::Position(update)) = frame.packet else {
+                panic!("{:?} did not take the fast path", line);
+            };
+
+            let mut parsed = LineWriter::new(Vec::new());
+            parsed.send(&Packet::parse(line).unwrap()).await.unwrap();
+            parsed.flush().await.unwrap();
+            let mut forwarded = LineWriter::new(Vec::new());
+            let len = forwarded.send_position(&update).await.unwrap();
+            forwarded.flush().await.unwrap();
+            assert_eq!(forwarded.writer, parsed.writer, "{:?}", line);
+            assert_eq!(len, parsed.writer.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_long_line_is_skipped() {
+        let input = format!(
+            "#TMDLH123:*:{}\r\n#TMDLH123:CCA456:Still here\r\n",
+            "X".repeat(10_000)
+        );
+        let mut reader = LineReader::new(input.as_bytes(), 4096);
+
+        let frame = reader.next_frame().await.unwrap().unwrap();
+        assert!(matches!(frame.packet, Err(PacketError::TooLong(4096))));
+        assert_eq!(frame.len, 10_014);
+        assert_eq!(frame.line.len(), 4096);
+
+        // The rest of the long line doesn't leak into the next one
+        let frame = reader.next_frame().await.unwrap().unwrap();
+        assert_eq!(frame.line, "#TMDLH123:CCA456:Still here\r\n");
+        assert!(frame.packet.is_ok());
+        assert!(reader.next_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_message_routing() {
+        let own: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:50002".parse().unwrap();
+        let (broadcast_tx, _) = broadcast::channel(16);
+        let pipeline = Arc::new(Pipeline::new(broadcast_tx, 16));
+        let outbox = Outbox {
+            addr: own,
+            queue: pipeline.subscribe(own),
+            pipeline: pipeline.clone(),
+            handler_stats: Arc::new(HandlerStats::new()),
+            counters: Arc::default(),
+            capture: None,
+            batch_bytes: WRITE_BATCH_BYTES,
+            clients: Arc::default(),
+            middleware: MiddlewareChain::default(),
+            callsign: OnceLock::new(),
+        };
+        let packet = Arc::new(Packet::parse("#TMDLH123:CCA456:Hello").unwrap());
+        let sent = |target, msg| {
+            let outbox = &outbox;
+            async move {
+                let mut writer = LineWriter::new(Vec::new());
+                let queued = outbox.queue_message(&mut writer, target, msg).await;
+                queued.map(|len| len.is_some())
+            }
+        };
+
+        // A packet goes to everyone but its sender
+        assert_eq!(
+            sent(own, ServerMessage::Packet(packet.clone())).await,
+            Ok(false)
+        );
+        assert_eq!(
+            sent(other, ServerMessage::Packet(packet.clone())).await,
+            Ok(true)
+        );
+        // Direct messages and kicks only concern their target
+        assert_eq!(
+            sent(own, ServerMessage::Direct(packet.clone())).await,
+            Ok(true)
+        );
+        assert_eq!(
+            sent(other, ServerMessage::Direct(packet.clone())).await,
+            Ok(false)
+        );
+        assert_eq!(
+            sent(own, ServerMessage::Disconnect("test")).await,
+            Err(DisconnectReason::Kicked("test"))
+        );
+        assert_eq!(
+            sent(other, ServerMessage::Disconnect("test")).await,
+            Ok(false)
+        );
+    }
+
+    /// Counts the writes that reach the socket
+    struct CountingWriter<W> {
+        inner: W,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    const BACKLOG: usize = 20_000;
+
+    /// Socket writes for sending a backlog of position updates to a local
+    /// client that reads and discards them
+    async fn socket_writes(batch_bytes: usize) -> usize {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink_addr = listener.local_addr().unwrap();
+        let sink = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::io::copy(&mut stream, &mut tokio::io::sink())
+                .await
+                .unwrap()
+        });
+        let writes = Arc::new(AtomicUsize::new(0));
+        let writer = LineWriter::new(CountingWriter {
+            inner: tokio::net::TcpStream::connect(sink_addr).await.unwrap(),
+            writes: writes.clone(),
+        });
+
+        let addr: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+        let (broadcast_tx, _) = broadcast::channel(32 * 1024);
+        let pipeline = Arc::new(Pipeline::new(broadcast_tx.clone(), 32 * 1024));
+        let outbox = Outbox {
+            addr,
+            queue: pipeline.subscribe(addr),
+            pipeline: pipeline.clone(),
+            handler_stats: Arc::new(HandlerStats::new()),
+            counters: Arc::default(),
+            capture: None,
+            batch_bytes,
+            clients: Arc::default(),
+            middleware: MiddlewareChain::default(),
+            callsign: OnceLock::new(),
+        };
+        let update = PositionUpdate::parse("@N:CCA1501:1200:1:40.08:116.58:5000:250:0:0").unwrap();
+        let update = Arc::new(update);
+        for _ in 0..BACKLOG {
+            let _ = broadcast_tx.send((addr, ServerMessage::Position(update.clone())));
+        }
+        let _ = broadcast_tx.send((addr, ServerMessage::Disconnect("test")));
+
+        let reason = outbox.run(writer).await;
+        let received = sink.await.unwrap();
+
+        assert_eq!(reason, DisconnectReason::Kicked("test"));
+        assert_eq!(received as usize, BACKLOG * update.line().len());
+        writes.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_writes() {
+        // A batch of one is a write per packet, as before coalescing
+        assert!(socket_writes(0).await >= BACKLOG);
+        assert!(socket_writes(WRITE_BATCH_BYTES).await < BACKLOG / 100);
+    }
 }
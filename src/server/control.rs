@@ -0,0 +1,574 @@
+use crate::airports::AirportCache;
+use crate::client::{Client, ClientType};
+use crate::db::service;
+use crate::packet::{Packet, PacketType};
+use crate::server::config::ServerMessage;
+use crate::server::dump::Dumper;
+use crate::server::handler_stats::{HandlerReport, HandlerStats};
+use crate::server::health::Health;
+use crate::server::pipeline::{Pipeline, PipelineReport};
+use crate::server::registry::ClientRegistry;
+use crate::webhooks::{Event, Webhooks};
+use sea_orm::DatabaseConnection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, RwLock};
+
+/// Time allowed for sending a request and for getting the reply
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest request line accepted, in bytes
+const MAX_REQUEST_LENGTH: u64 = 4096;
+
+/// Audit log actor for actions taken through the control socket
+const ACTOR: &str = "control";
+
+/// One request per connection, as a line of JSON
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+    secret: String,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    List,
+    Kick { callsign: String, reason: String },
+    Stats,
+    Drain,
+    Dump,
+    ReloadAirports,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Response {
+    Clients { clients: Vec<ClientInfo> },
+    Kicked,
+    Stats { stats: ServerStats },
+    Draining,
+    Dumped { path: PathBuf },
+    AirportsReloaded { count: usize },
+    Failed { message: String },
+    NotFound,
+    Unauthorized,
+    Invalid { message: String },
+}
+
+/// Packet counts, handler times and queue depths, for `stats --handlers`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerStats {
+    pub handlers: HandlerReport,
+    pub queues: PipelineReport,
+}
+
+/// A logged-in client as reported to the admin tool
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub callsign: String,
+    pub cid: Option<String>,
+    /// "pilot", "atc" or "observer"
+    pub client_type: String,
+    pub rating: Option<i32>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Knots
+    #[serde(default)]
+    pub groundspeed: Option<u32>,
+    /// Degrees
+    #[serde(default)]
+    pub heading: Option<u32>,
+    #[serde(default)]
+    pub on_ground: Option<bool>,
+    /// Seconds since login
+    pub connected_secs: u64,
+    pub ip: IpAddr,
+    /// Approximate memory the server keeps for the client, in bytes
+    #[serde(default)]
+    pub memory_bytes: u64,
+    /// Type designator from the flight plan, or the pilot's `#SB` reply
+    #[serde(default)]
+    pub aircraft_type: Option<String>,
+}
+
+impl ClientInfo {
+    fn from_client(client: &Client, now: Instant) -> Option<Self> {
+        if !client.is_active() {
+            return None;
+        }
+        let client_type = match client.client_type.as_ref()? {
+            ClientType::Pilot => "pilot",
+            ClientType::Atc => "atc",
+            ClientType::Observer => "observer",
+        };
+        Some(Self {
+            callsign: client.callsign()?.to_string(),
+            cid: client.network_id.clone(),
+            client_type: client_type.to_string(),
+            rating: client.rating,
+            latitude: client.latitude,
+            longitude: client.longitude,
+            groundspeed: client.groundspeed,
+            heading: client.heading,
+            on_ground: client.on_ground,
+            connected_secs: client
+                .logged_in_at
+                .map_or(0, |at| now.saturating_duration_since(at).as_secs()),
+            ip: client.addr.ip(),
+            memory_bytes: client.memory_estimate() as u64,
+            aircraft_type: client.aircraft_type().map(str::to_string),
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ControlError {
+    #[error("Cannot reach the control socket at {addr}: {source} (is the server running with [control] enabled?)")]
+    Connect { addr: String, source: io::Error },
+    #[error("The server refused the control secret")]
+    Unauthorized,
+    #[error("No client with callsign {0} is online")]
+    NotFound(String),
+    #[error("The server rejected the request: {0}")]
+    Invalid(String),
+    #[error("The server failed to carry out the request: {0}")]
+    Failed(String),
+    #[error("Control socket error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Unexpected reply from the control socket: {0}")]
+    Protocol(String),
+    #[error("The control socket did not answer within {}s", TIMEOUT.as_secs())]
+    Timeout,
+}
+
+/// What the control socket needs from the running server
+pub(crate) struct ControlState {
+    pub clients: Arc<RwLock<ClientRegistry>>,
+    pub broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+    pub db: Arc<DatabaseConnection>,
+    pub handler_stats: Arc<HandlerStats>,
+    pub health: Arc<Health>,
+    pub pipeline: Arc<Pipeline>,
+    pub dumper: Arc<Dumper>,
+    pub airports: Arc<AirportCache>,
+    pub webhooks: Arc<Webhooks>,
+    /// Callsign the server's own packets come from
+    pub server_callsign: String,
+    pub secret: String,
+}
+
+/// Answer control requests until the listener fails
+pub(crate) async fn serve(listener: TcpListener, state: Arc<ControlState>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!("Failed to accept a control connection: {}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, addr, &state).await {
+                tracing::warn!("Control connection from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    state: &ControlState,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST_LENGTH));
+    let mut line = String::new();
+    match tokio::time::timeout(TIMEOUT, reader.read_line(&mut line)).await {
+        Ok(read) => read?,
+        Err(_) => return Ok(()),
+    };
+
+    let response = handle_request(&line, addr, state).await;
+    let mut reply = serde_json::to_string(&response).map_err(io::Error::other)?;
+    reply.push('\n');
+    writer.write_all(reply.as_bytes()).await
+}
+
+async fn handle_request(line: &str, addr: SocketAddr, state: &ControlState) -> Response {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => {
+            return Response::Invalid {
+                message: e.to_string(),
+            }
+        }
+    };
+    if !secrets_match(&request.secret, &state.secret) {
+        tracing::warn!("Control request from {} with a wrong secret", addr);
+        return Response::Unauthorized;
+    }
+
+    match request.command {
+        Command::List => {
+            let now = Instant::now();
+            let mut clients: Vec<ClientInfo> = state
+                .clients
+                .read()
+                .await
+                .values()
+                .filter_map(|client| ClientInfo::from_client(client, now))
+                .collect();
+            clients.sort_by(|a, b| a.callsign.cmp(&b.callsign));
+            Response::Clients { clients }
+        }
+        Command::Kick { callsign, reason } => kick(state, &callsign, &reason).await,
+        Command::Stats => Response::Stats {
+            stats: ServerStats {
+                handlers: state.handler_stats.report(),
+                queues: state.pipeline.report(&*state.clients.read().await),
+            },
+        },
+        Command::Drain => {
+            if state.health.start_draining() {
+                tracing::warn!(
+                    "Draining on request of the control socket: new connections are refused"
+                );
+                if let Err(e) =
+                    service::record_audit_event(&*state.db, ACTOR, "server.drain", None, None).await
+                {
+                    tracing::error!("Failed to record the drain: {}", e);
+                }
+            }
+            Response::Draining
+        }
+        Command::Dump => match state.dumper.dump().await {
+            Ok(path) => Response::Dumped { path },
+            Err(e) => {
+                tracing::error!("Failed to dump the server state: {}", e);
+                Response::Failed {
+                    message: e.to_string(),
+                }
+            }
+        },
+        Command::ReloadAirports => match state.airports.reload(&state.db).await {
+            Ok(count) => Response::AirportsReloaded { count },
+            Err(e) => {
+                tracing::error!("Failed to reload airports: {}", e);
+                Response::Failed {
+                    message: e.to_string(),
+                }
+            }
+        },
+    }
+}
+
+/// Tell a client why it is being removed, then disconnect it
+async fn kick(state: &ControlState, callsign: &str, reason: &str) -> Response {
+    let target = state
+        .clients
+        .read()
+        .await
+        .named(callsign)
+        .filter(|(_, client)| client.is_active())
+        .and_then(|(addr, client)| Some((*addr, client.callsign()?.to_string())));
+    let Some((addr, callsign)) = target else {
+        return Response::NotFound;
+    };
+
+    tracing::warn!(
+        "Kicking {} ({}) from the control socket: {}",
+        callsign,
+        addr,
+        reason
+    );
+    let notice = Packet {
+        packet_type: PacketType::Client,
+        command: "TM".to_string(),
+        source: state.server_callsign.clone(),
+        destination: callsign.clone(),
+        data: vec![format!("You have been disconnected: {}", reason)],
+    };
+    let _ = state
+        .broadcast_tx
+        .send((addr, ServerMessage::Direct(Arc::new(notice))));
+    let _ = state
+        .broadcast_tx
+        .send((addr, ServerMessage::Disconnect("admin")));
+    crate::metrics::kick("admin");
+    state
+        .webhooks
+        .emit(&Event::client_kicked(&callsign, reason));
+
+    if let Err(e) = service::record_audit_event(
+        &*state.db,
+        ACTOR,
+        "client.kick",
+        Some(&callsign),
+        Some(reason),
+    )
+    .await
+    {
+        tracing::error!("Failed to record the kick of {}: {}", callsign, e);
+    }
+    Response::Kicked
+}
+
+/// Compare digests so the time taken doesn't depend on where the secrets differ
+pub(crate) fn secrets_match(given: &str, expected: &str) -> bool {
+    let given = Sha256::digest(given.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    given
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// List the clients logged in to the server behind `addr`
+pub async fn list_clients(addr: &str, secret: &str) -> Result<Vec<ClientInfo>, ControlError> {
+    match send(addr, secret, Command::List).await? {
+        Response::Clients { clients } => Ok(clients),
+        other => Err(ControlError::Protocol(format!("{:?}", other))),
+    }
+}
+
+/// Disconnect the client using `callsign`, telling it the reason
+pub async fn kick_client(
+    addr: &str,
+    secret: &str,
+    callsign: &str,
+    reason: &str,
+) -> Result<(), ControlError> {
+    let command = Command::Kick {
+        callsign: callsign.to_string(),
+        reason: reason.to_string(),
+    };
+    match send(addr, secret, command).await? {
+        Response::Kicked => Ok(()),
+        Response::NotFound => Err(ControlError::NotFound(callsign.to_string())),
+        other => Err(ControlError::Protocol(format!("{:?}", other))),
+    }
+}
+
+/// Make the server behind `addr` refuse new connections while keeping the
+/// ones it has
+pub async fn drain(addr: &str, secret: &str) -> Result<(), ControlError> {
+    match send(addr, secret, Command::Drain).await? {
+        Response::Draining => Ok(()),
+        other => Err(ControlError::Protocol(format!("{:?}", other))),
+    }
+}
+
+/// Make the server behind `addr` write its state to a JSON file, returning
+/// the file's path on the server
+pub async fn dump_state(addr: &str, secret: &str) -> Result<PathBuf, ControlError> {
+    match send(addr, secret, Command::Dump).await? {
+        Response::Dumped { path } => Ok(path),
+        other => Err(ControlError::Protocol(format!("{:?}", other))),
+    }
+}
+
+/// Make the server behind `addr` read the airports from the database again,
+/// returning how many it has
+pub async fn reload_airports(addr: &str, secret: &str) -> Result<usize, ControlError> {
+    match send(addr, secret, Command::ReloadAirports).await? {
+        Response::AirportsReloaded { count } => Ok(count),
+        other => Err(ControlError::Protocol(format!("{:?}", other))),
+    }
+}
+
+/// Packet counts, handler times and queue depths of the server behind `addr`
+pub async fn server_stats(addr: &str, secret: &str) -> Result<ServerStats, ControlError> {
+    match send(addr, secret, Command::Stats).await? {
+        Response::Stats { stats } => Ok(stats),
+        other => Err(ControlError::Protocol(format!("{:?}", other))),
+    }
+}
+
+async fn send(addr: &str, secret: &str, command: Command) -> Result<Response, ControlError> {
+    let request = Request {
+        secret: secret.to_string(),
+        command,
+    };
+    let exchange = async {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|source| ControlError::Connect {
+                addr: addr.to_string(),
+                source,
+            })?;
+        let (reader, mut writer) = stream.into_split();
+        let mut line =
+            serde_json::to_string(&request).map_err(|e| ControlError::Protocol(e.to_string()))?;
+        line.push('\n');
+        writer.write_all(line.as_bytes()).await?;
+
+        line.clear();
+        BufReader::new(reader).read_line(&mut line).await?;
+        serde_json::from_str(&line).map_err(|e| ControlError::Protocol(e.to_string()))
+    };
+
+    match tokio::time::timeout(TIMEOUT, exchange).await {
+        Err(_) => Err(ControlError::Timeout),
+        Ok(Ok(Response::Unauthorized)) => Err(ControlError::Unauthorized),
+        Ok(Ok(Response::Invalid { message })) => Err(ControlError::Invalid(message)),
+        Ok(Ok(Response::Failed { message })) => Err(ControlError::Failed(message)),
+        Ok(response) => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientState;
+
+    /// A control socket on a free port with one pilot online
+    async fn start() -> (String, broadcast::Receiver<(SocketAddr, ServerMessage)>) {
+        start_with(Arc::new(HandlerStats::new()), Arc::new(Health::new())).await
+    }
+
+    async fn start_with(
+        handler_stats: Arc<HandlerStats>,
+        health: Arc<Health>,
+    ) -> (String, broadcast::Receiver<(SocketAddr, ServerMessage)>) {
+        let mut pilot = Client::new("127.0.0.1:50001".parse().unwrap());
+        pilot.state = ClientState::Active;
+        pilot.client_type = Some(ClientType::Pilot);
+        pilot.callsign = Some("CCA1501".into());
+        pilot.network_id = Some("1234567".to_string());
+        pilot.logged_in_at = Some(Instant::now());
+        let (broadcast_tx, rx) = broadcast::channel(16);
+        let pipeline = Arc::new(Pipeline::new(broadcast_tx.clone(), 16));
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([(pilot.addr, pilot)])));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let dumper = Dumper {
+            config: crate::config::DumpConfig {
+                directory: std::env::temp_dir().join(format!("openfsd-dumps-{}", addr)),
+                redact_ips: false,
+            },
+            clients: clients.clone(),
+            callsign_map: Arc::default(),
+            pipeline: pipeline.clone(),
+            handler_stats: handler_stats.clone(),
+            stats: Arc::new(crate::stats::StatsCollector::new()),
+        };
+        let state = ControlState {
+            clients,
+            broadcast_tx,
+            db: Arc::new(crate::db::init_ephemeral().await.unwrap()),
+            handler_stats,
+            health,
+            pipeline,
+            dumper: Arc::new(dumper),
+            airports: Arc::default(),
+            webhooks: Arc::default(),
+            server_callsign: "SERVER".to_string(),
+            secret: "s3cret".to_string(),
+        };
+
+        tokio::spawn(serve(listener, Arc::new(state)));
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn test_list_and_kick() {
+        let (addr, mut rx) = start().await;
+
+        let clients = list_clients(&addr, "s3cret").await.unwrap();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].callsign, "CCA1501");
+        assert_eq!(clients[0].cid.as_deref(), Some("1234567"));
+        assert_eq!(clients[0].client_type, "pilot");
+
+        assert!(matches!(
+            kick_client(&addr, "s3cret", "CSN6311", "Test").await,
+            Err(ControlError::NotFound(_))
+        ));
+        kick_client(&addr, "s3cret", "cca1501", "Test")
+            .await
+            .unwrap();
+        let (_, notice) = rx.try_recv().unwrap();
+        assert!(
+            matches!(notice, ServerMessage::Direct(packet) if packet.data[0].ends_with("Test"))
+        );
+        assert!(matches!(
+            rx.try_recv().unwrap().1,
+            ServerMessage::Disconnect("admin")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_server_stats() {
+        let stats = Arc::new(HandlerStats::new());
+        stats.record_received("AX");
+        stats.record_handled("AX", Duration::from_millis(30));
+        let (addr, _rx) = start_with(stats.clone(), Arc::new(Health::new())).await;
+
+        let report = server_stats(&addr, "s3cret").await.unwrap();
+        assert_eq!(report.handlers, stats.report());
+        assert_eq!(report.handlers.commands[0].command, "AX");
+        assert_eq!(report.queues.broadcast.depth, 0);
+        assert_eq!(report.queues.lagged, 0);
+        assert!(matches!(
+            server_stats(&addr, "guess").await,
+            Err(ControlError::Unauthorized)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let health = Arc::new(Health::new());
+        let (addr, _rx) = start_with(Arc::new(HandlerStats::new()), health.clone()).await;
+
+        drain(&addr, "s3cret").await.unwrap();
+        assert!(health.is_draining());
+        // Asking again is harmless
+        drain(&addr, "s3cret").await.unwrap();
+        assert_eq!(list_clients(&addr, "s3cret").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dump() {
+        let (addr, _rx) = start().await;
+
+        let path = dump_state(&addr, "s3cret").await.unwrap();
+        let dump: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(dump["clients"][0]["callsign"], "CCA1501");
+        assert_eq!(dump["clients"][0]["network_id"], "1234567");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_airports() {
+        let (addr, _rx) = start().await;
+        // An empty table is fine; lookups just find nothing
+        assert_eq!(reload_airports(&addr, "s3cret").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_secret() {
+        let (addr, mut rx) = start().await;
+        assert!(matches!(
+            list_clients(&addr, "guess").await,
+            Err(ControlError::Unauthorized)
+        ));
+        assert!(matches!(
+            kick_client(&addr, "", "CCA1501", "Test").await,
+            Err(ControlError::Unauthorized)
+        ));
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,71 @@
+//! Warnings about slow packet handlers, lock waits and client writes
+//!
+//! All packets go through one processor task and most handlers take the
+//! clients write lock, so one stalled handler or lock holder shows up as lag
+//! for everyone. Anything over its threshold is logged in the span of the
+//! connection it happened on, which names the callsign and command, and is
+//! counted in the metrics. The thresholds are set once at startup; a
+//! threshold of 0 turns that warning off.
+
+use crate::config::DiagnosticsConfig;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{RwLock, RwLockWriteGuard};
+
+static SLOW_PACKET_MS: AtomicU64 = AtomicU64::new(DiagnosticsConfig::DEFAULT_SLOW_PACKET_MS);
+static SLOW_LOCK_MS: AtomicU64 = AtomicU64::new(DiagnosticsConfig::DEFAULT_SLOW_LOCK_MS);
+static SLOW_WRITE_MS: AtomicU64 = AtomicU64::new(DiagnosticsConfig::DEFAULT_SLOW_WRITE_MS);
+
+/// Use the thresholds of `config` from now on
+pub fn configure(config: &DiagnosticsConfig) {
+    SLOW_PACKET_MS.store(config.slow_packet_ms, Ordering::Relaxed);
+    SLOW_LOCK_MS.store(config.slow_lock_ms, Ordering::Relaxed);
+    SLOW_WRITE_MS.store(config.slow_write_ms, Ordering::Relaxed);
+}
+
+/// Whether `elapsed` is over the threshold in `limit`, 0 meaning never
+fn exceeds(limit: &AtomicU64, elapsed: Duration) -> bool {
+    let limit = limit.load(Ordering::Relaxed);
+    limit > 0 && elapsed.as_millis() >= u128::from(limit)
+}
+
+/// Warn if handling a packet with `command` took too long
+pub fn packet_handled(command: &str, elapsed: Duration) {
+    if exceeds(&SLOW_PACKET_MS, elapsed) {
+        crate::metrics::slow_operation("packet");
+        tracing::warn!(
+            command,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "Slow packet handler"
+        );
+    }
+}
+
+/// Warn if writing a batch of `packets` to a client took too long
+pub fn batch_written(packets: usize, elapsed: Duration) {
+    if exceeds(&SLOW_WRITE_MS, elapsed) {
+        crate::metrics::slow_operation("write");
+        tracing::warn!(
+            packets,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "Slow write to client"
+        );
+    }
+}
+
+/// Take the write half of `lock`, named `name` in the warning if that takes
+/// too long
+pub async fn write_lock<'a, T>(lock: &'a RwLock<T>, name: &'static str) -> RwLockWriteGuard<'a, T> {
+    let started = Instant::now();
+    let guard = lock.write().await;
+    let elapsed = started.elapsed();
+    if exceeds(&SLOW_LOCK_MS, elapsed) {
+        crate::metrics::slow_operation("lock");
+        tracing::warn!(
+            lock = name,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "Slow to acquire a write lock"
+        );
+    }
+    guard
+}
@@ -0,0 +1,361 @@
+//! Snapshots of the whole server state as JSON, for debugging production
+//!
+//! A dump is taken on SIGUSR1 or the control socket's `dump` command and
+//! written to a timestamped file in `[dump] directory`, so two of them can be
+//! diffed. The clients and the callsign map are copied together under their
+//! read locks, which a dump gives up on after [`LOCK_TIMEOUT`] rather than
+//! hold up packet processing; the file is written once the locks are released.
+
+use crate::callsign::Callsign;
+use crate::client::{Client, ClientType, Facility};
+use crate::config::DumpConfig;
+use crate::flight_plan::FlightPlan;
+use crate::server::handler_stats::{HandlerReport, HandlerStats};
+use crate::server::pipeline::{Pipeline, PipelineReport};
+use crate::server::registry::ClientRegistry;
+use crate::server::session::SessionCounters;
+use crate::stats::{DailyStats, StatsCollector};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::RwLock;
+
+/// Longest a dump waits for the read locks before giving up
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+pub enum DumpError {
+    #[error("The server state stayed locked for {}s, try again", LOCK_TIMEOUT.as_secs())]
+    Busy,
+    #[error("Failed to serialize the state dump: {0}")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Failed to write the state dump to {path}: {source}")]
+    Write { path: PathBuf, source: io::Error },
+}
+
+/// The server state at one point in time
+#[derive(Debug, Serialize)]
+pub struct StateDump {
+    pub taken_at: DateTime<Utc>,
+    /// Every connection, logged in or not, by callsign and then address
+    pub clients: Vec<ClientDump>,
+    /// Address each callsign is registered to
+    pub callsigns: BTreeMap<String, SocketAddr>,
+    /// Flight plans on file, by callsign
+    pub flight_plans: BTreeMap<String, FlightPlan>,
+    /// Controller tracking each aircraft, by aircraft callsign
+    pub track_owners: BTreeMap<String, String>,
+    /// Beacon codes assigned by controllers, by aircraft callsign
+    pub squawks: BTreeMap<String, String>,
+    pub queues: PipelineReport,
+    pub handlers: HandlerReport,
+    /// Statistics of the current UTC day
+    pub stats: DailyStats,
+}
+
+/// A connection with everything the server keeps about it, except secrets
+/// such as a pending auth challenge
+#[derive(Debug, Serialize)]
+pub struct ClientDump {
+    pub session_id: String,
+    pub addr: SocketAddr,
+    /// "connected", "identified", "active" or "disconnected"
+    pub state: String,
+    pub callsign: Option<String>,
+    /// "pilot", "atc" or "observer"
+    pub client_type: Option<String>,
+    pub real_name: Option<String>,
+    pub network_id: Option<String>,
+    pub rating: Option<i32>,
+    pub protocol_revision: Option<u32>,
+    pub is_guest: bool,
+    pub client_string: Option<String>,
+    pub client_id: Option<String>,
+    pub unverified_client: bool,
+    pub challenge_pending: bool,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub altitude: Option<i32>,
+    pub groundspeed: Option<u32>,
+    pub heading: Option<u32>,
+    pub facility: Option<String>,
+    pub frequency: Option<u32>,
+    pub declared_range_nm: Option<u32>,
+    pub squawk: Option<String>,
+    pub assigned_squawk: Option<String>,
+    pub tracking_controller: Option<String>,
+    /// Seconds since the connection was accepted
+    pub connected_secs: u64,
+    /// Seconds since login
+    pub logged_in_secs: Option<u64>,
+    pub logon_time: Option<DateTime<Utc>>,
+    pub flight_plan: Option<FlightPlan>,
+    pub flight_plan_reminded: bool,
+    pub atis: Vec<String>,
+    pub atis_updated_at: Option<DateTime<Utc>>,
+    /// ATIS lines received since the last end marker
+    pub atis_upload: Vec<String>,
+    pub traffic: Traffic,
+}
+
+/// The counters of a connection so far
+#[derive(Debug, Serialize)]
+pub struct Traffic {
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub position_updates: u64,
+    pub parse_errors: u64,
+}
+
+impl Traffic {
+    fn new(counters: &SessionCounters) -> Self {
+        Self {
+            packets_in: counters.packets_in.load(Ordering::Relaxed),
+            packets_out: counters.packets_out.load(Ordering::Relaxed),
+            bytes_in: counters.bytes_in.load(Ordering::Relaxed),
+            bytes_out: counters.bytes_out.load(Ordering::Relaxed),
+            position_updates: counters.position_updates.load(Ordering::Relaxed),
+            parse_errors: counters.parse_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ClientDump {
+    fn new(client: &Client, now: Instant, redact_ips: bool) -> Self {
+        Self {
+            session_id: client.session_id.clone(),
+            addr: address(client.addr, redact_ips),
+            state: format!("{:?}", client.state).to_lowercase(),
+            callsign: client.callsign().map(str::to_owned),
+            client_type: client.client_type.as_ref().map(|client_type| {
+                match client_type {
+                    ClientType::Pilot => "pilot",
+                    ClientType::Atc => "atc",
+                    ClientType::Observer => "observer",
+                }
+                .to_string()
+            }),
+            real_name: client.real_name.clone(),
+            network_id: client.network_id.clone(),
+            rating: client.rating,
+            protocol_revision: client.protocol_revision,
+            is_guest: client.is_guest,
+            client_string: client.client_string.clone(),
+            client_id: client.client_id.clone(),
+            unverified_client: client.unverified_client,
+            challenge_pending: client.auth_challenge.is_some(),
+            latitude: client.latitude,
+            longitude: client.longitude,
+            altitude: client.altitude,
+            groundspeed: client.groundspeed,
+            heading: client.heading,
+            facility: client.facility.map(facility_name),
+            frequency: client.frequency,
+            declared_range_nm: client.declared_range_nm,
+            squawk: client.squawk.clone(),
+            assigned_squawk: client.assigned_squawk.clone(),
+            tracking_controller: client.tracking_controller.clone(),
+            connected_secs: now.saturating_duration_since(client.connected_at).as_secs(),
+            logged_in_secs: client
+                .logged_in_at
+                .map(|at| now.saturating_duration_since(at).as_secs()),
+            logon_time: client.logon_time,
+            flight_plan: client.flight_plan.clone(),
+            flight_plan_reminded: client.flight_plan_reminded,
+            atis: client.atis.clone(),
+            atis_updated_at: client.atis_updated_at,
+            atis_upload: client.atis_upload.clone(),
+            traffic: Traffic::new(&client.counters),
+        }
+    }
+}
+
+fn facility_name(facility: Facility) -> String {
+    format!("{:?}", facility).to_lowercase()
+}
+
+/// `addr`, or only its port if IP addresses are redacted
+fn address(addr: SocketAddr, redact_ips: bool) -> SocketAddr {
+    if !redact_ips {
+        return addr;
+    }
+    let ip = match addr.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    SocketAddr::new(ip, addr.port())
+}
+
+/// `field` of every client that has a callsign and a value for it
+fn by_callsign<T>(
+    clients: &[ClientDump],
+    field: impl Fn(&ClientDump) -> Option<T>,
+) -> BTreeMap<String, T> {
+    clients
+        .iter()
+        .filter_map(|client| Some((client.callsign.clone()?, field(client)?)))
+        .collect()
+}
+
+/// Takes state dumps of the running server
+pub(crate) struct Dumper {
+    pub config: DumpConfig,
+    pub clients: Arc<RwLock<ClientRegistry>>,
+    pub callsign_map: Arc<RwLock<HashMap<Callsign, SocketAddr>>>,
+    pub pipeline: Arc<Pipeline>,
+    pub handler_stats: Arc<HandlerStats>,
+    pub stats: Arc<StatsCollector>,
+}
+
+impl Dumper {
+    /// Write a dump of the current state, returning the file it went to
+    pub async fn dump(&self) -> Result<PathBuf, DumpError> {
+        let dump = self.snapshot().await?;
+        let json = serde_json::to_vec_pretty(&dump)?;
+        let path = self.config.directory.join(format!(
+            "state-{}.json",
+            dump.taken_at.format("%Y%m%dT%H%M%S%.3fZ")
+        ));
+        let written = async {
+            tokio::fs::create_dir_all(&self.config.directory).await?;
+            tokio::fs::write(&path, json).await
+        };
+        if let Err(source) = written.await {
+            return Err(DumpError::Write { path, source });
+        }
+        tracing::info!(
+            clients = dump.clients.len(),
+            "Dumped the server state to {}",
+            path.display()
+        );
+        Ok(path)
+    }
+
+    /// Copy the state, holding the read locks only as long as that takes
+    pub async fn snapshot(&self) -> Result<StateDump, DumpError> {
+        let redact_ips = self.config.redact_ips;
+        let now = Instant::now();
+        let copy = async {
+            let clients = self.clients.read().await;
+            let callsign_map = self.callsign_map.read().await;
+            let dumps: Vec<ClientDump> = clients
+                .values()
+                .map(|client| ClientDump::new(client, now, redact_ips))
+                .collect();
+            let callsigns: BTreeMap<String, SocketAddr> = callsign_map
+                .iter()
+                .map(|(callsign, addr)| (callsign.to_string(), address(*addr, redact_ips)))
+                .collect();
+            (dumps, callsigns, self.pipeline.report(&clients))
+        };
+        let (mut clients, callsigns, mut queues) = tokio::time::timeout(LOCK_TIMEOUT, copy)
+            .await
+            .map_err(|_| DumpError::Busy)?;
+
+        clients.sort_by(|a, b| (&a.callsign, a.addr).cmp(&(&b.callsign, b.addr)));
+        for client in &mut queues.worst_clients {
+            client.addr = address(client.addr, redact_ips);
+        }
+        let flight_plans = by_callsign(&clients, |client| client.flight_plan.clone());
+        let track_owners = by_callsign(&clients, |client| client.tracking_controller.clone());
+        let squawks = by_callsign(&clients, |client| client.assigned_squawk.clone());
+
+        Ok(StateDump {
+            taken_at: Utc::now(),
+            clients,
+            callsigns,
+            flight_plans,
+            track_owners,
+            squawks,
+            queues,
+            handlers: self.handler_stats.report(),
+            stats: self.stats.today(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientState;
+    use tokio::sync::broadcast;
+
+    fn dumper(config: DumpConfig) -> Dumper {
+        let mut pilot = Client::new("192.0.2.7:50001".parse().unwrap());
+        pilot.state = ClientState::Active;
+        pilot.client_type = Some(ClientType::Pilot);
+        pilot.callsign = Some("CCA1501".into());
+        pilot.network_id = Some("1234567".to_string());
+        pilot.auth_challenge = Some("0123456789abcdef".to_string());
+        pilot.assigned_squawk = Some("4521".to_string());
+        pilot.tracking_controller = Some("ZBAA_CTR".to_string());
+        pilot.flight_plan = Some(FlightPlan {
+            departure: "ZBAA".to_string(),
+            destination: "ZSSS".to_string(),
+            ..FlightPlan::default()
+        });
+        pilot.counters.received(40);
+        let stranger = Client::new("192.0.2.8:50002".parse().unwrap());
+        let callsigns = HashMap::from([("CCA1501".into(), pilot.addr)]);
+        let clients = ClientRegistry::from([(pilot.addr, pilot), (stranger.addr, stranger)]);
+        let (broadcast_tx, _) = broadcast::channel(16);
+        Dumper {
+            config,
+            clients: Arc::new(RwLock::new(clients)),
+            callsign_map: Arc::new(RwLock::new(callsigns)),
+            pipeline: Arc::new(Pipeline::new(broadcast_tx, 16)),
+            handler_stats: Arc::new(HandlerStats::new()),
+            stats: Arc::new(StatsCollector::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let dump = dumper(DumpConfig::default()).snapshot().await.unwrap();
+        assert_eq!(dump.clients.len(), 2);
+        // Connections without a callsign come first
+        assert_eq!(dump.clients[0].state, "connected");
+        let pilot = &dump.clients[1];
+        assert_eq!(pilot.callsign.as_deref(), Some("CCA1501"));
+        assert_eq!(pilot.client_type.as_deref(), Some("pilot"));
+        assert!(pilot.challenge_pending);
+        assert_eq!(pilot.traffic.bytes_in, 40);
+        assert_eq!(dump.callsigns["CCA1501"], pilot.addr);
+        assert_eq!(dump.flight_plans["CCA1501"].destination, "ZSSS");
+        assert_eq!(dump.track_owners["CCA1501"], "ZBAA_CTR");
+        assert_eq!(dump.squawks["CCA1501"], "4521");
+
+        let json = serde_json::to_string(&dump).unwrap();
+        assert!(json.contains("192.0.2.7:50001"));
+        assert!(!json.contains("0123456789abcdef"));
+    }
+
+    #[tokio::test]
+    async fn test_redacted_ips() {
+        let config = DumpConfig {
+            redact_ips: true,
+            ..DumpConfig::default()
+        };
+        let dump = dumper(config).snapshot().await.unwrap();
+        let json = serde_json::to_string(&dump).unwrap();
+        assert!(!json.contains("192.0.2."), "{}", json);
+        assert_eq!(dump.callsigns["CCA1501"].to_string(), "0.0.0.0:50001");
+    }
+
+    #[tokio::test]
+    async fn test_locked_state_gives_up() {
+        let dumper = dumper(DumpConfig::default());
+        let _writer = dumper.clients.write().await;
+        tokio::time::pause();
+        assert!(matches!(dumper.snapshot().await, Err(DumpError::Busy)));
+    }
+}
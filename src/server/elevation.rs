@@ -0,0 +1,263 @@
+//! Ratings raised for a mentoring session
+//!
+//! A supervisor lets a student control above their rating for a while with
+//! `.elevate <callsign> <rating> [minutes]`. Only the connection's rating is
+//! raised, never the account's, and the login and position checks go by
+//! [`Client::effective_rating`], which falls back to the stored rating as
+//! soon as the time is up. [`expire`] then puts the stored rating back,
+//! tells the student and announces the controller to the network again, as
+//! [`mentor_left`] does right away when the mentor disconnects.
+
+use crate::client::{Client, Elevation};
+use crate::packet::{Packet, PacketType};
+use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::handlers::command::send_lines;
+use crate::server::registry::ClientRegistry;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+
+/// How often elevations that ran out are looked for
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Highest controller rating, administrator
+pub const MAX_RATING: i32 = 12;
+
+/// `#AA(callsign):SERVER:(real name):(network ID)::(rating):(protocol)`, how
+/// other clients learn a controller's rating; the password is left out
+pub fn announcement(client: &Client) -> Option<Packet> {
+    Some(Packet {
+        packet_type: PacketType::Client,
+        command: "AA".to_string(),
+        source: client.callsign()?.to_string(),
+        destination: "SERVER".to_string(),
+        data: vec![
+            client.real_name.clone().unwrap_or_default(),
+            client.network_id.clone().unwrap_or_default(),
+            String::new(),
+            client.rating.unwrap_or(1).to_string(),
+            client
+                .protocol_revision
+                .map(|revision| revision.to_string())
+                .unwrap_or_default(),
+        ],
+    })
+}
+
+/// Announce the client at `addr` to everyone else with its current rating
+pub fn announce(
+    client: &Client,
+    addr: SocketAddr,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+) {
+    if let Some(packet) = announcement(client) {
+        let _ = broadcast_tx.send((addr, ServerMessage::Packet(Arc::new(packet))));
+    }
+}
+
+/// Revert the elevations that ran out by `now`, returning how many
+pub async fn expire(
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+    now: Instant,
+) -> usize {
+    revert(
+        clients,
+        broadcast_tx,
+        server_callsign,
+        |elevation| now >= elevation.until,
+        "the elevation ran out",
+    )
+    .await
+}
+
+/// Revert the elevations granted from `mentor_addr`, which disconnected
+pub async fn mentor_left(
+    clients: &Arc<RwLock<ClientRegistry>>,
+    mentor_addr: SocketAddr,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+) -> usize {
+    revert(
+        clients,
+        broadcast_tx,
+        server_callsign,
+        |elevation| elevation.mentor_addr == mentor_addr,
+        "your mentor disconnected",
+    )
+    .await
+}
+
+/// Put back the stored rating of each client whose elevation `ended`
+/// says is over
+async fn revert(
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+    ended: impl Fn(&Elevation) -> bool,
+    reason: &str,
+) -> usize {
+    let mut reverted = 0;
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    clients_map.for_each_mut(|addr, client| {
+        let Some(elevation) = client.elevation.take_if(|elevation| ended(elevation)) else {
+            return;
+        };
+        client.rating = elevation.stored_rating;
+        let callsign = client.callsign().unwrap_or_default().to_string();
+        tracing::info!(
+            "Rating of {} back to {:?}, elevated by {}: {}",
+            callsign,
+            client.rating,
+            elevation.mentor,
+            reason
+        );
+        send_lines(
+            vec![format!(
+                "Your rating is back to {}: {}",
+                client.rating.unwrap_or(1),
+                reason
+            )],
+            *addr,
+            &callsign,
+            server_callsign,
+            broadcast_tx,
+        );
+        announce(client, *addr, broadcast_tx);
+        reverted += 1;
+    });
+    reverted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ClientState, ClientType};
+    use crate::config::VisibilityConfig;
+    use crate::packet::PositionUpdate;
+    use crate::server::handlers::handle_atc_position_update;
+
+    const STUDENT_ADDR: &str = "127.0.0.1:50001";
+    const MENTOR_ADDR: &str = "127.0.0.1:50002";
+
+    /// An S1 student, able to staff ground but not approach
+    fn student() -> Client {
+        let mut client = Client::new(STUDENT_ADDR.parse().unwrap());
+        client.state = ClientState::Active;
+        client.client_type = Some(ClientType::Atc);
+        client.callsign = Some("ZSPD_APP".into());
+        client.network_id = Some("1000001".to_string());
+        client.rating = Some(2);
+        client
+    }
+
+    /// Send an approach update from the student and return whether it was
+    /// taken, rather than refused with an `$ER`
+    async fn approach_update_taken(clients: &Arc<RwLock<ClientRegistry>>, now: Instant) -> bool {
+        let addr: SocketAddr = STUDENT_ADDR.parse().unwrap();
+        clients.write().await.get_mut(&addr).unwrap().last_position = None;
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+        let update = PositionUpdate::parse("%ZSPD_APP:20650:5:100:4:31.14:121.80:0").unwrap();
+        handle_atc_position_update(
+            update,
+            addr,
+            clients,
+            &broadcast_tx,
+            &VisibilityConfig::default(),
+            "SERVER",
+            now,
+        )
+        .await;
+        let refused = std::iter::from_fn(|| rx.try_recv().ok()).any(|(_, msg)| {
+            matches!(msg, ServerMessage::Direct(packet) if packet.command == "ER" && packet.data[0] == "011")
+        });
+        let taken = clients.read().await[&addr].last_position.is_some();
+        assert_ne!(taken, refused);
+        taken
+    }
+
+    #[tokio::test]
+    async fn test_elevation_allows_position_until_expiry() {
+        let student = student();
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([(student.addr, student)])));
+        let start = Instant::now();
+
+        // S1 can't staff approach
+        assert!(!approach_update_taken(&clients, start).await);
+
+        // Raised to S3 for ten minutes
+        let until = start + Duration::from_secs(600);
+        {
+            let mut clients_map = clients.write().await;
+            let mut student = clients_map.get_mut(&STUDENT_ADDR.parse().unwrap()).unwrap();
+            student.elevation = Some(Elevation {
+                stored_rating: student.rating,
+                until,
+                mentor_addr: MENTOR_ADDR.parse().unwrap(),
+                mentor: "1000000".to_string(),
+            });
+            student.rating = Some(4);
+        }
+        assert!(approach_update_taken(&clients, start).await);
+
+        // Refused again once the time is up, before the sweep reverts it
+        assert!(!approach_update_taken(&clients, until).await);
+
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+        assert_eq!(expire(&clients, &broadcast_tx, "SERVER", start).await, 0);
+        assert_eq!(expire(&clients, &broadcast_tx, "SERVER", until).await, 1);
+        let student = &clients.read().await[&STUDENT_ADDR.parse().unwrap()];
+        assert_eq!(student.rating, Some(2));
+        assert!(student.elevation.is_none());
+        let sent: Vec<String> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|(_, msg)| match msg {
+                ServerMessage::Direct(packet) | ServerMessage::Packet(packet) => packet.to_string(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                "#TMSERVER:ZSPD_APP:Your rating is back to 2: the elevation ran out",
+                "#AAZSPD_APP:SERVER::1000001::2:",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mentor_leaving_ends_elevation() {
+        let mut student = student();
+        student.elevation = Some(Elevation {
+            stored_rating: Some(2),
+            until: Instant::now() + Duration::from_secs(600),
+            mentor_addr: MENTOR_ADDR.parse().unwrap(),
+            mentor: "1000000".to_string(),
+        });
+        student.rating = Some(4);
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([(student.addr, student)])));
+        let (broadcast_tx, _rx) = broadcast::channel(16);
+
+        let other: SocketAddr = "127.0.0.1:50004".parse().unwrap();
+        assert_eq!(
+            mentor_left(&clients, other, &broadcast_tx, "SERVER").await,
+            0
+        );
+        assert_eq!(
+            mentor_left(
+                &clients,
+                MENTOR_ADDR.parse().unwrap(),
+                &broadcast_tx,
+                "SERVER"
+            )
+            .await,
+            1
+        );
+        assert_eq!(
+            clients.read().await[&STUDENT_ADDR.parse().unwrap()].rating,
+            Some(2)
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use crate::client::ClientType;
+use crate::config::{FeaturesConfig, MissingFlightPlanAction};
+use crate::packet::Packet;
+use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::registry::ClientRegistry;
+use crate::webhooks::{Event, Webhooks};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+
+/// How often pilots without a flight plan are looked for
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Warn or disconnect pilots who haven't filed within the grace period
+///
+/// Does nothing unless `require_flight_plan` is set. Pilots are only warned
+/// once per connection.
+pub async fn check_flight_plans(
+    clients: &Arc<RwLock<ClientRegistry>>,
+    features: &FeaturesConfig,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+    webhooks: &Webhooks,
+    now: Instant,
+) {
+    if !features.require_flight_plan {
+        return;
+    }
+    let grace = Duration::from_secs(u64::from(features.flight_plan_grace_minutes) * 60);
+
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    let overdue: Vec<SocketAddr> = clients_map
+        .of_type(ClientType::Pilot)
+        .filter(|(_, client)| {
+            client.is_active()
+                && client.flight_plan.is_none()
+                && client.logged_in_at.is_some_and(|logged_in_at| {
+                    now.saturating_duration_since(logged_in_at) >= grace
+                })
+        })
+        .map(|(addr, _)| *addr)
+        .collect();
+    for addr in overdue {
+        let Some(mut client) = clients_map.get_mut(&addr) else {
+            continue;
+        };
+        let callsign = client.callsign().unwrap_or_default().to_string();
+
+        match features.missing_flight_plan_action {
+            MissingFlightPlanAction::Warn if !client.flight_plan_reminded => {
+                tracing::info!("Reminding {} to file a flight plan", callsign);
+                client.flight_plan_reminded = true;
+                send_notice(
+                    server_callsign,
+                    broadcast_tx,
+                    addr,
+                    &callsign,
+                    "Please file a flight plan",
+                );
+            }
+            MissingFlightPlanAction::Warn => {}
+            MissingFlightPlanAction::Kick => {
+                tracing::warn!("Disconnecting {}: no flight plan filed", callsign);
+                send_notice(
+                    server_callsign,
+                    broadcast_tx,
+                    addr,
+                    &callsign,
+                    "Disconnected: a flight plan is required on this server",
+                );
+                let _ = broadcast_tx.send((addr, ServerMessage::Disconnect("flight_plan")));
+                crate::metrics::kick("flight_plan");
+                webhooks.emit(&Event::client_kicked(&callsign, "no flight plan filed"));
+            }
+        }
+    }
+}
+
+fn send_notice(
+    server_callsign: &str,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    addr: SocketAddr,
+    callsign: &str,
+    text: &str,
+) {
+    let notice = Packet {
+        packet_type: crate::packet::PacketType::Client,
+        command: "TM".to_string(),
+        source: server_callsign.to_string(),
+        destination: callsign.to_string(),
+        data: vec![text.to_string()],
+    };
+    let _ = broadcast_tx.send((addr, ServerMessage::Direct(Arc::new(notice))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientState};
+
+    const PILOT_ADDR: &str = "127.0.0.1:50001";
+
+    /// Run a check 15 minutes after the pilot logged in
+    async fn check(features: FeaturesConfig) -> Vec<ServerMessage> {
+        let logged_in_at = Instant::now();
+        let mut pilot = Client::new(PILOT_ADDR.parse().unwrap());
+        pilot.state = ClientState::Active;
+        pilot.client_type = Some(ClientType::Pilot);
+        pilot.callsign = Some("CCA1501".into());
+        pilot.logged_in_at = Some(logged_in_at);
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([(pilot.addr, pilot)])));
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+
+        let later = logged_in_at + Duration::from_secs(15 * 60);
+        let webhooks = Webhooks::default();
+        check_flight_plans(
+            &clients,
+            &features,
+            &broadcast_tx,
+            "SERVER",
+            &webhooks,
+            later,
+        )
+        .await;
+        // A second check doesn't repeat the reminder
+        check_flight_plans(
+            &clients,
+            &features,
+            &broadcast_tx,
+            "SERVER",
+            &webhooks,
+            later,
+        )
+        .await;
+
+        let mut messages = Vec::new();
+        while let Ok((addr, msg)) = rx.try_recv() {
+            assert_eq!(addr, PILOT_ADDR.parse().unwrap());
+            messages.push(msg);
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_not_required() {
+        assert!(check(FeaturesConfig::default()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_warn_once() {
+        let messages = check(FeaturesConfig {
+            require_flight_plan: true,
+            ..FeaturesConfig::default()
+        })
+        .await;
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(&messages[0], ServerMessage::Direct(packet) if packet.command == "TM"));
+    }
+
+    #[tokio::test]
+    async fn test_kick() {
+        let messages = check(FeaturesConfig {
+            require_flight_plan: true,
+            missing_flight_plan_action: MissingFlightPlanAction::Kick,
+            ..FeaturesConfig::default()
+        })
+        .await;
+        assert!(messages
+            .iter()
+            .any(|msg| matches!(msg, ServerMessage::Disconnect("flight_plan"))));
+    }
+
+    #[tokio::test]
+    async fn test_within_grace_period() {
+        let messages = check(FeaturesConfig {
+            require_flight_plan: true,
+            flight_plan_grace_minutes: 20,
+            ..FeaturesConfig::default()
+        })
+        .await;
+        assert!(messages.is_empty());
+    }
+}
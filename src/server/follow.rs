@@ -0,0 +1,650 @@
+//! Observers riding along with a pilot
+//!
+//! An observer sends `.follow <callsign>` and from then on gets that pilot's
+//! position updates however far apart they are, and a copy of the messages
+//! on the frequency the pilot last spoke on, until it sends `.unfollow` or
+//! either of them disconnects. Flight plans and amendments reach every
+//! client already. The pilot is told and can `.reject` a follower; with
+//! `features.follow_requires_consent` a follower gets nothing until the
+//! pilot sends `.accept`. A pilot has at most `features.max_followers`
+//! followers, counting those waiting.
+
+use crate::client::{format_frequency, ClientType};
+use crate::config::FeaturesConfig;
+use crate::server::config::ServerMessage;
+use crate::server::handlers::command::send_lines;
+use crate::server::registry::ClientRegistry;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, RwLock};
+
+/// Who follows which pilot, by the pilot's address
+#[derive(Debug, Default)]
+pub struct Follows {
+    pilots: Mutex<HashMap<SocketAddr, Followed>>,
+}
+
+#[derive(Debug)]
+struct Followed {
+    callsign: String,
+    followers: Vec<Follower>,
+    /// Frequency the pilot last sent a message to, as addressed (`@24550`)
+    frequency: Option<String>,
+}
+
+#[derive(Debug)]
+struct Follower {
+    addr: SocketAddr,
+    callsign: String,
+    /// Whether the follower gets the pilot's traffic yet
+    accepted: bool,
+}
+
+impl Follows {
+    /// Followers of the pilot at `pilot` that get its updates
+    pub fn followers(&self, pilot: SocketAddr) -> Vec<SocketAddr> {
+        let pilots = self.pilots.lock().unwrap();
+        pilots.get(&pilot).map_or_else(Vec::new, |followed| {
+            followed
+                .followers
+                .iter()
+                .filter(|follower| follower.accepted)
+                .map(|follower| follower.addr)
+                .collect()
+        })
+    }
+
+    /// Followers to copy a message from `sender` to `frequency` to, with
+    /// their callsigns: those of pilots last heard on that frequency, the
+    /// sender included
+    pub fn overhearing(&self, sender: SocketAddr, frequency: &str) -> Vec<(SocketAddr, String)> {
+        let mut pilots = self.pilots.lock().unwrap();
+        if let Some(followed) = pilots.get_mut(&sender) {
+            followed.frequency = Some(frequency.to_string());
+        }
+        let mut listeners: Vec<(SocketAddr, String)> = pilots
+            .values()
+            .filter(|followed| followed.frequency.as_deref() == Some(frequency))
+            .flat_map(|followed| &followed.followers)
+            .filter(|follower| follower.accepted && follower.addr != sender)
+            .map(|follower| (follower.addr, follower.callsign.clone()))
+            .collect();
+        listeners.sort();
+        listeners.dedup();
+        listeners
+    }
+
+    /// The pilot `observer` follows or waits to follow, with its callsign
+    fn following(&self, observer: SocketAddr) -> Option<(SocketAddr, String)> {
+        let pilots = self.pilots.lock().unwrap();
+        pilots
+            .iter()
+            .find(|(_, followed)| followed.followers.iter().any(|f| f.addr == observer))
+            .map(|(addr, followed)| (*addr, followed.callsign.clone()))
+    }
+
+    /// How many follow the pilot at `pilot` or wait to
+    fn count(&self, pilot: SocketAddr) -> usize {
+        let pilots = self.pilots.lock().unwrap();
+        pilots
+            .get(&pilot)
+            .map_or(0, |followed| followed.followers.len())
+    }
+
+    /// Add a follower, or one waiting, to the pilot at `pilot`
+    fn add(&self, pilot: SocketAddr, pilot_callsign: &str, follower: Follower) {
+        let mut pilots = self.pilots.lock().unwrap();
+        let followed = pilots.entry(pilot).or_insert_with(|| Followed {
+            callsign: pilot_callsign.to_string(),
+            followers: Vec::new(),
+            frequency: None,
+        });
+        followed.followers.push(follower);
+    }
+
+    /// Stop `observer` following, returning the pilot it followed
+    fn unfollow(&self, observer: SocketAddr) -> Option<(SocketAddr, String)> {
+        let (pilot, callsign) = self.following(observer)?;
+        self.remove(pilot, |follower| follower.addr == observer);
+        Some((pilot, callsign))
+    }
+
+    /// Let the follower of `pilot` called `callsign` have its traffic,
+    /// returning the follower's address if it was waiting
+    fn accept(&self, pilot: SocketAddr, callsign: &str) -> Option<SocketAddr> {
+        let mut pilots = self.pilots.lock().unwrap();
+        let follower = pilots
+            .get_mut(&pilot)?
+            .followers
+            .iter_mut()
+            .find(|follower| {
+                !follower.accepted && follower.callsign.eq_ignore_ascii_case(callsign)
+            })?;
+        follower.accepted = true;
+        Some(follower.addr)
+    }
+
+    /// Remove the followers of `pilot` that `matches`, returning them
+    fn remove(&self, pilot: SocketAddr, matches: impl Fn(&Follower) -> bool) -> Vec<Follower> {
+        let mut pilots = self.pilots.lock().unwrap();
+        let Some(followed) = pilots.get_mut(&pilot) else {
+            return Vec::new();
+        };
+        let (removed, kept) = followed.followers.drain(..).partition(|f| matches(f));
+        followed.followers = kept;
+        if followed.followers.is_empty() {
+            pilots.remove(&pilot);
+        }
+        removed
+    }
+
+    /// Forget the client at `addr`, which disconnected, telling its
+    /// followers or the pilot it followed
+    pub fn disconnected(
+        &self,
+        addr: SocketAddr,
+        broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+        server_callsign: &str,
+    ) {
+        let pilot = self.pilots.lock().unwrap().remove(&addr);
+        if let Some(pilot) = pilot {
+            for follower in pilot.followers {
+                send_lines(
+                    vec![format!(
+                        "{} disconnected; you no longer follow them",
+                        pilot.callsign
+                    )],
+                    follower.addr,
+                    &follower.callsign,
+                    server_callsign,
+                    broadcast_tx,
+                );
+            }
+        }
+        if let Some((pilot, pilot_callsign)) = self.following(addr) {
+            for follower in self.remove(pilot, |follower| follower.addr == addr) {
+                send_lines(
+                    vec![format!("{} no longer follows you", follower.callsign)],
+                    pilot,
+                    &pilot_callsign,
+                    server_callsign,
+                    broadcast_tx,
+                );
+            }
+        }
+    }
+}
+
+/// `.follow <callsign>` - an observer asks to ride along with a pilot
+pub async fn follow(
+    args: &[&str],
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    follows: &Follows,
+    features: &FeaturesConfig,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+) -> Vec<String> {
+    let [callsign] = args else {
+        return vec!["Usage: .follow <callsign>".to_string()];
+    };
+    if features.max_followers == 0 {
+        return vec!["Following pilots is turned off on this server".to_string()];
+    }
+    let (observer, pilot_addr, pilot) = {
+        let clients = clients.read().await;
+        let Some(observer) = clients.get(&sender_addr).filter(|c| c.is_active()) else {
+            return Vec::new();
+        };
+        if !observer.is_observer() {
+            return vec!["Only observers can follow a pilot".to_string()];
+        }
+        let Some((addr, pilot)) = clients.named(callsign).filter(|(_, c)| c.is_active()) else {
+            return vec![format!("{} is not connected", callsign)];
+        };
+        let pilot_callsign = pilot.callsign().unwrap_or(callsign).to_string();
+        if pilot.client_type != Some(ClientType::Pilot) {
+            return vec![format!("{} is not a pilot", pilot_callsign)];
+        }
+        (
+            observer.callsign().unwrap_or_default().to_string(),
+            *addr,
+            pilot_callsign,
+        )
+    };
+    if follows.following(sender_addr).map(|(addr, _)| addr) == Some(pilot_addr) {
+        return vec![format!("You follow {} already", pilot)];
+    }
+    if follows.count(pilot_addr) >= features.max_followers {
+        return vec![format!("{} has as many followers as allowed", pilot)];
+    }
+
+    // One pilot at a time
+    if let Some((previous_addr, previous_callsign)) = follows.unfollow(sender_addr) {
+        send_lines(
+            vec![format!("{} no longer follows you", observer)],
+            previous_addr,
+            &previous_callsign,
+            server_callsign,
+            broadcast_tx,
+        );
+    }
+    let accepted = !features.follow_requires_consent;
+    let follower = Follower {
+        addr: sender_addr,
+        callsign: observer.clone(),
+        accepted,
+    };
+    follows.add(pilot_addr, &pilot, follower);
+    tracing::info!(
+        "{} {} {}",
+        observer,
+        if accepted {
+            "follows"
+        } else {
+            "asks to follow"
+        },
+        pilot
+    );
+
+    let (notice, reply) = if accepted {
+        (
+            format!(
+                "{} is following your flight; send .reject {} to stop them",
+                observer, observer
+            ),
+            format!("Following {} until you send .unfollow", pilot),
+        )
+    } else {
+        (
+            format!(
+                "{} asks to follow your flight; send .accept {} or .reject {}",
+                observer, observer, observer
+            ),
+            format!(
+                "Asked {} to let you follow; waiting for them to accept",
+                pilot
+            ),
+        )
+    };
+    send_lines(
+        vec![notice],
+        pilot_addr,
+        &pilot,
+        server_callsign,
+        broadcast_tx,
+    );
+    vec![reply]
+}
+
+/// `.unfollow` - stop following
+pub fn unfollow(
+    sender_addr: SocketAddr,
+    follows: &Follows,
+    observer: &str,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+) -> Vec<String> {
+    let Some((pilot_addr, pilot)) = follows.unfollow(sender_addr) else {
+        return vec!["You are not following anyone".to_string()];
+    };
+    send_lines(
+        vec![format!("{} no longer follows you", observer)],
+        pilot_addr,
+        &pilot,
+        server_callsign,
+        broadcast_tx,
+    );
+    vec![format!("You no longer follow {}", pilot)]
+}
+
+/// `.accept <callsign>` - a pilot lets a waiting observer follow
+pub fn accept(
+    args: &[&str],
+    sender_addr: SocketAddr,
+    follows: &Follows,
+    pilot: &str,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+) -> Vec<String> {
+    let [observer] = args else {
+        return vec!["Usage: .accept <callsign>".to_string()];
+    };
+    let observer = observer.to_ascii_uppercase();
+    let Some(observer_addr) = follows.accept(sender_addr, &observer) else {
+        return vec![format!("{} hasn't asked to follow you", observer)];
+    };
+    tracing::info!("{} accepted {} as a follower", pilot, observer);
+    send_lines(
+        vec![format!(
+            "{} accepted; following them until you send .unfollow",
+            pilot
+        )],
+        observer_addr,
+        &observer,
+        server_callsign,
+        broadcast_tx,
+    );
+    vec![format!(
+        "{} follows you now; send .reject {} to stop them",
+        observer, observer
+    )]
+}
+
+/// `.reject <callsign>` - a pilot turns away or drops a follower
+pub fn reject(
+    args: &[&str],
+    sender_addr: SocketAddr,
+    follows: &Follows,
+    pilot: &str,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+) -> Vec<String> {
+    let [observer] = args else {
+        return vec!["Usage: .reject <callsign>".to_string()];
+    };
+    let removed = follows.remove(sender_addr, |follower| {
+        follower.callsign.eq_ignore_ascii_case(observer)
+    });
+    let Some(follower) = removed.into_iter().next() else {
+        return vec![format!(
+            "{} isn't following you",
+            observer.to_ascii_uppercase()
+        )];
+    };
+    tracing::info!("{} rejected {} as a follower", pilot, follower.callsign);
+    send_lines(
+        vec![format!("{} declined to be followed", pilot)],
+        follower.addr,
+        &follower.callsign,
+        server_callsign,
+        broadcast_tx,
+    );
+    vec![format!("{} no longer follows you", follower.callsign)]
+}
+
+/// What a follower is sent for a message on a followed pilot's frequency
+pub fn overheard(source: &str, frequency: &str, text: &str) -> String {
+    let frequency = frequency.trim_start_matches('@');
+    match frequency.parse() {
+        Ok(frequency) => format!("{} on {}: {}", source, format_frequency(frequency), text),
+        Err(_) => format!("{} on {}: {}", source, frequency, text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::packet::{Packet, PositionUpdate};
+    use crate::server::handlers::{handle_position_update, handle_text_message};
+    use crate::server::state::ServerState;
+
+    const PILOT_ADDR: &str = "127.0.0.1:50001";
+    const OBSERVER_ADDR: &str = "127.0.0.1:50002";
+    const OTHER_OBSERVER_ADDR: &str = "127.0.0.1:50003";
+
+    fn client(addr: &str, callsign: &str, client_type: ClientType, point: (f64, f64)) -> Client {
+        let mut client =
+            Client::active_for_tests(addr.parse::<SocketAddr>().unwrap(), callsign, client_type);
+        client.latitude = Some(point.0);
+        client.longitude = Some(point.1);
+        client
+    }
+
+    /// A pilot over Beijing and two observers in Singapore, far out of range
+    fn clients() -> Arc<RwLock<ClientRegistry>> {
+        Arc::new(RwLock::new(ClientRegistry::from_iter(
+            [
+                client(PILOT_ADDR, "CCA1501", ClientType::Pilot, (40.1, 116.6)),
+                client(OBSERVER_ADDR, "WSSS_OBS", ClientType::Atc, (1.36, 103.99)),
+                client(
+                    OTHER_OBSERVER_ADDR,
+                    "WSSL_OBS",
+                    ClientType::Atc,
+                    (1.41, 103.87),
+                ),
+            ]
+            .map(|client| (client.addr, client)),
+        )))
+    }
+
+    struct Session {
+        state: ServerState,
+        features: FeaturesConfig,
+        rx: broadcast::Receiver<(SocketAddr, ServerMessage)>,
+    }
+
+    impl Session {
+        async fn new(features: FeaturesConfig) -> Self {
+            let db = Arc::new(crate::db::init_ephemeral().await.unwrap());
+            let state = ServerState {
+                clients: clients(),
+                ..ServerState::for_tests(db)
+            };
+            Self {
+                rx: state.broadcast_tx.subscribe(),
+                state,
+                features,
+            }
+        }
+
+        /// `.follow CCA1501` from the observer at `addr`, returning the reply
+        async fn follow(&self, addr: &str) -> Vec<String> {
+            follow(
+                &["cca1501"],
+                addr.parse().unwrap(),
+                &self.state.clients,
+                &self.state.follows,
+                &self.features,
+                &self.state.broadcast_tx,
+                "SERVER",
+            )
+            .await
+        }
+
+        /// A position update from the pilot, returning who got it
+        async fn pilot_moves(&mut self) -> Vec<SocketAddr> {
+            let update = PositionUpdate::parse("@NCCA1501:1200:1:40.2:116.7:9000:250:0:0").unwrap();
+            handle_position_update(update, PILOT_ADDR.parse().unwrap(), &self.state).await;
+            self.sent()
+                .into_iter()
+                .filter_map(|(addr, msg)| matches!(msg, ServerMessage::Position(_)).then_some(addr))
+                .collect()
+        }
+
+        /// The server's notes since last asked, with where each went
+        fn notices(&mut self) -> Vec<(SocketAddr, String)> {
+            self.sent()
+                .into_iter()
+                .filter_map(|(addr, msg)| match msg {
+                    ServerMessage::Direct(packet) => Some((addr, packet.data.join(":"))),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        fn sent(&mut self) -> Vec<(SocketAddr, ServerMessage)> {
+            std::iter::from_fn(|| self.rx.try_recv().ok()).collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follower_gets_pilot_beyond_range() {
+        let mut session = Session::new(FeaturesConfig::default()).await;
+        let (pilot, observer) = (PILOT_ADDR.parse().unwrap(), OBSERVER_ADDR.parse().unwrap());
+        assert!(session.pilot_moves().await.is_empty());
+
+        assert_eq!(
+            session.follow(OBSERVER_ADDR).await,
+            ["Following CCA1501 until you send .unfollow"]
+        );
+        assert_eq!(
+            session.notices(),
+            [(
+                pilot,
+                "WSSS_OBS is following your flight; send .reject WSSS_OBS to stop them".to_string()
+            )]
+        );
+        assert_eq!(session.pilot_moves().await, [observer]);
+
+        // What is said on the pilot's frequency, by the pilot and to it
+        for line in [
+            "#TMCCA1501:@24550:CCA1501 ready for departure",
+            "#TMZBAA_TWR:@24550:CCA1501 cleared for takeoff",
+            "#TMZBAA_GND:@21900:not for CCA1501",
+        ] {
+            let packet = Packet::parse(line).unwrap();
+            let sender = if packet.source == "CCA1501" {
+                pilot
+            } else {
+                "127.0.0.1:50009".parse().unwrap()
+            };
+            handle_text_message(packet, sender, &session.state).await;
+        }
+        assert_eq!(
+            session.notices(),
+            [
+                (
+                    observer,
+                    "CCA1501 on 124.550: CCA1501 ready for departure".to_string()
+                ),
+                (
+                    observer,
+                    "ZBAA_TWR on 124.550: CCA1501 cleared for takeoff".to_string()
+                ),
+            ]
+        );
+
+        // Only observers follow, one pilot at a time
+        let replies = follow(
+            &["CCA1501"],
+            pilot,
+            &session.state.clients,
+            &session.state.follows,
+            &session.features,
+            &session.state.broadcast_tx,
+            "SERVER",
+        )
+        .await;
+        assert_eq!(replies, ["Only observers can follow a pilot"]);
+        assert_eq!(
+            session.follow(OBSERVER_ADDR).await,
+            ["You follow CCA1501 already"]
+        );
+
+        assert_eq!(
+            unfollow(
+                observer,
+                &session.state.follows,
+                "WSSS_OBS",
+                &session.state.broadcast_tx,
+                "SERVER"
+            ),
+            ["You no longer follow CCA1501"]
+        );
+        assert!(session.pilot_moves().await.is_empty());
+        assert_eq!(
+            unfollow(
+                observer,
+                &session.state.follows,
+                "WSSS_OBS",
+                &session.state.broadcast_tx,
+                "SERVER"
+            ),
+            ["You are not following anyone"]
+        );
+
+        // The pilot leaving ends it too
+        session.follow(OBSERVER_ADDR).await;
+        session.notices();
+        session
+            .state
+            .follows
+            .disconnected(pilot, &session.state.broadcast_tx, "SERVER");
+        assert_eq!(
+            session.notices(),
+            [(
+                observer,
+                "CCA1501 disconnected; you no longer follow them".to_string()
+            )]
+        );
+        assert!(session.state.follows.followers(pilot).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pilot_consent_required() {
+        let mut session = Session::new(FeaturesConfig {
+            follow_requires_consent: true,
+            max_followers: 1,
+            ..FeaturesConfig::default()
+        })
+        .await;
+        let (pilot, observer) = (PILOT_ADDR.parse().unwrap(), OBSERVER_ADDR.parse().unwrap());
+
+        assert_eq!(
+            session.follow(OBSERVER_ADDR).await,
+            ["Asked CCA1501 to let you follow; waiting for them to accept"]
+        );
+        assert_eq!(
+            session.notices(),
+            [(
+                pilot,
+                "WSSS_OBS asks to follow your flight; send .accept WSSS_OBS or .reject WSSS_OBS"
+                    .to_string()
+            )]
+        );
+        // Nothing while waiting, and the one place is taken
+        assert!(session.pilot_moves().await.is_empty());
+        assert_eq!(
+            session.follow(OTHER_OBSERVER_ADDR).await,
+            ["CCA1501 has as many followers as allowed"]
+        );
+
+        let accept = |args: &[&str]| {
+            accept(
+                args,
+                pilot,
+                &session.state.follows,
+                "CCA1501",
+                &session.state.broadcast_tx,
+                "SERVER",
+            )
+        };
+        assert_eq!(
+            accept(&["WSSL_OBS"]),
+            ["WSSL_OBS hasn't asked to follow you"]
+        );
+        assert_eq!(
+            accept(&["wsss_obs"]),
+            ["WSSS_OBS follows you now; send .reject WSSS_OBS to stop them"]
+        );
+        assert_eq!(
+            session.notices(),
+            [(
+                observer,
+                "CCA1501 accepted; following them until you send .unfollow".to_string()
+            )]
+        );
+        assert_eq!(session.pilot_moves().await, [observer]);
+
+        let reject = |args: &[&str]| {
+            reject(
+                args,
+                pilot,
+                &session.state.follows,
+                "CCA1501",
+                &session.state.broadcast_tx,
+                "SERVER",
+            )
+        };
+        assert_eq!(reject(&["WSSS_OBS"]), ["WSSS_OBS no longer follows you"]);
+        assert_eq!(reject(&["WSSS_OBS"]), ["WSSS_OBS isn't following you"]);
+        assert_eq!(
+            session.notices(),
+            [(observer, "CCA1501 declined to be followed".to_string())]
+        );
+        assert!(session.pilot_moves().await.is_empty());
+    }
+}
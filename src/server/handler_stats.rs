@@ -0,0 +1,252 @@
+//! Per-command counters and handler times, kept in memory
+//!
+//! Every packet goes through the one processor task, so a slow handler
+//! (say, one waiting on a METAR fetch) holds up all clients. These numbers
+//! show which one it is: how often each command arrives, how long its handler
+//! takes, and how many error replies and unparsable lines there were. They
+//! are read through the control socket and go to Prometheus as well when
+//! that is enabled.
+
+use crate::metrics::{self, DURATION_BUCKETS, KNOWN_COMMANDS};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Counters since the server started
+pub struct HandlerStats {
+    /// One per known command, then one for the rest
+    commands: Vec<CommandCounters>,
+    /// `$ER` replies by error code
+    error_replies: Mutex<BTreeMap<String, u64>>,
+    parse_errors: AtomicU64,
+}
+
+#[derive(Default)]
+struct CommandCounters {
+    received: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+    /// Handled packets per `DURATION_BUCKETS` bound, then one for slower
+    buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+}
+
+/// The counters at one point in time, as the control socket reports them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HandlerReport {
+    /// Upper bounds of the histogram buckets, in milliseconds
+    pub bucket_bounds_ms: Vec<f64>,
+    /// Commands seen so far, busiest handler first
+    pub commands: Vec<CommandReport>,
+    /// `$ER` replies sent, by error code
+    pub error_replies: BTreeMap<String, u64>,
+    /// Lines that were not valid packets
+    pub parse_errors: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandReport {
+    /// The command, or "other" for those without counters of their own
+    pub command: String,
+    pub received: u64,
+    pub handled: u64,
+    pub total_ms: f64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+    /// Bucket bound at or below which 99% of the packets were handled; the
+    /// maximum if that's beyond the last bucket
+    pub p99_ms: f64,
+    /// Packets per bucket of `bucket_bounds_ms`, the last one for slower ones
+    pub buckets: Vec<u64>,
+}
+
+impl Default for HandlerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HandlerStats {
+    pub fn new() -> Self {
+        Self {
+            commands: (0..=KNOWN_COMMANDS.len())
+                .map(|_| CommandCounters::default())
+                .collect(),
+            error_replies: Mutex::new(BTreeMap::new()),
+            parse_errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Run `handler` for a packet with `command` and record how long it took
+    pub async fn time<T>(&self, command: &str, handler: impl Future<Output = T>) -> T {
+        let started = Instant::now();
+        let output = handler.await;
+        self.record_handled(command, started.elapsed());
+        output
+    }
+
+    pub fn record_received(&self, command: &str) {
+        self.counters(command)
+            .received
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_handled(&self, command: &str, duration: Duration) {
+        metrics::handler_duration(command, duration);
+
+        let counters = self.counters(command);
+        let nanos = duration.as_nanos().min(u64::MAX as u128) as u64;
+        counters.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        counters.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+        let seconds = duration.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        counters.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An `$ER` packet sent to a client
+    pub fn record_error_reply(&self, code: &str) {
+        metrics::error_reply(code);
+        *self
+            .error_replies
+            .lock()
+            .unwrap()
+            .entry(code.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn record_parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> HandlerReport {
+        let names = KNOWN_COMMANDS.iter().copied().chain(["other"]);
+        let mut commands: Vec<CommandReport> = names
+            .zip(&self.commands)
+            .filter_map(|(name, counters)| counters.report(name))
+            .collect();
+        commands.sort_by(|a, b| b.total_ms.total_cmp(&a.total_ms));
+
+        HandlerReport {
+            bucket_bounds_ms: DURATION_BUCKETS
+                .iter()
+                .map(|bound| bound * 1000.0)
+                .collect(),
+            commands,
+            error_replies: self.error_replies.lock().unwrap().clone(),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    fn counters(&self, command: &str) -> &CommandCounters {
+        let index = KNOWN_COMMANDS
+            .iter()
+            .position(|known| *known == command)
+            .unwrap_or(KNOWN_COMMANDS.len());
+        &self.commands[index]
+    }
+}
+
+impl CommandCounters {
+    /// `None` for a command that never came up
+    fn report(&self, command: &str) -> Option<CommandReport> {
+        let received = self.received.load(Ordering::Relaxed);
+        let buckets: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let handled: u64 = buckets.iter().sum();
+        if received == 0 && handled == 0 {
+            return None;
+        }
+
+        let millis = |nanos: u64| nanos as f64 / 1_000_000.0;
+        let total_ms = millis(self.total_nanos.load(Ordering::Relaxed));
+        let max_ms = millis(self.max_nanos.load(Ordering::Relaxed));
+        let mut seen = 0;
+        let p99_ms = buckets
+            .iter()
+            .zip(DURATION_BUCKETS)
+            .find_map(|(count, bound)| {
+                seen += count;
+                (handled > 0 && seen * 100 >= handled * 99).then_some(bound * 1000.0)
+            })
+            .unwrap_or(max_ms);
+
+        Some(CommandReport {
+            command: command.to_string(),
+            received,
+            handled,
+            total_ms,
+            mean_ms: if handled > 0 {
+                total_ms / handled as f64
+            } else {
+                0.0
+            },
+            max_ms,
+            p99_ms,
+            buckets,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_slow_handler_shows_in_histogram() {
+        let stats = HandlerStats::new();
+        for _ in 0..10 {
+            stats.record_received("N");
+            stats.time("N", async {}).await;
+        }
+        stats.record_received("AX");
+        // Stands in for a handler blocked on a weather fetch
+        stats
+            .time("AX", tokio::time::sleep(Duration::from_millis(60)))
+            .await;
+        stats.record_received("XX");
+        stats.record_error_reply("009");
+        stats.record_parse_error();
+
+        let report = stats.report();
+        let commands: Vec<_> = report.commands.iter().map(|c| c.command.as_str()).collect();
+        assert_eq!(commands[0], "AX");
+        assert!(commands.contains(&"N") && commands.contains(&"other"));
+
+        let ax = &report.commands[0];
+        assert_eq!((ax.received, ax.handled), (1, 1));
+        assert!(ax.max_ms >= 60.0, "{:?}", ax);
+        // Counted in one of the buckets above 50 ms
+        let fast = report
+            .bucket_bounds_ms
+            .iter()
+            .position(|bound| *bound >= 50.0)
+            .unwrap();
+        assert_eq!(ax.buckets[..=fast].iter().sum::<u64>(), 0);
+        assert_eq!(ax.buckets.iter().sum::<u64>(), 1);
+        assert!(ax.p99_ms >= 100.0, "{:?}", ax);
+
+        let n = report.commands.iter().find(|c| c.command == "N").unwrap();
+        assert_eq!(n.handled, 10);
+        assert!(n.p99_ms < 60.0, "{:?}", n);
+
+        let other = report
+            .commands
+            .iter()
+            .find(|c| c.command == "other")
+            .unwrap();
+        assert_eq!((other.received, other.handled), (1, 0));
+        assert_eq!(
+            report.error_replies,
+            BTreeMap::from([("009".to_string(), 1)])
+        );
+        assert_eq!(report.parse_errors, 1);
+    }
+}
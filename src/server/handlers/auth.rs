@@ -1,7 +1,17 @@
 use crate::auth;
-use crate::client::{Client, ClientState, ClientType};
+use crate::callsign::Callsign;
+use crate::client::{ClientState, ClientType, Delivery};
+use crate::db::service::{self, LoginClaims, LoginRecord};
+use crate::metrics;
 use crate::packet::Packet;
-use crate::server::config::{ServerConfig, ServerMessage};
+use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::handlers::flight_plan;
+use crate::server::limits::truncate_field;
+use crate::server::registry::ClientRegistry;
+use crate::server::snapshot;
+use crate::server::state::ServerState;
+use crate::webhooks::Event;
 use sea_orm::DatabaseConnection;
 use std::collections::HashMap;
 use std::net::SocketAddr;
@@ -9,16 +19,16 @@ use std::sync::Arc;
 use tokio::sync::{broadcast, RwLock};
 
 /// Handle client identification (VATSIM)
-pub async fn handle_identification(
-    packet: Packet,
-    sender_addr: SocketAddr,
-    clients: &Arc<RwLock<HashMap<SocketAddr, Client>>>,
-    _callsign_map: &Arc<RwLock<HashMap<String, SocketAddr>>>,
-    _config: &ServerConfig,
-    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
-    db: &Arc<DatabaseConnection>,
-) {
-    log::info!(
+pub async fn handle_identification(packet: Packet, sender_addr: SocketAddr, state: &ServerState) {
+    let ServerState {
+        clients,
+        config,
+        broadcast_tx,
+        db,
+        random,
+        ..
+    } = state;
+    tracing::info!(
         "Client identification from {}: {}",
         sender_addr,
         packet.source
@@ -27,45 +37,104 @@ pub async fn handle_identification(
     // Parse client ID packet
     // $ID(callsign):SERVER:(client id):(client string):3:2:(network ID):(num)
     let client_id_str = packet.data.get(0).cloned().unwrap_or_default();
-    let client_string = packet.data.get(1).cloned();
+    let client_string = packet.data.get(1).cloned().map(|mut client_string| {
+        truncate_field(&mut client_string, config.limits.max_field_bytes);
+        client_string
+    });
     let network_id = packet.data.get(4).cloned();
 
-    // Validate client ID against whitelist
-    match auth::validate_client_id(db, &client_id_str).await {
-        Ok(()) => {
-            log::info!("Client ID {} is whitelisted", client_id_str);
+    // Validate client ID and version against whitelist
+    let verification = match auth::validate_client_id(
+        db,
+        &client_id_str,
+        client_string.as_deref(),
+        &config.whitelist,
+    )
+    .await
+    {
+        Ok(verification) => {
+            if verification == auth::ClientVerification::Verified {
+                tracing::info!("Client ID {} is whitelisted", client_id_str);
+            }
+            verification
         }
         Err(e) => {
-            log::warn!("Client ID validation failed: {}", e);
+            tracing::warn!("Client ID validation failed: {}", e);
+            let message = match &e {
+                auth::AuthError::ClientVersionTooOld { min_version, .. } => format!(
+                    "Client version too old, please upgrade to {} or later",
+                    min_version
+                ),
+                auth::AuthError::UnparseableClientVersion(_) => {
+                    "Unable to determine client version".to_string()
+                }
+                _ => "Unauthorized client software".to_string(),
+            };
             // Send error message and disconnect
             let error_packet = Packet {
                 packet_type: crate::packet::PacketType::Request,
                 command: "ER".to_string(),
-                source: "server".to_string(),
+                source: config.server_callsign.clone(),
                 destination: packet.source.clone(),
-                data: vec![
-                    "016".to_string(),
-                    String::new(),
-                    "Unauthorized client software".to_string(),
-                ],
+                data: vec!["016".to_string(), String::new(), message],
             };
-            let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(error_packet)));
+            let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(error_packet))));
             return;
         }
-    }
+    };
 
     // Update client info
     {
-        let mut clients_map = clients.write().await;
-        if let Some(client) = clients_map.get_mut(&sender_addr) {
-            client.callsign = Some(packet.source.clone());
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        if let Some(mut client) = clients_map.get_mut(&sender_addr) {
+            client.record_identity(&packet.source, network_id.as_deref());
+            client.callsign = Some(Callsign::new(&packet.source));
             client.client_string = client_string.clone();
             client.network_id = network_id;
+            client.client_id = Some(client_id_str.clone());
+            client.unverified_client = verification == auth::ClientVerification::Unverified;
             client.state = ClientState::Identified;
+        };
+    }
+
+    // Challenge the client if its software has a key
+    let client_key = match service::find_client_key(db, &client_id_str).await {
+        Ok(client_key) => client_key,
+        Err(e) => {
+            tracing::error!("Failed to look up client key for {}: {}", client_id_str, e);
+            None
+        }
+    };
+
+    if client_key.is_some() && config.dialect.issues_challenges() {
+        let challenge = random.token();
+        {
+            let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+            if let Some(mut client) = clients_map.get_mut(&sender_addr) {
+                client.auth_challenge = Some(challenge.clone());
+            };
         }
+
+        // $ZCSERVER:(callsign):(challenge)
+        let challenge_packet = Packet {
+            packet_type: crate::packet::PacketType::Request,
+            command: "ZC".to_string(),
+            source: config.server_callsign.clone(),
+            destination: packet.source.clone(),
+            data: vec![challenge],
+        };
+        let _ = broadcast_tx.send((
+            sender_addr,
+            ServerMessage::Direct(Arc::new(challenge_packet)),
+        ));
+    } else if config.auth.require_challenge {
+        tracing::warn!(
+            "Auth challenges are required but client {} has no key, skipping challenge",
+            client_id_str
+        );
     }
 
-    log::info!(
+    tracing::info!(
         "Client {} identified with client software: {:?}",
         packet.source,
         client_string
@@ -73,16 +142,21 @@ pub async fn handle_identification(
 }
 
 /// Handle login (AA for ATC, AP for pilot)
-pub async fn handle_login(
-    packet: Packet,
-    sender_addr: SocketAddr,
-    clients: &Arc<RwLock<HashMap<SocketAddr, Client>>>,
-    callsign_map: &Arc<RwLock<HashMap<String, SocketAddr>>>,
-    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
-    db: &Arc<DatabaseConnection>,
-) {
+pub async fn handle_login(packet: Packet, sender_addr: SocketAddr, state: &ServerState) {
+    let ServerState {
+        clients,
+        callsign_map,
+        config,
+        broadcast_tx,
+        db,
+        stats,
+        motd,
+        throttle,
+        ..
+    } = state;
+    let clock = &*state.clock;
     let callsign = packet.source.clone();
-    log::info!("Login attempt from {} ({})", sender_addr, callsign);
+    tracing::info!("Login attempt from {} ({})", sender_addr, callsign);
 
     // Extract client type from command and parse login data
     let client_type = match packet.command.as_str() {
@@ -92,7 +166,7 @@ pub async fn handle_login(
     };
 
     // Parse login data
-    let (real_name, network_id, password, _rating) = match packet.command.as_str() {
+    let (real_name, network_id, password, requested_rating) = match packet.command.as_str() {
         "AA" => {
             // #AA(callsign):SERVER:(full name):(network ID):(password):(rating):(protocol version)
             let real_name = packet.data.get(0).cloned();
@@ -116,7 +190,7 @@ pub async fn handle_login(
     let network_id_str = match network_id.clone() {
         Some(id) => id,
         None => {
-            log::warn!("Missing network ID for login");
+            tracing::warn!("Missing network ID for login");
             return;
         }
     };
@@ -124,86 +198,226 @@ pub async fn handle_login(
     let password_str = match password {
         Some(pwd) => pwd,
         None => {
-            log::warn!("Missing password for login");
+            tracing::warn!("Missing password for login");
             return;
         }
     };
 
+    // Observers log in as ATC with an _OBS callsign or rating 1
+    let is_observer = client_type == ClientType::Atc
+        && (callsign.to_ascii_uppercase().ends_with("_OBS") || requested_rating == Some(1));
+    if is_observer && !config.features.allow_observers {
+        tracing::warn!(
+            "Observer login for {} from {} refused, observers are not allowed",
+            callsign,
+            sender_addr
+        );
+        send_login_error(
+            &config.server_callsign,
+            broadcast_tx,
+            sender_addr,
+            &callsign,
+            "011",
+            "Observers are not allowed on this server",
+        );
+        return;
+    }
+
+    // Refuse network IDs locked out after too many failed logins
+    if let Some(remaining) = throttle.lockout_remaining(&network_id_str, clock.now()) {
+        tracing::warn!(
+            "Login for {} from {} refused, locked out for another {}s",
+            network_id_str,
+            sender_addr,
+            remaining.as_secs()
+        );
+        send_login_error(
+            &config.server_callsign,
+            broadcast_tx,
+            sender_addr,
+            &callsign,
+            "013",
+            "Too many failed logins, try again later",
+        );
+        return;
+    }
+
     // Authenticate user
-    let user = match auth::validate_login(db, &network_id_str, &password_str).await {
-        Ok(user) => {
-            log::info!("User {} authenticated successfully", network_id_str);
-            user
+    let real_name = real_name.unwrap_or_default();
+    let login = match auth::authenticate(
+        db,
+        &network_id_str,
+        &password_str,
+        &real_name,
+        &config.auth,
+        clock.utc(),
+    )
+    .await
+    {
+        Ok(login) => {
+            tracing::info!("User {} authenticated successfully", network_id_str);
+            throttle.record_success(&network_id_str);
+            login
         }
         Err(e) => {
-            log::warn!("Authentication failed for {}: {}", network_id_str, e);
-            // Send error message
-            let error_packet = Packet {
-                packet_type: crate::packet::PacketType::Request,
-                command: "ER".to_string(),
-                source: "server".to_string(),
-                destination: callsign.clone(),
-                data: vec![
-                    "003".to_string(),
-                    String::new(),
-                    "Invalid credentials".to_string(),
-                ],
+            tracing::warn!("Authentication failed for {}: {}", network_id_str, e);
+            let wrong_credentials = matches!(
+                e,
+                auth::AuthError::InvalidCredentials | auth::AuthError::UserNotFound
+            );
+            if wrong_credentials && throttle.record_failure(&network_id_str, clock.now()) {
+                tracing::warn!(
+                    "Locking out {} after repeated failed logins from {}",
+                    network_id_str,
+                    sender_addr
+                );
+            }
+            let (code, message) = match e {
+                auth::AuthError::AccountDisabled => ("013", "Account disabled"),
+                _ => ("003", "Invalid credentials"),
             };
-            let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(error_packet)));
+            send_login_error(
+                &config.server_callsign,
+                broadcast_tx,
+                sender_addr,
+                &callsign,
+                code,
+                message,
+            );
             return;
         }
     };
 
+    // Checked after the credentials, like disabled accounts
+    match service::find_active_ban(db, &network_id_str, sender_addr.ip(), clock.utc()).await {
+        Ok(Some(ban)) => {
+            tracing::warn!(
+                "Login for {} from {} refused by ban {}: {}",
+                network_id_str,
+                sender_addr,
+                ban.id,
+                ban.reason
+            );
+            metrics::ban_refusal();
+            let message = format!("Banned: {}", ban.reason);
+            send_login_error(
+                &config.server_callsign,
+                broadcast_tx,
+                sender_addr,
+                &callsign,
+                "013",
+                &message,
+            );
+            return;
+        }
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to check bans for {}: {}", network_id_str, e),
+    }
+
     // Use rating from database
+    let user = login.user;
     let atc_rating = user.atc_rating;
     let pilot_rating = user.pilot_rating;
     let db_real_name = user.real_name.clone();
+    let stored_rating = match client_type {
+        ClientType::Atc => atc_rating,
+        ClientType::Pilot => pilot_rating,
+        _ => 1,
+    };
+
+    // Controllers can't claim more than their rating, or what a mentor
+    // raised it to on this connection
+    let elevated_rating = clients
+        .read()
+        .await
+        .get(&sender_addr)
+        .filter(|client| client.elevation.is_some())
+        .and_then(|client| client.effective_rating(clock.now()))
+        .filter(|rating| *rating > stored_rating);
+    if client_type == ClientType::Atc
+        && requested_rating.is_some_and(|rating| rating > elevated_rating.unwrap_or(stored_rating))
+    {
+        tracing::warn!(
+            "Login for {} from {} refused, rating {:?} requested with {}",
+            callsign,
+            sender_addr,
+            requested_rating,
+            elevated_rating.unwrap_or(stored_rating)
+        );
+        send_login_error(
+            &config.server_callsign,
+            broadcast_tx,
+            sender_addr,
+            &callsign,
+            "011",
+            "Requested level too high",
+        );
+        return;
+    }
 
     // Update client state
+    let protocol_field = if packet.command == "AA" { 4 } else { 3 };
+    let mut unverified_client = false;
+    // One copy, shared by the client and the callsign map
+    let name = Callsign::new(&callsign);
     {
-        let mut clients_map = clients.write().await;
-        if let Some(client) = clients_map.get_mut(&sender_addr) {
-            client.callsign = Some(callsign.clone());
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        if let Some(mut client) = clients_map.get_mut(&sender_addr) {
+            unverified_client = client.unverified_client;
+            client.record_identity(&callsign, Some(&network_id_str));
+            client.callsign = Some(name.clone());
             client.client_type = Some(client_type.clone());
             client.state = ClientState::Active;
+            client.logged_in_at = Some(clock.now());
+            client.logon_time = Some(clock.utc());
+            client.protocol_revision = packet.data.get(protocol_field).and_then(|s| s.parse().ok());
             client.real_name = Some(db_real_name.clone());
+            client.is_guest = login.is_guest;
             client.network_id = Some(network_id_str.clone());
-            client.rating = Some(match client_type {
-                ClientType::Atc => atc_rating,
-                ClientType::Pilot => pilot_rating,
-                _ => 1,
-            });
-        }
+            client.rating = Some(elevated_rating.unwrap_or(stored_rating));
+            if let Some(elevation) = &mut client.elevation {
+                elevation.stored_rating = Some(stored_rating);
+            }
+            if is_observer && config.visibility.snapshot_observers {
+                client.delivery = Delivery::Snapshot;
+            }
+        };
     }
 
     // Add to callsign map
     {
-        let mut map = callsign_map.write().await;
-        map.insert(callsign.clone(), sender_addr);
+        let mut map = diagnostics::write_lock(callsign_map, "callsigns").await;
+        map.insert(name, sender_addr);
     }
 
-    log::info!("Login successful for {}", callsign);
+    if unverified_client {
+        tracing::warn!(
+            "Login successful for {} with unverified client software",
+            callsign
+        );
+    } else {
+        tracing::info!("Login successful for {}", callsign);
+    }
+    metrics::login(true);
+    if !login.is_guest {
+        stats.record_login(&network_id_str);
+    }
 
-    // Send welcome messages (VATSIM style)
-    let welcome_messages = vec![
-        "By using your VATSIM assigned identification number on this server you",
-        "hereby agree to the terms of the VATSIM Code of Regulations and the",
-        "VATSIM User Agreement and the VATSIM Code of Conduct which may be viewed",
-        "at http://www.vatsim.net/network/docs/",
-        "All logins are tracked and identification numbers are recorded.",
-        "Users must enter their real full first names and surnames when logging",
-        "onto any of the VATSIM.net servers.",
-    ];
+    // Send welcome messages (VATSIM style), controllers get their own lines too
+    let mut welcome_messages = motd.motd(db, &config.motd).await;
+    if client_type == ClientType::Atc {
+        welcome_messages.extend(motd.atc_motd(db, &config.atc_motd).await);
+    }
 
     for msg in welcome_messages {
         let welcome_packet = Packet {
             packet_type: crate::packet::PacketType::Client,
             command: "TM".to_string(),
-            source: "server".to_string(),
+            source: config.server_callsign.clone(),
             destination: callsign.clone(),
-            data: vec![msg.to_string()],
+            data: vec![msg],
         };
-        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(welcome_packet)));
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(welcome_packet))));
     }
 
     // Complete VATSIM login sequence for ATC
@@ -212,68 +426,129 @@ pub async fn handle_login(
         let caps_request = Packet {
             packet_type: crate::packet::PacketType::Request,
             command: "CQ".to_string(),
-            source: "SERVER".to_string(),
+            source: config.server_callsign.clone(),
             destination: callsign.clone(),
             data: vec!["CAPS".to_string()],
         };
-        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(caps_request)));
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(caps_request))));
 
         // Send additional ATC capability requests
         let atc_info_request = Packet {
             packet_type: crate::packet::PacketType::Request,
             command: "CR".to_string(),
-            source: "SERVER".to_string(),
+            source: config.server_callsign.clone(),
             destination: callsign.clone(),
             data: vec!["CAPS:ATCINFO=1:SECPOS=1:MODELDESC=1:ONGOINGCOORD=1".to_string()],
         };
-        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(atc_info_request)));
+        let _ = broadcast_tx.send((
+            sender_addr,
+            ServerMessage::Direct(Arc::new(atc_info_request)),
+        ));
 
         // Send IP information
         let ip_request = Packet {
             packet_type: crate::packet::PacketType::Request,
             command: "CR".to_string(),
-            source: "SERVER".to_string(),
+            source: config.server_callsign.clone(),
             destination: callsign.clone(),
             data: vec!["IP".to_string(), sender_addr.ip().to_string()],
         };
-        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(ip_request)));
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(ip_request))));
     }
 
     // Complete VATSIM login sequence for Pilots
+    let mut snapshot = None;
+    let mut prefile = None;
     if client_type == ClientType::Pilot {
         // Request client capabilities
         let caps_request = Packet {
             packet_type: crate::packet::PacketType::Request,
             command: "CQ".to_string(),
-            source: "SERVER".to_string(),
+            source: config.server_callsign.clone(),
             destination: callsign.clone(),
             data: vec!["CAPS".to_string()],
         };
-        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(caps_request)));
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(caps_request))));
 
         // Send IP information
         let ip_request = Packet {
             packet_type: crate::packet::PacketType::Request,
             command: "CR".to_string(),
-            source: "SERVER".to_string(),
+            source: config.server_callsign.clone(),
             destination: callsign.clone(),
             data: vec!["IP".to_string(), sender_addr.ip().to_string()],
         };
-        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(ip_request)));
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(ip_request))));
 
-        // Send no flight plan warning (if applicable)
-        let no_fp_warning = Packet {
-            packet_type: crate::packet::PacketType::Request,
-            command: "ER".to_string(),
-            source: "server".to_string(),
-            destination: callsign.clone(),
-            data: vec![
-                "008".to_string(),
-                callsign.clone(),
-                "No flightplan".to_string(),
-            ],
-        };
-        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(no_fp_warning)));
+        // A crashed session to resume, else a prefiled flight plan to
+        // activate, claimed with the rest of the login bookkeeping
+        snapshot = snapshot::find_snapshot(&network_id_str, &callsign, db).await;
+        if snapshot.is_none() {
+            prefile = flight_plan::find_prefile(&network_id_str, &callsign, db, clock.utc()).await;
+        }
+    }
+
+    let session_id = {
+        let clients_map = clients.read().await;
+        clients_map
+            .get(&sender_addr)
+            .map(|client| client.session_id.clone())
+            .unwrap_or_default()
+    };
+    let record = LoginRecord {
+        session_id,
+        network_id: network_id_str.clone(),
+        callsign: callsign.clone(),
+        client_type: match client_type {
+            ClientType::Pilot => "pilot",
+            ClientType::Atc => "atc",
+            ClientType::Observer => "observer",
+        }
+        .to_string(),
+        address: sender_addr.ip().to_string(),
+        logged_in_at: clock.utc(),
+        snapshot_id: snapshot.as_ref().map(|snapshot| snapshot.id),
+        prefile_id: prefile.as_ref().map(|prefile| prefile.id),
+    };
+    let claims = persist_login(db, record, login.is_guest).await;
+
+    // Resume the session, else activate the prefile, else warn that there
+    // is no flight plan
+    if client_type == ClientType::Pilot {
+        if let Some(snapshot) = snapshot.filter(|_| claims.snapshot) {
+            snapshot::restore_snapshot(
+                &snapshot,
+                sender_addr,
+                clients,
+                callsign_map,
+                broadcast_tx,
+                &config.server_callsign,
+            )
+            .await;
+        } else if let Some(prefile) = prefile.filter(|_| claims.prefile) {
+            flight_plan::deliver_prefiled_flight_plan(
+                &prefile,
+                &callsign,
+                sender_addr,
+                clients,
+                broadcast_tx,
+            )
+            .await;
+        } else {
+            let no_fp_warning = Packet {
+                packet_type: crate::packet::PacketType::Request,
+                command: "ER".to_string(),
+                source: config.server_callsign.clone(),
+                destination: callsign.clone(),
+                data: vec![
+                    "008".to_string(),
+                    callsign.clone(),
+                    "No flightplan".to_string(),
+                ],
+            };
+            let _ =
+                broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(no_fp_warning))));
+        }
     }
 
     // Broadcast client addition to all other clients
@@ -284,24 +559,100 @@ pub async fn handle_login(
         destination: "SERVER".to_string(),
         data: packet.data.clone(),
     };
-    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(add_client_packet)));
+    let _ = broadcast_tx.send((
+        sender_addr,
+        ServerMessage::Packet(Arc::new(add_client_packet)),
+    ));
+    if let Some(client) = clients.read().await.get(&sender_addr) {
+        state.webhooks.emit(&Event::client_connected(client));
+    }
+}
+
+/// Persist the bookkeeping of a login, returning what it claimed
+///
+/// Done off the handshake path unless there is a snapshot or prefile to
+/// claim, as neither may be used before the claim is stored. Guests leave
+/// no trace beyond their claims.
+async fn persist_login(
+    db: &Arc<DatabaseConnection>,
+    record: LoginRecord,
+    is_guest: bool,
+) -> LoginClaims {
+    if record.snapshot_id.is_none() && record.prefile_id.is_none() {
+        if !is_guest {
+            let db = db.clone();
+            tokio::spawn(async move {
+                if let Err(e) = service::record_login(&db, &record).await {
+                    tracing::error!("Failed to record login of {}: {}", record.callsign, e);
+                }
+            });
+        }
+        return LoginClaims::default();
+    }
+
+    let claimed = if is_guest {
+        service::claim_for_login(db.as_ref(), record.snapshot_id, record.prefile_id).await
+    } else {
+        service::record_login(db, &record).await
+    };
+    claimed.unwrap_or_else(|e| {
+        tracing::error!("Failed to record login of {}: {}", record.callsign, e);
+        LoginClaims::default()
+    })
+}
+
+/// Reply to a refused login with an $ER packet
+fn send_login_error(
+    server_callsign: &str,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    sender_addr: SocketAddr,
+    callsign: &str,
+    code: &str,
+    message: &str,
+) {
+    metrics::login(false);
+    let error_packet = Packet {
+        packet_type: crate::packet::PacketType::Request,
+        command: "ER".to_string(),
+        source: server_callsign.to_string(),
+        destination: callsign.to_string(),
+        data: vec![code.to_string(), String::new(), message.to_string()],
+    };
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(error_packet))));
 }
 
 /// Handle logoff
 pub async fn handle_logoff(
     packet: Packet,
     sender_addr: SocketAddr,
-    _clients: &Arc<RwLock<HashMap<SocketAddr, Client>>>,
-    callsign_map: &Arc<RwLock<HashMap<String, SocketAddr>>>,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    callsign_map: &Arc<RwLock<HashMap<Callsign, SocketAddr>>>,
     broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    db: &Arc<DatabaseConnection>,
 ) {
     let callsign = packet.source.clone();
-    log::info!("Logoff from {} ({})", sender_addr, callsign);
+    tracing::info!("Logoff from {} ({})", sender_addr, callsign);
+
+    // A clean logoff ends the session, nothing to resume later, and a
+    // controller's visibility centers with it
+    let network_id = {
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        clients_map.get_mut(&sender_addr).and_then(|mut client| {
+            client.visibility_centers.clear();
+            client.network_id.clone()
+        })
+    };
+    if let Some(network_id) = network_id {
+        if let Err(e) = service::delete_position_snapshot(db.as_ref(), &network_id, &callsign).await
+        {
+            tracing::error!("Failed to delete position snapshot for {}: {}", callsign, e);
+        }
+    }
 
     // Remove from callsign map
     {
-        let mut map = callsign_map.write().await;
-        map.remove(&callsign);
+        let mut map = diagnostics::write_lock(callsign_map, "callsigns").await;
+        map.remove(Callsign::key(&callsign).as_ref());
     }
 
     // Broadcast client removal to all other clients
@@ -312,5 +663,179 @@ pub async fn handle_logoff(
         destination: packet.destination.clone(),
         data: packet.data.clone(),
     };
-    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(remove_packet)));
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(remove_packet))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::config::FeaturesConfig;
+    use crate::server::clock::{Clock, SystemClock, TestClock};
+    use crate::server::config::ServerConfig;
+    use std::time::Duration;
+
+    const ADDR: &str = "127.0.0.1:50001";
+
+    /// A database with the account 1234567, password "secret"
+    async fn db_with_user() -> Arc<DatabaseConnection> {
+        let db = Arc::new(crate::db::init_ephemeral().await.unwrap());
+        let password_hash = crate::auth::password::hash_password("secret").unwrap();
+        service::create_user(
+            &db,
+            "1234567".to_string(),
+            password_hash,
+            "Test Observer".to_string(),
+            1,
+            1,
+        )
+        .await
+        .unwrap();
+        db
+    }
+
+    /// Log in as an observer and return whether the login went through
+    async fn observer_login(
+        db: Arc<DatabaseConnection>,
+        allow_observers: bool,
+        clock: Arc<dyn Clock>,
+    ) -> bool {
+        let login = "#AAZSPD_OBS:SERVER:Test Observer:1234567:secret:1:100";
+        login_with(db, login, allow_observers, clock).await
+    }
+
+    /// Send the login packet `line` as it comes off the wire and return
+    /// whether the login went through
+    async fn login_with(
+        db: Arc<DatabaseConnection>,
+        line: &str,
+        allow_observers: bool,
+        clock: Arc<dyn Clock>,
+    ) -> bool {
+        let mut client = Client::new(ADDR.parse().unwrap());
+        client.state = ClientState::Identified;
+        let state = ServerState {
+            clients: Arc::new(RwLock::new(ClientRegistry::from([(client.addr, client)]))),
+            config: ServerConfig {
+                features: FeaturesConfig {
+                    allow_observers,
+                    ..FeaturesConfig::default()
+                },
+                ..ServerConfig::default()
+            },
+            clock,
+            ..ServerState::for_tests(db)
+        };
+        let mut rx = state.broadcast_tx.subscribe();
+
+        let login = Packet::parse(line).unwrap();
+        handle_login(login, ADDR.parse().unwrap(), &state).await;
+
+        // Pilots are also told when they have no flight plan on file
+        let refused = std::iter::from_fn(|| rx.try_recv().ok()).any(|(_, msg)| {
+            matches!(msg, ServerMessage::Direct(packet)
+                if packet.command == "ER" && packet.data.first().is_some_and(|code| code != "008"))
+        });
+        let active = state.clients.read().await[&ADDR.parse().unwrap()].is_active();
+        assert_eq!(active, !refused);
+        active
+    }
+
+    #[tokio::test]
+    async fn test_observers_allowed() {
+        assert!(observer_login(db_with_user().await, true, Arc::new(SystemClock)).await);
+    }
+
+    #[tokio::test]
+    async fn test_observers_refused() {
+        assert!(!observer_login(db_with_user().await, false, Arc::new(SystemClock)).await);
+    }
+
+    #[tokio::test]
+    async fn test_banned_address_refused() {
+        let db = db_with_user().await;
+        service::add_ban(
+            &db,
+            service::NewBan {
+                ip: Some("127.0.0.0/8".to_string()),
+                reason: "Test".to_string(),
+                ..Default::default()
+            },
+            "test",
+        )
+        .await
+        .unwrap();
+        assert!(!observer_login(db, true, Arc::new(SystemClock)).await);
+    }
+
+    #[tokio::test]
+    async fn test_ban_expires() {
+        let db = db_with_user().await;
+        let clock = Arc::new(TestClock::default());
+        service::add_ban(
+            &db,
+            service::NewBan {
+                network_id: Some("1234567".to_string()),
+                reason: "Test".to_string(),
+                expires_at: Some(clock.utc() + chrono::Duration::hours(1)),
+                ..Default::default()
+            },
+            "test",
+        )
+        .await
+        .unwrap();
+        assert!(!observer_login(db.clone(), true, clock.clone()).await);
+
+        // Only skip the two hours: the database's timeouts run on tokio's
+        // clock too
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_secs(2 * 3600)).await;
+        tokio::time::resume();
+        assert!(observer_login(db, true, clock).await);
+    }
+
+    #[tokio::test]
+    async fn test_token_login_over_the_wire() {
+        let db = db_with_user().await;
+        let token = crate::auth::token::issue_login_token(
+            &db,
+            "1234567",
+            chrono::Duration::hours(1),
+            chrono::Utc::now(),
+        )
+        .await
+        .unwrap();
+        let login = format!(
+            "#APCCA1501:SERVER:1234567:{}{}:1:100:1:Test Pilot ZSPD",
+            crate::auth::token::TOKEN_PREFIX,
+            token
+        );
+        assert!(login_with(db.clone(), &login, true, Arc::new(SystemClock)).await);
+        // Single use
+        assert!(!login_with(db, &login, true, Arc::new(SystemClock)).await);
+    }
+
+    #[tokio::test]
+    async fn test_token_expires_on_server_clock() {
+        let db = db_with_user().await;
+        let clock = Arc::new(TestClock::default());
+        let token = crate::auth::token::issue_login_token(
+            &db,
+            "1234567",
+            chrono::Duration::hours(1),
+            clock.utc(),
+        )
+        .await
+        .unwrap();
+        let login = format!(
+            "#APCCA1501:SERVER:1234567:{}{}:1:100:1:Test Pilot ZSPD",
+            crate::auth::token::TOKEN_PREFIX,
+            token
+        );
+
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_secs(2 * 3600)).await;
+        tokio::time::resume();
+        assert!(!login_with(db, &login, true, clock).await);
+    }
 }
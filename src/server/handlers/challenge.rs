@@ -0,0 +1,140 @@
+use crate::auth::challenge;
+use crate::db::service;
+use crate::packet::Packet;
+use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::registry::ClientRegistry;
+use crate::webhooks::{Event, Webhooks};
+use sea_orm::DatabaseConnection;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, RwLock};
+
+/// Look up the challenge key for the client software of a connection
+async fn lookup_client_key(
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    db: &Arc<DatabaseConnection>,
+) -> Option<String> {
+    let client_id = {
+        let clients_map = clients.read().await;
+        clients_map.get(&sender_addr)?.client_id.clone()?
+    };
+
+    match service::find_client_key(db, &client_id).await {
+        Ok(key) => key,
+        Err(e) => {
+            tracing::error!("Failed to look up client key for {}: {}", client_id, e);
+            None
+        }
+    }
+}
+
+/// Handle auth challenge ($ZC) sent by a client
+pub async fn handle_auth_challenge(
+    packet: Packet,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    db: &Arc<DatabaseConnection>,
+    server_callsign: &str,
+) {
+    // Challenges between clients are simply forwarded
+    if !packet.destination.eq_ignore_ascii_case("SERVER") {
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(packet))));
+        return;
+    }
+
+    // $ZC(callsign):SERVER:(challenge)
+    let challenge_str = match packet.data.first() {
+        Some(challenge_str) => challenge_str,
+        None => {
+            tracing::warn!("Empty auth challenge from {}", packet.source);
+            return;
+        }
+    };
+
+    let client_key = match lookup_client_key(sender_addr, clients, db).await {
+        Some(client_key) => client_key,
+        None => {
+            tracing::debug!("No client key for {}, ignoring auth challenge", packet.source);
+            return;
+        }
+    };
+
+    let response = Packet {
+        packet_type: crate::packet::PacketType::Request,
+        command: "ZR".to_string(),
+        source: server_callsign.to_string(),
+        destination: packet.source.clone(),
+        data: vec![challenge::compute_response(&client_key, challenge_str)],
+    };
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(response))));
+}
+
+/// Handle auth challenge response ($ZR) sent by a client
+pub async fn handle_auth_response(
+    packet: Packet,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    db: &Arc<DatabaseConnection>,
+    server_callsign: &str,
+    webhooks: &Webhooks,
+) {
+    // Responses between clients are simply forwarded
+    if !packet.destination.eq_ignore_ascii_case("SERVER") {
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(packet))));
+        return;
+    }
+
+    let pending_challenge = {
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        clients_map
+            .get_mut(&sender_addr)
+            .and_then(|mut client| client.auth_challenge.take())
+    };
+
+    let pending_challenge = match pending_challenge {
+        Some(pending_challenge) => pending_challenge,
+        None => {
+            tracing::warn!("Unexpected auth response from {}", packet.source);
+            return;
+        }
+    };
+
+    let client_key = match lookup_client_key(sender_addr, clients, db).await {
+        Some(client_key) => client_key,
+        None => {
+            tracing::warn!("Client key for {} disappeared, skipping challenge", packet.source);
+            return;
+        }
+    };
+
+    // $ZR(callsign):SERVER:(response)
+    let response = packet.data.first().map(String::as_str).unwrap_or_default();
+    if challenge::verify_response(&client_key, &pending_challenge, response) {
+        tracing::info!("Auth challenge passed by {}", packet.source);
+        return;
+    }
+
+    tracing::warn!("Auth challenge failed by {} - disconnecting", packet.source);
+    let error_packet = Packet {
+        packet_type: crate::packet::PacketType::Request,
+        command: "ER".to_string(),
+        source: server_callsign.to_string(),
+        destination: packet.source.clone(),
+        data: vec![
+            "016".to_string(),
+            String::new(),
+            "Invalid auth challenge response".to_string(),
+        ],
+    };
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(error_packet))));
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Disconnect("auth_challenge")));
+    crate::metrics::kick("auth_challenge");
+    webhooks.emit(&Event::client_kicked(
+        &packet.source,
+        "invalid auth challenge response",
+    ));
+}
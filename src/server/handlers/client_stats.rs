@@ -0,0 +1,288 @@
+//! A client's own connection statistics
+//!
+//! To tell whether a problem is theirs or the server's, clients ask with
+//! `.stats` or `$CQ(callsign):SERVER:STATS` and get a few `#TM` lines: the
+//! keepalive round trip, packets and bytes each way, how often positions
+//! arrive, how many of their messages were dropped and how long they have
+//! been connected, all from the connection's counters. Supervisors can add
+//! another client's callsign to see that client's statistics.
+
+use crate::client::Client;
+use crate::packet::Packet;
+use crate::server::config::ServerMessage;
+use crate::server::handlers::command::{send_lines, supervisor_network_id};
+use crate::server::registry::ClientRegistry;
+use sea_orm::DatabaseConnection;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+
+/// `$CQ` subtype of the query
+pub const QUERY: &str = "STATS";
+
+/// Whether `packet` is a `$CQ` asking the server for statistics
+pub fn is_query(packet: &Packet, server_callsign: &str) -> bool {
+    (packet.destination.eq_ignore_ascii_case("SERVER")
+        || packet.destination.eq_ignore_ascii_case(server_callsign))
+        && packet
+            .data
+            .first()
+            .is_some_and(|kind| kind.eq_ignore_ascii_case(QUERY))
+}
+
+/// Answer `$CQ(callsign):SERVER:STATS[:(callsign)]` with a `#TM` per line
+pub async fn handle_query(
+    packet: &Packet,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    db: &DatabaseConnection,
+    server_callsign: &str,
+    now: Instant,
+) {
+    let target = packet.data.get(1).map(String::as_str);
+    let lines = reply(target, sender_addr, clients, db, now).await;
+    send_lines(
+        lines,
+        sender_addr,
+        &packet.source,
+        server_callsign,
+        broadcast_tx,
+    );
+}
+
+/// The statistics of `target`, or of the sender if it is left out or
+/// names the sender; only supervisors may name someone else
+pub async fn reply(
+    target: Option<&str>,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    db: &DatabaseConnection,
+    now: Instant,
+) -> Vec<String> {
+    let own_callsign = clients
+        .read()
+        .await
+        .get(&sender_addr)
+        .and_then(|client| client.callsign().map(str::to_string));
+    let target = target.filter(|target| {
+        own_callsign
+            .as_deref()
+            .is_none_or(|own| !own.eq_ignore_ascii_case(target))
+    });
+    if target.is_some()
+        && supervisor_network_id(sender_addr, clients, db)
+            .await
+            .is_none()
+    {
+        return vec!["Only supervisors can see another client's statistics".to_string()];
+    }
+
+    let clients_map = clients.read().await;
+    let client = match target {
+        Some(callsign) => clients_map.named(callsign).map(|(_, client)| client),
+        None => clients_map.get(&sender_addr),
+    };
+    match client {
+        Some(client) => lines(client, now),
+        None => vec![format!("{} is not connected", target.unwrap_or_default())],
+    }
+}
+
+/// The statistics of `client` at `now`
+pub fn lines(client: &Client, now: Instant) -> Vec<String> {
+    let counters = &client.counters;
+    let ping = match client.ping {
+        Some(ping) => format!("ping {} ms", ping.as_millis()),
+        None => "ping not measured yet".to_string(),
+    };
+    let position_updates = counters.position_updates.load(Ordering::Relaxed);
+    let positions = match client.position_interval() {
+        Some(interval) => format!(
+            "Position updates {}, one every {:.1} s",
+            position_updates,
+            interval.as_secs_f64()
+        ),
+        None => format!("Position updates {}", position_updates),
+    };
+    vec![
+        format!(
+            "{} connected {}, {}",
+            client.callsign().unwrap_or("You"),
+            format_duration(now.saturating_duration_since(client.connected_at)),
+            ping
+        ),
+        format!(
+            "Packets in {} ({}), out {} ({})",
+            counters.packets_in.load(Ordering::Relaxed),
+            format_bytes(counters.bytes_in.load(Ordering::Relaxed)),
+            counters.packets_out.load(Ordering::Relaxed),
+            format_bytes(counters.bytes_out.load(Ordering::Relaxed)),
+        ),
+        positions,
+        format!(
+            "Messages dropped {}",
+            counters.dropped.load(Ordering::Relaxed)
+        ),
+    ]
+}
+
+/// A session length, e.g. "1h 05m" or "4m 30s"
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs / 3600 {
+        0 => format!("{}m {:02}s", secs / 60, secs % 60),
+        hours => format!("{}h {:02}m", hours, secs / 60 % 60),
+    }
+}
+
+/// A byte count, e.g. "900 B" or "2.5 KiB"
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1048576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1048576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientType;
+    use crate::db::entities::user::SUPERVISOR_RATING;
+    use crate::db::service;
+
+    const PILOT_ADDR: &str = "127.0.0.1:50001";
+    const OTHER_ADDR: &str = "127.0.0.1:50002";
+
+    fn client(
+        addr: &str,
+        callsign: &str,
+        client_type: ClientType,
+        network_id: &str,
+        connected_at: Instant,
+    ) -> Client {
+        let mut client =
+            Client::active_for_tests(addr.parse::<SocketAddr>().unwrap(), callsign, client_type);
+        client.network_id = Some(network_id.to_string());
+        client.connected_at = connected_at;
+        client
+    }
+
+    /// CCA1501 after a scripted session: logged in, ten positions five
+    /// seconds apart, a few messages, one of them over the rate limit
+    fn pilot(start: Instant) -> Client {
+        let mut pilot = client(PILOT_ADDR, "CCA1501", ClientType::Pilot, "1234567", start);
+        let counters = pilot.counters.clone();
+        for bytes in [40, 60, 80] {
+            counters.received(bytes);
+        }
+        for n in 0..10 {
+            counters.received(50);
+            counters.position_update();
+            pilot.position_received(start + Duration::from_secs(20 + 5 * n));
+        }
+        for _ in 0..3 {
+            counters.received(30);
+        }
+        counters.dropped();
+        for _ in 0..1000 {
+            counters.sent(45);
+        }
+        pilot.ping = Some(Duration::from_millis(85));
+        pilot
+    }
+
+    async fn setup(
+        start: Instant,
+        other_rating: i32,
+    ) -> (DatabaseConnection, Arc<RwLock<ClientRegistry>>) {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        service::create_user(
+            &db,
+            "1000001".to_string(),
+            "hash".to_string(),
+            "Test User".to_string(),
+            other_rating,
+            1,
+        )
+        .await
+        .unwrap();
+        let other = client(OTHER_ADDR, "ZSPD_SUP", ClientType::Atc, "1000001", start);
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([
+            (PILOT_ADDR.parse().unwrap(), pilot(start)),
+            (other.addr, other),
+        ])));
+        (db, clients)
+    }
+
+    #[tokio::test]
+    async fn test_own_stats_match_counters() {
+        let start = Instant::now();
+        let (db, clients) = setup(start, 5).await;
+        let now = start + Duration::from_secs(3900);
+        let expected = [
+            "CCA1501 connected 1h 05m, ping 85 ms",
+            "Packets in 16 (770 B), out 1000 (43.9 KiB)",
+            "Position updates 10, one every 5.0 s",
+            "Messages dropped 1",
+        ];
+
+        let pilot_addr = PILOT_ADDR.parse().unwrap();
+        assert_eq!(reply(None, pilot_addr, &clients, &db, now).await, expected);
+        assert_eq!(
+            reply(Some("cca1501"), pilot_addr, &clients, &db, now).await,
+            expected
+        );
+
+        // Asked with $CQ, answered with #TMs from the server
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+        let query = Packet::parse("$CQCCA1501:SERVER:STATS").unwrap();
+        assert!(is_query(&query, "SERVER"));
+        handle_query(
+            &query,
+            pilot_addr,
+            &clients,
+            &broadcast_tx,
+            &db,
+            "SERVER",
+            now,
+        )
+        .await;
+        let sent: Vec<String> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|(_, msg)| match msg {
+                ServerMessage::Direct(packet) => packet.to_string(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(sent.len(), expected.len());
+        assert_eq!(sent[3], "#TMSERVER:CCA1501:Messages dropped 1");
+    }
+
+    #[tokio::test]
+    async fn test_others_stats_only_for_supervisors() {
+        let start = Instant::now();
+        let now = start + Duration::from_secs(270);
+        let other_addr = OTHER_ADDR.parse().unwrap();
+
+        let (db, clients) = setup(start, 5).await;
+        assert_eq!(
+            reply(Some("CCA1501"), other_addr, &clients, &db, now).await,
+            ["Only supervisors can see another client's statistics"]
+        );
+        // Their own still works
+        let own = reply(None, other_addr, &clients, &db, now).await;
+        assert_eq!(own[0], "ZSPD_SUP connected 4m 30s, ping not measured yet");
+        assert_eq!(own[2], "Position updates 0");
+
+        let (db, clients) = setup(start, SUPERVISOR_RATING).await;
+        let replies = reply(Some("CCA1501"), other_addr, &clients, &db, now).await;
+        assert_eq!(replies[0], "CCA1501 connected 4m 30s, ping 85 ms");
+        assert_eq!(
+            reply(Some("DLH123"), other_addr, &clients, &db, now).await,
+            ["DLH123 is not connected"]
+        );
+    }
+}
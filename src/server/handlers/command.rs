@@ -0,0 +1,751 @@
+use crate::client::{ClientType, Elevation};
+use crate::db::service;
+use crate::packet::Packet;
+use crate::server::clock::Clock;
+use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::elevation::{self, MAX_RATING};
+use crate::server::follow;
+use crate::server::handlers::{client_stats, metar_subscription, nearest_atc};
+use crate::server::registry::ClientRegistry;
+use crate::server::state::ServerState;
+use sea_orm::DatabaseConnection;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, RwLock};
+
+/// Notes listed by `.notes`
+const NOTES_SHOWN: u64 = 5;
+
+/// How long `.mute` lasts without a duration
+const DEFAULT_MUTE_MINUTES: u64 = 10;
+
+/// Longest mute, a day
+const MAX_MUTE_MINUTES: u64 = 24 * 60;
+
+/// How long `.elevate` lasts without a duration
+const DEFAULT_ELEVATION_MINUTES: u64 = 60;
+
+/// Longest elevation, a long session
+const MAX_ELEVATION_MINUTES: u64 = 8 * 60;
+
+/// Whether a text message is a dot-command addressed to the server
+pub fn is_server_command(packet: &Packet) -> bool {
+    packet.destination.eq_ignore_ascii_case("SERVER")
+        && packet
+            .data
+            .first()
+            .is_some_and(|text| text.starts_with('.'))
+}
+
+/// Handle a dot-command such as `.notes 1234567` sent as #TM to SERVER
+///
+/// The reply goes only to the sender, as one #TM per line.
+pub async fn handle_server_command(packet: Packet, sender_addr: SocketAddr, state: &ServerState) {
+    let ServerState {
+        clients,
+        broadcast_tx,
+        db,
+        airports,
+        follows,
+        ..
+    } = state;
+    let weather = &*state.weather.borrow().clone();
+    let visibility = &state.config.visibility;
+    let features = &state.config.features;
+    let server_callsign = state.config.server_callsign.as_str();
+    let clock = &*state.clock;
+    // Colons in the message split it into several fields
+    let text = packet.data.join(":");
+    let mut words = text.split_whitespace();
+    let command = words.next().unwrap_or_default().to_ascii_lowercase();
+    let args: Vec<&str> = words.collect();
+    tracing::info!("Server command from {}: {}", packet.source, command);
+
+    let lines = match command.as_str() {
+        ".notes" => notes_command(&args, sender_addr, clients, db).await,
+        ".mute" => {
+            mute_command(
+                &args,
+                sender_addr,
+                clients,
+                broadcast_tx,
+                db,
+                server_callsign,
+                clock,
+            )
+            .await
+        }
+        ".unmute" => {
+            unmute_command(
+                &args,
+                sender_addr,
+                clients,
+                broadcast_tx,
+                db,
+                server_callsign,
+                clock,
+            )
+            .await
+        }
+        ".elevate" => {
+            elevate_command(
+                &args,
+                sender_addr,
+                clients,
+                broadcast_tx,
+                db,
+                server_callsign,
+                clock,
+            )
+            .await
+        }
+        ".follow" => {
+            follow::follow(
+                &args,
+                sender_addr,
+                clients,
+                follows,
+                features,
+                broadcast_tx,
+                server_callsign,
+            )
+            .await
+        }
+        ".unfollow" => follow::unfollow(
+            sender_addr,
+            follows,
+            &packet.source,
+            broadcast_tx,
+            server_callsign,
+        ),
+        ".accept" => follow::accept(
+            &args,
+            sender_addr,
+            follows,
+            &packet.source,
+            broadcast_tx,
+            server_callsign,
+        ),
+        ".reject" => follow::reject(
+            &args,
+            sender_addr,
+            follows,
+            &packet.source,
+            broadcast_tx,
+            server_callsign,
+        ),
+        ".atc" => {
+            let clients = clients.read().await;
+            nearest_atc::reply(sender_addr, &clients, &airports.get(), visibility)
+        }
+        ".stats" => match args[..] {
+            [] | [_] => {
+                client_stats::reply(args.first().copied(), sender_addr, clients, db, clock.now())
+                    .await
+            }
+            _ => vec!["Usage: .stats [callsign]".to_string()],
+        },
+        ".subscribe-metar" | ".unsubscribe-metar" => match args[..] {
+            [station] if command == ".subscribe-metar" => vec![
+                metar_subscription::subscribe(
+                    station,
+                    &packet.source,
+                    sender_addr,
+                    clients,
+                    broadcast_tx,
+                    weather,
+                    server_callsign,
+                )
+                .await,
+            ],
+            [station] => vec![metar_subscription::unsubscribe(station, sender_addr, clients).await],
+            _ => vec![format!("Usage: {} <icao>", command)],
+        },
+        _ => vec![format!("Unknown command: {}", command)],
+    };
+    send_lines(
+        lines,
+        sender_addr,
+        &packet.source,
+        server_callsign,
+        broadcast_tx,
+    );
+}
+
+/// Send `lines` to `destination` alone, one #TM from the server each
+pub fn send_lines(
+    lines: Vec<String>,
+    sender_addr: SocketAddr,
+    destination: &str,
+    server_callsign: &str,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+) {
+    for line in lines {
+        let reply = Packet {
+            packet_type: crate::packet::PacketType::Client,
+            command: "TM".to_string(),
+            source: server_callsign.to_string(),
+            destination: destination.to_string(),
+            data: vec![line],
+        };
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(reply))));
+    }
+}
+
+/// CID of the sender if it is logged in with a supervisor account
+pub(crate) async fn supervisor_network_id(
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    db: &DatabaseConnection,
+) -> Option<String> {
+    let network_id = {
+        let clients_map = clients.read().await;
+        let client = clients_map.get(&sender_addr)?;
+        if !client.is_active() || client.is_guest {
+            return None;
+        }
+        client.network_id.clone()?
+    };
+
+    match service::find_user_by_network_id(db, &network_id).await {
+        Ok(Some(user)) if user.is_supervisor() => Some(network_id),
+        Ok(_) => None,
+        Err(e) => {
+            tracing::error!("Failed to look up user {}: {}", network_id, e);
+            None
+        }
+    }
+}
+
+/// `.notes <cid>` - most recent supervisor notes on an account
+async fn notes_command(
+    args: &[&str],
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    db: &DatabaseConnection,
+) -> Vec<String> {
+    let Some(supervisor) = supervisor_network_id(sender_addr, clients, db).await else {
+        return vec!["Permission denied".to_string()];
+    };
+    let [network_id] = args else {
+        return vec!["Usage: .notes <cid>".to_string()];
+    };
+
+    let notes = match service::list_user_notes(db, network_id, NOTES_SHOWN).await {
+        Ok(notes) => notes,
+        Err(e) => {
+            tracing::error!("Failed to list notes for {}: {}", network_id, e);
+            return vec!["Notes are unavailable".to_string()];
+        }
+    };
+    if let Err(e) =
+        service::record_audit_event(db, &supervisor, "note.view", Some(network_id), None).await
+    {
+        tracing::error!("Failed to audit note lookup by {}: {}", supervisor, e);
+    }
+
+    if notes.is_empty() {
+        return vec![format!("No notes for {}", network_id)];
+    }
+    let mut lines = vec![format!("Latest notes for {}:", network_id)];
+    lines.extend(notes.iter().map(|note| {
+        format!(
+            "{} by {}: {}",
+            note.created_at.format("%Y-%m-%d"),
+            note.author_network_id,
+            note.text
+        )
+    }));
+    lines
+}
+
+/// `.mute <callsign> [minutes]` - drop a client's messages to frequencies
+/// and broadcasts for a while, telling it once
+async fn mute_command(
+    args: &[&str],
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    db: &DatabaseConnection,
+    server_callsign: &str,
+    clock: &dyn Clock,
+) -> Vec<String> {
+    let Some(supervisor) = supervisor_network_id(sender_addr, clients, db).await else {
+        return vec!["Permission denied".to_string()];
+    };
+    let minutes = match args {
+        [_] => Some(DEFAULT_MUTE_MINUTES),
+        [_, minutes] => minutes
+            .parse()
+            .ok()
+            .filter(|minutes| (1..=MAX_MUTE_MINUTES).contains(minutes)),
+        _ => None,
+    };
+    let (Some(callsign), Some(minutes)) = (args.first(), minutes) else {
+        return vec![format!(
+            "Usage: .mute <callsign> [minutes], at most {}",
+            MAX_MUTE_MINUTES
+        )];
+    };
+
+    let until = clock.now() + Duration::from_secs(minutes * 60);
+    let (addr, callsign, network_id) = {
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        let Some(mut client) = clients_map.named_mut(callsign) else {
+            return vec![format!("{} is not connected", callsign)];
+        };
+        client.muted_until = Some(until);
+        (
+            client.addr,
+            client.callsign().unwrap_or(callsign).to_string(),
+            client.network_id.clone(),
+        )
+    };
+    tracing::info!("{} muted {} for {} minutes", supervisor, callsign, minutes);
+    send_lines(
+        vec![format!(
+            "A supervisor muted you for {} minutes; your messages to frequencies and broadcasts won't be delivered",
+            minutes
+        )],
+        addr,
+        &callsign,
+        server_callsign,
+        broadcast_tx,
+    );
+    audit(
+        db,
+        &supervisor,
+        "client.mute",
+        network_id.as_deref().unwrap_or(&callsign),
+        &format!("{} for {} minutes", callsign, minutes),
+    )
+    .await;
+    vec![format!("Muted {} for {} minutes", callsign, minutes)]
+}
+
+/// `.unmute <callsign>` - end a mute early
+async fn unmute_command(
+    args: &[&str],
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    db: &DatabaseConnection,
+    server_callsign: &str,
+    clock: &dyn Clock,
+) -> Vec<String> {
+    let Some(supervisor) = supervisor_network_id(sender_addr, clients, db).await else {
+        return vec!["Permission denied".to_string()];
+    };
+    let [callsign] = args else {
+        return vec!["Usage: .unmute <callsign>".to_string()];
+    };
+
+    let (addr, callsign, network_id) = {
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        let Some(mut client) = clients_map.named_mut(callsign) else {
+            return vec![format!("{} is not connected", callsign)];
+        };
+        let muted = client.is_muted(clock.now());
+        client.muted_until = None;
+        let callsign = client.callsign().unwrap_or(callsign).to_string();
+        if !muted {
+            return vec![format!("{} is not muted", callsign)];
+        }
+        (client.addr, callsign, client.network_id.clone())
+    };
+    tracing::info!("{} unmuted {}", supervisor, callsign);
+    send_lines(
+        vec!["You are no longer muted".to_string()],
+        addr,
+        &callsign,
+        server_callsign,
+        broadcast_tx,
+    );
+    audit(
+        db,
+        &supervisor,
+        "client.unmute",
+        network_id.as_deref().unwrap_or(&callsign),
+        &callsign,
+    )
+    .await;
+    vec![format!("Unmuted {}", callsign)]
+}
+
+/// `.elevate <callsign> <rating> [minutes]` - let a controller work above
+/// their rating for a mentoring session, on this connection only
+async fn elevate_command(
+    args: &[&str],
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    db: &DatabaseConnection,
+    server_callsign: &str,
+    clock: &dyn Clock,
+) -> Vec<String> {
+    let Some(mentor) = supervisor_network_id(sender_addr, clients, db).await else {
+        return vec!["Permission denied".to_string()];
+    };
+    let minutes = match args {
+        [_, _] => Some(DEFAULT_ELEVATION_MINUTES),
+        [_, _, minutes] => minutes
+            .parse()
+            .ok()
+            .filter(|minutes| (1..=MAX_ELEVATION_MINUTES).contains(minutes)),
+        _ => None,
+    };
+    let rating = args
+        .get(1)
+        .and_then(|rating| rating.parse::<i32>().ok())
+        .filter(|rating| (2..=MAX_RATING).contains(rating));
+    let (Some(callsign), Some(rating), Some(minutes)) = (args.first(), rating, minutes) else {
+        return vec![format!(
+            "Usage: .elevate <callsign> <rating 2-{}> [minutes], at most {}",
+            MAX_RATING, MAX_ELEVATION_MINUTES
+        )];
+    };
+
+    let until = clock.now() + Duration::from_secs(minutes * 60);
+    let (addr, callsign, network_id) = {
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        let Some(mut client) = clients_map.named_mut(callsign) else {
+            return vec![format!("{} is not connected", callsign)];
+        };
+        let callsign = client.callsign().unwrap_or(callsign).to_string();
+        if !client.is_active() || client.client_type != Some(ClientType::Atc) {
+            return vec![format!("{} is not a controller", callsign)];
+        }
+        // Elevating again replaces the elevation, not the stored rating
+        let stored_rating = match &client.elevation {
+            Some(elevation) => elevation.stored_rating,
+            None => client.rating,
+        };
+        if stored_rating.is_some_and(|stored| stored >= rating) {
+            return vec![format!(
+                "{} is rated {} already",
+                callsign,
+                stored_rating.unwrap_or_default()
+            )];
+        }
+        client.rating = Some(rating);
+        client.elevation = Some(Elevation {
+            stored_rating,
+            until,
+            mentor_addr: sender_addr,
+            mentor: mentor.clone(),
+        });
+        elevation::announce(&client, client.addr, broadcast_tx);
+        (client.addr, callsign, client.network_id.clone())
+    };
+    tracing::info!(
+        "{} raised {} to rating {} for {} minutes",
+        mentor,
+        callsign,
+        rating,
+        minutes
+    );
+    send_lines(
+        vec![format!(
+            "Your mentor raised your rating to {} for {} minutes",
+            rating, minutes
+        )],
+        addr,
+        &callsign,
+        server_callsign,
+        broadcast_tx,
+    );
+    audit(
+        db,
+        &mentor,
+        "client.elevate",
+        network_id.as_deref().unwrap_or(&callsign),
+        &format!("{} to rating {} for {} minutes", callsign, rating, minutes),
+    )
+    .await;
+    vec![format!(
+        "Raised {} to rating {} for {} minutes",
+        callsign, rating, minutes
+    )]
+}
+
+/// Record a supervisor's action, logging rather than failing if that can't
+/// be done
+async fn audit(
+    db: &DatabaseConnection,
+    supervisor: &str,
+    action: &str,
+    target: &str,
+    details: &str,
+) {
+    if let Err(e) =
+        service::record_audit_event(db, supervisor, action, Some(target), Some(details)).await
+    {
+        tracing::error!("Failed to audit {} by {}: {}", action, supervisor, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientState};
+    use std::time::Instant;
+
+    const ADDR: &str = "127.0.0.1:50001";
+    const PILOT_ADDR: &str = "127.0.0.1:50002";
+
+    /// A client logged in as `network_id`, with an account of `atc_rating`,
+    /// and the pilot CCA1501 with notes on its account
+    async fn setup(
+        network_id: &str,
+        atc_rating: i32,
+    ) -> (Arc<DatabaseConnection>, Arc<RwLock<ClientRegistry>>) {
+        let db = Arc::new(crate::db::init_ephemeral().await.unwrap());
+        for (cid, rating) in [(network_id, atc_rating), ("1234567", 1)] {
+            service::create_user(
+                &db,
+                cid.to_string(),
+                "hash".to_string(),
+                "Test User".to_string(),
+                rating,
+                1,
+            )
+            .await
+            .unwrap();
+        }
+        service::add_user_note(&db, "1234567", "1000000", "Warned for phraseology")
+            .await
+            .unwrap();
+
+        let mut client = Client::new(ADDR.parse().unwrap());
+        client.state = ClientState::Active;
+        client.network_id = Some(network_id.to_string());
+        let mut pilot = Client::new(PILOT_ADDR.parse().unwrap());
+        pilot.state = ClientState::Active;
+        pilot.callsign = Some("CCA1501".into());
+        pilot.network_id = Some("1234567".to_string());
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([
+            (client.addr, client),
+            (pilot.addr, pilot),
+        ])));
+        (db, clients)
+    }
+
+    /// Send `command` from ZSPD_SUP and return the replies and
+    /// announcements, with the address each went to or came from
+    async fn send(
+        command: &str,
+        db: &Arc<DatabaseConnection>,
+        clients: &Arc<RwLock<ClientRegistry>>,
+    ) -> Vec<(SocketAddr, String)> {
+        let state = ServerState {
+            clients: clients.clone(),
+            ..ServerState::for_tests(db.clone())
+        };
+        let mut rx = state.broadcast_tx.subscribe();
+        let packet = Packet::parse(&format!("#TMZSPD_SUP:SERVER:{}", command)).unwrap();
+        assert!(is_server_command(&packet));
+        handle_server_command(packet, ADDR.parse().unwrap(), &state).await;
+
+        let mut replies = Vec::new();
+        while let Ok((addr, ServerMessage::Direct(reply) | ServerMessage::Packet(reply))) =
+            rx.try_recv()
+        {
+            replies.push((addr, reply.data.join(":")));
+        }
+        replies
+    }
+
+    async fn run(network_id: &str, atc_rating: i32) -> Vec<String> {
+        let (db, clients) = setup(network_id, atc_rating).await;
+        send(".notes 1234567", &db, &clients)
+            .await
+            .into_iter()
+            .map(|(addr, reply)| {
+                assert_eq!(addr, ADDR.parse().unwrap());
+                reply
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_notes_for_supervisor() {
+        let replies = run("1000001", crate::db::entities::user::SUPERVISOR_RATING).await;
+        assert_eq!(replies.len(), 2);
+        assert!(replies[1].ends_with("by 1000000: Warned for phraseology"));
+    }
+
+    #[tokio::test]
+    async fn test_notes_denied_to_controller() {
+        let replies = run("1000001", 5).await;
+        assert_eq!(replies, vec!["Permission denied".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_mute_and_unmute() {
+        let supervisor = crate::db::entities::user::SUPERVISOR_RATING;
+        let (db, clients) = setup("1000001", supervisor).await;
+        let (addr, pilot_addr) = (ADDR.parse().unwrap(), PILOT_ADDR.parse().unwrap());
+        let muted_until = |clients: &ClientRegistry| clients.get(&pilot_addr).unwrap().muted_until;
+
+        let replies = send(".mute cca1501 15", &db, &clients).await;
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].0, pilot_addr);
+        assert!(replies[0]
+            .1
+            .starts_with("A supervisor muted you for 15 minutes"));
+        assert_eq!(
+            replies[1],
+            (addr, "Muted CCA1501 for 15 minutes".to_string())
+        );
+        let until = muted_until(&*clients.read().await).unwrap();
+        let left = until - Instant::now();
+        assert!(left > Duration::from_secs(14 * 60) && left <= Duration::from_secs(15 * 60));
+
+        for (command, reply) in [
+            (
+                ".mute CCA1501 0",
+                "Usage: .mute <callsign> [minutes], at most 1440",
+            ),
+            (
+                ".mute CCA1501 1441",
+                "Usage: .mute <callsign> [minutes], at most 1440",
+            ),
+            (".mute CES2101", "CES2101 is not connected"),
+        ] {
+            assert_eq!(
+                send(command, &db, &clients).await,
+                [(addr, reply.to_string())]
+            );
+        }
+
+        let replies = send(".unmute CCA1501", &db, &clients).await;
+        assert_eq!(
+            replies,
+            [
+                (pilot_addr, "You are no longer muted".to_string()),
+                (addr, "Unmuted CCA1501".to_string()),
+            ]
+        );
+        assert_eq!(muted_until(&*clients.read().await), None);
+        assert_eq!(
+            send(".unmute CCA1501", &db, &clients).await,
+            [(addr, "CCA1501 is not muted".to_string())]
+        );
+
+        let events = service::list_audit_events(&db, "1234567").await.unwrap();
+        let actions: Vec<(&str, &str, Option<&str>)> = events
+            .iter()
+            .map(|event| {
+                (
+                    event.actor.as_str(),
+                    event.action.as_str(),
+                    event.details.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                ("1000001", "client.unmute", Some("CCA1501")),
+                ("1000001", "client.mute", Some("CCA1501 for 15 minutes")),
+                ("1000000", "note.add", Some("Warned for phraseology")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mute_denied_to_controller() {
+        let (db, clients) = setup("1000001", 5).await;
+        assert_eq!(
+            send(".mute CCA1501", &db, &clients).await,
+            [(ADDR.parse().unwrap(), "Permission denied".to_string())]
+        );
+        let clients = clients.read().await;
+        assert_eq!(
+            clients
+                .get(&PILOT_ADDR.parse().unwrap())
+                .unwrap()
+                .muted_until,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_elevate() {
+        let supervisor = crate::db::entities::user::SUPERVISOR_RATING;
+        let (db, clients) = setup("1000001", supervisor).await;
+        let (addr, student_addr) = (ADDR.parse().unwrap(), "127.0.0.1:50003".parse().unwrap());
+        let mut student = Client::new(student_addr);
+        student.state = ClientState::Active;
+        student.client_type = Some(ClientType::Atc);
+        student.callsign = Some("ZSPD_APP".into());
+        student.real_name = Some("Student".to_string());
+        student.network_id = Some("1000002".to_string());
+        student.rating = Some(2);
+        clients.write().await.insert(student_addr, student);
+
+        let replies = send(".elevate zspd_app 4 90", &db, &clients).await;
+        assert_eq!(
+            replies,
+            [
+                (student_addr, "Student:1000002::4:".to_string()),
+                (
+                    student_addr,
+                    "Your mentor raised your rating to 4 for 90 minutes".to_string()
+                ),
+                (
+                    addr,
+                    "Raised ZSPD_APP to rating 4 for 90 minutes".to_string()
+                ),
+            ]
+        );
+        {
+            let clients = clients.read().await;
+            let student = clients.get(&student_addr).unwrap();
+            assert_eq!(student.rating, Some(4));
+            let elevation = student.elevation.as_ref().unwrap();
+            assert_eq!(elevation.stored_rating, Some(2));
+            assert_eq!(elevation.mentor_addr, addr);
+            assert_eq!(student.effective_rating(elevation.until), Some(2));
+        }
+
+        for (command, reply) in [
+            (
+                ".elevate ZSPD_APP 13",
+                "Usage: .elevate <callsign> <rating 2-12> [minutes], at most 480",
+            ),
+            (
+                ".elevate ZSPD_APP 4 481",
+                "Usage: .elevate <callsign> <rating 2-12> [minutes], at most 480",
+            ),
+            (".elevate ZSPD_APP 2", "ZSPD_APP is rated 2 already"),
+            (".elevate CCA1501 4", "CCA1501 is not a controller"),
+            (".elevate ZSPD_TWR 4", "ZSPD_TWR is not connected"),
+        ] {
+            assert_eq!(
+                send(command, &db, &clients).await,
+                [(addr, reply.to_string())]
+            );
+        }
+
+        let events = service::list_audit_events(&db, "1000002").await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].actor, "1000001");
+        assert_eq!(events[0].action, "client.elevate");
+        assert_eq!(
+            events[0].details.as_deref(),
+            Some("ZSPD_APP to rating 4 for 90 minutes")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_elevate_denied_to_controller() {
+        let (db, clients) = setup("1000001", 5).await;
+        assert_eq!(
+            send(".elevate CCA1501 4", &db, &clients).await,
+            [(ADDR.parse().unwrap(), "Permission denied".to_string())]
+        );
+    }
+}
@@ -1,25 +1,91 @@
+use crate::client::{Client, ClientType};
+use crate::config::LimitsConfig;
+use crate::db::entities::prefiled_flight_plan;
+use crate::db::service;
+use crate::flight_plan::{FlightPlan, FlightPlanError};
 use crate::packet::Packet;
 use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::registry::ClientRegistry;
+use crate::server::state::ServerState;
+use crate::webhooks::Event;
+use sea_orm::DatabaseConnection;
 use std::net::SocketAddr;
-use tokio::sync::broadcast;
+use std::sync::Arc;
+use tokio::sync::{broadcast, RwLock};
+
+/// Controllers further than this from a pilot don't receive its flight plan
+const FLIGHT_PLAN_RANGE_NM: f64 = 300.0;
 
 /// Handle flight plan
-pub async fn handle_flight_plan(
-    packet: Packet,
-    sender_addr: SocketAddr,
-    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
-) {
-    log::info!("Flight plan from {}", packet.source);
+pub async fn handle_flight_plan(packet: Packet, sender_addr: SocketAddr, state: &ServerState) {
+    let ServerState {
+        clients,
+        broadcast_tx,
+        stats,
+        webhooks,
+        ..
+    } = state;
+    let limits = &state.config.limits;
+    let server_callsign = state.config.server_callsign.as_str();
+    tracing::info!("Flight plan from {}", packet.source);
 
-    // Broadcast flight plan to all clients
-    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(packet.clone())));
+    let plan = FlightPlan::from_packet(&packet);
 
-    // Send flight plan acknowledgment (VATSIM protocol)
+    // Refuse plans that can't be relayed or stored as they are
+    let refusal = match &plan {
+        Some(plan) => match check_plan(plan, limits) {
+            Err(reason) => Some(reason.to_string()),
+            Ok(()) if over_memory_cap(clients.read().await.get(&sender_addr), plan, limits) => {
+                Some(format!(
+                    "more than {} bytes stored for this client",
+                    limits.max_client_bytes
+                ))
+            }
+            Ok(()) => None,
+        },
+        None => None,
+    };
+    if let Some(reason) = refusal {
+        tracing::warn!("Invalid flight plan from {}: {}", packet.source, reason);
+        // $ER(server):(callsign):004:(callsign):(reason)
+        let error_packet = Packet {
+            packet_type: crate::packet::PacketType::Request,
+            command: "ER".to_string(),
+            source: server_callsign.to_string(),
+            destination: packet.source.clone(),
+            data: vec![
+                "004".to_string(),
+                packet.source.clone(),
+                format!("Invalid flight plan: {}", reason),
+            ],
+        };
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(error_packet))));
+        return;
+    }
+
+    // Keep the latest plan for the connection
+    match plan {
+        Some(plan) => {
+            stats.record_flight_plan();
+            webhooks.emit(&Event::flight_plan_filed(&packet.source, &plan));
+            let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+            if let Some(mut client) = clients_map.get_mut(&sender_addr) {
+                client.flight_plan = Some(plan);
+                client.flight_plan_unsent = false;
+            }
+            drop(clients_map);
+        }
+        None => tracing::warn!("Malformed flight plan from {}", packet.source),
+    }
+
+    // Send flight plan acknowledgment (VATSIM protocol), once the plan has
+    // gone out to all clients
     // #PC(server):(callsign):CCP:BC:(flightplan callsign):0
     let ack_packet = Packet {
         packet_type: crate::packet::PacketType::Client,
         command: "PC".to_string(),
-        source: "server".to_string(),
+        source: server_callsign.to_string(),
         destination: packet.source.clone(),
         data: vec![
             "CCP".to_string(),
@@ -28,5 +94,425 @@ pub async fn handle_flight_plan(
             "0".to_string(),
         ],
     };
-    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(ack_packet)));
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(packet))));
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(ack_packet))));
+}
+
+/// Handle flight plan amendment from a controller
+///
+/// $AM(controller):SERVER:(callsign):(rules):(aircraft):...:(route), with the
+/// plan fields in $FP order
+pub async fn handle_amendment(
+    packet: Packet,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    limits: &LimitsConfig,
+) {
+    let Some((callsign, fields)) = packet.data.split_first() else {
+        tracing::warn!("Malformed amendment from {}", packet.source);
+        return;
+    };
+    let Some(plan) = FlightPlan::from_fields(fields) else {
+        tracing::warn!(
+            "Malformed amendment from {} for {}",
+            packet.source,
+            callsign
+        );
+        return;
+    };
+    if let Err(reason) = plan.check_lengths(limits.max_field_bytes) {
+        tracing::warn!(
+            "Ignoring amendment from {} for {}: {}",
+            packet.source,
+            callsign,
+            reason
+        );
+        return;
+    }
+
+    {
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        let is_controller = clients_map
+            .get(&sender_addr)
+            .is_some_and(|client| client.client_type == Some(ClientType::Atc));
+        if !is_controller {
+            tracing::warn!("Ignoring amendment from non-controller {}", packet.source);
+            return;
+        }
+        let Some(mut pilot) = clients_map.named_mut(callsign) else {
+            tracing::warn!("Amendment from {} for unknown {}", packet.source, callsign);
+            return;
+        };
+        if over_memory_cap(Some(&*pilot), &plan, limits) {
+            tracing::warn!(
+                "Ignoring amendment from {} for {}: over {} bytes stored",
+                packet.source,
+                callsign,
+                limits.max_client_bytes
+            );
+            return;
+        }
+        pilot.flight_plan = Some(plan);
+    }
+
+    tracing::info!("{} amended the flight plan of {}", packet.source, callsign);
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(packet))));
+}
+
+/// Check a plan a client sent, including the length of each field
+fn check_plan(plan: &FlightPlan, limits: &LimitsConfig) -> Result<(), FlightPlanError> {
+    plan.validate()?;
+    plan.check_lengths(limits.max_field_bytes)
+}
+
+/// Whether storing `plan` in place of the client's current one would take
+/// it over its memory cap
+fn over_memory_cap(client: Option<&Client>, plan: &FlightPlan, limits: &LimitsConfig) -> bool {
+    let Some(client) = client else {
+        return false;
+    };
+    let current = client.flight_plan.as_ref().map_or(0, FlightPlan::text_len);
+    client.memory_estimate() - current + plan.text_len() > limits.max_client_bytes
+}
+
+/// The prefile a pilot can activate at login
+pub async fn find_prefile(
+    network_id: &str,
+    callsign: &str,
+    db: &DatabaseConnection,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<prefiled_flight_plan::Model> {
+    match service::find_prefiled_flight_plan(db, network_id, callsign, now).await {
+        Ok(prefile) => prefile,
+        Err(e) => {
+            tracing::error!(
+                "Failed to look up prefiled flight plan for {}: {}",
+                callsign,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Activate a pilot's prefiled flight plan at login
+///
+/// The plan becomes the connection's live plan and is sent to the pilot.
+/// Controllers in range receive it with the pilot's first position, as
+/// until then there is nothing to measure their range from. Only for
+/// prefiles the login has claimed, see [`service::record_login`].
+pub async fn deliver_prefiled_flight_plan(
+    prefile: &prefiled_flight_plan::Model,
+    callsign: &str,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+) {
+    tracing::info!(
+        "Activating prefiled flight plan for {} ({})",
+        callsign,
+        prefile.network_id
+    );
+    let plan = prefile.flight_plan();
+    let fp_packet = Arc::new(plan.to_packet(callsign));
+
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    if let Some(mut client) = clients_map.get_mut(&sender_addr) {
+        client.flight_plan = Some(plan);
+        client.flight_plan_unsent = true;
+    }
+    drop(clients_map);
+
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(fp_packet)));
+}
+
+/// Send a pilot's flight plan to the active controllers within
+/// [`FLIGHT_PLAN_RANGE_NM`] of its position
+pub async fn send_to_controllers_in_range(
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+) {
+    let clients_map = clients.read().await;
+    let Some(pilot) = clients_map.get(&sender_addr) else {
+        return;
+    };
+    let (Some(callsign), Some(plan)) = (pilot.callsign(), &pilot.flight_plan) else {
+        return;
+    };
+    let fp_packet = Arc::new(plan.to_packet(callsign));
+    for (addr, _) in clients_map
+        .of_type(ClientType::Atc)
+        .filter(|(addr, client)| {
+            **addr != sender_addr
+                && client.is_active()
+                && pilot
+                    .distance_nm(client)
+                    .is_some_and(|distance| distance <= FLIGHT_PLAN_RANGE_NM)
+        })
+    {
+        let _ = broadcast_tx.send((*addr, ServerMessage::Direct(fp_packet.clone())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::callsign::Callsign;
+    use crate::client::ClientState;
+    use crate::packet::PositionUpdate;
+    use crate::server::handlers::handle_login;
+    use crate::server::handlers::position::handle_position_update;
+    use std::collections::HashMap;
+
+    const PILOT_ADDR: &str = "127.0.0.1:50001";
+    const ATC_ADDR: &str = "127.0.0.1:50002";
+
+    struct TestServer {
+        clients: Arc<RwLock<ClientRegistry>>,
+        callsign_map: Arc<RwLock<HashMap<Callsign, SocketAddr>>>,
+        broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+        db: Arc<DatabaseConnection>,
+    }
+
+    impl TestServer {
+        /// What the handlers get, around this server's clients and database
+        fn state(&self) -> ServerState {
+            ServerState {
+                clients: self.clients.clone(),
+                callsign_map: self.callsign_map.clone(),
+                broadcast_tx: self.broadcast_tx.clone(),
+                ..ServerState::for_tests(self.db.clone())
+            }
+        }
+    }
+
+    async fn setup() -> TestServer {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let password_hash = crate::auth::password::hash_password("secret").unwrap();
+        service::create_user(
+            &db,
+            "1234567".to_string(),
+            password_hash,
+            "Test Pilot".to_string(),
+            1,
+            1,
+        )
+        .await
+        .unwrap();
+
+        let mut clients = ClientRegistry::new();
+        let mut pilot = Client::new(PILOT_ADDR.parse().unwrap());
+        pilot.state = ClientState::Identified;
+        clients.insert(pilot.addr, pilot);
+
+        let mut atc = Client::new(ATC_ADDR.parse().unwrap());
+        atc.state = ClientState::Active;
+        atc.callsign = Some("ZSPD_APP".into());
+        atc.client_type = Some(ClientType::Atc);
+        // Shanghai, with Beijing out of range
+        atc.latitude = Some(31.14);
+        atc.longitude = Some(121.81);
+        clients.insert(atc.addr, atc);
+
+        let (broadcast_tx, _) = broadcast::channel(100);
+        TestServer {
+            clients: Arc::new(RwLock::new(clients)),
+            callsign_map: Arc::new(RwLock::new(HashMap::new())),
+            broadcast_tx,
+            db: Arc::new(db),
+        }
+    }
+
+    async fn prefile(server: &TestServer, expires_at: chrono::DateTime<chrono::Utc>) {
+        let plan = FlightPlan {
+            flight_rules: "I".to_string(),
+            aircraft: "H/B744/L".to_string(),
+            departure: "ZBAA".to_string(),
+            destination: "ZSPD".to_string(),
+            route: "ELKUR W40 YQG".to_string(),
+            ..FlightPlan::default()
+        };
+        service::upsert_prefiled_flight_plan(&server.db, "1234567", "CCA1501", &plan, expires_at)
+            .await
+            .unwrap();
+    }
+
+    /// Log the pilot in and collect the direct messages each address received
+    async fn connect_pilot(server: &TestServer) -> Vec<(SocketAddr, Packet)> {
+        let mut rx = server.broadcast_tx.subscribe();
+        let login =
+            Packet::parse("#APCCA1501:SERVER:1234567:secret:1:101:1:Test Pilot ZBAA").unwrap();
+        handle_login(login, PILOT_ADDR.parse().unwrap(), &server.state()).await;
+
+        let mut direct = Vec::new();
+        while let Ok((addr, msg)) = rx.try_recv() {
+            if let ServerMessage::Direct(packet) = msg {
+                direct.push((addr, Packet::clone(&packet)));
+            }
+        }
+        direct
+    }
+
+    fn flight_plans_to(messages: &[(SocketAddr, Packet)], addr: &str) -> Vec<Packet> {
+        let addr: SocketAddr = addr.parse().unwrap();
+        messages
+            .iter()
+            .filter(|(to, packet)| *to == addr && packet.command == "FP")
+            .map(|(_, packet)| packet.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_prefile_delivered_at_login() {
+        let server = setup().await;
+        prefile(&server, chrono::Utc::now() + chrono::Duration::hours(2)).await;
+
+        let messages = connect_pilot(&server).await;
+
+        let to_pilot = flight_plans_to(&messages, PILOT_ADDR);
+        assert_eq!(to_pilot.len(), 1);
+        assert_eq!(to_pilot[0].source, "CCA1501");
+        assert_eq!(to_pilot[0].data[3], "ZBAA");
+        // Nothing to tell whether the controller is in range yet
+        assert!(flight_plans_to(&messages, ATC_ADDR).is_empty());
+        assert!(!messages.iter().any(|(_, p)| p.command == "ER"));
+
+        let clients_map = server.clients.read().await;
+        let pilot = &clients_map[&PILOT_ADDR.parse().unwrap()];
+        assert_eq!(pilot.flight_plan.as_ref().unwrap().destination, "ZSPD");
+        drop(clients_map);
+
+        // The prefile is used up by the time the login is handled
+        let now = chrono::Utc::now();
+        assert!(
+            service::find_prefiled_flight_plan(&server.db, "1234567", "CCA1501", now)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    /// Send a position update from the pilot and collect the flight plans
+    /// the controller received
+    async fn pilot_position(server: &TestServer, line: &str) -> Vec<Packet> {
+        let mut rx = server.broadcast_tx.subscribe();
+        handle_position_update(
+            PositionUpdate::parse(line).unwrap(),
+            PILOT_ADDR.parse().unwrap(),
+            &server.state(),
+        )
+        .await;
+        let messages: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|(addr, msg)| match msg {
+                ServerMessage::Direct(packet) => Some((addr, Packet::clone(&packet))),
+                _ => None,
+            })
+            .collect();
+        flight_plans_to(&messages, ATC_ADDR)
+    }
+
+    #[tokio::test]
+    async fn test_prefile_sent_to_controllers_in_range_of_first_position() {
+        let server = setup().await;
+        prefile(&server, chrono::Utc::now() + chrono::Duration::hours(2)).await;
+        connect_pilot(&server).await;
+
+        // Still at Beijing: out of the controller's range, and not sent
+        // again later
+        let at_beijing = "@NCCA1501:1200:1:40.08:116.58:150:0:0:0";
+        assert!(pilot_position(&server, at_beijing).await.is_empty());
+        assert!(pilot_position(&server, at_beijing).await.is_empty());
+
+        let server = setup().await;
+        prefile(&server, chrono::Utc::now() + chrono::Duration::hours(2)).await;
+        connect_pilot(&server).await;
+
+        // Near Shanghai: sent with the first position only
+        let near_shanghai = "@NCCA1501:1200:1:31.5:121.3:9000:250:0:0";
+        let sent = pilot_position(&server, near_shanghai).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].source, "CCA1501");
+        assert_eq!(sent[0].data[7], "ZSPD");
+        assert!(pilot_position(&server, near_shanghai).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expired_prefile_ignored_at_login() {
+        let server = setup().await;
+        prefile(&server, chrono::Utc::now() - chrono::Duration::minutes(1)).await;
+
+        let messages = connect_pilot(&server).await;
+
+        assert!(flight_plans_to(&messages, PILOT_ADDR).is_empty());
+        assert!(flight_plans_to(&messages, ATC_ADDR).is_empty());
+        assert!(messages
+            .iter()
+            .any(|(_, p)| p.command == "ER" && p.data.first().map(String::as_str) == Some("008")));
+    }
+
+    #[tokio::test]
+    async fn test_amendment_replaces_plan() {
+        let server = setup().await;
+        connect_pilot(&server).await;
+        let mut rx = server.broadcast_tx.subscribe();
+        let amendment = Packet::parse(
+            "$AMZSPD_APP:SERVER:CCA1501:I:B738:450:ZBAA:1200:0:FL290:ZSSS:2:10:3:30:ZSHC::DCT",
+        )
+        .unwrap();
+
+        // Pilots can't amend plans
+        handle_amendment(
+            amendment.clone(),
+            PILOT_ADDR.parse().unwrap(),
+            &server.clients,
+            &server.broadcast_tx,
+            &LimitsConfig::default(),
+        )
+        .await;
+        assert!(rx.try_recv().is_err());
+
+        handle_amendment(
+            amendment,
+            ATC_ADDR.parse().unwrap(),
+            &server.clients,
+            &server.broadcast_tx,
+            &LimitsConfig::default(),
+        )
+        .await;
+        let plan = server.clients.read().await[&PILOT_ADDR.parse().unwrap()]
+            .flight_plan
+            .clone()
+            .unwrap();
+        assert_eq!(plan.altitude, "FL290");
+        assert_eq!(plan.destination, "ZSSS");
+        assert!(matches!(rx.try_recv(), Ok((_, ServerMessage::Packet(p))) if p.command == "AM"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_plan_refused() {
+        let server = setup().await;
+        connect_pilot(&server).await;
+        let mut rx = server.broadcast_tx.subscribe();
+        let plan =
+            Packet::parse("$FPCCA1501:*A:X:B738:450:ZBAA:1200:0:FL290:ZSSS:2:10:3:30:ZSHC::DCT")
+                .unwrap();
+
+        handle_flight_plan(plan, PILOT_ADDR.parse().unwrap(), &server.state()).await;
+
+        assert!(server.clients.read().await[&PILOT_ADDR.parse().unwrap()]
+            .flight_plan
+            .is_none());
+        match rx.try_recv() {
+            Ok((_, ServerMessage::Direct(p))) => {
+                assert_eq!(p.command, "ER");
+                assert_eq!(p.data[0], "004");
+                assert!(p.data[2].contains("flight rules"), "{}", p.data[2]);
+            }
+            other => panic!("expected an error, got {:?}", other),
+        }
+        // Nothing is relayed to other clients
+        assert!(rx.try_recv().is_err());
+    }
 }
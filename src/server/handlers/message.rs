@@ -1,52 +1,71 @@
 use crate::packet::Packet;
 use crate::server::config::ServerMessage;
+use crate::server::follow;
+use crate::server::handlers::command::send_lines;
+use crate::server::state::ServerState;
+use crate::webhooks::Event;
 use std::net::SocketAddr;
-use tokio::sync::broadcast;
-
-/// Process message content for IVAO escaping (:: -> :)
-/// IVAO uses :: as escape sequence for colons in message content
-fn process_message_content(content: &str) -> String {
-    content.replace("::", ":")
-}
+use std::sync::Arc;
 
 /// Handle text message
-pub async fn handle_text_message(
-    packet: Packet,
-    sender_addr: SocketAddr,
-    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
-) {
-    log::info!(
-        "Text message from {} to {}: {:?}",
+///
+/// Messages from a muted client to a frequency or a broadcast are dropped;
+/// private messages, including `.wallop` calls to supervisors, still pass.
+/// Observers following a pilot get a copy of what is said on its frequency.
+pub async fn handle_text_message(packet: Packet, sender_addr: SocketAddr, state: &ServerState) {
+    let ServerState {
+        clients,
+        broadcast_tx,
+        stats,
+        follows,
+        ..
+    } = state;
+    let dialect = state.config.dialect;
+    let server_callsign = state.config.server_callsign.as_str();
+    let clock = &*state.clock;
+    if is_public(&packet.destination) {
+        let clients_map = clients.read().await;
+        let muted = clients_map
+            .get(&sender_addr)
+            .filter(|client| client.is_muted(clock.now()));
+        if let Some(client) = muted {
+            client.counters.dropped();
+            tracing::info!(
+                "Dropped message from muted {} to {}",
+                packet.source,
+                packet.destination
+            );
+            return;
+        }
+    }
+
+    tracing::info!(
+        "Text message from {} to {}: {}",
         packet.source,
         packet.destination,
-        packet.data
+        dialect.message_text(&packet.data)
     );
 
-    // Process message content for IVAO escaping
-    let mut processed_packet = packet.clone();
-    if !processed_packet.data.is_empty() {
-        processed_packet.data = processed_packet.data
-            .iter()
-            .map(|content| process_message_content(content))
-            .collect();
-    }
-
     // Check for flight plan acknowledgment (VATSIM protocol)
     // Format: #TM(own callsign):FP:(flightplan callsign) GET
-    if processed_packet.data.get(0) == Some(&"FP".to_string()) &&
-       processed_packet.data.get(1).is_some() &&
-       processed_packet.data.get(2) == Some(&"GET".to_string()) {
-
-        let flightplan_callsign = &processed_packet.data[1];
-        log::info!("Flight plan acknowledgment from {} for {}", processed_packet.source, flightplan_callsign);
+    if packet.data.get(0) == Some(&"FP".to_string())
+        && packet.data.get(1).is_some()
+        && packet.data.get(2) == Some(&"GET".to_string())
+    {
+        let flightplan_callsign = &packet.data[1];
+        tracing::info!(
+            "Flight plan acknowledgment from {} for {}",
+            packet.source,
+            flightplan_callsign
+        );
 
         // Send server acknowledgment
-        // #PCserver:(own callsign):CCP:BC:(flightplan callsign):0
+        // #PC(server):(own callsign):CCP:BC:(flightplan callsign):0
         let ack_packet = Packet {
             packet_type: crate::packet::PacketType::Client,
             command: "PC".to_string(),
-            source: "server".to_string(),
-            destination: processed_packet.source.clone(),
+            source: server_callsign.to_string(),
+            destination: packet.source.clone(),
             data: vec![
                 "CCP".to_string(),
                 "BC".to_string(),
@@ -54,10 +73,106 @@ pub async fn handle_text_message(
                 "0".to_string(),
             ],
         };
-        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(ack_packet)));
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(ack_packet))));
         return;
     }
 
-    // Broadcast message to all clients
-    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(processed_packet)));
+    // .wallop: a call for any supervisor online
+    if packet.destination == "*S" {
+        let message = dialect.message_text(&packet.data);
+        state
+            .webhooks
+            .emit(&Event::supervisor_called(&packet.source, &message));
+    }
+
+    if packet.destination.starts_with('@') {
+        let text = follow::overheard(
+            &packet.source,
+            &packet.destination,
+            &dialect.message_text(&packet.data),
+        );
+        for (addr, callsign) in follows.overhearing(sender_addr, &packet.destination) {
+            send_lines(
+                vec![text.clone()],
+                addr,
+                &callsign,
+                server_callsign,
+                broadcast_tx,
+            );
+        }
+    }
+
+    // Broadcast message to all clients as sent; they undo the escaping
+    stats.record_message();
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(packet))));
+}
+
+/// Whether a message goes to a frequency (`@24550`) or a broadcast (`*`,
+/// `*A`, ...) rather than to a callsign or the supervisors (`*S`)
+fn is_public(destination: &str) -> bool {
+    destination.starts_with('@') || (destination.starts_with('*') && destination != "*S")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientState};
+    use crate::server::clock::{Clock, SystemClock};
+    use crate::server::registry::ClientRegistry;
+    use std::time::Duration;
+    use tokio::sync::RwLock;
+
+    const ADDR: &str = "127.0.0.1:50001";
+
+    /// Send each line from a client muted for a minute and return the
+    /// destinations of the messages that went out
+    async fn send(lines: &[&str]) -> Vec<String> {
+        let mut client = Client::new(ADDR.parse().unwrap());
+        client.state = ClientState::Active;
+        client.callsign = Some("CCA1501".into());
+        client.muted_until = Some(SystemClock.now() + Duration::from_secs(60));
+        // Messages never reach the database, which could not be opened on
+        // the paused clock anyway
+        let state = ServerState {
+            clients: Arc::new(RwLock::new(ClientRegistry::from([(client.addr, client)]))),
+            ..ServerState::for_tests(Arc::new(sea_orm::DatabaseConnection::Disconnected))
+        };
+        let mut rx = state.broadcast_tx.subscribe();
+        let mut sent = Vec::new();
+        for line in lines {
+            if *line == "wait" {
+                tokio::time::advance(Duration::from_secs(60)).await;
+                continue;
+            }
+            handle_text_message(Packet::parse(line).unwrap(), ADDR.parse().unwrap(), &state).await;
+            while let Ok((_, ServerMessage::Packet(packet))) = rx.try_recv() {
+                sent.push(packet.destination.clone());
+            }
+        }
+        sent
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_muted_client_reaches_only_private_destinations() {
+        let sent = send(&[
+            "#TMCCA1501:@24550:hello tower",
+            "#TMCCA1501:*:hello everyone",
+            "#TMCCA1501:*A:hello controllers",
+            "#TMCCA1501:ZSPD_TWR:hello in private",
+            "#TMCCA1501:*S:help, I've been muted",
+        ])
+        .await;
+        assert_eq!(sent, ["ZSPD_TWR", "*S"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mute_expires() {
+        let sent = send(&[
+            "#TMCCA1501:@24550:still muted",
+            "wait",
+            "#TMCCA1501:@24550:muted no more",
+        ])
+        .await;
+        assert_eq!(sent, ["@24550"]);
+    }
 }
@@ -0,0 +1,256 @@
+//! Subscriptions to a station's METAR
+//!
+//! Instead of asking again and again, a client can subscribe with
+//! `$AX(callsign):SERVER:SUBSCRIBE:(ICAO)` or `.subscribe-metar ICAO`, and
+//! unsubscribe with `UNSUBSCRIBE` or `.unsubscribe-metar ICAO`. It is sent
+//! the current METAR as an `$AR` right away, and every new one after that as
+//! the server finds it (see `metar_updates`), until it unsubscribes or
+//! disconnects.
+
+use crate::packet::{Packet, PacketType};
+use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::handlers::command::send_lines;
+use crate::server::registry::ClientRegistry;
+use crate::weather::{self, WeatherService};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, RwLock};
+
+/// `$AX` subtype that subscribes to a station
+pub const SUBSCRIBE: &str = "SUBSCRIBE";
+
+/// `$AX` subtype that ends a subscription
+pub const UNSUBSCRIBE: &str = "UNSUBSCRIBE";
+
+/// Whether an `$AX` subscribes or unsubscribes rather than asks once
+pub fn is_subscription(packet: &Packet) -> bool {
+    packet.data.first().is_some_and(|kind| {
+        kind.eq_ignore_ascii_case(SUBSCRIBE) || kind.eq_ignore_ascii_case(UNSUBSCRIBE)
+    })
+}
+
+/// Handle `$AX(callsign):SERVER:SUBSCRIBE:(ICAO)` and `UNSUBSCRIBE`,
+/// answering with a `#TM`
+pub async fn handle_subscription(
+    packet: Packet,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    weather: &WeatherService,
+    server_callsign: &str,
+) {
+    let [kind, station, ..] = packet.data.as_slice() else {
+        tracing::warn!("Invalid METAR subscription from {}", sender_addr);
+        return;
+    };
+    let reply = if kind.eq_ignore_ascii_case(SUBSCRIBE) {
+        subscribe(
+            station,
+            &packet.source,
+            sender_addr,
+            clients,
+            broadcast_tx,
+            weather,
+            server_callsign,
+        )
+        .await
+    } else {
+        unsubscribe(station, sender_addr, clients).await
+    };
+    send_lines(
+        vec![reply],
+        sender_addr,
+        &packet.source,
+        server_callsign,
+        broadcast_tx,
+    );
+}
+
+/// Subscribe the client at `sender_addr` to `station` and send it the
+/// current METAR, returning the reply to show it
+pub async fn subscribe(
+    station: &str,
+    callsign: &str,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    weather: &WeatherService,
+    server_callsign: &str,
+) -> String {
+    let station = match weather::normalize_station(station) {
+        Ok(station) => station,
+        Err(e) => return e.to_string(),
+    };
+    {
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        let Some(mut client) = clients_map.get_mut(&sender_addr) else {
+            return String::new();
+        };
+        let subscriptions = &mut client.metar_subscriptions;
+        if subscriptions.contains_key(&station) {
+            return format!("Already subscribed to {}", station);
+        }
+        if subscriptions.len() >= weather.max_subscriptions() {
+            return format!(
+                "Subscribed to {} stations already; unsubscribe from one first",
+                subscriptions.len()
+            );
+        }
+        subscriptions.insert(station.clone(), None);
+    }
+    tracing::info!("{} subscribed to the METAR of {}", callsign, station);
+
+    // Without a METAR now, the first one found is pushed later
+    let metar = match weather.metar(&station).await {
+        Ok(metar) => metar,
+        Err(e) => {
+            tracing::warn!("No METAR for {}: {}", station, e);
+            return format!("Subscribed to {}; no METAR is available yet", station);
+        }
+    };
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    if let Some(mut client) = clients_map.get_mut(&sender_addr) {
+        // Unless the refresh got there first or the client unsubscribed
+        if let Some(sent @ None) = client.metar_subscriptions.get_mut(&station) {
+            *sent = Some(metar.clone());
+            let packet = metar_packet(server_callsign, callsign, metar);
+            let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(packet))));
+        }
+    }
+    format!(
+        "Subscribed to {}; new METARs will be sent as they come",
+        station
+    )
+}
+
+/// End the subscription of the client at `sender_addr` to `station`,
+/// returning the reply to show it
+pub async fn unsubscribe(
+    station: &str,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+) -> String {
+    let station = station.trim().to_ascii_uppercase();
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    let Some(mut client) = clients_map.get_mut(&sender_addr) else {
+        return String::new();
+    };
+    match client.metar_subscriptions.remove(&station) {
+        Some(_) => format!("Unsubscribed from {}", station),
+        None => format!("Not subscribed to {}", station),
+    }
+}
+
+/// `$AR(server):(callsign):METAR:(report)`
+pub fn metar_packet(server_callsign: &str, callsign: &str, metar: String) -> Packet {
+    Packet {
+        packet_type: PacketType::Request,
+        command: "AR".to_string(),
+        source: server_callsign.to_string(),
+        destination: callsign.to_string(),
+        data: vec!["METAR".to_string(), metar],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientState};
+    use crate::config::{WeatherConfig, WeatherProvider};
+
+    const ADDR: &str = "127.0.0.1:50001";
+
+    /// A static provider with METARs for ZBAA and ZSPD, taking up to two
+    /// subscriptions
+    fn weather() -> WeatherService {
+        let path = std::env::temp_dir().join(format!("openfsd-subscribe-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "ZBAA 121200Z 36004MPS CAVOK 22/08 Q1012 NOSIG\n\
+             ZSPD 121200Z 09005MPS 9999 FEW020 25/18 Q1010 NOSIG\n",
+        )
+        .unwrap();
+        let weather = WeatherService::from_config(&WeatherConfig {
+            provider: WeatherProvider::Static,
+            static_file: Some(path.clone()),
+            max_metar_subscriptions: 2,
+            ..WeatherConfig::default()
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        weather
+    }
+
+    /// Send `line` and return the replies as lines
+    async fn send(
+        line: &str,
+        clients: &Arc<RwLock<ClientRegistry>>,
+        weather: &WeatherService,
+    ) -> Vec<String> {
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+        let packet = Packet::parse(line).unwrap();
+        assert!(is_subscription(&packet));
+        handle_subscription(
+            packet,
+            ADDR.parse().unwrap(),
+            clients,
+            &broadcast_tx,
+            weather,
+            "SERVER",
+        )
+        .await;
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|(_, msg)| match msg {
+                ServerMessage::Direct(packet) => packet.to_string(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_and_unsubscribe() {
+        let mut client = Client::new(ADDR.parse().unwrap());
+        client.state = ClientState::Active;
+        client.callsign = Some("ZBAA_TWR".into());
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([(client.addr, client)])));
+        let weather = weather();
+
+        let replies = send("$AXZBAA_TWR:SERVER:SUBSCRIBE:zbaa", &clients, &weather).await;
+        assert_eq!(
+            replies,
+            [
+                "$ARSERVER:ZBAA_TWR:METAR:ZBAA 121200Z 36004MPS CAVOK 22/08 Q1012 NOSIG",
+                "#TMSERVER:ZBAA_TWR:Subscribed to ZBAA; new METARs will be sent as they come",
+            ]
+        );
+        let replies = send("$AXZBAA_TWR:SERVER:SUBSCRIBE:ZBAA", &clients, &weather).await;
+        assert_eq!(replies, ["#TMSERVER:ZBAA_TWR:Already subscribed to ZBAA"]);
+
+        // Stations without a METAR can be subscribed to, up to the cap
+        let replies = send("$AXZBAA_TWR:SERVER:SUBSCRIBE:ZGGG", &clients, &weather).await;
+        assert_eq!(
+            replies,
+            ["#TMSERVER:ZBAA_TWR:Subscribed to ZGGG; no METAR is available yet"]
+        );
+        let replies = send("$AXZBAA_TWR:SERVER:SUBSCRIBE:ZSPD", &clients, &weather).await;
+        assert_eq!(
+            replies,
+            ["#TMSERVER:ZBAA_TWR:Subscribed to 2 stations already; unsubscribe from one first"]
+        );
+        let replies = send("$AXZBAA_TWR:SERVER:SUBSCRIBE:ZB/AA", &clients, &weather).await;
+        assert_eq!(replies, ["#TMSERVER:ZBAA_TWR:Invalid station: ZB/AA"]);
+
+        let replies = send("$AXZBAA_TWR:SERVER:UNSUBSCRIBE:ZGGG", &clients, &weather).await;
+        assert_eq!(replies, ["#TMSERVER:ZBAA_TWR:Unsubscribed from ZGGG"]);
+        let replies = send("$AXZBAA_TWR:SERVER:UNSUBSCRIBE:ZGGG", &clients, &weather).await;
+        assert_eq!(replies, ["#TMSERVER:ZBAA_TWR:Not subscribed to ZGGG"]);
+
+        let clients = clients.read().await;
+        let client = clients.get(&ADDR.parse().unwrap()).unwrap();
+        assert_eq!(
+            client.metar_subscriptions.keys().collect::<Vec<_>>(),
+            ["ZBAA"]
+        );
+    }
+}
@@ -1,11 +1,22 @@
 pub mod auth;
+pub mod challenge;
+pub mod client_stats;
+pub mod command;
 pub mod flight_plan;
 pub mod message;
+pub mod metar_subscription;
+pub mod nearest_atc;
 pub mod position;
+pub mod pro_controller;
 pub mod request;
 
 pub use auth::{handle_identification, handle_login, handle_logoff};
-pub use flight_plan::handle_flight_plan;
+pub use challenge::{handle_auth_challenge, handle_auth_response};
+pub use command::{handle_server_command, is_server_command};
+pub use flight_plan::{handle_amendment, handle_flight_plan};
 pub use message::handle_text_message;
-pub use position::handle_position_update;
-pub use request::{handle_metar_request, handle_request, handle_response};
+pub use position::{handle_atc_position_update, handle_position_update, handle_visibility_center};
+pub use pro_controller::handle_pro_controller;
+pub use request::{
+    handle_handoff, handle_metar_request, handle_plane_info, handle_request, handle_response,
+};
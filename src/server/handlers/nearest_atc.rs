@@ -0,0 +1,291 @@
+//! Who a pilot should contact: the controllers online around them
+//!
+//! Pilots ask with `.atc` or `$CQ(callsign):SERVER:NEARATC`, and get one
+//! `#TM` line per controller whose range covers them, up to five: the most
+//! local facility first (delivery, ground, tower, approach, then center and
+//! FSS), and the nearest first within a facility. A pilot who hasn't sent a
+//! position yet is placed at the departure airport of their flight plan, if
+//! it is in the airport database. With no one relevant online the reply
+//! points to UNICOM.
+
+use crate::airports::Airports;
+use crate::client::{distance_nm, format_frequency, Client, ClientType, Facility};
+use crate::config::VisibilityConfig;
+use crate::packet::Packet;
+use crate::server::config::ServerMessage;
+use crate::server::handlers::command::send_lines;
+use crate::server::registry::ClientRegistry;
+use crate::server::visibility::effective_range_nm;
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+
+/// `$CQ` subtype of the query; plain `ATC` already asks whether a callsign
+/// is a valid controller
+pub const QUERY: &str = "NEARATC";
+
+/// Most controllers listed
+const MAX_LISTED: usize = 5;
+
+/// What controllers send as their frequency before they have one, 199.998
+const NO_FREQUENCY: u32 = 99998;
+
+/// Sent when no controller covers the pilot
+pub const UNICOM_ADVICE: &str =
+    "No ATC covers your position. Announce your intentions on UNICOM 122.800.";
+
+/// Whether `packet` is a `$CQ` asking the server for nearby controllers
+pub fn is_query(packet: &Packet, server_callsign: &str) -> bool {
+    (packet.destination.eq_ignore_ascii_case("SERVER")
+        || packet.destination.eq_ignore_ascii_case(server_callsign))
+        && packet
+            .data
+            .first()
+            .is_some_and(|kind| kind.eq_ignore_ascii_case(QUERY))
+}
+
+/// Answer `$CQ(callsign):SERVER:NEARATC` with a `#TM` per controller
+pub fn handle_query(
+    packet: &Packet,
+    sender_addr: SocketAddr,
+    clients: &ClientRegistry,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    airports: &Airports,
+    visibility: &VisibilityConfig,
+    server_callsign: &str,
+) {
+    let lines = reply(sender_addr, clients, airports, visibility);
+    send_lines(
+        lines,
+        sender_addr,
+        &packet.source,
+        server_callsign,
+        broadcast_tx,
+    );
+}
+
+/// The reply to a client asking who to contact, one line each
+pub fn reply(
+    sender_addr: SocketAddr,
+    clients: &ClientRegistry,
+    airports: &Airports,
+    visibility: &VisibilityConfig,
+) -> Vec<String> {
+    let Some(requester) = clients.get(&sender_addr) else {
+        return Vec::new();
+    };
+    let departure = requester
+        .flight_plan
+        .as_ref()
+        .and_then(|plan| airports.position(&plan.departure));
+    let Some(point) = requester.latitude.zip(requester.longitude).or(departure) else {
+        return vec!["Your position isn't known yet; send a position first.".to_string()];
+    };
+
+    let controllers = relevant(point, clients, visibility);
+    if controllers.is_empty() {
+        return vec![UNICOM_ADVICE.to_string()];
+    }
+    controllers
+        .into_iter()
+        .map(|(client, distance)| {
+            format!(
+                "{} {} ({:.0}nm)",
+                client.callsign().unwrap_or_default(),
+                format_frequency(client.frequency.unwrap_or_default()),
+                distance
+            )
+        })
+        .collect()
+}
+
+/// Controllers whose range covers `point`, with their distance to it in
+/// nautical miles, the most local facility and then the nearest first
+pub fn relevant<'a>(
+    point: (f64, f64),
+    clients: &'a ClientRegistry,
+    visibility: &VisibilityConfig,
+) -> Vec<(&'a Client, f64)> {
+    let mut controllers: Vec<(&Client, usize, f64)> = clients
+        .of_type(ClientType::Atc)
+        .filter_map(|(_, client)| {
+            let rank = rank(client)?;
+            let distance = client
+                .visibility_points()
+                .map(|p| distance_nm(p, point))
+                .min_by(f64::total_cmp)?;
+            (distance <= f64::from(effective_range_nm(visibility, client)))
+                .then_some((client, rank, distance))
+        })
+        .collect();
+    controllers.sort_by(|a, b| a.1.cmp(&b.1).then(a.2.total_cmp(&b.2)));
+    controllers
+        .into_iter()
+        .take(MAX_LISTED)
+        .map(|(client, _, distance)| (client, distance))
+        .collect()
+}
+
+/// Place in the order a pilot meets the facility, or `None` for clients
+/// that can't be contacted: observers, ATIS stations and controllers
+/// without a frequency
+fn rank(client: &Client) -> Option<usize> {
+    let callsign = client.callsign()?;
+    if !client.is_active()
+        || callsign.ends_with("_ATIS")
+        || client
+            .frequency
+            .is_none_or(|frequency| frequency == NO_FREQUENCY)
+    {
+        return None;
+    }
+    match client.facility? {
+        Facility::Delivery => Some(0),
+        Facility::Ground => Some(1),
+        Facility::Tower => Some(2),
+        Facility::Approach => Some(3),
+        Facility::Center => Some(4),
+        Facility::Fss => Some(5),
+        Facility::Observer => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airports::Airport;
+    use crate::flight_plan::FlightPlan;
+
+    const PILOT_ADDR: &str = "127.0.0.1:50000";
+
+    /// On the ground at Pudong
+    const ZSPD: (f64, f64) = (31.1434, 121.8052);
+
+    fn controller(
+        port: u16,
+        callsign: &str,
+        facility: Facility,
+        frequency: u32,
+        (latitude, longitude): (f64, f64),
+    ) -> Client {
+        let mut client =
+            Client::active_for_tests(([127, 0, 0, 1], port), callsign, ClientType::Atc);
+        client.facility = Some(facility);
+        client.frequency = Some(frequency);
+        client.latitude = Some(latitude);
+        client.longitude = Some(longitude);
+        client
+    }
+
+    fn registry(pilot: Client, controllers: Vec<Client>) -> ClientRegistry {
+        std::iter::once(pilot)
+            .chain(controllers)
+            .map(|client| (client.addr, client))
+            .collect()
+    }
+
+    fn pilot_at(point: Option<(f64, f64)>) -> Client {
+        let mut pilot =
+            Client::active_for_tests(([127, 0, 0, 1], 50000), "CES2101", ClientType::Pilot);
+        pilot.latitude = point.map(|p| p.0);
+        pilot.longitude = point.map(|p| p.1);
+        pilot
+    }
+
+    /// Controllers around Shanghai, and some that don't count
+    fn shanghai() -> Vec<Client> {
+        vec![
+            controller(50001, "ZSHA_CTR", Facility::Center, 25950, (31.2, 121.3)),
+            controller(50002, "ZSSS_APP", Facility::Approach, 19400, (31.2, 121.3)),
+            controller(50003, "ZSPD_APP", Facility::Approach, 20650, ZSPD),
+            controller(50004, "ZSPD_TWR", Facility::Tower, 18700, ZSPD),
+            controller(50005, "ZSPD_GND", Facility::Ground, 21650, ZSPD),
+            // Out of range
+            controller(50006, "ZBAA_TWR", Facility::Tower, 18500, (40.08, 116.58)),
+            controller(50007, "ZBPE_CTR", Facility::Center, 25300, (40.0, 116.0)),
+            // Can't be called
+            controller(50008, "ZSPD_ATIS", Facility::Tower, 27000, ZSPD),
+            controller(50009, "ZSPD_OBS", Facility::Observer, 99998, ZSPD),
+            controller(50010, "ZSPD_DEL", Facility::Delivery, 99998, ZSPD),
+        ]
+    }
+
+    fn ask(clients: &ClientRegistry, airports: &Airports) -> Vec<String> {
+        reply(
+            PILOT_ADDR.parse().unwrap(),
+            clients,
+            airports,
+            &VisibilityConfig::default(),
+        )
+    }
+
+    #[test]
+    fn test_order_by_facility_and_distance() {
+        let clients = registry(pilot_at(Some(ZSPD)), shanghai());
+        assert_eq!(
+            ask(&clients, &Airports::default()),
+            [
+                "ZSPD_GND 121.650 (0nm)",
+                "ZSPD_TWR 118.700 (0nm)",
+                "ZSPD_APP 120.650 (0nm)",
+                "ZSSS_APP 119.400 (26nm)",
+                "ZSHA_CTR 125.950 (26nm)",
+            ]
+        );
+
+        // Only five are listed
+        let mut controllers = shanghai();
+        controllers.push(controller(
+            50011,
+            "ZSPD_DEL",
+            Facility::Delivery,
+            24100,
+            ZSPD,
+        ));
+        let clients = registry(pilot_at(Some(ZSPD)), controllers);
+        let lines = ask(&clients, &Airports::default());
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "ZSPD_DEL 124.100 (0nm)");
+        assert!(!lines.iter().any(|line| line.starts_with("ZSHA_CTR")));
+    }
+
+    #[test]
+    fn test_departure_airport_stands_in_for_position() {
+        let mut pilot = pilot_at(None);
+        pilot.flight_plan = Some(FlightPlan {
+            departure: "ZSPD".into(),
+            ..FlightPlan::default()
+        });
+        let clients = registry(pilot, shanghai());
+        let airports = Airports::from_iter([Airport {
+            icao: "ZSPD".into(),
+            name: "Shanghai Pudong International Airport".into(),
+            latitude: ZSPD.0,
+            longitude: ZSPD.1,
+            elevation_ft: Some(13),
+        }]);
+        assert_eq!(ask(&clients, &airports)[0], "ZSPD_GND 121.650 (0nm)");
+
+        // Not without the airport database
+        assert_eq!(ask(&clients, &Airports::default()).len(), 1);
+        assert!(ask(&clients, &Airports::default())[0].contains("position"));
+    }
+
+    #[test]
+    fn test_no_atc_online() {
+        // Over the Pacific, with only Shanghai online
+        let clients = registry(pilot_at(Some((30.0, 160.0))), shanghai());
+        assert_eq!(ask(&clients, &Airports::default()), [UNICOM_ADVICE]);
+
+        let clients = registry(pilot_at(Some(ZSPD)), Vec::new());
+        assert_eq!(ask(&clients, &Airports::default()), [UNICOM_ADVICE]);
+    }
+
+    #[test]
+    fn test_is_query() {
+        let query = |line| is_query(&Packet::parse(line).unwrap(), "OPENFSD");
+        assert!(query("$CQCES2101:SERVER:NEARATC"));
+        assert!(query("$CQCES2101:OPENFSD:nearatc"));
+        assert!(!query("$CQCES2101:SERVER:ATC:ZSPD_TWR"));
+        assert!(!query("$CQCES2101:ZSPD_TWR:NEARATC"));
+    }
+}
@@ -1,37 +1,599 @@
-use crate::packet::Packet;
+use crate::client::{Client, ClientType, Facility};
+use crate::config::VisibilityConfig;
+use crate::packet::{Packet, PositionUpdate};
 use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::handlers::flight_plan;
+use crate::server::registry::ClientRegistry;
+use crate::server::state::ServerState;
+use crate::server::visibility;
+use crate::tracks::{TrackRecorder, TrackSample};
+use crate::webhooks::Event;
+use chrono::{DateTime, Utc};
 use std::net::SocketAddr;
-use tokio::sync::broadcast;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{broadcast, RwLock};
+
+/// Pilots slower than this, in knots, count as on the ground whatever their
+/// client says...
+const PARKED_GROUNDSPEED_KT: u32 = 40;
+
+/// ...if they are within this many feet of the field elevation, where it
+/// is known
+const FIELD_ELEVATION_MARGIN_FT: i32 = 300;
 
 /// Handle position update
+///
+/// Observers following the pilot get it wherever they are.
 pub async fn handle_position_update(
-    packet: Packet,
+    update: PositionUpdate,
     sender_addr: SocketAddr,
-    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    state: &ServerState,
 ) {
-    log::debug!(
+    let ServerState {
+        clients,
+        broadcast_tx,
+        tracks,
+        follows,
+        webhooks,
+        clock,
+        ..
+    } = state;
+    let visibility = &state.config.visibility;
+    tracing::debug!(
         "Position update from {}: {}",
         sender_addr,
-        packet.destination
+        update.callsign()
     );
 
+    // Every recipient shares the one update
+    let update = Arc::new(update);
+
+    // @(mode):(callsign):(squawk):(rating):(lat):(lon):(alt):(groundspeed):(pbh):(flags)
+    let [squawk, _rating, latitude, longitude, altitude, groundspeed, pbh] =
+        update.leading_fields();
+
     // Check for emergency squawk code (7500) - immediate disconnect
-    // Position update format for pilots: @(mode):(callsign):(squawk):(rating):(lat):(lon):(alt):(groundspeed):(num1):(num2)
-    if packet.packet_type == crate::packet::PacketType::PilotUpdate {
-        if let Some(squawk) = packet.data.get(1) {
-            if squawk == "7500" {
-                log::warn!(
-                    "Squawk 7500 (hijacking) detected from {} - immediate disconnect",
-                    packet.source
-                );
-
-                // Send disconnect message
-                let _ = broadcast_tx.send((sender_addr, ServerMessage::Disconnect));
-                return;
+    if squawk == Some("7500") {
+        tracing::warn!(
+            "Squawk 7500 (hijacking) detected from {} - immediate disconnect",
+            update.callsign()
+        );
+
+        // Send disconnect message
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Disconnect("squawk_7500")));
+        crate::metrics::kick("squawk_7500");
+        webhooks.emit(&Event::client_kicked(update.callsign(), "squawk 7500"));
+        return;
+    }
+
+    // Remember the latest position for snapshots and range checks
+    let mut send_flight_plan = false;
+    {
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        if let Some(mut client) = clients_map.get_mut(&sender_addr) {
+            send_flight_plan = std::mem::take(&mut client.flight_plan_unsent);
+            client.counters.position_update();
+            client.position_received(clock.now());
+            if client.squawk.as_deref() != squawk {
+                client.squawk = squawk.map(str::to_string);
+            }
+            client.latitude = latitude.and_then(|s| s.parse().ok());
+            client.longitude = longitude.and_then(|s| s.parse().ok());
+            client.altitude = altitude.and_then(|s| s.parse().ok());
+            client.groundspeed = groundspeed.and_then(|s| s.parse().ok());
+            client.heading = pbh.and_then(heading_from_pbh);
+            let speed = client.groundspeed;
+            client.progress.update(speed, clock.utc());
+            client.on_ground = match pbh.and_then(on_ground_from_pbh) {
+                Some(true) => {
+                    client.field_elevation = client.altitude;
+                    Some(true)
+                }
+                reported => client
+                    .groundspeed
+                    .map(|_| looks_parked(&client))
+                    .or(reported),
+            };
+            client.last_position = Some(update.clone());
+
+            if let Some(sample) = track_sample(&mut client, groundspeed, tracks, clock.utc()) {
+                tracks.submit(sample);
+            }
+        };
+    }
+
+    // A plan activated at login waited for a position to range against
+    if send_flight_plan {
+        flight_plan::send_to_controllers_in_range(sender_addr, clients, broadcast_tx).await;
+    }
+
+    let followers = follows.followers(sender_addr);
+    send_in_range(
+        update,
+        sender_addr,
+        clients,
+        broadcast_tx,
+        visibility,
+        followers,
+    )
+    .await;
+}
+
+/// Handle ATC position update
+///
+/// An update claiming a rating above the controller's, or for a facility
+/// that needs a higher one, is refused with an `$ER` and goes no further.
+pub async fn handle_atc_position_update(
+    update: PositionUpdate,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    visibility: &VisibilityConfig,
+    server_callsign: &str,
+    now: Instant,
+) {
+    tracing::debug!(
+        "ATC position update from {}: {}",
+        sender_addr,
+        update.callsign()
+    );
+
+    let update = Arc::new(update);
+
+    // %(callsign):(frequency):(facility):(visibility range):(rating):(lat):(lon):(elevation)
+    let [frequency, facility, range, rating, latitude, longitude, elevation] =
+        update.leading_fields();
+    let facility = facility.and_then(Facility::from_code);
+    {
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        if let Some(mut client) = clients_map.get_mut(&sender_addr) {
+            let claimed = rating.and_then(|s| s.parse::<i32>().ok());
+            if let Some(allowed) = client.effective_rating(now) {
+                let needed = facility.map_or(1, Facility::minimum_rating);
+                if claimed.unwrap_or_default().max(needed) > allowed {
+                    tracing::warn!(
+                        "Refusing ATC update from {}: rating {} doesn't allow {:?}",
+                        update.callsign(),
+                        allowed,
+                        facility
+                    );
+                    let error_packet = Packet {
+                        packet_type: crate::packet::PacketType::Request,
+                        command: "ER".to_string(),
+                        source: server_callsign.to_string(),
+                        destination: update.callsign().to_string(),
+                        data: vec![
+                            "011".to_string(),
+                            String::new(),
+                            "Requested level too high".to_string(),
+                        ],
+                    };
+                    let _ = broadcast_tx
+                        .send((sender_addr, ServerMessage::Direct(Arc::new(error_packet))));
+                    return;
+                }
             }
+            client.counters.position_update();
+            client.position_received(now);
+            client.frequency = frequency.and_then(|s| s.parse().ok());
+            client.facility = facility;
+            client.declared_range_nm = range.and_then(|s| s.parse().ok());
+            client.latitude = latitude.and_then(|s| s.parse().ok());
+            client.longitude = longitude.and_then(|s| s.parse().ok());
+            client.altitude = elevation.and_then(|s| s.parse().ok());
+            client.last_position = Some(update.clone());
+        };
+    }
+
+    send_in_range(
+        update,
+        sender_addr,
+        clients,
+        broadcast_tx,
+        visibility,
+        Vec::new(),
+    )
+    .await;
+}
+
+/// Handle a controller's visibility center update
+///
+/// '(callsign):(index):(lat):(lon) sets the center at that index; an update
+/// without coordinates clears them all.
+pub async fn handle_visibility_center(
+    packet: Packet,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    visibility: &VisibilityConfig,
+) {
+    let field = |i: usize| packet.data.get(i).map(String::as_str).unwrap_or_default();
+    let center = if field(1).is_empty() && field(2).is_empty() {
+        None
+    } else {
+        let index = field(0).parse::<usize>().ok();
+        let latitude = field(1).parse::<f64>().ok().filter(|lat| lat.abs() <= 90.0);
+        let longitude = field(2)
+            .parse::<f64>()
+            .ok()
+            .filter(|lon| lon.abs() <= 180.0);
+        let (Some(index), Some(latitude), Some(longitude)) = (index, latitude, longitude) else {
+            tracing::debug!("Ignoring malformed visibility center from {}", sender_addr);
+            return;
+        };
+        Some((index, (latitude, longitude)))
+    };
+
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    let Some(mut client) = clients_map.get_mut(&sender_addr) else {
+        return;
+    };
+    if !client.is_active() || client.client_type == Some(ClientType::Pilot) {
+        return;
+    }
+    match center {
+        None => client.visibility_centers.clear(),
+        Some((index, _)) if index >= visibility.max_centers => tracing::warn!(
+            "Ignoring visibility center {} from {}, at most {} are kept",
+            index,
+            packet.destination,
+            visibility.max_centers
+        ),
+        Some((index, point)) => {
+            client.visibility_centers.insert(index, point);
+        }
+    }
+}
+
+/// Send a position update to the clients in visibility range of its
+/// sender, and to `followers` wherever they are
+async fn send_in_range(
+    update: Arc<PositionUpdate>,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    visibility: &VisibilityConfig,
+    followers: Vec<SocketAddr>,
+) {
+    let mut recipients = visibility::recipients(visibility, &*clients.read().await, sender_addr);
+    for follower in followers {
+        if !recipients.contains(&follower) {
+            recipients.push(follower);
         }
     }
+    for addr in recipients {
+        let _ = broadcast_tx.send((addr, ServerMessage::Position(update.clone())));
+    }
+}
+
+/// Heading in degrees from a packed pitch/bank/heading field, where it
+/// takes bits 2-11 as a fraction of 1024
+fn heading_from_pbh(pbh: &str) -> Option<u32> {
+    // Negative pitch makes some clients send the field as a signed number
+    let pbh = pbh.parse::<i64>().ok()? as u32;
+    let heading = f64::from((pbh >> 2) & 0x3ff) * 360.0 / 1024.0;
+    Some(heading.round() as u32 % 360)
+}
+
+/// The on-ground flag, bit 1 of a packed pitch/bank/heading field
+fn on_ground_from_pbh(pbh: &str) -> Option<bool> {
+    let pbh = pbh.parse::<i64>().ok()? as u32;
+    Some(pbh & 0b10 != 0)
+}
+
+/// Whether a pilot looks parked: slow, and near the elevation it was last on
+/// the ground at if that is known. Some clients never set the on-ground flag.
+fn looks_parked(client: &Client) -> bool {
+    let slow = client
+        .groundspeed
+        .is_some_and(|groundspeed| groundspeed < PARKED_GROUNDSPEED_KT);
+    let near_field = match (client.altitude, client.field_elevation) {
+        (Some(altitude), Some(elevation)) => {
+            (altitude - elevation).abs() <= FIELD_ELEVATION_MARGIN_FT
+        }
+        _ => true,
+    };
+    slow && near_field
+}
 
-    // Broadcast position update to all clients
-    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(packet)));
+/// Build a track sample if this update should be recorded
+fn track_sample(
+    client: &mut Client,
+    groundspeed: Option<&str>,
+    tracks: &TrackRecorder,
+    now: DateTime<Utc>,
+) -> Option<TrackSample> {
+    let callsign = client.callsign()?.to_string();
+    if !tracks.records(&callsign) || !tracks.should_sample(&mut client.track_decimator) {
+        return None;
+    }
+
+    Some(TrackSample {
+        session_id: client.session_id.clone(),
+        callsign,
+        network_id: client.network_id.clone()?,
+        recorded_at: now,
+        latitude: client.latitude?,
+        longitude: client.longitude?,
+        altitude: client.altitude.unwrap_or_default(),
+        groundspeed: groundspeed.and_then(|s| s.parse().ok()).unwrap_or_default(),
+        aircraft: client
+            .flight_plan
+            .as_ref()
+            .map(|plan| plan.aircraft.clone())
+            .filter(|aircraft| !aircraft.is_empty()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::clock::{Clock, TestClock};
+    use std::time::Duration;
+
+    const CENTER_ADDR: &str = "127.0.0.1:50001";
+    const PILOT_ADDR: &str = "127.0.0.1:50002";
+
+    fn client(
+        addr: &str,
+        callsign: &str,
+        client_type: ClientType,
+        latitude: f64,
+        longitude: f64,
+    ) -> Client {
+        let mut client =
+            Client::active_for_tests(addr.parse::<SocketAddr>().unwrap(), callsign, client_type);
+        client.latitude = Some(latitude);
+        client.longitude = Some(longitude);
+        client
+    }
+
+    /// Send an ATC update from the center and return whether the pilot got it
+    async fn pilot_receives(atc_update: &str, visibility: &VisibilityConfig) -> bool {
+        // Beijing and Singapore are about 2400nm apart
+        let center = client(CENTER_ADDR, "ZBPE_CTR", ClientType::Atc, 40.08, 116.58);
+        let pilot = client(PILOT_ADDR, "SIA802", ClientType::Pilot, 1.36, 103.99);
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([
+            (center.addr, center),
+            (pilot.addr, pilot),
+        ])));
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+
+        let update = PositionUpdate::parse(atc_update).unwrap();
+        handle_atc_position_update(
+            update,
+            CENTER_ADDR.parse().unwrap(),
+            &clients,
+            &broadcast_tx,
+            visibility,
+            "SERVER",
+            Instant::now(),
+        )
+        .await;
+
+        let mut received = false;
+        while let Ok((addr, msg)) = rx.try_recv() {
+            if let ServerMessage::Position(update) = msg {
+                assert_eq!(update.callsign(), "ZBPE_CTR");
+                received |= addr == PILOT_ADDR.parse().unwrap();
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn test_cap_overrides_declared_range() {
+        let update = "%ZBPE_CTR:25300:6:10000:5:40.08:116.58:0";
+        assert!(!pilot_receives(update, &VisibilityConfig::default()).await);
+
+        let uncapped = VisibilityConfig {
+            max_range_nm: 20_000,
+            ..VisibilityConfig::default()
+        };
+        assert!(pilot_receives(update, &uncapped).await);
+    }
+
+    #[tokio::test]
+    async fn test_pilot_update_filtered_by_range() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let near = client(CENTER_ADDR, "CES2101", ClientType::Pilot, 40.0, 116.5);
+        let far = client(PILOT_ADDR, "SIA802", ClientType::Pilot, 1.36, 103.99);
+        let sender: SocketAddr = "127.0.0.1:50003".parse().unwrap();
+        let pilot = Client::active_for_tests(sender, "CCA1501", ClientType::Pilot);
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([
+            (near.addr, near),
+            (far.addr, far),
+            (sender, pilot),
+        ])));
+        let state = ServerState {
+            clients,
+            ..ServerState::for_tests(Arc::new(db))
+        };
+        let mut rx = state.broadcast_tx.subscribe();
+
+        let update = PositionUpdate::parse("@NCCA1501:1200:1:40.1:116.6:9000:250:0:0").unwrap();
+        handle_position_update(update, sender, &state).await;
+
+        let mut recipients = Vec::new();
+        while let Ok((addr, _)) = rx.try_recv() {
+            recipients.push(addr);
+        }
+        assert_eq!(recipients, vec![CENTER_ADDR.parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_recipients_share_one_packet() {
+        let center = client(CENTER_ADDR, "ZBPE_CTR", ClientType::Atc, 40.08, 116.58);
+        let mut clients = ClientRegistry::from([(center.addr, center)]);
+        for n in 0..20 {
+            let pilot = client(
+                &format!("127.0.0.1:{}", 51000 + n),
+                &format!("CCA{}", 2000 + n),
+                ClientType::Pilot,
+                40.0,
+                116.5,
+            );
+            clients.insert(pilot.addr, pilot);
+        }
+        let clients = Arc::new(RwLock::new(clients));
+        let (broadcast_tx, mut rx) = broadcast::channel(64);
+
+        let update = PositionUpdate::parse("%ZBPE_CTR:25300:6:300:5:40.08:116.58:0").unwrap();
+        handle_atc_position_update(
+            update,
+            CENTER_ADDR.parse().unwrap(),
+            &clients,
+            &broadcast_tx,
+            &VisibilityConfig::default(),
+            "SERVER",
+            Instant::now(),
+        )
+        .await;
+
+        let mut sent = Vec::new();
+        while let Ok((_, msg)) = rx.try_recv() {
+            if let ServerMessage::Position(update) = msg {
+                sent.push(update);
+            }
+        }
+        assert_eq!(sent.len(), 20);
+        assert!(sent.iter().all(|update| Arc::ptr_eq(update, &sent[0])));
+        // The center keeps the last one too, for position snapshots
+        assert_eq!(Arc::strong_count(&sent[0]), 21);
+    }
+
+    #[tokio::test]
+    async fn test_visibility_centers() {
+        let center = client(CENTER_ADDR, "EDGG_CTR", ClientType::Atc, 50.03, 8.57);
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([(center.addr, center)])));
+        let visibility = VisibilityConfig::default();
+        let send = |line: &'static str| {
+            let clients = clients.clone();
+            let visibility = visibility.clone();
+            async move {
+                let packet = Packet::parse(line).unwrap();
+                handle_visibility_center(
+                    packet,
+                    CENTER_ADDR.parse().unwrap(),
+                    &clients,
+                    &visibility,
+                )
+                .await;
+                let clients = clients.read().await;
+                let client = clients.get(&CENTER_ADDR.parse().unwrap()).unwrap();
+                client.visibility_centers.clone()
+            }
+        };
+
+        send("'EDGG_CTR:1:58.36:8.57").await;
+        let centers = send("'EDGG_CTR:2:52.5:13.4").await;
+        assert_eq!(centers.len(), 2);
+        assert_eq!(centers[&1], (58.36, 8.57));
+
+        // Past max_centers, or malformed
+        send("'EDGG_CTR:4:48.1:11.6").await;
+        let centers = send("'EDGG_CTR:3:95.0:11.6").await;
+        assert_eq!(centers.len(), 2);
+
+        assert!(send("'EDGG_CTR:0::").await.is_empty());
+    }
+
+    /// Send the pilot's update at 50002 and return what the server made of it
+    async fn motion(clients: &Arc<RwLock<ClientRegistry>>, line: &str) -> Option<bool> {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let state = ServerState {
+            clients: clients.clone(),
+            ..ServerState::for_tests(Arc::new(db))
+        };
+        handle_position_update(
+            PositionUpdate::parse(line).unwrap(),
+            PILOT_ADDR.parse().unwrap(),
+            &state,
+        )
+        .await;
+        clients.read().await[&PILOT_ADDR.parse().unwrap()].on_ground
+    }
+
+    #[tokio::test]
+    async fn test_motion_state() {
+        let pilot = client(PILOT_ADDR, "DLH4AB", ClientType::Pilot, 50.03, 8.57);
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([(pilot.addr, pilot)])));
+        let pilot_addr: SocketAddr = PILOT_ADDR.parse().unwrap();
+
+        // Taxiing at Frankfurt with the on-ground bit set: heading 90
+        assert_eq!(
+            motion(&clients, "@N:DLH4AB:2000:1:50.03:8.57:364:12:1026:0").await,
+            Some(true)
+        );
+        {
+            let clients = clients.read().await;
+            let pilot = &clients[&pilot_addr];
+            assert_eq!((pilot.heading, pilot.groundspeed), (Some(90), Some(12)));
+            assert_eq!(pilot.field_elevation, Some(364));
+        }
+
+        // Climbing out
+        let airborne = "@N:DLH4AB:2000:1:50.05:8.60:3000:180:1024:0";
+        assert_eq!(motion(&clients, airborne).await, Some(false));
+        // Slow but well above the field, like a helicopter
+        let hovering = "@N:DLH4AB:2000:1:50.05:8.60:2500:30:1024:0";
+        assert_eq!(motion(&clients, hovering).await, Some(false));
+
+        // Parked at the gate again, but the client says airborne
+        let parked = "@N:DLH4AB:2000:1:50.03:8.57:370:0:1024:0";
+        assert_eq!(motion(&clients, parked).await, Some(true));
+
+        // Without a known field elevation, slow is enough
+        clients
+            .write()
+            .await
+            .for_each_mut(|_, client| client.field_elevation = None);
+        assert_eq!(motion(&clients, hovering).await, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_update_timed_by_server_clock() {
+        let pilot = client(PILOT_ADDR, "DLH4AB", ClientType::Pilot, 50.03, 8.57);
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let clock = Arc::new(TestClock::default());
+        let state = ServerState {
+            clients: Arc::new(RwLock::new(ClientRegistry::from([(pilot.addr, pilot)]))),
+            clock: clock.clone(),
+            ..ServerState::for_tests(Arc::new(db))
+        };
+
+        // The idle sweeper reads the same clock, paused or not
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        handle_position_update(
+            PositionUpdate::parse("@N:DLH4AB:2000:1:50.05:8.60:3000:180:1024:0").unwrap(),
+            PILOT_ADDR.parse().unwrap(),
+            &state,
+        )
+        .await;
+
+        let clients = state.clients.read().await;
+        let pilot = &clients[&PILOT_ADDR.parse().unwrap()];
+        assert_eq!(pilot.last_position_at, Some(clock.now()));
+    }
+
+    #[test]
+    fn test_on_ground_from_pbh() {
+        assert_eq!(on_ground_from_pbh("0"), Some(false));
+        assert_eq!(on_ground_from_pbh("2"), Some(true));
+        assert_eq!(on_ground_from_pbh("1026"), Some(true));
+        assert_eq!(on_ground_from_pbh("-4193278"), Some(true));
+        assert_eq!(on_ground_from_pbh("abc"), None);
+    }
+
+    #[test]
+    fn test_heading_from_pbh() {
+        assert_eq!(heading_from_pbh("0"), Some(0));
+        assert_eq!(heading_from_pbh("1024"), Some(90));
+        // Pitched down, so the top bits are set
+        assert_eq!(heading_from_pbh("-4193280"), Some(90));
+        assert_eq!(heading_from_pbh("4092"), Some(0));
+        assert_eq!(heading_from_pbh("abc"), None);
+    }
 }
@@ -0,0 +1,39 @@
+use crate::packet::Packet;
+use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::registry::ClientRegistry;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, RwLock};
+
+/// Handle pro-controller packet (#PC)
+pub async fn handle_pro_controller(
+    packet: Packet,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+) {
+    tracing::debug!(
+        "Pro-controller packet from {} to {}: {:?}",
+        packet.source,
+        packet.destination,
+        packet.data
+    );
+
+    // Beacon code assignment: #PC(controller):(to):CCP:BC:(callsign):(code)
+    if let ["CCP", "BC", callsign, code, ..] = packet
+        .data
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        if let Some(mut pilot) = clients_map.named_mut(callsign) {
+            tracing::info!("{} assigned squawk {} to {}", packet.source, code, callsign);
+            pilot.assigned_squawk = Some(code.to_string());
+        };
+    }
+
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(packet))));
+}
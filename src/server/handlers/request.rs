@@ -1,7 +1,14 @@
-use crate::client::{Client, ClientType};
+use crate::client::ClientType;
+use crate::config::LimitsConfig;
 use crate::packet::Packet;
+use crate::plane_info::PlaneInfo;
+use crate::server::atis_broadcast;
+use crate::server::capabilities;
 use crate::server::config::ServerMessage;
-use std::collections::HashMap;
+use crate::server::diagnostics;
+use crate::server::limits::truncate_field;
+use crate::server::registry::ClientRegistry;
+use crate::weather::WeatherService;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::{broadcast, RwLock};
@@ -10,10 +17,10 @@ use tokio::sync::{broadcast, RwLock};
 pub async fn handle_request(
     packet: Packet,
     sender_addr: SocketAddr,
-    clients: &Arc<RwLock<HashMap<SocketAddr, Client>>>,
+    clients: &Arc<RwLock<ClientRegistry>>,
     broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
 ) {
-    log::debug!(
+    tracing::debug!(
         "Request from {} ({}): {} -> {}",
         sender_addr,
         packet.source,
@@ -29,7 +36,7 @@ pub async fn handle_request(
     match request_type.as_str() {
         "CAPS" => {
             // Just forward CAPS requests to the destination
-            let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(packet)));
+            let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(packet))));
         }
         "ATIS" => {
             // Handle ATIS requests
@@ -47,23 +54,46 @@ pub async fn handle_request(
             // Handle aircraft configuration request (VATSIM only)
             handle_acc_request(packet, sender_addr, clients, broadcast_tx).await;
         }
+        "IT" | "DR" => {
+            // Remember track ownership, then forward to other controllers
+            record_track(&packet, clients).await;
+            let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(packet))));
+        }
         _ => {
             // Forward other requests
-            let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(packet)));
+            let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(packet))));
         }
     }
 }
 
+/// Record a controller initiating (IT) or dropping (DR) a track
+/// $CQ(controller):@94835:IT:(callsign)
+async fn record_track(packet: &Packet, clients: &Arc<RwLock<ClientRegistry>>) {
+    let callsign = match packet.data.get(1) {
+        Some(callsign) => callsign,
+        None => return,
+    };
+
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    if let Some(mut pilot) = clients_map.named_mut(callsign) {
+        if packet.data[0] == "IT" {
+            pilot.tracking_controller = Some(packet.source.clone());
+        } else if pilot.tracking_controller.as_deref() == Some(packet.source.as_str()) {
+            pilot.tracking_controller = None;
+        }
+    };
+}
+
 /// Handle real name request
 pub async fn handle_real_name_request(
     packet: Packet,
     sender_addr: SocketAddr,
-    clients: &Arc<RwLock<HashMap<SocketAddr, Client>>>,
+    clients: &Arc<RwLock<ClientRegistry>>,
     broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
 ) {
     let clients_map = clients.read().await;
     if let Some(client) = clients_map.get(&sender_addr) {
-        if let Some(callsign) = &client.callsign {
+        if let Some(callsign) = client.callsign() {
             let real_name = client.real_name.clone().unwrap_or_default();
             let rating = client.rating.unwrap_or(0);
             let client_type = client.client_type.clone();
@@ -93,12 +123,12 @@ pub async fn handle_real_name_request(
             let response = Packet {
                 packet_type: crate::packet::PacketType::Request,
                 command: "CR".to_string(),
-                source: callsign.clone(),
+                source: callsign.to_string(),
                 destination: packet.source.clone(),
                 data: response_data,
             };
 
-            let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(response)));
+            let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(response))));
         }
     }
 }
@@ -108,60 +138,73 @@ pub async fn handle_metar_request(
     packet: Packet,
     sender_addr: SocketAddr,
     broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    weather: &WeatherService,
+    server_callsign: &str,
 ) {
     // Extract ICAO code from packet data
     // $AX(callsign):SERVER:METAR:(ICAO airport code)
     if packet.data.len() < 2 {
-        log::warn!("Invalid METAR request format from {}", sender_addr);
+        tracing::warn!("Invalid METAR request format from {}", sender_addr);
         return;
     }
 
     let icao = &packet.data[1];
-    log::info!("METAR request for {} from {}", icao, packet.source);
-
-    // For now, send a dummy METAR response
-    // In a real implementation, you would fetch actual METAR data
-    let metar_data = format!(
-        "{} 121200Z AUTO 09008KT 9999 FEW040 BKN100 15/08 Q1013 NOSIG",
-        icao
-    );
+    tracing::info!("METAR request for {} from {}", icao, packet.source);
 
-    let response = Packet {
-        packet_type: crate::packet::PacketType::Request,
-        command: "AR".to_string(),
-        source: "server".to_string(),
-        destination: packet.source.clone(),
-        data: vec!["METAR".to_string(), metar_data],
+    let response = match weather.metar(icao).await {
+        Ok(metar) => Packet {
+            packet_type: crate::packet::PacketType::Request,
+            command: "AR".to_string(),
+            source: server_callsign.to_string(),
+            destination: packet.source.clone(),
+            data: vec!["METAR".to_string(), metar],
+        },
+        Err(e) => {
+            tracing::warn!("No METAR for {}: {}", icao, e);
+            // $ER(server):(callsign):009:(ICAO):No such weather profile
+            Packet {
+                packet_type: crate::packet::PacketType::Request,
+                command: "ER".to_string(),
+                source: server_callsign.to_string(),
+                destination: packet.source.clone(),
+                data: vec![
+                    "009".to_string(),
+                    icao.to_string(),
+                    "No weather profile".to_string(),
+                ],
+            }
+        }
     };
 
-    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(response)));
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(response))));
 }
 
 /// Handle ATIS request
-/// Returns the requested callsign's voice server URL and ATIS message
+///
+/// Answers with the voice server and the ATIS the controller uploaded, or
+/// passes the request on for the controller's client to answer itself.
 pub async fn handle_atis_request(
     packet: Packet,
     sender_addr: SocketAddr,
-    clients: &Arc<RwLock<HashMap<SocketAddr, Client>>>,
+    clients: &Arc<RwLock<ClientRegistry>>,
     broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
 ) {
-    log::info!("ATIS request from {} to {}", packet.source, packet.destination);
-
-    // For now, send a sample ATIS response
-    // In a real implementation, this would be stored per-client or fetched from database
-
-    // Sample ATIS messages
-    let atis_lines = vec![
-        "London Heathrow ATIS Information Alpha",
-        "Runway 27L in use for landing",
-        "Runway 27R in use for departure",
-        "Wind 270 at 8 knots",
-        "Visibility 10km",
-        "Cloud scattered at 4000ft",
-        "Temperature 15 Celsius",
-        "QNH 1013",
-        "Advise on first contact you have information Alpha",
-    ];
+    tracing::info!(
+        "ATIS request from {} to {}",
+        packet.source,
+        packet.destination
+    );
+
+    let atis_lines = clients
+        .read()
+        .await
+        .named(&packet.destination)
+        .map(|(_, client)| client.atis.clone())
+        .unwrap_or_default();
+    if atis_lines.is_empty() {
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(packet))));
+        return;
+    }
 
     // Send voice server URL
     let voice_response = Packet {
@@ -175,7 +218,7 @@ pub async fn handle_atis_request(
             "voice.vatsim.net/uk".to_string(),
         ],
     };
-    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(voice_response)));
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(voice_response))));
 
     // Send ATIS text lines
     for line in &atis_lines {
@@ -184,13 +227,9 @@ pub async fn handle_atis_request(
             command: "CR".to_string(),
             source: packet.destination.clone(),
             destination: packet.source.clone(),
-            data: vec![
-                "ATIS".to_string(),
-                "T".to_string(),
-                line.to_string(),
-            ],
+            data: vec!["ATIS".to_string(), "T".to_string(), line.to_string()],
         };
-        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(text_response)));
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(text_response))));
     }
 
     // Send end marker with line count
@@ -205,7 +244,74 @@ pub async fn handle_atis_request(
             (atis_lines.len() + 2).to_string(), // +2 for voice and end lines
         ],
     };
-    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(end_response)));
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(end_response))));
+}
+
+/// Keep the ATIS a controller uploads
+///
+/// $CR(callsign):SERVER:ATIS:T:(line) for each line, then
+/// $CR(callsign):SERVER:ATIS:E:(line count) to replace the stored ATIS,
+/// with a trailing `NOBROADCAST` to keep it from being rebroadcast
+///
+/// Lines are cut to `max_field_bytes`, and lines past `max_atis_lines` or
+/// the client's memory cap are dropped.
+async fn store_atis(
+    packet: &Packet,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    limits: &LimitsConfig,
+) {
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    let Some(mut client) = clients_map.get_mut(&sender_addr) else {
+        return;
+    };
+    if client.client_type != Some(ClientType::Atc) {
+        return;
+    }
+
+    match (packet.data.get(1).map(String::as_str), packet.data.get(2)) {
+        (Some("T"), Some(line)) => {
+            if client.atis_upload.len() >= limits.max_atis_lines {
+                tracing::warn!(
+                    "Dropping ATIS line from {}: more than {} lines",
+                    packet.source,
+                    limits.max_atis_lines
+                );
+                return;
+            }
+            let mut line = line.clone();
+            if truncate_field(&mut line, limits.max_field_bytes) {
+                tracing::debug!("Cut an ATIS line from {} short", packet.source);
+            }
+            if client.memory_estimate() + line.len() > limits.max_client_bytes {
+                tracing::warn!(
+                    "Dropping ATIS line from {}: over {} bytes stored",
+                    packet.source,
+                    limits.max_client_bytes
+                );
+                return;
+            }
+            client.atis_upload.push(line);
+        }
+        (Some("E"), _) => {
+            let atis = std::mem::take(&mut client.atis_upload);
+            if atis != client.atis {
+                client.atis_broadcast_at = None;
+            }
+            client.atis = atis;
+            client.atis_updated_at = Some(chrono::Utc::now());
+            client.atis_no_rebroadcast = packet
+                .data
+                .get(3)
+                .is_some_and(|flag| flag.eq_ignore_ascii_case(atis_broadcast::OPT_OUT));
+            tracing::info!(
+                "{} updated their ATIS ({} lines)",
+                packet.source,
+                client.atis.len()
+            );
+        }
+        _ => {}
+    }
 }
 
 /// Handle system information request (INF)
@@ -213,26 +319,19 @@ pub async fn handle_atis_request(
 pub async fn handle_inf_request(
     packet: Packet,
     sender_addr: SocketAddr,
-    clients: &Arc<RwLock<HashMap<SocketAddr, Client>>>,
+    clients: &Arc<RwLock<ClientRegistry>>,
     broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
 ) {
-    log::info!("System information request from {} to {}", packet.source, packet.destination);
+    tracing::info!(
+        "System information request from {} to {}",
+        packet.source,
+        packet.destination
+    );
 
     // Find the target client
     let target_callsign = &packet.destination;
     let clients_map = clients.read().await;
-
-    let mut found_client = None;
-    for (addr, client) in clients_map.iter() {
-        if let Some(callsign) = &client.callsign {
-            if callsign == target_callsign {
-                found_client = Some((addr, client));
-                break;
-            }
-        }
-    }
-
-    if let Some((client_addr, client)) = found_client {
+    if let Some((client_addr, client)) = clients_map.named(target_callsign) {
         let client_string = client.client_string.clone().unwrap_or_default();
         let real_name = client.real_name.clone().unwrap_or_default();
         let network_id = client.network_id.clone().unwrap_or_default();
@@ -245,10 +344,14 @@ pub async fn handle_inf_request(
             network_id,
             real_name,
             client_addr.ip(),
-            client.client_type.as_ref().map(|t| match t {
-                ClientType::Atc => "",
-                _ => "Prepar3dV3",
-            }).unwrap_or("")
+            client
+                .client_type
+                .as_ref()
+                .map(|t| match t {
+                    ClientType::Atc => "",
+                    _ => "Prepar3dV3",
+                })
+                .unwrap_or("")
         );
 
         let response = Packet {
@@ -259,9 +362,12 @@ pub async fn handle_inf_request(
             data: vec![inf_response],
         };
 
-        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(response)));
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(response))));
     } else {
-        log::warn!("System information request for unknown client: {}", target_callsign);
+        tracing::warn!(
+            "System information request for unknown client: {}",
+            target_callsign
+        );
     }
 }
 
@@ -269,9 +375,12 @@ pub async fn handle_inf_request(
 pub async fn handle_response(
     packet: Packet,
     sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
     broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    limits: &LimitsConfig,
+    server_callsign: &str,
 ) {
-    log::debug!(
+    tracing::debug!(
         "Response from {} ({}): {} -> {}",
         sender_addr,
         packet.source,
@@ -279,8 +388,99 @@ pub async fn handle_response(
         packet.destination
     );
 
+    let to_server = packet.destination.eq_ignore_ascii_case("SERVER")
+        || packet.destination.eq_ignore_ascii_case(server_callsign);
+    match packet.data.first().map(String::as_str) {
+        Some("ATIS") if to_server => {
+            store_atis(&packet, sender_addr, clients, limits).await;
+            return;
+        }
+        Some("CAPS") if to_server => {
+            let caps = capabilities::parse(&packet.data[1..]);
+            tracing::debug!("{} supports {:?}", packet.source, caps);
+            let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+            if let Some(mut client) = clients_map.get_mut(&sender_addr) {
+                client.capabilities = caps;
+            }
+            return;
+        }
+        _ => {}
+    }
+
     // Broadcast response to all clients
-    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(packet)));
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(packet))));
+}
+
+/// Record what a pilot says it is flying in a `#SB ... PI:GEN` reply
+///
+/// The reply itself is forwarded like any `#SB`. Each field is cut to
+/// `max_field_bytes`, and one that would take the client past its memory
+/// cap is dropped.
+pub async fn handle_plane_info(
+    packet: &Packet,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    limits: &LimitsConfig,
+) {
+    let Some(mut info) = PlaneInfo::from_packet(packet) else {
+        return;
+    };
+    for field in info.fields_mut() {
+        truncate_field(field, limits.max_field_bytes);
+    }
+
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    let Some(mut client) = clients_map.get_mut(&sender_addr) else {
+        return;
+    };
+    if client.client_type != Some(ClientType::Pilot) {
+        return;
+    }
+    let stored = client.plane_info.as_ref().map_or(0, PlaneInfo::text_len);
+    if client.memory_estimate() - stored + info.text_len() > limits.max_client_bytes {
+        tracing::warn!(
+            "Not storing the aircraft of {}: over {} bytes stored",
+            packet.source,
+            limits.max_client_bytes
+        );
+        return;
+    }
+    tracing::debug!("{} is flying {:?}", packet.source, info);
+    client.plane_info = Some(info);
+}
+
+/// Handle a handoff offer ($HO) or acceptance ($HA)
+///
+/// $HO(from):(to):(callsign) offers the aircraft to another controller and
+/// $HA(from):(to):(callsign) accepts it, making the sender the tracking
+/// controller. Both go on to the other controller.
+pub async fn handle_handoff(
+    packet: Packet,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+) {
+    tracing::debug!(
+        "Handoff {} from {} to {}: {:?}",
+        packet.command,
+        packet.source,
+        packet.destination,
+        packet.data
+    );
+
+    if packet.command == "HA" {
+        if let Some(callsign) = packet.data.first() {
+            let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+            let is_controller = clients_map
+                .get(&sender_addr)
+                .is_some_and(|client| client.client_type == Some(ClientType::Atc));
+            if let Some(mut pilot) = clients_map.named_mut(callsign).filter(|_| is_controller) {
+                pilot.tracking_controller = Some(packet.source.clone());
+            };
+        }
+    }
+
+    let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(Arc::new(packet))));
 }
 
 /// Handle aircraft configuration request (ACC) - VATSIM only
@@ -288,26 +488,19 @@ pub async fn handle_response(
 pub async fn handle_acc_request(
     packet: Packet,
     sender_addr: SocketAddr,
-    clients: &Arc<RwLock<HashMap<SocketAddr, Client>>>,
+    clients: &Arc<RwLock<ClientRegistry>>,
     broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
 ) {
-    log::info!("Aircraft configuration request from {} to {}", packet.source, packet.destination);
+    tracing::info!(
+        "Aircraft configuration request from {} to {}",
+        packet.source,
+        packet.destination
+    );
 
     // Find the target client
     let target_callsign = &packet.destination;
     let clients_map = clients.read().await;
-
-    let mut found_client = None;
-    for (_addr, client) in clients_map.iter() {
-        if let Some(callsign) = &client.callsign {
-            if callsign == target_callsign {
-                found_client = Some(client);
-                break;
-            }
-        }
-    }
-
-    if let Some(client) = found_client {
+    if let Some((_, client)) = clients_map.named(target_callsign) {
         // Generate sample aircraft configuration data in JSON format
         // In a real implementation, this would be collected from the client
         let acc_response = r#"{
@@ -345,8 +538,233 @@ pub async fn handle_acc_request(
             data: vec!["ACC".to_string(), acc_response.to_string()],
         };
 
-        let _ = broadcast_tx.send((sender_addr, ServerMessage::Packet(response)));
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(response))));
     } else {
-        log::warn!("ACC request for unknown client: {}", target_callsign);
+        tracing::warn!("ACC request for unknown client: {}", target_callsign);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientState};
+
+    const PILOT_ADDR: &str = "127.0.0.1:50001";
+    const ATC_ADDR: &str = "127.0.0.1:50002";
+
+    fn setup() -> Arc<RwLock<ClientRegistry>> {
+        let mut pilot = Client::new(PILOT_ADDR.parse().unwrap());
+        pilot.state = ClientState::Active;
+        pilot.callsign = Some("CCA1501".into());
+        pilot.client_type = Some(ClientType::Pilot);
+        pilot.tracking_controller = Some("ZSHA_CTR".to_string());
+
+        let mut atc = Client::new(ATC_ADDR.parse().unwrap());
+        atc.state = ClientState::Active;
+        atc.callsign = Some("ZSPD_APP".into());
+        atc.client_type = Some(ClientType::Atc);
+
+        Arc::new(RwLock::new(ClientRegistry::from([
+            (pilot.addr, pilot),
+            (atc.addr, atc),
+        ])))
+    }
+
+    fn drain(rx: &mut broadcast::Receiver<(SocketAddr, ServerMessage)>) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        while let Ok((_, msg)) = rx.try_recv() {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_atis_upload_and_request() {
+        let clients = setup();
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+        let pilot_addr: SocketAddr = PILOT_ADDR.parse().unwrap();
+        let request = Packet::parse("$CQCCA1501:ZSPD_APP:ATIS").unwrap();
+
+        // Without an uploaded ATIS the controller's client answers
+        handle_request(request.clone(), pilot_addr, &clients, &broadcast_tx).await;
+        assert!(matches!(&drain(&mut rx)[..], [ServerMessage::Packet(p)] if p.command == "CQ"));
+
+        for raw in [
+            "$CRZSPD_APP:SERVER:ATIS:T:Pudong Information Kilo",
+            "$CRZSPD_APP:SERVER:ATIS:T:Runway 34L",
+            "$CRZSPD_APP:SERVER:ATIS:E:2",
+        ] {
+            let packet = Packet::parse(raw).unwrap();
+            handle_response(
+                packet,
+                ATC_ADDR.parse().unwrap(),
+                &clients,
+                &broadcast_tx,
+                &LimitsConfig::default(),
+                "SERVER",
+            )
+            .await;
+        }
+        assert!(drain(&mut rx).is_empty());
+
+        handle_request(request, pilot_addr, &clients, &broadcast_tx).await;
+        let replies: Vec<_> = drain(&mut rx)
+            .into_iter()
+            .map(|msg| match msg {
+                ServerMessage::Direct(packet) => packet.to_string(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            replies[1..],
+            [
+                "$CRZSPD_APP:CCA1501:ATIS:T:Pudong Information Kilo",
+                "$CRZSPD_APP:CCA1501:ATIS:T:Runway 34L",
+                "$CRZSPD_APP:CCA1501:ATIS:E:4",
+            ]
+        );
+
+        // Pilots can't upload an ATIS
+        let packet = Packet::parse("$CRCCA1501:SERVER:ATIS:E:0").unwrap();
+        handle_response(
+            packet,
+            pilot_addr,
+            &clients,
+            &broadcast_tx,
+            &LimitsConfig::default(),
+            "SERVER",
+        )
+        .await;
+        assert!(clients.read().await[&pilot_addr].atis.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_atis_upload_limits() {
+        let clients = setup();
+        let (broadcast_tx, _rx) = broadcast::channel(16);
+        let atc_addr: SocketAddr = ATC_ADDR.parse().unwrap();
+        let limits = LimitsConfig {
+            max_field_bytes: 10,
+            max_atis_lines: 3,
+            ..LimitsConfig::default()
+        };
+        let upload =
+            |line: &str| Packet::parse(&format!("$CRZSPD_APP:SERVER:ATIS:{}", line)).unwrap();
+
+        for line in ["T:Pudong Information Kilo", "T:A", "T:B", "T:C", "E:4"] {
+            handle_response(
+                upload(line),
+                atc_addr,
+                &clients,
+                &broadcast_tx,
+                &limits,
+                "SERVER",
+            )
+            .await;
+        }
+        assert_eq!(
+            clients.read().await[&atc_addr].atis,
+            ["Pudong Inf", "A", "B"]
+        );
+
+        // Nothing more once the client's memory cap is reached
+        let cap = clients.read().await[&atc_addr].memory_estimate() + 1;
+        let limits = LimitsConfig {
+            max_client_bytes: cap,
+            ..LimitsConfig::default()
+        };
+        for line in ["T:A", "T:Runway 34L", "E:2"] {
+            handle_response(
+                upload(line),
+                atc_addr,
+                &clients,
+                &broadcast_tx,
+                &limits,
+                "SERVER",
+            )
+            .await;
+        }
+        assert_eq!(clients.read().await[&atc_addr].atis, ["A"]);
+        assert!(clients.read().await[&atc_addr].memory_estimate() <= cap);
+    }
+
+    #[tokio::test]
+    async fn test_plane_info_is_recorded() {
+        let clients = setup();
+        let pilot_addr: SocketAddr = PILOT_ADDR.parse().unwrap();
+        let limits = LimitsConfig {
+            max_field_bytes: 6,
+            ..LimitsConfig::default()
+        };
+        let reply = |line: &str| Packet::parse(line).unwrap();
+
+        handle_plane_info(
+            &reply("#SBCCA1501:AFR44:PI:GEN:EQUIPMENT=A21N:AIRLINE=CCA:LIVERY=B-30EE-CCA"),
+            pilot_addr,
+            &clients,
+            &limits,
+        )
+        .await;
+        let info = clients.read().await[&pilot_addr]
+            .plane_info
+            .clone()
+            .unwrap();
+        assert_eq!(info.equipment.as_deref(), Some("A21N"));
+        assert_eq!(info.livery.as_deref(), Some("B-30EE"));
+        assert_eq!(
+            clients.read().await[&pilot_addr].aircraft_type(),
+            Some("A21N")
+        );
+
+        // Requests and unreadable replies leave it alone
+        for line in ["#SBCCA1501:AFR44:PI", "#SBCCA1501:AFR44:PI:GEN:EQUIPMENT"] {
+            handle_plane_info(&reply(line), pilot_addr, &clients, &limits).await;
+        }
+        assert_eq!(clients.read().await[&pilot_addr].plane_info, Some(info));
+
+        // Controllers don't fly anything
+        let atc_addr: SocketAddr = ATC_ADDR.parse().unwrap();
+        let line = "#SBZSPD_APP:AFR44:PI:GEN:EQUIPMENT=B738";
+        handle_plane_info(&reply(line), atc_addr, &clients, &limits).await;
+        assert_eq!(clients.read().await[&atc_addr].plane_info, None);
+    }
+
+    #[tokio::test]
+    async fn test_handoff_accept_moves_track() {
+        let clients = setup();
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+        let pilot_addr: SocketAddr = PILOT_ADDR.parse().unwrap();
+
+        let offer = Packet::parse("$HOZSHA_CTR:ZSPD_APP:CCA1501").unwrap();
+        handle_handoff(
+            offer,
+            "127.0.0.1:50003".parse().unwrap(),
+            &clients,
+            &broadcast_tx,
+        )
+        .await;
+        assert_eq!(
+            clients.read().await[&pilot_addr]
+                .tracking_controller
+                .as_deref(),
+            Some("ZSHA_CTR")
+        );
+
+        let accept = Packet::parse("$HAZSPD_APP:ZSHA_CTR:CCA1501").unwrap();
+        handle_handoff(accept.clone(), pilot_addr, &clients, &broadcast_tx).await;
+        assert_eq!(
+            clients.read().await[&pilot_addr]
+                .tracking_controller
+                .as_deref(),
+            Some("ZSHA_CTR")
+        );
+        handle_handoff(accept, ATC_ADDR.parse().unwrap(), &clients, &broadcast_tx).await;
+        assert_eq!(
+            clients.read().await[&pilot_addr]
+                .tracking_controller
+                .as_deref(),
+            Some("ZSPD_APP")
+        );
+        assert_eq!(drain(&mut rx).len(), 3);
     }
 }
@@ -0,0 +1,86 @@
+//! Liveness and drain state, for the health endpoints and the listeners
+//!
+//! The accept loop beats every [`BEAT_INTERVAL`] even while no one connects;
+//! if it misses beats for [`LIVENESS_TIMEOUT`] it is considered stuck. A
+//! draining server keeps its clients but refuses new connections.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often the accept loop reports that it is alive
+pub const BEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the accept loop may go without a beat before it counts as stuck
+pub const LIVENESS_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Health {
+    started: Instant,
+    /// Milliseconds after `started` of the accept loop's last beat
+    last_beat_ms: AtomicU64,
+    draining: AtomicBool,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_beat_ms: AtomicU64::new(0),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Record that the accept loop is running
+    pub fn beat(&self) {
+        self.last_beat_ms
+            .store(self.millis_since_start(Instant::now()), Ordering::Relaxed);
+    }
+
+    /// Time since the last beat
+    pub fn since_beat(&self, now: Instant) -> Duration {
+        let last = self.last_beat_ms.load(Ordering::Relaxed);
+        Duration::from_millis(self.millis_since_start(now).saturating_sub(last))
+    }
+
+    pub fn is_alive(&self, now: Instant) -> bool {
+        self.since_beat(now) < LIVENESS_TIMEOUT
+    }
+
+    /// Stop admitting new connections; returns false if already draining
+    pub fn start_draining(&self) -> bool {
+        !self.draining.swap(true, Ordering::Relaxed)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    fn millis_since_start(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_millis() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_liveness() {
+        let health = Health::new();
+        health.beat();
+        let now = Instant::now();
+        assert!(health.is_alive(now));
+        assert!(!health.is_alive(now + LIVENESS_TIMEOUT));
+        assert!(health.since_beat(now + Duration::from_secs(10)) >= Duration::from_secs(10));
+
+        assert!(!health.is_draining());
+        assert!(health.start_draining());
+        assert!(!health.start_draining());
+        assert!(health.is_draining());
+    }
+}
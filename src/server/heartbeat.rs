@@ -0,0 +1,109 @@
+use crate::config::{HeartbeatConfig, HeartbeatStyle};
+use crate::packet::{Packet, PacketType};
+use crate::server::clock::Clock;
+use crate::server::config::{ServerMessage, NO_CLIENT};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+
+/// Keepalive packet sent to every client, or `None` when disabled
+pub fn heartbeat_packet(config: &HeartbeatConfig) -> Option<Packet> {
+    if !config.enabled {
+        return None;
+    }
+
+    let (command, data) = match config.style {
+        HeartbeatStyle::Dl => ("DL", vec!["0".to_string(), "0".to_string()]),
+        HeartbeatStyle::Tm => ("TM", vec![String::new()]),
+    };
+    Some(Packet {
+        packet_type: PacketType::Client,
+        command: command.to_string(),
+        source: config.source.clone(),
+        destination: "*".to_string(),
+        data,
+    })
+}
+
+/// Send heartbeats until the config sender is dropped
+///
+/// Settings are re-read whenever `config` changes, so a reload takes effect
+/// without waiting for the current interval to pass.
+pub async fn run(
+    mut config: watch::Receiver<HeartbeatConfig>,
+    broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+    clock: Arc<dyn Clock>,
+) {
+    loop {
+        let current = config.borrow_and_update().clone();
+        let packet = heartbeat_packet(&current);
+
+        let wait = async {
+            match packet {
+                Some(_) => {
+                    clock
+                        .sleep(Duration::from_secs(current.interval_secs))
+                        .await
+                }
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = wait => {
+                if let Some(packet) = packet {
+                    let _ = broadcast_tx.send((NO_CLIENT, ServerMessage::Broadcast(Arc::new(packet))));
+                }
+            }
+            changed = config.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::clock::SystemClock;
+
+    #[test]
+    fn test_packet_styles() {
+        let mut config = HeartbeatConfig::default();
+        assert_eq!(
+            heartbeat_packet(&config).unwrap().to_string(),
+            "#DLSERVER:*:0:0"
+        );
+
+        config.style = HeartbeatStyle::Tm;
+        config.source = "OPENFSD".to_string();
+        assert_eq!(
+            heartbeat_packet(&config).unwrap().to_string(),
+            "#TMOPENFSD:*:"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_disabled_heartbeat_sends_nothing() {
+        let (config_tx, config_rx) = watch::channel(HeartbeatConfig {
+            enabled: false,
+            interval_secs: 1,
+            ..HeartbeatConfig::default()
+        });
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+        tokio::spawn(run(config_rx, broadcast_tx, Arc::new(SystemClock)));
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert!(rx.try_recv().is_err());
+
+        // Enabling it on reload starts the heartbeat
+        config_tx.send_modify(|config| config.enabled = true);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        match rx.try_recv() {
+            Ok((_, ServerMessage::Broadcast(packet))) => assert_eq!(packet.command, "DL"),
+            other => panic!("expected a heartbeat, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! Disconnecting clients that went quiet
+//!
+//! What counts as quiet depends on the client. Pilots and controllers send
+//! a position every few seconds, so one that stops for longer than the
+//! `[idle]` timeout for its type has most likely gone. Observers may send
+//! nothing but the odd request for a long time, so they are only judged on
+//! whether they sent anything at all, over a much longer timeout. ATIS
+//! connections are left alone. Every [`CHECK_INTERVAL`], [`run`] tells the
+//! clients found idle why and disconnects them.
+
+use crate::client::{Client, ClientType};
+use crate::config::IdleConfig;
+use crate::packet::{Packet, PacketType};
+use crate::server::clock::Clock;
+use crate::server::config::ServerMessage;
+use crate::server::registry::ClientRegistry;
+use crate::webhooks::{Event, Webhooks};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+
+/// How often idle clients are looked for
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How a client is judged idle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Idle after this long without a position update
+    Position(Duration),
+    /// Idle after this long without sending anything
+    Inactivity(Duration),
+    /// Never disconnected for being quiet
+    Exempt,
+}
+
+impl Policy {
+    /// Why a client was disconnected under this policy
+    fn reason(self) -> String {
+        match self {
+            Self::Position(timeout) => format!(
+                "no position update for {} minutes",
+                timeout.as_secs().div_ceil(60)
+            ),
+            Self::Inactivity(timeout) => {
+                format!("inactive for {} minutes", timeout.as_secs().div_ceil(60))
+            }
+            Self::Exempt => String::new(),
+        }
+    }
+}
+
+/// The policy for `client`: ATIS connections are exempt, observers are
+/// judged on anything they send, pilots and other controllers on their
+/// position updates
+pub fn policy(client: &Client, config: &IdleConfig) -> Policy {
+    let (policy, secs): (fn(Duration) -> Policy, u64) = if client.is_atis() {
+        return Policy::Exempt;
+    } else if client.is_observer() {
+        (Policy::Inactivity, config.observer_timeout_secs)
+    } else {
+        match client.client_type {
+            Some(ClientType::Pilot) => (Policy::Position, config.pilot_position_timeout_secs),
+            Some(ClientType::Atc) => (Policy::Position, config.atc_position_timeout_secs),
+            _ => return Policy::Exempt,
+        }
+    };
+    match secs {
+        0 => Policy::Exempt,
+        secs => policy(Duration::from_secs(secs)),
+    }
+}
+
+/// Whether a client judged by `policy` is idle at `now`, given when it last
+/// sent a position and when it last sent anything
+pub fn is_idle(
+    policy: Policy,
+    last_position_at: Instant,
+    last_packet_at: Instant,
+    now: Instant,
+) -> bool {
+    match policy {
+        Policy::Position(timeout) => now.saturating_duration_since(last_position_at) >= timeout,
+        Policy::Inactivity(timeout) => now.saturating_duration_since(last_packet_at) >= timeout,
+        Policy::Exempt => false,
+    }
+}
+
+/// When each client was last seen sending anything, as far as the sweeps
+/// can tell from its packet count
+#[derive(Debug, Default)]
+pub struct Activity(HashMap<SocketAddr, (u64, Instant)>);
+
+impl Activity {
+    /// Note the client's packet count at `now`, returning when it last
+    /// changed
+    fn last_packet_at(&mut self, addr: SocketAddr, packets: u64, now: Instant) -> Instant {
+        let seen = self.0.entry(addr).or_insert((packets, now));
+        if seen.0 != packets {
+            *seen = (packets, now);
+        }
+        seen.1
+    }
+}
+
+/// Disconnect quiet clients until the server stops
+pub async fn run(
+    clients: Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+    config: IdleConfig,
+    server_callsign: String,
+    webhooks: Arc<Webhooks>,
+    clock: Arc<dyn Clock>,
+) {
+    let mut activity = Activity::default();
+    let mut ticks = clock.interval(CHECK_INTERVAL);
+    loop {
+        ticks.tick().await;
+        sweep(
+            &clients,
+            &broadcast_tx,
+            &config,
+            &server_callsign,
+            &webhooks,
+            &mut activity,
+            clock.now(),
+        )
+        .await;
+    }
+}
+
+/// Disconnect the logged-in clients that are idle at `now`, returning how
+/// many
+pub async fn sweep(
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    config: &IdleConfig,
+    server_callsign: &str,
+    webhooks: &Webhooks,
+    activity: &mut Activity,
+    now: Instant,
+) -> usize {
+    let clients_map = clients.read().await;
+    activity.0.retain(|addr, _| clients_map.contains_key(addr));
+
+    let mut idle = Vec::new();
+    for (addr, client) in clients_map.iter() {
+        let (Some(callsign), Some(logged_in_at)) = (client.callsign(), client.logged_in_at) else {
+            continue;
+        };
+        let packets = client.counters.packets_in.load(Ordering::Relaxed);
+        let last_packet_at = activity.last_packet_at(*addr, packets, now);
+        let last_position_at = client.last_position_at.unwrap_or(logged_in_at);
+        let policy = policy(client, config);
+        if client.is_active() && is_idle(policy, last_position_at, last_packet_at, now) {
+            idle.push((*addr, callsign.to_string(), policy.reason()));
+        }
+    }
+    drop(clients_map);
+
+    for (addr, callsign, reason) in &idle {
+        tracing::info!("Disconnecting {}: {}", callsign, reason);
+        let notice = Packet {
+            packet_type: PacketType::Client,
+            command: "TM".to_string(),
+            source: server_callsign.to_string(),
+            destination: callsign.clone(),
+            data: vec![format!("Disconnected: {}", reason)],
+        };
+        let _ = broadcast_tx.send((*addr, ServerMessage::Direct(Arc::new(notice))));
+        let _ = broadcast_tx.send((*addr, ServerMessage::Disconnect("idle")));
+        crate::metrics::kick("idle");
+        webhooks.emit(&Event::client_kicked(callsign, reason));
+    }
+    idle.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Facility;
+    use crate::server::clock::TestClock;
+
+    #[test]
+    fn test_policy_by_type() {
+        let config = IdleConfig::default();
+        let minutes = |minutes: u64| Duration::from_secs(minutes * 60);
+
+        let pilot = Client::active_for_tests(([127, 0, 0, 1], 50001), "CCA1501", ClientType::Pilot);
+        assert_eq!(policy(&pilot, &config), Policy::Position(minutes(5)));
+        let controller =
+            Client::active_for_tests(([127, 0, 0, 1], 50002), "ZSPD_APP", ClientType::Atc);
+        assert_eq!(policy(&controller, &config), Policy::Position(minutes(5)));
+
+        // Observers however they are recognized
+        let observer =
+            Client::active_for_tests(([127, 0, 0, 1], 50003), "ZSPD_OBS", ClientType::Observer);
+        assert_eq!(policy(&observer, &config), Policy::Inactivity(minutes(240)));
+        let suffixed =
+            Client::active_for_tests(([127, 0, 0, 1], 50004), "ZSPD_OBS", ClientType::Atc);
+        assert_eq!(policy(&suffixed, &config), Policy::Inactivity(minutes(240)));
+        let mut facility =
+            Client::active_for_tests(([127, 0, 0, 1], 50005), "ZSPD_M_CTR", ClientType::Atc);
+        facility.facility = Some(Facility::Observer);
+        assert_eq!(policy(&facility, &config), Policy::Inactivity(minutes(240)));
+
+        let atis = Client::active_for_tests(([127, 0, 0, 1], 50006), "ZSPD_ATIS", ClientType::Atc);
+        assert_eq!(policy(&atis, &config), Policy::Exempt);
+        // A pilot can't dodge the timeout with an ATIS-like callsign
+        let pilot_atis =
+            Client::active_for_tests(([127, 0, 0, 1], 50007), "CCA_ATIS", ClientType::Pilot);
+        assert_eq!(policy(&pilot_atis, &config), Policy::Position(minutes(5)));
+    }
+
+    #[test]
+    fn test_zero_timeout_exempts() {
+        let config = IdleConfig {
+            pilot_position_timeout_secs: 0,
+            observer_timeout_secs: 0,
+            ..IdleConfig::default()
+        };
+        let pilot = Client::active_for_tests(([127, 0, 0, 1], 50001), "CCA1501", ClientType::Pilot);
+        assert_eq!(policy(&pilot, &config), Policy::Exempt);
+        let observer =
+            Client::active_for_tests(([127, 0, 0, 1], 50003), "ZSPD_OBS", ClientType::Observer);
+        assert_eq!(policy(&observer, &config), Policy::Exempt);
+        let controller =
+            Client::active_for_tests(([127, 0, 0, 1], 50002), "ZSPD_APP", ClientType::Atc);
+        assert_ne!(policy(&controller, &config), Policy::Exempt);
+    }
+
+    #[test]
+    fn test_is_idle() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let timeout = Duration::from_secs(300);
+
+        // Chatting doesn't keep a pilot on without positions
+        assert!(!is_idle(Policy::Position(timeout), start, at(290), at(299)));
+        assert!(is_idle(Policy::Position(timeout), start, at(290), at(300)));
+        // Anything keeps an observer on
+        assert!(!is_idle(
+            Policy::Inactivity(timeout),
+            start,
+            at(290),
+            at(300)
+        ));
+        assert!(is_idle(
+            Policy::Inactivity(timeout),
+            start,
+            at(290),
+            at(590)
+        ));
+        assert!(!is_idle(Policy::Exempt, start, start, at(86400)));
+    }
+
+    /// Addresses disconnected so far
+    fn disconnected(rx: &mut broadcast::Receiver<(SocketAddr, ServerMessage)>) -> Vec<u16> {
+        let mut ports: Vec<u16> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter(|(_, msg)| matches!(msg, ServerMessage::Disconnect("idle")))
+            .map(|(addr, _)| addr.port())
+            .collect();
+        ports.sort();
+        ports.dedup();
+        ports
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_observer_outlives_silent_pilot() {
+        let logged_in_at = tokio::time::Instant::now().into_std();
+        let mut clients = [
+            Client::active_for_tests(([127, 0, 0, 1], 50001), "CCA1501", ClientType::Pilot),
+            Client::active_for_tests(([127, 0, 0, 1], 50002), "ZSPD_OBS", ClientType::Observer),
+            Client::active_for_tests(([127, 0, 0, 1], 50003), "ZSPD_ATIS", ClientType::Atc),
+            Client::active_for_tests(([127, 0, 0, 1], 50004), "ZSSS_OBS", ClientType::Observer),
+        ];
+        for client in &mut clients {
+            client.logged_in_at = Some(logged_in_at);
+        }
+        let chatty = clients[3].counters.clone();
+        let clients = Arc::new(RwLock::new(ClientRegistry::from_iter(
+            clients.map(|client| (client.addr, client)),
+        )));
+        let (broadcast_tx, mut rx) = broadcast::channel(64);
+        tokio::spawn(run(
+            clients.clone(),
+            broadcast_tx.clone(),
+            IdleConfig::default(),
+            "SERVER".to_string(),
+            Arc::default(),
+            Arc::new(TestClock::default()),
+        ));
+        // One observer sends a request now and then
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(30 * 60)).await;
+                chatty.received(20);
+            }
+        });
+
+        // The pilot goes once it has sent no position for five minutes
+        tokio::time::sleep(Duration::from_secs(290)).await;
+        assert!(disconnected(&mut rx).is_empty());
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        assert_eq!(disconnected(&mut rx), [50001]);
+        clients
+            .write()
+            .await
+            .remove(&SocketAddr::from(([127, 0, 0, 1], 50001)));
+
+        // The silent observer lasts four hours, the other one and the ATIS
+        // stay on
+        tokio::time::sleep(Duration::from_secs(4 * 3600 - 330)).await;
+        assert!(disconnected(&mut rx).is_empty());
+        tokio::time::sleep(CHECK_INTERVAL * 2).await;
+        assert_eq!(disconnected(&mut rx), [50002]);
+        clients
+            .write()
+            .await
+            .remove(&SocketAddr::from(([127, 0, 0, 1], 50002)));
+        tokio::time::sleep(Duration::from_secs(24 * 3600)).await;
+        assert!(disconnected(&mut rx).is_empty());
+    }
+}
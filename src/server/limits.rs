@@ -0,0 +1,358 @@
+use crate::config::LimitsConfig;
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "http")]
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Allows `rate` events per second on average and bursts of up to `capacity`
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, capacity: u32, now: Instant) -> Self {
+        Self {
+            capacity: capacity as f64,
+            rate: rate as f64,
+            tokens: capacity as f64,
+            updated_at: now,
+        }
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated_at = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Allows at most `limit` events in any `window`
+#[derive(Debug)]
+pub struct SlidingWindow {
+    limit: usize,
+    window: Duration,
+    events: VecDeque<Instant>,
+}
+
+impl SlidingWindow {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit: limit as usize,
+            window,
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn try_record(&mut self, now: Instant) -> bool {
+        while let Some(oldest) = self.events.front() {
+            if now.saturating_duration_since(*oldest) < self.window {
+                break;
+            }
+            self.events.pop_front();
+        }
+        if self.events.len() >= self.limit {
+            return false;
+        }
+        self.events.push_back(now);
+        true
+    }
+}
+
+/// What to do about a packet that didn't parse
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorAction {
+    /// Answer with a syntax error so the client can correct itself
+    Reply,
+    /// Only log it; the client has been told often enough
+    Ignore,
+    /// The connection has used up its parse error budget
+    Disconnect,
+}
+
+/// Flood protection for a single connection
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    packets: TokenBucket,
+    text_messages: SlidingWindow,
+    parse_errors: u32,
+    max_parse_errors: u32,
+    parse_error_rate: SlidingWindow,
+    parse_error_replies: u32,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: &LimitsConfig) -> Self {
+        Self {
+            packets: TokenBucket::new(limits.packets_per_second, limits.burst, Instant::now()),
+            text_messages: SlidingWindow::new(
+                limits.text_messages_per_minute,
+                Duration::from_secs(60),
+            ),
+            parse_errors: 0,
+            max_parse_errors: limits.max_parse_errors,
+            parse_error_rate: SlidingWindow::new(
+                limits.parse_errors_per_second,
+                Duration::from_secs(1),
+            ),
+            parse_error_replies: limits.parse_error_replies,
+        }
+    }
+
+    /// Whether another packet fits the rate; clients over it are flooding
+    pub fn allow_packet(&mut self, now: Instant) -> bool {
+        self.packets.try_take(now)
+    }
+
+    pub fn allow_text_message(&mut self, now: Instant) -> bool {
+        self.text_messages.try_record(now)
+    }
+
+    /// Count a malformed packet against the connection's budget
+    pub fn record_parse_error(&mut self, now: Instant) -> ParseErrorAction {
+        self.parse_errors += 1;
+        let within_rate = self.parse_error_rate.try_record(now);
+        if self.parse_errors > self.max_parse_errors || !within_rate {
+            ParseErrorAction::Disconnect
+        } else if self.parse_errors <= self.parse_error_replies {
+            ParseErrorAction::Reply
+        } else {
+            ParseErrorAction::Ignore
+        }
+    }
+}
+
+/// Cut `value` down to at most `max` bytes, on a character boundary;
+/// returns whether anything was cut
+pub fn truncate_field(value: &mut String, max: usize) -> bool {
+    if value.len() <= max {
+        return false;
+    }
+    let mut end = max;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value.truncate(end);
+    true
+}
+
+#[derive(Debug, Default)]
+struct LoginFailures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Locks network IDs out after repeated failed logins
+#[derive(Debug)]
+pub struct LoginThrottle {
+    failures_before_lockout: u32,
+    lockout: Duration,
+    failures: Mutex<HashMap<String, LoginFailures>>,
+}
+
+impl LoginThrottle {
+    pub fn new(limits: &LimitsConfig) -> Self {
+        Self {
+            failures_before_lockout: limits.login_failures_before_lockout,
+            lockout: Duration::from_secs(u64::from(limits.lockout_minutes) * 60),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Time left until `network_id` may try again, if it is locked out
+    pub fn lockout_remaining(&self, network_id: &str, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let locked_until = failures.get(network_id)?.locked_until?;
+        Some(locked_until.saturating_duration_since(now)).filter(|left| !left.is_zero())
+    }
+
+    /// Count a failed login; returns true if it locked the network ID out
+    pub fn record_failure(&self, network_id: &str, now: Instant) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let entry = failures.entry(network_id.to_string()).or_default();
+        // A lockout that has run out starts a fresh count
+        if entry.locked_until.is_some_and(|until| until <= now) {
+            *entry = LoginFailures::default();
+        }
+        entry.count += 1;
+        if entry.count < self.failures_before_lockout {
+            return false;
+        }
+        entry.locked_until = Some(now + self.lockout);
+        true
+    }
+
+    pub fn record_success(&self, network_id: &str) {
+        self.failures.lock().unwrap().remove(network_id);
+    }
+}
+
+/// Window `[api] registrations_per_hour` is counted over
+#[cfg(feature = "http")]
+const REGISTRATION_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Caps account registrations per IP address
+#[cfg(feature = "http")]
+#[derive(Debug)]
+pub struct RegistrationThrottle {
+    per_hour: u32,
+    attempts: Mutex<HashMap<IpAddr, SlidingWindow>>,
+}
+
+#[cfg(feature = "http")]
+impl RegistrationThrottle {
+    pub fn new(per_hour: u32) -> Self {
+        Self {
+            per_hour,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count an attempt from `ip`; false once it is over the hourly limit
+    pub fn try_register(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut attempts = self.attempts.lock().unwrap();
+        // Addresses whose attempts have all aged out start over anyway
+        attempts.retain(|_, window| {
+            window
+                .events
+                .back()
+                .is_some_and(|last| now.saturating_duration_since(*last) < REGISTRATION_WINDOW)
+        });
+        attempts
+            .entry(ip)
+            .or_insert_with(|| SlidingWindow::new(self.per_hour, REGISTRATION_WINDOW))
+            .try_record(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_rate_follows_config() {
+        let start = Instant::now();
+        let mut limits = LimitsConfig::default();
+        let mut default_limiter = ConnectionLimiter::new(&limits);
+        limits.packets_per_second = 2;
+        limits.burst = 3;
+        let mut strict = ConnectionLimiter::new(&limits);
+
+        // Ten packets at once: the default burst takes them, the strict one three
+        let allowed = (0..10).filter(|_| strict.allow_packet(start)).count();
+        assert_eq!(allowed, 3);
+        assert!((0..10).all(|_| default_limiter.allow_packet(start)));
+
+        // Two more per second afterwards
+        let later = start + Duration::from_secs(1);
+        assert!(strict.allow_packet(later));
+        assert!(strict.allow_packet(later));
+        assert!(!strict.allow_packet(later));
+    }
+
+    #[test]
+    fn test_text_message_window() {
+        let start = Instant::now();
+        let limits = LimitsConfig {
+            text_messages_per_minute: 2,
+            ..LimitsConfig::default()
+        };
+        let mut limiter = ConnectionLimiter::new(&limits);
+        assert!(limiter.allow_text_message(start));
+        assert!(limiter.allow_text_message(start + Duration::from_secs(30)));
+        assert!(!limiter.allow_text_message(start + Duration::from_secs(59)));
+        assert!(limiter.allow_text_message(start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_parse_error_budget() {
+        use ParseErrorAction::*;
+        let start = Instant::now();
+        let limits = LimitsConfig {
+            max_parse_errors: 4,
+            parse_errors_per_second: 2,
+            parse_error_replies: 1,
+            ..LimitsConfig::default()
+        };
+        let mut limiter = ConnectionLimiter::new(&limits);
+        // The fifth error in total is over the budget, however slow
+        let actions: Vec<_> = [0, 0, 1, 2, 9]
+            .into_iter()
+            .map(|second| limiter.record_parse_error(start + Duration::from_secs(second)))
+            .collect();
+        assert_eq!(actions, [Reply, Ignore, Ignore, Ignore, Disconnect]);
+
+        // Three in one second are over the rate
+        let mut limiter = ConnectionLimiter::new(&limits);
+        let actions: Vec<_> = (0..3).map(|_| limiter.record_parse_error(start)).collect();
+        assert_eq!(actions, [Reply, Ignore, Disconnect]);
+    }
+
+    #[test]
+    fn test_truncate_field() {
+        let mut ascii = "ABCDEF".to_string();
+        assert!(!truncate_field(&mut ascii, 6));
+        assert!(truncate_field(&mut ascii, 4));
+        assert_eq!(ascii, "ABCD");
+
+        // Never in the middle of a character
+        let mut accented = "Zürich".to_string();
+        assert!(truncate_field(&mut accented, 2));
+        assert_eq!(accented, "Z");
+    }
+
+    #[test]
+    fn test_login_lockout() {
+        let start = Instant::now();
+        let limits = LimitsConfig {
+            login_failures_before_lockout: 3,
+            lockout_minutes: 10,
+            ..LimitsConfig::default()
+        };
+        let throttle = LoginThrottle::new(&limits);
+
+        assert!(!throttle.record_failure("1234567", start));
+        assert!(!throttle.record_failure("1234567", start));
+        assert!(throttle.lockout_remaining("1234567", start).is_none());
+        assert!(throttle.record_failure("1234567", start));
+        assert_eq!(
+            throttle.lockout_remaining("1234567", start),
+            Some(Duration::from_secs(600))
+        );
+        assert!(throttle.lockout_remaining("7654321", start).is_none());
+
+        let after = start + Duration::from_secs(600);
+        assert!(throttle.lockout_remaining("1234567", after).is_none());
+        assert!(!throttle.record_failure("1234567", after));
+
+        throttle.record_success("1234567");
+        assert!(!throttle.record_failure("1234567", after));
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_registration_throttle() {
+        let start = Instant::now();
+        let throttle = RegistrationThrottle::new(2);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert!(throttle.try_register(ip, start));
+        assert!(throttle.try_register(ip, start));
+        assert!(!throttle.try_register(ip, start));
+        assert!(throttle.try_register(other, start));
+
+        let later = start + REGISTRATION_WINDOW;
+        assert!(throttle.try_register(ip, later));
+        assert_eq!(throttle.attempts.lock().unwrap().len(), 1);
+    }
+}
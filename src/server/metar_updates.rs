@@ -0,0 +1,198 @@
+//! New METARs for subscribed clients
+//!
+//! Every `metar_refresh_secs`, [`run`] fetches the METAR of each station a
+//! client subscribed to, past the cache, and sends it as an `$AR` to each
+//! subscriber that hasn't had that report yet. A station is fetched once
+//! however many clients subscribed to it.
+
+use crate::server::clock::Clock;
+use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::handlers::metar_subscription::metar_packet;
+use crate::server::registry::ClientRegistry;
+use crate::weather::WeatherService;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch, RwLock};
+
+/// Push new METARs until the server stops
+///
+/// The interval is read from the weather service each time, so a reload
+/// that changes it applies from the next fetch.
+pub async fn run(
+    clients: Arc<RwLock<ClientRegistry>>,
+    weather: watch::Receiver<Arc<WeatherService>>,
+    broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: String,
+    clock: Arc<dyn Clock>,
+) {
+    loop {
+        let interval = weather.borrow().metar_refresh();
+        clock.sleep(interval).await;
+        let weather = weather.borrow().clone();
+        push_updates(&clients, &weather, &broadcast_tx, &server_callsign).await;
+    }
+}
+
+/// Fetch the subscribed stations once and send each subscriber the METARs
+/// it hasn't had, returning how many were sent
+pub async fn push_updates(
+    clients: &Arc<RwLock<ClientRegistry>>,
+    weather: &WeatherService,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+) -> usize {
+    let stations: BTreeSet<String> = clients
+        .read()
+        .await
+        .values()
+        .flat_map(|client| client.metar_subscriptions.keys().cloned())
+        .collect();
+    if stations.is_empty() {
+        return 0;
+    }
+
+    let mut metars = HashMap::new();
+    for station in stations {
+        match weather.refresh_metar(&station).await {
+            Ok(metar) => {
+                metars.insert(station, metar);
+            }
+            Err(e) => tracing::debug!("No METAR for subscribed station {}: {}", station, e),
+        }
+    }
+
+    let mut sent = 0;
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    clients_map.for_each_mut(|addr, client| {
+        let callsign = client.callsign().unwrap_or_default().to_string();
+        for (station, last) in &mut client.metar_subscriptions {
+            let Some(metar) = metars.get(station) else {
+                continue;
+            };
+            if last.as_ref() == Some(metar) {
+                continue;
+            }
+            *last = Some(metar.clone());
+            let packet = metar_packet(server_callsign, &callsign, metar.clone());
+            let _ = broadcast_tx.send((*addr, ServerMessage::Direct(Arc::new(packet))));
+            sent += 1;
+        }
+    });
+    if sent > 0 {
+        tracing::debug!("Pushed {} METAR updates", sent);
+    }
+    sent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientType};
+    use crate::config::{WeatherConfig, WeatherProvider};
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A provider answering every request with the METAR set last
+    async fn provider(metar: Arc<Mutex<String>>) -> WeatherService {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                let body = metar.lock().unwrap().clone();
+                let response = format!("HTTP/1.0 200 OK\r\n\r\n{}\n", body);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        WeatherService::from_config(&WeatherConfig {
+            provider: WeatherProvider::Custom,
+            url_template: Some(format!("http://127.0.0.1:{}/{{icao}}", port)),
+            ..WeatherConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn client(port: u16, callsign: &str, client_type: ClientType, stations: &[&str]) -> Client {
+        let mut client = Client::active_for_tests(([127, 0, 0, 1], port), callsign, client_type);
+        client.metar_subscriptions = stations.iter().map(|s| (s.to_string(), None)).collect();
+        client
+    }
+
+    /// Where each pushed METAR went
+    fn pushed(
+        rx: &mut broadcast::Receiver<(SocketAddr, ServerMessage)>,
+    ) -> Vec<(u16, String, String)> {
+        let mut pushed: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|(addr, msg)| match msg {
+                ServerMessage::Direct(packet) => (
+                    addr.port(),
+                    packet.destination.clone(),
+                    packet.data[1].clone(),
+                ),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        pushed.sort();
+        pushed
+    }
+
+    #[tokio::test]
+    async fn test_changed_metar_pushed_once_to_subscribers() {
+        let metar = Arc::new(Mutex::new(
+            "ZBAA 121200Z 36004MPS CAVOK 22/08 Q1012".to_string(),
+        ));
+        let weather = provider(metar.clone()).await;
+        let clients = Arc::new(RwLock::new(ClientRegistry::from_iter(
+            [
+                client(50001, "ZBAA_TWR", ClientType::Atc, &["ZBAA"]),
+                client(50002, "ZBAA_APP", ClientType::Atc, &["ZBAA", "ZBTJ"]),
+                client(50003, "CCA1501", ClientType::Pilot, &[]),
+            ]
+            .map(|client| (client.addr, client)),
+        )));
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+        let push = || push_updates(&clients, &weather, &broadcast_tx, "SERVER");
+
+        // First sight, to both subscribers; the mock has the same report for
+        // ZBTJ, so ZBAA_APP gets it for that station too
+        assert_eq!(push().await, 3);
+        let first = "ZBAA 121200Z 36004MPS CAVOK 22/08 Q1012".to_string();
+        assert_eq!(
+            pushed(&mut rx),
+            [
+                (50001, "ZBAA_TWR".into(), first.clone()),
+                (50002, "ZBAA_APP".into(), first.clone()),
+                (50002, "ZBAA_APP".into(), first.clone()),
+            ]
+        );
+
+        // Unchanged, nothing to send
+        assert_eq!(push().await, 0);
+        assert!(pushed(&mut rx).is_empty());
+
+        // Changed, once per subscriber and station
+        let second = "ZBAA 121230Z 36006MPS CAVOK 23/08 Q1011".to_string();
+        *metar.lock().unwrap() = second.clone();
+        clients
+            .write()
+            .await
+            .get_mut(&"127.0.0.1:50002".parse().unwrap())
+            .unwrap()
+            .metar_subscriptions
+            .remove("ZBTJ");
+        assert_eq!(push().await, 2);
+        assert_eq!(
+            pushed(&mut rx),
+            [
+                (50001, "ZBAA_TWR".into(), second.clone()),
+                (50002, "ZBAA_APP".into(), second),
+            ]
+        );
+        assert_eq!(push().await, 0);
+    }
+}
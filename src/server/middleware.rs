@@ -0,0 +1,486 @@
+//! Hooks for deployment policies, such as filtering text messages, without
+//! changing the processor
+//!
+//! A [`PacketMiddleware`] sees each packet a client sends before it is
+//! handled, and each packet on its way to a client before it is written.
+//! The middlewares form a [`MiddlewareChain`]. Inbound packets pass through
+//! them in the order they were added and outbound ones in reverse, so the
+//! first one added is the outermost: it sees what a client sent before
+//! anyone changed it, and what a client is sent after everyone has. A
+//! middleware replacing a packet hands the replacement on to the next; one
+//! that drops or rejects it ends the chain, and the rest never see it.
+//! Rejecting an inbound packet answers its sender with `$ER`, rejecting an
+//! outbound one drops it.
+//!
+//! The built-in middlewares under `[middleware]`, [`PacketLogger`] then
+//! [`TextFilter`], come before those added with
+//! [`ServerBuilder::with_middleware`](super::ServerBuilder::with_middleware).
+//! Position updates, most of the traffic, bypass the chain.
+
+use crate::callsign::Callsign;
+use crate::config::{MiddlewareConfig, TextFilterAction, TextFilterConfig};
+use crate::packet::{redact_password, Packet, PacketType};
+use regex::Regex;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// What a middleware hook returns
+pub type MiddlewareFuture<'a> = Pin<Box<dyn Future<Output = Decision> + Send + 'a>>;
+
+/// The connection a packet comes from or goes to
+#[derive(Debug, Clone)]
+pub struct PacketContext {
+    pub addr: SocketAddr,
+    /// Its callsign, once logged in
+    pub callsign: Option<Callsign>,
+}
+
+/// What becomes of a packet a middleware has seen
+#[derive(Debug, Clone)]
+pub enum Decision {
+    /// Hand it on as it is
+    Continue,
+    /// Drop it without telling anyone
+    Drop,
+    /// Hand this on instead
+    Replace(Packet),
+    /// Drop it and tell the client that sent it why
+    Reject(Rejection),
+}
+
+/// Why an inbound packet was rejected, sent back as `$ER`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    /// FSD error code, e.g. `004`
+    pub code: String,
+    pub message: String,
+}
+
+impl Rejection {
+    pub fn new(code: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+
+    /// The `$ER` from `server_callsign` telling `callsign`
+    pub fn to_packet(&self, server_callsign: &str, callsign: &str) -> Packet {
+        Packet {
+            packet_type: PacketType::Request,
+            command: "ER".to_string(),
+            source: server_callsign.to_string(),
+            destination: callsign.to_string(),
+            data: vec![self.code.clone(), String::new(), self.message.clone()],
+        }
+    }
+}
+
+/// A policy applied to the packets passing through the server
+///
+/// Both hooks let every packet through unless overridden.
+pub trait PacketMiddleware: Send + Sync {
+    /// Name used in logs
+    fn name(&self) -> &str;
+
+    /// Called with each packet a client sends, before it is handled
+    fn on_inbound<'a>(
+        &'a self,
+        ctx: &'a PacketContext,
+        packet: &'a Packet,
+    ) -> MiddlewareFuture<'a> {
+        let _ = (ctx, packet);
+        Box::pin(std::future::ready(Decision::Continue))
+    }
+
+    /// Called with each packet for the client in `ctx`, before it is written
+    fn on_outbound<'a>(
+        &'a self,
+        ctx: &'a PacketContext,
+        packet: &'a Packet,
+    ) -> MiddlewareFuture<'a> {
+        let _ = (ctx, packet);
+        Box::pin(std::future::ready(Decision::Continue))
+    }
+}
+
+/// The middlewares a server runs, in the order they were added
+#[derive(Clone, Default)]
+pub struct MiddlewareChain {
+    middlewares: Arc<[Arc<dyn PacketMiddleware>]>,
+}
+
+impl MiddlewareChain {
+    pub fn new(middlewares: Vec<Arc<dyn PacketMiddleware>>) -> Self {
+        Self {
+            middlewares: middlewares.into(),
+        }
+    }
+
+    /// The built-in middlewares `config` turns on
+    pub fn built_in(config: &MiddlewareConfig) -> Result<Self, regex::Error> {
+        let mut chain = Self::default();
+        if config.log_packets {
+            chain.push(Arc::new(PacketLogger));
+        }
+        if !config.text_filter.patterns.is_empty() {
+            chain.push(Arc::new(TextFilter::new(&config.text_filter)?));
+        }
+        Ok(chain)
+    }
+
+    /// Add `middleware` at the end
+    pub fn push(&mut self, middleware: Arc<dyn PacketMiddleware>) {
+        self.append(&Self::new(vec![middleware]));
+    }
+
+    /// Add the middlewares of `other` at the end
+    pub fn append(&mut self, other: &MiddlewareChain) {
+        self.middlewares = self
+            .middlewares
+            .iter()
+            .chain(other.middlewares.iter())
+            .cloned()
+            .collect();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.middlewares.is_empty()
+    }
+
+    /// Pass a packet from a client through the chain: the packet to handle,
+    /// `None` if it was dropped, or why it was rejected
+    pub async fn inbound(
+        &self,
+        ctx: &PacketContext,
+        mut packet: Packet,
+    ) -> Result<Option<Packet>, Rejection> {
+        for middleware in self.middlewares.iter() {
+            match middleware.on_inbound(ctx, &packet).await {
+                Decision::Continue => {}
+                Decision::Replace(replacement) => packet = replacement,
+                Decision::Drop => {
+                    tracing::debug!(
+                        middleware = middleware.name(),
+                        "Dropped {} from {}",
+                        packet.command,
+                        ctx.addr
+                    );
+                    return Ok(None);
+                }
+                Decision::Reject(rejection) => {
+                    tracing::debug!(
+                        middleware = middleware.name(),
+                        "Rejected {} from {}: {}",
+                        packet.command,
+                        ctx.addr,
+                        rejection.message
+                    );
+                    return Err(rejection);
+                }
+            }
+        }
+        Ok(Some(packet))
+    }
+
+    /// Pass a packet for a client through the chain, last middleware first:
+    /// the packet to write, or `None` if it was dropped or rejected
+    pub async fn outbound(
+        &self,
+        ctx: &PacketContext,
+        mut packet: Arc<Packet>,
+    ) -> Option<Arc<Packet>> {
+        for middleware in self.middlewares.iter().rev() {
+            match middleware.on_outbound(ctx, &packet).await {
+                Decision::Continue => {}
+                Decision::Replace(replacement) => packet = Arc::new(replacement),
+                Decision::Drop | Decision::Reject(_) => {
+                    tracing::debug!(
+                        middleware = middleware.name(),
+                        "Dropped {} to {}",
+                        packet.command,
+                        ctx.addr
+                    );
+                    return None;
+                }
+            }
+        }
+        Some(packet)
+    }
+}
+
+impl fmt::Debug for MiddlewareChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.middlewares.iter().map(|middleware| middleware.name()))
+            .finish()
+    }
+}
+
+/// Logs every packet passing through at info, under the
+/// `openfsd::packets` target, with passwords redacted
+#[derive(Debug, Default)]
+pub struct PacketLogger;
+
+impl PacketMiddleware for PacketLogger {
+    fn name(&self) -> &str {
+        "packet_logger"
+    }
+
+    fn on_inbound<'a>(
+        &'a self,
+        ctx: &'a PacketContext,
+        packet: &'a Packet,
+    ) -> MiddlewareFuture<'a> {
+        tracing::info!(
+            target: "openfsd::packets",
+            "{} sent {}",
+            ctx.addr,
+            redact_password(&packet.to_string())
+        );
+        Box::pin(std::future::ready(Decision::Continue))
+    }
+
+    fn on_outbound<'a>(
+        &'a self,
+        ctx: &'a PacketContext,
+        packet: &'a Packet,
+    ) -> MiddlewareFuture<'a> {
+        tracing::info!(target: "openfsd::packets", "{} is sent {}", ctx.addr, packet);
+        Box::pin(std::future::ready(Decision::Continue))
+    }
+}
+
+/// Text messages matching any of the configured patterns are masked,
+/// dropped or rejected
+#[derive(Debug)]
+pub struct TextFilter {
+    patterns: Vec<Regex>,
+    action: TextFilterAction,
+    replacement: String,
+}
+
+impl TextFilter {
+    pub fn new(config: &TextFilterConfig) -> Result<Self, regex::Error> {
+        Ok(Self {
+            patterns: config
+                .patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?,
+            action: config.action,
+            replacement: config.replacement.clone(),
+        })
+    }
+
+    fn filter(&self, packet: &Packet) -> Decision {
+        let matches = |field: &String| self.patterns.iter().any(|p| p.is_match(field));
+        if packet.command != "TM" || !packet.data.iter().any(matches) {
+            return Decision::Continue;
+        }
+        match self.action {
+            TextFilterAction::Mask => {
+                let mut masked = packet.clone();
+                for field in &mut masked.data {
+                    for pattern in &self.patterns {
+                        *field = pattern
+                            .replace_all(field, self.replacement.as_str())
+                            .into_owned();
+                    }
+                }
+                Decision::Replace(masked)
+            }
+            TextFilterAction::Drop => Decision::Drop,
+            TextFilterAction::Reject => Decision::Reject(Rejection::new(
+                "004",
+                "Message not sent: it contains filtered text",
+            )),
+        }
+    }
+}
+
+impl PacketMiddleware for TextFilter {
+    fn name(&self) -> &str {
+        "text_filter"
+    }
+
+    fn on_inbound<'a>(
+        &'a self,
+        _ctx: &'a PacketContext,
+        packet: &'a Packet,
+    ) -> MiddlewareFuture<'a> {
+        Box::pin(std::future::ready(self.filter(packet)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Applies `decide` to the text of inbound messages and records what it
+    /// saw in both directions
+    struct Probe {
+        name: &'static str,
+        decide: fn(&str) -> Decision,
+        seen: Mutex<Vec<String>>,
+    }
+
+    impl Probe {
+        fn new(name: &'static str, decide: fn(&str) -> Decision) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                decide,
+                seen: Mutex::default(),
+            })
+        }
+
+        fn seen(&self) -> Vec<String> {
+            std::mem::take(&mut self.seen.lock().unwrap())
+        }
+    }
+
+    impl PacketMiddleware for Probe {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn on_inbound<'a>(
+            &'a self,
+            _ctx: &'a PacketContext,
+            packet: &'a Packet,
+        ) -> MiddlewareFuture<'a> {
+            let text = packet.data.join(":");
+            self.seen.lock().unwrap().push(text.clone());
+            Box::pin(async move { (self.decide)(&text) })
+        }
+
+        fn on_outbound<'a>(
+            &'a self,
+            ctx: &'a PacketContext,
+            packet: &'a Packet,
+        ) -> MiddlewareFuture<'a> {
+            self.on_inbound(ctx, packet)
+        }
+    }
+
+    fn ctx() -> PacketContext {
+        PacketContext {
+            addr: "127.0.0.1:50001".parse().unwrap(),
+            callsign: Some(Callsign::new("CCA1501")),
+        }
+    }
+
+    fn message(text: &str) -> Packet {
+        Packet::parse(&format!("#TMCCA1501:DLH123:{}", text)).unwrap()
+    }
+
+    /// Replaces "hello" with "hi"
+    fn shorten(text: &str) -> Decision {
+        match text {
+            "hello" => Decision::Replace(message("hi")),
+            _ => Decision::Continue,
+        }
+    }
+
+    /// Drops "spam" and rejects "shout"
+    fn police(text: &str) -> Decision {
+        match text {
+            "spam" => Decision::Drop,
+            "shout" => Decision::Reject(Rejection::new("004", "No shouting")),
+            _ => Decision::Continue,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inbound_chain() {
+        let (first, second) = (Probe::new("first", shorten), Probe::new("second", police));
+        let chain = MiddlewareChain::new(vec![first.clone(), second.clone()]);
+
+        // The replacement is what the second middleware and the handler see
+        let handled = chain.inbound(&ctx(), message("hello")).await.unwrap();
+        assert_eq!(handled.unwrap().data, ["hi"]);
+        assert_eq!(second.seen(), ["hi"]);
+
+        assert!(chain
+            .inbound(&ctx(), message("spam"))
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            chain.inbound(&ctx(), message("shout")).await.unwrap_err(),
+            Rejection::new("004", "No shouting")
+        );
+        assert_eq!(first.seen(), ["hello", "spam", "shout"]);
+        assert_eq!(second.seen(), ["spam", "shout"]);
+
+        // A drop or rejection ends the chain
+        let chain = MiddlewareChain::new(vec![second.clone(), first.clone()]);
+        assert!(chain
+            .inbound(&ctx(), message("spam"))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(chain.inbound(&ctx(), message("shout")).await.is_err());
+        assert!(first.seen().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_outbound_chain_runs_in_reverse() {
+        let (first, second) = (Probe::new("first", police), Probe::new("second", shorten));
+        let chain = MiddlewareChain::new(vec![first.clone(), second.clone()]);
+
+        let sent = chain.outbound(&ctx(), Arc::new(message("hello"))).await;
+        assert_eq!(sent.unwrap().data, ["hi"]);
+        assert_eq!(second.seen(), ["hello"]);
+        assert_eq!(first.seen(), ["hi"]);
+
+        // Rejecting a packet for a client only drops it
+        assert!(chain
+            .outbound(&ctx(), Arc::new(message("shout")))
+            .await
+            .is_none());
+        assert!(chain
+            .outbound(&ctx(), Arc::new(message("spam")))
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_text_filter() {
+        let config = |action| TextFilterConfig {
+            patterns: vec![r"(?i)\bdarn\b".to_string()],
+            action,
+            ..TextFilterConfig::default()
+        };
+        let masking = TextFilter::new(&config(TextFilterAction::Mask)).unwrap();
+        match masking.filter(&message("Darn: it, darnit")) {
+            Decision::Replace(packet) => assert_eq!(packet.data, ["***", " it, darnit"]),
+            other => panic!("expected a masked message, got {:?}", other),
+        }
+        assert!(matches!(
+            masking.filter(&message("all good")),
+            Decision::Continue
+        ));
+        // Only text messages are filtered
+        let query = Packet::parse("$CQCCA1501:DLH123:darn").unwrap();
+        assert!(matches!(masking.filter(&query), Decision::Continue));
+
+        let dropping = TextFilter::new(&config(TextFilterAction::Drop)).unwrap();
+        assert!(matches!(dropping.filter(&message("darn")), Decision::Drop));
+        let rejecting = TextFilter::new(&config(TextFilterAction::Reject)).unwrap();
+        assert!(matches!(
+            rejecting.filter(&message("darn")),
+            Decision::Reject(_)
+        ));
+
+        let invalid = TextFilterConfig {
+            patterns: vec!["(".to_string()],
+            ..TextFilterConfig::default()
+        };
+        assert!(TextFilter::new(&invalid).is_err());
+    }
+}
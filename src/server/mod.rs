@@ -1,131 +1,779 @@
+#[cfg(feature = "http")]
+mod announcements;
+mod api;
+mod atis_broadcast;
+mod builder;
+mod capabilities;
+mod capture;
+mod chaos;
+pub mod clock;
 mod config;
 mod connection;
+pub mod control;
+mod diagnostics;
+mod elevation;
+pub mod dump;
+mod flight_plan_check;
+mod follow;
+pub mod handler_stats;
 mod handlers;
+pub mod health;
+mod heartbeat;
+mod idle;
+mod limits;
+mod metar_updates;
+pub mod middleware;
+mod ping;
+pub mod pipeline;
 mod processor;
+mod random;
+pub mod registry;
+pub mod session;
+mod snapshot;
+mod state;
+#[cfg(feature = "http")]
+pub mod status;
+pub mod subscribers;
+pub mod upgrade;
+pub(crate) mod visibility;
+#[cfg(feature = "websocket")]
+mod websocket;
 
-pub use config::{ServerConfig, ServerMessage};
+pub use builder::{BuildError, ServerBuilder};
+pub use config::{ServerConfig, ServerMessage, DEFAULT_SERVER_CALLSIGN, NO_CLIENT};
+pub use tokio_util::sync::CancellationToken;
 
+use crate::airports::AirportCache;
+use crate::callsign::Callsign;
 use crate::client::Client;
-use crate::packet::Packet;
+use crate::config::{HeartbeatConfig, LimitsConfig};
+use crate::db::service;
+use crate::error::ServerError;
+use crate::motd::MotdCache;
+use crate::packet::Inbound;
+use crate::stats::{DailyStats, StatsCollector};
+use crate::tracks::TrackRecorder;
+use crate::weather::WeatherService;
+use crate::webhooks::Webhooks;
+use clock::Clock;
+use dump::{DumpError, Dumper};
+use follow::Follows;
+use handler_stats::{HandlerReport, HandlerStats};
+use health::Health;
+use limits::LoginThrottle;
+use middleware::MiddlewareChain;
+use pipeline::{Pipeline, PipelineReport};
+use registry::ClientRegistry;
 use sea_orm::DatabaseConnection;
+use state::ServerState;
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, RwLock};
+use tokio::task::JoinSet;
+use tracing::Instrument;
 
-/// Main FSD Server
+/// A running FSD server, built with [`ServerBuilder`]
+///
+/// [`run`](Server::run) serves clients until the shutdown token is cancelled
+/// or the listener fails; the other methods inspect and adjust the server
+/// while it runs.
 pub struct Server {
     config: ServerConfig,
-    clients: Arc<RwLock<HashMap<SocketAddr, Client>>>,
-    callsign_map: Arc<RwLock<HashMap<String, SocketAddr>>>,
+    clients: Arc<RwLock<ClientRegistry>>,
+    callsign_map: Arc<RwLock<HashMap<Callsign, SocketAddr>>>,
     broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
     db: Arc<DatabaseConnection>,
+    stats: Arc<StatsCollector>,
+    handler_stats: Arc<HandlerStats>,
+    health: Arc<Health>,
+    pipeline: Arc<Pipeline>,
+    motd: Arc<MotdCache>,
+    airports: Arc<AirportCache>,
+    follows: Arc<Follows>,
+    heartbeat: watch::Sender<HeartbeatConfig>,
+    weather: watch::Sender<Arc<WeatherService>>,
+    login_throttle: Arc<LoginThrottle>,
+    dumper: Arc<Dumper>,
+    random: Arc<random::Random>,
+    clock: Arc<dyn Clock>,
+    middleware: MiddlewareChain,
+    /// Requests to hand the listener over, while `run` is accepting
+    handover: Mutex<Option<mpsc::Sender<Handover>>>,
+    /// Listeners given to the builder, used up the first time the
+    /// listeners are opened
+    listeners: Mutex<Listeners>,
+    shutdown: CancellationToken,
+}
+
+/// A request to hand the listener over, answered with the new process's ID
+type Handover = oneshot::Sender<std::io::Result<u32>>;
+
+/// Listeners bound by the caller instead of at the configured addresses
+#[derive(Debug, Default)]
+pub(crate) struct Listeners {
+    pub control: Option<TcpListener>,
+    pub status: Option<TcpListener>,
+    pub websocket: Option<TcpListener>,
+}
+
+/// `listener` if there is one, or a new one bound to `address`
+async fn bind_unless_given(
+    listener: Option<TcpListener>,
+    address: &str,
+) -> Result<TcpListener, ServerError> {
+    match listener {
+        Some(listener) => Ok(listener),
+        None => TcpListener::bind(address)
+            .await
+            .map_err(ServerError::bind(address)),
+    }
 }
 
 impl Server {
-    pub fn new(config: ServerConfig, db: DatabaseConnection) -> Self {
-        let (broadcast_tx, _) = broadcast::channel(1000);
+    /// A server that stops when `shutdown` is cancelled
+    pub(crate) fn new(
+        config: ServerConfig,
+        db: DatabaseConnection,
+        weather: WeatherService,
+        middleware: MiddlewareChain,
+        listeners: Listeners,
+        clock: Arc<dyn Clock>,
+        shutdown: CancellationToken,
+    ) -> Self {
+        let (broadcast_tx, _) = broadcast::channel(config.runtime.broadcast_capacity);
+        let pipeline = Arc::new(Pipeline::new(
+            broadcast_tx.clone(),
+            config.runtime.broadcast_capacity,
+        ));
+        let heartbeat = watch::Sender::new(config.heartbeat.clone());
+        let weather = watch::Sender::new(Arc::new(weather));
+        let login_throttle = Arc::new(LoginThrottle::new(&config.limits));
+        let clients = Arc::new(RwLock::new(ClientRegistry::new()));
+        let callsign_map = Arc::new(RwLock::new(HashMap::new()));
+        let stats = Arc::new(StatsCollector::new());
+        let handler_stats = Arc::new(HandlerStats::new());
+        let dumper = Arc::new(Dumper {
+            config: config.dump.clone(),
+            clients: clients.clone(),
+            callsign_map: callsign_map.clone(),
+            pipeline: pipeline.clone(),
+            handler_stats: handler_stats.clone(),
+            stats: stats.clone(),
+        });
+        let random = Arc::new(random::Random::new(config.runtime.random_seed));
 
         Self {
             config,
-            clients: Arc::new(RwLock::new(HashMap::new())),
-            callsign_map: Arc::new(RwLock::new(HashMap::new())),
+            clients,
+            callsign_map,
             broadcast_tx,
             db: Arc::new(db),
+            stats,
+            handler_stats,
+            health: Arc::new(Health::new()),
+            pipeline,
+            motd: Arc::new(MotdCache::default()),
+            airports: Arc::default(),
+            follows: Arc::default(),
+            heartbeat,
+            weather,
+            login_throttle,
+            dumper,
+            random,
+            clock,
+            middleware,
+            handover: Mutex::new(None),
+            listeners: Mutex::new(listeners),
+            shutdown,
         }
     }
 
-    /// Start the FSD server
-    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Apply new heartbeat settings to the running server
+    pub fn reload_heartbeat(&self, config: HeartbeatConfig) {
+        self.heartbeat.send_replace(config);
+    }
+
+    /// Serve weather from a new service, dropping everything cached so far
+    pub fn reload_weather(&self, weather: WeatherService) {
+        self.weather.send_replace(Arc::new(weather));
+    }
+
+    /// Live statistics for the current day
+    pub fn live_stats(&self) -> DailyStats {
+        self.stats.today()
+    }
+
+    /// Keep serving the clients online but refuse new connections, e.g.
+    /// before a restart
+    pub fn drain(&self) {
+        if self.health.start_draining() {
+            tracing::warn!("Draining: new connections are refused from now on");
+        }
+    }
+
+    /// Start this binary again and hand it the FSD listener, for an upgrade
+    /// without dropping clients; returns the new process's ID
+    ///
+    /// The ports of the other listeners are freed for the new process
+    /// first. This server then accepts no one and [`run`](Server::run)
+    /// returns once its last client has left, or after
+    /// `runtime.upgrade_drain_secs`. Like [`dump_state`](Server::dump_state),
+    /// the returned future doesn't borrow the server, so it can be spawned.
+    pub fn hand_over(&self) -> impl Future<Output = std::io::Result<u32>> + Send + 'static {
+        let requests = self.handover.lock().unwrap().clone();
+        async move {
+            let not_running = || std::io::Error::other("the server is not accepting clients");
+            let (reply, replied) = oneshot::channel();
+            requests
+                .ok_or_else(not_running)?
+                .send(reply)
+                .await
+                .map_err(|_| not_running())?;
+            replied.await.map_err(|_| not_running())?
+        }
+    }
+
+    /// Packet counts and handler times since the server started
+    pub fn handler_report(&self) -> HandlerReport {
+        self.handler_stats.report()
+    }
+
+    /// Depths of the packet and broadcast queues
+    pub async fn queue_report(&self) -> PipelineReport {
+        self.pipeline.report(&*self.clients.read().await)
+    }
+
+    /// Write the full server state to a JSON file for debugging; the
+    /// returned future doesn't borrow the server, so it can be spawned
+    pub fn dump_state(&self) -> impl Future<Output = Result<PathBuf, DumpError>> + Send + 'static {
+        let dumper = self.dumper.clone();
+        async move { dumper.dump().await }
+    }
+
+    /// The token that stops [`run`](Server::run) when cancelled
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Serve clients until the shutdown token is cancelled, then persist
+    /// pending statistics and stop every task the server started
+    pub async fn run(&self) -> Result<(), ServerError> {
         let addr = format!("{}:{}", self.config.address, self.config.port);
-        let listener = TcpListener::bind(&addr).await?;
+        let listener = TcpListener::bind(&addr)
+            .await
+            .map_err(ServerError::bind(&addr))?;
+        self.run_with_listener(listener).await
+    }
 
-        log::info!(
+    /// Like [`run`](Server::run), but accept FSD clients on a listener the
+    /// caller has bound already, e.g. to an ephemeral port in tests
+    pub async fn run_with_listener(
+        &self,
+        listener: TcpListener,
+    ) -> Result<(), ServerError> {
+        tracing::info!(
             "FSD Server {} v{} listening on {}",
             self.config.server_name,
             self.config.server_version,
-            addr
+            listener.local_addr()?
         );
 
-        let (packet_tx, mut packet_rx) = mpsc::channel::<(SocketAddr, Packet)>(1000);
+        if self.config.auth.allow_guest {
+            tracing::warn!("==============================================================");
+            tracing::warn!("GUEST MODE ENABLED: unknown network IDs can log in with ANY");
+            tracing::warn!("password. Never enable auth.allow_guest on a public server!");
+            tracing::warn!("==============================================================");
+        }
+        tracing::info!("Speaking the {:?} protocol dialect", self.config.dialect);
+        if !self.config.whitelist.enforce {
+            tracing::warn!("Client whitelist enforcement is disabled");
+        }
+        if let Err(e) = self.airports.reload(&self.db).await {
+            tracing::error!("Failed to load airports: {}", e);
+        }
+
+        let runtime = &self.config.runtime;
+        let worker_threads = tokio::runtime::Handle::current().metrics().num_workers();
+        tracing::info!(
+            "Queueing up to {} packets for the processor and {} messages per connection, on {} worker threads",
+            runtime.packet_queue,
+            runtime.broadcast_capacity,
+            worker_threads
+        );
+        let (packet_tx, mut packet_rx) =
+            mpsc::channel::<(SocketAddr, Inbound)>(runtime.packet_queue);
+        self.pipeline.watch_packets(&packet_tx);
+
+        // Everything spawned here is aborted when run returns
+        let mut tasks = JoinSet::new();
+        let webhooks = Arc::new(Webhooks::start(
+            &self.config.webhooks,
+            &self.config.server_name,
+        ));
+        tasks.spawn(pipeline::sample(
+            self.pipeline.clone(),
+            self.clients.clone(),
+        ));
 
         // Spawn packet processor task
-        let clients = self.clients.clone();
-        let callsign_map = self.callsign_map.clone();
-        let config = self.config.clone();
-        let broadcast_tx = self.broadcast_tx.clone();
-        let db = self.db.clone();
+        let state = ServerState {
+            config: self.config.clone(),
+            clients: self.clients.clone(),
+            callsign_map: self.callsign_map.clone(),
+            broadcast_tx: self.broadcast_tx.clone(),
+            db: self.db.clone(),
+            stats: self.stats.clone(),
+            handler_stats: self.handler_stats.clone(),
+            tracks: Arc::new(TrackRecorder::start(
+                self.config.tracks.clone(),
+                self.db.clone(),
+            )),
+            motd: self.motd.clone(),
+            weather: self.weather.subscribe(),
+            airports: self.airports.clone(),
+            follows: self.follows.clone(),
+            throttle: self.login_throttle.clone(),
+            random: self.random.clone(),
+            clock: self.clock.clone(),
+            middleware: self.middleware.clone(),
+            webhooks: webhooks.clone(),
+        };
 
-        tokio::spawn(async move {
+        tasks.spawn(async move {
             while let Some((addr, packet)) = packet_rx.recv().await {
-                processor::process_packet(
-                    packet,
-                    addr,
-                    &clients,
-                    &callsign_map,
-                    &config,
-                    &broadcast_tx,
-                    &db,
+                processor::process_packet(packet, addr, &state).await;
+            }
+        });
+
+        // Spawn position snapshot task
+        let clients_snapshot = self.clients.clone();
+        let db_snapshot = self.db.clone();
+        let clock = self.clock.clone();
+        tasks.spawn(async move {
+            let mut interval = clock.interval(snapshot::SNAPSHOT_INTERVAL);
+            loop {
+                interval.tick().await;
+                snapshot::snapshot_clients(&clients_snapshot, &db_snapshot).await;
+            }
+        });
+
+        if self.config.visibility.snapshot_observers {
+            tasks.spawn(subscribers::run(
+                self.clients.clone(),
+                self.broadcast_tx.clone(),
+                std::time::Duration::from_millis(self.config.visibility.snapshot_interval_ms),
+                self.clock.clone(),
+            ));
+        }
+
+        // Spawn flight plan requirement task
+        let clients_filing = self.clients.clone();
+        let features = self.config.features.clone();
+        let broadcast_filing = self.broadcast_tx.clone();
+        let server_callsign = self.config.server_callsign.clone();
+        let webhooks_filing = webhooks.clone();
+        let clock = self.clock.clone();
+        tasks.spawn(async move {
+            let mut interval = clock.interval(flight_plan_check::CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                flight_plan_check::check_flight_plans(
+                    &clients_filing,
+                    &features,
+                    &broadcast_filing,
+                    &server_callsign,
+                    &webhooks_filing,
+                    clock.now(),
                 )
                 .await;
             }
         });
 
-        // Spawn heartbeat task
-        let broadcast_tx_heartbeat = self.broadcast_tx.clone();
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+        // Spawn rating elevation expiry task
+        let clients_elevated = self.clients.clone();
+        let broadcast_elevated = self.broadcast_tx.clone();
+        let server_callsign = self.config.server_callsign.clone();
+        let clock = self.clock.clone();
+        tasks.spawn(async move {
+            let mut interval = clock.interval(elevation::CHECK_INTERVAL);
             loop {
                 interval.tick().await;
-                let heartbeat = Packet {
-                    packet_type: crate::packet::PacketType::Client,
-                    command: "DL".to_string(),
-                    source: "SERVER".to_string(),
-                    destination: "*".to_string(),
-                    data: vec!["0".to_string(), "0".to_string()],
-                };
-                // Use a dummy address for server-originated broadcasts
-                let _ = broadcast_tx_heartbeat.send((
-                    "0.0.0.0:0".parse().unwrap(),
-                    ServerMessage::Packet(heartbeat),
-                ));
+                elevation::expire(
+                    &clients_elevated,
+                    &broadcast_elevated,
+                    &server_callsign,
+                    clock.now(),
+                )
+                .await;
             }
         });
 
-        // Accept connections
-        loop {
-            let (stream, addr) = listener.accept().await?;
+        // Spawn ATIS rebroadcast task
+        if let Some(interval) = self.config.features.atis_rebroadcast() {
+            tasks.spawn(atis_broadcast::run(
+                self.clients.clone(),
+                self.broadcast_tx.clone(),
+                interval,
+                self.clock.clone(),
+            ));
+        }
 
-            // Check max clients
-            {
-                let clients = self.clients.read().await;
-                if clients.len() >= self.config.max_clients {
-                    log::warn!("Max clients reached, rejecting connection from {}", addr);
-                    continue;
-                }
+        // Spawn scheduled announcements task
+        tasks.spawn(announcements::run(
+            self.db.clone(),
+            self.clients.clone(),
+            self.broadcast_tx.clone(),
+            self.config.server_callsign.clone(),
+            self.config.features.announcement_grace(),
+            self.clock.clone(),
+        ));
+
+        // Spawn daily statistics task
+        let stats_daily = self.stats.clone();
+        let db_stats = self.db.clone();
+        let clock = self.clock.clone();
+        tasks.spawn(async move {
+            loop {
+                clock.sleep(clock::until_next_utc_midnight(&*clock)).await;
+                flush_stats(&stats_daily, &db_stats).await;
             }
+        });
 
-            // Add new client
-            {
-                let mut clients = self.clients.write().await;
-                clients.insert(addr, Client::new(addr));
+        // Spawn METAR subscription task
+        tasks.spawn(metar_updates::run(
+            self.clients.clone(),
+            self.weather.subscribe(),
+            self.broadcast_tx.clone(),
+            self.config.server_callsign.clone(),
+            self.clock.clone(),
+        ));
+
+        // Spawn heartbeat task
+        tasks.spawn(heartbeat::run(
+            self.heartbeat.subscribe(),
+            self.broadcast_tx.clone(),
+            self.clock.clone(),
+        ));
+
+        // Spawn idle client task
+        tasks.spawn(idle::run(
+            self.clients.clone(),
+            self.broadcast_tx.clone(),
+            self.config.idle.clone(),
+            self.config.server_callsign.clone(),
+            webhooks.clone(),
+            self.clock.clone(),
+        ));
+
+        // Spawn keepalive ping task
+        tasks.spawn(ping::run(
+            self.clients.clone(),
+            self.broadcast_tx.clone(),
+            self.config.server_callsign.clone(),
+            self.clock.clone(),
+        ));
+
+        // Serve Prometheus metrics and hand them to the OTLP export
+        let metrics = &self.config.metrics;
+        #[cfg(feature = "telemetry")]
+        let exporting = crate::telemetry::is_running();
+        #[cfg(not(feature = "telemetry"))]
+        let exporting = false;
+        if metrics.enabled || exporting {
+            crate::metrics::install(metrics).map_err(|e| ServerError::Metrics(e.to_string()))?;
+            if metrics.enabled {
+                tracing::info!("Metrics endpoint listening on {}", metrics.address);
             }
+            tasks.spawn(crate::metrics::sample(
+                self.clients.clone(),
+                self.broadcast_tx.clone(),
+            ));
+            crate::metrics::runtime(
+                runtime.packet_queue,
+                runtime.broadcast_capacity,
+                worker_threads,
+            );
+        }
 
-            // Spawn client handler
-            let packet_tx = packet_tx.clone();
-            let broadcast_rx = self.broadcast_tx.subscribe();
-            let clients = self.clients.clone();
+        diagnostics::configure(&self.config.diagnostics);
 
-            tokio::spawn(async move {
-                if let Err(e) =
-                    connection::handle_client(stream, addr, packet_tx, broadcast_rx, clients).await
-                {
-                    log::error!("Client {} error: {}", addr, e);
+        // Write the whazzup status file
+        let whazzup = &self.config.whazzup;
+        if whazzup.enabled {
+            tracing::info!(
+                "Writing whazzup to {} every {}s",
+                whazzup.path.display(),
+                whazzup.interval_secs
+            );
+            tasks.spawn(crate::whazzup::run(
+                self.config.clone(),
+                self.clients.clone(),
+                self.clock.clone(),
+            ));
+        }
+
+        let sessions = connection::Sessions {
+            packet_tx,
+            broadcast_tx: self.broadcast_tx.clone(),
+            clients: self.clients.clone(),
+            follows: self.follows.clone(),
+            db: self.db.clone(),
+            stats: self.stats.clone(),
+            handler_stats: self.handler_stats.clone(),
+            limits: self.config.limits.clone(),
+            dialect: self.config.dialect,
+            server_callsign: self.config.server_callsign.clone(),
+            ident_banner: self.config.ident_banner().to_string(),
+            max_clients: self.config.max_clients,
+            capture: self.start_capture(),
+            chaos: self.start_chaos(),
+            health: self.health.clone(),
+            pipeline: self.pipeline.clone(),
+            random: self.random.clone(),
+            clock: self.clock.clone(),
+            middleware: self.middleware.clone(),
+            webhooks,
+        };
+
+        let mut listeners = self.open_listeners(&sessions).await?;
+        let (handover_tx, mut handover_rx) = mpsc::channel::<Handover>(1);
+        *self.handover.lock().unwrap() = Some(handover_tx);
+        let result = async {
+            // Accept connections, beating while idle so the health check can
+            // tell a quiet server from a stuck one
+            let mut beat = self.clock.interval(health::BEAT_INTERVAL);
+            let mut sessions_running = JoinSet::new();
+            let successor = loop {
+                let (stream, addr) = tokio::select! {
+                    accepted = listener.accept() => accepted?,
+                    _ = beat.tick() => {
+                        self.health.beat();
+                        continue;
+                    }
+                    Some(_) = sessions_running.join_next() => continue,
+                    Some(reply) = handover_rx.recv() => {
+                        // The new process binds the other ports itself
+                        listeners.shutdown().await;
+                        crate::metrics::close_endpoint();
+                        match upgrade::spawn_successor(&listener) {
+                            Ok(child) => {
+                                let _ = reply.send(Ok(child.id()));
+                                break child.id();
+                            }
+                            Err(e) => {
+                                tracing::error!("Could not start the new process: {}", e);
+                                if self.config.metrics.enabled {
+                                    tracing::warn!(
+                                        "The metrics endpoint stays closed until the server restarts"
+                                    );
+                                }
+                                let reopened = self.open_listeners(&sessions).await;
+                                let _ = reply.send(Err(e));
+                                listeners = reopened?;
+                                continue;
+                            }
+                        }
+                    }
+                    _ = self.shutdown.cancelled() => {
+                        tracing::info!("Shutting down...");
+                        flush_stats(&self.stats, &self.db).await;
+                        return Ok(());
+                    }
+                };
+                self.health.beat();
+                let Some(span) = sessions.admit(addr).await else {
+                    continue;
+                };
+
+                let (reader, writer) = stream.into_split();
+                let session = sessions.clone().serve(
+                    addr,
+                    connection::LineReader::new(reader, self.config.limits.max_line_bytes),
+                    connection::LineWriter::new(writer),
+                );
+                sessions_running.spawn(session.instrument(span));
+
+                tracing::info!("Accepted connection from {}", addr);
+            };
+
+            // Serve the clients this process has until they leave; new ones
+            // go to the successor
+            drop(listener);
+            *self.handover.lock().unwrap() = None;
+            self.health.start_draining();
+            let drain_secs = self.config.runtime.upgrade_drain_secs;
+            tracing::info!(
+                "Handed the FSD listener over to process {}, serving the remaining clients for up to {}s",
+                successor,
+                drain_secs
+            );
+            let deadline = self.clock.sleep(Duration::from_secs(drain_secs));
+            tokio::pin!(deadline);
+            while !self.clients.read().await.is_empty() {
+                tokio::select! {
+                    _ = beat.tick() => self.health.beat(),
+                    Some(_) = sessions_running.join_next() => {}
+                    _ = &mut deadline => {
+                        tracing::info!("Closing the connections left after the upgrade");
+                        break;
+                    }
+                    _ = self.shutdown.cancelled() => break,
                 }
-            });
+            }
+            tracing::info!("Shutting down after the upgrade...");
+            flush_stats(&self.stats, &self.db).await;
+            Ok(())
+        }
+        .await;
+        *self.handover.lock().unwrap() = None;
+        result
+    }
+
+    /// Open the listeners besides the FSD one, as configured: the control
+    /// socket, the status feeds and WebSocket clients
+    async fn open_listeners(
+        &self,
+        sessions: &connection::Sessions,
+    ) -> Result<JoinSet<()>, ServerError> {
+        let given = std::mem::take(&mut *self.listeners.lock().unwrap());
+        let mut listeners = JoinSet::new();
+        // Spawn control socket task
+        let control = &self.config.control;
+        if control.enabled {
+            let secret = control.secret.clone().unwrap_or_default();
+            let listener = bind_unless_given(given.control, &control.address).await?;
+            tracing::info!("Control socket listening on {}", control.address);
+            listeners.spawn(control::serve(
+                listener,
+                Arc::new(control::ControlState {
+                    clients: self.clients.clone(),
+                    broadcast_tx: self.broadcast_tx.clone(),
+                    db: self.db.clone(),
+                    handler_stats: self.handler_stats.clone(),
+                    health: self.health.clone(),
+                    pipeline: self.pipeline.clone(),
+                    dumper: self.dumper.clone(),
+                    airports: self.airports.clone(),
+                    webhooks: sessions.webhooks.clone(),
+                    server_callsign: self.config.server_callsign.clone(),
+                    secret,
+                }),
+            ));
+        }
+
+        // Serve the status feeds over HTTP
+        #[cfg(feature = "http")]
+        if self.config.status.enabled {
+            let address = &self.config.status.address;
+            let listener = bind_unless_given(given.status, address).await?;
+            tracing::info!("Status feeds served on http://{}", address);
+            listeners.spawn(status::serve(
+                listener,
+                Arc::new(status::StatusState::new(
+                    self.config.clone(),
+                    self.clients.clone(),
+                    self.db.clone(),
+                    self.airports.clone(),
+                    self.health.clone(),
+                )),
+            ));
+        }
+
+        // Bridge WebSocket clients into the same sessions
+        #[cfg(feature = "websocket")]
+        if self.config.websocket.enabled {
+            let address = &self.config.websocket.address;
+            let listener = bind_unless_given(given.websocket, address).await?;
+            tracing::info!("WebSocket clients accepted on ws://{}", address);
+            listeners.spawn(websocket::serve(listener, sessions.clone()));
+        }
+
+        #[cfg(not(feature = "websocket"))]
+        let _ = sessions;
+        Ok(listeners)
+    }
+
+    /// The delay and loss to inject into what clients are sent, if enabled
+    fn start_chaos(&self) -> Option<Arc<chaos::Chaos>> {
+        let config = &self.config.chaos;
+        let chaos = chaos::Chaos::new(config, &self.config.server_callsign)?;
+        tracing::warn!("==============================================================");
+        tracing::warn!(
+            "CHAOS MODE ENABLED: packets to clients are delayed by {}ms",
+            config.delay_ms
+        );
+        tracing::warn!(
+            "plus up to {}ms and dropped at a rate of {}, for {}",
+            config.jitter_ms,
+            config.drop_rate,
+            match config.callsigns.is_empty() {
+                true => "every client".to_string(),
+                false => config.callsigns.join(", "),
+            }
+        );
+        tracing::warn!("==============================================================");
+        Some(Arc::new(chaos))
+    }
 
-            log::info!("Accepted connection from {}", addr);
+    /// Start the capture writer if capturing is enabled; a directory that
+    /// can't be used is logged and leaves it off
+    fn start_capture(&self) -> Option<capture::Capturer> {
+        let config = &self.config.capture;
+        if !config.enabled {
+            return None;
+        }
+        match capture::Capturer::start(config) {
+            Ok(capturer) => {
+                tracing::info!("Capturing connections to {}", config.directory.display());
+                Some(capturer)
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Failed to start capturing to {}: {}",
+                    config.directory.display(),
+                    e
+                );
+                None
+            }
         }
     }
 }
+
+/// Why a new connection from `ip` would exceed the per-IP limits, if it would
+fn per_ip_limit_reached(
+    clients: &HashMap<SocketAddr, Client>,
+    ip: IpAddr,
+    limits: &LimitsConfig,
+) -> Option<&'static str> {
+    let from_ip: Vec<&Client> = clients.values().filter(|c| c.addr.ip() == ip).collect();
+    if from_ip.len() >= limits.max_connections_per_ip as usize {
+        return Some("too many connections from this address");
+    }
+    let unauthenticated = from_ip.iter().filter(|c| !c.is_active()).count();
+    if unauthenticated >= limits.max_unauthenticated_per_ip as usize {
+        return Some("too many unauthenticated connections from this address");
+    }
+    None
+}
+
+/// Write flushed statistics to the stats_daily table
+async fn flush_stats(stats: &StatsCollector, db: &DatabaseConnection) {
+    let daily = stats.flush();
+    match service::save_daily_stats(db, &daily).await {
+        Ok(row) => tracing::info!(
+            "Statistics for {}: peak {} clients, {} unique CIDs, {} connections, {} messages, {} flight plans",
+            row.date,
+            row.peak_clients,
+            row.unique_cids,
+            row.connections,
+            row.messages,
+            row.flight_plans
+        ),
+        Err(e) => tracing::error!("Failed to save statistics for {}: {}", daily.date, e),
+    }
+}
@@ -0,0 +1,143 @@
+//! Round-trip time to each client
+//!
+//! Every [`INTERVAL`], [`run`] sends each logged-in client a keepalive
+//! `$PI(server):(callsign):(token)`, which FSD clients answer by echoing it
+//! back as `$PO`. [`pong`] takes the round trip from the answer matching
+//! the token sent last, for `.stats` to show. A ping that goes unanswered
+//! is simply replaced by the next one.
+
+use crate::packet::{Packet, PacketType};
+use crate::server::clock::Clock;
+use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::registry::ClientRegistry;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+
+/// How often clients are pinged
+pub const INTERVAL: Duration = Duration::from_secs(30);
+
+/// Ping the clients until the server stops
+pub async fn run(
+    clients: Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: String,
+    clock: Arc<dyn Clock>,
+) {
+    loop {
+        clock.sleep(INTERVAL).await;
+        let token = clock.utc().timestamp().to_string();
+        send_pings(
+            &clients,
+            &broadcast_tx,
+            &server_callsign,
+            &token,
+            clock.now(),
+        )
+        .await;
+    }
+}
+
+/// Send `$PI` with `token` to each logged-in client, returning how many
+pub async fn send_pings(
+    clients: &Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+    token: &str,
+    now: Instant,
+) -> usize {
+    let mut sent = 0;
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    clients_map.for_each_mut(|addr, client| {
+        let Some(callsign) = client.callsign().filter(|_| client.is_active()) else {
+            return;
+        };
+        let ping = Packet {
+            packet_type: PacketType::Request,
+            command: "PI".to_string(),
+            source: server_callsign.to_string(),
+            destination: callsign.to_string(),
+            data: vec![token.to_string()],
+        };
+        let _ = broadcast_tx.send((*addr, ServerMessage::Direct(Arc::new(ping))));
+        client.ping_sent = Some((token.to_string(), now));
+        sent += 1;
+    });
+    sent
+}
+
+/// Whether `packet` is a `$PO` answering the server's ping
+pub fn is_pong(packet: &Packet, server_callsign: &str) -> bool {
+    packet.command == "PO"
+        && (packet.destination.eq_ignore_ascii_case("SERVER")
+            || packet.destination.eq_ignore_ascii_case(server_callsign))
+}
+
+/// Take the round trip from `$PO(callsign):SERVER:(token)` arriving at
+/// `now`, if it answers the ping sent last
+pub async fn pong(
+    packet: &Packet,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    now: Instant,
+) {
+    let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+    let Some(mut client) = clients_map.get_mut(&sender_addr) else {
+        return;
+    };
+    let answers = |(token, _): &(String, Instant)| packet.data.first() == Some(token);
+    if let Some((_, sent_at)) = client.ping_sent.take_if(|sent| answers(sent)) {
+        let ping = now.saturating_duration_since(sent_at);
+        tracing::trace!("Ping to {}: {:?}", packet.source, ping);
+        client.ping = Some(ping);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientState};
+
+    #[tokio::test]
+    async fn test_ping_measured_from_matching_pong() {
+        let mut client = Client::new("127.0.0.1:50001".parse().unwrap());
+        client.state = ClientState::Active;
+        client.callsign = Some("CCA1501".into());
+        let addr = client.addr;
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([(addr, client)])));
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+        let sent_at = Instant::now();
+
+        assert_eq!(
+            send_pings(&clients, &broadcast_tx, "SERVER", "1760700000", sent_at).await,
+            1
+        );
+        match rx.try_recv() {
+            Ok((to, ServerMessage::Direct(packet))) => {
+                assert_eq!(to, addr);
+                assert_eq!(packet.to_string(), "$PISERVER:CCA1501:1760700000");
+            }
+            other => panic!("expected a ping, got {:?}", other),
+        }
+
+        // A stale answer is ignored, the matching one measured once
+        let stale = Packet::parse("$POCCA1501:SERVER:1760699970").unwrap();
+        pong(&stale, addr, &clients, sent_at + Duration::from_millis(40)).await;
+        assert_eq!(clients.read().await[&addr].ping, None);
+        let answer = Packet::parse("$POCCA1501:SERVER:1760700000").unwrap();
+        pong(&answer, addr, &clients, sent_at + Duration::from_millis(85)).await;
+        pong(
+            &answer,
+            addr,
+            &clients,
+            sent_at + Duration::from_millis(500),
+        )
+        .await;
+        assert_eq!(
+            clients.read().await[&addr].ping,
+            Some(Duration::from_millis(85))
+        );
+    }
+}
@@ -0,0 +1,381 @@
+//! Depths of the queues between the connections and the processor
+//!
+//! Packets from every connection wait in one channel for the processor, and
+//! everything sent to clients goes through one broadcast channel that each
+//! connection's writer reads at its own pace. A writer that falls further
+//! behind than the broadcast channel holds has lagged: what it missed is
+//! dropped and its connection closed. Both sizes come from the `[runtime]`
+//! section of the configuration. [`sample`] reads the depths into the
+//! metrics and warns about a queue that stays nearly full, naming the client
+//! if it is one connection's.
+
+use crate::client::Client;
+use crate::metrics;
+use crate::packet::Inbound;
+use crate::server::config::ServerMessage;
+use crate::server::registry::ClientRegistry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, RwLock};
+
+/// How often [`sample`] reads the queues
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A queue this full (in percent) for [`FULL_FOR`] is warned about
+const FULL_PERCENT: usize = 80;
+const FULL_FOR: Duration = Duration::from_secs(5);
+
+/// At most one warning about full queues per interval
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Connections named in a report, deepest queue first
+const WORST_CLIENTS: usize = 5;
+
+type Message = (SocketAddr, ServerMessage);
+
+/// A connection's end of the broadcast channel; the writer holds the lock
+/// only while waiting for the next message, so the sampler can read the
+/// depth of a writer that is stuck sending
+pub type ClientQueue = Arc<tokio::sync::Mutex<broadcast::Receiver<Message>>>;
+
+pub struct Pipeline {
+    broadcast_tx: broadcast::Sender<Message>,
+    /// Messages a connection may fall behind before it lags
+    broadcast_capacity: usize,
+    packet_tx: OnceLock<mpsc::WeakSender<(SocketAddr, Inbound)>>,
+    clients: Mutex<HashMap<SocketAddr, ClientQueue>>,
+    packet_high_water: AtomicUsize,
+    broadcast_high_water: AtomicUsize,
+    lagged: AtomicU64,
+    dropped: AtomicU64,
+    full: Mutex<FullQueues>,
+}
+
+/// Queues over [`FULL_PERCENT`] and since when
+#[derive(Default)]
+struct FullQueues {
+    since: HashMap<String, Instant>,
+    last_warning: Option<Instant>,
+}
+
+/// The queues at one point in time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PipelineReport {
+    /// Packets waiting for the processor
+    pub packets: QueueReport,
+    /// Messages the slowest connection has yet to send
+    pub broadcast: QueueReport,
+    /// Connections with messages waiting, deepest first
+    pub worst_clients: Vec<ClientQueueReport>,
+    /// Times a connection fell too far behind
+    pub lagged: u64,
+    /// Messages those connections missed
+    pub dropped: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueueReport {
+    pub depth: usize,
+    pub capacity: usize,
+    /// Deepest it has been since the server started
+    pub high_water: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientQueueReport {
+    pub addr: SocketAddr,
+    pub callsign: Option<String>,
+    pub depth: usize,
+}
+
+impl QueueReport {
+    fn is_full(&self) -> bool {
+        self.capacity > 0 && self.depth * 100 >= self.capacity * FULL_PERCENT
+    }
+}
+
+impl Pipeline {
+    /// `broadcast_capacity` is the one `broadcast_tx` was created with,
+    /// which the channel doesn't tell
+    pub fn new(broadcast_tx: broadcast::Sender<Message>, broadcast_capacity: usize) -> Self {
+        Self {
+            broadcast_tx,
+            broadcast_capacity: broadcast_capacity.next_power_of_two(),
+            packet_tx: OnceLock::new(),
+            clients: Mutex::new(HashMap::new()),
+            packet_high_water: AtomicUsize::new(0),
+            broadcast_high_water: AtomicUsize::new(0),
+            lagged: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            full: Mutex::new(FullQueues::default()),
+        }
+    }
+
+    /// Report on the processor's channel from now on
+    pub fn watch_packets(&self, packet_tx: &mpsc::Sender<(SocketAddr, Inbound)>) {
+        let _ = self.packet_tx.set(packet_tx.downgrade());
+    }
+
+    /// Note the depth of the processor's channel after a packet was queued
+    pub fn packet_queued(&self, packet_tx: &mpsc::Sender<(SocketAddr, Inbound)>) {
+        let depth = packet_tx.max_capacity() - packet_tx.capacity();
+        self.packet_high_water.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    /// A queue for the connection from `addr`, counted until [`Self::close`]
+    pub fn subscribe(&self, addr: SocketAddr) -> ClientQueue {
+        let queue = Arc::new(tokio::sync::Mutex::new(self.broadcast_tx.subscribe()));
+        self.clients.lock().unwrap().insert(addr, queue.clone());
+        queue
+    }
+
+    pub fn close(&self, addr: SocketAddr) {
+        self.clients.lock().unwrap().remove(&addr);
+    }
+
+    /// A connection fell behind and missed `skipped` messages
+    pub fn lagged(&self, skipped: u64) {
+        metrics::broadcast_lagged(skipped);
+        self.lagged.fetch_add(1, Ordering::Relaxed);
+        self.dropped.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    /// The queues now, with the callsigns from `clients`
+    pub fn report(&self, clients: &HashMap<SocketAddr, Client>) -> PipelineReport {
+        let packets = match self.packet_tx.get().and_then(|tx| tx.upgrade()) {
+            Some(tx) => {
+                let depth = tx.max_capacity() - tx.capacity();
+                QueueReport {
+                    depth,
+                    capacity: tx.max_capacity(),
+                    high_water: self
+                        .packet_high_water
+                        .fetch_max(depth, Ordering::Relaxed)
+                        .max(depth),
+                }
+            }
+            None => QueueReport {
+                depth: 0,
+                capacity: 0,
+                high_water: self.packet_high_water.load(Ordering::Relaxed),
+            },
+        };
+
+        let broadcast_depth = self.broadcast_tx.len();
+        let broadcast = QueueReport {
+            depth: broadcast_depth,
+            capacity: self.broadcast_capacity,
+            high_water: self
+                .broadcast_high_water
+                .fetch_max(broadcast_depth, Ordering::Relaxed)
+                .max(broadcast_depth),
+        };
+
+        let mut worst_clients: Vec<ClientQueueReport> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(addr, queue)| {
+                // Locked while the writer waits, so nothing is queued
+                let depth = queue.try_lock().map_or(0, |rx| rx.len());
+                (depth > 0).then(|| ClientQueueReport {
+                    addr: *addr,
+                    callsign: clients
+                        .get(addr)
+                        .and_then(|c| c.callsign().map(str::to_owned)),
+                    depth,
+                })
+            })
+            .collect();
+        worst_clients.sort_by(|a, b| b.depth.cmp(&a.depth).then(a.addr.cmp(&b.addr)));
+        worst_clients.truncate(WORST_CLIENTS);
+
+        PipelineReport {
+            packets,
+            broadcast,
+            worst_clients,
+            lagged: self.lagged.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Names of the queues that have been nearly full for [`FULL_FOR`] at
+    /// `now`, if it's time for another warning about them
+    fn full_for_too_long(&self, report: &PipelineReport, now: Instant) -> Vec<String> {
+        let mut full = Vec::new();
+        if report.packets.is_full() {
+            full.push("packet queue".to_string());
+        }
+        if report.broadcast.is_full() {
+            full.push("broadcast queue".to_string());
+        }
+        for client in &report.worst_clients {
+            let queue = QueueReport {
+                depth: client.depth,
+                capacity: self.broadcast_capacity,
+                high_water: 0,
+            };
+            if queue.is_full() {
+                let name = client.callsign.as_deref().unwrap_or("an unnamed client");
+                full.push(format!("queue of {} ({})", name, client.addr));
+            }
+        }
+
+        let mut state = self.full.lock().unwrap();
+        state.since.retain(|name, _| full.contains(name));
+        for name in &full {
+            state.since.entry(name.clone()).or_insert(now);
+        }
+        let stuck: Vec<String> = full
+            .into_iter()
+            .filter(|name| now.duration_since(state.since[name]) >= FULL_FOR)
+            .collect();
+        let recently_warned = state
+            .last_warning
+            .is_some_and(|last| now.duration_since(last) < WARNING_INTERVAL);
+        if stuck.is_empty() || recently_warned {
+            return Vec::new();
+        }
+        state.last_warning = Some(now);
+        stuck
+    }
+}
+
+/// Record the queues every [`SAMPLE_INTERVAL`]
+pub async fn sample(pipeline: Arc<Pipeline>, clients: Arc<RwLock<ClientRegistry>>) {
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let report = pipeline.report(&*clients.read().await);
+        metrics::queues(&report);
+        let full = pipeline.full_for_too_long(&report, Instant::now());
+        if !full.is_empty() {
+            tracing::warn!(
+                "Over {}% full for at least {}s: {}",
+                FULL_PERCENT,
+                FULL_FOR.as_secs(),
+                full.join(", ")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RuntimeConfig;
+    use crate::packet::Packet;
+
+    const BROADCAST_CAPACITY: usize = RuntimeConfig::DEFAULT_BROADCAST_CAPACITY;
+
+    fn message(n: usize) -> Message {
+        let packet = Packet::parse(&format!("#TMSERVER:*:message {}", n)).unwrap();
+        (
+            "127.0.0.1:0".parse().unwrap(),
+            ServerMessage::Packet(Arc::new(packet)),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_share_packet() {
+        for subscribers in [1, 10, 100] {
+            let (broadcast_tx, _) = broadcast::channel(BROADCAST_CAPACITY);
+            let pipeline = Pipeline::new(broadcast_tx.clone(), BROADCAST_CAPACITY);
+            let queues: Vec<_> = (0..subscribers)
+                .map(|n| pipeline.subscribe(SocketAddr::from(([127, 0, 0, 1], 50000 + n))))
+                .collect();
+
+            let (addr, msg) = message(0);
+            let ServerMessage::Packet(sent) = &msg else {
+                unreachable!()
+            };
+            let sent = Arc::clone(sent);
+            broadcast_tx.send((addr, msg)).unwrap();
+
+            let mut received = Vec::new();
+            for queue in &queues {
+                match queue.lock().await.recv().await {
+                    Ok((_, ServerMessage::Packet(packet))) => received.push(packet),
+                    other => panic!("expected the packet, got {:?}", other),
+                }
+            }
+            // The one packet parsed, nothing copied for the subscribers
+            assert!(received.iter().all(|packet| Arc::ptr_eq(packet, &sent)));
+            assert_eq!(Arc::strong_count(&sent), subscribers as usize + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stuck_consumer() {
+        let (broadcast_tx, _) = broadcast::channel(BROADCAST_CAPACITY);
+        let (packet_tx, _packet_rx) = mpsc::channel(RuntimeConfig::DEFAULT_PACKET_QUEUE);
+        let pipeline = Pipeline::new(broadcast_tx.clone(), BROADCAST_CAPACITY);
+        pipeline.watch_packets(&packet_tx);
+
+        let stuck: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+        let keeping_up: SocketAddr = "127.0.0.1:50002".parse().unwrap();
+        let mut clients = HashMap::from([(stuck, Client::new(stuck))]);
+        clients.get_mut(&stuck).unwrap().callsign = Some("CCA1501".into());
+        let stuck_queue = pipeline.subscribe(stuck);
+        let keeping_up_queue = pipeline.subscribe(keeping_up);
+
+        // The processor never reads, the stuck writer is busy elsewhere
+        for n in 0..900 {
+            packet_tx
+                .send((keeping_up, Inbound::parse("#TMCCA1501:*:hello").unwrap()))
+                .await
+                .unwrap();
+            pipeline.packet_queued(&packet_tx);
+            broadcast_tx.send(message(n)).unwrap();
+            keeping_up_queue.lock().await.recv().await.unwrap();
+        }
+
+        let report = pipeline.report(&clients);
+        assert_eq!(report.packets.depth, 900);
+        assert_eq!(report.packets.high_water, 900);
+        assert_eq!(report.broadcast.depth, 900);
+        assert_eq!(
+            report.worst_clients,
+            [ClientQueueReport {
+                addr: stuck,
+                callsign: Some("CCA1501".to_string()),
+                depth: 900,
+            }]
+        );
+
+        // Warned about once it has been full for a while, then not again soon
+        let now = Instant::now();
+        assert!(pipeline.full_for_too_long(&report, now).is_empty());
+        let full = pipeline.full_for_too_long(&report, now + FULL_FOR);
+        assert_eq!(
+            full,
+            [
+                "packet queue",
+                "broadcast queue",
+                "queue of CCA1501 (127.0.0.1:50001)"
+            ]
+        );
+        assert!(pipeline
+            .full_for_too_long(&report, now + FULL_FOR * 2)
+            .is_empty());
+
+        // Going past the capacity makes the stuck writer lag
+        for n in 900..BROADCAST_CAPACITY + 100 {
+            broadcast_tx.send(message(n)).unwrap();
+        }
+        match stuck_queue.lock().await.recv().await {
+            Err(broadcast::error::RecvError::Lagged(skipped)) => pipeline.lagged(skipped),
+            other => panic!("expected a lag, got {:?}", other),
+        }
+        pipeline.close(stuck);
+        let report = pipeline.report(&clients);
+        assert_eq!((report.lagged, report.dropped), (1, 100));
+        assert!(report.worst_clients.iter().all(|client| client.addr != stuck));
+        assert_eq!(report.broadcast.high_water, BROADCAST_CAPACITY);
+    }
+}
@@ -1,62 +1,507 @@
-use crate::client::Client;
-use crate::packet::Packet;
-use crate::server::config::{ServerConfig, ServerMessage};
+use crate::packet::{Inbound, Packet, PacketType, PositionUpdate};
+use crate::server::atis_broadcast;
+use crate::server::capabilities;
+use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
 use crate::server::handlers;
-use sea_orm::DatabaseConnection;
-use std::collections::HashMap;
+use crate::server::middleware::PacketContext;
+use crate::server::ping;
+use crate::server::state::ServerState;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::{broadcast, RwLock};
+use tracing::Instrument;
 
 /// Process incoming packets and route to appropriate handlers
-pub async fn process_packet(
-    packet: Packet,
-    sender_addr: SocketAddr,
-    clients: &Arc<RwLock<HashMap<SocketAddr, Client>>>,
-    callsign_map: &Arc<RwLock<HashMap<String, SocketAddr>>>,
-    config: &ServerConfig,
-    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
-    db: &Arc<DatabaseConnection>,
-) {
-    log::debug!("Processing packet from {}: {}", sender_addr, packet);
+pub async fn process_packet(packet: Inbound, sender_addr: SocketAddr, state: &ServerState) {
+    // Handlers run in the connection's span, so their events carry its
+    // callsign and CID
+    let (span, callsign) = match state.clients.read().await.get(&sender_addr) {
+        Some(client) => (client.span.clone(), client.callsign.clone()),
+        None => (tracing::Span::none(), None),
+    };
+
+    let started = state.clock.now();
+    match packet {
+        // Most of the traffic: only sliced, and passed on as it came
+        Inbound::Position(update) => {
+            let command = update.command();
+            let route = handle_position(update, sender_addr, state)
+                .instrument(tracing::info_span!(parent: &span, "packet", command));
+            state.handler_stats.time(command, route).await;
+            span.in_scope(|| diagnostics::packet_handled(command, started.elapsed()));
+        }
+        Inbound::Packet(packet) => {
+            let packet = if state.middleware.is_empty() {
+                packet
+            } else {
+                let ctx = PacketContext {
+                    addr: sender_addr,
+                    callsign,
+                };
+                let source = packet.source.clone();
+                let inbound = state
+                    .middleware
+                    .inbound(&ctx, packet)
+                    .instrument(span.clone());
+                match inbound.await {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => return,
+                    Err(rejection) => {
+                        let callsign = ctx.callsign.as_deref().unwrap_or(&source);
+                        let error_packet =
+                            rejection.to_packet(&state.config.server_callsign, callsign);
+                        let _ = state
+                            .broadcast_tx
+                            .send((sender_addr, ServerMessage::Direct(Arc::new(error_packet))));
+                        return;
+                    }
+                }
+            };
+            let command = packet.command.clone();
+            let route = route_packet(packet, sender_addr, state).instrument(span.clone());
+            state.handler_stats.time(&command, route).await;
+            span.in_scope(|| diagnostics::packet_handled(&command, started.elapsed()));
+        }
+    }
+}
+
+async fn handle_position(update: PositionUpdate, sender_addr: SocketAddr, state: &ServerState) {
+    tracing::debug!(
+        "Processing packet from {}: {}",
+        sender_addr,
+        update.line().trim_end()
+    );
+
+    let config = &state.config;
+    if update.packet_type() == PacketType::AtcUpdate {
+        handlers::handle_atc_position_update(
+            update,
+            sender_addr,
+            &state.clients,
+            &state.broadcast_tx,
+            &config.visibility,
+            &config.server_callsign,
+            state.clock.now(),
+        )
+        .await
+    } else {
+        handlers::handle_position_update(update, sender_addr, state).await
+    }
+}
+
+#[tracing::instrument(name = "packet", skip_all, fields(command = %packet.command))]
+async fn route_packet(packet: Packet, sender_addr: SocketAddr, state: &ServerState) {
+    let ServerState {
+        clients,
+        config,
+        broadcast_tx,
+        db,
+        weather,
+        airports,
+        clock,
+        ..
+    } = state;
+    tracing::debug!(
+        "Processing packet from {}: {}",
+        sender_addr,
+        crate::packet::redact_password(&packet.to_string())
+    );
+
+    if packet.command == "SB" {
+        handlers::handle_plane_info(&packet, sender_addr, clients, &config.limits).await;
+    }
+    if let Some(capability) = capabilities::required(&packet) {
+        let clients = clients.read().await;
+        capabilities::forward(packet, capability, sender_addr, &clients, broadcast_tx);
+        return;
+    }
 
     match packet.command.as_str() {
-        "ID" => {
-            handlers::handle_identification(
+        "ID" => handlers::handle_identification(packet, sender_addr, state).await,
+        "AA" | "AP" => handlers::handle_login(packet, sender_addr, state).await,
+        "DA" | "DP" => {
+            handlers::handle_logoff(
                 packet,
                 sender_addr,
                 clients,
-                callsign_map,
-                config,
+                &state.callsign_map,
                 broadcast_tx,
                 db,
             )
             .await
         }
-        "AA" | "AP" => {
-            handlers::handle_login(packet, sender_addr, clients, callsign_map, broadcast_tx, db).await
+        "TM" if handlers::is_server_command(&packet) => {
+            handlers::handle_server_command(packet, sender_addr, state).await
         }
-        "DA" | "DP" => {
-            handlers::handle_logoff(packet, sender_addr, clients, callsign_map, broadcast_tx).await
+        "TM" => handlers::handle_text_message(packet, sender_addr, state).await,
+        "CQ" if handlers::nearest_atc::is_query(&packet, &config.server_callsign) => {
+            let clients = clients.read().await;
+            handlers::nearest_atc::handle_query(
+                &packet,
+                sender_addr,
+                &clients,
+                broadcast_tx,
+                &airports.get(),
+                &config.visibility,
+                &config.server_callsign,
+            )
         }
-        "TM" => {
-            handlers::handle_text_message(packet, sender_addr, broadcast_tx).await
+        "CQ" if handlers::client_stats::is_query(&packet, &config.server_callsign) => {
+            handlers::client_stats::handle_query(
+                &packet,
+                sender_addr,
+                clients,
+                broadcast_tx,
+                db,
+                &config.server_callsign,
+                clock.now(),
+            )
+            .await
         }
         "CQ" => {
             handlers::handle_request(packet, sender_addr, clients, broadcast_tx).await
         }
         "CR" => {
-            handlers::handle_response(packet, sender_addr, broadcast_tx).await
+            let atis_uploaded = atis_broadcast::is_upload_end(&packet);
+            handlers::handle_response(
+                packet,
+                sender_addr,
+                clients,
+                broadcast_tx,
+                &config.limits,
+                &config.server_callsign,
+            )
+            .await;
+            // A changed ATIS goes out right away rather than on the next check
+            if let (true, Some(interval)) = (atis_uploaded, config.features.atis_rebroadcast()) {
+                atis_broadcast::rebroadcast(clients, broadcast_tx, interval, clock.now()).await;
+            }
+        }
+        "PO" if ping::is_pong(&packet, &config.server_callsign) => {
+            ping::pong(&packet, sender_addr, clients, clock.now()).await
+        }
+        "AX" if handlers::metar_subscription::is_subscription(&packet) => {
+            let weather = weather.borrow().clone();
+            handlers::metar_subscription::handle_subscription(
+                packet,
+                sender_addr,
+                clients,
+                broadcast_tx,
+                &weather,
+                &config.server_callsign,
+            )
+            .await
         }
         "AX" => {
-            handlers::handle_metar_request(packet, sender_addr, broadcast_tx).await
+            let weather = weather.borrow().clone();
+            handlers::handle_metar_request(
+                packet,
+                sender_addr,
+                broadcast_tx,
+                &weather,
+                &config.server_callsign,
+            )
+            .await
+        }
+        // Position updates sent as JSON
+        "N" | "S" | "Y" | "%" => match PositionUpdate::from_packet(&packet) {
+            Some(update) => handle_position(update, sender_addr, state).await,
+            None => tracing::debug!("Ignoring malformed position update from {}", sender_addr),
+        },
+        "'" => {
+            handlers::handle_visibility_center(packet, sender_addr, clients, &config.visibility)
+                .await
+        }
+        "FP" => handlers::handle_flight_plan(packet, sender_addr, state).await,
+        "AM" => {
+            handlers::handle_amendment(packet, sender_addr, clients, broadcast_tx, &config.limits)
+                .await
+        }
+        "HO" | "HA" => handlers::handle_handoff(packet, sender_addr, clients, broadcast_tx).await,
+        "PC" => handlers::handle_pro_controller(packet, sender_addr, clients, broadcast_tx).await,
+        "ZC" => {
+            handlers::handle_auth_challenge(
+                packet,
+                sender_addr,
+                clients,
+                broadcast_tx,
+                db,
+                &config.server_callsign,
+            )
+            .await
+        }
+        "ZR" => {
+            handlers::handle_auth_response(
+                packet,
+                sender_addr,
+                clients,
+                broadcast_tx,
+                db,
+                &config.server_callsign,
+                &state.webhooks,
+            )
+            .await
         }
-        "N" | "S" | "Y" => {
-            handlers::handle_position_update(packet, sender_addr, broadcast_tx).await
+        _ if config.features.strict_mode => {
+            tracing::warn!(
+                "Rejecting unknown command {} from {}",
+                packet.command,
+                sender_addr
+            );
+            let error_packet = Packet {
+                packet_type: crate::packet::PacketType::Request,
+                command: "ER".to_string(),
+                source: config.server_callsign.clone(),
+                destination: packet.source.clone(),
+                data: vec![
+                    "004".to_string(),
+                    String::new(),
+                    format!("Unknown command {}", packet.command),
+                ],
+            };
+            let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(error_packet))));
         }
-        "FP" => handlers::handle_flight_plan(packet, sender_addr, broadcast_tx).await,
         _ => {
-            log::debug!("Unhandled command: {}", packet.command);
+            tracing::debug!("Unhandled command: {}", packet.command);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::config::{FeaturesConfig, LogFormat, WhitelistConfig};
+    use crate::server::config::ServerConfig;
+    use crate::server::registry::ClientRegistry;
+    use sea_orm::TransactionTrait;
+    use std::time::Duration;
+    use tokio::sync::RwLock;
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::{Layer, Registry};
+
+    const ADDR: &str = "127.0.0.1:50001";
+
+    /// Send an unknown command and return the replies
+    async fn send_unknown_command(strict_mode: bool) -> Vec<ServerMessage> {
+        let db = Arc::new(crate::db::init_ephemeral().await.unwrap());
+        let state = ServerState {
+            config: ServerConfig {
+                features: FeaturesConfig {
+                    strict_mode,
+                    ..FeaturesConfig::default()
+                },
+                ..ServerConfig::default()
+            },
+            ..ServerState::for_tests(db)
+        };
+        let mut rx = state.broadcast_tx.subscribe();
+
+        process_packet(
+            Packet::parse("$XXCCA1501:SERVER").unwrap().into(),
+            ADDR.parse().unwrap(),
+            &state,
+        )
+        .await;
+
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|(_, msg)| msg)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command_ignored() {
+        assert!(send_unknown_command(false).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command_rejected_in_strict_mode() {
+        let replies = send_unknown_command(true).await;
+        assert!(matches!(
+            replies.as_slice(),
+            [ServerMessage::Direct(packet)] if packet.command == "ER" && packet.data[0] == "004"
+        ));
+    }
+
+    /// Everything a test subscriber logged
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Log in CCA1501 and return what was logged at debug level in `format`;
+    /// with `db_busy` the database is held up that long when the `#AP` arrives
+    async fn log_in(format: LogFormat, db_busy: Option<Duration>) -> String {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        log_in_with(
+            crate::logging::layer(format, move || writer.clone(), LevelFilter::DEBUG),
+            db_busy,
+        )
+        .await;
+        let logged = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        logged
+    }
+
+    /// Log in CCA1501 with `layer` seeing the events and spans; the
+    /// connection's span is closed by the time this returns
+    async fn log_in_with(layer: Box<dyn Layer<Registry> + Send + Sync>, db_busy: Option<Duration>) {
+        let _default = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+        let db = Arc::new(crate::db::init_ephemeral().await.unwrap());
+        let password_hash = crate::auth::password::hash_password("secret").unwrap();
+        crate::db::service::create_user(
+            &db,
+            "1234567".to_string(),
+            password_hash,
+            "Test Pilot".to_string(),
+            1,
+            1,
+        )
+        .await
+        .unwrap();
+        let config = ServerConfig {
+            whitelist: WhitelistConfig {
+                enforce: false,
+                ..WhitelistConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        let addr: SocketAddr = ADDR.parse().unwrap();
+        let state = ServerState {
+            config,
+            clients: Arc::new(RwLock::new(ClientRegistry::from([(
+                addr,
+                Client::new(addr),
+            )]))),
+            ..ServerState::for_tests(db.clone())
+        };
+
+        for line in [
+            "$IDCCA1501:SERVER:69d7:Test Client:3:2:1234567:12345",
+            "#APCCA1501:SERVER:1234567:secret:1:100:1:Test Pilot",
+        ] {
+            if let (true, Some(busy)) = (line.starts_with("#AP"), db_busy) {
+                // The ephemeral database has a single connection, so a
+                // transaction on it makes every other query wait
+                let transaction = db.begin().await.unwrap();
+                tokio::spawn(async move {
+                    tokio::time::sleep(busy).await;
+                    transaction.commit().await.unwrap();
+                });
+            }
+            process_packet(Packet::parse(line).unwrap().into(), addr, &state).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_events_carry_callsign() {
+        let logged = log_in(LogFormat::Text, None).await;
+        let line = logged
+            .lines()
+            .find(|line| line.contains("Login successful"))
+            .unwrap_or_else(|| panic!("no login event in\n{}", logged));
+        assert!(
+            line.contains("conn{addr=127.0.0.1:50001 callsign=\"CCA1501\" cid=\"1234567\"}:packet{command=AP}: "),
+            "{}",
+            line
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_handler_is_logged() {
+        let logged = log_in(LogFormat::Text, Some(Duration::from_millis(150))).await;
+        let line = logged
+            .lines()
+            .find(|line| line.contains("Slow packet handler"))
+            .unwrap_or_else(|| panic!("no slow handler warning in\n{}", logged));
+        assert!(line.contains("WARN"), "{}", line);
+        assert!(line.contains("callsign=\"CCA1501\""), "{}", line);
+        assert!(line.contains("command=\"AP\""), "{}", line);
+        let elapsed_ms: u64 = line
+            .split("elapsed_ms=")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|ms| ms.parse().ok())
+            .unwrap_or_else(|| panic!("no elapsed time in {}", line));
+        assert!(elapsed_ms >= 150, "{}", line);
+    }
+
+    #[tokio::test]
+    async fn test_json_log_fields() {
+        let logged = log_in(LogFormat::Json, None).await;
+        let lines: Vec<serde_json::Value> = logged
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let login = lines
+            .iter()
+            .find(|line| line["message"] == "Login successful for CCA1501")
+            .unwrap_or_else(|| panic!("no login event in\n{}", logged));
+        assert_eq!(login["level"], "INFO");
+        assert_eq!(login["span"], "conn:packet");
+        assert_eq!(login["addr"], ADDR);
+        assert_eq!(login["callsign"], "CCA1501");
+        assert_eq!(login["cid"], "1234567");
+        assert_eq!(login["command"], "AP");
+        let timestamp = login["timestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+    }
+
+    #[cfg(feature = "telemetry")]
+    #[tokio::test]
+    async fn test_login_spans_are_exported() {
+        use opentelemetry::trace::TracerProvider;
+        use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        log_in_with(crate::telemetry::layer(provider.tracer("test")), None).await;
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let attribute = |span: &SpanData, key: &str| {
+            span.attributes
+                .iter()
+                .find(|attribute| attribute.key.as_str() == key)
+                .map(|attribute| attribute.value.to_string())
+        };
+        let conn = spans
+            .iter()
+            .find(|span| span.name == "conn")
+            .unwrap_or_else(|| panic!("no connection span in {:?}", spans));
+        assert_eq!(attribute(conn, "addr").as_deref(), Some(ADDR));
+        assert_eq!(attribute(conn, "callsign").as_deref(), Some("CCA1501"));
+        assert_eq!(attribute(conn, "cid").as_deref(), Some("1234567"));
+
+        let packets: Vec<&SpanData> = spans.iter().filter(|span| span.name == "packet").collect();
+        let commands: Vec<Option<String>> = packets
+            .iter()
+            .map(|span| attribute(span, "command"))
+            .collect();
+        assert_eq!(commands, [Some("ID".to_string()), Some("AP".to_string())]);
+        for packet in &packets {
+            assert_eq!(packet.parent_span_id, conn.span_context.span_id());
+            assert_eq!(packet.span_context.trace_id(), conn.span_context.trace_id());
         }
+        assert!(
+            packets[1]
+                .events
+                .iter()
+                .any(|event| event.name.contains("Login successful")),
+            "{:?}",
+            packets[1].events
+        );
     }
 }
@@ -0,0 +1,77 @@
+//! The server's random numbers, drawn from one generator that can be seeded
+//!
+//! Tokens such as the `$DI` greeting and auth challenges come from
+//! [`Random`], which is seeded by the OS unless `runtime.random_seed` is set.
+//! With a seed the draws repeat from run to run, so a test can assert the
+//! exact token a client gets. Connections draw in the order the server gets
+//! to them, so that only holds while one connection at a time is drawing.
+
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use std::sync::Mutex;
+
+/// Random bytes in a token; it is twice as many hex digits long
+pub const TOKEN_BYTES: usize = 11;
+
+pub struct Random {
+    rng: Mutex<StdRng>,
+}
+
+impl Random {
+    /// A generator seeded with `seed`, or by the OS if there is none
+    pub fn new(seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            rng: Mutex::new(rng),
+        }
+    }
+
+    /// A token of [`TOKEN_BYTES`] random bytes in lowercase hex
+    pub fn token(&self) -> String {
+        let mut bytes = [0; TOKEN_BYTES];
+        self.rng.lock().unwrap().fill_bytes(&mut bytes);
+        hex::encode(bytes)
+    }
+
+    /// A generator of its own, e.g. for one connection, seeded from this one
+    pub fn fork(&self) -> StdRng {
+        StdRng::seed_from_u64(self.rng.lock().unwrap().gen())
+    }
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: u64 = 42;
+    const OTHER_SEED: u64 = 43;
+
+    #[test]
+    fn test_token_is_hex() {
+        let token = Random::default().token();
+        assert_eq!(token.len(), TOKEN_BYTES * 2);
+        assert!(token
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)));
+    }
+
+    #[test]
+    fn test_seed_repeats_draws() {
+        let (first, second) = (Random::new(Some(SEED)), Random::new(Some(SEED)));
+        let tokens: Vec<String> = (0..3).map(|_| first.token()).collect();
+        assert_eq!(tokens, (0..3).map(|_| second.token()).collect::<Vec<_>>());
+        assert_ne!(tokens[0], tokens[1]);
+        assert_eq!(first.fork().gen::<u64>(), second.fork().gen::<u64>());
+
+        assert_ne!(Random::new(Some(OTHER_SEED)).token(), tokens[0]);
+    }
+}
@@ -0,0 +1,543 @@
+//! The connected clients, with indexes over what routing looks them up by
+//!
+//! [`ClientRegistry`] reads like the `HashMap` it wraps, but every change
+//! goes through it so the indexes by callsign, client type, tuned frequency
+//! and position stay in step with the clients: [`ClientRegistry::insert`] and
+//! [`ClientRegistry::remove`] on login and disconnect, and the guard from
+//! [`ClientRegistry::get_mut`] re-indexes the client when it is dropped.
+//! Debug builds check after every change that the indexes only name
+//! clients that are in the map, and name each of them where it belongs.
+
+use crate::callsign::Callsign;
+use crate::client::{Client, ClientType};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+
+/// Size of a cell of the position index, in degrees of latitude and
+/// longitude; one degree of latitude is 60 nm
+const CELL_DEGREES: f64 = 1.0;
+const ROWS: i32 = (180.0 / CELL_DEGREES) as i32;
+const COLUMNS: i32 = (360.0 / CELL_DEGREES) as i32;
+
+/// A cell of the position index, as (row, column) from the south pole and
+/// the antimeridian
+type Cell = (i32, i32);
+
+fn cell(latitude: f64, longitude: f64) -> Cell {
+    let row = ((latitude + 90.0) / CELL_DEGREES).floor() as i32;
+    let column = ((longitude + 180.0) / CELL_DEGREES).floor() as i32;
+    (row.clamp(0, ROWS - 1), column.rem_euclid(COLUMNS))
+}
+
+/// Where a client is filed in the indexes
+#[derive(Debug, Clone, PartialEq)]
+struct Keys {
+    callsign: Option<Callsign>,
+    client_type: Option<ClientType>,
+    frequency: Option<u32>,
+    cell: Option<Cell>,
+}
+
+impl Keys {
+    fn of(client: &Client) -> Self {
+        Self {
+            callsign: client.callsign.clone(),
+            client_type: client.client_type.clone(),
+            frequency: client.frequency,
+            cell: client
+                .latitude
+                .zip(client.longitude)
+                .map(|(latitude, longitude)| cell(latitude, longitude)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    keys: HashMap<SocketAddr, Keys>,
+    /// More than one client per callsign only while a login replaces another
+    by_callsign: HashMap<Callsign, HashSet<SocketAddr>>,
+    by_type: HashMap<ClientType, HashSet<SocketAddr>>,
+    by_frequency: HashMap<u32, HashSet<SocketAddr>>,
+    by_cell: HashMap<Cell, HashSet<SocketAddr>>,
+    /// Clients that haven't reported a position, and so are in range of
+    /// everyone
+    unplaced: HashSet<SocketAddr>,
+}
+
+impl Index {
+    fn update(&mut self, addr: SocketAddr, client: &Client) {
+        let keys = Keys::of(client);
+        if self.keys.get(&addr) == Some(&keys) {
+            return;
+        }
+        self.remove(addr);
+        if let Some(callsign) = &keys.callsign {
+            file(&mut self.by_callsign, callsign.clone(), addr);
+        }
+        if let Some(client_type) = &keys.client_type {
+            file(&mut self.by_type, client_type.clone(), addr);
+        }
+        if let Some(frequency) = keys.frequency {
+            file(&mut self.by_frequency, frequency, addr);
+        }
+        match keys.cell {
+            Some(cell) => file(&mut self.by_cell, cell, addr),
+            None => {
+                self.unplaced.insert(addr);
+            }
+        }
+        self.keys.insert(addr, keys);
+    }
+
+    fn remove(&mut self, addr: SocketAddr) {
+        let Some(keys) = self.keys.remove(&addr) else {
+            return;
+        };
+        if let Some(callsign) = keys.callsign {
+            unfile(&mut self.by_callsign, callsign, addr);
+        }
+        if let Some(client_type) = keys.client_type {
+            unfile(&mut self.by_type, client_type, addr);
+        }
+        if let Some(frequency) = keys.frequency {
+            unfile(&mut self.by_frequency, frequency, addr);
+        }
+        match keys.cell {
+            Some(cell) => unfile(&mut self.by_cell, cell, addr),
+            None => {
+                self.unplaced.remove(&addr);
+            }
+        }
+    }
+}
+
+fn file<K: Hash + Eq>(index: &mut HashMap<K, HashSet<SocketAddr>>, key: K, addr: SocketAddr) {
+    index.entry(key).or_default().insert(addr);
+}
+
+fn unfile<K: Hash + Eq>(index: &mut HashMap<K, HashSet<SocketAddr>>, key: K, addr: SocketAddr) {
+    if let Some(addrs) = index.get_mut(&key) {
+        addrs.remove(&addr);
+        if addrs.is_empty() {
+            index.remove(&key);
+        }
+    }
+}
+
+/// Every connected client by address
+#[derive(Debug, Default)]
+pub struct ClientRegistry {
+    clients: HashMap<SocketAddr, Client>,
+    index: Index,
+}
+
+impl Deref for ClientRegistry {
+    type Target = HashMap<SocketAddr, Client>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.clients
+    }
+}
+
+impl<const N: usize> From<[(SocketAddr, Client); N]> for ClientRegistry {
+    fn from(clients: [(SocketAddr, Client); N]) -> Self {
+        clients.into_iter().collect()
+    }
+}
+
+impl FromIterator<(SocketAddr, Client)> for ClientRegistry {
+    fn from_iter<I: IntoIterator<Item = (SocketAddr, Client)>>(clients: I) -> Self {
+        let mut registry = Self::default();
+        for (addr, client) in clients {
+            registry.insert(addr, client);
+        }
+        registry
+    }
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace the client at `addr`, returning the one replaced
+    pub fn insert(&mut self, addr: SocketAddr, client: Client) -> Option<Client> {
+        self.index.update(addr, &client);
+        let replaced = self.clients.insert(addr, client);
+        debug_assert!(self.is_consistent());
+        replaced
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) -> Option<Client> {
+        self.index.remove(*addr);
+        let removed = self.clients.remove(addr);
+        debug_assert!(self.is_consistent());
+        removed
+    }
+
+    /// The client at `addr`, re-indexed once the guard is dropped
+    pub fn get_mut(&mut self, addr: &SocketAddr) -> Option<ClientMut<'_>> {
+        let client = self.clients.get_mut(addr)?;
+        Some(ClientMut {
+            addr: *addr,
+            client,
+            index: &mut self.index,
+        })
+    }
+
+    /// The first client `matches`, re-indexed once the guard is dropped
+    pub fn find_mut(&mut self, matches: impl Fn(&Client) -> bool) -> Option<ClientMut<'_>> {
+        let (addr, client) = self
+            .clients
+            .iter_mut()
+            .find(|(_, client)| matches(client))?;
+        Some(ClientMut {
+            addr: *addr,
+            client,
+            index: &mut self.index,
+        })
+    }
+
+    /// Change every client with `f`, re-indexing each afterwards
+    pub fn for_each_mut(&mut self, mut f: impl FnMut(&SocketAddr, &mut Client)) {
+        for (addr, client) in self.clients.iter_mut() {
+            f(addr, client);
+            self.index.update(*addr, client);
+        }
+        debug_assert!(self.is_consistent());
+    }
+
+    /// The client with `callsign`, in any case, logged in or not
+    pub fn named(&self, callsign: &str) -> Option<(&SocketAddr, &Client)> {
+        self.lookup(self.index.by_callsign.get(Callsign::key(callsign).as_ref()))
+            .next()
+    }
+
+    /// The client with `callsign`, re-indexed once the guard is dropped
+    pub fn named_mut(&mut self, callsign: &str) -> Option<ClientMut<'_>> {
+        let addr = *self.named(callsign)?.0;
+        self.get_mut(&addr)
+    }
+
+    /// Clients of `client_type`, logged in or not
+    pub fn of_type(
+        &self,
+        client_type: ClientType,
+    ) -> impl Iterator<Item = (&SocketAddr, &Client)> + '_ {
+        self.lookup(self.index.by_type.get(&client_type))
+    }
+
+    /// Controllers whose latest update was on `frequency`, as sent in the
+    /// ATC update (`24550` for 124.550)
+    pub fn on_frequency(
+        &self,
+        frequency: u32,
+    ) -> impl Iterator<Item = (&SocketAddr, &Client)> + '_ {
+        self.lookup(self.index.by_frequency.get(&frequency))
+    }
+
+    /// Clients that may be within `range_nm` of the point: those in the
+    /// cells the range touches, and those without a position, which are in
+    /// range of everyone
+    ///
+    /// A client in a corner cell can be further away than `range_nm`, so
+    /// callers still check the distance.
+    pub fn near(
+        &self,
+        latitude: f64,
+        longitude: f64,
+        range_nm: u32,
+    ) -> impl Iterator<Item = (&SocketAddr, &Client)> + '_ {
+        let cells = self.cells_near(latitude, longitude, range_nm);
+        let placed = cells
+            .into_iter()
+            .flat_map(|cell| self.lookup(self.index.by_cell.get(&cell)));
+        placed.chain(self.lookup(Some(&self.index.unplaced)))
+    }
+
+    /// The occupied cells within `range_nm` of the point
+    fn cells_near(&self, latitude: f64, longitude: f64, range_nm: u32) -> Vec<Cell> {
+        let span = f64::from(range_nm) / 60.0;
+        let (south, _) = cell(latitude - span, 0.0);
+        let (north, _) = cell(latitude + span, 0.0);
+        // Meridians converge, so the band is widest at its edge nearest a pole
+        let widest = (latitude.abs() + span).min(90.0);
+        let columns = if widest >= 89.0 {
+            None
+        } else {
+            let width = span / widest.to_radians().cos();
+            (width < 180.0).then(|| {
+                let (_, west) = cell(0.0, longitude - width);
+                let (_, east) = cell(0.0, longitude + width);
+                // Wrapping over the antimeridian makes west the larger one
+                (west, east)
+            })
+        };
+        let covers = |(row, column): Cell| {
+            (south..=north).contains(&row)
+                && match columns {
+                    None => true,
+                    Some((west, east)) if west <= east => (west..=east).contains(&column),
+                    Some((west, east)) => column >= west || column <= east,
+                }
+        };
+
+        let rows = north - south + 1;
+        let width = match columns {
+            None => COLUMNS,
+            Some((west, east)) => (east - west).rem_euclid(COLUMNS) + 1,
+        };
+        if (rows * width) as usize > self.index.by_cell.len() {
+            return self
+                .index
+                .by_cell
+                .keys()
+                .copied()
+                .filter(|c| covers(*c))
+                .collect();
+        }
+        (south..=north)
+            .flat_map(|row| match columns {
+                None => (0..COLUMNS).map(|column| (row, column)).collect::<Vec<_>>(),
+                Some((west, _)) => (0..width)
+                    .map(|offset| (row, (west + offset).rem_euclid(COLUMNS)))
+                    .collect(),
+            })
+            .filter(|cell| self.index.by_cell.contains_key(cell))
+            .collect()
+    }
+
+    fn lookup<'a>(
+        &'a self,
+        addrs: Option<&'a HashSet<SocketAddr>>,
+    ) -> impl Iterator<Item = (&'a SocketAddr, &'a Client)> + 'a {
+        addrs
+            .into_iter()
+            .flatten()
+            .filter_map(|addr| self.clients.get_key_value(addr))
+    }
+
+    /// Whether every indexed client is in the map, filed under what it
+    /// holds now, and every client in the map is indexed
+    fn is_consistent(&self) -> bool {
+        let filed = |addr: &SocketAddr, keys: &Keys| {
+            let holds =
+                |addrs: Option<&HashSet<SocketAddr>>| addrs.is_some_and(|a| a.contains(addr));
+            let in_callsign = keys
+                .callsign
+                .as_ref()
+                .is_none_or(|callsign| holds(self.index.by_callsign.get(callsign)));
+            let in_type = keys
+                .client_type
+                .as_ref()
+                .is_none_or(|client_type| holds(self.index.by_type.get(client_type)));
+            let in_frequency = keys
+                .frequency
+                .is_none_or(|frequency| holds(self.index.by_frequency.get(&frequency)));
+            let in_cell = match keys.cell {
+                Some(cell) => holds(self.index.by_cell.get(&cell)),
+                None => self.index.unplaced.contains(addr),
+            };
+            in_callsign && in_type && in_frequency && in_cell
+        };
+        let entries = self
+            .index
+            .by_callsign
+            .values()
+            .map(HashSet::len)
+            .sum::<usize>()
+            + self.index.by_type.values().map(HashSet::len).sum::<usize>()
+            + self
+                .index
+                .by_frequency
+                .values()
+                .map(HashSet::len)
+                .sum::<usize>()
+            + self.index.by_cell.values().map(HashSet::len).sum::<usize>()
+            + self.index.unplaced.len();
+        let expected = self
+            .index
+            .keys
+            .values()
+            .map(|keys| {
+                1 + usize::from(keys.callsign.is_some())
+                    + usize::from(keys.client_type.is_some())
+                    + usize::from(keys.frequency.is_some())
+            })
+            .sum::<usize>();
+
+        self.index.keys.len() == self.clients.len()
+            && entries == expected
+            && self.clients.iter().all(|(addr, client)| {
+                self.index
+                    .keys
+                    .get(addr)
+                    .is_some_and(|keys| *keys == Keys::of(client) && filed(addr, keys))
+            })
+    }
+}
+
+/// A client borrowed for changes from [`ClientRegistry`]
+pub struct ClientMut<'a> {
+    addr: SocketAddr,
+    client: &'a mut Client,
+    index: &'a mut Index,
+}
+
+impl Deref for ClientMut<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client
+    }
+}
+
+impl DerefMut for ClientMut<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client
+    }
+}
+
+impl Drop for ClientMut<'_> {
+    fn drop(&mut self) {
+        self.index.update(self.addr, self.client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientState;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn addrs<'a>(clients: impl Iterator<Item = (&'a SocketAddr, &'a Client)>) -> Vec<SocketAddr> {
+        let mut addrs: Vec<_> = clients.map(|(addr, _)| *addr).collect();
+        addrs.sort();
+        addrs
+    }
+
+    #[test]
+    fn test_index_follows_client_lifecycle() {
+        let mut registry = ClientRegistry::new();
+        let (pilot, controller) = (addr(50001), addr(50002));
+        registry.insert(pilot, Client::new(pilot));
+        registry.insert(controller, Client::new(controller));
+        assert!(registry.is_consistent());
+        assert_eq!(registry.of_type(ClientType::Pilot).count(), 0);
+        // Nobody has a position yet, so both are near anywhere
+        assert_eq!(addrs(registry.near(0.0, 0.0, 1)), [pilot, controller]);
+
+        // Login
+        for (addr, callsign, client_type) in [
+            (pilot, "cca1501", ClientType::Pilot),
+            (controller, "ZSPD_APP", ClientType::Atc),
+        ] {
+            let mut client = registry.get_mut(&addr).unwrap();
+            client.callsign = Some(Callsign::new(callsign));
+            client.client_type = Some(client_type);
+            client.state = ClientState::Active;
+        }
+        assert!(registry.is_consistent());
+        assert_eq!(addrs(registry.of_type(ClientType::Pilot)), [pilot]);
+        assert_eq!(addrs(registry.of_type(ClientType::Atc)), [controller]);
+        assert_eq!(
+            registry.named("CCA1501").map(|(addr, _)| *addr),
+            Some(pilot)
+        );
+        assert_eq!(
+            registry.named("zspd_app").map(|(addr, _)| *addr),
+            Some(controller)
+        );
+
+        // Position and frequency
+        {
+            let mut client = registry.get_mut(&pilot).unwrap();
+            client.latitude = Some(31.14);
+            client.longitude = Some(121.80);
+        }
+        {
+            let mut client = registry.get_mut(&controller).unwrap();
+            client.latitude = Some(40.08);
+            client.longitude = Some(116.58);
+            client.frequency = Some(24550);
+        }
+        assert!(registry.is_consistent());
+        assert_eq!(addrs(registry.on_frequency(24550)), [controller]);
+        assert_eq!(addrs(registry.near(31.0, 121.5, 50)), [pilot]);
+        assert_eq!(addrs(registry.near(40.0, 116.5, 50)), [controller]);
+
+        // The controller moves frequency and the pilot flies north
+        registry.get_mut(&controller).unwrap().frequency = Some(19000);
+        registry.for_each_mut(|addr, client| {
+            if *addr == pilot {
+                client.latitude = Some(39.90);
+                client.longitude = Some(116.40);
+            }
+        });
+        assert!(registry.is_consistent());
+        assert_eq!(registry.on_frequency(24550).count(), 0);
+        assert_eq!(addrs(registry.on_frequency(19000)), [controller]);
+        assert_eq!(registry.near(31.0, 121.5, 50).count(), 0);
+        assert_eq!(addrs(registry.near(40.0, 116.5, 50)), [pilot, controller]);
+
+        // Disconnect
+        registry.remove(&controller);
+        assert!(registry.is_consistent());
+        assert_eq!(registry.of_type(ClientType::Atc).count(), 0);
+        assert_eq!(registry.on_frequency(19000).count(), 0);
+        assert!(registry.named("ZSPD_APP").is_none());
+        registry.remove(&pilot);
+        assert!(registry.is_consistent());
+        assert!(registry.index.keys.is_empty());
+        assert!(registry.index.by_callsign.is_empty());
+        assert!(registry.index.by_type.is_empty());
+        assert!(registry.index.by_cell.is_empty());
+        assert!(registry.index.unplaced.is_empty());
+    }
+
+    #[test]
+    fn test_replacing_a_client_refiles_it() {
+        let at = addr(50001);
+        let mut pilot = Client::new(at);
+        pilot.client_type = Some(ClientType::Pilot);
+        let mut registry = ClientRegistry::from([(at, pilot)]);
+
+        let mut controller = Client::new(at);
+        controller.client_type = Some(ClientType::Atc);
+        registry.insert(at, controller);
+        assert!(registry.is_consistent());
+        assert_eq!(registry.of_type(ClientType::Pilot).count(), 0);
+        assert_eq!(addrs(registry.of_type(ClientType::Atc)), [at]);
+    }
+
+    #[test]
+    fn test_near_wraps_and_covers_poles() {
+        let mut registry = ClientRegistry::new();
+        for (port, (latitude, longitude)) in [
+            (50001, (0.0, 179.9)),
+            (50002, (0.0, -179.9)),
+            (50003, (89.9, 0.0)),
+            (50004, (89.9, 180.0)),
+        ] {
+            let mut client = Client::new(addr(port));
+            client.latitude = Some(latitude);
+            client.longitude = Some(longitude);
+            registry.insert(addr(port), client);
+        }
+        assert_eq!(
+            addrs(registry.near(0.0, 180.0, 30)),
+            [addr(50001), addr(50002)]
+        );
+        assert_eq!(
+            addrs(registry.near(89.5, 90.0, 60)),
+            [addr(50003), addr(50004)]
+        );
+        assert!(registry.near(45.0, 0.0, 30).next().is_none());
+    }
+}
@@ -0,0 +1,202 @@
+//! What a connection did, summarized when it closes
+//!
+//! Each connection counts its traffic in [`SessionCounters`], which the read
+//! loop, the writer task and the handlers share without taking the clients
+//! lock. When the connection closes, the counters, the client's identity and
+//! the [`DisconnectReason`] make up a [`SessionSummary`] that is logged as a
+//! single line and stored on the session's row, so the two always agree.
+
+use crate::client::Client;
+use crate::db::service::SessionEnd;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Traffic of one connection so far
+#[derive(Debug, Default)]
+pub struct SessionCounters {
+    /// Packets that parsed, whether or not they were accepted
+    pub packets_in: AtomicU64,
+    pub packets_out: AtomicU64,
+    /// Bytes of every line read, including ones that didn't parse
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    /// Pilot and ATC position updates
+    pub position_updates: AtomicU64,
+    pub parse_errors: AtomicU64,
+    /// Messages from or for the client that the server dropped: over the
+    /// text rate limit, sent while muted or filtered out by middleware
+    pub dropped: AtomicU64,
+}
+
+impl SessionCounters {
+    pub fn received(&self, bytes: usize) {
+        self.packets_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.packets_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn parse_error(&self, bytes: usize) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A blank line, which is neither a packet nor a parse error
+    pub fn blank_line(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn position_update(&self) {
+        self.position_updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Why a connection closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client closed the connection
+    Closed,
+    /// Reading from the client failed, e.g. the connection was reset
+    ReadError,
+    /// The connection timed out
+    Timeout,
+    /// Writing to the client failed
+    WriteError,
+    /// The connection fell too far behind the broadcast channel
+    Lagged,
+    /// The server stopped taking packets
+    ServerShutdown,
+    /// Disconnected by the server, for the reason counted in
+    /// `openfsd_kicks_total`, e.g. "admin"
+    Kicked(&'static str),
+}
+
+impl DisconnectReason {
+    /// Reason for a session ended by a read error
+    pub fn from_read_error(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::ReadError,
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => f.write_str("closed"),
+            Self::ReadError => f.write_str("read_error"),
+            Self::Timeout => f.write_str("timeout"),
+            Self::WriteError => f.write_str("write_error"),
+            Self::Lagged => f.write_str("lagged"),
+            Self::ServerShutdown => f.write_str("server_shutdown"),
+            Self::Kicked(reason) => write!(f, "kicked:{}", reason),
+        }
+    }
+}
+
+/// Everything logged and stored about a session when it closes
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionSummary {
+    pub callsign: Option<String>,
+    pub network_id: Option<String>,
+    pub client_software: Option<String>,
+    /// Since the connection was accepted
+    pub duration: Duration,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub position_updates: u64,
+    pub parse_errors: u64,
+    pub reason: DisconnectReason,
+}
+
+impl SessionSummary {
+    pub fn new(client: &Client, reason: DisconnectReason) -> Self {
+        let counters = &client.counters;
+        Self {
+            callsign: client.callsign().map(str::to_owned),
+            network_id: client.network_id.clone(),
+            client_software: client.client_string.clone(),
+            duration: client.connected_at.elapsed(),
+            packets_in: counters.packets_in.load(Ordering::Relaxed),
+            packets_out: counters.packets_out.load(Ordering::Relaxed),
+            bytes_in: counters.bytes_in.load(Ordering::Relaxed),
+            bytes_out: counters.bytes_out.load(Ordering::Relaxed),
+            position_updates: counters.position_updates.load(Ordering::Relaxed),
+            parse_errors: counters.parse_errors.load(Ordering::Relaxed),
+            reason,
+        }
+    }
+
+    /// Log the summary as one line with a field for each value
+    pub fn log(&self, addr: SocketAddr) {
+        tracing::info!(
+            callsign = self.callsign.as_deref(),
+            cid = self.network_id.as_deref(),
+            client_software = self.client_software.as_deref(),
+            duration_secs = self.duration.as_secs(),
+            packets_in = self.packets_in,
+            packets_out = self.packets_out,
+            bytes_in = self.bytes_in,
+            bytes_out = self.bytes_out,
+            position_updates = self.position_updates,
+            parse_errors = self.parse_errors,
+            reason = %self.reason,
+            "Client {} disconnected",
+            addr
+        );
+    }
+
+    /// The summary as stored on the row of `session_id`
+    pub fn to_record(&self, session_id: &str) -> SessionEnd {
+        SessionEnd {
+            session_id: session_id.to_string(),
+            disconnected_at: chrono::Utc::now(),
+            disconnect_reason: self.reason.to_string(),
+            client_software: self.client_software.clone(),
+            duration_secs: self.duration.as_secs() as i64,
+            packets_in: self.packets_in as i64,
+            packets_out: self.packets_out as i64,
+            bytes_in: self.bytes_in as i64,
+            bytes_out: self.bytes_out as i64,
+            position_updates: self.position_updates as i64,
+            parse_errors: self.parse_errors as i64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reasons() {
+        let timeout = io::Error::from(io::ErrorKind::TimedOut);
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            DisconnectReason::from_read_error(&timeout),
+            DisconnectReason::Timeout
+        );
+        assert_eq!(
+            DisconnectReason::from_read_error(&reset),
+            DisconnectReason::ReadError
+        );
+        assert_eq!(DisconnectReason::Closed.to_string(), "closed");
+        assert_eq!(
+            DisconnectReason::Kicked("admin").to_string(),
+            "kicked:admin"
+        );
+    }
+}
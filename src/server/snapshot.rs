@@ -0,0 +1,215 @@
+use crate::callsign::Callsign;
+use crate::client::{Client, ClientType};
+use crate::db::entities::position_snapshot;
+use crate::db::service;
+use crate::packet::Packet;
+use crate::server::config::ServerMessage;
+use crate::server::diagnostics;
+use crate::server::registry::ClientRegistry;
+use sea_orm::DatabaseConnection;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, RwLock};
+
+/// How often the state of connected pilots is persisted
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long after a disconnect a pilot can reconnect and resume
+pub fn snapshot_ttl() -> chrono::Duration {
+    chrono::Duration::minutes(15)
+}
+
+/// Persist snapshots of every logged-in pilot and drop stale ones
+pub async fn snapshot_clients(clients: &Arc<RwLock<ClientRegistry>>, db: &DatabaseConnection) {
+    // Don't hold the client lock across database writes
+    let pilots: Vec<Client> = {
+        let clients_map = clients.read().await;
+        clients_map
+            .of_type(ClientType::Pilot)
+            .filter(|(_, client)| client.is_active())
+            .map(|(_, client)| client.clone())
+            .collect()
+    };
+
+    for pilot in &pilots {
+        if let Err(e) = service::save_position_snapshot(db, pilot).await {
+            tracing::error!(
+                "Failed to save position snapshot for {:?}: {}",
+                pilot.callsign,
+                e
+            );
+        }
+    }
+
+    match service::delete_stale_position_snapshots(db, snapshot_ttl()).await {
+        Ok(0) => {}
+        Ok(removed) => tracing::debug!("Removed {} stale position snapshots", removed),
+        Err(e) => tracing::error!("Failed to remove stale position snapshots: {}", e),
+    }
+}
+
+/// The snapshot a pilot reconnecting within the snapshot TTL can resume
+pub async fn find_snapshot(
+    network_id: &str,
+    callsign: &str,
+    db: &DatabaseConnection,
+) -> Option<position_snapshot::Model> {
+    match service::find_position_snapshot(db, network_id, callsign, snapshot_ttl()).await {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            tracing::error!(
+                "Failed to look up position snapshot for {}: {}",
+                callsign,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Restore the state of a pilot from its snapshot
+///
+/// Brings back the assigned squawk, track owner and flight plan, sends the
+/// plan to the pilot and tells the tracking controller. Only for snapshots
+/// the login has claimed, see [`service::record_login`].
+pub async fn restore_snapshot(
+    snapshot: &position_snapshot::Model,
+    sender_addr: SocketAddr,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    callsign_map: &Arc<RwLock<HashMap<Callsign, SocketAddr>>>,
+    broadcast_tx: &broadcast::Sender<(SocketAddr, ServerMessage)>,
+    server_callsign: &str,
+) {
+    let (network_id, callsign) = (&snapshot.network_id, &snapshot.callsign);
+    tracing::info!(
+        "Restoring state of {} ({}) from {}",
+        callsign,
+        network_id,
+        snapshot.updated_at
+    );
+    let flight_plan = snapshot.flight_plan();
+    {
+        let mut clients_map = diagnostics::write_lock(clients, "clients").await;
+        if let Some(mut client) = clients_map.get_mut(&sender_addr) {
+            client.latitude = Some(snapshot.latitude);
+            client.longitude = Some(snapshot.longitude);
+            client.altitude = Some(snapshot.altitude);
+            client.squawk = snapshot.squawk.clone();
+            client.assigned_squawk = snapshot.assigned_squawk.clone();
+            client.tracking_controller = snapshot.tracking_controller.clone();
+            client.flight_plan = flight_plan.clone();
+        };
+    }
+
+    if let Some(plan) = &flight_plan {
+        let fp_packet = plan.to_packet(callsign);
+        let _ = broadcast_tx.send((sender_addr, ServerMessage::Direct(Arc::new(fp_packet))));
+    }
+
+    if let Some(controller) = &snapshot.tracking_controller {
+        let controller_addr = callsign_map
+            .read()
+            .await
+            .get(Callsign::key(controller).as_ref())
+            .copied();
+        if let Some(controller_addr) = controller_addr {
+            let notice = Packet {
+                packet_type: crate::packet::PacketType::Client,
+                command: "TM".to_string(),
+                source: server_callsign.to_string(),
+                destination: controller.clone(),
+                data: vec![format!(
+                    "{} reconnected, track restored (squawk {})",
+                    callsign,
+                    snapshot
+                        .assigned_squawk
+                        .as_deref()
+                        .unwrap_or("not assigned")
+                )],
+            };
+            let _ = broadcast_tx.send((controller_addr, ServerMessage::Direct(Arc::new(notice))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientState;
+
+    const PILOT_ADDR: &str = "127.0.0.1:50001";
+    const ATC_ADDR: &str = "127.0.0.1:50002";
+
+    fn pilot() -> Client {
+        let mut pilot = Client::new(PILOT_ADDR.parse().unwrap());
+        pilot.state = ClientState::Active;
+        pilot.client_type = Some(ClientType::Pilot);
+        pilot.network_id = Some("1234567".to_string());
+        pilot.callsign = Some("CCA1501".into());
+        pilot
+    }
+
+    #[tokio::test]
+    async fn test_restore_within_ttl() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        let mut before = pilot();
+        before.latitude = Some(31.14);
+        before.longitude = Some(121.8);
+        before.altitude = Some(12000);
+        before.assigned_squawk = Some("4521".to_string());
+        before.tracking_controller = Some("ZSPD_APP".to_string());
+        before.flight_plan = Some(crate::flight_plan::FlightPlan {
+            departure: "ZBAA".to_string(),
+            destination: "ZSPD".to_string(),
+            ..Default::default()
+        });
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([(before.addr, before)])));
+        snapshot_clients(&clients, &db).await;
+
+        // The pilot's client crashes and reconnects
+        let atc_addr: SocketAddr = ATC_ADDR.parse().unwrap();
+        let clients = Arc::new(RwLock::new(ClientRegistry::from([(
+            PILOT_ADDR.parse().unwrap(),
+            pilot(),
+        )])));
+        let callsign_map = Arc::new(RwLock::new(HashMap::from([("ZSPD_APP".into(), atc_addr)])));
+        let (broadcast_tx, mut rx) = broadcast::channel(16);
+
+        let pilot_addr = PILOT_ADDR.parse().unwrap();
+        let snapshot = find_snapshot("1234567", "CCA1501", &db).await.unwrap();
+        restore_snapshot(
+            &snapshot,
+            pilot_addr,
+            &clients,
+            &callsign_map,
+            &broadcast_tx,
+            "OPENFSD",
+        )
+        .await;
+
+        let clients_map = clients.read().await;
+        let restored = &clients_map[&pilot_addr];
+        assert_eq!(restored.assigned_squawk.as_deref(), Some("4521"));
+        assert_eq!(restored.tracking_controller.as_deref(), Some("ZSPD_APP"));
+        assert_eq!(restored.flight_plan.as_ref().unwrap().destination, "ZSPD");
+
+        let mut notified = false;
+        while let Ok((addr, msg)) = rx.try_recv() {
+            if let ServerMessage::Direct(packet) = msg {
+                if addr == atc_addr && packet.command == "TM" {
+                    assert_eq!(packet.source, "OPENFSD");
+                    notified = true;
+                }
+            }
+        }
+        assert!(notified);
+    }
+
+    #[tokio::test]
+    async fn test_no_restore_without_snapshot() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        assert!(find_snapshot("1234567", "CCA1501", &db).await.is_none());
+    }
+}
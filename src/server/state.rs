@@ -0,0 +1,76 @@
+//! What the packet handlers share, so each takes one reference instead of a
+//! parameter per piece
+
+use crate::airports::AirportCache;
+use crate::callsign::Callsign;
+use crate::motd::MotdCache;
+use crate::server::clock::Clock;
+use crate::server::config::{ServerConfig, ServerMessage};
+use crate::server::follow::Follows;
+use crate::server::handler_stats::HandlerStats;
+use crate::server::limits::LoginThrottle;
+use crate::server::middleware::MiddlewareChain;
+use crate::server::random::Random;
+use crate::server::registry::ClientRegistry;
+use crate::stats::StatsCollector;
+use crate::tracks::TrackRecorder;
+use crate::weather::WeatherService;
+use crate::webhooks::Webhooks;
+use sea_orm::DatabaseConnection;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch, RwLock};
+
+pub struct ServerState {
+    pub config: ServerConfig,
+    pub clients: Arc<RwLock<ClientRegistry>>,
+    pub callsign_map: Arc<RwLock<HashMap<Callsign, SocketAddr>>>,
+    pub broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+    pub db: Arc<DatabaseConnection>,
+    pub stats: Arc<StatsCollector>,
+    pub handler_stats: Arc<HandlerStats>,
+    pub tracks: Arc<TrackRecorder>,
+    pub motd: Arc<MotdCache>,
+    /// The current weather source, replaced when the config is reloaded
+    pub weather: watch::Receiver<Arc<WeatherService>>,
+    pub airports: Arc<AirportCache>,
+    pub follows: Arc<Follows>,
+    pub throttle: Arc<LoginThrottle>,
+    pub random: Arc<Random>,
+    pub clock: Arc<dyn Clock>,
+    /// Run on what clients send, before it is routed
+    pub middleware: MiddlewareChain,
+    pub webhooks: Arc<Webhooks>,
+}
+
+#[cfg(test)]
+impl ServerState {
+    /// Empty state around `db` with the default settings, on the system
+    /// clock; tests swap in the parts they look at
+    pub fn for_tests(db: Arc<DatabaseConnection>) -> Self {
+        use crate::config::{TracksConfig, WeatherConfig};
+
+        let config = ServerConfig::default();
+        let weather = WeatherService::from_config(&WeatherConfig::default()).unwrap();
+        Self {
+            clients: Arc::new(RwLock::new(ClientRegistry::new())),
+            callsign_map: Arc::new(RwLock::new(HashMap::new())),
+            broadcast_tx: broadcast::channel(100).0,
+            stats: Arc::new(StatsCollector::new()),
+            handler_stats: Arc::new(HandlerStats::new()),
+            tracks: Arc::new(TrackRecorder::start(TracksConfig::default(), db.clone())),
+            motd: Arc::default(),
+            weather: watch::channel(Arc::new(weather)).1,
+            airports: Arc::default(),
+            follows: Arc::default(),
+            throttle: Arc::new(LoginThrottle::new(&config.limits)),
+            random: Arc::default(),
+            clock: Arc::new(crate::server::clock::SystemClock),
+            middleware: MiddlewareChain::default(),
+            webhooks: Arc::default(),
+            config,
+            db,
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! HTTP listener for the public status feeds
+//!
+//! Serves the JSON datafeed at `/data/v3/openfsd-data.json` and the classic
+//! feed at `/whazzup.txt`, and the traffic as GeoJSON at
+//! `/api/map.geojson`. Building any of them walks every client under the read
+//! lock, so a built feed is reused for `[status] cache_secs` however many
+//! requests arrive in the meantime. With `[api] enabled = true` the same
+//! listener serves the authenticated REST API under `/api/`; the map shows
+//! no more than the datafeed, so it needs no token.
+//!
+//! `/healthz` and `/readyz` are the liveness and readiness probes for
+//! container orchestrators.
+
+use super::health::Health;
+use super::limits::RegistrationThrottle;
+use super::registry::ClientRegistry;
+use super::ServerConfig;
+use crate::airports::AirportCache;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::Utc;
+use sea_orm::DatabaseConnection;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, RwLock};
+
+/// Time the database has to answer the readiness check
+const READY_DB_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct StatusState {
+    pub(super) config: ServerConfig,
+    pub(super) clients: Arc<RwLock<ClientRegistry>>,
+    pub(super) db: Arc<DatabaseConnection>,
+    pub(super) airports: Arc<AirportCache>,
+    /// When the listener started, for the uptime the API reports
+    pub(super) started_at: Instant,
+    /// Attempts at `POST /api/register` per address
+    pub(super) registrations: RegistrationThrottle,
+    health: Arc<Health>,
+    datafeed: FeedCache,
+    whazzup: FeedCache,
+    map: FeedCache,
+}
+
+impl StatusState {
+    pub fn new(
+        config: ServerConfig,
+        clients: Arc<RwLock<ClientRegistry>>,
+        db: Arc<DatabaseConnection>,
+        airports: Arc<AirportCache>,
+        health: Arc<Health>,
+    ) -> Self {
+        let max_age = Duration::from_secs(config.status.cache_secs);
+        Self {
+            registrations: RegistrationThrottle::new(config.api.registrations_per_hour),
+            config,
+            clients,
+            db,
+            airports,
+            started_at: Instant::now(),
+            health,
+            datafeed: FeedCache::new(max_age),
+            whazzup: FeedCache::new(max_age),
+            map: FeedCache::new(max_age),
+        }
+    }
+}
+
+/// Serve the feeds until the listener fails
+pub async fn serve(listener: TcpListener, state: Arc<StatusState>) {
+    // Peer addresses are kept for the per-address registration limit
+    let service = router(state).into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(e) = axum::serve(listener, service).await {
+        tracing::error!("Status listener stopped: {}", e);
+    }
+}
+
+fn router(state: Arc<StatusState>) -> Router {
+    let mut router = Router::new()
+        .route("/data/v3/openfsd-data.json", get(datafeed))
+        .route("/whazzup.txt", get(whazzup))
+        .route("/api/map.geojson", get(map))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz));
+    if state.config.api.enabled {
+        router = router.merge(super::api::routes(state.config.api.registration));
+    }
+    router.with_state(state)
+}
+
+async fn datafeed(State(state): State<Arc<StatusState>>) -> impl IntoResponse {
+    let body = state
+        .datafeed
+        .get(|| async {
+            let clients = state.clients.read().await;
+            let airports = state.airports.get();
+            let feed = crate::datafeed::build(&clients, &state.config, &airports, Utc::now());
+            serde_json::to_vec(&feed)
+                .expect("the datafeed serializes")
+                .into()
+        })
+        .await;
+    ([(CONTENT_TYPE, "application/json")], body)
+}
+
+async fn whazzup(State(state): State<Arc<StatusState>>) -> impl IntoResponse {
+    let body = state
+        .whazzup
+        .get(|| async {
+            let clients = state.clients.read().await;
+            crate::whazzup::render(&clients, &state.config, Utc::now()).into()
+        })
+        .await;
+    ([(CONTENT_TYPE, "text/plain; charset=utf-8")], body)
+}
+
+async fn map(State(state): State<Arc<StatusState>>) -> impl IntoResponse {
+    let body = state
+        .map
+        .get(|| async {
+            let clients = state.clients.read().await;
+            let map = crate::map::build(&clients, &state.config);
+            serde_json::to_vec(&map).expect("the map serializes").into()
+        })
+        .await;
+    ([(CONTENT_TYPE, "application/geo+json")], body)
+}
+
+/// Liveness: 200 as long as the FSD accept loop keeps running
+async fn healthz(State(state): State<Arc<StatusState>>) -> impl IntoResponse {
+    let since_beat = state.health.since_beat(Instant::now());
+    if state.health.is_alive(Instant::now()) {
+        (StatusCode::OK, Json(serde_json::json!({ "status": "ok" })))
+    } else {
+        let body = serde_json::json!({
+            "status": "stuck",
+            "error": format!("the accept loop has not run for {}s", since_beat.as_secs()),
+        });
+        (StatusCode::SERVICE_UNAVAILABLE, Json(body))
+    }
+}
+
+/// Outcome of the readiness checks
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    /// Each check with `"ok"` or why it failed
+    checks: BTreeMap<&'static str, String>,
+}
+
+/// Readiness: 200 when the database answers and the server isn't
+/// draining, 503 with the failed checks otherwise
+async fn readyz(State(state): State<Arc<StatusState>>) -> impl IntoResponse {
+    let database = match tokio::time::timeout(READY_DB_TIMEOUT, state.db.ping()).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no answer within {}s", READY_DB_TIMEOUT.as_secs())),
+    };
+    let drain = if state.health.is_draining() {
+        Err("the server is draining".to_string())
+    } else {
+        Ok(())
+    };
+
+    let checks: BTreeMap<_, _> = [("database", database), ("drain", drain)]
+        .into_iter()
+        .map(|(name, result)| (name, result.err().unwrap_or_else(|| "ok".to_string())))
+        .collect();
+    let ready = checks.values().all(|check| check == "ok");
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(Readiness { ready, checks }))
+}
+
+/// The last rendering of a feed and when it was built
+struct FeedCache {
+    max_age: Duration,
+    latest: Mutex<Option<(Instant, Bytes)>>,
+}
+
+impl FeedCache {
+    fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            latest: Mutex::new(None),
+        }
+    }
+
+    /// The cached body, or a fresh one from `build` once it is too old;
+    /// requests arriving during a rebuild wait for it instead of starting
+    /// their own
+    async fn get<F, Fut>(&self, build: F) -> Bytes
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Bytes>,
+    {
+        let mut latest = self.latest.lock().await;
+        if let Some((built_at, body)) = &*latest {
+            if built_at.elapsed() < self.max_age {
+                return body.clone();
+            }
+        }
+        let body = build().await;
+        *latest = Some((Instant::now(), body.clone()));
+        body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::Response;
+
+    async fn test_state() -> Arc<StatusState> {
+        Arc::new(StatusState::new(
+            ServerConfig::default(),
+            Arc::new(RwLock::new(ClientRegistry::new())),
+            Arc::new(crate::db::init_ephemeral().await.unwrap()),
+            Arc::default(),
+            Arc::new(Health::new()),
+        ))
+    }
+
+    /// Status code and JSON body of a probe
+    async fn probe(response: impl IntoResponse) -> (StatusCode, serde_json::Value) {
+        let response: Response = response.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_ready() {
+        let state = test_state().await;
+        let (status, body) = probe(healthz(State(state.clone())).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+
+        let (status, body) = probe(readyz(State(state)).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({"ready": true, "checks": {"database": "ok", "drain": "ok"}})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_while_draining() {
+        let state = test_state().await;
+        state.health.start_draining();
+
+        let (status, body) = probe(readyz(State(state.clone())).await).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["checks"]["drain"], "the server is draining");
+        assert_eq!(body["checks"]["database"], "ok");
+        // Draining is no reason to restart the process
+        assert_eq!(probe(healthz(State(state)).await).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_without_database() {
+        let state = test_state().await;
+        state.db.close_by_ref().await.unwrap();
+
+        let (status, body) = probe(readyz(State(state)).await).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["ready"], false);
+        assert_ne!(body["checks"]["database"], "ok");
+        assert_eq!(body["checks"]["drain"], "ok");
+    }
+
+    #[tokio::test]
+    async fn test_feed_cache() {
+        let cache = FeedCache::new(Duration::from_secs(60));
+        assert_eq!(cache.get(|| async { Bytes::from("first") }).await, "first");
+        assert_eq!(cache.get(|| async { Bytes::from("second") }).await, "first");
+
+        let uncached = FeedCache::new(Duration::ZERO);
+        assert_eq!(
+            uncached.get(|| async { Bytes::from("first") }).await,
+            "first"
+        );
+        assert_eq!(
+            uncached.get(|| async { Bytes::from("second") }).await,
+            "second"
+        );
+    }
+}
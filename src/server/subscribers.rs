@@ -0,0 +1,148 @@
+//! Position snapshots for clients that take positions in batches
+//!
+//! Observers and web maps don't need each position update the moment it
+//! arrives. With `snapshot_observers` set, observers log in with
+//! [`Delivery::Snapshot`] and are left out of the fan-out of single updates.
+//! Instead [`run`] collects the latest update of every other client once per
+//! interval, and one [`PositionSnapshot`], written out once, goes through the
+//! broadcast channel to all of them.
+
+use crate::client::Delivery;
+use crate::packet::{Packet, PositionUpdate};
+use crate::server::clock::Clock;
+use crate::server::config::{ServerMessage, NO_CLIENT};
+use crate::server::registry::ClientRegistry;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::{broadcast, RwLock};
+
+/// The latest position of every client that sends them in real time
+#[derive(Debug)]
+pub struct PositionSnapshot {
+    /// Clients the snapshot is for
+    pub recipients: HashSet<SocketAddr>,
+    pub updates: Vec<Arc<PositionUpdate>>,
+    /// The updates as FSD lines, written to every recipient as they are
+    pub text: String,
+    json: OnceLock<String>,
+}
+
+impl PositionSnapshot {
+    /// The updates as a JSON array of packets, for WebSocket clients that
+    /// asked for JSON; the first of them to be sent the snapshot makes it
+    pub fn json(&self) -> &str {
+        self.json.get_or_init(|| {
+            let packets: Vec<Packet> = self.updates.iter().map(|u| u.to_packet()).collect();
+            serde_json::to_string(&packets).unwrap_or_default()
+        })
+    }
+}
+
+/// A snapshot of `clients` as they are now, unless nobody takes one or
+/// there is nothing in it
+pub fn build(clients: &ClientRegistry) -> Option<PositionSnapshot> {
+    let mut recipients = HashSet::new();
+    let mut updates = Vec::new();
+    for (addr, client) in clients.iter().filter(|(_, client)| client.is_active()) {
+        match client.delivery {
+            Delivery::Snapshot => {
+                recipients.insert(*addr);
+            }
+            Delivery::RealTime => updates.extend(client.last_position.clone()),
+        }
+    }
+    if recipients.is_empty() || updates.is_empty() {
+        return None;
+    }
+    let text = updates.iter().map(|update| update.line()).collect();
+    Some(PositionSnapshot {
+        recipients,
+        updates,
+        text,
+        json: OnceLock::new(),
+    })
+}
+
+/// Send a snapshot every `interval`
+pub async fn run(
+    clients: Arc<RwLock<ClientRegistry>>,
+    broadcast_tx: broadcast::Sender<(SocketAddr, ServerMessage)>,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+) {
+    let mut interval = clock.interval(interval);
+    loop {
+        interval.tick().await;
+        // Taken under one read lock, so no client is in it twice or half
+        // updated
+        let snapshot = build(&*clients.read().await);
+        if let Some(snapshot) = snapshot {
+            let _ = broadcast_tx.send((NO_CLIENT, ServerMessage::Snapshot(Arc::new(snapshot))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientType};
+
+    fn update(line: &str) -> Option<Arc<PositionUpdate>> {
+        Some(Arc::new(PositionUpdate::parse(line).unwrap()))
+    }
+
+    #[test]
+    fn test_snapshot_holds_latest_positions() {
+        let mut first =
+            Client::active_for_tests(([127, 0, 0, 1], 50001), "CCA1501", ClientType::Pilot);
+        let first_addr = first.addr;
+        first.last_position = update("@N:CCA1501:1200:1:40.08:116.58:5000:250:4194304:0");
+        let mut second =
+            Client::active_for_tests(([127, 0, 0, 1], 50002), "DLH123", ClientType::Pilot);
+        second.last_position = update("@N:DLH123:2000:1:31.14:121.80:3000:180:4194304:0");
+        let quiet = Client::active_for_tests(([127, 0, 0, 1], 50003), "CES2101", ClientType::Pilot);
+        let mut observer =
+            Client::active_for_tests(([127, 0, 0, 1], 50004), "ZSPD_OBS", ClientType::Atc);
+        observer.delivery = Delivery::Snapshot;
+        let observer_addr = observer.addr;
+        observer.last_position = update("%ZSPD_OBS:99998:0:300:1:31.14:121.80:0");
+        let mut clients = ClientRegistry::from([
+            (first.addr, first),
+            (second.addr, second),
+            (quiet.addr, quiet),
+            (observer.addr, observer),
+        ]);
+
+        let snapshot = build(&clients).unwrap();
+        assert_eq!(snapshot.recipients, HashSet::from([observer_addr]));
+        let mut lines: Vec<_> = snapshot.text.lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "@N:CCA1501:1200:1:40.08:116.58:5000:250:4194304:0",
+                "@N:DLH123:2000:1:31.14:121.80:3000:180:4194304:0",
+            ]
+        );
+        let json: Vec<Packet> = serde_json::from_str(snapshot.json()).unwrap();
+        assert_eq!(json.len(), 2);
+
+        // A newer position replaces the older one
+        clients.get_mut(&first_addr).unwrap().last_position =
+            update("@N:CCA1501:1200:1:40.10:116.60:6000:260:4194304:0");
+        let snapshot = build(&clients).unwrap();
+        assert!(snapshot.text.contains(":40.10:116.60:6000:"));
+        assert!(!snapshot.text.contains(":40.08:116.58:5000:"));
+    }
+
+    #[test]
+    fn test_no_snapshot_without_subscribers() {
+        let mut pilot =
+            Client::active_for_tests(([127, 0, 0, 1], 50001), "CCA1501", ClientType::Pilot);
+        pilot.last_position = update("@N:CCA1501:1200:1:40.08:116.58:5000:250:4194304:0");
+        let clients = ClientRegistry::from([(pilot.addr, pilot)]);
+        assert!(build(&clients).is_none());
+    }
+}
@@ -0,0 +1,122 @@
+//! Restarting without dropping clients, by handing the listener over
+//!
+//! On SIGUSR2 the running server starts its binary again, which may have
+//! been replaced by a newer one, and hands it the FSD listening socket the
+//! way systemd does socket activation: as descriptor 3, with `LISTEN_FDS=1`
+//! telling the new process it is there. The old process stops accepting and
+//! serves the clients it has until they have all left or
+//! `runtime.upgrade_drain_secs` is up, then exits. Clients connecting in the
+//! meantime land on the new process; the two don't share state, so clients
+//! of one don't see those of the other.
+
+use std::io;
+use std::net::TcpListener;
+use std::process::Child;
+
+/// Environment variable with the number of listening sockets passed in
+const LISTEN_FDS: &str = "LISTEN_FDS";
+/// Environment variable with the process the sockets are meant for; systemd
+/// sets it, a handover from a previous server doesn't
+const LISTEN_PID: &str = "LISTEN_PID";
+const LISTEN_FDNAMES: &str = "LISTEN_FDNAMES";
+
+/// Where passed sockets start, after stdin, stdout and stderr
+#[cfg(unix)]
+const LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+/// The FSD listener this process was handed on start, by a previous server
+/// or by systemd, if it was
+pub fn inherited_listener() -> io::Result<Option<TcpListener>> {
+    let Ok(count) = std::env::var(LISTEN_FDS) else {
+        return Ok(None);
+    };
+    let pid = std::env::var(LISTEN_PID).ok();
+    if pid.is_some_and(|pid| pid != std::process::id().to_string()) {
+        return Ok(None);
+    }
+    match count.parse::<u32>() {
+        Ok(0) => Ok(None),
+        Ok(_) => take_listener(),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}={} is not a number", LISTEN_FDS, count),
+        )),
+    }
+}
+
+#[cfg(unix)]
+fn take_listener() -> io::Result<Option<TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: LISTEN_FDS says descriptor 3 was passed to this process, and
+    // nothing else in it has taken ownership of the descriptor yet
+    let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    // Not passed on to processes this one starts, unless handed over again
+    // SAFETY: fcntl on a descriptor this process owns
+    if unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+fn take_listener() -> io::Result<Option<TcpListener>> {
+    Err(unsupported())
+}
+
+/// Start this binary again with the same arguments, handing it `listener`
+#[cfg(unix)]
+pub fn spawn_successor(listener: &impl std::os::unix::io::AsRawFd) -> io::Result<Child> {
+    use std::os::unix::process::CommandExt;
+
+    let fd = listener.as_raw_fd();
+    let mut command = std::process::Command::new(executable()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(LISTEN_FDS, "1")
+        .env(LISTEN_FDNAMES, "fsd")
+        .env_remove(LISTEN_PID);
+    // SAFETY: only async-signal-safe calls between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            let moved = if fd == LISTEN_FDS_START {
+                // dup2 onto itself would leave close-on-exec set
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, LISTEN_FDS_START)
+            };
+            if moved == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
+
+/// The binary to start again
+#[cfg(unix)]
+fn executable() -> io::Result<std::path::PathBuf> {
+    let current = std::env::current_exe()?;
+    if current.exists() {
+        return Ok(current);
+    }
+    // Replacing the binary leaves Linux calling this one "... (deleted)";
+    // the new one is where this one was started from
+    Ok(std::env::args_os().next().map_or(current, Into::into))
+}
+
+#[cfg(not(unix))]
+pub fn spawn_successor<T>(_listener: &T) -> io::Result<Child> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "handing the listener over needs a Unix system",
+    )
+}
@@ -0,0 +1,172 @@
+use crate::client::{distance_nm, Client, ClientType, Delivery, Facility};
+use crate::config::VisibilityConfig;
+use crate::server::registry::ClientRegistry;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+/// Range a client sees traffic in, in nautical miles
+///
+/// The range the client declared if it did, otherwise the default for its
+/// type and facility, but never more than `max_range_nm`.
+pub fn effective_range_nm(config: &VisibilityConfig, client: &Client) -> u32 {
+    let default = match (&client.client_type, client.facility) {
+        (Some(ClientType::Pilot), _) => config.pilot_range_nm,
+        (_, Some(Facility::Fss)) => config.atc.fss,
+        (_, Some(Facility::Delivery)) => config.atc.del,
+        (_, Some(Facility::Ground)) => config.atc.gnd,
+        (_, Some(Facility::Tower)) => config.atc.twr,
+        (_, Some(Facility::Approach)) => config.atc.app,
+        (_, Some(Facility::Center)) => config.atc.ctr,
+        _ => config.obs_range_nm,
+    };
+    client
+        .declared_range_nm
+        .unwrap_or(default)
+        .min(config.max_range_nm)
+}
+
+/// Whether two clients see each other, i.e. either one's range covers the
+/// distance between them
+///
+/// A controller's visibility centers count as well as its position, so the
+/// distance is between the nearest of each client's points. Clients that
+/// haven't reported a position yet are always in range.
+pub fn in_range(config: &VisibilityConfig, a: &Client, b: &Client) -> bool {
+    let nearest = a
+        .visibility_points()
+        .flat_map(|p| b.visibility_points().map(move |q| distance_nm(p, q)))
+        .min_by(f64::total_cmp);
+    match nearest {
+        Some(distance) => {
+            let range = effective_range_nm(config, a).max(effective_range_nm(config, b));
+            distance <= f64::from(range)
+        }
+        None => true,
+    }
+}
+
+/// Logged-in clients that should receive an update from `sender_addr` as
+/// it arrives; those taking snapshots get it with the next one instead
+///
+/// Pilots all see the same distance, so only those near the sender are
+/// checked; controllers and observers may have declared any range and are
+/// checked one by one.
+pub fn recipients(
+    config: &VisibilityConfig,
+    clients: &ClientRegistry,
+    sender_addr: SocketAddr,
+) -> Vec<SocketAddr> {
+    let Some(sender) = clients.get(&sender_addr) else {
+        return Vec::new();
+    };
+    let wanted = |(addr, client): &(&SocketAddr, &Client)| {
+        **addr != sender_addr
+            && client.is_active()
+            && client.delivery == Delivery::RealTime
+            && in_range(config, sender, client)
+    };
+    if sender.visibility_points().next().is_none() {
+        return clients
+            .iter()
+            .filter(wanted)
+            .map(|(addr, _)| *addr)
+            .collect();
+    }
+    let range = effective_range_nm(config, sender).max(config.pilot_range_nm);
+    // A pilot near more than one of a controller's points turns up once per point
+    let mut seen = HashSet::new();
+    sender
+        .visibility_points()
+        .flat_map(|(latitude, longitude)| clients.near(latitude, longitude, range))
+        .filter(|(addr, client)| {
+            client.client_type == Some(ClientType::Pilot)
+                && (sender.visibility_centers.is_empty() || seen.insert(**addr))
+        })
+        .chain(clients.of_type(ClientType::Atc))
+        .chain(clients.of_type(ClientType::Observer))
+        .filter(wanted)
+        .map(|(addr, _)| *addr)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(callsign: &str, client_type: ClientType, facility: Option<Facility>) -> Client {
+        let mut client = Client::active_for_tests(([127, 0, 0, 1], 50001), callsign, client_type);
+        client.facility = facility;
+        client
+    }
+
+    #[test]
+    fn test_type_defaults() {
+        let config = VisibilityConfig::default();
+        let pilot = client("CCA1501", ClientType::Pilot, None);
+        assert_eq!(effective_range_nm(&config, &pilot), config.pilot_range_nm);
+
+        let tower = client("ZSPD_TWR", ClientType::Atc, Some(Facility::Tower));
+        assert_eq!(effective_range_nm(&config, &tower), config.atc.twr);
+        let observer = client("ZSPD_OBS", ClientType::Atc, Some(Facility::Observer));
+        assert_eq!(effective_range_nm(&config, &observer), config.obs_range_nm);
+
+        let mut center = client("ZSHA_CTR", ClientType::Atc, Some(Facility::Center));
+        center.declared_range_nm = Some(400);
+        assert_eq!(effective_range_nm(&config, &center), 400);
+        center.declared_range_nm = Some(10_000);
+        assert_eq!(effective_range_nm(&config, &center), config.max_range_nm);
+    }
+
+    #[test]
+    fn test_snapshot_clients_are_left_out() {
+        let config = VisibilityConfig::default();
+        let mut clients = ClientRegistry::new();
+        for (port, callsign, client_type, delivery) in [
+            (50001, "CCA1501", ClientType::Pilot, Delivery::RealTime),
+            (50002, "ZSPD_APP", ClientType::Atc, Delivery::RealTime),
+            (50003, "ZSPD_OBS", ClientType::Atc, Delivery::Snapshot),
+        ] {
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            let mut client = Client::active_for_tests(addr, callsign, client_type);
+            client.delivery = delivery;
+            clients.insert(addr, client);
+        }
+
+        let sender = SocketAddr::from(([127, 0, 0, 1], 50001));
+        let recipients = recipients(&config, &clients, sender);
+        assert_eq!(recipients, [SocketAddr::from(([127, 0, 0, 1], 50002))]);
+    }
+
+    #[test]
+    fn test_pilots_near_any_center_are_visible() {
+        let config = VisibilityConfig::default();
+        let placed = |port: u16, callsign, client_type, latitude, longitude| {
+            let mut client =
+                Client::active_for_tests(([127, 0, 0, 1], port), callsign, client_type);
+            client.latitude = Some(latitude);
+            client.longitude = Some(longitude);
+            client
+        };
+        // An approach controller at Frankfurt with a second center 500nm north
+        let mut controller = placed(50001, "EDDF_APP", ClientType::Atc, 50.03, 8.57);
+        controller.facility = Some(Facility::Approach);
+        controller.visibility_centers.insert(1, (58.36, 8.57));
+        let near_first = placed(50002, "DLH123", ClientType::Pilot, 50.1, 8.6);
+        let near_second = placed(50003, "DLH456", ClientType::Pilot, 58.3, 8.5);
+        // About 250nm from either, outside the 150nm approach range
+        let midway = placed(50004, "DLH789", ClientType::Pilot, 54.2, 8.57);
+
+        let mut clients = ClientRegistry::new();
+        for client in [controller, near_first, near_second, midway] {
+            clients.insert(client.addr, client);
+        }
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+
+        let mut sent = recipients(&config, &clients, addr(50001));
+        sent.sort();
+        assert_eq!(sent, [addr(50002), addr(50003)]);
+
+        assert_eq!(recipients(&config, &clients, addr(50003)), [addr(50001)]);
+        assert!(recipients(&config, &clients, addr(50004)).is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+//! WebSocket listener for clients that can't open raw TCP, such as browsers
+//!
+//! Each WebSocket becomes an ordinary FSD session: it logs in, is rate
+//! limited and receives traffic exactly like a TCP client. Text messages
+//! carry FSD lines, several per message if the client likes. A client that
+//! asks for the `fsd-json` subprotocol sends and receives one JSON-encoded
+//! [`Packet`] per message instead, except for position snapshots, which
+//! arrive as one JSON array of packets.
+
+use super::connection::{Frame, FrameReader, PacketWriter, Sessions};
+use super::limits::truncate_field;
+use super::subscribers::PositionSnapshot;
+use crate::packet::{Inbound, Packet, PacketError, PositionUpdate};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, State};
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tracing::Instrument;
+
+/// Subprotocol for JSON-encoded packets
+pub const JSON_PROTOCOL: &str = "fsd-json";
+
+/// Largest message accepted from a client; a packet is at most 4096 bytes
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Accept WebSocket clients until the listener fails
+pub async fn serve(listener: TcpListener, sessions: Sessions) {
+    let router = Router::new().route("/", get(upgrade)).with_state(sessions);
+    let service = router.into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(e) = axum::serve(listener, service).await {
+        tracing::error!("WebSocket listener stopped: {}", e);
+    }
+}
+
+async fn upgrade(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(sessions): State<Sessions>,
+) -> Response {
+    ws.protocols([JSON_PROTOCOL])
+        .max_message_size(MAX_MESSAGE_SIZE)
+        .on_upgrade(move |socket| async move {
+            // Dropping the socket closes it, as the TCP listener does
+            let Some(span) = sessions.admit(addr).await else {
+                return;
+            };
+            let json = socket
+                .protocol()
+                .is_some_and(|protocol| protocol == JSON_PROTOCOL);
+            let (sink, stream) = socket.split();
+            let reader = WsReader {
+                stream,
+                json,
+                pending: VecDeque::new(),
+                max_line_bytes: sessions.limits.max_line_bytes,
+            };
+            let writer = WsWriter { sink, json };
+            tracing::info!("Accepted WebSocket connection from {}", addr);
+            sessions.serve(addr, reader, writer).instrument(span).await;
+        })
+}
+
+struct WsReader {
+    stream: SplitStream<WebSocket>,
+    json: bool,
+    /// Lines of the last message not handed out yet
+    pending: VecDeque<String>,
+    /// Longer lines are refused like those of a TCP client
+    max_line_bytes: usize,
+}
+
+impl WsReader {
+    /// A frame for a line over the limit, keeping only its start
+    fn too_long(&self, mut line: String, len: usize) -> Frame {
+        truncate_field(&mut line, self.max_line_bytes);
+        Frame {
+            packet: Err(PacketError::TooLong(self.max_line_bytes)),
+            len,
+            line,
+        }
+    }
+}
+
+impl FrameReader for WsReader {
+    async fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        loop {
+            if let Some(line) = self.pending.pop_front() {
+                if line.len() > self.max_line_bytes {
+                    let len = line.len();
+                    return Ok(Some(self.too_long(line, len)));
+                }
+                return Ok(Some(Frame {
+                    packet: Inbound::parse(&line),
+                    len: line.len(),
+                    line,
+                }));
+            }
+            let text = match self.stream.next().await {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(_))) | None => return Ok(None),
+                // Pings are answered by axum; binary messages mean nothing here
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(io::Error::other(e)),
+            };
+            if self.json {
+                if text.len() > self.max_line_bytes {
+                    return Ok(Some(self.too_long(text.to_string(), text.len())));
+                }
+                let packet = parse_json(&text);
+                let line = match &packet {
+                    Ok(packet) => packet.format(),
+                    Err(_) => text.to_string(),
+                };
+                return Ok(Some(Frame {
+                    packet: packet.map(Inbound::Packet),
+                    len: text.len(),
+                    line,
+                }));
+            }
+            self.pending.extend(
+                text.lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(str::to_string),
+            );
+        }
+    }
+}
+
+/// Decode a JSON packet, then put it through the line parser so it gets the
+/// same checks as one sent as text
+fn parse_json(text: &str) -> Result<Packet, PacketError> {
+    let packet: Packet = serde_json::from_str(text)?;
+    Packet::parse(&packet.format())
+}
+
+struct WsWriter {
+    sink: SplitSink<WebSocket, Message>,
+    json: bool,
+}
+
+impl WsWriter {
+    /// Queue one WebSocket message
+    async fn send_text(&mut self, text: String) -> io::Result<usize> {
+        let len = text.len();
+        self.sink
+            .feed(Message::Text(text.into()))
+            .await
+            .map_err(io::Error::other)?;
+        Ok(len)
+    }
+}
+
+impl PacketWriter for WsWriter {
+    async fn send(&mut self, packet: &Packet) -> io::Result<usize> {
+        let text = if self.json {
+            serde_json::to_string(packet)?
+        } else {
+            packet.format()
+        };
+        self.send_text(text).await
+    }
+
+    async fn send_position(&mut self, update: &PositionUpdate) -> io::Result<usize> {
+        let text = if self.json {
+            serde_json::to_string(&update.to_packet())?
+        } else {
+            update.line().to_string()
+        };
+        self.send_text(text).await
+    }
+
+    async fn send_snapshot(&mut self, snapshot: &PositionSnapshot) -> io::Result<usize> {
+        let text = if self.json {
+            snapshot.json()
+        } else {
+            &snapshot.text
+        };
+        self.send_text(text.to_string()).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush().await.map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::PacketType;
+
+    #[test]
+    fn test_parse_json() {
+        let packet = parse_json(
+            r#"{"packet_type":"client","command":"TM","source":"CCA1501","destination":"*","data":["hello"]}"#,
+        )
+        .unwrap();
+        assert_eq!(packet.packet_type, PacketType::Client);
+        assert_eq!(packet.format(), "#TMCCA1501:*:hello\r\n");
+
+        let round_trip = parse_json(&serde_json::to_string(&packet).unwrap()).unwrap();
+        assert_eq!(round_trip.format(), packet.format());
+
+        assert!(matches!(
+            parse_json(r#"{"command":"TM"}"#),
+            Err(PacketError::JsonError(_))
+        ));
+    }
+}
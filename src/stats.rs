@@ -0,0 +1,234 @@
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Aggregated statistics for one UTC day
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DailyStats {
+    pub date: NaiveDate,
+    pub peak_clients: i32,
+    pub unique_cids: i32,
+    pub connections: i64,
+    pub messages: i64,
+    pub flight_plans: i64,
+}
+
+impl DailyStats {
+    /// Combine with statistics already stored for the same day
+    ///
+    /// Counts add up; peaks and unique CIDs can't be summed, so the larger
+    /// value wins (unique CIDs undercount if the server restarted that day).
+    pub fn merge(&self, stored: &DailyStats) -> DailyStats {
+        DailyStats {
+            date: self.date,
+            peak_clients: self.peak_clients.max(stored.peak_clients),
+            unique_cids: self.unique_cids.max(stored.unique_cids),
+            connections: self.connections + stored.connections,
+            messages: self.messages + stored.messages,
+            flight_plans: self.flight_plans + stored.flight_plans,
+        }
+    }
+}
+
+/// Raw counters collected for the current day
+///
+/// Event counts are deltas since the last flush, while the peak and CID set
+/// cover the whole day so repeated flushes merge correctly.
+#[derive(Debug, Clone)]
+pub struct Counters {
+    pub date: NaiveDate,
+    pub current_clients: i32,
+    pub peak_clients: i32,
+    pub cids: HashSet<String>,
+    pub connections: i64,
+    pub messages: i64,
+    pub flight_plans: i64,
+}
+
+impl Counters {
+    pub fn new(date: NaiveDate) -> Self {
+        Self {
+            date,
+            current_clients: 0,
+            peak_clients: 0,
+            cids: HashSet::new(),
+            connections: 0,
+            messages: 0,
+            flight_plans: 0,
+        }
+    }
+
+    /// Statistics for the counters' day, without resetting anything
+    pub fn aggregate(&self) -> DailyStats {
+        DailyStats {
+            date: self.date,
+            peak_clients: self.peak_clients,
+            unique_cids: self.cids.len() as i32,
+            connections: self.connections,
+            messages: self.messages,
+            flight_plans: self.flight_plans,
+        }
+    }
+
+    /// Take the statistics to persist and reset the event counts
+    ///
+    /// When `today` is a new day, the peak starts over from the clients
+    /// still connected and the CID set is cleared.
+    pub fn flush(&mut self, today: NaiveDate) -> DailyStats {
+        let stats = self.aggregate();
+
+        self.connections = 0;
+        self.messages = 0;
+        self.flight_plans = 0;
+        if today != self.date {
+            self.date = today;
+            self.peak_clients = self.current_clients;
+            self.cids.clear();
+        }
+
+        stats
+    }
+}
+
+/// Thread-safe collector updated from the packet path
+///
+/// Guest logins are never counted.
+#[derive(Debug)]
+pub struct StatsCollector {
+    counters: Mutex<Counters>,
+}
+
+impl StatsCollector {
+    pub fn new() -> Self {
+        Self {
+            counters: Mutex::new(Counters::new(today())),
+        }
+    }
+
+    pub fn record_login(&self, network_id: &str) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.connections += 1;
+        counters.current_clients += 1;
+        counters.peak_clients = counters.peak_clients.max(counters.current_clients);
+        counters.cids.insert(network_id.to_string());
+    }
+
+    pub fn record_disconnect(&self) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.current_clients = (counters.current_clients - 1).max(0);
+    }
+
+    pub fn record_message(&self) {
+        self.counters.lock().unwrap().messages += 1;
+    }
+
+    pub fn record_flight_plan(&self) {
+        self.counters.lock().unwrap().flight_plans += 1;
+    }
+
+    /// Live numbers for the current day, excluding what was already flushed
+    pub fn today(&self) -> DailyStats {
+        self.counters.lock().unwrap().aggregate()
+    }
+
+    /// Take the statistics to persist, rolling over if the day has changed
+    pub fn flush(&self) -> DailyStats {
+        self.counters.lock().unwrap().flush(today())
+    }
+}
+
+impl Default for StatsCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn today() -> NaiveDate {
+    chrono::Utc::now().date_naive()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2025, 6, day).unwrap()
+    }
+
+    #[test]
+    fn test_flush_resets_counts_but_keeps_daily_peak() {
+        let mut counters = Counters::new(date(1));
+        counters.current_clients = 3;
+        counters.peak_clients = 7;
+        counters.cids = HashSet::from(["1".to_string(), "2".to_string()]);
+        counters.connections = 9;
+        counters.messages = 40;
+
+        let stats = counters.flush(date(1));
+        assert_eq!(stats.peak_clients, 7);
+        assert_eq!(stats.unique_cids, 2);
+        assert_eq!(stats.connections, 9);
+        assert_eq!(stats.messages, 40);
+
+        // A second flush the same day only carries new events
+        counters.messages = 5;
+        let stats = counters.flush(date(1));
+        assert_eq!(stats.peak_clients, 7);
+        assert_eq!(stats.connections, 0);
+        assert_eq!(stats.messages, 5);
+    }
+
+    #[test]
+    fn test_flush_rolls_over_to_new_day() {
+        let mut counters = Counters::new(date(1));
+        counters.current_clients = 3;
+        counters.peak_clients = 7;
+        counters.cids = HashSet::from(["1".to_string()]);
+
+        assert_eq!(counters.flush(date(2)).date, date(1));
+        assert_eq!(counters.date, date(2));
+        assert_eq!(counters.peak_clients, 3);
+        assert!(counters.cids.is_empty());
+    }
+
+    #[test]
+    fn test_merge_with_stored_day() {
+        let stored = DailyStats {
+            date: date(1),
+            peak_clients: 12,
+            unique_cids: 30,
+            connections: 50,
+            messages: 400,
+            flight_plans: 20,
+        };
+        let flushed = DailyStats {
+            date: date(1),
+            peak_clients: 15,
+            unique_cids: 10,
+            connections: 5,
+            messages: 60,
+            flight_plans: 2,
+        };
+
+        let merged = flushed.merge(&stored);
+        assert_eq!(merged.peak_clients, 15);
+        assert_eq!(merged.unique_cids, 30);
+        assert_eq!(merged.connections, 55);
+        assert_eq!(merged.messages, 460);
+        assert_eq!(merged.flight_plans, 22);
+    }
+
+    #[test]
+    fn test_collector_ignores_repeat_cids() {
+        let collector = StatsCollector::new();
+        collector.record_login("1234567");
+        collector.record_login("1234567");
+        collector.record_disconnect();
+
+        let stats = collector.today();
+        assert_eq!(stats.connections, 2);
+        assert_eq!(stats.unique_cids, 1);
+        assert_eq!(stats.peak_clients, 2);
+    }
+}
@@ -0,0 +1,378 @@
+//! OpenTelemetry export
+//!
+//! With `[telemetry] enabled = true` the connection and packet spans go to an
+//! OpenTelemetry collector over OTLP/gRPC, with the callsign, CID and command
+//! as attributes, and so does every series in [`crate::metrics`]. Finished
+//! spans are queued for a background thread that exports them in batches;
+//! when the queue is full they are dropped rather than waited for, and the
+//! collector is only connected to when there is something to send, so a
+//! slow or unreachable collector never holds up a packet.
+
+use crate::config::TelemetryConfig;
+use crate::metrics::DURATION_BUCKETS;
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use opentelemetry::metrics::Meter;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{ExporterBuildError, WithExportConfig, WithTonicConfig};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use thiserror::Error;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Longest a single export may take before it is given up
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Meter of the running export, once `start` has succeeded
+static METER: OnceLock<Meter> = OnceLock::new();
+
+#[derive(Debug, Error)]
+pub enum TelemetryError {
+    #[error("Invalid telemetry header \"{0}\"")]
+    InvalidHeader(String),
+    #[error("Failed to set up the OTLP exporter: {0}")]
+    Exporter(#[from] ExporterBuildError),
+}
+
+/// The tracer and meter providers; flushes and shuts them down when dropped
+pub struct Telemetry {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Telemetry {
+    /// Set up the exporters for `config`
+    ///
+    /// Must be called within a Tokio runtime, which the gRPC client runs on.
+    pub fn start(config: &TelemetryConfig) -> Result<Self, TelemetryError> {
+        let metadata = metadata(config)?;
+        let resource = Resource::builder()
+            .with_service_name(config.service_name.clone())
+            .build();
+
+        let spans = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(&config.endpoint)
+            .with_timeout(EXPORT_TIMEOUT)
+            .with_metadata(metadata.clone())
+            .build()?;
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(spans)
+            .with_resource(resource.clone())
+            .build();
+
+        let metrics = opentelemetry_otlp::MetricExporter::builder()
+            .with_tonic()
+            .with_endpoint(&config.endpoint)
+            .with_timeout(EXPORT_TIMEOUT)
+            .with_metadata(metadata)
+            .build()?;
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(metrics).build())
+            .with_resource(resource)
+            .build();
+
+        use opentelemetry::metrics::MeterProvider;
+        let _ = METER.set(meter_provider.meter("openfsd"));
+        Ok(Self {
+            tracer_provider,
+            meter_provider,
+        })
+    }
+
+    /// Layer that turns spans into OpenTelemetry spans for the exporter
+    pub fn layer<S>(&self) -> Box<dyn Layer<S> + Send + Sync>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    {
+        layer(self.tracer_provider.tracer("openfsd"))
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.tracer_provider.shutdown() {
+            tracing::warn!("Failed to flush the pending spans: {}", e);
+        }
+        if let Err(e) = self.meter_provider.shutdown() {
+            tracing::warn!("Failed to flush the pending metrics: {}", e);
+        }
+    }
+}
+
+/// Layer that hands spans to `tracer`
+pub(crate) fn layer<S>(tracer: SdkTracer) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_threads(false)
+        .boxed()
+}
+
+/// `config.headers` as gRPC metadata
+fn metadata(config: &TelemetryConfig) -> Result<MetadataMap, TelemetryError> {
+    let mut metadata = MetadataMap::new();
+    for (name, value) in &config.headers {
+        let invalid = || TelemetryError::InvalidHeader(name.clone());
+        let key = MetadataKey::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+        let value = MetadataValue::try_from(value.as_str()).map_err(|_| invalid())?;
+        metadata.insert(key, value);
+    }
+    Ok(metadata)
+}
+
+/// Whether `start` has set up the export
+pub fn is_running() -> bool {
+    METER.get().is_some()
+}
+
+/// Recorder for the `metrics` facade that records into the OTLP meter, if
+/// the export is running
+pub(crate) fn recorder() -> Option<OtlpRecorder> {
+    METER.get().map(|meter| OtlpRecorder::new(meter.clone()))
+}
+
+/// Records each `metrics` series into an OpenTelemetry instrument of the
+/// same name, with the labels as attributes
+pub(crate) struct OtlpRecorder {
+    meter: Meter,
+    counters: Mutex<HashMap<Key, Counter>>,
+    gauges: Mutex<HashMap<Key, Gauge>>,
+    histograms: Mutex<HashMap<Key, Histogram>>,
+}
+
+impl OtlpRecorder {
+    pub(crate) fn new(meter: Meter) -> Self {
+        Self {
+            meter,
+            counters: Mutex::default(),
+            gauges: Mutex::default(),
+            histograms: Mutex::default(),
+        }
+    }
+}
+
+impl Recorder for OtlpRecorder {
+    fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(key.clone()).or_insert_with(|| {
+            Counter::from_arc(Arc::new(OtlpCounter {
+                counter: self.meter.u64_counter(key.name().to_string()).build(),
+                attributes: attributes(key),
+            }))
+        });
+        counter.clone()
+    }
+
+    fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+        let mut gauges = self.gauges.lock().unwrap();
+        let gauge = gauges.entry(key.clone()).or_insert_with(|| {
+            Gauge::from_arc(Arc::new(OtlpGauge {
+                gauge: self.meter.f64_gauge(key.name().to_string()).build(),
+                attributes: attributes(key),
+                value: Mutex::new(0.0),
+            }))
+        });
+        gauge.clone()
+    }
+
+    fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(key.clone()).or_insert_with(|| {
+            let mut builder = self.meter.f64_histogram(key.name().to_string());
+            if key.name().ends_with("_seconds") {
+                builder = builder.with_boundaries(DURATION_BUCKETS.to_vec());
+            }
+            Histogram::from_arc(Arc::new(OtlpHistogram {
+                histogram: builder.build(),
+                attributes: attributes(key),
+            }))
+        });
+        histogram.clone()
+    }
+}
+
+fn attributes(key: &Key) -> Vec<KeyValue> {
+    key.labels()
+        .map(|label| KeyValue::new(label.key().to_string(), label.value().to_string()))
+        .collect()
+}
+
+struct OtlpCounter {
+    counter: opentelemetry::metrics::Counter<u64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl CounterFn for OtlpCounter {
+    fn increment(&self, value: u64) {
+        self.counter.add(value, &self.attributes);
+    }
+
+    /// OpenTelemetry counters only go up by deltas; the server never sets one
+    fn absolute(&self, _value: u64) {}
+}
+
+struct OtlpGauge {
+    gauge: opentelemetry::metrics::Gauge<f64>,
+    attributes: Vec<KeyValue>,
+    /// OpenTelemetry gauges only take absolute values
+    value: Mutex<f64>,
+}
+
+impl OtlpGauge {
+    fn update(&self, f: impl FnOnce(f64) -> f64) {
+        let mut value = self.value.lock().unwrap();
+        *value = f(*value);
+        self.gauge.record(*value, &self.attributes);
+    }
+}
+
+impl GaugeFn for OtlpGauge {
+    fn increment(&self, value: f64) {
+        self.update(|current| current + value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|current| current - value);
+    }
+
+    fn set(&self, value: f64) {
+        self.update(|_| value);
+    }
+}
+
+struct OtlpHistogram {
+    histogram: opentelemetry::metrics::Histogram<f64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl HistogramFn for OtlpHistogram {
+    fn record(&self, value: f64) {
+        self.histogram.record(value, &self.attributes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+    use opentelemetry_sdk::metrics::InMemoryMetricExporter;
+    use std::time::Instant;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_metadata() {
+        let mut config = TelemetryConfig::default();
+        config
+            .headers
+            .insert("x-api-key".to_string(), "secret".to_string());
+        let map = metadata(&config).unwrap();
+        assert_eq!(map.get("x-api-key").unwrap(), "secret");
+
+        config
+            .headers
+            .insert("not a header".to_string(), "value".to_string());
+        assert!(matches!(
+            metadata(&config),
+            Err(TelemetryError::InvalidHeader(name)) if name == "not a header"
+        ));
+    }
+
+    #[test]
+    fn test_recorder() {
+        let exporter = InMemoryMetricExporter::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build())
+            .build();
+        let recorder = OtlpRecorder::new(opentelemetry::metrics::MeterProvider::meter(
+            &provider, "test",
+        ));
+        metrics::with_local_recorder(&recorder, || {
+            crate::metrics::packet_received("AP", 80);
+            crate::metrics::packet_received("AP", 70);
+            crate::metrics::packet_received("XX", 10);
+            metrics::gauge!("openfsd_clients", "type" => "pilot").set(3.0);
+            metrics::gauge!("openfsd_clients", "type" => "pilot").increment(1.0);
+        });
+        provider.force_flush().unwrap();
+
+        let metrics = exporter.get_finished_metrics().unwrap();
+        let metrics: Vec<_> = metrics
+            .iter()
+            .flat_map(|resource| resource.scope_metrics())
+            .flat_map(|scope| scope.metrics())
+            .collect();
+        let find = |name: &str| {
+            metrics
+                .iter()
+                .find(|metric| metric.name() == name)
+                .unwrap_or_else(|| panic!("no {}", name))
+                .data()
+        };
+
+        let AggregatedMetrics::U64(MetricData::Sum(packets)) =
+            find("openfsd_packets_received_total")
+        else {
+            panic!("packets are not a u64 sum");
+        };
+        let mut counts: Vec<(String, u64)> = packets
+            .data_points()
+            .map(|point| {
+                let command = point.attributes().next().unwrap().value.to_string();
+                (command, point.value())
+            })
+            .collect();
+        counts.sort();
+        assert_eq!(counts, [("AP".to_string(), 2), ("other".to_string(), 1)]);
+
+        let AggregatedMetrics::F64(MetricData::Gauge(clients)) = find("openfsd_clients") else {
+            panic!("clients is not an f64 gauge");
+        };
+        let values: Vec<f64> = clients.data_points().map(|point| point.value()).collect();
+        assert_eq!(values, [4.0]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unreachable_collector() {
+        let config = TelemetryConfig {
+            enabled: true,
+            endpoint: "http://127.0.0.1:1".to_string(),
+            ..TelemetryConfig::default()
+        };
+        let telemetry = Telemetry::start(&config).unwrap();
+        let subscriber = tracing_subscriber::registry().with(telemetry.layer());
+        tracing::subscriber::with_default(subscriber, || {
+            let started = Instant::now();
+            // Below the batch size: a worker exporting while spans still end
+            // can underflow the SDK's batch counter, which panics in debug builds
+            for _ in 0..500 {
+                tracing::info_span!("packet", command = "AP").in_scope(|| {});
+            }
+            assert!(started.elapsed() < Duration::from_secs(2));
+        });
+
+        let started = Instant::now();
+        tokio::task::spawn_blocking(move || drop(telemetry))
+            .await
+            .unwrap();
+        assert!(started.elapsed() < EXPORT_TIMEOUT * 2);
+    }
+}
@@ -0,0 +1,415 @@
+use crate::db::entities::flight_track;
+use crate::db::service;
+use chrono::{DateTime, SecondsFormat, Utc};
+use sea_orm::{DatabaseConnection, DbErr};
+use std::io::{self, Write};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Samples fetched from the database at a time
+const PAGE_SIZE: u64 = 1000;
+
+/// FSD altitudes are in feet, both output formats use meters
+const FEET_TO_METERS: f64 = 0.3048;
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("Database error: {0}")]
+    Database(#[from] DbErr),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackFormat {
+    GeoJson,
+    Kml,
+}
+
+impl FromStr for TrackFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "geojson" => Ok(TrackFormat::GeoJson),
+            "kml" => Ok(TrackFormat::Kml),
+            _ => Err(format!("Unknown track format: {}", s)),
+        }
+    }
+}
+
+/// Metadata written alongside a track
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackInfo {
+    pub session_id: String,
+    pub callsign: String,
+    pub network_id: String,
+    pub aircraft: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub samples: u64,
+}
+
+/// Write the track of a session to `out`
+///
+/// Samples are read and written page by page, so long tracks never have to
+/// fit in memory. Returns `None` without writing anything if the session
+/// has no samples.
+pub async fn export_track<W: Write>(
+    db: &DatabaseConnection,
+    session_id: &str,
+    format: TrackFormat,
+    out: &mut W,
+) -> Result<Option<TrackInfo>, ExportError> {
+    let Some((first, last, samples)) = service::find_track_bounds(db, session_id).await? else {
+        return Ok(None);
+    };
+    let info = TrackInfo {
+        session_id: session_id.to_string(),
+        callsign: first.callsign,
+        network_id: first.network_id,
+        aircraft: service::find_track_aircraft(db, session_id).await?,
+        started_at: first.recorded_at,
+        ended_at: last.recorded_at,
+        samples,
+    };
+
+    match format {
+        TrackFormat::GeoJson => {
+            let mut writer = GeoJsonWriter::begin(out, &info)?;
+            let mut pages = SamplePages::new(session_id);
+            while let Some(page) = pages.next(db).await? {
+                for sample in &page {
+                    writer.position(sample)?;
+                }
+            }
+            writer.finish()?;
+        }
+        TrackFormat::Kml => {
+            // gx:Track lists all timestamps before all coordinates
+            let mut writer = KmlWriter::begin(out, &info)?;
+            let mut pages = SamplePages::new(session_id);
+            while let Some(page) = pages.next(db).await? {
+                for sample in &page {
+                    writer.when(sample)?;
+                }
+            }
+            let mut pages = SamplePages::new(session_id);
+            while let Some(page) = pages.next(db).await? {
+                for sample in &page {
+                    writer.coord(sample)?;
+                }
+            }
+            writer.finish()?;
+        }
+    }
+
+    out.flush()?;
+    Ok(Some(info))
+}
+
+/// Keyset pagination over the samples of one session
+struct SamplePages<'a> {
+    session_id: &'a str,
+    after_id: i64,
+    done: bool,
+}
+
+impl<'a> SamplePages<'a> {
+    fn new(session_id: &'a str) -> Self {
+        Self {
+            session_id,
+            after_id: 0,
+            done: false,
+        }
+    }
+
+    async fn next(
+        &mut self,
+        db: &DatabaseConnection,
+    ) -> Result<Option<Vec<flight_track::Model>>, DbErr> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let page = service::list_track_samples_after(db, self.session_id, self.after_id, PAGE_SIZE)
+            .await?;
+        self.done = (page.len() as u64) < PAGE_SIZE;
+        match page.last() {
+            Some(last) => {
+                self.after_id = last.id;
+                Ok(Some(page))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Samples with unparseable coordinates would produce invalid output
+fn is_valid(sample: &flight_track::Model) -> bool {
+    sample.latitude.is_finite() && sample.longitude.is_finite()
+}
+
+fn altitude_meters(sample: &flight_track::Model) -> f64 {
+    f64::from(sample.altitude) * FEET_TO_METERS
+}
+
+fn timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Streams a GeoJSON Feature with a LineString geometry
+///
+/// A single-sample track becomes a Point, since a LineString needs at least
+/// two positions.
+pub struct GeoJsonWriter<'a, W: Write> {
+    out: &'a mut W,
+    single_point: bool,
+    written: u64,
+}
+
+impl<'a, W: Write> GeoJsonWriter<'a, W> {
+    pub fn begin(out: &'a mut W, info: &TrackInfo) -> io::Result<Self> {
+        let properties = serde_json::json!({
+            "session_id": info.session_id,
+            "callsign": info.callsign,
+            "network_id": info.network_id,
+            "aircraft": info.aircraft,
+            "start_time": timestamp(&info.started_at),
+            "end_time": timestamp(&info.ended_at),
+            "samples": info.samples,
+        });
+        let single_point = info.samples == 1;
+        write!(
+            out,
+            r#"{{"type":"Feature","properties":{},"geometry":{{"type":"{}","coordinates":{}"#,
+            properties,
+            if single_point { "Point" } else { "LineString" },
+            if single_point { "" } else { "[" }
+        )?;
+
+        Ok(Self {
+            out,
+            single_point,
+            written: 0,
+        })
+    }
+
+    pub fn position(&mut self, sample: &flight_track::Model) -> io::Result<()> {
+        if !is_valid(sample) || (self.single_point && self.written > 0) {
+            return Ok(());
+        }
+        if self.written > 0 {
+            self.out.write_all(b",")?;
+        }
+        // GeoJSON positions are longitude first
+        write!(
+            self.out,
+            "[{},{},{:.1}]",
+            sample.longitude,
+            sample.latitude,
+            altitude_meters(sample)
+        )?;
+        self.written += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        if self.single_point {
+            if self.written == 0 {
+                self.out.write_all(b"[]")?;
+            }
+            self.out.write_all(b"}}\n")
+        } else {
+            self.out.write_all(b"]}}\n")
+        }
+    }
+}
+
+/// Streams a KML document with a single gx:Track placemark
+///
+/// All `when` elements must be written before the first `coord`.
+pub struct KmlWriter<'a, W: Write> {
+    out: &'a mut W,
+    in_coords: bool,
+}
+
+impl<'a, W: Write> KmlWriter<'a, W> {
+    pub fn begin(out: &'a mut W, info: &TrackInfo) -> io::Result<Self> {
+        let callsign = xml_escape(&info.callsign);
+        writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            out,
+            r#"<kml xmlns="http://www.opengis.net/kml/2.2" xmlns:gx="http://www.google.com/kml/ext/2.2">"#
+        )?;
+        writeln!(out, "<Document>")?;
+        writeln!(out, "<name>{}</name>", callsign)?;
+        writeln!(out, "<Placemark>")?;
+        writeln!(out, "<name>{}</name>", callsign)?;
+        writeln!(
+            out,
+            "<TimeSpan><begin>{}</begin><end>{}</end></TimeSpan>",
+            timestamp(&info.started_at),
+            timestamp(&info.ended_at)
+        )?;
+        writeln!(out, "<ExtendedData>")?;
+        let mut data = vec![
+            ("session_id", info.session_id.clone()),
+            ("network_id", info.network_id.clone()),
+            ("samples", info.samples.to_string()),
+        ];
+        if let Some(aircraft) = &info.aircraft {
+            data.push(("aircraft", aircraft.clone()));
+        }
+        for (name, value) in data {
+            writeln!(
+                out,
+                r#"<Data name="{}"><value>{}</value></Data>"#,
+                name,
+                xml_escape(&value)
+            )?;
+        }
+        writeln!(out, "</ExtendedData>")?;
+        writeln!(out, "<gx:Track>")?;
+        writeln!(out, "<altitudeMode>absolute</altitudeMode>")?;
+
+        Ok(Self {
+            out,
+            in_coords: false,
+        })
+    }
+
+    pub fn when(&mut self, sample: &flight_track::Model) -> io::Result<()> {
+        debug_assert!(!self.in_coords, "when after coord");
+        if !is_valid(sample) {
+            return Ok(());
+        }
+        writeln!(self.out, "<when>{}</when>", timestamp(&sample.recorded_at))
+    }
+
+    pub fn coord(&mut self, sample: &flight_track::Model) -> io::Result<()> {
+        self.in_coords = true;
+        if !is_valid(sample) {
+            return Ok(());
+        }
+        writeln!(
+            self.out,
+            "<gx:coord>{} {} {:.1}</gx:coord>",
+            sample.longitude,
+            sample.latitude,
+            altitude_meters(sample)
+        )
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        writeln!(self.out, "</gx:Track>")?;
+        writeln!(self.out, "</Placemark>")?;
+        writeln!(self.out, "</Document>")?;
+        writeln!(self.out, "</kml>")
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracks::TrackSample;
+
+    async fn record(db: &DatabaseConnection, positions: &[(f64, f64, i32)]) {
+        let start = chrono::Utc::now() - chrono::Duration::minutes(10);
+        let samples: Vec<TrackSample> = positions
+            .iter()
+            .enumerate()
+            .map(|(i, &(latitude, longitude, altitude))| TrackSample {
+                session_id: "abc123".to_string(),
+                callsign: "CCA1501".to_string(),
+                network_id: "1234567".to_string(),
+                recorded_at: start + chrono::Duration::seconds(15 * i as i64),
+                latitude,
+                longitude,
+                altitude,
+                groundspeed: 250,
+                aircraft: Some("H/B744/L".to_string()),
+            })
+            .collect();
+        service::insert_track_samples(db, &samples).await.unwrap();
+    }
+
+    async fn export(db: &DatabaseConnection, format: TrackFormat) -> (Option<TrackInfo>, String) {
+        let mut out = Vec::new();
+        let info = export_track(db, "abc123", format, &mut out).await.unwrap();
+        (info, String::from_utf8(out).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_geojson_line_string() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        record(
+            &db,
+            &[
+                (40.08, 116.58, 0),
+                (39.5, 117.2, 10000),
+                (31.14, 121.8, 3000),
+            ],
+        )
+        .await;
+
+        let (info, output) = export(&db, TrackFormat::GeoJson).await;
+        assert_eq!(info.unwrap().samples, 3);
+
+        let feature: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(feature["type"], "Feature");
+        assert_eq!(feature["properties"]["callsign"], "CCA1501");
+        assert_eq!(feature["properties"]["aircraft"], "H/B744/L");
+        assert_eq!(feature["geometry"]["type"], "LineString");
+
+        let coordinates = feature["geometry"]["coordinates"].as_array().unwrap();
+        assert_eq!(coordinates.len(), 3);
+        // Longitude comes first
+        assert_eq!(coordinates[0][0], 116.58);
+        assert_eq!(coordinates[0][1], 40.08);
+        assert_eq!(coordinates[1][2], 3048.0);
+    }
+
+    #[tokio::test]
+    async fn test_geojson_single_sample_is_point() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        record(&db, &[(40.08, 116.58, 0)]).await;
+
+        let (_, output) = export(&db, TrackFormat::GeoJson).await;
+        let feature: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(feature["geometry"]["type"], "Point");
+        assert_eq!(
+            feature["geometry"]["coordinates"],
+            serde_json::json!([116.58, 40.08, 0.0])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_kml_track() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+        record(&db, &[(40.08, 116.58, 0), (31.14, 121.8, 3000)]).await;
+
+        let (_, output) = export(&db, TrackFormat::Kml).await;
+        assert_eq!(output.matches("<when>").count(), 2);
+        assert_eq!(output.matches("<gx:coord>").count(), 2);
+        assert!(output.contains("<gx:coord>116.58 40.08 0.0</gx:coord>"));
+        assert!(output.find("</when>").unwrap() < output.find("<gx:coord>").unwrap());
+        assert!(output.contains("<value>H/B744/L</value>"));
+    }
+
+    #[tokio::test]
+    async fn test_empty_track() {
+        let db = crate::db::init_ephemeral().await.unwrap();
+
+        let (info, output) = export(&db, TrackFormat::GeoJson).await;
+        assert!(info.is_none());
+        assert!(output.is_empty());
+    }
+}
@@ -0,0 +1,195 @@
+pub mod export;
+
+use crate::config::TracksConfig;
+use crate::db::service;
+use sea_orm::DatabaseConnection;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Most samples written in a single insert
+const MAX_BATCH: usize = 256;
+
+/// How often old tracks are purged
+const RETENTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// One recorded position of a flight
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackSample {
+    pub session_id: String,
+    pub callsign: String,
+    pub network_id: String,
+    pub recorded_at: chrono::DateTime<chrono::Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: i32,
+    pub groundspeed: i32,
+    pub aircraft: Option<String>,
+}
+
+/// Picks which position updates of a connection are worth recording
+///
+/// An update is sampled if it is the first one, if `every` updates have
+/// passed since the last sample, or if `interval` has elapsed.
+#[derive(Debug, Clone, Default)]
+pub struct Decimator {
+    updates_since_sample: u32,
+    last_sample: Option<Instant>,
+}
+
+impl Decimator {
+    pub fn should_sample(&mut self, now: Instant, every: u32, interval: Duration) -> bool {
+        self.updates_since_sample += 1;
+
+        let due = match self.last_sample {
+            None => true,
+            Some(last) => {
+                (every > 0 && self.updates_since_sample >= every)
+                    || now.duration_since(last) >= interval
+            }
+        };
+
+        if due {
+            self.updates_since_sample = 0;
+            self.last_sample = Some(now);
+        }
+        due
+    }
+}
+
+/// Hands track samples to a background writer
+///
+/// Samples are dropped rather than slowing down position handling when the
+/// writer falls behind.
+#[derive(Debug)]
+pub struct TrackRecorder {
+    config: TracksConfig,
+    tx: Option<mpsc::Sender<TrackSample>>,
+}
+
+impl TrackRecorder {
+    /// Create the recorder and, if any recording is configured, its writer tasks
+    pub fn start(config: TracksConfig, db: Arc<DatabaseConnection>) -> Self {
+        if !config.enabled && config.callsigns.is_empty() {
+            return Self { config, tx: None };
+        }
+
+        let (tx, rx) = mpsc::channel(config.queue_capacity.max(1));
+        tokio::spawn(write_samples(rx, db.clone()));
+        if config.retention_days > 0 {
+            tokio::spawn(purge_old_tracks(config.retention_days, db));
+        }
+
+        Self {
+            config,
+            tx: Some(tx),
+        }
+    }
+
+    /// Whether positions of this callsign are recorded
+    pub fn records(&self, callsign: &str) -> bool {
+        self.tx.is_some()
+            && (self.config.enabled
+                || self
+                    .config
+                    .callsigns
+                    .iter()
+                    .any(|c| c.eq_ignore_ascii_case(callsign)))
+    }
+
+    /// Let the decimator decide whether the current update becomes a sample
+    pub fn should_sample(&self, decimator: &mut Decimator) -> bool {
+        decimator.should_sample(
+            Instant::now(),
+            self.config.sample_every,
+            Duration::from_secs(self.config.sample_interval_secs),
+        )
+    }
+
+    pub fn submit(&self, sample: TrackSample) {
+        if let Some(tx) = &self.tx {
+            if let Err(e) = tx.try_send(sample) {
+                tracing::warn!("Dropping track sample: {}", e);
+            }
+        }
+    }
+}
+
+/// Write queued samples in batches
+async fn write_samples(mut rx: mpsc::Receiver<TrackSample>, db: Arc<DatabaseConnection>) {
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    while rx.recv_many(&mut batch, MAX_BATCH).await > 0 {
+        if let Err(e) = service::insert_track_samples(&db, &batch).await {
+            tracing::error!("Failed to write {} track samples: {}", batch.len(), e);
+        }
+        batch.clear();
+    }
+}
+
+/// Periodically delete tracks older than the retention period
+async fn purge_old_tracks(retention_days: u32, db: Arc<DatabaseConnection>) {
+    let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        let cutoff = chrono::Utc::now() - chrono::Duration::days(i64::from(retention_days));
+        match service::delete_track_samples_before(&db, cutoff).await {
+            Ok(0) => {}
+            Ok(removed) => tracing::info!("Purged {} track samples before {}", removed, cutoff),
+            Err(e) => tracing::error!("Failed to purge old track samples: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(15);
+
+    #[test]
+    fn test_first_update_is_sampled() {
+        let mut decimator = Decimator::default();
+        assert!(decimator.should_sample(Instant::now(), 10, INTERVAL));
+    }
+
+    #[test]
+    fn test_every_nth_update() {
+        let mut decimator = Decimator::default();
+        let now = Instant::now();
+
+        let sampled: Vec<bool> = (0..7)
+            .map(|_| decimator.should_sample(now, 3, INTERVAL))
+            .collect();
+        assert_eq!(sampled, vec![true, false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn test_interval_forces_sample() {
+        let mut decimator = Decimator::default();
+        let start = Instant::now();
+
+        assert!(decimator.should_sample(start, 0, INTERVAL));
+        assert!(!decimator.should_sample(start + Duration::from_secs(5), 0, INTERVAL));
+        assert!(!decimator.should_sample(start + Duration::from_secs(14), 0, INTERVAL));
+        assert!(decimator.should_sample(start + Duration::from_secs(15), 0, INTERVAL));
+        // The interval restarts from the latest sample
+        assert!(!decimator.should_sample(start + Duration::from_secs(20), 0, INTERVAL));
+    }
+
+    #[tokio::test]
+    async fn test_per_callsign_recording() {
+        let db = Arc::new(crate::db::init_ephemeral().await.unwrap());
+        let recorder = TrackRecorder::start(
+            TracksConfig {
+                callsigns: vec!["CCA1501".to_string()],
+                ..TracksConfig::default()
+            },
+            db.clone(),
+        );
+        assert!(recorder.records("cca1501"));
+        assert!(!recorder.records("CES2001"));
+
+        let disabled = TrackRecorder::start(TracksConfig::default(), db);
+        assert!(!disabled.records("CCA1501"));
+    }
+}
@@ -0,0 +1,643 @@
+use crate::config::{WeatherConfig, WeatherProvider};
+use crate::http_client::{self, HttpError};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Report URLs of aviationweather.gov
+pub const NOAA_URL_TEMPLATE: &str = "https://aviationweather.gov/api/data/{product}?ids={icao}";
+
+#[derive(Debug, Error)]
+pub enum WeatherError {
+    #[error("Invalid station: {0}")]
+    InvalidStation(String),
+    #[error("No {product} available for {icao}")]
+    NotFound { product: Product, icao: String },
+    #[error("Weather provider did not answer in time")]
+    Timeout,
+    #[error("Every weather provider is cooling down after failing")]
+    Unavailable,
+    #[error("Weather provider returned HTTP {0}")]
+    Status(u16),
+    #[error("Invalid weather URL: {0}")]
+    InvalidUrl(String),
+    #[error("Failed to read {path}: {source}")]
+    StaticFile {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Weather request failed: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl From<HttpError> for WeatherError {
+    fn from(e: HttpError) -> Self {
+        match e {
+            HttpError::InvalidUrl(url) => WeatherError::InvalidUrl(url),
+            HttpError::Timeout => WeatherError::Timeout,
+            HttpError::Status(status) => WeatherError::Status(status),
+            HttpError::Io(e) => WeatherError::Io(e),
+        }
+    }
+}
+
+/// Kind of weather report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Product {
+    Metar,
+    Taf,
+}
+
+impl Product {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Product::Metar => "metar",
+            Product::Taf => "taf",
+        }
+    }
+}
+
+impl fmt::Display for Product {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug)]
+enum Source {
+    /// Fetched over HTTP(S) from a URL template
+    Http {
+        url_template: String,
+        api_key: Option<(String, String)>,
+    },
+    /// METARs by station, read once from a file
+    Static(HashMap<String, String>),
+}
+
+/// One of the sources tried in turn
+#[derive(Debug)]
+struct Provider {
+    /// Shown in metrics and logs, e.g. "noaa"
+    name: String,
+    source: Source,
+    timeout: Duration,
+    /// Until when the provider is skipped after failing
+    cooldown_until: Mutex<Option<Instant>>,
+}
+
+impl Provider {
+    fn new(name: String, source: Source, timeout: Duration) -> Self {
+        Self {
+            name,
+            source,
+            timeout,
+            cooldown_until: Mutex::new(None),
+        }
+    }
+
+    fn is_cooling_down(&self) -> bool {
+        self.cooldown_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    async fn fetch(&self, product: Product, icao: &str) -> Result<String, WeatherError> {
+        let not_found = || WeatherError::NotFound {
+            product,
+            icao: icao.to_string(),
+        };
+        match &self.source {
+            Source::Http {
+                url_template,
+                api_key,
+            } => {
+                let url = render_url(url_template, product, icao);
+                let header = api_key
+                    .as_ref()
+                    .map(|(name, value)| (name.as_str(), value.as_str()));
+                let body = http_client::get(&url, header, self.timeout).await;
+                crate::metrics::weather_fetch(product.as_str(), body.is_ok());
+                parse_report(&body?, product).ok_or_else(not_found)
+            }
+            Source::Static(reports) if product == Product::Metar => {
+                reports.get(icao).cloned().ok_or_else(not_found)
+            }
+            Source::Static(_) => Err(not_found()),
+        }
+    }
+}
+
+/// A report and where it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Served {
+    pub report: String,
+    /// Name of the provider that served it, "cache" for a report still
+    /// within its TTL or "stale" for one past it
+    pub source: String,
+}
+
+/// Fetched report, when it was fetched and the provider it came from
+type CacheEntry = (Instant, String, String);
+
+/// METAR and TAF lookups with a per-product cache
+///
+/// Built from the `[weather]` config section; a reload builds a new one.
+/// Providers are tried in order: the one set as `provider`, then each of
+/// the `fallbacks`. A provider that can't be reached is skipped for
+/// `provider_cooldown_secs` rather than waited on again by every request.
+/// When none has a report, one cached no longer than `stale_max_minutes`
+/// ago is served with a remark saying how old it is.
+#[derive(Debug)]
+pub struct WeatherService {
+    providers: Vec<Provider>,
+    metar_ttl: Duration,
+    taf_ttl: Duration,
+    stale_max: Duration,
+    cooldown: Duration,
+    metar_refresh: Duration,
+    max_subscriptions: usize,
+    cache: Mutex<HashMap<(Product, String), CacheEntry>>,
+}
+
+impl WeatherService {
+    /// Fails only if the static provider's file can't be read
+    pub fn from_config(config: &WeatherConfig) -> Result<Self, WeatherError> {
+        let url_source = |url_template: &str, api_key: Option<&String>| Source::Http {
+            url_template: url_template.to_string(),
+            api_key: api_key.map(|key| (config.api_key_header.clone(), key.clone())),
+        };
+        let source = match config.provider {
+            WeatherProvider::Noaa => url_source(NOAA_URL_TEMPLATE, config.api_key.as_ref()),
+            WeatherProvider::Custom => url_source(
+                config.url_template.as_deref().unwrap_or_default(),
+                config.api_key.as_ref(),
+            ),
+            WeatherProvider::Static => match &config.static_file {
+                Some(path) => Source::Static(load_static_file(path)?),
+                None => Source::Static(HashMap::new()),
+            },
+        };
+        let timeout = Duration::from_secs(config.request_timeout_secs);
+        let mut providers = vec![Provider::new(
+            config.provider.as_str().to_string(),
+            source,
+            timeout,
+        )];
+        for (i, fallback) in config.fallbacks.iter().enumerate() {
+            providers.push(Provider::new(
+                fallback
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("fallback{}", i + 1)),
+                url_source(&fallback.url_template, fallback.api_key.as_ref()),
+                fallback.timeout_secs.map_or(timeout, Duration::from_secs),
+            ));
+        }
+
+        Ok(Self {
+            providers,
+            metar_ttl: Duration::from_secs(config.metar_cache_ttl_secs),
+            taf_ttl: Duration::from_secs(config.taf_cache_ttl_secs),
+            stale_max: Duration::from_secs(config.stale_max_minutes * 60),
+            cooldown: Duration::from_secs(config.provider_cooldown_secs),
+            metar_refresh: Duration::from_secs(config.metar_refresh_secs),
+            max_subscriptions: config.max_metar_subscriptions,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// How often the METARs of subscribed stations are fetched
+    pub fn metar_refresh(&self) -> Duration {
+        self.metar_refresh
+    }
+
+    /// Stations one client may subscribe to
+    pub fn max_subscriptions(&self) -> usize {
+        self.max_subscriptions
+    }
+
+    pub async fn metar(&self, icao: &str) -> Result<String, WeatherError> {
+        Ok(self.report(Product::Metar, icao).await?.report)
+    }
+
+    pub async fn taf(&self, icao: &str) -> Result<String, WeatherError> {
+        Ok(self.report(Product::Taf, icao).await?.report)
+    }
+
+    /// Fetch the METAR of `icao` even if one is cached, for the refresh of
+    /// subscribed stations; a stale one is never returned
+    pub async fn refresh_metar(&self, icao: &str) -> Result<String, WeatherError> {
+        let (report, _) = self
+            .fetch(Product::Metar, &normalize_station(icao)?)
+            .await?;
+        Ok(report)
+    }
+
+    /// A report from the cache, the first provider that has one, or failing
+    /// that a stale one from the cache
+    pub async fn report(&self, product: Product, icao: &str) -> Result<Served, WeatherError> {
+        let icao = normalize_station(icao)?;
+        let ttl = match product {
+            Product::Metar => self.metar_ttl,
+            Product::Taf => self.taf_ttl,
+        };
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&(product, icao.clone()))
+            .cloned();
+        if let Some((fetched_at, report, _)) = &cached {
+            if fetched_at.elapsed() < ttl {
+                crate::metrics::weather_cache_hit(product.as_str());
+                return Ok(self.served(product, report.clone(), "cache"));
+            }
+        }
+
+        let error = match self.fetch(product, &icao).await {
+            Ok((report, source)) => return Ok(self.served(product, report, &source)),
+            Err(e) => e,
+        };
+        match cached {
+            Some((fetched_at, report, source)) if fetched_at.elapsed() <= self.stale_max => {
+                let minutes = fetched_at.elapsed().as_secs() / 60;
+                tracing::warn!(
+                    "Serving the {} of {} from {} {} minutes ago: {}",
+                    product,
+                    icao,
+                    source,
+                    minutes,
+                    error
+                );
+                Ok(self.served(product, stale_remark(&report, minutes), "stale"))
+            }
+            _ => Err(error),
+        }
+    }
+
+    fn served(&self, product: Product, report: String, source: &str) -> Served {
+        crate::metrics::weather_served(product.as_str(), source);
+        Served {
+            report,
+            source: source.to_string(),
+        }
+    }
+
+    /// Try each provider in turn and cache the first report, returning it
+    /// with the name of its provider
+    ///
+    /// The error is the last provider's, or "not found" if any of them
+    /// answered without a report.
+    async fn fetch(&self, product: Product, icao: &str) -> Result<(String, String), WeatherError> {
+        let mut error = None;
+        for provider in &self.providers {
+            if provider.is_cooling_down() {
+                tracing::debug!("Skipping weather provider {}", provider.name);
+                continue;
+            }
+            match provider.fetch(product, icao).await {
+                Ok(report) => {
+                    self.cache.lock().unwrap().insert(
+                        (product, icao.to_string()),
+                        (Instant::now(), report.clone(), provider.name.clone()),
+                    );
+                    return Ok((report, provider.name.clone()));
+                }
+                Err(e @ WeatherError::NotFound { .. }) => error = Some(e),
+                Err(e) => {
+                    tracing::warn!(
+                        "Weather provider {} failed, skipping it for {}s: {}",
+                        provider.name,
+                        self.cooldown.as_secs(),
+                        e
+                    );
+                    *provider.cooldown_until.lock().unwrap() = Some(Instant::now() + self.cooldown);
+                    if !matches!(error, Some(WeatherError::NotFound { .. })) {
+                        error = Some(e);
+                    }
+                }
+            }
+        }
+        Err(error.unwrap_or(WeatherError::Unavailable))
+    }
+}
+
+/// `report` with a remark that it is `minutes` old
+fn stale_remark(report: &str, minutes: u64) -> String {
+    let remark = format!("STALE {}MIN", minutes);
+    if report.split_whitespace().any(|word| word == "RMK") {
+        format!("{} {}", report, remark)
+    } else {
+        format!("{} RMK {}", report, remark)
+    }
+}
+
+/// Upper-case station identifier, rejecting anything that isn't 3-4
+/// letters or digits so it can be put into a URL as is
+pub fn normalize_station(icao: &str) -> Result<String, WeatherError> {
+    let icao = icao.trim();
+    if !(3..=4).contains(&icao.len()) || !icao.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(WeatherError::InvalidStation(icao.to_string()));
+    }
+    Ok(icao.to_ascii_uppercase())
+}
+
+/// Fill in the `{icao}` and `{product}` placeholders of a URL template
+pub fn render_url(template: &str, product: Product, icao: &str) -> String {
+    template
+        .replace("{icao}", icao)
+        .replace("{product}", &product.to_string())
+}
+
+/// The report in a provider response, on a single line
+///
+/// METARs are one line; TAFs span several, which are joined.
+fn parse_report(body: &str, product: Product) -> Option<String> {
+    let mut lines = body.lines().map(str::trim).filter(|line| !line.is_empty());
+    let report = match product {
+        Product::Metar => lines.next()?.to_string(),
+        Product::Taf => lines.collect::<Vec<_>>().join(" "),
+    };
+    (!report.is_empty()).then_some(report)
+}
+
+/// Read METARs keyed by station from a file with one report per line
+///
+/// Blank lines and lines starting with `#` are skipped, and a leading
+/// "METAR" or "SPECI" is ignored when finding the station.
+fn load_static_file(path: &Path) -> Result<HashMap<String, String>, WeatherError> {
+    let content = std::fs::read_to_string(path).map_err(|source| WeatherError::StaticFile {
+        path: path.display().to_string(),
+        source,
+    })?;
+
+    let mut reports = HashMap::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let report = line
+            .strip_prefix("METAR ")
+            .or_else(|| line.strip_prefix("SPECI "))
+            .unwrap_or(line)
+            .trim_start();
+        if let Some(station) = report.split_whitespace().next() {
+            reports.insert(station.to_ascii_uppercase(), report.to_string());
+        }
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WeatherFallbackConfig;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn custom(url_template: String, timeout_secs: u64) -> WeatherService {
+        WeatherService::from_config(&WeatherConfig {
+            provider: WeatherProvider::Custom,
+            url_template: Some(url_template),
+            request_timeout_secs: timeout_secs,
+            api_key: Some("secret".to_string()),
+            ..WeatherConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_render_url() {
+        assert_eq!(
+            render_url(NOAA_URL_TEMPLATE, Product::Metar, "ZBAA"),
+            "https://aviationweather.gov/api/data/metar?ids=ZBAA"
+        );
+        assert_eq!(
+            render_url("http://wx.local/{icao}/{product}.txt", Product::Taf, "ZSPD"),
+            "http://wx.local/ZSPD/taf.txt"
+        );
+        assert!(matches!(
+            normalize_station("ZB/AA"),
+            Err(WeatherError::InvalidStation(_))
+        ));
+        assert_eq!(normalize_station(" zbaa ").unwrap(), "ZBAA");
+    }
+
+    #[tokio::test]
+    async fn test_static_file() {
+        let path = std::env::temp_dir().join(format!("openfsd-metars-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# Training lab weather\n\
+             ZBAA 121200Z 36004MPS CAVOK 22/08 Q1012 NOSIG\n\
+             \n\
+             METAR ZSPD 121200Z 09005MPS 9999 FEW020 25/18 Q1010 NOSIG\n",
+        )
+        .unwrap();
+
+        let weather = WeatherService::from_config(&WeatherConfig {
+            provider: WeatherProvider::Static,
+            static_file: Some(path.clone()),
+            ..WeatherConfig::default()
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            weather.metar("zbaa").await.unwrap(),
+            "ZBAA 121200Z 36004MPS CAVOK 22/08 Q1012 NOSIG"
+        );
+        assert!(weather.metar("ZSPD").await.unwrap().starts_with("ZSPD "));
+        assert!(matches!(
+            weather.metar("ZGGG").await,
+            Err(WeatherError::NotFound { .. })
+        ));
+        assert!(matches!(
+            weather.taf("ZBAA").await,
+            Err(WeatherError::NotFound { .. })
+        ));
+
+        let missing = WeatherService::from_config(&WeatherConfig {
+            provider: WeatherProvider::Static,
+            static_file: Some(path),
+            ..WeatherConfig::default()
+        });
+        assert!(matches!(missing, Err(WeatherError::StaticFile { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_custom_provider() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 1024];
+            let n = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nZBAA 121200Z 36004MPS CAVOK 22/08 Q1012\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request[..n]).to_string()
+        });
+
+        let weather = custom(
+            format!("http://127.0.0.1:{}/{{product}}?ids={{icao}}", port),
+            5,
+        );
+        assert_eq!(
+            weather.metar("ZBAA").await.unwrap(),
+            "ZBAA 121200Z 36004MPS CAVOK 22/08 Q1012"
+        );
+        let request = server.await.unwrap();
+        assert!(request.starts_with("GET /metar?ids=ZBAA HTTP/1.0\r\n"));
+        assert!(request.contains("X-API-Key: secret\r\n"));
+
+        // Served from the cache, the mock server is gone
+        assert!(weather.metar("ZBAA").await.is_ok());
+    }
+
+    /// A provider answering with `metar`, or HTTP 503 while it is `None`,
+    /// and how many requests it had
+    async fn mock_provider(metar: Arc<Mutex<Option<String>>>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                counted.fetch_add(1, Ordering::SeqCst);
+                let response = match metar.lock().unwrap().clone() {
+                    Some(metar) => format!("HTTP/1.0 200 OK\r\n\r\n{}\n", metar),
+                    None => "HTTP/1.0 503 Service Unavailable\r\n\r\n".to_string(),
+                };
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (format!("http://127.0.0.1:{}/{{icao}}", port), requests)
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let primary_metar = Arc::new(Mutex::new(Some("ZBAA 121200Z 36004MPS CAVOK".to_string())));
+        let secondary_metar = Arc::new(Mutex::new(Some("ZBAA 121200Z 36004MPS 9999".to_string())));
+        let (primary_url, primary_requests) = mock_provider(primary_metar.clone()).await;
+        let (secondary_url, secondary_requests) = mock_provider(secondary_metar.clone()).await;
+        let weather = WeatherService::from_config(&WeatherConfig {
+            provider: WeatherProvider::Custom,
+            url_template: Some(primary_url),
+            fallbacks: vec![WeatherFallbackConfig {
+                name: Some("mirror".to_string()),
+                url_template: secondary_url,
+                timeout_secs: Some(2),
+                api_key: None,
+            }],
+            // Every request goes to the providers
+            metar_cache_ttl_secs: 0,
+            ..WeatherConfig::default()
+        })
+        .unwrap();
+        let served = |report: &str, source: &str| Served {
+            report: report.to_string(),
+            source: source.to_string(),
+        };
+
+        // Primary up
+        assert_eq!(
+            weather.report(Product::Metar, "ZBAA").await.unwrap(),
+            served("ZBAA 121200Z 36004MPS CAVOK", "custom")
+        );
+        assert_eq!(secondary_requests.load(Ordering::SeqCst), 0);
+
+        // Primary down, secondary up; the primary is asked only once
+        *primary_metar.lock().unwrap() = None;
+        for _ in 0..2 {
+            assert_eq!(
+                weather.report(Product::Metar, "ZBAA").await.unwrap(),
+                served("ZBAA 121200Z 36004MPS 9999", "mirror")
+            );
+        }
+        assert_eq!(primary_requests.load(Ordering::SeqCst), 2);
+        assert_eq!(secondary_requests.load(Ordering::SeqCst), 2);
+
+        // Everything down, the last report is served stale
+        *secondary_metar.lock().unwrap() = None;
+        assert_eq!(
+            weather.report(Product::Metar, "ZBAA").await.unwrap(),
+            served("ZBAA 121200Z 36004MPS 9999 RMK STALE 0MIN", "stale")
+        );
+        assert_eq!(secondary_requests.load(Ordering::SeqCst), 3);
+        // ...but never to a subscription refresh, and with both providers
+        // cooling down nothing is asked
+        assert!(matches!(
+            weather.refresh_metar("ZBAA").await,
+            Err(WeatherError::Unavailable)
+        ));
+        assert_eq!(primary_requests.load(Ordering::SeqCst), 2);
+        assert_eq!(secondary_requests.load(Ordering::SeqCst), 3);
+        // Nothing cached for another station
+        assert!(matches!(
+            weather.metar("ZSPD").await,
+            Err(WeatherError::Unavailable)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stale_limit() {
+        let metar = Arc::new(Mutex::new(Some(
+            "ZBAA 121200Z 36004MPS CAVOK RMK AO2".to_string(),
+        )));
+        let (url, _) = mock_provider(metar.clone()).await;
+        let weather = |stale_max_minutes| {
+            WeatherService::from_config(&WeatherConfig {
+                provider: WeatherProvider::Custom,
+                url_template: Some(url.clone()),
+                metar_cache_ttl_secs: 0,
+                stale_max_minutes,
+                ..WeatherConfig::default()
+            })
+            .unwrap()
+        };
+        let (stale, never) = (weather(60), weather(0));
+        assert!(stale.metar("ZBAA").await.is_ok());
+        assert!(never.metar("ZBAA").await.is_ok());
+
+        *metar.lock().unwrap() = None;
+        // Appended to the remarks already there
+        assert_eq!(
+            stale.metar("ZBAA").await.unwrap(),
+            "ZBAA 121200Z 36004MPS CAVOK RMK AO2 STALE 0MIN"
+        );
+        assert!(matches!(
+            never.metar("ZBAA").await,
+            Err(WeatherError::Status(503))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_hanging_provider_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            // Accept and never answer
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        });
+
+        let weather = custom(format!("http://127.0.0.1:{}/{{icao}}", port), 1);
+        let started = Instant::now();
+        assert!(matches!(
+            weather.metar("ZBAA").await,
+            Err(WeatherError::Timeout)
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,460 @@
+//! Outbound webhooks for server events
+//!
+//! [`Webhooks::emit`] is called on the FSD path and only renders and queues the event.
+//! Every target has its own bounded queue and delivery task, which retries
+//! with exponential backoff; a full queue drops the event, so an endpoint
+//! that is slow or down never holds up clients or the other targets.
+
+use crate::client::{Client, ClientType};
+use crate::config::{WebhookEvent, WebhookTarget, WebhooksConfig};
+use crate::flight_plan::FlightPlan;
+use crate::http_client::{self, HttpError};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Placeholders a template may use; those an event doesn't have render empty
+pub const FIELDS: &[&str] = &[
+    "event",
+    "server",
+    "timestamp",
+    "callsign",
+    "cid",
+    "real_name",
+    "client_type",
+    "rating",
+    "flight_rules",
+    "aircraft",
+    "departure",
+    "destination",
+    "altitude",
+    "route",
+    "message",
+    "reason",
+];
+
+/// Whether `template` gives valid JSON once its placeholders are filled in
+pub fn template_is_valid(template: &str) -> bool {
+    let values: Vec<(&str, String)> = FIELDS.iter().map(|name| (*name, "x".into())).collect();
+    serde_json::from_str::<serde_json::Value>(&fill(template, &values)).is_ok()
+}
+
+/// Something that happened, with the fields a webhook can show
+#[derive(Debug, Clone)]
+pub struct Event {
+    kind: WebhookEvent,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Event {
+    pub fn client_connected(client: &Client) -> Self {
+        Self::about_client(WebhookEvent::ClientConnected, client)
+    }
+
+    pub fn client_disconnected(client: &Client) -> Self {
+        Self::about_client(WebhookEvent::ClientDisconnected, client)
+    }
+
+    pub fn flight_plan_filed(callsign: &str, plan: &FlightPlan) -> Self {
+        Self {
+            kind: WebhookEvent::FlightPlanFiled,
+            fields: vec![
+                ("callsign", callsign.to_string()),
+                ("flight_rules", plan.flight_rules.clone()),
+                ("aircraft", plan.aircraft_type().to_string()),
+                ("departure", plan.departure.clone()),
+                ("destination", plan.destination.clone()),
+                ("altitude", plan.altitude.clone()),
+                ("route", plan.route.clone()),
+            ],
+        }
+    }
+
+    /// A `.wallop`, i.e. a text message to `*S`
+    pub fn supervisor_called(callsign: &str, message: &str) -> Self {
+        Self {
+            kind: WebhookEvent::SupervisorCalled,
+            fields: vec![
+                ("callsign", callsign.to_string()),
+                ("message", message.to_string()),
+            ],
+        }
+    }
+
+    pub fn client_kicked(callsign: &str, reason: &str) -> Self {
+        Self {
+            kind: WebhookEvent::ClientKicked,
+            fields: vec![
+                ("callsign", callsign.to_string()),
+                ("reason", reason.to_string()),
+            ],
+        }
+    }
+
+    fn about_client(kind: WebhookEvent, client: &Client) -> Self {
+        let client_type = match client.client_type {
+            Some(ClientType::Pilot) => "pilot",
+            Some(ClientType::Atc) => "atc",
+            Some(ClientType::Observer) => "observer",
+            None => "",
+        };
+        Self {
+            kind,
+            fields: vec![
+                (
+                    "callsign",
+                    client.callsign().unwrap_or_default().to_string(),
+                ),
+                ("cid", client.network_id.clone().unwrap_or_default()),
+                ("real_name", client.real_name.clone().unwrap_or_default()),
+                ("client_type", client_type.to_string()),
+                (
+                    "rating",
+                    client.rating.map(|r| r.to_string()).unwrap_or_default(),
+                ),
+            ],
+        }
+    }
+
+    fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Name of an event as configured and sent
+fn event_name(kind: WebhookEvent) -> &'static str {
+    match kind {
+        WebhookEvent::ClientConnected => "client_connected",
+        WebhookEvent::ClientDisconnected => "client_disconnected",
+        WebhookEvent::FlightPlanFiled => "flight_plan_filed",
+        WebhookEvent::SupervisorCalled => "supervisor_called",
+        WebhookEvent::ClientKicked => "client_kicked",
+    }
+}
+
+struct Target {
+    config: WebhookTarget,
+    queue: mpsc::Sender<String>,
+    /// Host of the URL, for logs; the URL itself often holds a secret
+    host: String,
+}
+
+impl Target {
+    fn wants(&self, event: &Event) -> bool {
+        if !self.config.events.contains(&event.kind) {
+            return false;
+        }
+        if event.kind != WebhookEvent::FlightPlanFiled || self.config.airports.is_empty() {
+            return true;
+        }
+        [event.field("departure"), event.field("destination")]
+            .into_iter()
+            .flatten()
+            .any(|icao| {
+                self.config
+                    .airports
+                    .iter()
+                    .any(|airport| airport.eq_ignore_ascii_case(icao))
+            })
+    }
+}
+
+/// The configured targets of one server; without any, events go nowhere
+#[derive(Default)]
+pub struct Webhooks {
+    server_name: String,
+    targets: Vec<Target>,
+}
+
+impl Webhooks {
+    /// Spawn a delivery task for every target
+    pub fn start(config: &WebhooksConfig, server_name: &str) -> Self {
+        if !config.targets.is_empty() {
+            tracing::info!("Sending events to {} webhook(s)", config.targets.len());
+        }
+        let retry_delay = Duration::from_secs(config.retry_delay_secs);
+        Self::start_with_retry_delay(config, server_name, retry_delay)
+    }
+
+    fn start_with_retry_delay(
+        config: &WebhooksConfig,
+        server_name: &str,
+        retry_delay: Duration,
+    ) -> Self {
+        let targets = config
+            .targets
+            .iter()
+            .map(|target| {
+                let (queue, rx) = mpsc::channel(config.queue_size.max(1));
+                let host = url::Url::parse(&target.url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_string))
+                    .unwrap_or_default();
+                let delivery = Delivery {
+                    url: target.url.clone(),
+                    host: host.clone(),
+                    max_attempts: config.max_attempts.max(1),
+                    retry_delay,
+                    timeout: Duration::from_secs(config.timeout_secs),
+                };
+                tokio::spawn(delivery.run(rx));
+                Target {
+                    config: target.clone(),
+                    queue,
+                    host,
+                }
+            })
+            .collect();
+        Self {
+            server_name: server_name.to_string(),
+            targets,
+        }
+    }
+
+    /// Queue `event` for every target that wants it
+    pub fn emit(&self, event: &Event) {
+        let now = Utc::now();
+        for target in self.targets.iter().filter(|target| target.wants(event)) {
+            let body = render(&target.config, event, &self.server_name, now);
+            if target.queue.try_send(body).is_err() {
+                tracing::warn!(
+                    "Webhook queue for {} is full, dropping a {} event",
+                    target.host,
+                    event_name(event.kind)
+                );
+            }
+        }
+    }
+}
+
+/// The body sent to `target`: its template filled in, or the event's fields
+/// as a JSON object
+fn render(target: &WebhookTarget, event: &Event, server: &str, now: DateTime<Utc>) -> String {
+    let mut values = vec![
+        ("event", event_name(event.kind).to_string()),
+        ("server", server.to_string()),
+        ("timestamp", now.to_rfc3339_opts(SecondsFormat::Secs, true)),
+    ];
+    values.extend(event.fields.iter().cloned());
+    match &target.template {
+        Some(template) => fill(template, &values),
+        None => {
+            let object: serde_json::Map<String, serde_json::Value> = values
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.into()))
+                .collect();
+            serde_json::Value::Object(object).to_string()
+        }
+    }
+}
+
+/// Replace every `{field}` in `template` with its value, escaped to sit
+/// inside a JSON string; braces around anything else are kept as written
+fn fill(template: &str, values: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let name = after.find('}').map(|end| &after[..end]);
+        match name.filter(|name| FIELDS.contains(name)) {
+            Some(name) => {
+                let value = values
+                    .iter()
+                    .find(|(field, _)| *field == name)
+                    .map(|(_, value)| value.as_str())
+                    .unwrap_or_default();
+                let quoted = serde_json::Value::from(value).to_string();
+                out.push_str(&quoted[1..quoted.len() - 1]);
+                rest = &after[name.len() + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Sends one target's queued bodies in order
+struct Delivery {
+    url: String,
+    host: String,
+    max_attempts: u32,
+    retry_delay: Duration,
+    timeout: Duration,
+}
+
+impl Delivery {
+    async fn run(self, mut queue: mpsc::Receiver<String>) {
+        while let Some(body) = queue.recv().await {
+            let mut delay = self.retry_delay;
+            for attempt in 1..=self.max_attempts {
+                match http_client::post_json(&self.url, body.clone(), self.timeout).await {
+                    Ok(()) => break,
+                    Err(e) if attempt == self.max_attempts || !is_transient(&e) => {
+                        tracing::warn!(
+                            "Giving up on a webhook to {} after {} attempt(s): {}",
+                            self.host,
+                            attempt,
+                            e
+                        );
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::debug!("Webhook to {} failed, retrying: {}", self.host, e);
+                        tokio::time::sleep(delay).await;
+                        delay *= 2;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Whether trying again later could succeed; other 4xx answers won't change
+fn is_transient(e: &HttpError) -> bool {
+    match e {
+        HttpError::Status(status) => *status == 429 || *status >= 500,
+        HttpError::InvalidUrl(_) => false,
+        HttpError::Timeout | HttpError::Io(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn plan(departure: &str, destination: &str) -> FlightPlan {
+        FlightPlan {
+            flight_rules: "I".to_string(),
+            aircraft: "H/B744/L".to_string(),
+            departure: departure.to_string(),
+            destination: destination.to_string(),
+            altitude: "FL350".to_string(),
+            route: "CDY W40 DOGAR".to_string(),
+            ..FlightPlan::default()
+        }
+    }
+
+    fn config(url: String, template: Option<&str>, airports: &[&str]) -> WebhooksConfig {
+        WebhooksConfig {
+            targets: vec![WebhookTarget {
+                url,
+                events: vec![WebhookEvent::FlightPlanFiled, WebhookEvent::ClientKicked],
+                template: template.map(str::to_string),
+                airports: airports.iter().map(|a| a.to_string()).collect(),
+            }],
+            timeout_secs: 5,
+            ..WebhooksConfig::default()
+        }
+    }
+
+    /// Answer the next request with `status` and return its body
+    async fn answer(listener: &TcpListener, status: &str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .and_then(|length| length.parse().ok())
+                    .unwrap_or_default();
+                if body.len() >= length || n == 0 {
+                    assert!(head.starts_with("POST /hook HTTP/1.0\r\n"), "{}", head);
+                    let response = format!("HTTP/1.0 {}\r\n\r\n", status);
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    return body.to_string();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fill() {
+        let values = [
+            ("callsign", "CCA1501".to_string()),
+            ("message", "say \"hi\"\n".to_string()),
+        ];
+        assert_eq!(
+            fill(
+                r#"{"content": "{callsign}: {message} {departure} {unknown}"}"#,
+                &values
+            ),
+            r#"{"content": "CCA1501: say \"hi\"\n  {unknown}"}"#
+        );
+        assert!(template_is_valid(r#"{"text": "{callsign} filed {route}"}"#));
+        assert!(!template_is_valid("{callsign}"));
+    }
+
+    #[tokio::test]
+    async fn test_payload_contents() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let plain = Webhooks::start(&config(url.clone(), None, &["ZSPD"]), "OpenFSD");
+        // Neither end is the hub, so this one isn't sent
+        plain.emit(&Event::flight_plan_filed("CES5101", &plan("ZGGG", "ZUUU")));
+        plain.emit(&Event::flight_plan_filed("CCA1501", &plan("ZBAA", "ZSPD")));
+        let body: serde_json::Value =
+            serde_json::from_str(&answer(&listener, "204 No Content").await).unwrap();
+        assert_eq!(body["event"], "flight_plan_filed");
+        assert_eq!(body["server"], "OpenFSD");
+        assert_eq!(body["callsign"], "CCA1501");
+        assert_eq!(body["aircraft"], "B744");
+        assert_eq!(body["destination"], "ZSPD");
+        assert!(body["timestamp"].as_str().unwrap().ends_with('Z'));
+
+        let template = r#"{"content": "{callsign} was kicked: {reason}"}"#;
+        let discord = Webhooks::start(&config(url, Some(template), &[]), "OpenFSD");
+        discord.emit(&Event::client_connected(&Client::new(
+            "127.0.0.1:1".parse().unwrap(),
+        )));
+        discord.emit(&Event::client_kicked("CCA1501", "no \"flight plan\""));
+        let body: serde_json::Value =
+            serde_json::from_str(&answer(&listener, "200 OK").await).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"content": "CCA1501 was kicked: no \"flight plan\""})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_delivered() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let webhooks = Webhooks::start_with_retry_delay(
+            &config(url, None, &[]),
+            "OpenFSD",
+            Duration::from_millis(10),
+        );
+
+        webhooks.emit(&Event::client_kicked("CCA1501", "flood"));
+        webhooks.emit(&Event::client_kicked("CES5101", "flood"));
+        let first = answer(&listener, "503 Service Unavailable").await;
+        let retried = answer(&listener, "429 Too Many Requests").await;
+        let delivered = answer(&listener, "204 No Content").await;
+        assert_eq!(first, retried);
+        assert_eq!(first, delivered);
+        assert!(first.contains("CCA1501"));
+
+        // A permanent error isn't retried; the next event follows
+        assert!(answer(&listener, "400 Bad Request")
+            .await
+            .contains("CES5101"));
+        webhooks.emit(&Event::client_kicked("CSN3101", "flood"));
+        assert!(answer(&listener, "200 OK").await.contains("CSN3101"));
+    }
+}
@@ -0,0 +1,340 @@
+//! Status feed in the classic whazzup.txt format
+//!
+//! Servinfo consumers and statistics sites read the `!CLIENTS` section as
+//! colon-separated lines with a fixed set of 41 fields, each followed by a
+//! colon, so every line has to keep that shape even when a value is unknown:
+//!
+//! ```text
+//! callsign:cid:realname:clienttype:frequency:latitude:longitude:altitude:
+//! groundspeed:planned_aircraft:planned_tascruise:planned_depairport:
+//! planned_altitude:planned_destairport:server:protrevision:rating:
+//! transponder:facilitytype:visualrange:planned_revision:planned_flighttype:
+//! planned_deptime:planned_actdeptime:planned_hrsenroute:planned_minenroute:
+//! planned_hrsfuel:planned_minfuel:planned_altairport:planned_remarks:
+//! planned_route:planned_depairport_lat:planned_depairport_lon:
+//! planned_destairport_lat:planned_destairport_lon:atis_message:
+//! time_last_atis_received:time_logon:heading:QNH_iHg:QNH_Mb:
+//! ```
+
+use crate::client::{format_frequency, Client, ClientType};
+use crate::server::clock::Clock;
+use crate::server::registry::ClientRegistry;
+use crate::server::ServerConfig;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+/// Fields of a `!CLIENTS` line
+pub const CLIENT_FIELDS: usize = 41;
+
+/// Format version announced in `!GENERAL`
+const VERSION: u32 = 8;
+
+/// Separator between the lines of an ATIS in `atis_message`
+const ATIS_LINE_SEPARATOR: &str = "^§";
+
+/// Write the feed to the configured file every `interval_secs`
+pub async fn run(
+    config: ServerConfig,
+    clients: Arc<RwLock<ClientRegistry>>,
+    clock: Arc<dyn Clock>,
+) {
+    let mut interval = clock.interval(Duration::from_secs(config.whazzup.interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+
+        let feed = render(&*clients.read().await, &config, clock.utc());
+        if let Err(e) = write_atomically(&config.whazzup.path, &feed).await {
+            tracing::error!(
+                "Failed to write whazzup file {}: {}",
+                config.whazzup.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Write through a temporary file so readers never see a partial feed
+async fn write_atomically(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    tokio::fs::write(&temporary, contents).await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+/// The feed for the logged-in clients, sorted by callsign
+pub fn render(
+    clients: &HashMap<SocketAddr, Client>,
+    config: &ServerConfig,
+    now: DateTime<Utc>,
+) -> String {
+    let mut listed: Vec<&Client> = clients
+        .values()
+        .filter(|client| client.is_active() && client.callsign.is_some())
+        .collect();
+    listed.sort_by(|a, b| a.callsign.cmp(&b.callsign));
+
+    let reload_minutes = config.whazzup.interval_secs.div_ceil(60).max(1);
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "; Created by {} {}",
+        config.server_name, config.server_version
+    );
+    out.push_str("!GENERAL:\n");
+    let _ = writeln!(out, "VERSION = {}", VERSION);
+    let _ = writeln!(out, "RELOAD = {}", reload_minutes);
+    let _ = writeln!(out, "UPDATE = {}", timestamp(now));
+    let _ = writeln!(out, "CONNECTED CLIENTS = {}", listed.len());
+    out.push_str("CONNECTED SERVERS = 1\n");
+
+    out.push_str("!CLIENTS:\n");
+    for client in listed {
+        out.push_str(&client_line(client, &config.server_name));
+        out.push('\n');
+    }
+
+    out.push_str("!SERVERS:\n");
+    let server = [
+        config.server_name.as_str(),
+        &config.hostname,
+        &config.location,
+        &config.server_name,
+        "1",
+    ];
+    out.push_str(&join(server.iter().map(|field| field.to_string())));
+    out.push('\n');
+    out
+}
+
+/// One `!CLIENTS` line; fields that don't apply to the client are empty
+fn client_line(client: &Client, server: &str) -> String {
+    let is_pilot = client.client_type == Some(ClientType::Pilot);
+    let number = |value: Option<String>| value.unwrap_or_else(|| "0".to_string());
+    let text = |value: &Option<String>| value.clone().unwrap_or_default();
+
+    let mut fields = vec![
+        client.callsign().unwrap_or_default().to_string(),
+        text(&client.network_id),
+        text(&client.real_name),
+        if is_pilot { "PILOT" } else { "ATC" }.to_string(),
+        match client.frequency {
+            Some(frequency) if !is_pilot => format_frequency(frequency),
+            _ => String::new(),
+        },
+        number(client.latitude.map(|lat| format!("{:.5}", lat))),
+        number(client.longitude.map(|lon| format!("{:.5}", lon))),
+        number(client.altitude.map(|alt| alt.to_string())),
+        number(
+            client
+                .groundspeed
+                .filter(|_| is_pilot)
+                .map(|gs| gs.to_string()),
+        ),
+    ];
+
+    let plan = client.flight_plan.as_ref().filter(|_| is_pilot);
+    let planned = |field: fn(&crate::flight_plan::FlightPlan) -> &String| {
+        plan.map(|plan| field(plan).clone()).unwrap_or_default()
+    };
+    fields.extend([
+        planned(|plan| &plan.aircraft),
+        planned(|plan| &plan.cruise_speed),
+        planned(|plan| &plan.departure),
+        planned(|plan| &plan.altitude),
+        planned(|plan| &plan.destination),
+        server.to_string(),
+        client
+            .protocol_revision
+            .map(|revision| revision.to_string())
+            .unwrap_or_default(),
+        client.rating.unwrap_or_default().to_string(),
+        if is_pilot {
+            text(&client.squawk)
+        } else {
+            String::new()
+        },
+        match client.facility {
+            Some(facility) if !is_pilot => (facility as u8).to_string(),
+            _ => String::new(),
+        },
+        match client.declared_range_nm {
+            Some(range) if !is_pilot => range.to_string(),
+            _ => String::new(),
+        },
+        if plan.is_some() { "1" } else { "" }.to_string(),
+        planned(|plan| &plan.flight_rules),
+        planned(|plan| &plan.departure_time),
+        planned(|plan| &plan.actual_departure_time),
+        planned(|plan| &plan.hours_enroute),
+        planned(|plan| &plan.minutes_enroute),
+        planned(|plan| &plan.hours_fuel),
+        planned(|plan| &plan.minutes_fuel),
+        planned(|plan| &plan.alternate),
+        planned(|plan| &plan.remarks),
+        planned(|plan| &plan.route),
+    ]);
+
+    // Airport positions aren't known to the server
+    let airport_position = if plan.is_some() { "0" } else { "" };
+    fields.extend(std::iter::repeat_n(airport_position.to_string(), 4));
+
+    let atis = if is_pilot { &[][..] } else { &client.atis[..] };
+    fields.extend([
+        atis.join(ATIS_LINE_SEPARATOR),
+        match client.atis_updated_at {
+            Some(updated_at) if !atis.is_empty() => timestamp(updated_at),
+            _ => String::new(),
+        },
+        client.logon_time.map(timestamp).unwrap_or_default(),
+        number(
+            client
+                .heading
+                .filter(|_| is_pilot)
+                .map(|hdg| hdg.to_string()),
+        ),
+        // Pilots' altimeter settings aren't sent to the server
+        "0".to_string(),
+        "0".to_string(),
+    ]);
+
+    debug_assert_eq!(fields.len(), CLIENT_FIELDS);
+    join(fields.into_iter())
+}
+
+/// Fields joined into a line, each escaped and followed by a colon
+fn join(fields: impl Iterator<Item = String>) -> String {
+    fields.fold(String::new(), |mut line, field| {
+        line.push_str(&escape(&field));
+        line.push(':');
+        line
+    })
+}
+
+/// A value made safe for one field: colons would shift every later column
+/// and line breaks would start a new record
+fn escape(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ':' => ' ',
+            '\r' | '\n' => ' ',
+            c => c,
+        })
+        .collect()
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%d%H%M%S").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Facility;
+    use crate::flight_plan::FlightPlan;
+    use crate::packet::Packet;
+    use chrono::TimeZone;
+
+    /// Known-good feed for the clients below
+    const GOLDEN: &str = include_str!("../tests/fixtures/whazzup.txt");
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 6, 1, hour, minute, 0).unwrap()
+    }
+
+    fn clients() -> HashMap<SocketAddr, Client> {
+        let mut pilot =
+            Client::active_for_tests(([127, 0, 0, 1], 50001), "CCA1501", ClientType::Pilot);
+        pilot.protocol_revision = Some(100);
+        pilot.network_id = Some("1234567".to_string());
+        // Names come from the database and may hold anything
+        pilot.real_name = Some("Li: Wei ZBAA".to_string());
+        pilot.rating = Some(1);
+        pilot.latitude = Some(40.08);
+        pilot.longitude = Some(116.58);
+        pilot.altitude = Some(35000);
+        pilot.groundspeed = Some(452);
+        pilot.heading = Some(184);
+        pilot.squawk = Some("4521".to_string());
+        pilot.logon_time = Some(at(11, 5));
+        pilot.flight_plan = FlightPlan::from_packet(
+            &Packet::parse(
+                "$FPCCA1501:*A:I:H/B744/L:490:ZBAA:1130:1135:FL350:ZSPD:1:55:3:30:ZSHC:/v/:CDY W40 DOGAR",
+            )
+            .unwrap(),
+        );
+
+        let mut approach =
+            Client::active_for_tests(([127, 0, 0, 1], 50002), "ZSPD_APP", ClientType::Atc);
+        approach.protocol_revision = Some(100);
+        approach.network_id = Some("1000000".to_string());
+        approach.real_name = Some("Zhang San".to_string());
+        approach.rating = Some(5);
+        approach.frequency = Some(25100);
+        approach.facility = Some(Facility::Approach);
+        approach.declared_range_nm = Some(150);
+        approach.latitude = Some(31.14);
+        approach.longitude = Some(121.79);
+        approach.logon_time = Some(at(10, 30));
+        approach.atis = vec![
+            "Shanghai Approach".to_string(),
+            "Radar services available".to_string(),
+        ];
+        approach.atis_updated_at = Some(at(10, 31));
+
+        // Not logged in yet, so not listed
+        let pending = Client::new(SocketAddr::from(([127, 0, 0, 1], 50003)));
+
+        [pilot, approach, pending]
+            .into_iter()
+            .map(|client| (client.addr, client))
+            .collect()
+    }
+
+    fn config() -> ServerConfig {
+        ServerConfig {
+            server_name: "OPENFSD".to_string(),
+            server_version: "0.1.0".to_string(),
+            hostname: "fsd.example.com".to_string(),
+            location: "Shanghai".to_string(),
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_matches_golden_file() {
+        assert_eq!(render(&clients(), &config(), at(12, 0)), GOLDEN);
+    }
+
+    #[test]
+    fn test_every_client_line_has_all_fields() {
+        let feed = render(&clients(), &config(), at(12, 0));
+        let lines: Vec<&str> = feed
+            .lines()
+            .skip_while(|line| *line != "!CLIENTS:")
+            .skip(1)
+            .take_while(|line| !line.starts_with('!'))
+            .collect();
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            assert!(line.ends_with(':'), "{}", line);
+            assert_eq!(line.matches(':').count(), CLIENT_FIELDS, "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_empty_feed() {
+        let feed = render(&HashMap::new(), &config(), at(12, 0));
+        assert!(
+            feed.contains("CONNECTED CLIENTS = 0\nCONNECTED SERVERS = 1\n!CLIENTS:\n!SERVERS:\n")
+        );
+        assert!(feed.ends_with("!SERVERS:\nOPENFSD:fsd.example.com:Shanghai:OPENFSD:1:\n"));
+    }
+}
@@ -0,0 +1,371 @@
+//! End-to-end tests of the openfsd-admin commands against a SQLite file
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+/// A database file removed again when the test ends
+struct TempDb(PathBuf);
+
+impl TempDb {
+    fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("openfsd-admin-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        TempDb(path)
+    }
+
+    fn url(&self) -> String {
+        format!("sqlite://{}?mode=rwc", self.0.display())
+    }
+
+    /// Run openfsd-admin against this database with `stdin` as input
+    fn admin(&self, args: &[&str], stdin: &str) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_openfsd-admin"))
+            .arg("--db")
+            .arg(self.url())
+            .args(args)
+            .env_remove("DATABASE_URL")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("start openfsd-admin");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(stdin.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn test_user_add_and_list() {
+    let db = TempDb::new("users");
+    let add = db.admin(
+        &[
+            "user",
+            "add",
+            "--cid",
+            "1234567",
+            "--name",
+            "Jane Doe",
+            "--atc-rating",
+            "3",
+            "--password-stdin",
+        ],
+        "correct horse\n",
+    );
+    assert!(
+        add.status.success(),
+        "{}",
+        String::from_utf8_lossy(&add.stderr)
+    );
+
+    let list = db.admin(&["user", "list", "--json"], "");
+    assert!(list.status.success());
+    let page: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+    assert_eq!(page["total"], 1);
+    let user = &page["items"][0];
+    assert_eq!(user["network_id"], "1234567");
+    assert_eq!(user["real_name"], "Jane Doe");
+    assert_eq!(user["atc_rating"], 3);
+    // The hash stays in the database
+    assert!(user.get("password_hash").is_none());
+
+    // Adding the same CID again fails with a non-zero exit code
+    let duplicate = db.admin(
+        &[
+            "user",
+            "add",
+            "--cid",
+            "1234567",
+            "--name",
+            "Jane Doe",
+            "--password-stdin",
+        ],
+        "another\n",
+    );
+    assert!(!duplicate.status.success());
+}
+
+#[test]
+fn test_password_is_not_an_argument() {
+    let db = TempDb::new("password-arg");
+    let add = db.admin(
+        &[
+            "user",
+            "add",
+            "--cid",
+            "1234567",
+            "--name",
+            "Jane Doe",
+            "--password",
+            "secret",
+        ],
+        "",
+    );
+    // Usage errors exit with 2 before anything is stored
+    assert_eq!(add.status.code(), Some(2));
+
+    let empty = db.admin(
+        &[
+            "user",
+            "add",
+            "--cid",
+            "1234567",
+            "--name",
+            "Jane Doe",
+            "--password-stdin",
+        ],
+        "\n",
+    );
+    assert!(!empty.status.success());
+}
+
+#[test]
+fn test_disable_and_delete() {
+    let db = TempDb::new("disable");
+    let add = db.admin(
+        &[
+            "user",
+            "add",
+            "--cid",
+            "1234567",
+            "--name",
+            "Jane Doe",
+            "--password-stdin",
+        ],
+        "correct horse\n",
+    );
+    assert!(add.status.success());
+
+    assert!(db
+        .admin(&["user", "disable", "1234567"], "")
+        .status
+        .success());
+    let list = db.admin(&["user", "list", "--json"], "");
+    let page: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+    assert!(page["items"][0]["disabled_at"].is_string());
+    assert!(!db
+        .admin(&["user", "disable", "1234567"], "")
+        .status
+        .success());
+
+    // Deleting asks first; without a yes nothing happens
+    let unconfirmed = db.admin(&["user", "delete", "1234567"], "n\n");
+    assert!(!unconfirmed.status.success());
+    assert!(db
+        .admin(&["user", "delete", "1234567", "--yes"], "")
+        .status
+        .success());
+
+    let missing = db.admin(&["user", "delete", "7654321", "--yes"], "");
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("User not found: 7654321"));
+}
+
+#[test]
+fn test_password_reset() {
+    let db = TempDb::new("passwd");
+    let add = db.admin(
+        &[
+            "user",
+            "add",
+            "--cid",
+            "1234567",
+            "--name",
+            "Jane Doe",
+            "--password-stdin",
+        ],
+        "correct horse\n",
+    );
+    assert!(add.status.success());
+
+    // config.toml asks for at least 8 characters
+    let short = db.admin(
+        &["user", "passwd", "1234567", "--password-stdin"],
+        "short\n",
+    );
+    assert_eq!(short.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&short.stderr).contains("at least 8 characters"));
+
+    let reset = db.admin(
+        &["user", "passwd", "1234567", "--password-stdin"],
+        "battery staple\n",
+    );
+    assert!(reset.status.success());
+
+    let generated = db.admin(&["user", "passwd", "1234567", "--generate"], "");
+    assert!(generated.status.success());
+    let stdout = String::from_utf8_lossy(&generated.stdout);
+    let password = stdout.lines().last().unwrap().rsplit(' ').next().unwrap();
+    assert_eq!(password.len(), 20);
+}
+
+#[test]
+fn test_whitelist_management() {
+    let db = TempDb::new("whitelist");
+    let add = db.admin(
+        &[
+            "whitelist",
+            "add",
+            "b0b0",
+            "Homebrew 1.0",
+            "--min-version",
+            "1.0.2",
+            "--key",
+            "s3cret",
+        ],
+        "",
+    );
+    assert!(
+        add.status.success(),
+        "{}",
+        String::from_utf8_lossy(&add.stderr)
+    );
+
+    // Keys are masked unless asked for and confirmed
+    let list = db.admin(&["whitelist", "list"], "");
+    assert!(list.status.success());
+    let table = String::from_utf8_lossy(&list.stdout);
+    assert!(table.contains("Homebrew 1.0"));
+    assert!(table.contains("1.0.2"));
+    assert!(!table.contains("s3cret"));
+    let refused = db.admin(&["whitelist", "list", "--show-keys"], "n\n");
+    assert!(!refused.status.success());
+    let shown = db.admin(
+        &[
+            "whitelist",
+            "list",
+            "--client-id",
+            "b0b0",
+            "--show-keys",
+            "--json",
+        ],
+        "y\n",
+    );
+    assert!(shown.status.success());
+    let page: serde_json::Value = serde_json::from_slice(&shown.stdout).unwrap();
+    assert_eq!(page["items"][0]["client_key"], "s3cret");
+
+    assert!(db
+        .admin(&["whitelist", "disable", "b0b0"], "")
+        .status
+        .success());
+    let list = db.admin(&["whitelist", "list", "--client-id", "b0b0", "--json"], "");
+    let page: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+    assert_eq!(page["items"][0]["enabled"], false);
+    assert!(page["items"][0].get("client_key").is_none());
+    assert!(!db
+        .admin(&["whitelist", "disable", "b0b0"], "")
+        .status
+        .success());
+    assert!(db
+        .admin(&["whitelist", "enable", "b0b0"], "")
+        .status
+        .success());
+
+    assert!(db
+        .admin(&["whitelist", "remove", "b0b0", "--yes"], "")
+        .status
+        .success());
+    let missing = db.admin(&["whitelist", "enable", "b0b0"], "");
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("Client not whitelisted: b0b0"));
+}
+
+#[test]
+fn test_user_import_and_export() {
+    let db = TempDb::new("import");
+    let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/users.csv");
+
+    let dry_run = db.admin(&["user", "import", fixture, "--dry-run"], "");
+    let list = db.admin(&["user", "list", "--json"], "");
+    let page: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+    assert_eq!(page["total"], 0);
+    assert_eq!(dry_run.status.code(), Some(1));
+
+    // The short password is reported; the other rows are imported anyway
+    let import = db.admin(&["user", "import", fixture], "");
+    assert_eq!(import.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&import.stdout);
+    assert!(stdout.contains("第 3 行: Password must be at least 8 characters long"));
+    let list = db.admin(&["user", "list", "--json"], "");
+    let page: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+    assert_eq!(page["total"], 2);
+    assert_eq!(page["items"][0]["email"], "jane@example.com");
+    assert_eq!(page["items"][1]["network_id"], "1000003");
+
+    let out = std::env::temp_dir().join(format!("openfsd-export-{}.csv", std::process::id()));
+    let out_path = out.to_str().unwrap();
+    assert!(db.admin(&["user", "export", out_path], "").status.success());
+    let plain = std::fs::read_to_string(&out).unwrap();
+    assert!(db
+        .admin(&["user", "export", out_path, "--include-hashes"], "")
+        .status
+        .success());
+    let with_hashes = std::fs::read_to_string(&out).unwrap();
+    std::fs::remove_file(&out).unwrap();
+    assert!(!plain.contains("argon2"));
+    assert!(plain.starts_with("cid,name,atc_rating,pilot_rating,email\n1000001,Jane Doe,3,1,"));
+    assert_eq!(with_hashes.matches("$argon2").count(), 2);
+}
+
+#[test]
+fn test_db_status_and_migrate() {
+    let db = TempDb::new("migrate");
+    let count = |output: &Output, marker: &str| {
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| line.starts_with(marker))
+            .count()
+    };
+
+    let status = db.admin(&["db", "status"], "");
+    assert!(status.status.success(), "{:?}", status);
+    let total = count(&status, "⏳");
+    assert!(total > 2);
+    assert_eq!(count(&status, "✅"), 0);
+
+    // The server won't start on a schema it isn't allowed to migrate
+    let server = Command::new(env!("CARGO_BIN_EXE_openfsd"))
+        .args(["--database-url", &db.url(), "--no-migrate"])
+        .output()
+        .unwrap();
+    assert!(!server.status.success());
+    assert!(String::from_utf8_lossy(&server.stderr).contains("pending"));
+
+    assert!(db
+        .admin(&["db", "migrate", "--steps", "2"], "")
+        .status
+        .success());
+    let status = db.admin(&["db", "status"], "");
+    assert_eq!(count(&status, "✅"), 2);
+    assert_eq!(count(&status, "⏳"), total - 2);
+
+    let migrate = db.admin(&["db", "migrate"], "");
+    assert_eq!(count(&migrate, "✅"), total - 2);
+    let check = db.admin(&["db", "check"], "");
+    let stdout = String::from_utf8_lossy(&check.stdout);
+    assert!(stdout.contains("SQLite"), "{}", stdout);
+    assert!(stdout.contains("待应用的迁移: 0"), "{}", stdout);
+
+    // Rolling back asks first
+    let declined = db.admin(&["db", "rollback", "--steps", "1"], "n\n");
+    assert_eq!(declined.status.code(), Some(1));
+    assert!(db
+        .admin(&["db", "rollback", "--steps", "1", "--yes"], "")
+        .status
+        .success());
+    let status = db.admin(&["db", "status"], "");
+    assert_eq!(count(&status, "⏳"), 1);
+}
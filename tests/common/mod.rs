@@ -0,0 +1,155 @@
+//! In-process server for end-to-end tests
+//!
+//! Every server gets its own in-memory database and ephemeral ports, so
+//! tests using it can run in parallel.
+
+// Each test binary uses a different part of the harness
+#![allow(dead_code)]
+
+use openfsd::auth::password::hash_password;
+use openfsd::config::Config;
+use openfsd::db::{self, service};
+use openfsd::fsd_client::Login;
+use openfsd::server::{CancellationToken, ServerBuilder};
+use sea_orm::DatabaseConnection;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// Network ID and password of the seeded user
+pub const TEST_CID: &str = "1234567";
+pub const TEST_PASSWORD: &str = "Correct-Horse-42";
+/// The whitelisted client software, which is challenged with its key
+pub const TEST_CLIENT_ID: &str = "c1c1";
+pub const TEST_CLIENT_KEY: &str = "harness-key";
+
+/// How long the server may take to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A running server, stopped when the handle is shut down or dropped
+pub struct ServerHandle {
+    /// The settings it runs with, including the addresses its control,
+    /// status and WebSocket listeners were bound to
+    pub config: Config,
+    shutdown: CancellationToken,
+    task: Option<JoinHandle<Result<(), String>>>,
+}
+
+impl ServerHandle {
+    /// Whether the server has stopped already, e.g. because it could not
+    /// start
+    pub fn has_stopped(&self) -> bool {
+        self.task.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// Stop the server and wait until it has, failing the test if it
+    /// doesn't stop cleanly
+    pub async fn shutdown(mut self) {
+        self.shutdown.cancel();
+        let task = self.task.take().unwrap();
+        let result = tokio::time::timeout(SHUTDOWN_TIMEOUT, task)
+            .await
+            .expect("server did not stop")
+            .expect("server task panicked");
+        assert_eq!(result, Ok(()));
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Start a server with the default test settings
+pub async fn spawn_test_server() -> (SocketAddr, ServerHandle, DatabaseConnection) {
+    spawn_test_server_with(|_| {}).await
+}
+
+/// Start a server on an ephemeral port with a fresh in-memory database
+/// holding the test user and the whitelisted test client; `configure`
+/// adjusts the settings first, and the listeners it enables get ephemeral
+/// ports too
+pub async fn spawn_test_server_with(
+    configure: impl FnOnce(&mut Config),
+) -> (SocketAddr, ServerHandle, DatabaseConnection) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut config = Config::default();
+    config.server.address = addr.ip().to_string();
+    config.server.port = addr.port();
+    config.database.url = "sqlite::memory:".to_string();
+    config.whitelist.enforce = true;
+    config.heartbeat.enabled = false;
+    configure(&mut config);
+
+    let db = db::init(&config.database).await.unwrap();
+    seed(&db).await;
+
+    let shutdown = CancellationToken::new();
+    let mut builder = ServerBuilder::new()
+        .with_database(db.clone())
+        .with_shutdown_token(shutdown.clone());
+    if config.control.enabled {
+        let listener = ephemeral(&mut config.control.address).await;
+        builder = builder.with_control_listener(listener);
+    }
+    if config.status.enabled {
+        let listener = ephemeral(&mut config.status.address).await;
+        builder = builder.with_status_listener(listener);
+    }
+    if config.websocket.enabled {
+        let listener = ephemeral(&mut config.websocket.address).await;
+        builder = builder.with_websocket_listener(listener);
+    }
+    let server = builder.with_config(config.clone()).build().unwrap();
+    let task = tokio::spawn(async move {
+        server
+            .run_with_listener(listener)
+            .await
+            .map_err(|e| e.to_string())
+    });
+
+    let handle = ServerHandle {
+        config,
+        shutdown,
+        task: Some(task),
+    };
+    (addr, handle, db)
+}
+
+/// A listener on an ephemeral port, with `address` set to where it is
+async fn ephemeral(address: &mut String) -> TcpListener {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    *address = listener.local_addr().unwrap().to_string();
+    listener
+}
+
+async fn seed(db: &DatabaseConnection) {
+    service::create_user(
+        db,
+        TEST_CID.to_string(),
+        hash_password(TEST_PASSWORD).unwrap(),
+        "Test Pilot".to_string(),
+        5,
+        1,
+    )
+    .await
+    .unwrap();
+    service::add_client_to_whitelist(
+        db,
+        TEST_CLIENT_ID.to_string(),
+        "Test Client".to_string(),
+        None,
+        Some(TEST_CLIENT_KEY.to_string()),
+    )
+    .await
+    .unwrap();
+}
+
+/// A login as the seeded user with the whitelisted client software
+pub fn test_login(callsign: &str) -> Login {
+    Login::new(callsign, TEST_CID, TEST_PASSWORD).with_client(TEST_CLIENT_ID, Some(TEST_CLIENT_KEY))
+}
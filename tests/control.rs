@@ -0,0 +1,306 @@
+//! End-to-end test of the control socket: a running server, a client
+//! logged in over TCP and openfsd-admin listing, dumping and kicking it,
+//! plus a state dump on SIGUSR1 from the server binary
+
+mod common;
+
+use common::{spawn_test_server_with, test_login, ServerHandle, TEST_CID, TEST_PASSWORD};
+use openfsd::fsd_client::{FsdClient, Login};
+use openfsd::packet::Packet;
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::process::Command;
+
+const SECRET: &str = "control-test-secret";
+
+/// A server with the control socket enabled, and where it dumps its state
+async fn start(name: &str) -> (FsdClient, ServerHandle, PathBuf) {
+    let dumps = std::env::temp_dir().join(format!(
+        "openfsd-control-dumps-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dumps);
+    let (addr, server, _db) = spawn_test_server_with(|config| {
+        config.control.enabled = true;
+        config.control.secret = Some(SECRET.to_string());
+        config.dump.directory = dumps.clone();
+        config.dump.redact_ips = true;
+    })
+    .await;
+    let client = FsdClient::connect(addr).await.unwrap();
+    (client, server, dumps)
+}
+
+/// Run openfsd-admin against the server's control socket
+async fn admin(server: &ServerHandle, args: &[&str]) -> Output {
+    admin_at(&server.config.control.address, args).await
+}
+
+/// Run openfsd-admin against the control socket at `control`
+async fn admin_at(control: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_openfsd-admin"))
+        .args(args)
+        .args(["--control", control])
+        .args(["--secret", SECRET])
+        .output()
+        .await
+        .expect("start openfsd-admin")
+}
+
+async fn list_json(server: &ServerHandle) -> String {
+    let output = admin(server, &["clients", "list", "--json"]).await;
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Log the pilot in and wait until the control socket lists it
+async fn login_pilot(server: &ServerHandle, pilot: &mut FsdClient, callsign: &str) {
+    pilot.login_pilot(&test_login(callsign)).await.unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !list_json(server).await.contains(callsign) {
+        assert!(Instant::now() < deadline, "{} did not log in", callsign);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_list_and_kick_through_admin_tool() {
+    let (mut pilot, server, _dumps) = start("kick").await;
+    assert_eq!(list_json(&server).await.trim(), "[]");
+
+    login_pilot(&server, &mut pilot, "CCA1501").await;
+    let clients: serde_json::Value = serde_json::from_str(&list_json(&server).await).unwrap();
+    let client = &clients[0];
+    assert_eq!(client["callsign"], "CCA1501");
+    assert_eq!(client["cid"], TEST_CID);
+    assert_eq!(client["client_type"], "pilot");
+    assert_eq!(client["ip"], "127.0.0.1");
+    assert!(client["aircraft_type"].is_null());
+
+    // The aircraft is picked up from the pilot's answer to another pilot
+    let answer =
+        Packet::parse("#SBCCA1501:DLH123:PI:GEN:EQUIPMENT=B738:AIRLINE=CCA:LIVERY=CCA").unwrap();
+    pilot.send(&answer).await.unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !list_json(&server)
+        .await
+        .contains("\"aircraft_type\":\"B738\"")
+    {
+        assert!(Instant::now() < deadline, "aircraft type not listed");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let table = admin(&server, &["clients", "list"]).await;
+    let table = String::from_utf8_lossy(&table.stdout);
+    assert!(table.contains("CCA1501"));
+    assert!(table.contains("B738"));
+
+    let kick = admin(
+        &server,
+        &["clients", "kick", "CCA1501", "--reason", "Test kick"],
+    )
+    .await;
+    assert!(kick.status.success(), "{:?}", kick);
+
+    // The client is told why, then the server closes the connection, which
+    // ends the loop
+    let mut received = Vec::new();
+    while let Some(packet) = tokio::time::timeout(Duration::from_secs(10), pilot.next_packet())
+        .await
+        .expect("connection not closed")
+    {
+        received.push(packet.to_string());
+    }
+    assert!(
+        received
+            .iter()
+            .any(|line| line == "#TMSERVER:CCA1501:You have been disconnected: Test kick"),
+        "{:?}",
+        received
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while list_json(&server).await.contains("CCA1501") {
+        assert!(Instant::now() < deadline, "kicked client still listed");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let missing = admin(
+        &server,
+        &["clients", "kick", "CCA1501", "--reason", "Again"],
+    )
+    .await;
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("No client with callsign CCA1501"));
+
+    server.shutdown().await;
+}
+
+/// Wait until the dump directory holds `count` dumps and parse the newest
+async fn wait_for_dump(dumps: &Path, count: usize) -> serde_json::Value {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(dumps)
+            .map(|entries| entries.map(|entry| entry.unwrap().path()).collect())
+            .unwrap_or_default();
+        if files.len() >= count {
+            files.sort();
+            let newest = std::fs::read_to_string(files.last().unwrap()).unwrap();
+            return serde_json::from_str(&newest).unwrap();
+        }
+        assert!(
+            Instant::now() < deadline,
+            "no state dump in {}",
+            dumps.display()
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_dump_state() {
+    let (mut pilot, server, dumps) = start("dump").await;
+    login_pilot(&server, &mut pilot, "CCA1501").await;
+
+    let output = admin(&server, &["clients", "dump"]).await;
+    assert!(output.status.success(), "{:?}", output);
+    let dump = wait_for_dump(&dumps, 1).await;
+    let pilot = dump["clients"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|client| client["callsign"] == "CCA1501")
+        .expect("pilot missing from the dump");
+    assert_eq!(pilot["network_id"], TEST_CID);
+    assert_eq!(pilot["state"], "active");
+    assert!(pilot["addr"].as_str().unwrap().starts_with("0.0.0.0:"));
+    assert!(dump["callsigns"]["CCA1501"].is_string());
+    assert!(!dump.to_string().contains(TEST_PASSWORD));
+
+    server.shutdown().await;
+    let _ = std::fs::remove_dir_all(&dumps);
+}
+
+/// A port nothing listens on
+async fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// Signals are handled in main, so this runs the server binary
+#[cfg(unix)]
+#[tokio::test]
+async fn test_sigusr1_dumps_state() {
+    let port = free_port().await;
+    let control = format!("127.0.0.1:{}", free_port().await);
+    let dir = std::env::temp_dir().join(format!("openfsd-control-signal-{}", port));
+    let dumps = dir.join("dumps");
+    let config = dir.join("openfsd.toml");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        &config,
+        format!(
+            "[server]\naddress = \"127.0.0.1\"\nport = {}\nname = \"OpenFSD\"\n\
+             version = \"test\"\nmax_clients = 10\n\n\
+             [whitelist]\nenforce = false\n\n\
+             [heartbeat]\nenabled = false\n\n\
+             [control]\nenabled = true\naddress = \"{}\"\nsecret = \"{}\"\n\n\
+             [dump]\ndirectory = \"{}\"\n",
+            port,
+            control,
+            SECRET,
+            dumps.display()
+        ),
+    )
+    .unwrap();
+
+    let server = Command::new(env!("CARGO_BIN_EXE_openfsd"))
+        .arg("--config")
+        .arg(&config)
+        .arg("--ephemeral")
+        .env("OPENFSD_BOOTSTRAP_CID", TEST_CID)
+        .env("OPENFSD_BOOTSTRAP_PASSWORD", TEST_PASSWORD)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("start openfsd");
+
+    // Migrations run before the listeners open
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let mut pilot = loop {
+        if let Ok(client) = FsdClient::connect(("127.0.0.1", port)).await {
+            break client;
+        }
+        assert!(Instant::now() < deadline, "server did not start");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+    pilot
+        .login_pilot(&Login::new("CCA1501", TEST_CID, TEST_PASSWORD))
+        .await
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let output = admin_at(&control, &["clients", "list", "--json"]).await;
+        if String::from_utf8_lossy(&output.stdout).contains("CCA1501") {
+            break;
+        }
+        assert!(Instant::now() < deadline, "CCA1501 did not log in");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let status = Command::new("kill")
+        .args(["-USR1", &server.id().unwrap().to_string()])
+        .status()
+        .await
+        .unwrap();
+    assert!(status.success());
+    let dump = wait_for_dump(&dumps, 1).await;
+    assert!(dump["clients"].to_string().contains("CCA1501"));
+
+    drop(server);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_control_errors() {
+    let (_client, server, _dumps) = start("errors").await;
+
+    let wrong = Command::new(env!("CARGO_BIN_EXE_openfsd-admin"))
+        .args([
+            "clients",
+            "list",
+            "--control",
+            &server.config.control.address,
+        ])
+        .args(["--secret", "guess"])
+        .output()
+        .await
+        .unwrap();
+    assert_eq!(wrong.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&wrong.stderr).contains("refused the control secret"));
+
+    // Nothing listens on a port once its listener is gone
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let unreachable = closed.local_addr().unwrap().to_string();
+    drop(closed);
+    let down = Command::new(env!("CARGO_BIN_EXE_openfsd-admin"))
+        .args(["clients", "list", "--control", &unreachable])
+        .args(["--secret", SECRET])
+        .output()
+        .await
+        .unwrap();
+    assert_eq!(down.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&down.stderr);
+    assert!(
+        stderr.contains("Cannot reach the control socket"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("[control] enabled"), "{}", stderr);
+
+    server.shutdown().await;
+}
@@ -0,0 +1,494 @@
+//! End-to-end sessions against an in-process server, driven by FsdClient
+
+mod common;
+
+use common::{spawn_test_server, spawn_test_server_with, test_login, TEST_CID};
+use openfsd::config::{RuntimeConfig, TextFilterAction};
+use openfsd::db::service::{self, SessionFilter};
+use openfsd::fsd_client::{FsdClient, FsdClientError, Position};
+use openfsd::packet::Packet;
+use std::time::{Duration, Instant};
+
+/// The next packet the test cares about, skipping everything else
+async fn next_matching(client: &mut FsdClient, matches: impl Fn(&Packet) -> bool) -> Packet {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let packet = client.next_packet().await.expect("connection closed");
+            if matches(&packet) {
+                return packet;
+            }
+        }
+    })
+    .await
+    .expect("packet did not arrive")
+}
+
+#[tokio::test]
+async fn test_pilot_login_handshake() {
+    let (addr, server, db) = spawn_test_server().await;
+    let mut client = FsdClient::connect(addr).await.unwrap();
+
+    // The server identifies itself first
+    let identification = client.next_packet().await.unwrap();
+    assert_eq!(identification.command, "DI");
+
+    // Logging in answers the auth challenge for the whitelisted client,
+    // and the server then tells the pilot its address
+    client.login_pilot(&test_login("DLH123")).await.unwrap();
+    let address = next_matching(&mut client, |p| {
+        p.command == "CR" && p.data.first().is_some_and(|d| d == "IP")
+    })
+    .await;
+    assert_eq!(address.destination, "DLH123");
+    assert_eq!(address.data[1], "127.0.0.1");
+
+    // The login is recorded in the background, shortly after
+    let filter = SessionFilter {
+        network_id: Some(TEST_CID.to_string()),
+        ..SessionFilter::default()
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let sessions = loop {
+        let sessions = service::list_sessions(&db, &filter, 0, 10).await.unwrap();
+        if !sessions.items.is_empty() || Instant::now() > deadline {
+            break sessions;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert_eq!(sessions.items.len(), 1);
+    assert_eq!(sessions.items[0].callsign, "DLH123");
+    assert_eq!(sessions.items[0].client_type, "pilot");
+
+    drop(client);
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_text_message_routing() {
+    let (addr, server, _db) = spawn_test_server().await;
+    let mut sender = FsdClient::connect(addr).await.unwrap();
+    let mut receiver = FsdClient::connect(addr).await.unwrap();
+    let mut bystander = FsdClient::connect(addr).await.unwrap();
+    sender.login_pilot(&test_login("DLH123")).await.unwrap();
+    receiver.login_pilot(&test_login("CCA456")).await.unwrap();
+    bystander.login_pilot(&test_login("BAW789")).await.unwrap();
+
+    sender.send_text("CCA456", "Private").await.unwrap();
+    sender.send_text("*", "Everyone").await.unwrap();
+
+    let private = next_matching(&mut receiver, |p| p.command == "TM" && p.source == "DLH123").await;
+    assert_eq!(private.destination, "CCA456");
+    assert_eq!(private.data, ["Private"]);
+    let broadcast =
+        next_matching(&mut receiver, |p| p.command == "TM" && p.source == "DLH123").await;
+    assert_eq!(broadcast.data, ["Everyone"]);
+
+    // Clients pick out what is addressed to them, so the bystander sees the
+    // broadcast go by
+    let seen = next_matching(&mut bystander, |p| {
+        p.command == "TM" && p.source == "DLH123" && p.destination == "*"
+    })
+    .await;
+    assert_eq!(seen.data, ["Everyone"]);
+
+    drop((sender, receiver, bystander));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_unlisted_client_is_rejected() {
+    let (addr, server, _db) = spawn_test_server().await;
+    let mut client = FsdClient::connect(addr).await.unwrap();
+
+    let login = test_login("DLH123").with_client("ffff", None);
+    match client.login_pilot(&login).await {
+        Err(FsdClientError::Refused { code, .. }) => assert_eq!(code, "016"),
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_smallest_queues_still_log_in() {
+    let (addr, server, _db) = spawn_test_server_with(|config| {
+        config.runtime.packet_queue = 1;
+        config.runtime.broadcast_capacity = RuntimeConfig::MIN_BROADCAST_CAPACITY;
+    })
+    .await;
+
+    // Readers take turns for the one slot, so both logins wait on each
+    // other's packets but neither gets stuck
+    let mut first = FsdClient::connect(addr).await.unwrap();
+    let mut second = FsdClient::connect(addr).await.unwrap();
+    let (dlh, cca) = (test_login("DLH123"), test_login("CCA456"));
+    let (first_login, second_login) = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::join!(first.login_pilot(&dlh), second.login_pilot(&cca),)
+    })
+    .await
+    .expect("logins deadlocked");
+    first_login.unwrap();
+    second_login.unwrap();
+
+    first.send_text("CCA456", "Through").await.unwrap();
+    let message = next_matching(&mut second, |p| p.command == "TM" && p.source == "DLH123").await;
+    assert_eq!(message.data, ["Through"]);
+
+    drop((first, second));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_client_memory_cap_holds() {
+    const MAX_CLIENT_BYTES: usize = 4096;
+    const MAX_FIELD_BYTES: usize = 200;
+    const SECRET: &str = "memory-test-secret";
+    let (addr, server, _db) = spawn_test_server_with(|config| {
+        config.limits.packets_per_second = 1000;
+        config.limits.burst = 1000;
+        config.limits.max_field_bytes = MAX_FIELD_BYTES;
+        config.limits.max_atis_lines = 100;
+        config.limits.max_client_bytes = MAX_CLIENT_BYTES;
+        config.control.enabled = true;
+        config.control.secret = Some(SECRET.to_string());
+    })
+    .await;
+    let mut client = FsdClient::connect(addr).await.unwrap();
+    let mut login = test_login("ZSPD_APP");
+    login.rating = 5;
+    client.login_atc(&login).await.unwrap();
+
+    // Every ATIS line as long as allowed and then some, more of them than
+    // fit in the cap
+    let line = "A".repeat(MAX_FIELD_BYTES * 2);
+    for _ in 0..100 {
+        let upload = format!("$CRZSPD_APP:SERVER:ATIS:T:{}", line);
+        client.send(&Packet::parse(&upload).unwrap()).await.unwrap();
+    }
+    let end = Packet::parse("$CRZSPD_APP:SERVER:ATIS:E:100").unwrap();
+    client.send(&end).await.unwrap();
+
+    // A flight plan with every field at the limit no longer fits
+    let field = |c: &str| c.repeat(MAX_FIELD_BYTES);
+    let plan = format!(
+        "$FPZSPD_APP:*A:I:{a}:{n}:{a}:{n}:{n}:{a}:{a}:{n}:{n}:{n}:{n}:{a}:{a}:{a}",
+        a = field("A"),
+        n = field("9")
+    );
+    client.send(&Packet::parse(&plan).unwrap()).await.unwrap();
+    let refused = next_matching(&mut client, |p| p.command == "ER").await;
+    assert_eq!(refused.data[0], "004");
+    assert!(
+        refused.data[2..].join(":").contains("bytes stored"),
+        "{:?}",
+        refused
+    );
+
+    // Some of the ATIS was kept, cut to the field limit
+    let request = Packet::parse("$CQZSPD_APP:ZSPD_APP:ATIS").unwrap();
+    client.send(&request).await.unwrap();
+    let first = next_matching(&mut client, |p| {
+        p.command == "CR" && p.data.get(1).is_some_and(|d| d == "T")
+    })
+    .await;
+    assert_eq!(first.data[2].len(), MAX_FIELD_BYTES);
+    let end = next_matching(&mut client, |p| {
+        p.command == "CR" && p.data.get(1).is_some_and(|d| d == "E")
+    })
+    .await;
+    let lines: usize = end.data[2].parse::<usize>().unwrap() - 2;
+    assert!((1..100).contains(&lines), "{} lines kept", lines);
+
+    let clients = openfsd::server::control::list_clients(&server.config.control.address, SECRET)
+        .await
+        .unwrap();
+    assert_eq!(clients.len(), 1);
+    let memory = clients[0].memory_bytes as usize;
+    assert!(memory <= MAX_CLIENT_BYTES, "{} bytes held", memory);
+    assert!(
+        memory + MAX_FIELD_BYTES > MAX_CLIENT_BYTES,
+        "{} bytes held",
+        memory
+    );
+
+    drop(client);
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_observer_receives_position_snapshots() {
+    let (addr, server, _db) = spawn_test_server_with(|config| {
+        config.visibility.snapshot_observers = true;
+        config.visibility.snapshot_interval_ms = 100;
+    })
+    .await;
+    let mut observer = FsdClient::connect(addr).await.unwrap();
+    observer.login_atc(&test_login("ZSPD_OBS")).await.unwrap();
+    let mut first = FsdClient::connect(addr).await.unwrap();
+    let mut second = FsdClient::connect(addr).await.unwrap();
+    first.login_pilot(&test_login("DLH123")).await.unwrap();
+    second.login_pilot(&test_login("CCA456")).await.unwrap();
+
+    for altitude in [1000, 2000] {
+        first
+            .send_position(&Position::new(31.14, 121.80, altitude))
+            .await
+            .unwrap();
+        second
+            .send_position(&Position::new(40.08, 116.58, altitude + 10_000))
+            .await
+            .unwrap();
+    }
+
+    // A snapshot is written in one go, so both latest positions arrive
+    // back to back, and again with every snapshot after it although the
+    // pilots have stopped sending
+    let latest = |packet: &Packet| match packet.destination.as_str() {
+        "DLH123" => packet.data[4] == "2000",
+        "CCA456" => packet.data[4] == "12000",
+        _ => false,
+    };
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let mut previous: Option<Packet> = None;
+        let mut snapshots = 0;
+        loop {
+            let packet = observer.next_packet().await.expect("connection closed");
+            if packet.command != "N" {
+                previous = None;
+                continue;
+            }
+            if let Some(previous) = previous.as_ref().filter(|previous| latest(previous)) {
+                if latest(&packet) && packet.destination != previous.destination {
+                    snapshots += 1;
+                    if snapshots == 2 {
+                        return;
+                    }
+                }
+            }
+            previous = Some(packet);
+        }
+    })
+    .await
+    .expect("no snapshot with both latest positions");
+
+    drop((observer, first, second));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_chaos_keeps_logins_through() {
+    let (addr, server, _db) = spawn_test_server_with(|config| {
+        config.chaos.enabled = true;
+        config.chaos.acknowledge_unsafe = true;
+        config.chaos.delay_ms = 20;
+        config.chaos.drop_rate = 1.0;
+        config.chaos.callsigns = vec!["CCA456".to_string()];
+    })
+    .await;
+    let mut sender = FsdClient::connect(addr).await.unwrap();
+    let mut receiver = FsdClient::connect(addr).await.unwrap();
+    let mut bystander = FsdClient::connect(addr).await.unwrap();
+
+    // Every packet that can be dropped is, yet the login goes through
+    sender.login_pilot(&test_login("DLH123")).await.unwrap();
+    receiver.login_pilot(&test_login("CCA456")).await.unwrap();
+    bystander.login_pilot(&test_login("BAW789")).await.unwrap();
+
+    // Only the receiver is affected, so the bystander gets the message
+    sender.send_text("*", "Lost").await.unwrap();
+    let seen = next_matching(&mut bystander, |p| {
+        p.command == "TM" && p.source == "DLH123"
+    })
+    .await;
+    assert_eq!(seen.data, ["Lost"]);
+
+    // The receiver still hears of the logoff, but not the message before it
+    sender.logoff().await.unwrap();
+    let logoff = next_matching(&mut receiver, |p| p.command == "DP" || p.command == "TM").await;
+    assert_eq!(logoff.command, "DP");
+    assert_eq!(logoff.source, "DLH123");
+
+    drop((sender, receiver, bystander));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_seeded_server_repeats_tokens() {
+    const SEED: u64 = 42;
+    const OTHER_SEED: u64 = 43;
+
+    /// The token in the greeting of a seeded server's first connection
+    async fn first_token(seed: u64) -> String {
+        let (addr, server, _db) =
+            spawn_test_server_with(|config| config.runtime.random_seed = Some(seed)).await;
+        let mut client = FsdClient::connect(addr).await.unwrap();
+        let identification = client.next_packet().await.unwrap();
+        assert_eq!(identification.command, "DI");
+        drop(client);
+        server.shutdown().await;
+        identification.data[1].clone()
+    }
+
+    let token = first_token(SEED).await;
+    assert_eq!(token.len(), 22);
+    assert!(token
+        .bytes()
+        .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase()));
+    assert_eq!(first_token(SEED).await, token);
+    assert_ne!(first_token(OTHER_SEED).await, token);
+}
+
+#[tokio::test]
+async fn test_text_filter_rejects_messages() {
+    let (addr, server, _db) = spawn_test_server_with(|config| {
+        config.middleware.text_filter.patterns = vec![r"(?i)\bdarn\b".to_string()];
+        config.middleware.text_filter.action = TextFilterAction::Reject;
+    })
+    .await;
+    let mut sender = FsdClient::connect(addr).await.unwrap();
+    let mut receiver = FsdClient::connect(addr).await.unwrap();
+    sender.login_pilot(&test_login("DLH123")).await.unwrap();
+    receiver.login_pilot(&test_login("CCA456")).await.unwrap();
+
+    sender.send_text("CCA456", "Darn it").await.unwrap();
+    let error = next_matching(&mut sender, |p| p.command == "ER" && p.data[0] != "008").await;
+    assert_eq!(error.destination, "DLH123");
+    assert_eq!(error.data[0], "004");
+
+    // Only the filtered message is held back
+    sender.send_text("CCA456", "Fine").await.unwrap();
+    let seen = next_matching(&mut receiver, |p| p.command == "TM" && p.source == "DLH123").await;
+    assert_eq!(seen.data, ["Fine"]);
+
+    drop((sender, receiver));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_server_packets_use_configured_callsign() {
+    let (addr, server, _db) = spawn_test_server_with(|config| {
+        config.server.server_callsign = Some("OPENFSD".to_string());
+        config.server.ident_banner = Some("OpenFSD Training".to_string());
+    })
+    .await;
+    let mut client = FsdClient::connect(addr).await.unwrap();
+
+    let identification = client.next_packet().await.unwrap();
+    assert_eq!(identification.command, "DI");
+    assert_eq!(identification.destination, "OPENFSD");
+    assert_eq!(identification.data[0], "OpenFSD Training");
+
+    // The login waits for the CAPS request from the server's callsign;
+    // everything after comes from it as well up to the reply: the
+    // challenge, the MOTD, the IP request and the flight plan notice
+    client.login_pilot(&test_login("DLH123")).await.unwrap();
+    client.send_text("SERVER", ".bogus").await.unwrap();
+    let mut commands = Vec::new();
+    loop {
+        let packet = tokio::time::timeout(Duration::from_secs(10), client.next_packet())
+            .await
+            .expect("no reply")
+            .expect("connection closed");
+        assert_eq!(packet.source, "OPENFSD", "{}", packet.format().trim_end());
+        commands.push(packet.command.clone());
+        if packet.command == "TM" && packet.data[0].starts_with("Unknown command") {
+            break;
+        }
+    }
+    for command in ["ZC", "CR", "ER"] {
+        assert!(commands.iter().any(|c| c == command), "no {}", command);
+    }
+
+    drop(client);
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_optional_traffic_only_reaches_capable_clients() {
+    let (addr, server, _db) = spawn_test_server().await;
+    let mut sender = FsdClient::connect(addr).await.unwrap();
+    let mut modern = FsdClient::connect(addr).await.unwrap();
+    let mut legacy = FsdClient::connect(addr).await.unwrap();
+    sender.login_pilot(&test_login("BAW789")).await.unwrap();
+    // FsdClient answers CAPS with ACCONFIG and MODELDESC; the legacy client
+    // follows up with an answer that lists nothing
+    modern.login_pilot(&test_login("DLH123")).await.unwrap();
+    legacy.login_pilot(&test_login("CCA456")).await.unwrap();
+    legacy
+        .send(&Packet::parse("$CRCCA456:SERVER:CAPS").unwrap())
+        .await
+        .unwrap();
+
+    // Both answers are in once their next messages arrive
+    modern.send_text("BAW789", "Ready").await.unwrap();
+    legacy.send_text("BAW789", "Ready").await.unwrap();
+    for callsign in ["DLH123", "CCA456"] {
+        next_matching(&mut sender, |p| p.command == "TM" && p.source == callsign).await;
+    }
+
+    let config = r#"$CQBAW789:@94836:ACC:{"config":{"lights":{"strobe_on":true}}}"#;
+    sender.send(&Packet::parse(config).unwrap()).await.unwrap();
+    for destination in ["DLH123", "CCA456"] {
+        let line = format!("#SBBAW789:{}:PI:GEN:EQUIPMENT=B738", destination);
+        sender.send(&Packet::parse(&line).unwrap()).await.unwrap();
+    }
+    sender.send_text("*", "Done").await.unwrap();
+
+    // Skip what the new clients learn of the sender's login
+    let from_sender =
+        |p: &Packet| p.source == "BAW789" && ["CQ", "SB", "TM"].contains(&p.command.as_str());
+    let acc = next_matching(&mut modern, from_sender).await;
+    assert_eq!(acc.command, "CQ");
+    assert_eq!(acc.data[0], "ACC");
+    let model = next_matching(&mut modern, from_sender).await;
+    assert_eq!(model.command, "SB");
+    assert_eq!(model.destination, "DLH123");
+    assert_eq!(next_matching(&mut modern, from_sender).await.command, "TM");
+
+    // The legacy client gets nothing but the text message
+    let first = next_matching(&mut legacy, from_sender).await;
+    assert_eq!(first.command, "TM");
+    assert_eq!(first.data, ["Done"]);
+
+    drop((sender, modern, legacy));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_stats_query_counts_positions() {
+    let (addr, server, _db) = spawn_test_server().await;
+    let mut client = FsdClient::connect(addr).await.unwrap();
+    client.login_pilot(&test_login("DLH123")).await.unwrap();
+    for altitude in (1000..=5000).step_by(1000) {
+        client
+            .send_position(&Position::new(31.14, 121.80, altitude))
+            .await
+            .unwrap();
+    }
+
+    client
+        .send(&Packet::parse("$CQDLH123:SERVER:STATS").unwrap())
+        .await
+        .unwrap();
+    let reply = |packet: &Packet| {
+        packet.command == "TM" && packet.source == "SERVER" && packet.destination == "DLH123"
+    };
+    // Past the MOTD, the first line is the one with the callsign
+    let connected = next_matching(&mut client, |packet| {
+        reply(packet) && packet.data[0].starts_with("DLH123 connected 0m")
+    })
+    .await;
+    assert!(connected.data[0].ends_with(", ping not measured yet"));
+    next_matching(&mut client, reply).await;
+    let positions = next_matching(&mut client, reply).await;
+    assert!(
+        positions.data[0].starts_with("Position updates 5, one every "),
+        "{}",
+        positions.data[0]
+    );
+    let dropped = next_matching(&mut client, reply).await;
+    assert_eq!(dropped.data, ["Messages dropped 0"]);
+
+    drop(client);
+    server.shutdown().await;
+}
@@ -0,0 +1,165 @@
+//! Drives a real server with the FsdClient library: logins with an auth
+//! challenge, typed requests and messages between two clients
+
+mod common;
+
+use common::{spawn_test_server_with, test_login, ServerHandle};
+use openfsd::auth::password::hash_password;
+use openfsd::config::WeatherProvider;
+use openfsd::db::service;
+use openfsd::flight_plan::FlightPlan;
+use openfsd::fsd_client::{FsdClient, FsdClientError, Login, Position};
+use openfsd::packet::Packet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// A second account, besides the harness's test user
+const OTHER_CID: &str = "7654321";
+const OTHER_PASSWORD: &str = "Battery-Staple-7";
+const METAR: &str = "ZBAA 121200Z 36004MPS CAVOK 22/08 Q1012 NOSIG";
+
+/// A server with a second account and static weather
+struct TestServer {
+    addr: SocketAddr,
+    weather: PathBuf,
+    _handle: ServerHandle,
+}
+
+impl TestServer {
+    async fn start(name: &str) -> Self {
+        let weather =
+            std::env::temp_dir().join(format!("openfsd-lib-{}-{}.metar", name, std::process::id()));
+        std::fs::write(&weather, format!("{}\n", METAR)).unwrap();
+        let (addr, handle, db) = spawn_test_server_with(|config| {
+            config.weather.provider = WeatherProvider::Static;
+            config.weather.static_file = Some(weather.clone());
+        })
+        .await;
+        service::create_user(
+            &db,
+            OTHER_CID.to_string(),
+            hash_password(OTHER_PASSWORD).unwrap(),
+            "Library Pilot".to_string(),
+            1,
+            1,
+        )
+        .await
+        .unwrap();
+        TestServer {
+            addr,
+            weather,
+            _handle: handle,
+        }
+    }
+
+    async fn connect(&self) -> FsdClient {
+        FsdClient::connect(self.addr).await.unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.weather);
+    }
+}
+
+/// A login as the test user, or with `index` 1 as the second account
+fn pilot(index: usize, callsign: &str) -> Login {
+    match index {
+        0 => test_login(callsign),
+        _ => {
+            let mut login = test_login(callsign);
+            login.cid = OTHER_CID.to_string();
+            login.password = OTHER_PASSWORD.to_string();
+            login
+        }
+    }
+}
+
+/// The next packet the test cares about, skipping everything else
+async fn next_matching(client: &mut FsdClient, matches: impl Fn(&Packet) -> bool) -> Packet {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let packet = client.next_packet().await.expect("connection closed");
+            if matches(&packet) {
+                return packet;
+            }
+        }
+    })
+    .await
+    .expect("packet did not arrive")
+}
+
+#[tokio::test]
+async fn test_pilot_session() {
+    let server = TestServer::start("session").await;
+    let mut client = server.connect().await;
+
+    // The server challenges the whitelisted client software, and a wrong
+    // answer would get the login refused
+    client.login_pilot(&pilot(0, "DLH123")).await.unwrap();
+    assert_eq!(client.callsign().as_deref(), Some("DLH123"));
+
+    assert_eq!(client.request_metar("ZBAA").await.unwrap(), METAR);
+    match client.request_metar("XXXX").await {
+        Err(FsdClientError::Refused { code, .. }) => assert_eq!(code, "009"),
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+
+    client
+        .send_position(&Position::new(40.08, 116.58, 5000))
+        .await
+        .unwrap();
+    let plan = FlightPlan {
+        flight_rules: "I".to_string(),
+        aircraft: "B738".to_string(),
+        cruise_speed: "450".to_string(),
+        departure: "ZBAA".to_string(),
+        departure_time: "1200".to_string(),
+        actual_departure_time: "1200".to_string(),
+        altitude: "FL350".to_string(),
+        destination: "ZSPD".to_string(),
+        hours_enroute: "2".to_string(),
+        minutes_enroute: "0".to_string(),
+        hours_fuel: "4".to_string(),
+        minutes_fuel: "0".to_string(),
+        route: "DCT".to_string(),
+        ..FlightPlan::default()
+    };
+    client.file_flight_plan(&plan).await.unwrap();
+    client.logoff().await.unwrap();
+    assert_eq!(client.callsign(), None);
+}
+
+#[tokio::test]
+async fn test_text_between_clients() {
+    let server = TestServer::start("text").await;
+    let mut sender = server.connect().await;
+    let mut receiver = server.connect().await;
+    sender.login_pilot(&pilot(0, "DLH123")).await.unwrap();
+    receiver.login_pilot(&pilot(1, "CCA456")).await.unwrap();
+
+    sender.send_text("CCA456", "Hello there").await.unwrap();
+    let message = next_matching(&mut receiver, |p| p.command == "TM" && p.source == "DLH123").await;
+    assert_eq!(message.destination, "CCA456");
+    assert_eq!(message.data, ["Hello there"]);
+}
+
+#[tokio::test]
+async fn test_wrong_password_is_refused() {
+    let server = TestServer::start("refused").await;
+    let mut client = server.connect().await;
+
+    let mut login = pilot(0, "DLH123");
+    login.password = "wrong".to_string();
+    match client.login_pilot(&login).await {
+        Err(FsdClientError::Refused { .. }) => {}
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+    assert_eq!(client.callsign(), None);
+    assert!(matches!(
+        client.send_text("*", "Hello").await,
+        Err(FsdClientError::NotLoggedIn)
+    ));
+}
@@ -0,0 +1,78 @@
+//! End-to-end test of the parse error budget: a running server warns a
+//! client sending garbage and then drops it, while blank keepalive lines
+//! never count against the budget
+
+mod common;
+
+use common::spawn_test_server;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+/// Connect and read past the server identification
+async fn connect(addr: SocketAddr) -> (OwnedWriteHalf, BufReader<OwnedReadHalf>) {
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut reader = BufReader::new(reader);
+    assert!(read_line(&mut reader)
+        .await
+        .unwrap()
+        .starts_with("$DISERVER"));
+    (writer, reader)
+}
+
+/// The next line from the server, or `None` once it has closed the connection
+async fn read_line(reader: &mut BufReader<OwnedReadHalf>) -> Option<String> {
+    let mut line = String::new();
+    let read = tokio::time::timeout(Duration::from_secs(10), reader.read_line(&mut line))
+        .await
+        .expect("no line from the server");
+    match read {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line),
+    }
+}
+
+#[tokio::test]
+async fn test_garbage_is_answered_then_dropped() {
+    let (addr, server, _db) = spawn_test_server().await;
+    let (mut writer, mut reader) = connect(addr).await;
+
+    // Well over the default rate of five a second
+    for _ in 0..10 {
+        writer.write_all(b"this is not a packet\r\n").await.unwrap();
+    }
+
+    let mut replies = Vec::new();
+    while let Some(line) = read_line(&mut reader).await {
+        replies.push(line);
+    }
+    assert_eq!(replies.len(), 3, "{:?}", replies);
+    assert!(
+        replies
+            .iter()
+            .all(|line| line.starts_with("$ERSERVER:unknown:004::")),
+        "{:?}",
+        replies
+    );
+
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_blank_lines_are_not_parse_errors() {
+    let (addr, server, _db) = spawn_test_server().await;
+    let (mut writer, mut reader) = connect(addr).await;
+
+    for _ in 0..30 {
+        writer.write_all(b"\r\n").await.unwrap();
+    }
+    // Still connected, and this is the first parse error
+    writer.write_all(b"garbage\r\n").await.unwrap();
+    let reply = read_line(&mut reader).await.expect("connection closed");
+    assert!(reply.starts_with("$ERSERVER:unknown:004::"), "{}", reply);
+
+    drop(writer);
+    server.shutdown().await;
+}
@@ -0,0 +1,104 @@
+//! End-to-end test of the Prometheus endpoint: logins against a running
+//! server, then a scrape that has to show them
+#![cfg(feature = "prometheus")]
+
+mod common;
+
+use common::{spawn_test_server_with, test_login, ServerHandle};
+use openfsd::fsd_client::{FsdClient, FsdClientError};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// A server with the metrics endpoint on a free port, and the endpoint's
+/// address
+///
+/// The exporter binds the endpoint itself, so a port that was free a moment
+/// ago may be taken by the time it does; the server then fails to start and
+/// another port is tried.
+async fn start() -> (SocketAddr, ServerHandle, String) {
+    for _ in 0..10 {
+        let metrics = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .to_string();
+        let (addr, server, _db) = spawn_test_server_with(|config| {
+            config.metrics.enabled = true;
+            config.metrics.address = metrics.clone();
+        })
+        .await;
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(&metrics).await.is_err() && !server.has_stopped() {
+            assert!(Instant::now() < deadline, "metrics endpoint did not open");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        if !server.has_stopped() {
+            return (addr, server, metrics);
+        }
+    }
+    panic!("no free port for the metrics endpoint");
+}
+
+async fn scrape(metrics: &str) -> String {
+    let mut stream = TcpStream::connect(metrics).await.unwrap();
+    let request = format!(
+        "GET /metrics HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        metrics
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    response
+}
+
+/// Scrape until `line` shows up; gauges are only sampled periodically
+async fn wait_for(metrics: &str, line: &str) -> String {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let body = scrape(metrics).await;
+        if body.lines().any(|l| l == line) {
+            return body;
+        }
+        assert!(Instant::now() < deadline, "no \"{}\" in\n{}", line, body);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_scrape_after_login() {
+    let (addr, server, metrics) = start().await;
+
+    let mut pilot = FsdClient::connect(addr).await.unwrap();
+    pilot.login_pilot(&test_login("CCA1501")).await.unwrap();
+    let mut refused = FsdClient::connect(addr).await.unwrap();
+    let mut login = test_login("CCA1502");
+    login.password = "wrong".to_string();
+    assert!(matches!(
+        refused.login_pilot(&login).await,
+        Err(FsdClientError::Refused { .. })
+    ));
+
+    let body = wait_for(&metrics, "openfsd_clients{type=\"pilot\"} 1").await;
+    for line in [
+        "openfsd_logins_total{result=\"success\"} 1",
+        "openfsd_logins_total{result=\"failure\"} 1",
+        "openfsd_packets_received_total{command=\"AP\"} 2",
+        "openfsd_packets_received_total{command=\"ID\"} 2",
+    ] {
+        assert!(
+            body.lines().any(|l| l == line),
+            "no \"{}\" in\n{}",
+            line,
+            body
+        );
+    }
+    assert!(body.contains("openfsd_packets_sent_total{command=\"TM\"}"));
+    assert!(body.contains("openfsd_handler_duration_seconds_bucket{command=\"AP\""));
+    assert!(body.contains("# TYPE openfsd_received_bytes_total counter"));
+
+    drop((pilot, refused));
+    server.shutdown().await;
+}
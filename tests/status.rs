@@ -0,0 +1,474 @@
+//! End-to-end tests of the status listener: a pilot logs in and files a
+//! plan, then the feeds and the REST API have to list it
+#![cfg(feature = "http")]
+
+mod common;
+
+use common::{spawn_test_server_with, ServerHandle, TEST_CID, TEST_PASSWORD};
+use openfsd::config::Config;
+use openfsd::datafeed::DataFeed;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+const TOKEN: &str = "api-t0ken";
+
+/// A server with the status listener enabled
+struct TestServer {
+    addr: SocketAddr,
+    status: String,
+    _handle: ServerHandle,
+}
+
+impl TestServer {
+    /// Start with `configure` adjusting the settings
+    async fn start(configure: impl FnOnce(&mut Config)) -> Self {
+        let (addr, handle, _db) = spawn_test_server_with(|config| {
+            config.server.name = "OpenFSD".to_string();
+            config.server.version = "test".to_string();
+            config.server.hostname = Some("fsd.example.com".to_string());
+            config.whitelist.enforce = false;
+            config.status.enabled = true;
+            config.status.cache_secs = 0;
+            configure(config);
+        })
+        .await;
+        TestServer {
+            addr,
+            status: handle.config.status.address.clone(),
+            _handle: handle,
+        }
+    }
+
+    /// Start with the API enabled
+    async fn with_api() -> Self {
+        Self::start(|config| {
+            config.api.enabled = true;
+            config.api.token = Some(TOKEN.to_string());
+        })
+        .await
+    }
+
+    /// Log in a pilot and return the connection once the server has
+    /// accepted it
+    async fn login(&self, callsign: &str) -> BufReader<TcpStream> {
+        self.login_as(callsign, TEST_CID, TEST_PASSWORD).await
+    }
+
+    /// Log in a pilot with the given account
+    async fn login_as(&self, callsign: &str, cid: &str, password: &str) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(self.addr).await.unwrap();
+        let login = format!(
+            "$ID{cs}:SERVER:b0b0:Test Client 1.0:3:2:{cid}:12345\r\n\
+             #AP{cs}:SERVER:{cid}:{pw}:1:100:1:Test Pilot\r\n",
+            cs = callsign,
+            cid = cid,
+            pw = password
+        );
+        stream.write_all(login.as_bytes()).await.unwrap();
+
+        let mut reader = BufReader::new(stream);
+        read_until(&mut reader, "$CQSERVER").await;
+        reader
+    }
+
+    /// Log in a pilot, report a position and file a plan
+    async fn fly(&self, callsign: &str) -> BufReader<TcpStream> {
+        let mut reader = self.login(callsign).await;
+        let flight = format!(
+            "@N:{cs}:2200:1:40.08:116.58:35000:452:4261412864:0\r\n\
+             $FP{cs}:*A:I:H/B744/L:490:ZBAA:1130:1135:FL350:ZSPD:1:55:3:30:ZSHC::CDY W40 DOGAR\r\n",
+            cs = callsign
+        );
+        reader.get_mut().write_all(flight.as_bytes()).await.unwrap();
+        reader
+    }
+
+    /// GET a path that has to succeed and return the response head and body
+    async fn get(&self, path: &str) -> (String, String) {
+        let (status, head, body) = self.request(path, None).await;
+        assert_eq!(status, 200, "{}\n{}", head, body);
+        (head, body)
+    }
+
+    /// GET a path, with a bearer token if given, and return the status code,
+    /// the head and the body
+    async fn request(&self, path: &str, token: Option<&str>) -> (u16, String, String) {
+        self.send("GET", path, token, None).await
+    }
+
+    /// Send a request with an optional bearer token and JSON body
+    async fn send(
+        &self,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: Option<&Value>,
+    ) -> (u16, String, String) {
+        let mut stream = TcpStream::connect(&self.status).await.unwrap();
+        let mut headers = String::new();
+        if let Some(token) = token {
+            headers.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        let body = body.map(Value::to_string).unwrap_or_default();
+        if !body.is_empty() {
+            headers.push_str("Content-Type: application/json\r\n");
+        }
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            self.status,
+            headers,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head[9..12].parse().unwrap();
+        (status, head.to_string(), body.to_string())
+    }
+
+    /// GET an API path with the token and parse the JSON answer
+    async fn api(&self, path: &str) -> (u16, Value) {
+        let (status, _, body) = self.request(path, Some(TOKEN)).await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    /// POST a prefile with the token and parse the JSON answer
+    async fn prefile(&self, prefile: &Value) -> (u16, Value) {
+        let (status, _, body) = self
+            .send("POST", "/api/prefile", Some(TOKEN), Some(prefile))
+            .await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    /// POST a registration, authorized by the invite code in its body or
+    /// by `token`
+    async fn register(&self, registration: &Value, token: Option<&str>) -> (u16, Value) {
+        let (status, _, body) = self
+            .send("POST", "/api/register", token, Some(registration))
+            .await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    /// Fetch the datafeed until `ready` holds; the server handles the
+    /// pilot's packets on its own schedule
+    async fn wait_for_datafeed(&self, ready: impl Fn(&DataFeed) -> bool) -> (String, DataFeed) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let (head, body) = self.get("/data/v3/openfsd-data.json").await;
+            let feed: DataFeed = serde_json::from_str(&body).unwrap();
+            if ready(&feed) {
+                return (head, feed);
+            }
+            assert!(Instant::now() < deadline, "feed never ready:\n{}", body);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// Read lines until one starts with `prefix` and return it
+async fn read_until(reader: &mut BufReader<TcpStream>, prefix: &str) -> String {
+    let mut line = String::new();
+    while !line.starts_with(prefix) {
+        line.clear();
+        let read = tokio::time::timeout(Duration::from_secs(10), reader.read_line(&mut line))
+            .await
+            .unwrap_or_else(|_| panic!("no {} in time", prefix))
+            .unwrap();
+        assert!(read > 0, "connection closed before {}", prefix);
+    }
+    line
+}
+
+#[tokio::test]
+async fn test_feeds_list_a_flying_pilot() {
+    let server = TestServer::start(|_| {}).await;
+
+    let (head, feed) = server
+        .wait_for_datafeed(|feed| feed.pilots.is_empty())
+        .await;
+    assert!(
+        head.to_lowercase()
+            .contains("content-type: application/json"),
+        "{}",
+        head
+    );
+    assert_eq!(feed.general.connected_clients, 0);
+    assert_eq!(feed.servers[0].hostname_or_ip, "fsd.example.com");
+
+    let _pilot = server.fly("CCA1501").await;
+    let (_, feed) = server
+        .wait_for_datafeed(|feed| {
+            feed.pilots
+                .first()
+                .is_some_and(|pilot| pilot.flight_plan.is_some() && pilot.groundspeed > 0)
+        })
+        .await;
+    let pilot = &feed.pilots[0];
+    assert_eq!(pilot.callsign, "CCA1501");
+    assert_eq!(pilot.cid, 1234567);
+    assert_eq!(pilot.transponder, "2200");
+    let plan = pilot.flight_plan.as_ref().unwrap();
+    assert_eq!(
+        (plan.departure.as_str(), plan.arrival.as_str()),
+        ("ZBAA", "ZSPD")
+    );
+    assert_eq!(plan.aircraft_short, "B744");
+
+    let (head, body) = server.get("/whazzup.txt").await;
+    assert!(
+        head.to_lowercase().contains("content-type: text/plain"),
+        "{}",
+        head
+    );
+    assert!(
+        body.lines()
+            .any(|line| line.starts_with("CCA1501:1234567:")),
+        "{}",
+        body
+    );
+}
+
+#[tokio::test]
+async fn test_health_probes() {
+    let server = TestServer::start(|_| {}).await;
+
+    let (_, body) = server.get("/healthz").await;
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap()["status"],
+        "ok"
+    );
+    let (_, body) = server.get("/readyz").await;
+    let readiness: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(readiness["ready"], true, "{}", body);
+}
+
+#[tokio::test]
+async fn test_api() {
+    let server = TestServer::with_api().await;
+    let _pilot = server.fly("CCA1501").await;
+    let _ = server
+        .wait_for_datafeed(|feed| {
+            feed.pilots
+                .first()
+                .is_some_and(|pilot| pilot.flight_plan.is_some())
+        })
+        .await;
+
+    for path in ["/api/server", "/api/clients", "/api/clients/CCA1501"] {
+        let (status, head, _) = server.request(path, None).await;
+        assert_eq!(status, 401, "{}", path);
+        assert!(
+            head.to_lowercase().contains("www-authenticate: bearer"),
+            "{}",
+            head
+        );
+        let (status, _, _) = server.request(path, Some("wrong")).await;
+        assert_eq!(status, 401, "{}", path);
+    }
+
+    let (status, info) = server.api("/api/server").await;
+    assert_eq!(status, 200);
+    assert_eq!(info["name"], "OpenFSD");
+    assert_eq!(info["version"], "test");
+    assert_eq!(info["clients"]["pilot"], 1);
+    assert!(info["uptime_secs"].is_u64());
+
+    let (status, clients) = server.api("/api/clients").await;
+    assert_eq!(status, 200);
+    assert_eq!(clients.as_array().unwrap().len(), 1);
+    assert_eq!(clients[0]["callsign"], "CCA1501");
+    assert_eq!(clients[0]["cid"], TEST_CID);
+    assert_eq!(clients[0]["client_software"], "Test Client 1.0");
+    assert!(clients[0]["address"]
+        .as_str()
+        .unwrap()
+        .starts_with("127.0.0.1:"));
+
+    let (status, client) = server.api("/api/clients/cca1501").await;
+    assert_eq!(status, 200);
+    assert_eq!(client["squawk"], "2200");
+    assert_eq!(client["has_flight_plan"], true);
+
+    let (status, plan) = server.api("/api/flightplans/CCA1501").await;
+    assert_eq!(status, 200);
+    assert_eq!(plan["departure"], "ZBAA");
+    assert_eq!(plan["destination"], "ZSPD");
+
+    for path in ["/api/clients/CES2200", "/api/flightplans/CES2200"] {
+        let (status, body) = server.api(path).await;
+        assert_eq!(status, 404, "{}", path);
+        assert!(body["error"].is_string());
+    }
+}
+
+#[tokio::test]
+async fn test_prefile() {
+    let server = TestServer::with_api().await;
+    let plan = json!({
+        "cid": TEST_CID,
+        "callsign": "cca1501",
+        "flight_rules": "I",
+        "aircraft": "H/B744/L",
+        "cruise_speed": "490",
+        "departure": "ZBAA",
+        "departure_time": "1130",
+        "altitude": "FL350",
+        "destination": "ZSPD",
+        "hours_enroute": "1",
+        "minutes_enroute": "55",
+        "remarks": "/V/",
+        "route": "CDY W40 DOGAR",
+        "ttl_minutes": 60
+    });
+
+    let (status, _, _) = server.send("POST", "/api/prefile", None, Some(&plan)).await;
+    assert_eq!(status, 401);
+
+    for (field, value, reason) in [
+        ("departure", json!(""), "departure is required"),
+        ("flight_rules", json!("X"), "Unknown flight rules"),
+        ("route", json!("CDY:W40"), "route must not contain colons"),
+        ("callsign", json!("CCA 1501"), "Invalid callsign"),
+        ("ttl_minutes", json!(0), "ttl_minutes must be between"),
+    ] {
+        let mut invalid = plan.clone();
+        invalid[field] = value;
+        let (status, body) = server.prefile(&invalid).await;
+        assert_eq!(status, 400, "{}", field);
+        assert!(
+            body["error"].as_str().unwrap().contains(reason),
+            "{}: {}",
+            field,
+            body
+        );
+    }
+    let (status, body) = server.prefile(&json!([])).await;
+    assert_eq!(status, 400, "{}", body);
+
+    let (status, stored) = server.prefile(&plan).await;
+    assert_eq!(status, 201, "{}", stored);
+    assert_eq!(stored["callsign"], "CCA1501");
+    assert_eq!(stored["cid"], TEST_CID);
+    assert_eq!(stored["destination"], "ZSPD");
+    let expires_at: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(stored["expires_at"].clone()).unwrap();
+    let ttl = expires_at - chrono::Utc::now();
+    assert!(ttl > chrono::Duration::minutes(59) && ttl <= chrono::Duration::minutes(60));
+
+    // The pilot gets the plan on logging in, and it becomes the live plan
+    let mut pilot = server.login("CCA1501").await;
+    let fp = read_until(&mut pilot, "$FP").await;
+    assert!(
+        fp.starts_with("$FPCCA1501:*A:I:H/B744/L:490:ZBAA:"),
+        "{}",
+        fp
+    );
+    assert!(fp.trim_end().ends_with(":/V/:CDY W40 DOGAR"), "{}", fp);
+    let (status, live) = server.api("/api/flightplans/CCA1501").await;
+    assert_eq!(status, 200);
+    assert_eq!(live["route"], "CDY W40 DOGAR");
+    assert_eq!(
+        live["route_elements"],
+        json!([
+            {"text": "CDY", "kind": "waypoint"},
+            {"text": "W40", "kind": "airway"},
+            {"text": "DOGAR", "kind": "waypoint"},
+        ])
+    );
+
+    // A prefile can be withdrawn once
+    let mut other = plan.clone();
+    other["callsign"] = json!("CES2200");
+    let (status, stored) = server.prefile(&other).await;
+    assert_eq!(status, 201);
+    let path = format!("/api/prefile/{}", stored["id"]);
+    let (status, _, _) = server.send("DELETE", &path, None, None).await;
+    assert_eq!(status, 401);
+    let (status, _, _) = server.send("DELETE", &path, Some(TOKEN), None).await;
+    assert_eq!(status, 204);
+    let (status, _, _) = server.send("DELETE", &path, Some(TOKEN), None).await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn test_api_is_off_by_default() {
+    let server = TestServer::start(|_| {}).await;
+    let (status, _, _) = server.request("/api/server", Some(TOKEN)).await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn test_register() {
+    let server = TestServer::start(|config| {
+        config.api.enabled = true;
+        config.api.token = Some(TOKEN.to_string());
+        config.api.registration = true;
+        config.api.invite_code = Some("welcome".to_string());
+        config.api.registrations_per_hour = 4;
+    })
+    .await;
+    let registration = |cid: &str, password: &str, invite_code: &str| {
+        json!({
+            "cid": cid,
+            "password": password,
+            "real_name": "New Pilot",
+            "invite_code": invite_code,
+        })
+    };
+
+    let (status, answer) = server
+        .register(&registration(TEST_CID, "long enough", "welcome"), None)
+        .await;
+    assert_eq!(status, 409, "{}", answer);
+
+    let (status, answer) = server
+        .register(&registration("2345678", "short", "welcome"), None)
+        .await;
+    assert_eq!(status, 400);
+    assert_eq!(
+        answer["error"],
+        "Password must be at least 8 characters long"
+    );
+
+    let (status, _) = server
+        .register(&registration("2345678", "long enough", "guess"), None)
+        .await;
+    assert_eq!(status, 401);
+
+    let (status, user) = server
+        .register(&registration("2345678", "long enough", "welcome"), None)
+        .await;
+    assert_eq!(status, 201, "{}", user);
+    assert_eq!(user["cid"], "2345678");
+    assert_eq!(user["atc_rating"], 1);
+    assert_eq!(user["pilot_rating"], 1);
+
+    let mut reader = server.login_as("NEW123", "2345678", "long enough").await;
+    reader
+        .get_mut()
+        .write_all(b"#DPNEW123:2345678\r\n")
+        .await
+        .unwrap();
+
+    // Four attempts per hour, failed or not
+    let (status, _) = server
+        .register(&registration("3456789", "long enough", "welcome"), None)
+        .await;
+    assert_eq!(status, 429);
+}
+
+#[tokio::test]
+async fn test_register_is_off_by_default() {
+    let server = TestServer::with_api().await;
+    let (status, _, _) = server
+        .send("POST", "/api/register", Some(TOKEN), Some(&json!({})))
+        .await;
+    assert_eq!(status, 404);
+}
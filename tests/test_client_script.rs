@@ -0,0 +1,193 @@
+//! Runs the test_client example in script mode against a real server whose
+//! client software has a challenge key, so the scripted session only passes
+//! if the automatic CAPS and auth challenge answers work
+
+mod common;
+
+use common::{
+    spawn_test_server_with, ServerHandle, TEST_CID, TEST_CLIENT_ID, TEST_CLIENT_KEY, TEST_PASSWORD,
+};
+use openfsd::config::{LogFormat, LogRotation, LoggingConfig, TelemetryConfig};
+use openfsd::db::service::{self, SessionFilter};
+use sea_orm::DatabaseConnection;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+/// A server whose whitelisted client software is challenged with its key
+struct TestServer {
+    addr: SocketAddr,
+    db: DatabaseConnection,
+    _handle: ServerHandle,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        let (addr, handle, db) = spawn_test_server_with(|_| {}).await;
+        TestServer {
+            addr,
+            db,
+            _handle: handle,
+        }
+    }
+
+    /// Run the test client against this server with a script
+    async fn run_script(&self, script: &Path) -> Output {
+        tokio::process::Command::new(test_client())
+            .arg("--server")
+            .arg(self.addr.to_string())
+            .args(["--cid", TEST_CID, "--password", TEST_PASSWORD])
+            .args([
+                "--client-id",
+                TEST_CLIENT_ID,
+                "--client-key",
+                TEST_CLIENT_KEY,
+            ])
+            .arg("--script")
+            .arg(script)
+            .output()
+            .await
+            .expect("start test_client")
+    }
+}
+
+/// The test_client example, built next to the server binary. `cargo test`
+/// builds examples, but not when only this test is selected
+fn test_client() -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_BIN_EXE_openfsd"))
+        .parent()
+        .unwrap()
+        .join("examples")
+        .join(format!("test_client{}", std::env::consts::EXE_SUFFIX));
+    if !path.exists() {
+        let status = Command::new(env!("CARGO"))
+            .args(["build", "--example", "test_client"])
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .status()
+            .expect("run cargo build");
+        assert!(status.success(), "could not build the test_client example");
+    }
+    path
+}
+
+#[tokio::test]
+async fn test_pilot_script_passes() {
+    let server = TestServer::start().await;
+    let script =
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples/scripts/pilot_session.txt");
+
+    let output = server.run_script(&script).await;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("Answering auth challenge"), "{}", stdout);
+    assert!(
+        stdout.contains("Answering capabilities request"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("Script completed"), "{}", stdout);
+}
+
+#[tokio::test]
+async fn test_session_summary_is_logged_and_stored() {
+    // The server runs on this thread, so it logs to this subscriber
+    let log = std::env::temp_dir().join(format!("openfsd-script-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&log);
+    let logging = LoggingConfig {
+        console_level: Some("off".to_string()),
+        file: Some(log.clone()),
+        rotation: LogRotation::Never,
+        format: LogFormat::Json,
+        ..LoggingConfig::default()
+    };
+    let (subscriber, _guard) =
+        openfsd::logging::subscriber(&logging, &TelemetryConfig::default(), "info").unwrap();
+    let _default = tracing::subscriber::set_default(subscriber);
+
+    let server = TestServer::start().await;
+    let script =
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples/scripts/pilot_session.txt");
+    let output = server.run_script(&script).await;
+    assert!(output.status.success());
+
+    // The summary is written once the server notices the client is gone
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let summary = loop {
+        let log = std::fs::read_to_string(&log).unwrap_or_default();
+        let summary = log
+            .lines()
+            .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+            .find(|line| line["reason"].is_string() && line["callsign"] == "TEST123");
+        if let Some(summary) = summary {
+            break summary;
+        }
+        assert!(Instant::now() < deadline, "no session summary in {}", log);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+    let _ = std::fs::remove_file(&log);
+    assert_eq!(summary["cid"], TEST_CID);
+    assert_eq!(summary["reason"], "closed");
+    assert_eq!(summary["position_updates"], 1);
+    assert_eq!(summary["parse_errors"], 0);
+    assert!(summary["client_software"].is_string(), "{}", summary);
+    for field in ["packets_in", "packets_out", "bytes_in", "bytes_out"] {
+        assert!(summary[field].as_u64().unwrap() > 0, "{}", summary);
+    }
+
+    // The session's row is closed with the same values, right after the
+    // summary is logged
+    let session = loop {
+        let sessions = service::list_sessions(&server.db, &SessionFilter::default(), 0, 10)
+            .await
+            .unwrap();
+        let session = sessions.items[0].clone();
+        if session.disconnect_reason.is_some() {
+            break session;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "session was not closed: {:?}",
+            session
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+    assert_eq!(session.disconnect_reason.as_deref(), Some("closed"));
+    assert_eq!(
+        session.client_software.as_deref(),
+        summary["client_software"].as_str()
+    );
+    for (field, value) in [
+        ("packets_in", session.packets_in),
+        ("packets_out", session.packets_out),
+        ("bytes_in", session.bytes_in),
+        ("bytes_out", session.bytes_out),
+        ("position_updates", session.position_updates),
+        ("parse_errors", session.parse_errors),
+    ] {
+        assert_eq!(value, summary[field].as_i64(), "{}", field);
+    }
+}
+
+#[tokio::test]
+async fn test_failed_expectation_exits_non_zero() {
+    let server = TestServer::start().await;
+    let script = std::env::temp_dir().join(format!("openfsd-script-{}.txt", server.addr.port()));
+    std::fs::write(
+        &script,
+        "id TEST123\nlogin pilot\nexpect $CQSERVER:TEST123:CAPS 5s\nexpect NEVER-SENT 300ms\n",
+    )
+    .unwrap();
+
+    let output = server.run_script(&script).await;
+    let _ = std::fs::remove_file(&script);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("line 4"), "{}", stderr);
+    assert!(stderr.contains("NEVER-SENT"), "{}", stderr);
+}
@@ -0,0 +1,230 @@
+//! End-to-end test of an upgrade on SIGUSR2: the running server hands its
+//! listener to a new process and keeps serving the clients it has until they
+//! leave
+#![cfg(unix)]
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+const SECRET: &str = "upgrade-test-secret";
+const CID: &str = "1234567";
+const PASSWORD: &str = "Correct-Horse-42";
+const METAR: &str = "ZSPD 121200Z 36004MPS CAVOK 22/08 Q1012 NOSIG";
+
+/// A server on free ports, with its log lines passed on as they come; the
+/// processes are stopped and the files removed when the test ends
+struct TestServer {
+    child: Child,
+    successor: Option<u32>,
+    logs: mpsc::Receiver<String>,
+    config: PathBuf,
+    metars: PathBuf,
+    port: u16,
+    control: String,
+}
+
+impl TestServer {
+    fn start() -> Self {
+        let port = free_port();
+        let control = format!("127.0.0.1:{}", free_port());
+        let config = std::env::temp_dir().join(format!("openfsd-upgrade-{}.toml", port));
+        let metars = std::env::temp_dir().join(format!("openfsd-upgrade-metars-{}.txt", port));
+        std::fs::write(&metars, format!("{}\n", METAR)).unwrap();
+        std::fs::write(
+            &config,
+            format!(
+                "[server]\naddress = \"127.0.0.1\"\nport = {}\nname = \"OpenFSD\"\n\
+                 version = \"test\"\nmax_clients = 10\n\n\
+                 [whitelist]\nenforce = false\n\n\
+                 [heartbeat]\nenabled = false\n\n\
+                 [weather]\nprovider = \"static\"\nstatic_file = \"{}\"\n\n\
+                 [control]\nenabled = true\naddress = \"{}\"\nsecret = \"{}\"\n",
+                port,
+                metars.display(),
+                control,
+                SECRET
+            ),
+        )
+        .unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_openfsd"))
+            .arg("--config")
+            .arg(&config)
+            .arg("--ephemeral")
+            .env("OPENFSD_BOOTSTRAP_CID", CID)
+            .env("OPENFSD_BOOTSTRAP_PASSWORD", PASSWORD)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("start openfsd");
+        let logs = read_logs(child.stderr.take().unwrap());
+        let server = TestServer {
+            child,
+            successor: None,
+            logs,
+            config,
+            metars,
+            port,
+            control,
+        };
+        server.wait_for_control();
+        server
+    }
+
+    /// Wait until a server answers on the control socket
+    fn wait_for_control(&self) {
+        // Migrations run before the listeners open
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while TcpStream::connect(&self.control).is_err() {
+            assert!(Instant::now() < deadline, "server did not start");
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// The first log line containing `text`
+    fn wait_for_log(&self, text: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.logs.recv_timeout(left) {
+                Ok(line) if line.contains(text) => return line,
+                Ok(_) => continue,
+                Err(_) => panic!("no log line with {:?}", text),
+            }
+        }
+    }
+
+    /// Log in a pilot and wait until the server on the control socket has
+    /// accepted it
+    fn login_pilot(&self, callsign: &str) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        write!(
+            stream,
+            "$ID{cs}:SERVER:b0b0:Test Client 1.0:3:2:{cid}:12345\r\n\
+             #AP{cs}:SERVER:{cid}:{pw}:1:100:1:Test Pilot\r\n",
+            cs = callsign,
+            cid = CID,
+            pw = PASSWORD
+        )
+        .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !self.list_json().contains(callsign) {
+            assert!(Instant::now() < deadline, "{} did not log in", callsign);
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        BufReader::new(stream)
+    }
+
+    fn list_json(&self) -> String {
+        let output = Command::new(env!("CARGO_BIN_EXE_openfsd-admin"))
+            .arg("--config")
+            .arg(&self.config)
+            .args(["clients", "list", "--json"])
+            .output()
+            .expect("start openfsd-admin");
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if let Some(pid) = self.successor {
+            let _ = Command::new("kill").arg(pid.to_string()).status();
+        }
+        let _ = std::fs::remove_file(&self.config);
+        let _ = std::fs::remove_file(&self.metars);
+    }
+}
+
+/// Pass on the lines both processes log, the new one inheriting the pipe
+fn read_logs(stderr: ChildStderr) -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stderr).lines() {
+            let Ok(line) = line else { break };
+            let _ = tx.send(line);
+        }
+    });
+    rx
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Ask for a METAR and wait for the answer, which only comes from a server
+/// still serving the connection
+fn request_metar(pilot: &mut BufReader<TcpStream>, callsign: &str) {
+    write!(pilot.get_mut(), "$AX{}:SERVER:METAR:ZSPD\r\n", callsign).unwrap();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = pilot.read_line(&mut line).expect("no METAR");
+        assert!(read > 0, "connection closed");
+        if line.starts_with("$AR") {
+            assert!(line.contains(METAR), "{}", line);
+            return;
+        }
+    }
+}
+
+#[test]
+fn test_upgrade_keeps_clients() {
+    let mut server = TestServer::start();
+    let mut old = server.login_pilot("CCA1501");
+
+    let status = Command::new("kill")
+        .args(["-USR2", &server.child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let handed = server.wait_for_log("Handed the FSD listener over to process ");
+    let pid: u32 = handed
+        .split("to process ")
+        .nth(1)
+        .and_then(|rest| rest.split(|c: char| !c.is_ascii_digit()).next())
+        .and_then(|pid| pid.parse().ok())
+        .expect("no process ID in the log");
+    assert_ne!(pid, server.child.id());
+    server.successor = Some(pid);
+
+    // The control socket now belongs to the new process, which gets the
+    // new client but never saw the old one
+    server.wait_for_control();
+    let mut new = server.login_pilot("DLH123");
+    assert!(!server.list_json().contains("CCA1501"));
+    request_metar(&mut new, "DLH123");
+
+    // The old process still serves the client it had
+    request_metar(&mut old, "CCA1501");
+
+    // and exits once it has left
+    drop(old);
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let exited = loop {
+        if let Some(status) = server.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "old process did not exit");
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    assert!(exited.success(), "{:?}", exited);
+
+    request_metar(&mut new, "DLH123");
+    assert!(server.list_json().contains("DLH123"));
+}
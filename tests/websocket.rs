@@ -0,0 +1,166 @@
+//! End-to-end tests of the WebSocket bridge: clients log in over a
+//! WebSocket and see traffic from a pilot connected over TCP
+#![cfg(feature = "websocket")]
+
+mod common;
+
+use common::{spawn_test_server_with, ServerHandle, TEST_CID, TEST_PASSWORD};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A server with the WebSocket listener enabled
+struct TestServer {
+    addr: SocketAddr,
+    websocket: String,
+    _handle: ServerHandle,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        let (addr, handle, _db) = spawn_test_server_with(|config| {
+            config.whitelist.enforce = false;
+            config.websocket.enabled = true;
+        })
+        .await;
+        TestServer {
+            addr,
+            websocket: handle.config.websocket.address.clone(),
+            _handle: handle,
+        }
+    }
+
+    /// Open a WebSocket, asking for `protocol` if given
+    async fn connect(&self, protocol: Option<&str>) -> WebSocket {
+        let mut request = format!("ws://{}/", self.websocket)
+            .into_client_request()
+            .unwrap();
+        if let Some(protocol) = protocol {
+            request
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", protocol.parse().unwrap());
+        }
+        let (socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        socket
+    }
+
+    /// Log in a pilot over TCP and return the connection once the server has
+    /// accepted it
+    async fn login_tcp(&self, callsign: &str) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(self.addr).await.unwrap();
+        stream
+            .write_all(login_lines(callsign).as_bytes())
+            .await
+            .unwrap();
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while !line.starts_with("$CQSERVER") {
+            line.clear();
+            let read = tokio::time::timeout(Duration::from_secs(10), reader.read_line(&mut line));
+            assert!(read.await.unwrap().unwrap() > 0, "TCP login failed");
+        }
+        reader
+    }
+}
+
+/// $ID and #AP lines logging in `callsign` with the test account
+fn login_lines(callsign: &str) -> String {
+    format!(
+        "$ID{cs}:SERVER:b0b0:Web Client 1.0:3:2:{cid}:12345\r\n\
+         #AP{cs}:SERVER:{cid}:{pw}:1:100:1:Web Pilot\r\n",
+        cs = callsign,
+        cid = TEST_CID,
+        pw = TEST_PASSWORD
+    )
+}
+
+/// Read text messages until one satisfies `wanted` and return it
+async fn read_until(socket: &mut WebSocket, wanted: impl Fn(&str) -> bool) -> String {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(10), socket.next())
+            .await
+            .expect("timed out waiting for a message")
+            .expect("socket closed")
+            .unwrap();
+        if let Message::Text(text) = message {
+            if wanted(&text) {
+                return text.to_string();
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_login_and_broadcast_over_lines() {
+    let server = TestServer::start().await;
+    let mut socket = server.connect(None).await;
+
+    let greeting = read_until(&mut socket, |_| true).await;
+    assert!(greeting.starts_with("$DISERVER:CLIENT:"), "{}", greeting);
+
+    // Both login lines in one message
+    socket
+        .send(Message::Text(login_lines("WEB1").into()))
+        .await
+        .unwrap();
+    read_until(&mut socket, |text| text.starts_with("$CQSERVER:WEB1")).await;
+
+    let _pilot = server.login_tcp("TCP1").await;
+    let added = read_until(&mut socket, |text| text.starts_with("#AP")).await;
+    assert!(added.starts_with("#APTCP1:SERVER:"), "{}", added);
+}
+
+#[tokio::test]
+async fn test_login_and_broadcast_as_json() {
+    let server = TestServer::start().await;
+    let mut socket = server.connect(Some("fsd-json")).await;
+    let packet = |text: &str| serde_json::from_str::<Value>(text).unwrap();
+
+    let greeting = packet(&read_until(&mut socket, |_| true).await);
+    assert_eq!(greeting["command"], "DI");
+    assert_eq!(greeting["packet_type"], "request");
+
+    let login = [
+        json!({
+            "packet_type": "request",
+            "command": "ID",
+            "source": "WEB2",
+            "destination": "SERVER",
+            "data": ["b0b0", "Web Client 1.0", "3", "2", TEST_CID, "12345"],
+        }),
+        json!({
+            "packet_type": "client",
+            "command": "AP",
+            "source": "WEB2",
+            "destination": "SERVER",
+            "data": [TEST_CID, TEST_PASSWORD, "1", "100", "1", "Web Pilot"],
+        }),
+    ];
+    for message in login {
+        socket
+            .send(Message::Text(message.to_string().into()))
+            .await
+            .unwrap();
+    }
+    read_until(&mut socket, |text| {
+        let packet = packet(text);
+        packet["command"] == "CQ" && packet["destination"] == "WEB2"
+    })
+    .await;
+
+    let _pilot = server.login_tcp("TCP2").await;
+    read_until(&mut socket, |text| {
+        let packet = packet(text);
+        packet["command"] == "AP" && packet["source"] == "TCP2"
+    })
+    .await;
+}